@@ -0,0 +1,104 @@
+use std::{collections::HashMap, env, time::Duration};
+
+use common::{config::Config, logging};
+use db_utils::types::DBClient;
+
+use crate::notify::Alert;
+use crate::reports::ReportWriter;
+use crate::rules::AlertConfig;
+mod notify;
+mod quality_alerts;
+mod reports;
+mod retention;
+mod rules;
+mod stage_notices;
+mod storage_alerts;
+
+#[tokio::main]
+async fn main() {
+    let service_config = Config::load().expect("ALERTING: Failed to load config");
+    logging::init(&service_config.logging);
+    let broker = service_config.kafka.consumer_brokers().to_string();
+    let rules_path = env::var("ALERT_RULES_PATH").unwrap_or("alert_rules.yaml".to_string());
+
+    let contents = std::fs::read_to_string(&rules_path).expect("Failed to read alert rules file");
+    let config = AlertConfig::from_yaml(&contents).expect("Failed to parse alert rules");
+
+    let db = DBClient::new(&service_config.mongo).await;
+    let http = reqwest::Client::new();
+    let reports = match service_config.reports.enabled {
+        true => Some(ReportWriter {
+            storage: storage::from_config(&service_config.storage, &service_config.s3.bucket)
+                .await,
+            config: service_config.reports.clone(),
+        }),
+        false => None,
+    };
+
+    // Rules only notify when they change state, so a sustained problem doesn't spam every tick
+    let mut firing: HashMap<String, bool> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.evaluation_interval_secs));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = retention::warn_expiring_batches(&db, &http, &service_config.retention).await {
+            tracing::error!(error = %e, "Failed to check expiring batch results");
+        }
+        if let Err(e) =
+            storage_alerts::send_storage_alerts(&db, &http, &service_config.storage).await
+        {
+            tracing::error!(error = %e, "Failed to send storage alerts");
+        }
+        if let Err(e) =
+            quality_alerts::send_quality_alerts(&db, &http, &service_config.quality_drift).await
+        {
+            tracing::error!(error = %e, "Failed to send quality drift alerts");
+        }
+        if let Err(e) = stage_notices::send_stage_notices(&db, &http).await {
+            tracing::error!(error = %e, "Failed to send stage notices");
+        }
+        if let Some(reports) = &reports
+            && let Err(e) = reports.report_finished_batches(&db, &http).await
+        {
+            tracing::error!(error = %e, "Failed to report finished batches");
+        }
+
+        for rule in &config.rules {
+            let value = match rule.metric.measure(&db, &broker).await {
+                Ok(value) => value,
+                Err(e) => {
+                    tracing::error!(rule = %rule.name, error = %e, "Failed to evaluate rule");
+                    continue;
+                }
+            };
+
+            let is_firing = rule.comparison.holds(value, rule.threshold);
+            let was_firing = firing.insert(rule.name.clone(), is_firing).unwrap_or(false);
+            if is_firing == was_firing {
+                continue;
+            }
+
+            let alert = Alert {
+                rule: rule.name.clone(),
+                value,
+                threshold: rule.threshold,
+                resolved: !is_firing,
+                message: match is_firing {
+                    true => format!(
+                        "[FIRING] {}: {:?} is {} ({:?} {})",
+                        rule.name, rule.metric, value, rule.comparison, rule.threshold
+                    ),
+                    false => format!("[RESOLVED] {}: {:?} is {}", rule.name, rule.metric, value),
+                },
+            };
+            tracing::warn!(rule = %rule.name, value, resolved = alert.resolved, "{}", alert.message);
+
+            for target in &rule.notify {
+                if let Err(e) = target.send(&http, &alert).await {
+                    tracing::error!(rule = %rule.name, error = %e, "Failed to notify");
+                }
+            }
+        }
+    }
+}
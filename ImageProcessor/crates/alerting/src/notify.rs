@@ -0,0 +1,80 @@
+use chrono::Utc;
+use common::{error::ProcessorError, notifications::NotificationEvent};
+use db_utils::types::{DBClient, DBDatasetProcessingJob};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const PAGERDUTY_EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// Where a rule's notifications are delivered
+#[derive(Debug, Deserialize)]
+pub enum NotificationTarget {
+    Webhook { url: String },
+    Slack { webhook_url: String },
+    PagerDuty { routing_key: String },
+}
+
+/// A rule changing state, either starting to fire or resolving
+#[derive(Debug, Serialize)]
+pub struct Alert {
+    pub rule: String,
+    pub value: f64,
+    pub threshold: f64,
+    pub resolved: bool,
+    pub message: String,
+}
+
+impl NotificationTarget {
+    pub async fn send(&self, client: &reqwest::Client, alert: &Alert) -> Result<(), String> {
+        let request = match self {
+            NotificationTarget::Webhook { url } => client.post(url).json(alert),
+            NotificationTarget::Slack { webhook_url } => client
+                .post(webhook_url)
+                .json(&json!({ "text": alert.message })),
+            NotificationTarget::PagerDuty { routing_key } => {
+                client.post(PAGERDUTY_EVENTS_URL).json(&json!({
+                    "routing_key": routing_key,
+                    "event_action": if alert.resolved { "resolve" } else { "trigger" },
+                    "dedup_key": alert.rule,
+                    "payload": {
+                        "summary": alert.message,
+                        "source": "distributed-dataset-processor",
+                        "severity": "error",
+                    },
+                }))
+            }
+        };
+
+        request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map(|_| ())
+            .map_err(|e| format!("Failed to deliver alert {}: {}", alert.rule, e))
+    }
+}
+
+/// The submitter's webhook of a batch, `None` if it has none or the preferences that apply to the
+/// batch hold the event back
+pub async fn submitter_webhook<'a>(
+    db: &DBClient,
+    batch: &'a DBDatasetProcessingJob,
+    event: NotificationEvent,
+) -> Result<Option<&'a String>, ProcessorError> {
+    let Some(url) = batch
+        .retention
+        .as_ref()
+        .and_then(|retention| retention.notification_url.as_ref())
+    else {
+        return Ok(None);
+    };
+
+    let preferences = db.batch_notification_preferences(batch).await?;
+    match preferences.allows(event, Utc::now()) {
+        true => Ok(Some(url)),
+        false => {
+            tracing::debug!(batch_id = %batch.batch_id, ?event, "Held back by the preferences");
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use common::{
+    config::QualityDriftConfig, drift::QualityDrift, error::ProcessorError,
+    notifications::NotificationEvent,
+};
+use db_utils::types::{DBClient, DBDatasetProcessingJob, DBDatasetTask};
+use serde::Serialize;
+
+use crate::notify::submitter_webhook;
+
+/// Sent to the batch's and the operators' webhooks once a stage's outputs drifted from earlier
+/// stages of its pipeline, see `common::drift`
+#[derive(Debug, Serialize)]
+struct QualityDriftNotice<'a> {
+    batch_id: uuid::Uuid,
+    dataset_key: &'a str,
+    owner: Option<&'a str>,
+    stage: u32,
+    drifts: &'a [QualityDrift],
+    message: String,
+}
+
+/// Sends the drifts of every stage checked since the last tick.
+///
+/// A stage is claimed before its drifts are delivered, so a webhook that is down doesn't get the
+/// same alert on every tick.
+pub async fn send_quality_alerts(
+    db: &DBClient,
+    http: &reqwest::Client,
+    config: &QualityDriftConfig,
+) -> Result<(), ProcessorError> {
+    for task in db.get_unsent_quality_drifts().await? {
+        if !db.claim_quality_alert(&task.task_id).await? {
+            continue; // Another instance sent it first
+        }
+        let Some(batch) = db.get_batch(&task.batch_id).await? else {
+            continue;
+        };
+        let Some(notice) = quality_drift_notice(&batch, &task) else {
+            continue;
+        };
+        tracing::warn!(batch_id = %batch.batch_id, stage = task.stage, "{}", notice.message);
+
+        let targets = submitter_webhook(db, &batch, NotificationEvent::QualityDrift)
+            .await?
+            .into_iter()
+            .chain(config.alert_webhook.as_ref());
+        for url in targets {
+            let delivered = http
+                .post(url)
+                .json(&notice)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            if let Err(e) = delivered {
+                tracing::error!(
+                    batch_id = %batch.batch_id,
+                    error = %e,
+                    "Failed to deliver quality drift alert"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn quality_drift_notice<'a>(
+    batch: &'a DBDatasetProcessingJob,
+    task: &'a DBDatasetTask,
+) -> Option<QualityDriftNotice<'a>> {
+    let quality = task.quality.as_ref()?;
+    let metrics = quality
+        .drifts
+        .iter()
+        .map(|drift| format!("{:?} {:.3} vs {:.3}", drift.metric, drift.observed, drift.baseline))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Some(QualityDriftNotice {
+        batch_id: batch.batch_id,
+        dataset_key: &batch.dataset_key,
+        owner: batch.owner.as_deref(),
+        stage: task.stage,
+        drifts: &quality.drifts,
+        message: format!(
+            "[QUALITY] Stage {} ({}) of batch {} drifted from its baseline: {}",
+            task.stage, quality.pipeline, batch.batch_id, metrics
+        ),
+    })
+}
@@ -0,0 +1,271 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use common::{
+    config::ReportConfig,
+    error::ProcessorError,
+    lifecycle::BatchState,
+    manifest::manifest_key,
+    notifications::NotificationEvent,
+    operations_name,
+    report::{
+        BatchFinishedNotice, BatchReport, CostEstimate, FailureSummary, ReportLink,
+        StageSummary, report_key,
+    },
+};
+use db_utils::types::{BatchReportRecord, DBClient, DBDatasetProcessingJob};
+use storage::{PutOptions, StorageBackend};
+
+use crate::notify::submitter_webhook;
+
+// Batches that finished longer ago are left alone, so enabling reports doesn't write one for
+// every batch in the history
+const LOOKBACK_HOURS: i64 = 24;
+
+const BYTES_PER_GB: f64 = 1e9;
+
+/// Writes the report of every batch that finished and sends the notices referencing them, see
+/// `common::report`
+pub struct ReportWriter {
+    pub storage: Arc<dyn StorageBackend>,
+    pub config: ReportConfig,
+}
+
+impl ReportWriter {
+    /// Reports every batch that finished since the last tick.
+    ///
+    /// A report is claimed before it is written, so a batch whose report failed isn't retried on
+    /// every tick, the failure is logged instead.
+    pub async fn report_finished_batches(
+        &self,
+        db: &DBClient,
+        http: &reqwest::Client,
+    ) -> Result<(), ProcessorError> {
+        let since = Utc::now() - chrono::Duration::hours(LOOKBACK_HOURS);
+
+        for batch in db.get_batches_awaiting_report(since).await? {
+            let tenant_id = batch.tenant_id.as_deref();
+            let record = BatchReportRecord {
+                html_key: report_key(tenant_id, &batch.batch_id, "html"),
+                markdown_key: report_key(tenant_id, &batch.batch_id, "md"),
+                time_created: Utc::now(),
+            };
+            if !db.claim_batch_report(&batch.batch_id, &record).await? {
+                continue; // Another instance reports it
+            }
+
+            if let Err(e) = self.write_report(db, http, &batch, &record).await {
+                tracing::error!(
+                    batch_id = %batch.batch_id,
+                    error = %e,
+                    "Failed to write batch report"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write_report(
+        &self,
+        db: &DBClient,
+        http: &reqwest::Client,
+        batch: &DBDatasetProcessingJob,
+        record: &BatchReportRecord,
+    ) -> Result<(), ProcessorError> {
+        let report = self.build_report(db, batch).await?;
+
+        for (key, body, content_type) in [
+            (&record.markdown_key, report.to_markdown(), "text/markdown; charset=utf-8"),
+            (&record.html_key, report.to_html(), "text/html; charset=utf-8"),
+        ] {
+            let options = PutOptions {
+                content_type: Some(content_type.to_string()),
+                ..Default::default()
+            };
+            self.storage
+                .put_object(key, body.into_bytes().into(), &options)
+                .await?;
+        }
+
+        let notice = BatchFinishedNotice {
+            batch_id: batch.batch_id,
+            state: batch.state,
+            images: report.images(),
+            failed: report.failed(),
+            report_html_key: record.html_key.clone(),
+            report_markdown_key: record.markdown_key.clone(),
+            report_url: self.presign(&record.html_key).await,
+            message: format!(
+                "[REPORT] Batch {} finished as {:?}: {} images, {} failed",
+                batch.batch_id,
+                batch.state,
+                report.images(),
+                report.failed()
+            ),
+        };
+        tracing::info!(batch_id = %batch.batch_id, key = %record.html_key, "{}", notice.message);
+
+        let event = match batch.state {
+            BatchState::Failed | BatchState::TimedOut => NotificationEvent::BatchFailed,
+            _ => NotificationEvent::BatchCompleted,
+        };
+        let targets = submitter_webhook(db, batch, event)
+            .await?
+            .into_iter()
+            .chain(self.config.webhook.as_ref());
+        for url in targets {
+            let delivered = http
+                .post(url)
+                .json(&notice)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            if let Err(e) = delivered {
+                tracing::error!(
+                    batch_id = %batch.batch_id,
+                    error = %e,
+                    "Failed to deliver batch report notice"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn build_report(
+        &self,
+        db: &DBClient,
+        batch: &DBDatasetProcessingJob,
+    ) -> Result<BatchReport, ProcessorError> {
+        let mut counts = db
+            .image_status_counts_by_dataset_task(&batch.batch_id)
+            .await?;
+        let byte_totals = db.stage_byte_totals(&batch.batch_id).await?;
+
+        // A stage starts once the previous one finished, the first one on submission
+        let mut previous_end = batch.time_created;
+        let mut stages = Vec::new();
+        for task in db.get_dataset_tasks(&batch.batch_id).await? {
+            let counts = counts.remove(&task.task_id).unwrap_or_default();
+            let duration_secs = task.time_completed.map(|end| {
+                let secs = (end - previous_end).num_seconds().max(0);
+                previous_end = end;
+                secs
+            });
+            let operations = std::iter::once(&task.operation).chain(&task.fused_operations);
+            stages.push(StageSummary {
+                stage: task.stage,
+                operation: operations_name(operations),
+                images: task.image_count.unwrap_or(counts.total()),
+                succeeded: counts.success,
+                failed: counts.failure,
+                filtered: counts.filtered,
+                duration_secs,
+            });
+        }
+
+        let failures = db.get_image_failures(&batch.batch_id, None).await?;
+        let failures_total = failures.len();
+        let mut listed = Vec::new();
+        for failure in failures.into_iter().take(self.config.max_failures) {
+            // The thumbnail is the input the stage failed on, straight from storage
+            let thumbnail_url = match db.get_image_task(&failure.image_task_id).await? {
+                Some(task) => self.presign(&task.s3_key).await,
+                None => None,
+            };
+            listed.push(FailureSummary {
+                filename: failure.filename,
+                stage: failure.stage,
+                error: failure.error,
+                thumbnail_url,
+            });
+        }
+
+        let priced = self.config.compute_hour_cost > 0.0 || self.config.gb_written_cost > 0.0;
+        let cost = match priced {
+            true => {
+                let compute_ms: f64 = db
+                    .batch_image_task_latencies(&batch.batch_id)
+                    .await?
+                    .values()
+                    .flatten()
+                    .sum();
+                let bytes_written: u64 = byte_totals
+                    .values()
+                    .map(|totals| totals.bytes_written)
+                    .sum();
+                Some(self.estimate_cost(
+                    compute_ms / 3_600_000.0,
+                    bytes_written as f64 / BYTES_PER_GB,
+                ))
+            }
+            false => None,
+        };
+
+        Ok(BatchReport {
+            batch_id: batch.batch_id,
+            dataset_key: batch.dataset_key.clone(),
+            owner: batch.owner.clone(),
+            state: batch.state,
+            time_created: batch.time_created,
+            time_completed: batch.time_completed.unwrap_or_else(Utc::now),
+            stages,
+            failures: listed,
+            failures_total,
+            cost,
+            links: self.links(batch).await,
+        })
+    }
+
+    fn estimate_cost(&self, compute_hours: f64, gb_written: f64) -> CostEstimate {
+        CostEstimate {
+            compute_hours,
+            gb_written,
+            amount: compute_hours * self.config.compute_hour_cost
+                + gb_written * self.config.gb_written_cost,
+            currency: self.config.currency.clone(),
+        }
+    }
+
+    /// The api-server endpoints of the batch and its last results manifest
+    async fn links(&self, batch: &DBDatasetProcessingJob) -> Vec<ReportLink> {
+        let mut links = Vec::new();
+
+        if let Some(api_url) = &self.config.api_url {
+            let api_url = api_url.trim_end_matches('/');
+            for (label, endpoint) in [
+                ("Status", "status"),
+                ("Statistics", "stats"),
+                ("Failed images", "failures"),
+            ] {
+                links.push(ReportLink {
+                    label: label.to_string(),
+                    url: format!("{}/batch/{}/{}", api_url, batch.batch_id, endpoint),
+                });
+            }
+        }
+
+        if let Some(sequence) = batch.manifests_published.checked_sub(1) {
+            let key = manifest_key(batch.tenant_id.as_deref(), &batch.batch_id, sequence);
+            if let Some(url) = self.presign(&key).await {
+                links.push(ReportLink {
+                    label: "Results manifest".to_string(),
+                    url,
+                });
+            }
+        }
+
+        links
+    }
+
+    /// A presigned link to an object, `None` if it couldn't be signed
+    async fn presign(&self, key: &str) -> Option<String> {
+        let expires_in = Duration::from_secs(self.config.link_expiry_secs);
+        self.storage
+            .presign_get(key, expires_in)
+            .await
+            .inspect_err(|e| tracing::warn!(key, error = %e, "Failed to presign report link"))
+            .ok()
+    }
+}
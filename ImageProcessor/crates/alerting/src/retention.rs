@@ -0,0 +1,85 @@
+use chrono::{Duration, Utc};
+use common::{
+    config::RetentionConfig, error::ProcessorError, notifications::NotificationEvent,
+};
+use db_utils::types::{DBClient, DBDatasetProcessingJob};
+use serde::Serialize;
+
+use crate::notify::submitter_webhook;
+
+/// Sent to the batch's and the operators' webhooks a few days before its results expire
+#[derive(Debug, Serialize)]
+struct ExpiryWarning {
+    batch_id: uuid::Uuid,
+    dataset_key: String,
+    owner: Option<String>,
+    expires_at: chrono::DateTime<Utc>,
+    message: String,
+}
+
+/// Warns about every batch whose results expire within `warn_days_before` days.
+///
+/// A batch is only warned about once, recorded once a webhook took the warning. When every
+/// webhook fails the warning is left unrecorded and sent again on the next tick.
+pub async fn warn_expiring_batches(
+    db: &DBClient,
+    http: &reqwest::Client,
+    config: &RetentionConfig,
+) -> Result<(), ProcessorError> {
+    let deadline = Utc::now() + Duration::days(config.warn_days_before as i64);
+
+    for batch in db.get_batches_expiring_before(deadline).await? {
+        let Some(warning) = expiry_warning(&batch) else {
+            continue;
+        };
+
+        let targets: Vec<&String> =
+            submitter_webhook(db, &batch, NotificationEvent::ResultsExpiring)
+                .await?
+                .into_iter()
+                .chain(config.warning_webhook.as_ref())
+                .collect();
+        let mut delivered = targets.is_empty(); // Only logged, nothing to retry
+        for url in targets {
+            let sent = http
+                .post(url)
+                .json(&warning)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            match sent {
+                Ok(_) => delivered = true,
+                Err(e) => tracing::error!(
+                    batch_id = %batch.batch_id,
+                    error = %e,
+                    "Failed to deliver expiry warning"
+                ),
+            }
+        }
+        if !delivered {
+            continue;
+        }
+
+        // Not recorded if another instance warned about it first
+        if db.mark_expiry_warning_sent(&batch.batch_id).await? {
+            tracing::info!(batch_id = %batch.batch_id, "{}", warning.message);
+        }
+    }
+
+    Ok(())
+}
+
+fn expiry_warning(batch: &DBDatasetProcessingJob) -> Option<ExpiryWarning> {
+    let expires_at = batch.retention.as_ref()?.expires_at?;
+
+    Some(ExpiryWarning {
+        batch_id: batch.batch_id,
+        dataset_key: batch.dataset_key.clone(),
+        owner: batch.owner.clone(),
+        expires_at,
+        message: format!(
+            "[RETENTION] The results of batch {} expire at {}",
+            batch.batch_id, expires_at
+        ),
+    })
+}
@@ -0,0 +1,107 @@
+use chrono::{Duration, Utc};
+use db_utils::types::{DBClient, TaskStatus};
+use queue::lag::{consumer_group_lag, total_lag};
+use serde::Deserialize;
+
+use crate::notify::NotificationTarget;
+
+// ============================================================================
+// RULE CONFIGURATION
+// ============================================================================
+
+fn default_evaluation_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlertConfig {
+    #[serde(default = "default_evaluation_interval")]
+    pub evaluation_interval_secs: u64,
+    pub rules: Vec<AlertRule>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: Metric,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    pub notify: Vec<NotificationTarget>,
+}
+
+/// The pipeline health signals a rule can be evaluated against
+#[derive(Debug, Deserialize)]
+pub enum Metric {
+    /// Fraction of image tasks created in the window that ended in Failure
+    FailureRate { window_minutes: i64 },
+    /// Total number of messages the consumer group still has to read from the topic
+    QueueLag { topic: String, group_id: String },
+    /// Held tasks none of the workers heard from in the last `worker_ttl_secs` can run, see
+    /// `common::capabilities`
+    UnservableHeldTasks { worker_ttl_secs: i64 },
+}
+
+#[derive(Debug, Deserialize)]
+pub enum Comparison {
+    GreaterThan,
+    LessThan,
+}
+
+impl AlertConfig {
+    pub fn from_yaml(contents: &str) -> Result<Self, String> {
+        serde_yaml::from_str(contents).map_err(|e| format!("Invalid alert rules: {}", e))
+    }
+}
+
+// ============================================================================
+// EVALUATION
+// ============================================================================
+
+impl Comparison {
+    pub fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::GreaterThan => value > threshold,
+            Comparison::LessThan => value < threshold,
+        }
+    }
+}
+
+impl Metric {
+    /// Measures the current value of the metric
+    pub async fn measure(&self, db: &DBClient, brokers: &str) -> Result<f64, String> {
+        match self {
+            Metric::FailureRate { window_minutes } => {
+                let since = Utc::now() - Duration::minutes(*window_minutes);
+                let total = db
+                    .count_image_tasks_since(since, None)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if total == 0 {
+                    return Ok(0.0);
+                }
+                let failed = db
+                    .count_image_tasks_since(since, Some(TaskStatus::Failure))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok(failed as f64 / total as f64)
+            }
+            Metric::QueueLag { topic, group_id } => {
+                let (brokers, group_id, topic) =
+                    (brokers.to_string(), group_id.clone(), topic.clone());
+                let lags = tokio::task::spawn_blocking(move || {
+                    consumer_group_lag(&brokers, &group_id, &topic)
+                })
+                .await
+                .map_err(|e| format!("Join error: {}", e))??;
+                Ok(total_lag(&lags) as f64)
+            }
+            Metric::UnservableHeldTasks { worker_ttl_secs } => {
+                let since = Utc::now() - Duration::seconds(*worker_ttl_secs);
+                db.count_unservable_held_tasks(since)
+                    .await
+                    .map(|count| count as f64)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
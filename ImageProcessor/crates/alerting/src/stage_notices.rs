@@ -0,0 +1,75 @@
+use chrono::{Duration, Utc};
+use common::{error::ProcessorError, notifications::NotificationEvent, operations_name};
+use db_utils::types::{DBClient, TaskStatus};
+use serde::Serialize;
+
+use crate::notify::submitter_webhook;
+
+// Stages that finished longer ago are left alone, so enabling the notices of a batch doesn't
+// send one for every stage it ran before
+const LOOKBACK_HOURS: i64 = 24;
+
+/// Sent to the batch's webhook once a stage finished, if its preferences ask for every stage
+#[derive(Debug, Serialize)]
+struct StageFinishedNotice {
+    batch_id: uuid::Uuid,
+    stage: u32,
+    operation: String,
+    status: TaskStatus,
+    images: Option<u64>,
+    message: String,
+}
+
+/// Notifies about every stage that finished since the last tick.
+///
+/// A stage is claimed before its notice is delivered, so a webhook that is down doesn't get the
+/// same notice on every tick.
+pub async fn send_stage_notices(
+    db: &DBClient,
+    http: &reqwest::Client,
+) -> Result<(), ProcessorError> {
+    let since = Utc::now() - Duration::hours(LOOKBACK_HOURS);
+
+    for task in db.get_stages_awaiting_notice(since).await? {
+        if !db.claim_stage_notice(&task.task_id).await? {
+            continue; // Another instance notified about it first
+        }
+        let Some(batch) = db.get_batch(&task.batch_id).await? else {
+            continue;
+        };
+        let Some(url) = submitter_webhook(db, &batch, NotificationEvent::StageFinished).await?
+        else {
+            continue;
+        };
+
+        let operations = std::iter::once(&task.operation).chain(&task.fused_operations);
+        let operation = operations_name(operations);
+        let notice = StageFinishedNotice {
+            batch_id: task.batch_id,
+            stage: task.stage,
+            message: format!(
+                "[STAGE] Stage {} ({}) of batch {} finished as {:?}",
+                task.stage, operation, task.batch_id, task.status
+            ),
+            operation,
+            status: task.status,
+            images: task.image_count,
+        };
+        let delivered = http
+            .post(url)
+            .json(&notice)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        if let Err(e) = delivered {
+            tracing::error!(
+                batch_id = %task.batch_id,
+                stage = task.stage,
+                error = %e,
+                "Failed to deliver stage notice"
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use common::{
+    config::StorageConfig,
+    error::{ProcessorError, S3ErrorKind},
+    notifications::NotificationEvent,
+};
+use db_utils::types::{DBClient, DBDatasetProcessingJob};
+use serde::Serialize;
+
+use crate::notify::submitter_webhook;
+
+/// Sent to the batch's and the operators' webhooks once storage denied a task of the batch
+#[derive(Debug, Serialize)]
+struct StorageAlertNotice {
+    batch_id: uuid::Uuid,
+    dataset_key: String,
+    owner: Option<String>,
+    kind: S3ErrorKind,
+    key: String,
+    error: String,
+    message: String,
+}
+
+/// Sends every storage alert raised since the last tick.
+///
+/// An alert is marked as sent before it is delivered, so a webhook that is down doesn't get the
+/// same alert on every tick.
+pub async fn send_storage_alerts(
+    db: &DBClient,
+    http: &reqwest::Client,
+    config: &StorageConfig,
+) -> Result<(), ProcessorError> {
+    for batch in db.get_unsent_storage_alerts().await? {
+        if !db.mark_storage_alert_sent(&batch.batch_id).await? {
+            continue; // Another instance sent it first
+        }
+
+        let Some(notice) = storage_alert_notice(&batch) else {
+            continue;
+        };
+        tracing::error!(batch_id = %batch.batch_id, key = %notice.key, "{}", notice.message);
+
+        let targets = submitter_webhook(db, &batch, NotificationEvent::StorageAlert)
+            .await?
+            .into_iter()
+            .chain(config.alert_webhook.as_ref());
+        for url in targets {
+            let delivered = http
+                .post(url)
+                .json(&notice)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            if let Err(e) = delivered {
+                tracing::error!(
+                    batch_id = %batch.batch_id,
+                    error = %e,
+                    "Failed to deliver storage alert"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn storage_alert_notice(batch: &DBDatasetProcessingJob) -> Option<StorageAlertNotice> {
+    let alert = batch.storage_alert.as_ref()?;
+
+    Some(StorageAlertNotice {
+        batch_id: batch.batch_id,
+        dataset_key: batch.dataset_key.clone(),
+        owner: batch.owner.clone(),
+        kind: alert.kind,
+        key: alert.key.clone(),
+        error: alert.message.clone(),
+        message: format!(
+            "[STORAGE] Batch {} was denied access to {}, its images fail until access is granted",
+            batch.batch_id, alert.key
+        ),
+    })
+}
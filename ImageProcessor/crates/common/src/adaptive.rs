@@ -0,0 +1,152 @@
+use crate::{ImageOperation, dimensions::Dimensions};
+
+// ============================================================================
+// ADAPTIVE PARAMETERS
+// Some operations are expressed relative to the size of the image they run on
+// (e.g. "resize so the long edge is 1024px", "blur with a sigma of 2px per
+// megapixel") or pick a different operation per
+// resolution bucket. They are resolved into a plain operation per image, in the
+// worker, once the real size of the image is known.
+// ============================================================================
+
+/// One resolution bucket of a `ByResolution` operation. Rules are checked in order and the first
+/// one whose bound fits the image is used, a rule without a bound matches every image.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ResolutionRule {
+    pub max_megapixels: Option<f32>, // Upper bound (inclusive) of the bucket, None for a catch-all
+    pub operation: ImageOperation,
+}
+
+impl ResolutionRule {
+    pub fn matches(&self, dims: Dimensions) -> bool {
+        self.max_megapixels
+            .is_none_or(|max| dims.megapixels() <= max)
+    }
+}
+
+impl ImageOperation {
+    /// Whether the parameters of the operation depend on the size of the image
+    pub fn is_adaptive(&self) -> bool {
+        matches!(
+            self,
+            ImageOperation::ResizeLongEdge { .. }
+                | ImageOperation::ResizeShortEdge { .. }
+                | ImageOperation::CenterCrop { .. }
+                | ImageOperation::BlurPerMegapixel { .. }
+                | ImageOperation::ByResolution { .. }
+        )
+    }
+
+    /// Turns the operation into one with absolute parameters for an image of the given size.
+    ///
+    /// Returns `None` when no resolution bucket matches, meaning the image is left unchanged.
+    pub fn resolve(&self, dims: Dimensions) -> Option<ImageOperation> {
+        match self {
+            ImageOperation::ResizeLongEdge { long_edge, filter } => Some(ImageOperation::Resize {
+                scaling_factor: *long_edge as f32 / dims.long_edge().max(1) as f32,
+                filter: *filter,
+            }),
+            ImageOperation::ResizeShortEdge { short_edge, filter } => {
+                Some(ImageOperation::Resize {
+                    scaling_factor: *short_edge as f32 / dims.short_edge().max(1) as f32,
+                    filter: *filter,
+                })
+            }
+            ImageOperation::CenterCrop { width, height } => Some(ImageOperation::Crop {
+                x: dims.width.saturating_sub(*width) / 2,
+                y: dims.height.saturating_sub(*height) / 2,
+                width: *width,
+                height: *height,
+            }),
+            ImageOperation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            } => Some(ImageOperation::Blur {
+                sigma: sigma_per_megapixel * dims.megapixels(),
+            }),
+            ImageOperation::ByResolution { rules } => rules
+                .iter()
+                .find(|rule| rule.matches(dims))
+                .and_then(|rule| rule.operation.resolve(dims)),
+            op => Some(op.clone()),
+        }
+    }
+
+    /// Problems with the adaptive parameters of the operation that make it unusable
+    pub fn adaptive_issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        match self {
+            ImageOperation::ResizeLongEdge { long_edge: 0, .. } => {
+                issues.push("ResizeLongEdge needs a long_edge of at least 1 pixel".to_string());
+            }
+            ImageOperation::ResizeShortEdge { short_edge: 0, .. } => {
+                issues.push("ResizeShortEdge needs a short_edge of at least 1 pixel".to_string());
+            }
+            ImageOperation::CenterCrop { width, height } if *width == 0 || *height == 0 => {
+                issues.push("CenterCrop needs a width and height of at least 1 pixel".to_string());
+            }
+            ImageOperation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            } if !sigma_per_megapixel.is_finite() || *sigma_per_megapixel <= 0.0 => {
+                issues.push("BlurPerMegapixel needs a positive sigma_per_megapixel".to_string());
+            }
+            ImageOperation::ByResolution { rules } => {
+                if rules.is_empty() {
+                    issues.push("ByResolution needs at least one rule".to_string());
+                }
+                for rule in rules {
+                    if matches!(rule.operation, ImageOperation::ByResolution { .. }) {
+                        issues.push("ByResolution rules can't be nested".to_string());
+                    }
+                    // Every stage must know the extension of its outputs before seeing the images
+                    if matches!(rule.operation, ImageOperation::Convert { .. }) {
+                        issues.push("ByResolution rules can't convert the format".to_string());
+                    }
+                    // Rules are resolved while the image is processed, too late to leave it out
+                    if matches!(rule.operation, ImageOperation::QualityGate { .. }) {
+                        issues.push("ByResolution rules can't be quality gates".to_string());
+                    }
+                    if rule.max_megapixels.is_some_and(|max| max <= 0.0) {
+                        issues.push("ByResolution max_megapixels must be positive".to_string());
+                    }
+                    issues.extend(rule.operation.adaptive_issues());
+                }
+            }
+            _ => {}
+        }
+
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blur_sigma_scales_with_the_area() {
+        let blur = ImageOperation::BlurPerMegapixel {
+            sigma_per_megapixel: 2.0,
+        };
+        let sigma = |width, height| match blur.resolve(Dimensions { width, height }) {
+            Some(ImageOperation::Blur { sigma }) => sigma,
+            other => panic!("resolved to {:?}", other),
+        };
+
+        assert_eq!(sigma(1000, 1000), 2.0);
+        assert_eq!(sigma(2000, 2000), 8.0);
+    }
+
+    #[test]
+    fn long_edge_resize_scales_to_the_long_edge() {
+        let resize = ImageOperation::ResizeLongEdge {
+            long_edge: 1024,
+            filter: None,
+        };
+
+        match resize.resolve(Dimensions { width: 2048, height: 512 }) {
+            Some(ImageOperation::Resize { scaling_factor, .. }) => assert_eq!(scaling_factor, 0.5),
+            other => panic!("resolved to {:?}", other),
+        }
+    }
+}
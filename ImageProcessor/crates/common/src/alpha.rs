@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// ALPHA CHANNELS
+// Images with transparency keep it through every operation by default. Resize
+// and blur filter premultiplied colours, so fully transparent pixels don't
+// bleed their hidden colour into the edges, and converting to a format without
+// an alpha channel composites the image over white instead of exposing those
+// colours. A job can also get rid of the channel at the end of every stage,
+// either composited over a colour of its choice or dropped as is.
+// ============================================================================
+
+/// Background transparent images are composited over when their format has no alpha channel
+pub const DEFAULT_BACKGROUND: [u8; 3] = [255, 255, 255];
+
+/// What a job does with the alpha channel of its images
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlphaPolicy {
+    #[default]
+    Preserve,
+    Flatten { background: [u8; 3] }, // Composited over the RGB colour, opaque from then on
+    Drop,                            // The channel is discarded, hidden colours show
+}
+
+impl AlphaPolicy {
+    /// The colour an image is composited over once its alpha channel has to go
+    pub fn background(&self) -> [u8; 3] {
+        match self {
+            AlphaPolicy::Flatten { background } => *background,
+            _ => DEFAULT_BACKGROUND,
+        }
+    }
+}
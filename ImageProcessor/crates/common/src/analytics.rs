@@ -0,0 +1,105 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::slo::LatencyPercentiles;
+
+// ============================================================================
+// OPERATION ANALYTICS
+// Every image task a worker processes records how long it spent in each phase:
+// waiting on its topic since it was sent, downloading its input, running its
+// operations and uploading the output. The records are kept per operation and
+// release, so the api-server can report throughput and latency percentiles of
+// an operation over time and across releases, for capacity planning and to
+// spot regressions. Unlike the SLO windows (see `slo`), which only know the
+// whole latency, the phases tell a slow store from a slow operation.
+// ============================================================================
+
+/// The release recorded when the config names none, the version of the binaries
+pub const DEFAULT_RELEASE: &str = env!("CARGO_PKG_VERSION");
+
+/// How long each phase of an image task took, in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct TaskTimings {
+    pub queue_wait_ms: Option<u64>, // From the message being sent until a worker picked it up
+    pub download_ms: Option<u64>,   // None when the input was handed over locally
+    pub process_ms: Option<u64>,    // None when the output came from the result cache
+    pub upload_ms: u64,
+    pub total_ms: u64, // From the worker picking the task up until it finished
+}
+
+/// The tasks of one operation and release that finished within a window
+#[derive(Serialize, Debug, Clone)]
+pub struct OperationWindow {
+    pub operation: String, // Name of the operation, or of the chain of a fused stage
+    pub release: String,
+    pub window_start: DateTime<Utc>,
+    pub window_end: DateTime<Utc>,
+    pub tasks: u64,
+    pub throughput_per_min: f64, // Tasks finished per minute of the window
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub queue_wait: Option<LatencyPercentiles>,
+    pub download: Option<LatencyPercentiles>,
+    pub process: Option<LatencyPercentiles>,
+    pub upload: Option<LatencyPercentiles>,
+    pub total: Option<LatencyPercentiles>,
+    pub truncated: bool, // Older tasks of the window were left out, see `analytics.max_samples`
+}
+
+/// Collects the timings of the tasks falling into one `OperationWindow`
+#[derive(Debug, Default)]
+pub struct WindowSamples {
+    pub tasks: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    queue_wait: Vec<f64>,
+    download: Vec<f64>,
+    process: Vec<f64>,
+    upload: Vec<f64>,
+    total: Vec<f64>,
+}
+
+impl WindowSamples {
+    pub fn add(&mut self, timings: &TaskTimings, bytes_read: Option<u64>, bytes_written: u64) {
+        self.tasks += 1;
+        self.bytes_read += bytes_read.unwrap_or(0);
+        self.bytes_written += bytes_written;
+        let optional = [
+            (&mut self.queue_wait, timings.queue_wait_ms),
+            (&mut self.download, timings.download_ms),
+            (&mut self.process, timings.process_ms),
+        ];
+        for (samples, ms) in optional {
+            samples.extend(ms.map(|ms| ms as f64));
+        }
+        self.upload.push(timings.upload_ms as f64);
+        self.total.push(timings.total_ms as f64);
+    }
+
+    /// The window the samples were collected for, `end` is cut short for a window still running
+    pub fn into_window(
+        self,
+        operation: String,
+        release: String,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> OperationWindow {
+        let minutes = (end - start).num_seconds().max(1) as f64 / 60.0;
+        OperationWindow {
+            operation,
+            release,
+            window_start: start,
+            window_end: end,
+            tasks: self.tasks,
+            throughput_per_min: self.tasks as f64 / minutes,
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+            queue_wait: LatencyPercentiles::from_samples(self.queue_wait),
+            download: LatencyPercentiles::from_samples(self.download),
+            process: LatencyPercentiles::from_samples(self.process),
+            upload: LatencyPercentiles::from_samples(self.upload),
+            total: LatencyPercentiles::from_samples(self.total),
+            truncated: false,
+        }
+    }
+}
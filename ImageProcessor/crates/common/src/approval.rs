@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{DatasetProcessingJob, PipelineMode};
+
+// ============================================================================
+// STAGE APPROVAL
+// A job listing operations in `requires_approval` has a person review their
+// outputs before anything runs on them, e.g. blurred faces before the images
+// are resized for distribution. Such a stage finishes as usual, but the
+// scheduler doesn't release the stages after it until its outputs are
+// approved through the api-server. Rejecting them fails the stage, so the
+// stages after it are skipped and the batch fails like after any failed stage.
+//
+// Only operations other operations run after can require approval, the
+// results of a job aren't held back.
+// ============================================================================
+
+/// The decision of the person who reviewed the outputs of a stage
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StageApproval {
+    pub approved: bool,
+    pub reviewer: String, // Who decided, as given in the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(with = "crate::timestamp")]
+    pub time_decided: DateTime<Utc>,
+}
+
+impl DatasetProcessingJob {
+    /// Checks the operations a job holds for approval, returning every invalid field with its
+    /// message
+    pub fn approval_issues(&self) -> Vec<(String, String)> {
+        let mut issues = Vec::new();
+        if self.requires_approval.is_empty() {
+            return issues;
+        }
+
+        if self.pipeline_mode == PipelineMode::Fused {
+            issues.push((
+                "requires_approval".to_string(),
+                "A fused job runs its operations as one stage and can't hold any for approval"
+                    .to_string(),
+            ));
+        }
+        let inputs = self.inputs();
+        for (i, operation) in self.requires_approval.iter().enumerate() {
+            let field = format!("requires_approval[{}]", i);
+            if *operation >= self.operations.len() {
+                issues.push((field, format!("The job has no operation {}", operation)));
+            } else if !self.has_dependents(*operation, &inputs) {
+                issues.push((
+                    field,
+                    format!(
+                        "Operation {} is a result of the job, only operations others run after \
+                         can require approval",
+                        operation
+                    ),
+                ));
+            }
+        }
+        issues
+    }
+
+    /// Whether any operation runs after the given one, mirroring the stages `into_dataset_tasks`
+    /// creates
+    fn has_dependents(&self, operation: usize, inputs: &[Option<usize>]) -> bool {
+        (operation + 1..self.operations.len()).any(|i| match self.dependencies.is_empty() {
+            true => i == operation + 1,
+            // Operations reading the dataset wait for the first one, which extracts it
+            false => {
+                self.dependencies
+                    .get(i)
+                    .is_some_and(|parents| parents.contains(&operation))
+                    || (operation == 0 && inputs[i].is_none())
+            }
+        })
+    }
+}
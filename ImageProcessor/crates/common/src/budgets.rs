@@ -0,0 +1,128 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DecomposerConfig;
+
+// ============================================================================
+// DECOMPOSITION BUDGETS
+// A pathological dataset, e.g. an archive of millions of tiny entries or one
+// that passed the dataset limits but reads at a crawl, would hold a decomposer
+// for hours. Every dataset task gets a budget of wall time and bytes extracted
+// from its archive, checked before each image is extracted. A task over its
+// budget stops extracting and waits for the images in flight, then records
+// how far it got on the dataset task and fails with the budget it exceeded.
+// The images registered until then are left to the workers, the stage fails
+// either way.
+// ============================================================================
+
+/// The budget a dataset task is decomposed within, from the moment it was created
+#[derive(Debug, Clone)]
+pub struct DecompositionBudget {
+    started: Instant,
+    max_wall_time: Option<Duration>,
+    max_extracted_bytes: Option<u64>,
+    extracted_bytes: u64,
+}
+
+/// The part of its budget a dataset task used up
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BudgetExceeded {
+    WallTime { limit_secs: u64 },
+    ExtractedBytes { bytes: u64, limit: u64 },
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetExceeded::WallTime { limit_secs } => {
+                write!(f, "Decomposition took longer than its budget of {}s", limit_secs)
+            }
+            BudgetExceeded::ExtractedBytes { bytes, limit } => write!(
+                f,
+                "Decomposition extracted {} bytes, its budget is {}",
+                bytes, limit
+            ),
+        }
+    }
+}
+
+/// How far a dataset task got before it exceeded its budget, recorded on the task
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BudgetCheckpoint {
+    pub exceeded: BudgetExceeded,
+    pub images_registered: u64, // Image tasks created, the failed ones included
+    pub images_total: u64,      // Images the dataset lists
+    pub next_image: Option<String>, // The first image that wasn't extracted
+    pub extracted_bytes: u64,
+    pub elapsed_secs: u64,
+    #[serde(with = "crate::timestamp")]
+    pub time_recorded: DateTime<Utc>,
+}
+
+impl DecompositionBudget {
+    pub fn from_config(config: &DecomposerConfig) -> Self {
+        Self {
+            started: Instant::now(),
+            max_wall_time: config.max_wall_secs.map(Duration::from_secs),
+            max_extracted_bytes: config.max_extracted_bytes,
+            extracted_bytes: 0,
+        }
+    }
+
+    pub fn add_extracted(&mut self, bytes: u64) {
+        self.extracted_bytes += bytes;
+    }
+
+    /// Runs a step that can't be checked as it goes, e.g. a download, within the wall time left
+    pub async fn within<T>(&self, step: impl Future<Output = T>) -> Result<T, BudgetExceeded> {
+        let Some(limit) = self.max_wall_time else {
+            return Ok(step.await);
+        };
+        tokio::time::timeout(limit.saturating_sub(self.started.elapsed()), step)
+            .await
+            .map_err(|_| BudgetExceeded::WallTime {
+                limit_secs: limit.as_secs(),
+            })
+    }
+
+    /// Fails once either part of the budget was used up
+    pub fn check(&self) -> Result<(), BudgetExceeded> {
+        if let Some(limit) = self.max_wall_time
+            && self.started.elapsed() >= limit
+        {
+            return Err(BudgetExceeded::WallTime {
+                limit_secs: limit.as_secs(),
+            });
+        }
+        match self.max_extracted_bytes {
+            Some(limit) if self.extracted_bytes > limit => Err(BudgetExceeded::ExtractedBytes {
+                bytes: self.extracted_bytes,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The checkpoint of a task that exceeded its budget
+    pub fn checkpoint(
+        &self,
+        exceeded: BudgetExceeded,
+        images_registered: u64,
+        images_total: u64,
+        next_image: Option<String>,
+    ) -> BudgetCheckpoint {
+        BudgetCheckpoint {
+            exceeded,
+            images_registered,
+            images_total,
+            next_image,
+            extracted_bytes: self.extracted_bytes,
+            elapsed_secs: self.started.elapsed().as_secs(),
+            time_recorded: Utc::now(),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{ImageOperation, ImageTask, config::MessageEncoding};
+
+// ============================================================================
+// WORKER CAPABILITIES
+// Workers of a fleet don't all run the same tasks: an older build doesn't
+// know the operations a newer producer sends, and only some hosts have a GPU.
+// A worker handed a task it can't run doesn't fail it, it moves the task to
+// the holding topic with the capability it lacks, see
+// `queue::holding::CapabilityHolding`.
+//
+// Every worker registers what it can run with a heartbeat. The scheduler
+// records the held tasks and requeues them on their topic once a live worker
+// has the capability, the tasks no live worker can run are what the
+// `UnservableHeldTasks` alerting rule watches.
+// ============================================================================
+
+/// Reason given to the tasks moved to the holding topic
+pub const CAPABILITY_MISMATCH: &str = "capability-mismatch";
+
+/// What a task may need from the worker running it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "name")]
+pub enum Capability {
+    Operation(String), // By name, e.g. "Resize", including variants this build doesn't know
+    Gpu,
+}
+
+/// What a worker can run, registered with its heartbeat
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkerCapabilities {
+    pub operations: Vec<String>, // Names of the operations the worker runs
+    pub gpu: bool,
+}
+
+/// A task a worker couldn't run, as published to the holding topic
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeldTask {
+    pub hold_id: uuid::Uuid,
+    pub original_topic: String, // Where the task is requeued once a worker can run it
+    pub capability: Capability, // The one the worker lacked
+    pub reason: String,         // `CAPABILITY_MISMATCH`
+    pub rejected_by: String,    // Id of the worker that held it
+    pub held_at: DateTime<Utc>,
+    #[serde(default)]
+    pub encoding: MessageEncoding, // Of the message, see `queue::encoding`
+    pub message: String, // The message as it was received, requeued unchanged, hex unless JSON
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Operation(name) => write!(f, "operation {}", name),
+            Capability::Gpu => write!(f, "gpu"),
+        }
+    }
+}
+
+impl Capability {
+    /// The capability a message that failed to deserialize with `error` lacks, if the error is
+    /// a variant this build doesn't know, e.g. an operation added by a newer producer
+    pub fn from_unknown_variant(error: &str) -> Option<Self> {
+        // Serde reports them as "unknown variant `Name`, expected one of ..."
+        let (_, rest) = error.split_once("unknown variant `")?;
+        let (name, _) = rest.split_once('`')?;
+        Some(Capability::Operation(name.to_string()))
+    }
+}
+
+impl HeldTask {
+    /// A message of `original_topic` held now by `rejected_by` for lack of `capability`
+    pub fn new(
+        original_topic: &str,
+        capability: Capability,
+        rejected_by: &str,
+        encoding: MessageEncoding,
+        message: String,
+    ) -> Self {
+        Self {
+            hold_id: uuid::Uuid::new_v4(),
+            original_topic: original_topic.to_string(),
+            capability,
+            reason: CAPABILITY_MISMATCH.to_string(),
+            rejected_by: rejected_by.to_string(),
+            held_at: Utc::now(),
+            encoding,
+            message,
+        }
+    }
+}
+
+impl WorkerCapabilities {
+    /// The capabilities of a worker running `operations`, every operation of this build when
+    /// empty
+    pub fn new(operations: &[String], gpu: bool) -> Self {
+        let operations = match operations.is_empty() {
+            true => ImageOperation::NAMES.iter().map(|name| name.to_string()).collect(),
+            false => operations.to_vec(),
+        };
+        Self { operations, gpu }
+    }
+
+    pub fn satisfies(&self, capability: &Capability) -> bool {
+        match capability {
+            Capability::Operation(name) => self.operations.contains(name),
+            Capability::Gpu => self.gpu,
+        }
+    }
+
+    /// The first capability the task needs that the worker lacks. `gpu_operations` are the
+    /// operations that have to run on a GPU, see `CapabilitiesConfig`.
+    pub fn missing(&self, task: &ImageTask, gpu_operations: &[String]) -> Option<Capability> {
+        let mut required = task
+            .operations()
+            .map(|op| Capability::Operation(op.name().to_string()));
+        if let Some(capability) = required.find(|capability| !self.satisfies(capability)) {
+            return Some(capability);
+        }
+
+        let needs_gpu = task
+            .operations()
+            .any(|op| gpu_operations.iter().any(|name| name == op.name()));
+        (needs_gpu && !self.gpu).then_some(Capability::Gpu)
+    }
+}
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// CONSUMER CHECKPOINTS
+// A consumer group that never committed an offset on a partition starts where
+// its `StartPosition` says, see `config::StartPositionConfig`. With the
+// Checkpoint policy that is where the group was last recorded in the database:
+// consumers record the offsets their group committed every few seconds, so a
+// group that is recreated, or moved to a cluster that lost its offsets, picks
+// up close to where it left off instead of at the start of the topic.
+// ============================================================================
+
+/// The next offset a consumer group reads on a partition
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionOffset {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
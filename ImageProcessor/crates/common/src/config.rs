@@ -0,0 +1,1313 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    str::FromStr,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    Priority,
+    formats::InputFormat,
+    logging::{LogFormat, LogLevel},
+    resampling::ResampleFilter,
+};
+
+// ============================================================================
+// CONFIGURATION
+// Connection settings shared by every binary. Values are read from a TOML file
+// (the path in CONFIG_PATH, if set) and then overridden by environment
+// variables, so the docker-compose setup only needs to set what differs from
+// the defaults.
+// ============================================================================
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub kafka: KafkaConfig,
+    pub mongo: MongoConfig,
+    pub s3: S3Config,
+    pub storage: StorageConfig,
+    pub retry: RetryConfig,
+    pub api: ApiConfig,
+    pub simulation: SimulationConfig,
+    pub faults: FaultConfig,
+    pub analytics: AnalyticsConfig,
+    pub worker: WorkerConfig,
+    pub decomposer: DecomposerConfig,
+    pub dataset_limits: DatasetLimits,
+    pub metrics: MetricsConfig,
+    pub retention: RetentionConfig,
+    pub manifests: ManifestConfig,
+    pub mappings: MappingConfig,
+    pub outbox: OutboxConfig,
+    pub lag: LagConfig,
+    pub capabilities: CapabilitiesConfig,
+    pub downloads: DownloadsConfig,
+    pub slo: SloConfig,
+    pub profiles: ProfileConfig,
+    pub reports: ReportConfig,
+    pub previews: PreviewConfig,
+    pub sharing: SharingConfig,
+    pub quality_drift: QualityDriftConfig,
+    pub fairness: FairnessConfig,
+    pub auth: AuthConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub level: LogLevel, // Can be changed at runtime for the image workers, see `control`
+    pub format: LogFormat,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KafkaConfig {
+    pub backend: QueueKind,
+    pub brokers: String,
+    pub dataset_topic: String, // Jobs split into dataset tasks, read by the decomposer
+    pub image_topic: String,   // Image tasks, read by the image workers
+    pub control_topic: String, // Runtime commands for the image workers, see `control`
+    pub manifest_topic: String, // Partial results manifests of running batches, see `manifest`
+    pub events_topic: String,   // Progress events of batches for live UIs, see `events`
+    pub migration_topic: String, // Topic switches of running migrations, see `migration`
+    pub topic_partitions: i32, // Partition count used when the api-server creates the topics
+    pub split_partition_streams: bool, // Consumers handle every partition on its own task
+    pub image_task_encoding: MessageEncoding, // How image tasks are sent, see `queue::encoding`
+    // Image tasks whose operation is listed, by name, go to its topic instead of `image_topic`
+    pub operation_topics: HashMap<String, String>,
+    pub poison_pill: PoisonPillConfig,
+    pub priority: PriorityConfig,
+    pub failover: FailoverConfig,
+    pub start_position: StartPositionConfig,
+}
+
+impl KafkaConfig {
+    /// The brokers consumers read from, the secondary cluster while consumers are pointed at it
+    pub fn consumer_brokers(&self) -> &str {
+        let failover = &self.failover;
+        match (&failover.secondary_brokers, failover.consume_secondary) {
+            (Some(secondary), true) => secondary,
+            _ => &self.brokers,
+        }
+    }
+
+    /// Every topic image tasks are sent to, `image_topic` and those of `operation_topics`
+    pub fn image_topics(&self) -> Vec<String> {
+        let mut topics: Vec<String> = self.operation_topics.values().cloned().collect();
+        topics.push(self.image_topic.clone());
+        topics.sort();
+        topics.dedup();
+        topics
+    }
+}
+
+/// What carries the messages between the components, see `queue::memory`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueKind {
+    #[default]
+    Kafka,
+    Memory, // In-process, only reaches the components running in the same process
+}
+
+impl FromStr for QueueKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "kafka" => Ok(Self::Kafka),
+            "memory" => Ok(Self::Memory),
+            _ => Err(format!("Unknown queue backend {}", s)),
+        }
+    }
+}
+
+/// How a message payload is serialized, sent in its content-type header
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageEncoding {
+    #[default]
+    Json,
+    MessagePack, // Compact and faster to read, for the high-volume image tasks
+}
+
+impl FromStr for MessageEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "messagepack" | "msgpack" => Ok(Self::MessagePack),
+            _ => Err(format!("Unknown message encoding {}", s)),
+        }
+    }
+}
+
+/// A secondary Kafka cluster, usually a mirror of the primary, for disaster recovery
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FailoverConfig {
+    pub secondary_brokers: Option<String>, // Producers never fail over without a secondary
+    pub failure_threshold: u32, // Sends in a row that failed after all retries before failing over
+    pub consume_secondary: bool, // Consumers read from the secondary cluster instead
+}
+
+/// Routing and consumption of work by `Priority`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PriorityConfig {
+    pub dedicated_topics: bool, // Send High and Low work to "<topic>-high" and "<topic>-low"
+    // Messages of each priority a consumer handles at once, so every priority keeps a share
+    pub high_weight: usize,
+    pub normal_weight: usize,
+    pub low_weight: usize,
+}
+
+impl PriorityConfig {
+    pub fn weight(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::Low => self.low_weight,
+            Priority::Normal => self.normal_weight,
+            Priority::High => self.high_weight,
+        }
+    }
+}
+
+/// What a consumer does with a message it can't read or whose handler keeps failing
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PoisonPillPolicy {
+    /// Publish the message to the topic's dead letter topic and move on
+    #[default]
+    DeadLetter,
+    /// Stop consuming the message's partition without committing it, until the consumer restarts
+    Park,
+    /// Exit the process without committing the message, so it is the first one read on restart
+    Crash,
+    /// Log the message and move on, it is lost
+    Skip,
+}
+
+impl FromStr for PoisonPillPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "deadletter" | "dead_letter" | "dlq" => Ok(Self::DeadLetter),
+            "park" => Ok(Self::Park),
+            "crash" => Ok(Self::Crash),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!("Unknown poison pill policy {}", s)),
+        }
+    }
+}
+
+/// Where a consumer group starts reading a partition it never committed an offset on
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StartPosition {
+    /// The oldest message the topic still retains, reprocessing whatever history is left
+    #[default]
+    Earliest,
+    /// The messages produced from now on
+    Latest,
+    /// The first message produced at or after `StartPositionConfig::timestamp`
+    Timestamp,
+    /// Where the group was last recorded in the database, see `common::checkpoints`. Partitions
+    /// without a checkpoint start at the earliest message.
+    Checkpoint,
+}
+
+impl FromStr for StartPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "earliest" => Ok(Self::Earliest),
+            "latest" => Ok(Self::Latest),
+            "timestamp" => Ok(Self::Timestamp),
+            "checkpoint" => Ok(Self::Checkpoint),
+            _ => Err(format!("Unknown start position {}", s)),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct StartPositionConfig {
+    pub policy: StartPosition, // Applied to topics without an entry in `topics`
+    pub topics: HashMap<String, StartPosition>,
+    pub timestamp: Option<DateTime<Utc>>, // Where `Timestamp` starts, the earliest message when unset
+}
+
+impl StartPositionConfig {
+    pub fn policy_for(&self, topic: &str) -> StartPosition {
+        self.topics.get(topic).copied().unwrap_or(self.policy)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PoisonPillConfig {
+    pub policy: PoisonPillPolicy, // Applied to topics without an entry in `topics`
+    pub topics: HashMap<String, PoisonPillPolicy>,
+    pub max_handler_attempts: u32, // Times a handler may fail on a message before the policy applies
+    pub retry_backoff_ms: u64, // Wait before the second attempt, doubled for every further one
+    pub dead_letter_suffix: String, // Appended to a topic's name to get its dead letter topic
+}
+
+impl PoisonPillConfig {
+    pub fn policy_for(&self, topic: &str) -> PoisonPillPolicy {
+        self.topics.get(topic).copied().unwrap_or(self.policy)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MongoConfig {
+    pub uri: String,
+    pub database: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct S3Config {
+    pub bucket: String,
+}
+
+/// The api-server's download proxy, for environments where presigned URLs can't be handed out
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DownloadsConfig {
+    pub proxy_token: Option<String>, // Bearer token of the proxy, which is only served when set
+}
+
+/// API keys of the api-server. Authentication is enforced once any key is configured, requests
+/// then need an `X-API-Key` header and only see the batches of their key's tenant.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub api_keys: HashMap<String, String>, // API key -> tenant id
+    pub admin_keys: Vec<String>,           // Keys that see every tenant and reach the admin endpoints
+    pub managed_keys: bool, // Also accept the keys issued through /admin/tenants, see `tenancy`
+}
+
+impl AuthConfig {
+    pub fn enabled(&self) -> bool {
+        !self.api_keys.is_empty() || !self.admin_keys.is_empty() || self.managed_keys
+    }
+}
+
+/// How many image tasks a worker handles at once.
+///
+/// With a memory ceiling the worker adapts its concurrency between the two bounds to its resident
+/// memory, and stops pulling tasks while it is above the ceiling. Without one it always runs
+/// `max_concurrency` tasks.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WorkerConfig {
+    pub max_concurrency: usize,
+    pub min_concurrency: usize,
+    pub memory_ceiling_mb: Option<u64>,
+    pub memory_check_interval_ms: u64,
+    pub handoff_dir: Option<String>, // Spill directory for stages pinned to this worker, see `use_local_cache`
+    pub handoff_ttl_secs: u64,       // Spill files nobody picked up are removed after this long
+    pub max_input_bytes: Option<u64>, // Larger inputs are failed before being decoded
+    pub max_pixels: Option<u64>, // Images declaring more pixels fail before being decoded
+    pub max_width: Option<u32>,  // Wider images fail before being decoded
+    pub max_height: Option<u32>, // Taller images fail before being decoded
+    pub max_decode_alloc_mb: Option<u64>, // Memory a single decode may allocate, 512MB when unset
+    pub max_processing_secs: Option<u64>, // Tasks whose operations take longer fail with a Timeout
+    pub worker_id: Option<String>, // Addresses control commands to this worker, the hostname when unset
+    pub allowed_operations: Vec<String>, // Operation names the worker runs, every operation when empty
+    pub result_cache: bool, // Reuse the outputs of earlier tasks with the same input and operations
+    pub input_cache_mb: u64, // Encoded inputs kept in memory by key and ETag, 0 turns the cache off
+    pub input_cache_dir: Option<String>, // Inputs evicted from memory are kept here when set
+    pub input_cache_disk_mb: u64, // Bound of the disk tier
+    pub topics: Vec<String>, // Image topics consumed, `kafka.image_topic` when empty
+    pub gpu: bool, // Runs `capabilities.gpu_operations`
+    pub pinned_partitions: Vec<i32>, // Of every topic, consumed without rebalancing, see `use_local_cache`
+}
+
+/// Where datasets and results are stored, see the `storage` crate
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageKind,
+    pub endpoint: Option<String>, // S3-compatible endpoint (MinIO, GCS), AWS when unset
+    pub local_root: String,       // Directory holding the objects of the Local backend
+    pub local_url: String, // Where the api-server serves the presigned URLs of the Local backend
+    pub local_signing_key: String, // Signs those URLs, the same for every service
+    pub alert_webhook: Option<String>, // Operators' webhook, sent the storage alerts of every batch
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageKind {
+    #[default]
+    S3,
+    Local,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "s3" => Ok(Self::S3),
+            "local" => Ok(Self::Local),
+            _ => Err(format!("Unknown storage backend {}", s)),
+        }
+    }
+}
+
+/// Retry settings for producer sends, see `queue::retry::RetryPolicy`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+
+/// Limits the api-server applies to request bodies and to the datasets jobs are submitted for
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub max_body_bytes: usize, // Larger bodies are rejected with 413 before being buffered
+    pub max_json_depth: usize, // Deepest nesting of arrays and objects accepted in a JSON body
+    pub min_dataset_bytes: u64, // Smaller datasets (e.g. an upload that never finished) are rejected
+    pub max_dataset_bytes: Option<u64>,
+    pub max_dataset_age_days: Option<u32>, // Datasets last modified longer ago are rejected
+    pub max_inline_dataset_bytes: u64, // Largest dataset accepted by /submit_inline
+    // Serve only the status, results and stats endpoints, reading from the Mongo secondaries
+    pub read_only: bool,
+    pub rate_limit_per_sec: Option<f64>, // Requests per second per API key, or per IP without auth
+    pub rate_limit_burst: u32,           // Requests a client can make at once after idling
+}
+
+/// How long batch results are kept and when their owners are warned, see the alerting service
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub results_days: u32,             // Days the outputs of a finished batch are kept
+    pub warn_days_before: u32,         // Days before expiry the warning is sent
+    pub warning_webhook: Option<String>, // Operators' webhook, warned about every expiring batch
+    pub intermediate_hours: u64, // Hours intermediate stage outputs are kept, 0 to keep them
+}
+
+/// Partial results manifests the scheduler publishes while a batch runs, see `manifest`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ManifestConfig {
+    pub enabled: bool,
+    pub every_images: u64, // Published once this many outputs are in no manifest yet, 0 to not count
+    pub every_secs: u64, // Published this long after the previous one if outputs are new, 0 to not wait
+}
+
+/// Compaction of the image task mappings of finished stages, see `mappings`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MappingConfig {
+    pub compaction: bool,
+    pub compact_after_secs: u64, // Time a stage has been finished before its mappings are compacted
+    pub tasks_per_pass: i64, // Dataset tasks compacted per scheduler pass at most
+}
+
+/// Publishing of the image tasks the decomposer and the first dataset tasks the api-server
+/// record through an outbox in MongoDB, see `db_utils::outbox`. Needs MongoDB to run as a
+/// replica set, transactions aren't available otherwise.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub enabled: bool,
+    pub relay_after_secs: u64, // Age of an unsent entry before the scheduler's relay sends it
+    pub entries_per_pass: i64, // Entries the relay sends per scheduler pass at most
+    pub keep_sent_hours: u64,  // Sent entries are deleted after this long
+}
+
+/// Reporting of the consumer groups' lag by the scheduler, see `lag`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LagConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    pub record: bool, // Write the lag to the `consumer_lag` collection besides the metric
+    // The groups reported on and the topics they read, priority and operation topics included
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Tasks the worker they were handed to can't run, see `capabilities`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CapabilitiesConfig {
+    pub holding: bool, // Move such tasks to `holding_topic`, else the poison pill policy applies
+    pub holding_topic: String,
+    pub gpu_operations: Vec<String>, // Operations only workers with `worker.gpu` run, by name
+    pub heartbeat_secs: u64, // How often workers register their capabilities
+    pub worker_ttl_secs: u64, // Workers not heard from for this long no longer count
+}
+
+/// Latency objectives of the image operations, see `slo`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SloConfig {
+    pub window_secs: u64, // Length of the rolling windows latency percentiles are aggregated over
+    pub targets: HashMap<String, SloTarget>, // Per operation, keyed by name, e.g. "Resize"
+}
+
+/// Historical cost of the operations the scheduler plans with, see `profiles`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProfileConfig {
+    pub enabled: bool,
+    pub smoothing: f64, // Weight of the latest stage in the moving average, between 0 and 1
+    pub min_stages: u32, // Stages an operation is measured over before its profile is used
+    pub reference_ms: f64, // Per image, slower operations get lower memory thresholds
+}
+
+/// Summaries of finished batches the alerting service writes, see `report`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReportConfig {
+    pub enabled: bool,
+    pub compute_hour_cost: f64, // Cost of an hour of image processing, 0 to leave costs out
+    pub gb_written_cost: f64,   // Cost of a GB of outputs
+    pub currency: String,
+    pub max_failures: usize, // Failed images listed in a report, the rest are only counted
+    pub link_expiry_secs: u64, // Lifetime of the presigned links to the report and the thumbnails
+    pub api_url: Option<String>, // Public URL of the api-server, for links to the batch's endpoints
+    pub webhook: Option<String>, // Operators' webhook, notified about every finished batch
+}
+
+/// Limits an archive is checked against before it's decomposed, see `inspection`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DatasetLimits {
+    pub max_entries: u64, // Entries of the archive, directories included
+    pub max_uncompressed_bytes: u64, // All files together, decompressed
+    pub max_file_bytes: u64, // A single file, decompressed
+}
+
+/// Thumbnails of the final outputs of jobs that ask for them, see `previews`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PreviewConfig {
+    pub size: u32,   // Width and height of the square thumbnails, in pixels
+    pub quality: u8, // JPEG quality of the thumbnails, 1-100
+    pub link_expiry_secs: u64, // Lifetime of the presigned links the api-server hands out
+    pub max_links: usize, // Thumbnails listed per request at most, a sample of larger batches
+    pub filter: Option<ResampleFilter>, // Thumbnails are area averaged without one
+}
+
+/// Links sharing the results of a batch without an API key, see `sharing`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SharingConfig {
+    pub default_expiry_secs: u64, // Lifetime of a shared link created without one
+    pub max_expiry_secs: u64,     // Longest lifetime a shared link can be created with
+    pub link_expiry_secs: u64, // Lifetime of the presigned links opening a shared link hands out
+    pub max_links: usize,      // Objects listed per opening at most
+}
+
+/// Sampled quality metrics of the stages' outputs and their comparison with earlier stages of
+/// the same pipeline, see `drift`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QualityDriftConfig {
+    pub sample_rate: u32, // One in this many outputs of a stage is measured, 0 to measure none
+    pub min_samples: u64, // Measured outputs a stage needs to be checked or part of a baseline
+    pub baseline_stages: u32, // Latest stages of the same pipeline a stage is compared with
+    pub min_baseline_stages: u32, // Pipelines with fewer stages measured aren't checked yet
+    pub z_threshold: f64, // Deviations from the baseline a drifting metric is at least away
+    pub min_relative_change: f64, // and the share of the baseline's mean, so tiny shifts pass
+    pub alert_webhook: Option<String>, // Operators' webhook, notified about every drift
+}
+
+/// Bounds on the image tasks of a batch running at once, so one large batch can't take every
+/// worker, see `db_utils::fairness`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FairnessConfig {
+    pub max_in_flight_per_batch: Option<u64>, // Running image tasks per batch, unbounded if unset
+    pub defer_secs: u64, // How long a task of a batch over the bound waits before it is requeued
+    pub running_ttl_secs: u64, // Tasks running longer don't count, their worker likely died
+    pub requeue_per_pass: i64, // Deferred tasks the scheduler requeues per pass at most
+}
+
+/// Latency targets of an operation in milliseconds, percentiles without a target aren't checked
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub struct SloTarget {
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+/// Where the Kafka consumers serve their Prometheus metrics, the api-server uses its own port
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub address: String,
+}
+
+/// Settings of the consumer that splits datasets into image tasks
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DecomposerConfig {
+    pub max_buffered_images: usize, // Extracted images held in memory at once, waiting to be uploaded
+    pub max_concurrent_uploads: usize, // Extracted images uploaded to storage at once
+    pub lease_secs: u64, // A dataset task whose lease wasn't renewed for this long is republished
+    pub heartbeat_secs: u64, // How often the decomposer renews the lease of the task it works on
+    pub max_recoveries: u32, // Republishes of a dataset task before it is failed instead
+    pub sidecar_names: Vec<String>, // Files of a dataset kept with its results, see `sidecars`
+    pub input_formats: Vec<InputFormat>, // Images of a dataset that are decomposed, by content
+    pub max_wall_secs: Option<u64>, // Dataset tasks decomposing longer are failed, see `budgets`
+    pub max_extracted_bytes: Option<u64>, // Dataset tasks extracting more are failed, see `budgets`
+}
+
+/// Settings of the image worker's simulation mode, used to load test the pipeline without
+/// decoding images or paying for S3 reads
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SimulationConfig {
+    pub enabled: bool,
+    pub write_placeholders: bool, // Write an empty object to each output key so downstream reads succeed
+    pub failure_rate: f64,        // Share of tasks that are failed on purpose, between 0 and 1
+    pub default_latency: LatencyProfile,
+    pub latency: HashMap<String, LatencyProfile>, // Per operation, keyed by name, e.g. "Resize"
+}
+
+/// Faults injected on purpose to exercise the retry, dead letter and recovery paths, see
+/// `faults`. Only armed in builds with the `fault-injection` feature.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FaultConfig {
+    pub s3_put_failure_rate: f64, // Share of object writes failed, between 0 and 1
+    pub kafka_send_delay_rate: f64, // Share of Kafka sends delayed
+    pub kafka_send_delay_ms: u64,
+    pub db_write_failure_rate: f64, // Share of image task status changes failed
+    pub handler_drop_rate: f64, // Share of image tasks abandoned once running, like a crash
+}
+
+/// Timings of the image tasks kept for `GET /analytics/operations`, see `analytics`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AnalyticsConfig {
+    pub enabled: bool,           // Workers record the timings of the tasks they process
+    pub release: Option<String>, // Recorded with the timings, the version of the binaries if unset
+    pub default_hours: u32,      // How far back a report goes unless asked otherwise
+    pub max_hours: u32,          // How far back a report may be asked to go
+    pub bucket_hours: u32,       // Length of the windows a report is split into
+    pub max_samples: u64,        // Timings read per report at most, the most recent ones
+}
+
+/// A log-normal latency distribution, described by its median and 99th percentile
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy)]
+pub struct LatencyProfile {
+    pub median_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            backend: QueueKind::Kafka,
+            brokers: "localhost:9092".to_string(),
+            dataset_topic: "dataset-tasks".to_string(),
+            image_topic: "image-tasks".to_string(),
+            control_topic: "control".to_string(),
+            manifest_topic: "results-manifests".to_string(),
+            events_topic: "batch-events".to_string(),
+            migration_topic: "topic-migrations".to_string(),
+            topic_partitions: 3,
+            split_partition_streams: false,
+            image_task_encoding: MessageEncoding::Json,
+            operation_topics: HashMap::new(),
+            poison_pill: PoisonPillConfig::default(),
+            priority: PriorityConfig::default(),
+            failover: FailoverConfig::default(),
+            start_position: StartPositionConfig::default(),
+        }
+    }
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            secondary_brokers: None,
+            failure_threshold: 5,
+            consume_secondary: false,
+        }
+    }
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            dedicated_topics: false,
+            high_weight: 4,
+            normal_weight: 2,
+            low_weight: 1,
+        }
+    }
+}
+
+impl Default for PoisonPillConfig {
+    fn default() -> Self {
+        Self {
+            policy: PoisonPillPolicy::DeadLetter,
+            topics: HashMap::new(),
+            max_handler_attempts: 3,
+            retry_backoff_ms: 500,
+            dead_letter_suffix: "-dlq".to_string(),
+        }
+    }
+}
+
+impl Default for MongoConfig {
+    fn default() -> Self {
+        Self {
+            uri: "mongodb://mongodb:27017".to_string(),
+            database: "img-processing-server".to_string(),
+        }
+    }
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            bucket: "rust-backend-proj-bucket".to_string(),
+        }
+    }
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 1,
+            min_concurrency: 1,
+            memory_ceiling_mb: None,
+            memory_check_interval_ms: 1000,
+            handoff_dir: None,
+            handoff_ttl_secs: 600,
+            max_input_bytes: None,
+            max_pixels: None,
+            max_width: None,
+            max_height: None,
+            max_decode_alloc_mb: None,
+            max_processing_secs: None,
+            worker_id: None,
+            allowed_operations: Vec::new(),
+            result_cache: false,
+            input_cache_mb: 0,
+            input_cache_dir: None,
+            input_cache_disk_mb: 1024,
+            topics: Vec::new(),
+            gpu: false,
+            pinned_partitions: Vec::new(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageKind::S3,
+            endpoint: None,
+            local_root: "./data".to_string(),
+            local_url: "http://localhost:3030".to_string(),
+            local_signing_key: "local-development-only".to_string(),
+            alert_webhook: None,
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 10_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 1024 * 1024,
+            max_json_depth: 32,
+            min_dataset_bytes: 1,
+            max_dataset_bytes: None,
+            max_dataset_age_days: None,
+            max_inline_dataset_bytes: 32 * 1024 * 1024,
+            read_only: false,
+            rate_limit_per_sec: None,
+            rate_limit_burst: 20,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            results_days: 30,
+            warn_days_before: 3,
+            warning_webhook: None,
+            intermediate_hours: 0,
+        }
+    }
+}
+
+impl Default for ManifestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            every_images: 500,
+            every_secs: 300,
+        }
+    }
+}
+
+impl Default for MappingConfig {
+    fn default() -> Self {
+        Self {
+            compaction: true,
+            compact_after_secs: 600,
+            tasks_per_pass: 5,
+        }
+    }
+}
+
+impl Default for LagConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 15,
+            record: true,
+            groups: BTreeMap::from([
+                ("decompose-tasks".to_string(), vec!["dataset-tasks".to_string()]),
+                ("image-workers".to_string(), vec!["image-tasks".to_string()]),
+            ]),
+        }
+    }
+}
+
+impl Default for CapabilitiesConfig {
+    fn default() -> Self {
+        Self {
+            holding: true,
+            holding_topic: "held-image-tasks".to_string(),
+            gpu_operations: Vec::new(),
+            heartbeat_secs: 30,
+            worker_ttl_secs: 120,
+        }
+    }
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            relay_after_secs: 30,
+            entries_per_pass: 500,
+            keep_sent_hours: 24,
+        }
+    }
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 300,
+            targets: HashMap::new(),
+        }
+    }
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            smoothing: 0.3,
+            min_stages: 3,
+            reference_ms: 250.0,
+        }
+    }
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            compute_hour_cost: 0.0,
+            gb_written_cost: 0.0,
+            currency: "USD".to_string(),
+            max_failures: 50,
+            link_expiry_secs: 7 * 24 * 3600, // The longest S3 accepts
+            api_url: None,
+            webhook: None,
+        }
+    }
+}
+
+impl Default for DatasetLimits {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            max_uncompressed_bytes: 50 * 1024 * 1024 * 1024,
+            max_file_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self {
+            size: 256,
+            quality: 80,
+            link_expiry_secs: 3600,
+            max_links: 100,
+            filter: None,
+        }
+    }
+}
+
+impl Default for SharingConfig {
+    fn default() -> Self {
+        Self {
+            default_expiry_secs: 7 * 24 * 3600,
+            max_expiry_secs: 30 * 24 * 3600,
+            link_expiry_secs: 3600,
+            max_links: 1000,
+        }
+    }
+}
+
+impl Default for QualityDriftConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 50,
+            min_samples: 20,
+            baseline_stages: 20,
+            min_baseline_stages: 5,
+            z_threshold: 4.0,
+            min_relative_change: 0.05,
+            alert_webhook: None,
+        }
+    }
+}
+
+impl Default for FairnessConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight_per_batch: None,
+            defer_secs: 10,
+            running_ttl_secs: 900,
+            requeue_per_pass: 500,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0:9100".to_string(),
+        }
+    }
+}
+
+impl Default for DecomposerConfig {
+    fn default() -> Self {
+        Self {
+            max_buffered_images: 16,
+            max_concurrent_uploads: 8,
+            lease_secs: 120,
+            heartbeat_secs: 30,
+            max_recoveries: 3,
+            sidecar_names: vec!["labels.csv".to_string(), "metadata.parquet".to_string()],
+            input_formats: InputFormat::DEFAULT.to_vec(),
+            max_wall_secs: None,
+            max_extracted_bytes: None,
+        }
+    }
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            release: None,
+            default_hours: 7 * 24,
+            max_hours: 90 * 24,
+            bucket_hours: 24,
+            max_samples: 200_000,
+        }
+    }
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        Self {
+            s3_put_failure_rate: 0.0,
+            kafka_send_delay_rate: 0.0,
+            kafka_send_delay_ms: 2000,
+            db_write_failure_rate: 0.0,
+            handler_drop_rate: 0.0,
+        }
+    }
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            write_placeholders: true,
+            failure_rate: 0.0,
+            default_latency: LatencyProfile {
+                median_ms: 50.0,
+                p99_ms: 400.0,
+            },
+            latency: HashMap::new(),
+        }
+    }
+}
+
+impl SimulationConfig {
+    /// The latency profile of an operation, falling back to the default one
+    pub fn latency_for(&self, operation: &str) -> LatencyProfile {
+        self.latency
+            .get(operation)
+            .copied()
+            .unwrap_or(self.default_latency)
+    }
+}
+
+impl Config {
+    /// Loads the config from the TOML file in `CONFIG_PATH` (if set), then applies the
+    /// environment variable overrides
+    pub fn load() -> Result<Self, String> {
+        let mut config = match env::var("CONFIG_PATH") {
+            Ok(path) => Self::from_toml_file(&path)?,
+            Err(_) => Self::default(),
+        };
+        config.apply_env()?;
+
+        Ok(config)
+    }
+
+    pub fn from_toml_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+        Self::from_toml(&contents)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| format!("Failed to parse config: {}", e))
+    }
+
+    /// Overrides settings with the environment variables that are set
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        override_from_env(&mut self.logging.level, "LOG_LEVEL")?;
+        override_from_env(&mut self.logging.format, "LOG_FORMAT")?;
+        override_from_env(&mut self.kafka.backend, "QUEUE_BACKEND")?;
+        override_from_env(&mut self.kafka.brokers, "KAFKA_BROKER")?;
+        override_from_env(&mut self.kafka.dataset_topic, "KAFKA_DATASET_TOPIC")?;
+        override_from_env(&mut self.kafka.image_topic, "KAFKA_IMAGE_TOPIC")?;
+        override_from_env(&mut self.kafka.control_topic, "KAFKA_CONTROL_TOPIC")?;
+        override_from_env(&mut self.kafka.manifest_topic, "KAFKA_MANIFEST_TOPIC")?;
+        override_from_env(&mut self.kafka.events_topic, "KAFKA_EVENTS_TOPIC")?;
+        override_from_env(&mut self.kafka.migration_topic, "KAFKA_MIGRATION_TOPIC")?;
+        override_from_env(&mut self.kafka.topic_partitions, "KAFKA_TOPIC_PARTITIONS")?;
+        override_from_env(
+            &mut self.kafka.split_partition_streams,
+            "KAFKA_SPLIT_PARTITION_STREAMS",
+        )?;
+        override_from_env(
+            &mut self.kafka.image_task_encoding,
+            "KAFKA_IMAGE_TASK_ENCODING",
+        )?;
+        override_from_env(&mut self.mongo.uri, "MONGODB_URI")?;
+        override_from_env(&mut self.mongo.database, "MONGODB_DATABASE")?;
+        override_from_env(&mut self.outbox.enabled, "OUTBOX_ENABLED")?;
+        override_from_env(&mut self.lag.enabled, "LAG_REPORTER_ENABLED")?;
+        override_from_env(&mut self.lag.interval_secs, "LAG_REPORTER_INTERVAL_SECS")?;
+        override_from_env(&mut self.kafka.poison_pill.policy, "KAFKA_POISON_PILL_POLICY")?;
+        override_from_env(
+            &mut self.kafka.poison_pill.max_handler_attempts,
+            "KAFKA_MAX_HANDLER_ATTEMPTS",
+        )?;
+        override_from_env(&mut self.kafka.priority.dedicated_topics, "KAFKA_PRIORITY_TOPICS")?;
+        override_from_env(&mut self.kafka.start_position.policy, "KAFKA_START_POSITION")?;
+        if let Ok(timestamp) = env::var("KAFKA_START_TIMESTAMP") {
+            self.kafka.start_position.timestamp = Some(timestamp.parse().map_err(|_| {
+                format!("Invalid value for KAFKA_START_TIMESTAMP: {}", timestamp)
+            })?);
+        }
+        if let Ok(brokers) = env::var("KAFKA_SECONDARY_BROKERS") {
+            self.kafka.failover.secondary_brokers = Some(brokers);
+        }
+        override_from_env(
+            &mut self.kafka.failover.failure_threshold,
+            "KAFKA_FAILOVER_THRESHOLD",
+        )?;
+        override_from_env(
+            &mut self.kafka.failover.consume_secondary,
+            "KAFKA_CONSUME_SECONDARY",
+        )?;
+        override_from_env(&mut self.s3.bucket, "S3_BUCKET")?;
+        override_from_env(&mut self.storage.backend, "STORAGE_BACKEND")?;
+        override_from_env(&mut self.storage.local_root, "STORAGE_LOCAL_ROOT")?;
+        override_from_env(&mut self.storage.local_url, "STORAGE_LOCAL_URL")?;
+        override_from_env(&mut self.storage.local_signing_key, "STORAGE_LOCAL_SIGNING_KEY")?;
+        if let Ok(endpoint) = env::var("S3_ENDPOINT") {
+            self.storage.endpoint = Some(endpoint);
+        }
+        if let Ok(url) = env::var("STORAGE_ALERT_WEBHOOK") {
+            self.storage.alert_webhook = Some(url);
+        }
+        override_from_env(&mut self.worker.max_concurrency, "WORKER_MAX_CONCURRENCY")?;
+        override_from_env(&mut self.worker.min_concurrency, "WORKER_MIN_CONCURRENCY")?;
+        if let Ok(ceiling) = env::var("WORKER_MEMORY_CEILING_MB") {
+            self.worker.memory_ceiling_mb = Some(ceiling.parse().map_err(|_| {
+                format!("Invalid value for WORKER_MEMORY_CEILING_MB: {}", ceiling)
+            })?);
+        }
+        if let Ok(dir) = env::var("WORKER_HANDOFF_DIR") {
+            self.worker.handoff_dir = Some(dir);
+        }
+        if let Ok(limit) = env::var("WORKER_MAX_INPUT_BYTES") {
+            self.worker.max_input_bytes = Some(limit.parse().map_err(|_| {
+                format!("Invalid value for WORKER_MAX_INPUT_BYTES: {}", limit)
+            })?);
+        }
+        if let Ok(pixels) = env::var("WORKER_MAX_PIXELS") {
+            self.worker.max_pixels = Some(pixels.parse().map_err(|_| {
+                format!("Invalid value for WORKER_MAX_PIXELS: {}", pixels)
+            })?);
+        }
+        if let Ok(width) = env::var("WORKER_MAX_WIDTH") {
+            self.worker.max_width = Some(width.parse().map_err(|_| {
+                format!("Invalid value for WORKER_MAX_WIDTH: {}", width)
+            })?);
+        }
+        if let Ok(height) = env::var("WORKER_MAX_HEIGHT") {
+            self.worker.max_height = Some(height.parse().map_err(|_| {
+                format!("Invalid value for WORKER_MAX_HEIGHT: {}", height)
+            })?);
+        }
+        if let Ok(mb) = env::var("WORKER_MAX_DECODE_ALLOC_MB") {
+            self.worker.max_decode_alloc_mb = Some(mb.parse().map_err(|_| {
+                format!("Invalid value for WORKER_MAX_DECODE_ALLOC_MB: {}", mb)
+            })?);
+        }
+        if let Ok(secs) = env::var("WORKER_MAX_PROCESSING_SECS") {
+            self.worker.max_processing_secs = Some(secs.parse().map_err(|_| {
+                format!("Invalid value for WORKER_MAX_PROCESSING_SECS: {}", secs)
+            })?);
+        }
+        override_from_env(&mut self.worker.input_cache_mb, "WORKER_INPUT_CACHE_MB")?;
+        if let Ok(dir) = env::var("WORKER_INPUT_CACHE_DIR") {
+            self.worker.input_cache_dir = Some(dir);
+        }
+        override_from_env(&mut self.worker.input_cache_disk_mb, "WORKER_INPUT_CACHE_DISK_MB")?;
+        // Comma separated, replaces the topics of the config file
+        if let Ok(topics) = env::var("WORKER_TOPICS") {
+            self.worker.topics = topics
+                .split(',')
+                .map(str::trim)
+                .filter(|topic| !topic.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        // Comma separated, replaces the partitions of the config file
+        if let Ok(partitions) = env::var("WORKER_PINNED_PARTITIONS") {
+            self.worker.pinned_partitions = partitions
+                .split(',')
+                .map(str::trim)
+                .filter(|partition| !partition.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(|_| {
+                    format!("Invalid value for WORKER_PINNED_PARTITIONS: {}", partitions)
+                })?;
+        }
+        if let Ok(worker_id) = env::var("WORKER_ID") {
+            self.worker.worker_id = Some(worker_id);
+        }
+        if let Ok(token) = env::var("DOWNLOAD_PROXY_TOKEN") {
+            self.downloads.proxy_token = Some(token);
+        }
+        // Comma separated "key=tenant" pairs, added to the keys of the config file
+        if let Ok(keys) = env::var("API_KEYS") {
+            for pair in keys.split(',').filter(|pair| !pair.trim().is_empty()) {
+                let (key, tenant) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid value for API_KEYS: {}", pair))?;
+                self.auth
+                    .api_keys
+                    .insert(key.trim().to_string(), tenant.trim().to_string());
+            }
+        }
+        override_from_env(&mut self.auth.managed_keys, "API_MANAGED_KEYS")?;
+        if let Ok(keys) = env::var("API_ADMIN_KEYS") {
+            self.auth.admin_keys.extend(
+                keys.split(',')
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(String::from),
+            );
+        }
+        override_from_env(&mut self.retry.max_attempts, "PRODUCER_MAX_ATTEMPTS")?;
+        override_from_env(
+            &mut self.retry.initial_backoff_ms,
+            "PRODUCER_INITIAL_BACKOFF_MS",
+        )?;
+        override_from_env(&mut self.retry.max_backoff_ms, "PRODUCER_MAX_BACKOFF_MS")?;
+        override_from_env(&mut self.api.max_body_bytes, "API_MAX_BODY_BYTES")?;
+        override_from_env(&mut self.api.max_json_depth, "API_MAX_JSON_DEPTH")?;
+        override_from_env(&mut self.api.read_only, "API_READ_ONLY")?;
+        override_from_env(
+            &mut self.api.max_inline_dataset_bytes,
+            "API_MAX_INLINE_DATASET_BYTES",
+        )?;
+        override_from_env(&mut self.api.min_dataset_bytes, "API_MIN_DATASET_BYTES")?;
+        if let Ok(limit) = env::var("API_MAX_DATASET_BYTES") {
+            self.api.max_dataset_bytes = Some(limit.parse().map_err(|_| {
+                format!("Invalid value for API_MAX_DATASET_BYTES: {}", limit)
+            })?);
+        }
+        if let Ok(rate) = env::var("API_RATE_LIMIT_PER_SEC") {
+            self.api.rate_limit_per_sec = Some(rate.parse().map_err(|_| {
+                format!("Invalid value for API_RATE_LIMIT_PER_SEC: {}", rate)
+            })?);
+        }
+        override_from_env(&mut self.api.rate_limit_burst, "API_RATE_LIMIT_BURST")?;
+        override_from_env(&mut self.worker.result_cache, "WORKER_RESULT_CACHE")?;
+        override_from_env(&mut self.worker.gpu, "WORKER_GPU")?;
+        override_from_env(&mut self.capabilities.holding, "CAPABILITY_HOLDING")?;
+        override_from_env(&mut self.capabilities.holding_topic, "KAFKA_HOLDING_TOPIC")?;
+        override_from_env(&mut self.simulation.enabled, "WORKER_SIMULATE")?;
+        override_from_env(
+            &mut self.simulation.write_placeholders,
+            "SIMULATION_WRITE_PLACEHOLDERS",
+        )?;
+        override_from_env(&mut self.simulation.failure_rate, "SIMULATION_FAILURE_RATE")?;
+        override_from_env(&mut self.faults.s3_put_failure_rate, "FAULTS_S3_PUT_FAILURE_RATE")?;
+        override_from_env(&mut self.faults.kafka_send_delay_rate, "FAULTS_KAFKA_SEND_DELAY_RATE")?;
+        override_from_env(&mut self.faults.kafka_send_delay_ms, "FAULTS_KAFKA_SEND_DELAY_MS")?;
+        override_from_env(&mut self.faults.db_write_failure_rate, "FAULTS_DB_WRITE_FAILURE_RATE")?;
+        override_from_env(&mut self.faults.handler_drop_rate, "FAULTS_HANDLER_DROP_RATE")?;
+        override_from_env(&mut self.analytics.enabled, "ANALYTICS_ENABLED")?;
+        if let Ok(release) = env::var("ANALYTICS_RELEASE") {
+            self.analytics.release = Some(release);
+        }
+        override_from_env(&mut self.metrics.address, "METRICS_ADDR")?;
+        override_from_env(&mut self.retention.results_days, "RESULTS_RETENTION_DAYS")?;
+        override_from_env(&mut self.retention.warn_days_before, "RETENTION_WARN_DAYS")?;
+        if let Ok(url) = env::var("RETENTION_WARNING_WEBHOOK") {
+            self.retention.warning_webhook = Some(url);
+        }
+        override_from_env(&mut self.manifests.enabled, "MANIFESTS_ENABLED")?;
+        override_from_env(&mut self.manifests.every_images, "MANIFESTS_EVERY_IMAGES")?;
+        override_from_env(&mut self.manifests.every_secs, "MANIFESTS_EVERY_SECS")?;
+        override_from_env(&mut self.slo.window_secs, "SLO_WINDOW_SECS")?;
+        override_from_env(&mut self.profiles.enabled, "PROFILES_ENABLED")?;
+        override_from_env(&mut self.profiles.smoothing, "PROFILES_SMOOTHING")?;
+        override_from_env(&mut self.profiles.min_stages, "PROFILES_MIN_STAGES")?;
+        override_from_env(&mut self.profiles.reference_ms, "PROFILES_REFERENCE_MS")?;
+        override_from_env(&mut self.reports.enabled, "REPORTS_ENABLED")?;
+        override_from_env(&mut self.reports.compute_hour_cost, "REPORTS_COMPUTE_HOUR_COST")?;
+        override_from_env(&mut self.reports.gb_written_cost, "REPORTS_GB_WRITTEN_COST")?;
+        if let Ok(url) = env::var("REPORTS_API_URL") {
+            self.reports.api_url = Some(url);
+        }
+        if let Ok(url) = env::var("REPORTS_WEBHOOK") {
+            self.reports.webhook = Some(url);
+        }
+        override_from_env(&mut self.previews.size, "PREVIEWS_SIZE")?;
+        override_from_env(&mut self.previews.quality, "PREVIEWS_QUALITY")?;
+        override_from_env(&mut self.previews.link_expiry_secs, "PREVIEWS_LINK_EXPIRY_SECS")?;
+        override_from_env(&mut self.previews.max_links, "PREVIEWS_MAX_LINKS")?;
+        override_from_env(&mut self.sharing.max_expiry_secs, "SHARING_MAX_EXPIRY_SECS")?;
+        override_from_env(&mut self.sharing.max_links, "SHARING_MAX_LINKS")?;
+        override_from_env(&mut self.quality_drift.sample_rate, "QUALITY_DRIFT_SAMPLE_RATE")?;
+        override_from_env(&mut self.quality_drift.z_threshold, "QUALITY_DRIFT_Z_THRESHOLD")?;
+        if let Ok(url) = env::var("QUALITY_DRIFT_WEBHOOK") {
+            self.quality_drift.alert_webhook = Some(url);
+        }
+        if let Ok(max) = env::var("FAIRNESS_MAX_IN_FLIGHT_PER_BATCH") {
+            self.fairness.max_in_flight_per_batch = Some(max.parse().map_err(|_| {
+                format!("Invalid value for FAIRNESS_MAX_IN_FLIGHT_PER_BATCH: {}", max)
+            })?);
+        }
+        override_from_env(&mut self.fairness.defer_secs, "FAIRNESS_DEFER_SECS")?;
+        override_from_env(
+            &mut self.decomposer.max_buffered_images,
+            "DECOMPOSER_MAX_BUFFERED_IMAGES",
+        )?;
+        override_from_env(
+            &mut self.decomposer.max_concurrent_uploads,
+            "DECOMPOSER_MAX_CONCURRENT_UPLOADS",
+        )?;
+        override_from_env(&mut self.decomposer.lease_secs, "DECOMPOSER_LEASE_SECS")?;
+        override_from_env(
+            &mut self.decomposer.heartbeat_secs,
+            "DECOMPOSER_HEARTBEAT_SECS",
+        )?;
+        override_from_env(
+            &mut self.decomposer.max_recoveries,
+            "DECOMPOSER_MAX_RECOVERIES",
+        )?;
+        if let Ok(secs) = env::var("DECOMPOSER_MAX_WALL_SECS") {
+            self.decomposer.max_wall_secs = Some(secs.parse().map_err(|_| {
+                format!("Invalid value for DECOMPOSER_MAX_WALL_SECS: {}", secs)
+            })?);
+        }
+        if let Ok(bytes) = env::var("DECOMPOSER_MAX_EXTRACTED_BYTES") {
+            self.decomposer.max_extracted_bytes = Some(bytes.parse().map_err(|_| {
+                format!("Invalid value for DECOMPOSER_MAX_EXTRACTED_BYTES: {}", bytes)
+            })?);
+        }
+        override_from_env(&mut self.dataset_limits.max_entries, "DATASET_MAX_ENTRIES")?;
+        override_from_env(
+            &mut self.dataset_limits.max_uncompressed_bytes,
+            "DATASET_MAX_UNCOMPRESSED_BYTES",
+        )?;
+        override_from_env(&mut self.dataset_limits.max_file_bytes, "DATASET_MAX_FILE_BYTES")?;
+        // Comma separated, replaces the names of the config file
+        if let Ok(names) = env::var("DECOMPOSER_SIDECAR_NAMES") {
+            self.decomposer.sidecar_names = names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        // Comma separated names or extensions, e.g. "jpeg,png,tif"
+        if let Ok(formats) = env::var("DECOMPOSER_INPUT_FORMATS") {
+            self.decomposer.input_formats = formats
+                .split(',')
+                .map(str::trim)
+                .filter(|format| !format.is_empty())
+                .map(InputFormat::from_str)
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("Invalid value for DECOMPOSER_INPUT_FORMATS: {}", e))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn override_from_env<T: FromStr>(target: &mut T, var: &str) -> Result<(), String> {
+    if let Ok(value) = env::var(var) {
+        *target = value
+            .parse()
+            .map_err(|_| format!("Invalid value for {}: {}", var, value))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{logging::LogLevel, profiles::MemoryThresholds};
+
+// ============================================================================
+// CONTROL PLANE
+// Runtime commands for the image workers, published by the api-server (and by
+// the scheduler for the memory thresholds, see `profiles`) on the control
+// topic with the command's name as message type. Every worker reads
+// the whole topic in a consumer group of its own, starting from the commands
+// sent after it started, so the fleet is retuned without restarts.
+// ============================================================================
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    SetConcurrency { limit: usize }, // Image tasks handled at once, within the memory governor's bounds
+    SetLogLevel { level: LogLevel },
+    Drain,           // Finish the tasks being handled, then exit
+    ReloadAllowlist, // Read `worker.allowed_operations` from the config again
+    SetMemoryThresholds { thresholds: MemoryThresholds }, // Sent by the scheduler, see `profiles`
+}
+
+impl ControlCommand {
+    /// Every message type of the control topic
+    pub const MESSAGE_TYPES: [&str; 5] = [
+        "set_concurrency",
+        "set_log_level",
+        "drain",
+        "reload_allowlist",
+        "set_memory_thresholds",
+    ];
+
+    /// The message type the command is published under
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            ControlCommand::SetConcurrency { .. } => Self::MESSAGE_TYPES[0],
+            ControlCommand::SetLogLevel { .. } => Self::MESSAGE_TYPES[1],
+            ControlCommand::Drain => Self::MESSAGE_TYPES[2],
+            ControlCommand::ReloadAllowlist => Self::MESSAGE_TYPES[3],
+            ControlCommand::SetMemoryThresholds { .. } => Self::MESSAGE_TYPES[4],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ControlMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<String>, // Only this worker applies the command, every worker when unset
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+impl ControlMessage {
+    pub fn applies_to(&self, worker_id: &str) -> bool {
+        self.worker_id
+            .as_deref()
+            .is_none_or(|target| target == worker_id)
+    }
+}
@@ -0,0 +1,35 @@
+use std::future::Future;
+
+// ============================================================================
+// CORRELATION
+// The api-server gives every request a correlation id. Messages sent while
+// handling it carry the id in their envelope, and consumers handle them with
+// the id restored, so the messages they send in turn carry it too. Work the
+// scheduler publishes later gets the id recorded with its dataset task.
+// ============================================================================
+
+tokio::task_local! {
+    static CORRELATION_ID: uuid::Uuid;
+}
+
+/// The correlation id of the request or message being handled
+pub fn current() -> Option<uuid::Uuid> {
+    CORRELATION_ID.try_with(|id| *id).ok()
+}
+
+/// Runs `future` on behalf of the given correlation id
+pub async fn scope<F: Future>(correlation_id: uuid::Uuid, future: F) -> F::Output {
+    CORRELATION_ID.scope(correlation_id, future).await
+}
+
+/// Keeps the current correlation id for `future`, which is about to be spawned onto a task of
+/// its own
+pub fn inherit<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let correlation_id = current();
+    async move {
+        match correlation_id {
+            Some(correlation_id) => scope(correlation_id, future).await,
+            None => future.await,
+        }
+    }
+}
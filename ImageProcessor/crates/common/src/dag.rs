@@ -0,0 +1,117 @@
+use serde::{Deserialize, Deserializer};
+use uuid::Uuid;
+
+use crate::{DatasetProcessingJob, PipelineMode};
+
+// ============================================================================
+// OPERATION GRAPHS
+// The operations of a job run as a chain by default, every stage reading the
+// outputs of the one before it. A job listing `dependencies` runs them as a
+// DAG instead, e.g. GrayScale and InvertColors both applied to the dataset,
+// each writing a result set of its own.
+//
+// `dependencies[i]` lists the operations operation `i` runs after, by index.
+// Its images are the outputs of the first one listed, or the dataset itself
+// when none is, the others only have to succeed first. Operations reading the
+// dataset after the first one also wait for it, it extracts the dataset they
+// all read. An operation can only depend on operations listed before it, so
+// the stage numbers stay a topological order and every stage keeps the output
+// folder of its number. The operations no other one reads from are the
+// results of the job.
+// ============================================================================
+
+/// Where a stage of a DAG job reads its images from
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageInput {
+    Dataset,                             // The uploaded dataset, like the first stage
+    Stage { stage: u32, task_id: Uuid }, // The outputs of an earlier stage
+}
+
+/// The folder a stage reads its images from, 0 holds the dataset and `n` the outputs of stage
+/// `n - 1`. Stages without an input are part of a chain.
+pub fn input_folder(input: Option<StageInput>, stage: u32) -> u32 {
+    match input {
+        None => stage,
+        Some(StageInput::Dataset) => 0,
+        Some(StageInput::Stage { stage, .. }) => stage + 1,
+    }
+}
+
+/// The dataset task whose image tasks a stage's image tasks read the outputs of
+pub fn input_task_id(input: Option<StageInput>, depends_on: &[Uuid]) -> Option<Uuid> {
+    match input {
+        None => depends_on.first().copied(),
+        Some(StageInput::Dataset) => None,
+        Some(StageInput::Stage { task_id, .. }) => Some(task_id),
+    }
+}
+
+/// Reads `depends_on` written as a single id or `null` before stages could have several parents
+pub fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<Uuid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Uuid),
+        Many(Vec<Uuid>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(OneOrMany::One(id)) => vec![id],
+        Some(OneOrMany::Many(ids)) => ids,
+    })
+}
+
+impl DatasetProcessingJob {
+    /// The operation each operation reads the outputs of, `None` for the dataset itself
+    pub fn inputs(&self) -> Vec<Option<usize>> {
+        (0..self.operations.len())
+            .map(|i| match self.dependencies.is_empty() {
+                true => i.checked_sub(1),
+                false => self
+                    .dependencies
+                    .get(i)
+                    .and_then(|parents| parents.first().copied())
+                    .filter(|parent| *parent < i),
+            })
+            .collect()
+    }
+
+    /// Checks the dependencies of a DAG job, returning every invalid field with its message
+    pub fn dependency_issues(&self) -> Vec<(String, String)> {
+        let mut issues = Vec::new();
+        if self.dependencies.is_empty() {
+            return issues;
+        }
+
+        if self.pipeline_mode == PipelineMode::Fused {
+            issues.push((
+                "dependencies".to_string(),
+                "A fused job runs its operations as one chain and can't list dependencies"
+                    .to_string(),
+            ));
+        }
+        if self.dependencies.len() != self.operations.len() {
+            issues.push((
+                "dependencies".to_string(),
+                format!(
+                    "The dependencies list {} operations but the job has {}",
+                    self.dependencies.len(),
+                    self.operations.len()
+                ),
+            ));
+        }
+        for (i, parents) in self.dependencies.iter().enumerate() {
+            if parents.iter().any(|parent| *parent >= i) {
+                issues.push((
+                    format!("dependencies[{}]", i),
+                    "An operation can only depend on operations listed before it".to_string(),
+                ));
+            }
+        }
+        issues
+    }
+}
@@ -0,0 +1,63 @@
+use crate::formats::InputFormat;
+
+// ============================================================================
+// DATASET FORMATS
+// A dataset is a zip or gzipped tar archive of images, a single image, or a
+// prefix of loose images that the decomposer lists, e.g.
+// `uploads/cats/images/`. The format is told from the key alone, a prefix is
+// a key ending in '/'. The images inside an archive or prefix are told apart
+// by their content, see `formats::image_input_format`.
+// ============================================================================
+
+/// Extensions of the archives a dataset can be uploaded as
+pub const ARCHIVE_EXTENSIONS: [&str; 3] = ["zip", "tar.gz", "tgz"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatasetFormat {
+    Zip,
+    TarGz,
+    Image,  // A dataset with a single entry
+    Prefix, // Every image below the prefix
+}
+
+/// The extension of a dataset file, keeping the double extension of `tar.gz` together
+pub fn dataset_extension(name: &str) -> Option<&str> {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    if let Some(split) = file_name.len().checked_sub(".tar.gz".len())
+        && split > 0
+        && file_name.is_char_boundary(split)
+        && file_name[split..].eq_ignore_ascii_case(".tar.gz")
+    {
+        return Some(&file_name[split + 1..]);
+    }
+    file_name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty())
+}
+
+impl DatasetFormat {
+    /// The format of the dataset under `key`, a single image is recognized by the extensions of
+    /// `input_formats` in any case
+    pub fn from_key(key: &str, input_formats: &[InputFormat]) -> Result<Self, String> {
+        if key.ends_with('/') {
+            return Ok(DatasetFormat::Prefix);
+        }
+
+        let ext = dataset_extension(key)
+            .ok_or_else(|| format!("Could not determine file extension for key: {}", key))?
+            .to_lowercase();
+        match ext.as_str() {
+            "zip" => Ok(DatasetFormat::Zip),
+            "tar.gz" | "tgz" => Ok(DatasetFormat::TarGz),
+            ext if InputFormat::from_extension(ext).is_some_and(|f| input_formats.contains(&f)) => {
+                Ok(DatasetFormat::Image)
+            }
+            ext => Err(format!("Unsupported file extension: {}", ext)),
+        }
+    }
+
+    pub fn is_archive(&self) -> bool {
+        matches!(self, DatasetFormat::Zip | DatasetFormat::TarGz)
+    }
+}
@@ -0,0 +1,174 @@
+use crate::ImageOperation;
+
+// ============================================================================
+// DIMENSION TRACKING
+// Geometric operations change the size of an image in a predictable way, so
+// the size of every stage's output can be computed from the input size and the
+// chain of operations without decoding the intermediate images.
+// ============================================================================
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageOperation {
+    /// The size of the image produced by this operation for an input of the given size
+    pub fn output_dimensions(&self, input: Dimensions) -> Dimensions {
+        match self {
+            ImageOperation::Resize { scaling_factor, .. } => Dimensions {
+                width: scale_side(input.width, *scaling_factor),
+                height: scale_side(input.height, *scaling_factor),
+            },
+            ImageOperation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (_, _, width, height) = crop_rect(input, *x, *y, *width, *height);
+                Dimensions { width, height }
+            }
+            ImageOperation::Rotate { degrees } => rotated_dimensions(input, *degrees),
+            ImageOperation::GrayScale
+            | ImageOperation::Noise { .. }
+            | ImageOperation::InvertColors
+            | ImageOperation::Blur { .. }
+            | ImageOperation::Convert { .. }
+            | ImageOperation::QualityGate { .. } => input,
+            ImageOperation::ResizeLongEdge { .. }
+            | ImageOperation::ResizeShortEdge { .. }
+            | ImageOperation::CenterCrop { .. }
+            | ImageOperation::BlurPerMegapixel { .. }
+            | ImageOperation::ByResolution { .. } => {
+                match self.resolve(input) {
+                    Some(resolved) => resolved.output_dimensions(input),
+                    None => input,
+                }
+            }
+        }
+    }
+}
+
+impl Dimensions {
+    pub fn long_edge(&self) -> u32 {
+        self.width.max(self.height)
+    }
+
+    pub fn short_edge(&self) -> u32 {
+        self.width.min(self.height)
+    }
+
+    pub fn megapixels(&self) -> f32 {
+        (self.width as u64 * self.height as u64) as f32 / 1_000_000.0
+    }
+}
+
+fn scale_side(side: u32, scaling_factor: f32) -> u32 {
+    ((side as f32 * scaling_factor).round() as u32).max(1)
+}
+
+/// Clips a crop rectangle to the image, keeping at least one pixel so the output is never empty.
+///
+/// Returns the `(x, y, width, height)` that is actually cropped.
+pub fn crop_rect(
+    input: Dimensions,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> (u32, u32, u32, u32) {
+    let x = x.min(input.width.saturating_sub(1));
+    let y = y.min(input.height.saturating_sub(1));
+    let width = width.min(input.width - x).max(1);
+    let height = height.min(input.height - y).max(1);
+
+    (x, y, width, height)
+}
+
+/// The size of the canvas holding an image rotated by the given angle. Quarter turns are exact,
+/// other angles use the bounding box of the rotated image.
+pub fn rotated_dimensions(input: Dimensions, degrees: f32) -> Dimensions {
+    let turns = degrees.rem_euclid(360.0);
+    if turns == 0.0 || turns == 180.0 {
+        return input;
+    }
+    if turns == 90.0 || turns == 270.0 {
+        return Dimensions {
+            width: input.height,
+            height: input.width,
+        };
+    }
+
+    let (sin, cos) = turns.to_radians().sin_cos();
+    let (w, h) = (input.width as f32, input.height as f32);
+    Dimensions {
+        width: ((w * cos.abs() + h * sin.abs()).ceil() as u32).max(1),
+        height: ((w * sin.abs() + h * cos.abs()).ceil() as u32).max(1),
+    }
+}
+
+/// Applies the dimension math of every operation in order, starting from the original size
+pub fn propagate_dimensions(operations: &[ImageOperation], input: Dimensions) -> Dimensions {
+    operations
+        .iter()
+        .fold(input, |dims, op| op.output_dimensions(dims))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dims(width: u32, height: u32) -> Dimensions {
+        Dimensions { width, height }
+    }
+
+    #[test]
+    fn geometric_operations_change_the_size() {
+        let operations = [
+            ImageOperation::Resize {
+                scaling_factor: 0.5,
+                filter: None,
+            },
+            ImageOperation::Rotate { degrees: 90.0 },
+            ImageOperation::GrayScale,
+        ];
+        assert_eq!(propagate_dimensions(&operations, dims(640, 480)), dims(240, 320));
+
+        let tiny = ImageOperation::Resize {
+            scaling_factor: 0.001,
+            filter: None,
+        };
+        assert_eq!(tiny.output_dimensions(dims(100, 10)), dims(1, 1));
+    }
+
+    #[test]
+    fn adaptive_operations_are_sized_per_image() {
+        let long_edge = ImageOperation::ResizeLongEdge {
+            long_edge: 100,
+            filter: None,
+        };
+        assert_eq!(long_edge.output_dimensions(dims(400, 200)), dims(100, 50));
+        assert_eq!(long_edge.output_dimensions(dims(200, 400)), dims(50, 100));
+
+        let center_crop = ImageOperation::CenterCrop {
+            width: 224,
+            height: 224,
+        };
+        assert_eq!(center_crop.output_dimensions(dims(300, 256)), dims(224, 224));
+    }
+
+    #[test]
+    fn crops_are_clipped_to_the_image() {
+        assert_eq!(crop_rect(dims(100, 50), 80, 40, 50, 50), (80, 40, 20, 10));
+        assert_eq!(crop_rect(dims(100, 50), 200, 200, 10, 10), (99, 49, 1, 1));
+    }
+
+    #[test]
+    fn rotations_other_than_quarter_turns_grow_the_canvas() {
+        assert_eq!(rotated_dimensions(dims(40, 20), -180.0), dims(40, 20));
+        assert_eq!(rotated_dimensions(dims(40, 20), 450.0), dims(20, 40));
+        assert_eq!(rotated_dimensions(dims(10, 10), 45.0), dims(15, 15));
+    }
+}
@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::QualityDriftConfig;
+
+// ============================================================================
+// QUALITY DRIFT
+// The image workers measure one in `quality_drift.sample_rate` outputs of
+// every stage: sharpness (variance of the Laplacian of the luma), mean
+// brightness, and the SSIM against the input where both have the same aspect
+// ratio, i.e. where the stage didn't crop or rotate. The sample is chosen by
+// the image task id, so retries measure the same images.
+//
+// Once a stage finished the scheduler summarizes its sample and compares it
+// with the latest stages that ran the same pipeline, the same operations
+// after the same upstream ones, whatever their parameters. Datasets differ
+// from each other far more than the images of one stage do, so a stage is
+// compared with the spread of the baseline's stage means rather than with
+// its images: a metric drifts when its mean is `z_threshold` deviations and
+// at least `min_relative_change` away from the baseline's. Drifts are
+// recorded on the stage and its batch, and sent by the alerting service.
+// ============================================================================
+
+// Floor of the deviation a drift is measured in, relative to the baseline's mean
+const MIN_RELATIVE_DEVIATION: f64 = 1e-6;
+
+/// What was measured of a sampled output
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ImageQualitySample {
+    pub sharpness: f64,    // Variance of the Laplacian of the luma, higher is sharper
+    pub brightness: f64,   // Mean luma, 0 to 255
+    pub ssim: Option<f64>, // Against the input, `None` when the stage changed its aspect ratio
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityMetric {
+    Sharpness,
+    Brightness,
+    Ssim,
+}
+
+impl QualityMetric {
+    pub const ALL: [QualityMetric; 3] = [Self::Sharpness, Self::Brightness, Self::Ssim];
+
+    /// The field of `ImageQualitySample` holding the metric
+    pub fn field(&self) -> &'static str {
+        match self {
+            QualityMetric::Sharpness => "sharpness",
+            QualityMetric::Brightness => "brightness",
+            QualityMetric::Ssim => "ssim",
+        }
+    }
+}
+
+/// A metric over the sampled outputs of a stage
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MetricSummary {
+    pub count: u64,
+    pub mean: f64,
+    pub std_dev: f64, // Sample standard deviation, 0 for a single output
+}
+
+/// The metrics of the sampled outputs of a stage, `None` for a metric none of them had
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct StageQualitySummary {
+    pub sharpness: Option<MetricSummary>,
+    pub brightness: Option<MetricSummary>,
+    pub ssim: Option<MetricSummary>,
+}
+
+impl StageQualitySummary {
+    pub fn metric(&self, metric: QualityMetric) -> Option<&MetricSummary> {
+        match metric {
+            QualityMetric::Sharpness => self.sharpness.as_ref(),
+            QualityMetric::Brightness => self.brightness.as_ref(),
+            QualityMetric::Ssim => self.ssim.as_ref(),
+        }
+    }
+}
+
+/// A metric of a stage that moved away from its pipeline's baseline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QualityDrift {
+    pub stage: u32,
+    pub pipeline: String, // Operations of the stage after the upstream ones, e.g. "Resize+Convert"
+    pub metric: QualityMetric,
+    pub observed: f64, // Mean of the stage's sample
+    pub baseline: f64, // Mean of the baseline's stage means
+    pub baseline_stages: usize,
+    pub z_score: f64,
+}
+
+/// Whether the output of an image task is measured, one in `sample_rate` are and none with 0
+pub fn is_sampled(image_task_id: &uuid::Uuid, sample_rate: u32) -> bool {
+    match sample_rate {
+        0 => false,
+        rate => image_task_id.as_u128().is_multiple_of(rate as u128),
+    }
+}
+
+/// Mean and sample standard deviation of the values, `None` without any
+pub fn summarize(values: &[f64]) -> Option<MetricSummary> {
+    if values.is_empty() {
+        return None;
+    }
+    let count = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / count;
+    let variance = match values.len() {
+        1 => 0.0,
+        _ => values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count - 1.0),
+    };
+
+    Some(MetricSummary {
+        count: values.len() as u64,
+        mean,
+        std_dev: variance.sqrt(),
+    })
+}
+
+/// The metrics of a finished stage that drifted from the baseline, the summaries of the latest
+/// stages of the same pipeline. Stages with fewer than `min_samples` measured outputs are
+/// neither checked nor part of the baseline.
+pub fn detect_drifts(
+    stage: u32,
+    pipeline: &str,
+    current: &StageQualitySummary,
+    baseline: &[StageQualitySummary],
+    config: &QualityDriftConfig,
+) -> Vec<QualityDrift> {
+    QualityMetric::ALL
+        .into_iter()
+        .filter_map(|metric| {
+            let observed = current
+                .metric(metric)
+                .filter(|summary| summary.count >= config.min_samples)?;
+            let means: Vec<f64> = baseline
+                .iter()
+                .filter_map(|summary| summary.metric(metric))
+                .filter(|summary| summary.count >= config.min_samples)
+                .map(|summary| summary.mean)
+                .collect();
+            if (means.len() as u32) < config.min_baseline_stages {
+                return None;
+            }
+            let expected = summarize(&means)?;
+
+            // The spread between datasets, plus the uncertainty of the stage's own mean. A
+            // baseline of identical stages leaves the relative change to decide.
+            let deviation = (expected.std_dev.powi(2)
+                + observed.std_dev.powi(2) / observed.count as f64)
+                .sqrt()
+                .max(expected.mean.abs() * MIN_RELATIVE_DEVIATION)
+                .max(f64::EPSILON);
+            let difference = observed.mean - expected.mean;
+            let z_score = difference / deviation;
+            let relative = difference.abs() / expected.mean.abs().max(f64::EPSILON);
+            let drifted =
+                z_score.abs() >= config.z_threshold && relative >= config.min_relative_change;
+
+            drifted.then(|| QualityDrift {
+                stage,
+                pipeline: pipeline.to_string(),
+                metric,
+                observed: observed.mean,
+                baseline: expected.mean,
+                baseline_stages: means.len(),
+                z_score,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_in_sample_rate_outputs_is_measured() {
+        let ids: Vec<uuid::Uuid> = (0..1000u128).map(uuid::Uuid::from_u128).collect();
+        assert_eq!(ids.iter().filter(|id| is_sampled(id, 1)).count(), 1000);
+        assert_eq!(ids.iter().filter(|id| is_sampled(id, 10)).count(), 100);
+        assert_eq!(ids.iter().filter(|id| is_sampled(id, 0)).count(), 0);
+
+        // Every stage and every run measures the same images
+        let id = uuid::Uuid::new_v4();
+        assert_eq!(is_sampled(&id, 7), is_sampled(&id, 7));
+    }
+
+    #[test]
+    fn summaries_use_the_sample_standard_deviation() {
+        assert!(summarize(&[]).is_none());
+
+        let single = summarize(&[3.0]).unwrap();
+        assert_eq!((single.count, single.mean, single.std_dev), (1, 3.0, 0.0));
+
+        let summary = summarize(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+        assert_eq!(summary.count, 8);
+        assert_eq!(summary.mean, 5.0);
+        assert!((summary.std_dev - (32.0f64 / 7.0).sqrt()).abs() < 1e-9);
+    }
+}
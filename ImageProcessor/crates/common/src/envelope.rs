@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::correlation;
+
+// ============================================================================
+// MESSAGE ENVELOPE
+// Every Kafka message is wrapped in an envelope recording the schema version
+// of its payload. Consumers read envelopes of any version as long as the
+// payload can be deserialized, so payload types must only gain fields with a
+// `#[serde(default)]` and never rename or remove one within a version.
+// Bump `SCHEMA_VERSION` for changes older consumers can't read.
+// ============================================================================
+
+/// Version of the payloads written by this build
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Version reported for messages written before envelopes existed, which are the bare payload
+pub const LEGACY_SCHEMA_VERSION: u32 = 0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageEnvelope<T> {
+    pub schema_version: u32,
+    pub message_id: uuid::Uuid,
+    pub produced_at: DateTime<Utc>,
+    #[serde(default)]
+    pub trace: TraceContext,
+    pub payload: T,
+}
+
+/// W3C trace context of the request that produced the message, empty when it wasn't traced
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TraceContext {
+    pub traceparent: Option<String>,
+    pub tracestate: Option<String>,
+    pub correlation_id: Option<uuid::Uuid>, // Request the message was sent for, see `correlation`
+}
+
+impl TraceContext {
+    /// The context of the request or message being handled
+    pub fn current() -> Self {
+        Self {
+            correlation_id: correlation::current(),
+            ..Self::default()
+        }
+    }
+}
+
+impl<T> MessageEnvelope<T> {
+    /// Wraps a payload produced now with the current schema version, on behalf of the request
+    /// or message being handled
+    pub fn new(payload: T) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            message_id: uuid::Uuid::new_v4(),
+            produced_at: Utc::now(),
+            trace: TraceContext::current(),
+            payload,
+        }
+    }
+
+    /// Wraps a message written before envelopes existed
+    pub fn legacy(payload: T) -> Self {
+        Self {
+            schema_version: LEGACY_SCHEMA_VERSION,
+            message_id: uuid::Uuid::nil(),
+            produced_at: Utc::now(),
+            trace: TraceContext::default(),
+            payload,
+        }
+    }
+
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Whether the message was written by a newer build than this one
+    pub fn is_newer_schema(&self) -> bool {
+        self.schema_version > SCHEMA_VERSION
+    }
+}
@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+
+use crate::capabilities::Capability;
+
+// ============================================================================
+// PROCESSOR ERRORS
+// The error type returned by the db, queue and decomposition layers. Errors
+// from external systems record whether retrying the same call could succeed,
+// so callers can tell a broker hiccup from a request that will never work.
+// ============================================================================
+
+/// What a call to object storage ran into, see `storage::s3::classify`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S3ErrorKind {
+    NoSuchKey,    // The object doesn't exist, e.g. an input removed while its batch ran
+    AccessDenied, // The credentials may not touch the bucket or key, no retry changes that
+    SlowDown,     // The store throttles us, backing off helps
+    Timeout,
+    Other,
+}
+
+impl S3ErrorKind {
+    pub fn is_transient(&self) -> bool {
+        match self {
+            S3ErrorKind::SlowDown | S3ErrorKind::Timeout | S3ErrorKind::Other => true,
+            S3ErrorKind::NoSuchKey | S3ErrorKind::AccessDenied => false,
+        }
+    }
+
+    /// Whether waiting before the same call is worth it, unlike for errors that may be transient
+    pub fn is_throttle(&self) -> bool {
+        matches!(self, S3ErrorKind::SlowDown | S3ErrorKind::Timeout)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            S3ErrorKind::NoSuchKey => "no_such_key",
+            S3ErrorKind::AccessDenied => "access_denied",
+            S3ErrorKind::SlowDown => "slow_down",
+            S3ErrorKind::Timeout => "timeout",
+            S3ErrorKind::Other => "other",
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum ProcessorError {
+    #[error("Database error: {message}")]
+    Database { message: String, transient: bool },
+
+    #[error("Kafka error: {message}")]
+    Kafka { message: String, transient: bool },
+
+    #[error("S3 error: {message}")]
+    S3 {
+        message: String,
+        transient: bool,
+        kind: S3ErrorKind,
+    },
+
+    #[error("Serialization error: {0}")]
+    Serialization(String),
+
+    #[error("Validation error: {0}")]
+    Validation(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String), // Another writer changed the record first, re-reading it may succeed
+
+    #[error("Internal error: {0}")]
+    Internal(String), // A bug on our side, e.g. a task that panicked
+
+    #[error("Unsupported: needs {0}")]
+    Unsupported(Capability), // Another worker may run it, see `capabilities`
+}
+
+impl ProcessorError {
+    /// Whether retrying the operation that failed could succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ProcessorError::Database { transient, .. }
+            | ProcessorError::Kafka { transient, .. }
+            | ProcessorError::S3 { transient, .. } => *transient,
+            ProcessorError::Conflict(_) => true,
+            ProcessorError::Serialization(_)
+            | ProcessorError::Validation(_)
+            | ProcessorError::NotFound(_)
+            | ProcessorError::Internal(_)
+            | ProcessorError::Unsupported(_) => false,
+        }
+    }
+
+    pub fn database(message: impl ToString, transient: bool) -> Self {
+        ProcessorError::Database {
+            message: message.to_string(),
+            transient,
+        }
+    }
+
+    pub fn kafka(message: impl ToString, transient: bool) -> Self {
+        ProcessorError::Kafka {
+            message: message.to_string(),
+            transient,
+        }
+    }
+
+    pub fn s3(message: impl ToString, transient: bool) -> Self {
+        ProcessorError::S3 {
+            message: message.to_string(),
+            transient,
+            kind: S3ErrorKind::Other,
+        }
+    }
+
+    /// An S3 error whose kind is known, transient when the kind is
+    pub fn classified_s3(message: impl ToString, kind: S3ErrorKind) -> Self {
+        ProcessorError::S3 {
+            message: message.to_string(),
+            transient: kind.is_transient(),
+            kind,
+        }
+    }
+
+    /// The kind of an S3 error, `None` for every other error
+    pub fn s3_error_kind(&self) -> Option<S3ErrorKind> {
+        match self {
+            ProcessorError::S3 { kind, .. } => Some(*kind),
+            _ => None,
+        }
+    }
+
+    pub fn serialization(message: impl ToString) -> Self {
+        ProcessorError::Serialization(message.to_string())
+    }
+}
@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::lifecycle::BatchState;
+
+// ============================================================================
+// BATCH PROGRESS EVENTS
+// The decomposer, the workers and the scheduler publish what happens to a
+// batch on the events topic as it happens: a stage's decomposition starting,
+// every image finishing, a stage finishing and the batch reaching a final
+// state. The api-server streams them to browsers for live progress, see
+// `GET /batch/{batch_id}/events`. Events are best effort, a publish that fails
+// is only logged and the status endpoints remain the source of truth.
+// ============================================================================
+
+/// Message type of the events on the events topic
+pub const BATCH_EVENT_MESSAGE_TYPE: &str = "batch_event";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchEvent {
+    pub batch_id: uuid::Uuid,
+    pub tenant_id: Option<String>,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: BatchEventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BatchEventKind {
+    /// The decomposer started splitting a stage into image tasks
+    TaskStarted { task_id: uuid::Uuid, stage: u32 },
+    /// A worker finished an image task of a stage, `dataset_task_id` names the stage. A filtered
+    /// image neither succeeded nor failed, a quality gate left it out.
+    ImageCompleted {
+        image_task_id: uuid::Uuid,
+        dataset_task_id: uuid::Uuid,
+        succeeded: bool,
+        #[serde(default)]
+        filtered: bool,
+    },
+    /// Every image of a stage finished
+    StageFinished {
+        task_id: uuid::Uuid,
+        stage: u32,
+        succeeded: bool,
+        images: u64,
+    },
+    /// A person approved or rejected the outputs of a stage, see `approval`
+    StageReviewed {
+        task_id: uuid::Uuid,
+        stage: u32,
+        approved: bool,
+    },
+    /// The batch reached a final state, no event follows
+    BatchDone { state: BatchState },
+}
+
+impl BatchEvent {
+    pub fn new(batch_id: uuid::Uuid, tenant_id: Option<String>, kind: BatchEventKind) -> Self {
+        Self {
+            batch_id,
+            tenant_id,
+            time: Utc::now(),
+            kind,
+        }
+    }
+
+    /// The name of the event, e.g. the `event` field of a Server-Sent Event
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            BatchEventKind::TaskStarted { .. } => "task_started",
+            BatchEventKind::ImageCompleted { .. } => "image_completed",
+            BatchEventKind::StageFinished { .. } => "stage_finished",
+            BatchEventKind::StageReviewed { .. } => "stage_reviewed",
+            BatchEventKind::BatchDone { .. } => "batch_done",
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self.kind, BatchEventKind::BatchDone { .. })
+    }
+}
@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// FAILURE TAXONOMY
+// Every failed image task records what kind of failure it ran into next to
+// its error message, so a batch's failures can be counted by kind, stage and
+// operation instead of read one by one. A hundred `DecodeError`s in one folder
+// point at a bad export, a `Timeout` on every Blur at an undersized worker.
+// The kind is decided where the failure happens, not parsed from the message.
+// ============================================================================
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FailureKind {
+    DecodeError,       // The input is corrupt, truncated or not the image it claims to be
+    UnsupportedFormat, // Neither the input's format nor the output format can hold the image
+    Timeout,           // The operations took longer than the worker allows
+    StorageError,      // Reading the input or writing the output failed
+    OperationPanic,    // An operation panicked on the image
+    ResourceLimit,     // The image is larger than the worker or the decoder allows
+    #[default]
+    Other, // Anything else, e.g. an operation the worker doesn't run
+}
+
+impl FailureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureKind::DecodeError => "DecodeError",
+            FailureKind::UnsupportedFormat => "UnsupportedFormat",
+            FailureKind::Timeout => "Timeout",
+            FailureKind::StorageError => "StorageError",
+            FailureKind::OperationPanic => "OperationPanic",
+            FailureKind::ResourceLimit => "ResourceLimit",
+            FailureKind::Other => "Other",
+        }
+    }
+}
+
+/// An error that knows its kind, e.g. a decoder telling a corrupt input from an unsupported one
+#[derive(Debug, Clone)]
+pub struct ClassifiedError {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl ClassifiedError {
+    pub fn new(kind: FailureKind, message: impl ToString) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ClassifiedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Callers that only report the message keep using `?` on `String` errors
+impl From<ClassifiedError> for String {
+    fn from(error: ClassifiedError) -> Self {
+        error.message
+    }
+}
@@ -0,0 +1,95 @@
+use std::{sync::OnceLock, time::Duration};
+
+use crate::{config::FaultConfig, error::ProcessorError};
+
+// ============================================================================
+// FAULT INJECTION
+// Failures injected on purpose at the points the pipeline is meant to survive:
+// object writes, Kafka sends, image task status changes and the image worker's
+// handler. Each binary arms the `faults` of its config at startup, and every
+// hook below then rolls against its rate. Builds without the
+// `fault-injection` feature never arm them, the hooks do nothing there, so a
+// stray config can't break production.
+// ============================================================================
+
+static FAULTS: OnceLock<FaultConfig> = OnceLock::new();
+
+impl FaultConfig {
+    /// Whether any fault would be injected
+    pub fn is_active(&self) -> bool {
+        [
+            self.s3_put_failure_rate,
+            self.kafka_send_delay_rate,
+            self.db_write_failure_rate,
+            self.handler_drop_rate,
+        ]
+        .iter()
+        .any(|rate| *rate > 0.0)
+    }
+}
+
+/// Arms the configured faults for the rest of the process, the first call wins
+pub fn install(config: &FaultConfig) {
+    if !config.is_active() {
+        return;
+    }
+    match cfg!(feature = "fault-injection") {
+        true => {
+            tracing::warn!(?config, "Fault injection is armed");
+            let _ = FAULTS.set(config.clone());
+        }
+        false => tracing::warn!("Ignoring the configured faults, built without fault-injection"),
+    }
+}
+
+/// Fails an object write with a transient error, at `s3_put_failure_rate`
+pub fn s3_put(key: &str) -> Result<(), ProcessorError> {
+    match roll("s3_put", |config| config.s3_put_failure_rate) {
+        true => Err(ProcessorError::s3(format!("Injected failure writing {}", key), true)),
+        false => Ok(()),
+    }
+}
+
+/// Holds up a Kafka send for `kafka_send_delay_ms`, at `kafka_send_delay_rate`
+pub async fn kafka_send() {
+    if roll("kafka_send_delay", |config| config.kafka_send_delay_rate) {
+        let delay = FAULTS.get().map_or(0, |config| config.kafka_send_delay_ms);
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+}
+
+/// Fails a database write with a transient error, at `db_write_failure_rate`
+pub fn db_write(operation: &str) -> Result<(), ProcessorError> {
+    match roll("db_write", |config| config.db_write_failure_rate) {
+        true => Err(ProcessorError::database(
+            format!("Injected failure of {}", operation),
+            true,
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Whether the handler should give up on its task without a word, at `handler_drop_rate`
+pub fn drop_handler() -> bool {
+    roll("handler_drop", |config| config.handler_drop_rate)
+}
+
+#[cfg(feature = "fault-injection")]
+fn roll(fault: &str, rate: impl Fn(&FaultConfig) -> f64) -> bool {
+    use crate::metrics;
+    use rand::Rng;
+
+    let Some(config) = FAULTS.get() else {
+        return false;
+    };
+    let injected = rand::thread_rng().gen_bool(rate(config).clamp(0.0, 1.0));
+    if injected {
+        metrics::FAULTS_INJECTED.with_label_values(&[fault]).inc();
+    }
+    injected
+}
+
+#[cfg(not(feature = "fault-injection"))]
+fn roll(_fault: &str, _rate: impl Fn(&FaultConfig) -> f64) -> bool {
+    false
+}
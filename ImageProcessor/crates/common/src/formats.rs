@@ -0,0 +1,168 @@
+use std::str::FromStr;
+
+use crate::{ImageOperation, naming::with_extension};
+
+// ============================================================================
+// OUTPUT FORMATS
+// Images are written back in the format they were read in, unless a Convert
+// operation or the job's default output format asks for another one. The
+// extension of the output keys follows the format, so every stage can name
+// its input and output without looking at the images.
+//
+// The images of a dataset are told apart from its other files by their first
+// bytes rather than their names, so "IMG_01.JPEG" or an image without an
+// extension is picked up too. Only the formats of `decomposer.input_formats`
+// are, the extension is only trusted for a file whose content isn't known.
+// ============================================================================
+
+/// Bytes from the start of a file `InputFormat::detect` needs
+pub const MAGIC_BYTES: usize = 16;
+
+/// A format images can be read in
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputFormat {
+    Jpeg,
+    Png,
+    Tiff, // 8 and 16 bits per channel
+    Webp,
+    Avif, // Only decoded by workers built with the `avif-decode` feature of `image_ops`
+    Gif,
+    Bmp,
+}
+
+impl InputFormat {
+    /// The formats a dataset's images are read in unless the config says otherwise
+    pub const DEFAULT: [InputFormat; 4] = [Self::Jpeg, Self::Png, Self::Tiff, Self::Webp];
+
+    /// The format of a file from its first `MAGIC_BYTES` bytes, `None` if it isn't a known image
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        match header {
+            [0xFF, 0xD8, 0xFF, ..] => Some(Self::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(Self::Png),
+            // Classic and BigTIFF, in either byte order
+            [b'I', b'I', 0x2A | 0x2B, 0x00, ..] | [b'M', b'M', 0x00, 0x2A | 0x2B, ..] => {
+                Some(Self::Tiff)
+            }
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(Self::Webp),
+            [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => {
+                Some(Self::Avif)
+            }
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Self::Gif),
+            [b'B', b'M', ..] => Some(Self::Bmp),
+            _ => None,
+        }
+    }
+
+    /// The format a file extension usually stands for, in any case
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" | "jpe" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "tif" | "tiff" => Some(Self::Tiff),
+            "webp" => Some(Self::Webp),
+            "avif" => Some(Self::Avif),
+            "gif" => Some(Self::Gif),
+            "bmp" => Some(Self::Bmp),
+            _ => None,
+        }
+    }
+
+    /// The extensions `from_extension` recognizes, in lower case
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            InputFormat::Jpeg => &["jpg", "jpeg", "jpe"],
+            InputFormat::Png => &["png"],
+            InputFormat::Tiff => &["tif", "tiff"],
+            InputFormat::Webp => &["webp"],
+            InputFormat::Avif => &["avif"],
+            InputFormat::Gif => &["gif"],
+            InputFormat::Bmp => &["bmp"],
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    /// A format by its name or any of its extensions, e.g. "Tiff" or "tif"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_extension(s).ok_or_else(|| format!("Unknown image format {}", s))
+    }
+}
+
+/// The format of a file of a dataset if it is an image of one of `allowed`. The content decides,
+/// the extension of `name` only does when the content isn't of a known format, so an image that
+/// is truncated or corrupt still fails where it can be told about instead of being left out.
+pub fn image_input_format(
+    header: &[u8],
+    name: &str,
+    allowed: &[InputFormat],
+) -> Option<InputFormat> {
+    let format = InputFormat::detect(header).or_else(|| {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        let (_, extension) = file_name.rsplit_once('.')?;
+        InputFormat::from_extension(extension)
+    })?;
+    allowed.contains(&format).then_some(format)
+}
+
+/// A format images can be converted to
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+/// Quality used when the job's default output format converts the images
+pub const DEFAULT_QUALITY: u8 = 85;
+
+impl OutputFormat {
+    /// The extension of the keys the images are written to
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    /// Whether the encoder uses the quality, the others are lossless
+    pub fn is_lossy(&self) -> bool {
+        matches!(self, OutputFormat::Jpeg | OutputFormat::Avif)
+    }
+}
+
+/// The format and quality a chain of operations leaves the image in, the last `Convert` wins.
+///
+/// Returns `None` when no operation converts, meaning the image keeps its format.
+pub fn target_format<'a>(
+    operations: impl IntoIterator<Item = &'a ImageOperation>,
+) -> Option<(OutputFormat, u8)> {
+    operations
+        .into_iter()
+        .filter_map(|op| match op {
+            ImageOperation::Convert { format, quality } => Some((*format, *quality)),
+            _ => None,
+        })
+        .last()
+}
+
+/// The name of an image once written in the given format, unchanged if it keeps its format
+pub fn converted_name(name: &str, format: Option<OutputFormat>) -> String {
+    match format {
+        Some(format) => with_extension(name, format.extension()),
+        None => name.to_string(),
+    }
+}
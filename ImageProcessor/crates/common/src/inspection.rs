@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DatasetLimits;
+
+// ============================================================================
+// DATASET INSPECTION
+// Before the first stage decomposes an archive, the decomposer checks its
+// listing against `dataset_limits`: how many entries it has, how large they
+// are once decompressed, alone and together, and whether any entry would be
+// written outside of the dataset (e.g. `../etc/passwd` or `/tmp/x`). Sizes are
+// the ones the archive declares, so nothing is extracted to check them. The
+// report is recorded on the batch either way, and a dataset that breaks a
+// limit fails its batch before a single image task is created.
+// ============================================================================
+
+// Violations about single entries listed in a report, the rest are only counted
+const MAX_LISTED_VIOLATIONS: usize = 20;
+
+/// An entry of an archive as its listing describes it
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: u64, // Declared size once decompressed, 0 for directories
+    pub is_file: bool,
+}
+
+/// A limit the dataset broke
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DatasetViolation {
+    TooManyEntries { entries: u64, limit: u64 },
+    TooLarge { bytes: u64, limit: u64 }, // All files together, decompressed
+    FileTooLarge { path: String, bytes: u64, limit: u64 },
+    UnsafePath { path: String }, // Absolute, or climbing out of the dataset with `..`
+}
+
+/// What the inspection of a dataset found, recorded on its batch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatasetValidationReport {
+    pub entries: u64, // Directories included
+    pub files: u64,
+    pub uncompressed_bytes: u64,
+    pub largest_file_bytes: u64,
+    pub violations: Vec<DatasetViolation>,
+    #[serde(default)]
+    pub violations_omitted: u64, // Violations about single entries beyond the listed ones
+    #[serde(with = "crate::timestamp")]
+    pub time_validated: DateTime<Utc>,
+}
+
+impl DatasetValidationReport {
+    pub fn passed(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// One line describing why the dataset was rejected, for the error of the failed stage
+    pub fn summary(&self) -> String {
+        let mut reasons: Vec<String> = self
+            .violations
+            .iter()
+            .map(|violation| match violation {
+                DatasetViolation::TooManyEntries { entries, limit } => {
+                    format!("{} entries, at most {} are allowed", entries, limit)
+                }
+                DatasetViolation::TooLarge { bytes, limit } => {
+                    format!("{} bytes decompressed, at most {} are allowed", bytes, limit)
+                }
+                DatasetViolation::FileTooLarge { path, bytes, limit } => {
+                    format!("{} has {} bytes, at most {} are allowed", path, bytes, limit)
+                }
+                DatasetViolation::UnsafePath { path } => {
+                    format!("{} points outside of the dataset", path)
+                }
+            })
+            .collect();
+        if self.violations_omitted > 0 {
+            reasons.push(format!("{} more", self.violations_omitted));
+        }
+        format!("Dataset rejected: {}", reasons.join("; "))
+    }
+}
+
+/// Whether extracting an entry to its path would write outside of the dataset
+pub fn is_unsafe_path(path: &str) -> bool {
+    let path = path.replace('\\', "/");
+    let absolute = path.starts_with('/')
+        || path.as_bytes().get(1) == Some(&b':') // A Windows drive, e.g. `C:/`
+        || path.contains('\0');
+    absolute || path.split('/').any(|component| component == "..")
+}
+
+/// Checks the listing of an archive against the limits
+pub fn inspect_entries(
+    entries: &[ArchiveEntry],
+    limits: &DatasetLimits,
+) -> DatasetValidationReport {
+    let mut violations = Vec::new();
+    let mut omitted = 0;
+    let mut flag = |violation: DatasetViolation| match violations.len() < MAX_LISTED_VIOLATIONS {
+        true => violations.push(violation),
+        false => omitted += 1,
+    };
+
+    let mut files = 0;
+    let mut uncompressed_bytes: u64 = 0;
+    let mut largest_file_bytes = 0;
+    for entry in entries {
+        if is_unsafe_path(&entry.path) {
+            flag(DatasetViolation::UnsafePath {
+                path: entry.path.clone(),
+            });
+        }
+        if !entry.is_file {
+            continue;
+        }
+        files += 1;
+        uncompressed_bytes = uncompressed_bytes.saturating_add(entry.size);
+        largest_file_bytes = largest_file_bytes.max(entry.size);
+        if entry.size > limits.max_file_bytes {
+            flag(DatasetViolation::FileTooLarge {
+                path: entry.path.clone(),
+                bytes: entry.size,
+                limit: limits.max_file_bytes,
+            });
+        }
+    }
+
+    // The limits on the whole dataset come first, they say the most about it
+    let mut totals = Vec::new();
+    if entries.len() as u64 > limits.max_entries {
+        totals.push(DatasetViolation::TooManyEntries {
+            entries: entries.len() as u64,
+            limit: limits.max_entries,
+        });
+    }
+    if uncompressed_bytes > limits.max_uncompressed_bytes {
+        totals.push(DatasetViolation::TooLarge {
+            bytes: uncompressed_bytes,
+            limit: limits.max_uncompressed_bytes,
+        });
+    }
+    totals.append(&mut violations);
+
+    DatasetValidationReport {
+        entries: entries.len() as u64,
+        files,
+        uncompressed_bytes,
+        largest_file_bytes,
+        violations: totals,
+        violations_omitted: omitted,
+        time_validated: Utc::now(),
+    }
+}
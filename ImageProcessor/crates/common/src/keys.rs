@@ -0,0 +1,65 @@
+use crate::tenancy::{strip_tenant_prefix, tenant_key};
+
+// ============================================================================
+// OBJECT KEYS
+// Where a batch's objects are stored, inside the prefix of its tenant. The
+// stages of a batch write below a prefix named after the dataset and the
+// batch, `{dataset}/{batch}/`, one folder per stage: folder 0 holds the images
+// extracted from the dataset and folder `n` the outputs of stage `n - 1`.
+// The batch id keeps two batches of the same upload, e.g. a rerun with other
+// operations, from overwriting each other's images. Within a batch every image
+// already has a unique name, see `naming`. What the batch publishes (manifests,
+// reports, provenance) goes to `results/{batch}/`.
+// ============================================================================
+
+/// Where the stages of a batch on an uploaded dataset write, e.g. `cats/{batch}` for
+/// `uploads/cats/input.zip`. `None` for keys outside of the uploads of the tenant.
+pub fn stage_prefix(
+    tenant_id: Option<&str>,
+    dataset_key: &str,
+    batch_id: &uuid::Uuid,
+) -> Option<String> {
+    let key = strip_tenant_prefix(tenant_id, dataset_key)?;
+    let dataset_name = key.split('/').nth(1)?;
+    Some(tenant_key(tenant_id, &format!("{}/{}", dataset_name, batch_id)))
+}
+
+/// Where the stages of a batch on a single image write, named after the image as a dataset
+/// has no name of its own
+pub fn image_stage_prefix(
+    tenant_id: Option<&str>,
+    filename: &str,
+    batch_id: &uuid::Uuid,
+) -> String {
+    tenant_key(tenant_id, &format!("{}/{}", filename, batch_id))
+}
+
+/// The folder of a stage below the prefix, e.g. `cats/{batch}/2/`
+pub fn stage_folder(stage_prefix: &str, folder: u32) -> String {
+    format!("{}/{}/", stage_prefix, folder)
+}
+
+/// The key of an image in the folder of a stage, e.g. `cats/{batch}/2/01.png`
+pub fn stage_key(stage_prefix: &str, folder: u32, name: &str) -> String {
+    format!("{}{}", stage_folder(stage_prefix, folder), name)
+}
+
+/// The key a sidecar of an archive is extracted to, see `sidecars`
+pub fn sidecar_key(stage_prefix: &str, path: &str) -> String {
+    format!("{}/sidecars/{}", stage_prefix, path)
+}
+
+/// The prefix holding what a batch publishes, e.g. `results/{batch}/`
+pub fn results_prefix(tenant_id: Option<&str>, batch_id: &uuid::Uuid) -> String {
+    tenant_key(tenant_id, &format!("results/{}/", batch_id))
+}
+
+/// Where the zip of a batch's results is kept once it was built
+pub fn results_archive_key(tenant_id: Option<&str>, batch_id: &uuid::Uuid) -> String {
+    tenant_key(tenant_id, &format!("archives/{}/results.zip", batch_id))
+}
+
+/// The key of an object in the results prefix of a batch, e.g. `results/{batch}/manifest.json`
+pub fn results_key(tenant_id: Option<&str>, batch_id: &uuid::Uuid, name: &str) -> String {
+    format!("{}{}", results_prefix(tenant_id, batch_id), name)
+}
@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// CONSUMER LAG
+// How far each consumer group trails the topics it reads, the backlog an
+// autoscaler scales the replicas of the group by. The lag reporter of the
+// scheduler fetches it from outside the groups every few seconds, so a group
+// scaled down to nothing still has its backlog reported, and writes it to the
+// `consumer_lag` collection and the `kafka_consumer_group_lag` metric, see
+// `queue::lag_reporter`.
+// ============================================================================
+
+/// Lag of a consumer group on a single partition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartitionLag {
+    pub topic: String,
+    pub partition: i32,
+    pub committed_offset: Option<i64>, // None if the group never committed on this partition
+    pub high_watermark: i64,
+    pub lag: i64,
+}
@@ -1,4 +1,60 @@
+use std::collections::{BTreeMap, HashMap};
+
+use adaptive::ResolutionRule;
+use alpha::AlphaPolicy;
+use dag::StageInput;
+use dimensions::Dimensions;
+use formats::OutputFormat;
+use metadata::MetadataPolicy;
+use naming::{CollisionPolicy, OutputLayout};
+use notifications::NotificationPreferences;
+use resampling::ResampleFilter;
 use uuid::Uuid;
+pub mod adaptive;
+pub mod alpha;
+pub mod analytics;
+pub mod approval;
+pub mod budgets;
+pub mod capabilities;
+pub mod checkpoints;
+pub mod config;
+pub mod control;
+pub mod correlation;
+pub mod dag;
+pub mod datasets;
+pub mod dimensions;
+pub mod drift;
+pub mod envelope;
+pub mod error;
+pub mod events;
+pub mod failures;
+pub mod faults;
+pub mod formats;
+pub mod inspection;
+pub mod keys;
+pub mod lag;
+pub mod lifecycle;
+pub mod logging;
+pub mod manifest;
+pub mod metadata;
+pub mod metrics;
+pub mod migration;
+pub mod naming;
+pub mod notifications;
+pub mod presets;
+pub mod previews;
+pub mod profiles;
+pub mod provenance;
+pub mod report;
+pub mod reproducibility;
+pub mod resampling;
+pub mod sharing;
+pub mod sidecars;
+pub mod slo;
+pub mod tagging;
+pub mod tenancy;
+pub mod timestamp;
+pub mod validation;
 
 // ============================================================================
 // SHARED TYPES
@@ -6,10 +62,139 @@ use uuid::Uuid;
 
 #[derive(serde::Serialize, Debug, Clone, serde::Deserialize)]
 pub enum ImageOperation {
-    Resize { scaling_factor: f32 },
+    // Without a filter the direction picks one, see `resampling`
+    Resize {
+        scaling_factor: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<ResampleFilter>,
+    },
     GrayScale,
     Noise { noise_level: f32 },
     InvertColors,
+    Crop { x: u32, y: u32, width: u32, height: u32 }, // Clipped to the image when it extends past the edges
+    Rotate { degrees: f32 }, // Clockwise, the canvas grows to fit the rotated image
+    Blur { sigma: f32 },     // Gaussian blur, sigma in pixels
+    BlurPerMegapixel { sigma_per_megapixel: f32 }, // Sigma scaled by the image's area, resolved per image
+    // Resize so the longest side is `long_edge` pixels, resolved per image
+    ResizeLongEdge {
+        long_edge: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<ResampleFilter>,
+    },
+    // Resize so the shortest side is `short_edge` pixels, resolved per image
+    ResizeShortEdge {
+        short_edge: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<ResampleFilter>,
+    },
+    CenterCrop { width: u32, height: u32 }, // Crop around the centre of each image, resolved per image
+    ByResolution { rules: Vec<ResolutionRule> }, // Picks the operation from the size of each image
+    Convert { format: OutputFormat, quality: u8 }, // Encodes the output in `format`, quality 1-100 for lossy formats
+    // Leaves out the images missing any of the given bounds, the others pass through unchanged
+    QualityGate {
+        min_width: Option<u32>,
+        min_height: Option<u32>,
+        max_blur_score: Option<f64>,
+        min_entropy: Option<f64>,
+    },
+}
+
+impl ImageOperation {
+    /// Names of every operation of this build
+    pub const NAMES: [&'static str; 14] = [
+        "Resize",
+        "GrayScale",
+        "Noise",
+        "InvertColors",
+        "Crop",
+        "Rotate",
+        "Blur",
+        "BlurPerMegapixel",
+        "ResizeLongEdge",
+        "ResizeShortEdge",
+        "CenterCrop",
+        "ByResolution",
+        "Convert",
+        "QualityGate",
+    ];
+
+    /// Name of the operation, as used in config files and metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageOperation::Resize { .. } => "Resize",
+            ImageOperation::GrayScale => "GrayScale",
+            ImageOperation::Noise { .. } => "Noise",
+            ImageOperation::InvertColors => "InvertColors",
+            ImageOperation::Crop { .. } => "Crop",
+            ImageOperation::Rotate { .. } => "Rotate",
+            ImageOperation::Blur { .. } => "Blur",
+            ImageOperation::BlurPerMegapixel { .. } => "BlurPerMegapixel",
+            ImageOperation::ResizeLongEdge { .. } => "ResizeLongEdge",
+            ImageOperation::ResizeShortEdge { .. } => "ResizeShortEdge",
+            ImageOperation::CenterCrop { .. } => "CenterCrop",
+            ImageOperation::ByResolution { .. } => "ByResolution",
+            ImageOperation::Convert { .. } => "Convert",
+            ImageOperation::QualityGate { .. } => "QualityGate",
+        }
+    }
+}
+
+/// Name of a chain of operations applied by one task, e.g. "Resize+Blur"
+pub fn operations_name<'a>(operations: impl IntoIterator<Item = &'a ImageOperation>) -> String {
+    operations
+        .into_iter()
+        .map(ImageOperation::name)
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// How the operations of a job are spread over stages
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PipelineMode {
+    #[default]
+    Staged, // One stage per operation, every intermediate image goes through S3 and Kafka
+    Fused, // A single stage whose image tasks apply every operation in memory before one upload
+}
+
+/// How urgently a batch should be processed. With `PriorityConfig::dedicated_topics` High and
+/// Low work travels on topics of its own, so small urgent batches don't wait behind huge ones.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// The topic work of this priority is sent to when priorities have dedicated topics
+    pub fn topic(&self, topic: &str) -> String {
+        match self {
+            Priority::Low => format!("{}-low", topic),
+            Priority::Normal => topic.to_string(),
+            Priority::High => format!("{}-high", topic),
+        }
+    }
+}
+
+/// Who the results of a batch's image tasks are cached for, see the image worker's result cache.
+/// Results are only reused within the tenant unless the job opts into the shared namespace,
+/// meant for public images like common benchmark datasets.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheScope {
+    #[default]
+    Tenant, // Reused by the batches of the same tenant only
+    Shared, // Reused by every batch that opted in, whichever tenant submitted it
+}
+
+impl CacheScope {
+    /// Name of the scope, as used in metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheScope::Tenant => "tenant",
+            CacheScope::Shared => "shared",
+        }
+    }
 }
 
 // ============================================================================
@@ -24,7 +209,50 @@ pub enum ImageOperation {
 pub struct DatasetProcessingJob {
     pub batch_id: Option<uuid::Uuid>, // A unique ID, generated server-side, to track the entire batch
     pub dataset_key: String,          // Key of the dataset zip folder inside of s3
+    #[serde(default)]
     pub operations: Vec<ImageOperation>, // A list of the different operations to be applied
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<Vec<usize>>, // The operations each operation runs after, see `dag`. Empty for a chain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires_approval: Vec<usize>, // Operations reviewed before others run, see `approval`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>, // A preset from `presets`, run ahead of `operations`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pipeline: Option<String>, // A named pipeline of the tenant, run ahead of `operations`
+    #[serde(default)]
+    pub use_local_cache: bool, // Pin every stage of an image to the same worker to reuse its disk cache
+    #[serde(default)]
+    pub output_layout: OutputLayout, // Whether the folders of the archive are kept in the output keys
+    #[serde(default)]
+    pub collision_policy: CollisionPolicy, // How entries that end up with the same output name are handled
+    #[serde(default)]
+    pub hash_suffix: bool, // Append a content hash to the names of the final outputs, for cache-busting
+    #[serde(default)]
+    pub cache_scope: CacheScope, // Who may reuse the cached results of its image tasks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_format: Option<OutputFormat>, // Format of the final outputs when no operation converts them
+    #[serde(default)]
+    pub alpha_policy: AlphaPolicy, // What happens to the transparency of the images, see `alpha`
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy, // Whether outputs keep the EXIF block, see `metadata`
+    #[serde(default)]
+    pub rewrite_sidecars: bool, // Point the dataset's CSV sidecars at the renamed outputs, see `sidecars`
+    #[serde(default)]
+    pub previews: bool, // Write a thumbnail of every final output, see `previews`
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>, // Written as S3 object tags on every object of the batch
+    #[serde(default)]
+    pub owner: Option<String>, // Written as the "owner" object tag
+    #[serde(default)]
+    pub notification_url: Option<String>, // Webhook sent the batch's report and warned before its results expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notification_preferences: Option<NotificationPreferences>, // See `notifications`
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub pipeline_mode: PipelineMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>, // Set by the api-server from the API key, see `tenancy`
 }
 
 /// Represents a single dataset processing task (one operation on a dataset)
@@ -35,8 +263,41 @@ pub struct DatasetProcessingTask {
     pub task_id: uuid::Uuid, // Unique ID for this specific task,  generated server-side
     pub batch_id: uuid::Uuid, // Inherited from the parent job
     pub operation: ImageOperation, // The operation to be performed on the dataset
-    pub depends_on: Option<Uuid>, // The ID of the task this task depends on, if it exists
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fused_operations: Vec<ImageOperation>, // Applied after `operation` by the same image tasks, in fused mode
+    #[serde(default, deserialize_with = "dag::one_or_many")]
+    pub depends_on: Vec<Uuid>, // The IDs of the tasks that have to succeed before this one runs
     pub stage: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<StageInput>, // Where a stage of a DAG job reads its images, `None` in a chain
+    #[serde(default)]
+    pub upstream_operations: Vec<ImageOperation>, // Operations applied by the earlier stages, in order
+    #[serde(default)]
+    pub requires_approval: bool, // The stages after it wait for its outputs to be approved
+    #[serde(default)]
+    pub use_local_cache: bool, // Inherited from the parent job
+    #[serde(default)]
+    pub output_layout: OutputLayout, // Inherited from the parent job
+    #[serde(default)]
+    pub collision_policy: CollisionPolicy, // Inherited from the parent job
+    #[serde(default)]
+    pub hash_suffix: bool, // Only set on the last stage of a job that asked for hashed names
+    #[serde(default)]
+    pub cache_scope: CacheScope, // Inherited from the parent job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_format: Option<OutputFormat>, // Only set on the last stage, the job's output format
+    #[serde(default)]
+    pub alpha_policy: AlphaPolicy, // Inherited from the parent job
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy, // Inherited from the parent job
+    #[serde(default)]
+    pub previews: bool, // Only set on the last stage of a job that asked for previews
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub object_tags: BTreeMap<String, String>, // The job's tags and owner, see `tagging`
+    #[serde(default)]
+    pub priority: Priority, // Inherited from the parent job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>, // Inherited from the parent job
 }
 
 /// Represents an individual image processing task (smallest unit of work)
@@ -50,6 +311,78 @@ pub struct ImageTask {
     pub depends_on: Option<Uuid>,    // The ID of the task this task depends on, if it exists
     pub dependency_dataset_task_id: Option<Uuid>, // The ID of the dataset task this task depends on, if it exists
     pub operation: ImageOperation,                // The operation to be performed on the image
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fused_operations: Vec<ImageOperation>, // Applied in memory after `operation`, before the single upload
+    #[serde(default)]
+    pub input_dimensions: Option<Dimensions>, // Size of the image this stage receives, if known
+    #[serde(default)]
+    pub output_dimensions: Option<Dimensions>, // Size of the image this stage produces, if known
+    #[serde(default)]
+    pub affinity_key: Option<String>, // Key used to route every stage of an image to the same partition
+    #[serde(default)]
+    pub output_key: Option<String>, // Where the processed image is written, the next stage's input
+    #[serde(default)]
+    pub source_path: Option<String>, // Path of the image inside the uploaded dataset, e.g. "cats/01.png"
+    #[serde(default)]
+    pub hash_suffix: bool, // The worker renames the output after its content, see `naming::with_hash_suffix`
+    #[serde(default)]
+    pub cache_scope: CacheScope, // Inherited from the dataset task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_format: Option<OutputFormat>, // Used when none of the task's operations converts
+    #[serde(default)]
+    pub alpha_policy: AlphaPolicy, // Inherited from the dataset task
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy, // Inherited from the dataset task
+    #[serde(default)]
+    pub previews: bool, // The worker writes a thumbnail of the output, see `previews`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub object_tags: BTreeMap<String, String>, // Inherited from the dataset task
+    #[serde(default)]
+    pub priority: Priority, // Inherited from the dataset task
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant_id: Option<String>, // Inherited from the dataset task
+}
+
+impl DatasetProcessingTask {
+    /// Every operation the stage applies, in order
+    pub fn operations(&self) -> impl Iterator<Item = &ImageOperation> {
+        std::iter::once(&self.operation).chain(&self.fused_operations)
+    }
+
+    /// The folder of the stage prefix the stage reads its images from, see `dag::input_folder`
+    pub fn input_folder(&self) -> u32 {
+        dag::input_folder(self.input, self.stage)
+    }
+
+    /// The dataset task whose outputs the stage reads, `None` for a stage reading the dataset
+    pub fn input_task_id(&self) -> Option<Uuid> {
+        dag::input_task_id(self.input, &self.depends_on)
+    }
+
+    /// The format the images reach the stage in, `None` if no earlier stage converted them
+    pub fn input_format(&self) -> Option<OutputFormat> {
+        formats::target_format(&self.upstream_operations).map(|(format, _)| format)
+    }
+
+    /// The format the stage writes its images in, `None` if they keep the format of the upload
+    pub fn output_format(&self) -> Option<OutputFormat> {
+        formats::target_format(self.upstream_operations.iter().chain(self.operations()))
+            .map(|(format, _)| format)
+            .or(self.default_format)
+    }
+}
+
+impl ImageTask {
+    /// Every operation the task applies, in order
+    pub fn operations(&self) -> impl Iterator<Item = &ImageOperation> {
+        std::iter::once(&self.operation).chain(&self.fused_operations)
+    }
+
+    /// The format and quality the output is encoded in, `None` to keep the format of the input
+    pub fn target_format(&self) -> Option<(OutputFormat, u8)> {
+        let default = self.default_format.map(|format| (format, formats::DEFAULT_QUALITY));
+        formats::target_format(self.operations()).or(default)
+    }
 }
 
 // ============================================================================
@@ -62,6 +395,8 @@ pub struct SendDataResult {
     pub batch_id: Uuid,
     pub successes: Vec<DatasetProcessingTask>,
     pub failures: Vec<DatasetProcessingTask>,
+    pub deferred: Vec<DatasetProcessingTask>, // Later stages, published by the scheduler once their dependency finishes
+    pub attempts: HashMap<Uuid, u32>, // Number of send attempts made for each task, keyed by task_id
 }
 
 // ============================================================================
@@ -85,27 +420,93 @@ pub trait IntoDatasetTasks {
 impl IntoDatasetTasks for DatasetProcessingJob {
     fn into_dataset_tasks(self) -> Vec<DatasetProcessingTask> {
         let batch_id = self.batch_id.unwrap_or(Uuid::new_v4());
+        let object_tags = tagging::object_tags(self.owner.as_deref(), &self.tags);
+
+        let mut inputs = self.inputs();
+        // A fused job is a single stage running the first operation, followed by all the others
+        let mut operations = self.operations;
+        let fused_operations = match self.pipeline_mode {
+            PipelineMode::Fused if !operations.is_empty() => operations.split_off(1),
+            _ => Vec::new(),
+        };
+        inputs.truncate(operations.len());
+
+        let is_staged = self.pipeline_mode == PipelineMode::Staged;
+        let is_dag = !self.dependencies.is_empty() && is_staged;
+        let task_ids: Vec<Uuid> = operations.iter().map(|_| Uuid::new_v4()).collect();
+
+        let mut upstreams: Vec<Vec<ImageOperation>> = Vec::with_capacity(operations.len());
+        let mut tasks: Vec<DatasetProcessingTask> = Vec::with_capacity(operations.len());
+        for (i, op) in operations.iter().enumerate() {
+            // A chain runs after the previous stage, the stages of a DAG after the ones listed
+            let parents: Vec<usize> = match is_dag {
+                true => {
+                    let mut parents = self.dependencies.get(i).cloned().unwrap_or_default();
+                    parents.retain(|parent| *parent < i);
+                    if inputs[i].is_none() && i > 0 && !parents.contains(&0) {
+                        parents.push(0);
+                    }
+                    parents
+                }
+                false => i.checked_sub(1).into_iter().collect(),
+            };
+            let upstream = match inputs[i] {
+                Some(parent) => {
+                    let mut upstream = upstreams[parent].clone();
+                    upstream.push(operations[parent].clone());
+                    upstream
+                }
+                None => Vec::new(),
+            };
+            let input = is_dag.then(|| match inputs[i] {
+                Some(parent) => StageInput::Stage {
+                    stage: parent as u32,
+                    task_id: task_ids[parent],
+                },
+                None => StageInput::Dataset,
+            });
+
+            tasks.push(DatasetProcessingTask {
+                dataset_key: self.dataset_key.clone(),
+                task_id: task_ids[i],
+                batch_id,
+                operation: op.clone(),
+                fused_operations: fused_operations.clone(),
+                depends_on: parents.iter().map(|parent| task_ids[*parent]).collect(),
+                stage: i as u32,
+                input,
+                upstream_operations: upstream.clone(),
+                requires_approval: is_staged && self.requires_approval.contains(&i),
+                use_local_cache: self.use_local_cache,
+                output_layout: self.output_layout,
+                collision_policy: self.collision_policy,
+                hash_suffix: false,
+                cache_scope: self.cache_scope,
+                default_format: None,
+                alpha_policy: self.alpha_policy,
+                metadata_policy: self.metadata_policy,
+                previews: false,
+                object_tags: object_tags.clone(),
+                priority: self.priority,
+                tenant_id: self.tenant_id.clone(),
+            });
+            upstreams.push(upstream);
+        }
 
-        self.operations
-            .into_iter()
-            .scan((None, 0u32), |state, op| {
-                let (prev_task_id, stage_counter) = state;
-                let task_id = Uuid::new_v4();
-
-                let task = DatasetProcessingTask {
-                    dataset_key: self.dataset_key.clone(),
-                    task_id,
-                    batch_id,
-                    operation: op,
-                    depends_on: *prev_task_id,
-                    stage: *stage_counter,
-                };
-
-                *prev_task_id = Some(task_id);
-                *stage_counter += 1;
-                Some(task)
-            })
-            .collect()
+        // Only the outputs of the stages no other stage reads are published, the last one of a
+        // chain, earlier ones keep stable names. An explicit Convert anywhere on the way to a
+        // result takes precedence over the default format.
+        for (i, task) in tasks.iter_mut().enumerate() {
+            if inputs.contains(&Some(i)) {
+                continue;
+            }
+            let converts =
+                formats::target_format(task.upstream_operations.iter().chain(task.operations()));
+            task.hash_suffix = self.hash_suffix;
+            task.previews = self.previews;
+            task.default_format = self.output_format.filter(|_| converts.is_none());
+        }
+        tasks
     }
 }
 
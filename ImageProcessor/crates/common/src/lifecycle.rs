@@ -0,0 +1,145 @@
+// ============================================================================
+// BATCH LIFECYCLE
+// Every batch moves through a fixed set of states. The legal transitions are
+// defined here so every component (api-server, decomposer, scheduler) agrees
+// on them, and the database layer rejects anything else.
+//
+// Created -> Decomposing -> Processing(0) -> ... -> Processing(n) -> Finalizing -> Completed
+//
+// The stages of a DAG job can run side by side, see `dag`, so Processing can
+// skip stages and shows the latest one released.
+//
+// Any state that isn't final can also move to Failed, Cancelled or TimedOut.
+// A Failed batch can be reopened into Decomposing or Processing to retry its
+// failed tasks, every other final state stays final.
+// ============================================================================
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "state")]
+pub enum BatchState {
+    #[default]
+    Created, // Recorded, the first stage hasn't been picked up yet
+    Decomposing, // The first stage is splitting the archive into image tasks
+    Processing {
+        stage: u32,
+    }, // Images of the given stage are being processed
+    Finalizing,  // Every stage finished, results are being wrapped up
+    Completed,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+impl BatchState {
+    /// Whether the batch can no longer change state
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            BatchState::Completed
+                | BatchState::Failed
+                | BatchState::Cancelled
+                | BatchState::TimedOut
+        )
+    }
+
+    /// Whether moving from this state to `next` is allowed
+    pub fn can_transition_to(&self, next: &BatchState) -> bool {
+        if self.is_final() {
+            return false;
+        }
+
+        match (self, next) {
+            (_, BatchState::Failed | BatchState::Cancelled | BatchState::TimedOut) => true,
+            (BatchState::Created, BatchState::Decomposing) => true,
+            (BatchState::Decomposing, BatchState::Processing { stage: 0 }) => true,
+            (BatchState::Processing { stage }, BatchState::Processing { stage: next }) => {
+                next > stage
+            }
+            (BatchState::Processing { .. }, BatchState::Finalizing) => true,
+            (BatchState::Finalizing, BatchState::Completed) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether a batch in this state can be reopened in `next` to retry its failed tasks
+    pub fn can_reopen_to(&self, next: &BatchState) -> bool {
+        *self == BatchState::Failed
+            && matches!(
+                next,
+                BatchState::Decomposing | BatchState::Processing { .. }
+            )
+    }
+
+    /// Checks a transition, describing why it is rejected
+    pub fn transition(&self, next: BatchState) -> Result<BatchState, String> {
+        match self.can_transition_to(&next) {
+            true => Ok(next),
+            false => Err(format!(
+                "Illegal batch transition from {:?} to {:?}",
+                self, next
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_move_forward_through_their_stages() {
+        let path = [
+            BatchState::Created,
+            BatchState::Decomposing,
+            BatchState::Processing { stage: 0 },
+            BatchState::Processing { stage: 2 },
+            BatchState::Finalizing,
+            BatchState::Completed,
+        ];
+        for step in path.windows(2) {
+            assert_eq!(step[0].transition(step[1]), Ok(step[1]));
+        }
+
+        assert!(
+            !BatchState::Processing { stage: 2 }
+                .can_transition_to(&BatchState::Processing { stage: 1 })
+        );
+        assert!(!BatchState::Created.can_transition_to(&BatchState::Processing { stage: 0 }));
+        assert!(!BatchState::Decomposing.can_transition_to(&BatchState::Finalizing));
+    }
+
+    #[test]
+    fn any_running_batch_can_be_stopped() {
+        for state in [
+            BatchState::Created,
+            BatchState::Processing { stage: 1 },
+            BatchState::Finalizing,
+        ] {
+            for stop in [
+                BatchState::Failed,
+                BatchState::Cancelled,
+                BatchState::TimedOut,
+            ] {
+                assert!(state.can_transition_to(&stop), "{:?} -> {:?}", state, stop);
+            }
+        }
+    }
+
+    #[test]
+    fn finished_batches_stay_finished() {
+        assert!(
+            BatchState::Completed
+                .transition(BatchState::Failed)
+                .is_err()
+        );
+        assert!(!BatchState::Failed.can_transition_to(&BatchState::Decomposing));
+    }
+
+    #[test]
+    fn only_failed_batches_are_reopened() {
+        assert!(BatchState::Failed.can_reopen_to(&BatchState::Processing { stage: 1 }));
+        assert!(BatchState::Failed.can_reopen_to(&BatchState::Decomposing));
+        assert!(!BatchState::Failed.can_reopen_to(&BatchState::Completed));
+        assert!(!BatchState::Cancelled.can_reopen_to(&BatchState::Decomposing));
+    }
+}
@@ -0,0 +1,97 @@
+use std::{str::FromStr, sync::OnceLock};
+
+use tracing_subscriber::{Registry, filter::LevelFilter, fmt, prelude::*, reload};
+
+use crate::config::LoggingConfig;
+
+// ============================================================================
+// LOGGING
+// Every binary logs through `tracing`. Handlers run in spans carrying the
+// batch_id and task_id of their work and the correlation_id of the request
+// behind it (see `correlation`), which the JSON output attaches to every
+// event, so a single batch can be followed across the services. The level
+// can be changed while the process runs, see `control::ControlCommand`.
+// ============================================================================
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" | "warning" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(format!("Unknown log level {}", s)),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+/// How events are written to stdout
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Json, // One object per line, with the fields of the event and its spans
+    Text, // Human readable, for running a service locally
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "text" => Ok(Self::Text),
+            _ => Err(format!("Unknown log format {}", s)),
+        }
+    }
+}
+
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Installs the subscriber every event of the process goes to, once at startup
+pub fn init(config: &LoggingConfig) {
+    let (filter, handle) = reload::Layer::new(LevelFilter::from(config.level));
+    let registry = tracing_subscriber::registry().with(filter);
+    let installed = match config.format {
+        LogFormat::Json => registry.with(fmt::layer().json()).try_init(),
+        LogFormat::Text => registry.with(fmt::layer()).try_init(),
+    };
+
+    match installed {
+        Ok(()) => {
+            let _ = LEVEL.set(handle);
+        }
+        Err(e) => eprintln!("Failed to install the log subscriber: {}", e),
+    }
+}
+
+/// Changes the level of the subscriber installed by `init`
+pub fn set_log_level(level: LogLevel) {
+    let Some(handle) = LEVEL.get() else {
+        return;
+    };
+    if let Err(e) = handle.reload(LevelFilter::from(level)) {
+        tracing::error!(error = %e, "Failed to change the log level");
+    }
+}
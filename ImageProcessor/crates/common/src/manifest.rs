@@ -0,0 +1,91 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::keys::results_key;
+
+// ============================================================================
+// RESULTS MANIFESTS
+// Long batches publish their results in chunks instead of all at once. While
+// the last stage runs, the scheduler regularly writes the outputs finished
+// since the previous manifest to a numbered manifest in the results prefix of
+// the batch and announces it on the manifest topic. Together the manifests of
+// a batch list all of its outputs, the one written when the batch completed
+// is marked `complete`.
+//
+// A completed batch also gets a single manifest.json in its results prefix,
+// listing every input of the dataset with the output, status, size and
+// checksum of each stage it went through, see `BatchManifest`.
+// ============================================================================
+
+/// Message type of the notices on the manifest topic
+pub const MANIFEST_MESSAGE_TYPE: &str = "results_manifest";
+
+/// The key of a manifest of a batch, e.g. `results/{batch}/manifests/00003.json`
+pub fn manifest_key(tenant_id: Option<&str>, batch_id: &uuid::Uuid, sequence: u32) -> String {
+    results_key(tenant_id, batch_id, &format!("manifests/{:05}.json", sequence))
+}
+
+/// The key of the manifest of a completed batch, e.g. `results/{batch}/manifest.json`
+pub fn batch_manifest_key(tenant_id: Option<&str>, batch_id: &uuid::Uuid) -> String {
+    results_key(tenant_id, batch_id, "manifest.json")
+}
+
+/// One output of the batch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub key: String,
+    pub source_path: Option<String>, // The file of the dataset the output was made from
+    pub image_task_id: uuid::Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_hash: Option<String>, // The end of the output's chain, see `provenance`
+}
+
+/// The outputs of a batch that finished since its previous manifest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResultsManifest {
+    pub batch_id: uuid::Uuid,
+    pub sequence: u32, // Counts from 0, a skipped number only means an attempt that wrote nothing
+    pub complete: bool, // The batch completed, no manifest follows
+    pub time_created: DateTime<Utc>,
+    pub entries: Vec<ManifestEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance_root: Option<String>, // On the complete manifest, see `provenance`
+}
+
+/// Published on the manifest topic once a manifest is written
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestNotice {
+    pub batch_id: uuid::Uuid,
+    pub tenant_id: Option<String>,
+    pub sequence: u32,
+    pub complete: bool,
+    pub manifest_key: String,
+    pub entries: usize,
+}
+
+/// Every input of a completed batch and what its stages made of it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchManifest {
+    pub batch_id: uuid::Uuid,
+    pub dataset_key: String,
+    pub time_created: DateTime<Utc>,
+    pub provenance_root: Option<String>, // See `provenance`
+    pub inputs: Vec<BatchManifestInput>, // By path in the dataset
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchManifestInput {
+    pub filename: Option<String>, // Path in the dataset, unset for a single image dataset
+    pub stages: Vec<BatchManifestStage>, // In stage order, up to the one the image stopped at
+}
+
+/// What a stage did with an input
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchManifestStage {
+    pub stage: u32,
+    pub image_task_id: uuid::Uuid,
+    pub status: String, // As in the status endpoints, e.g. "Success" or "Filtered"
+    pub output_key: Option<String>,
+    pub size_bytes: Option<u64>, // Of the output, once written
+    pub sha256: Option<String>,  // Of the output, see `provenance`
+}
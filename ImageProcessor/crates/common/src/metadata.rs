@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// IMAGE METADATA
+// Workers encode the pixels of an image, so whatever metadata the input
+// carried is lost unless its job asks to keep it. A job keeping it has the
+// EXIF block of every input copied into its output, without the GPS position
+// if it asks for that. JPEG, PNG and WebP outputs can hold the block, other
+// formats are written without it. The block describes the uploaded image,
+// operations changing the size or orientation don't rewrite it.
+//
+// Whatever the policy, every image task records what it read from its input,
+// its size and the camera and orientation its EXIF block names.
+// ============================================================================
+
+/// What a job does with the metadata of its images
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataPolicy {
+    Preserve, // The EXIF block is copied as it is
+    #[default]
+    Strip, // Only the pixels are written
+    StripGps, // The EXIF block is copied without the GPS position
+}
+
+impl MetadataPolicy {
+    /// Whether outputs carry the EXIF block of their input
+    pub fn keeps_metadata(&self) -> bool {
+        !matches!(self, MetadataPolicy::Strip)
+    }
+}
+
+/// What a worker read from the input of an image task
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageMetadata {
+    pub width: u32,
+    pub height: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_make: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<u16>, // The EXIF orientation, 1-8, which the pixels are not rotated by
+    #[serde(default)]
+    pub has_gps: bool, // Whether the input's EXIF block holds a GPS position
+}
@@ -0,0 +1,325 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+// ============================================================================
+// METRICS
+// Prometheus metrics shared by every binary, registered in the default
+// registry. The api-server serves them on `/metrics`, the Kafka consumers
+// run `serve` to expose them on a port of their own.
+// ============================================================================
+
+// Buckets in seconds, from a small S3 object to a large archive or image
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+pub static TASKS_PRODUCED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "tasks_produced_total",
+        "Messages published to Kafka",
+        &["topic"]
+    )
+    .expect("Failed to register tasks_produced_total")
+});
+
+pub static TASKS_CONSUMED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "tasks_consumed_total",
+        "Messages received from Kafka",
+        &["topic"]
+    )
+    .expect("Failed to register tasks_consumed_total")
+});
+
+pub static TASKS_FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "tasks_failed_total",
+        "Tasks that failed, by kind (dataset or image)",
+        &["kind"]
+    )
+    .expect("Failed to register tasks_failed_total")
+});
+
+pub static POISON_PILLS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "poison_pills_total",
+        "Messages that couldn't be read or handled, by the policy applied to them",
+        &["topic", "policy"]
+    )
+    .expect("Failed to register poison_pills_total")
+});
+
+pub static TASKS_HELD: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "tasks_held_total",
+        "Messages moved to the holding topic, by the capability the worker lacked",
+        &["topic", "capability"]
+    )
+    .expect("Failed to register tasks_held_total")
+});
+
+pub static IMAGE_TASKS_DEFERRED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "image_tasks_deferred_total",
+        "Image tasks deferred because their batch had as many running as it may"
+    )
+    .expect("Failed to register image_tasks_deferred_total")
+});
+
+pub static UNSERVABLE_HELD_TASKS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "unservable_held_tasks",
+        "Held tasks no live worker can run, by the capability they need, set by the scheduler",
+        &["capability"]
+    )
+    .expect("Failed to register unservable_held_tasks")
+});
+
+pub static S3_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "s3_errors_total",
+        "Failed object storage calls, by kind (e.g. no_such_key, access_denied or slow_down)",
+        &["kind"]
+    )
+    .expect("Failed to register s3_errors_total")
+});
+
+pub static FAULTS_INJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "faults_injected_total",
+        "Faults injected on purpose, by fault (e.g. s3_put or handler_drop), see `faults`",
+        &["fault"]
+    )
+    .expect("Failed to register faults_injected_total")
+});
+
+pub static S3_DOWNLOAD_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "s3_download_seconds",
+        "Time to download an object from S3",
+        LATENCY_BUCKETS.to_vec()
+    )
+    .expect("Failed to register s3_download_seconds")
+});
+
+pub static S3_UPLOAD_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "s3_upload_seconds",
+        "Time to upload an object to S3",
+        LATENCY_BUCKETS.to_vec()
+    )
+    .expect("Failed to register s3_upload_seconds")
+});
+
+pub static IMAGE_PROCESSING_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "image_processing_seconds",
+        "Time to decode, process and encode an image",
+        &["operation"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .expect("Failed to register image_processing_seconds")
+});
+
+pub static IMAGE_BYTES_READ: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "image_bytes_read_total",
+        "Bytes of input images downloaded by the workers, by operation",
+        &["operation"]
+    )
+    .expect("Failed to register image_bytes_read_total")
+});
+
+pub static IMAGE_BYTES_WRITTEN: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "image_bytes_written_total",
+        "Bytes of output images uploaded by the workers, by operation",
+        &["operation"]
+    )
+    .expect("Failed to register image_bytes_written_total")
+});
+
+pub static KAFKA_CONSUMER_LAG: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "kafka_consumer_lag",
+        "Messages between the committed offset and the high watermark",
+        &["topic", "partition"]
+    )
+    .expect("Failed to register kafka_consumer_lag")
+});
+
+pub static KAFKA_CONSUMER_GROUP_LAG: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "kafka_consumer_group_lag",
+        "Messages a consumer group trails a partition by, reported by the scheduler",
+        &["group", "topic", "partition"]
+    )
+    .expect("Failed to register kafka_consumer_group_lag")
+});
+
+pub static KAFKA_PRODUCER_FAILED_OVER: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "kafka_producer_failed_over",
+        "Whether the producer of a topic sends to the secondary cluster (1) or the primary (0)",
+        &["topic"]
+    )
+    .expect("Failed to register kafka_producer_failed_over")
+});
+
+pub static KAFKA_CONSECUTIVE_SEND_FAILURES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "kafka_consecutive_send_failures",
+        "Sends in a row that failed after all retries, by the producer's topic",
+        &["topic"]
+    )
+    .expect("Failed to register kafka_consecutive_send_failures")
+});
+
+pub static KAFKA_FAILOVERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "kafka_failovers_total",
+        "Producers that switched to the secondary cluster, by topic",
+        &["topic"]
+    )
+    .expect("Failed to register kafka_failovers_total")
+});
+
+pub static DATASET_TASKS_RECOVERED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "dataset_tasks_recovered_total",
+        "Dataset tasks whose decomposer lease expired, by outcome (republished or failed)",
+        &["outcome"]
+    )
+    .expect("Failed to register dataset_tasks_recovered_total")
+});
+
+pub static RESULT_CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "result_cache_lookups_total",
+        "Image tasks that found their output in the result cache (hit) or not (miss), by scope",
+        &["scope", "result"]
+    )
+    .expect("Failed to register result_cache_lookups_total")
+});
+
+pub static LOCAL_HANDOFFS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "local_handoffs_total",
+        "Pinned stages that found the previous stage's pixels on local disk (hit) or not (miss)",
+        &["result"]
+    )
+    .expect("Failed to register local_handoffs_total")
+});
+
+pub static INPUT_CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "input_cache_lookups_total",
+        "Inputs the worker found in its input cache, by tier (memory, disk), or missed (none)",
+        &["tier"]
+    )
+    .expect("Failed to register input_cache_lookups_total")
+});
+
+pub static INPUT_CACHE_BYTES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "input_cache_bytes",
+        "Encoded inputs held in the worker's input cache, by tier",
+        &["tier"]
+    )
+    .expect("Failed to register input_cache_bytes")
+});
+
+pub static REQUESTS_RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "api_requests_rate_limited_total",
+        "Requests the api-server turned away with 429, by caller (tenant, admin or anonymous)",
+        &["caller"]
+    )
+    .expect("Failed to register api_requests_rate_limited_total")
+});
+
+pub static WORKER_CONCURRENCY_LIMIT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "worker_concurrency_limit",
+        "Image tasks the worker currently handles at once"
+    )
+    .expect("Failed to register worker_concurrency_limit")
+});
+
+pub static SCHEDULER_LEADER: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "scheduler_leader",
+        "Whether this scheduler instance holds the lease and moves batches along"
+    )
+    .expect("Failed to register scheduler_leader")
+});
+
+pub static PROCESS_RSS_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("process_rss_bytes", "Resident memory of the process")
+        .expect("Failed to register process_rss_bytes")
+});
+
+/// Every registered metric in the Prometheus text format
+pub fn encode() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!(error = %e, "Failed to encode metrics");
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Serves `GET /metrics` on the given address until the process exits.
+///
+/// A bare-bones HTTP listener for binaries that don't run a web server anyway, every other
+/// path gets a 404.
+pub async fn serve(address: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut request = [0u8; 1024];
+            let Ok(read) = stream.read(&mut request).await else {
+                return;
+            };
+
+            let request = String::from_utf8_lossy(&request[..read]);
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            let response = match path {
+                "/metrics" => {
+                    let body = encode();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        TextEncoder::new().format_type(),
+                        body.len(),
+                        body
+                    )
+                }
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+/// Starts `serve` in the background, logging instead of exiting if the address can't be bound
+pub fn spawn_server(address: String) {
+    tokio::spawn(async move {
+        tracing::info!(%address, "Serving metrics");
+        if let Err(e) = serve(&address).await {
+            tracing::error!(%address, error = %e, "Metrics listener stopped");
+        }
+    });
+}
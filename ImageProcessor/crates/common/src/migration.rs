@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// TOPIC MIGRATION
+// Kafka can't shrink or re-key the partitions of an existing topic, so moving
+// to a different partition layout means moving to a new topic. The api-server
+// runs a migration in the background, recording its progress in the
+// `topic_migrations` collection:
+//   1. create the new topic
+//   2. publish a `TopicSwitch` on the migration topic: every service moves its
+//      producers of the old topic to the new one, and its consumers read the
+//      new topic, next to the old one in Drain mode
+//   3. Mirror: copy what the group left unconsumed on the old topic to the new
+//      one, under a group of its own
+//      Drain: wait until the group has no lag left on the old topic, then
+//      publish a second switch that stops consumers reading it
+// Services replay the migration topic when they start, so one started with a
+// config still naming the old topic moves on like the others.
+// ============================================================================
+
+/// How the backlog of the old topic is handled
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum MigrationMode {
+    /// The group's consumers move to the new topic right away: copy the messages they didn't
+    /// consume yet to the new topic
+    Mirror,
+    /// The group's consumers read both topics until they have worked through the old one
+    Drain,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicMigrationPlan {
+    pub from_topic: String,
+    pub to_topic: String,
+    pub partitions: i32,
+    pub group_id: String, // The consumer group reading from the old topic
+    pub mode: MigrationMode,
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout_secs: u64, // How long draining, or mirroring, may take
+    #[serde(default = "default_switch_grace")]
+    pub switch_grace_secs: u64, // Given to the services to switch, longer than any task takes
+}
+
+fn default_drain_timeout() -> u64 {
+    600
+}
+
+fn default_switch_grace() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicMigrationReport {
+    pub from_topic: String,
+    pub to_topic: String,
+    pub messages_mirrored: u64,
+    pub remaining_lag: i64,
+}
+
+/// Why a migration failed
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MigrationError {
+    #[error("Topic {topic} already exists")]
+    TopicExists { topic: String },
+
+    #[error("Timed out moving {topic} ({remaining_lag} messages left)")]
+    Timeout { topic: String, remaining_lag: i64 },
+
+    #[error("Kafka error: {message}")]
+    Kafka { message: String },
+}
+
+impl MigrationError {
+    pub fn kafka(message: impl Into<String>) -> Self {
+        Self::Kafka {
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Moves the producers and consumers of `from_topic` to `to_topic`, published on the migration
+/// topic and applied by every service, see `queue::migration::TopicSwitcher`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicSwitch {
+    pub from_topic: String,
+    pub to_topic: String,
+    pub consume_from: bool, // Consumers keep reading `from_topic` next to `to_topic`
+}
+
+impl TopicSwitch {
+    /// The message type switches are published under
+    pub const MESSAGE_TYPE: &str = "switch_topic";
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+
+// ============================================================================
+// OUTPUT NAMING
+// Decides the name every archive entry is stored under. Flattening the layout
+// drops the folders of the archive, so two entries with the same basename in
+// different folders would write to the same key. Collisions are detected up
+// front and resolved with the job's collision policy.
+// ============================================================================
+
+/// How the folders of the uploaded archive are reflected in the output keys
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputLayout {
+    #[default]
+    KeepStructure, // "cats/01.png" is stored as "cats/01.png"
+    Flatten, // "cats/01.png" is stored as "01.png"
+}
+
+/// What to do when two entries end up with the same output name
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    #[default]
+    Suffix, // The first entry keeps the name, the others get "_1", "_2", ... before the extension
+    Error,         // The stage fails and lists the colliding entries
+    KeepStructure, // Every colliding entry keeps its full path inside the archive
+}
+
+impl OutputLayout {
+    pub fn output_name(&self, path: &str) -> String {
+        match self {
+            OutputLayout::KeepStructure => path.to_string(),
+            OutputLayout::Flatten => path.rsplit('/').next().unwrap_or(path).to_string(),
+        }
+    }
+}
+
+/// Inserts a content hash before the extension, "cats/01.png" becomes "cats/01.3f9a2c1d.png"
+pub fn with_hash_suffix(name: &str, hash: &str) -> String {
+    let (dir, file) = match name.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), name),
+    };
+
+    match file.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}{}.{}.{}", dir, stem, hash, ext),
+        _ => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+/// Replaces the extension of a name, "cats/01.png" becomes "cats/01.webp"
+pub fn with_extension(name: &str, extension: &str) -> String {
+    let (dir, file) = match name.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), name),
+    };
+
+    match file.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => format!("{}{}.{}", dir, stem, extension),
+        _ => format!("{}{}.{}", dir, file, extension),
+    }
+}
+
+/// Computes the output name of every archive entry, in the same order as `paths`.
+///
+/// The result only depends on the list of entries, so every stage of a batch resolves the same
+/// names for the same archive.
+pub fn resolve_output_names(
+    paths: &[String],
+    layout: OutputLayout,
+    policy: CollisionPolicy,
+) -> Result<Vec<String>, String> {
+    let candidates: Vec<String> = paths.iter().map(|p| layout.output_name(p)).collect();
+
+    let mut groups: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, name) in candidates.iter().enumerate() {
+        groups.entry(name.as_str()).or_default().push(i);
+    }
+
+    let mut collisions: Vec<(&str, &Vec<usize>)> = groups
+        .iter()
+        .filter(|(_, entries)| entries.len() > 1)
+        .map(|(name, entries)| (*name, entries))
+        .collect();
+    if collisions.is_empty() {
+        return Ok(candidates);
+    }
+    collisions.sort_by_key(|(_, entries)| entries[0]);
+
+    if policy == CollisionPolicy::Error {
+        let details: Vec<String> = collisions
+            .iter()
+            .map(|(name, entries)| {
+                let sources: Vec<&str> = entries.iter().map(|&i| paths[i].as_str()).collect();
+                format!("{} <- [{}]", name, sources.join(", "))
+            })
+            .collect();
+        return Err(format!("Output name collisions: {}", details.join("; ")));
+    }
+
+    let mut names = candidates.clone();
+    if policy == CollisionPolicy::KeepStructure {
+        for (_, entries) in &collisions {
+            for &i in entries.iter() {
+                names[i] = paths[i].clone();
+            }
+        }
+    }
+
+    // Whatever still collides (every group under Suffix, duplicate archive entries under
+    // KeepStructure) gets a numbered suffix, skipping names taken by any other entry
+    let mut taken: HashSet<String> = names.iter().cloned().collect();
+    let mut seen: HashSet<String> = HashSet::new();
+    for name in names.iter_mut() {
+        if seen.insert(name.clone()) {
+            continue;
+        }
+
+        let (stem, ext) = match name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && !ext.contains('/') => {
+                (stem.to_string(), format!(".{}", ext))
+            }
+            _ => (name.clone(), String::new()),
+        };
+        let renamed = (1..)
+            .map(|n| format!("{}_{}{}", stem, n, ext))
+            .find(|candidate| !taken.contains(candidate))
+            .expect("an unused suffix always exists");
+
+        taken.insert(renamed.clone());
+        seen.insert(renamed.clone());
+        *name = renamed;
+    }
+
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn flattened_collisions_get_numbered_suffixes() {
+        let names = resolve_output_names(
+            &paths(&["cats/01.png", "dogs/01.png", "birds/01.png", "01_1.png"]),
+            OutputLayout::Flatten,
+            CollisionPolicy::Suffix,
+        )
+        .unwrap();
+        // "01_1.png" is taken by an entry of its own, so the suffixes skip it
+        assert_eq!(names, ["01.png", "01_2.png", "01_3.png", "01_1.png"]);
+    }
+
+    #[test]
+    fn collisions_fail_the_stage_under_the_error_policy() {
+        let error = resolve_output_names(
+            &paths(&["cats/01.png", "dogs/01.png", "cats/02.png"]),
+            OutputLayout::Flatten,
+            CollisionPolicy::Error,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            "Output name collisions: 01.png <- [cats/01.png, dogs/01.png]"
+        );
+    }
+
+    #[test]
+    fn colliding_entries_keep_their_folders_under_keep_structure() {
+        let names = resolve_output_names(
+            &paths(&["cats/01.png", "dogs/01.png", "cats/02.png"]),
+            OutputLayout::Flatten,
+            CollisionPolicy::KeepStructure,
+        )
+        .unwrap();
+        assert_eq!(names, ["cats/01.png", "dogs/01.png", "02.png"]);
+    }
+
+    #[test]
+    fn suffixes_go_before_the_extension() {
+        assert_eq!(with_hash_suffix("cats/01.png", "3f9a"), "cats/01.3f9a.png");
+        assert_eq!(with_hash_suffix(".hidden", "3f9a"), ".hidden.3f9a");
+    }
+
+    #[test]
+    fn converted_names_swap_their_extension() {
+        assert_eq!(with_extension("cats/01.png", "webp"), "cats/01.webp");
+        assert_eq!(with_extension("cats/README", "webp"), "cats/README.webp");
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Duration, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// NOTIFICATION PREFERENCES
+// The submitter's webhook of a batch, its `notification_url`, is sent the
+// events its preferences ask for: the batch finishing, only its failures, or
+// every stage finishing on top. Preferences are set on the job, or else on
+// the tenant through the api-server, and default to the batch finishing.
+//
+// Quiet hours hold back the non-critical notifications, the ones that aren't
+// failures, e.g. a batch completing at night. They are dropped, not delayed.
+// The operators' webhooks are sent every event regardless.
+// ============================================================================
+
+/// Which events a submitter's webhook is sent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    #[default]
+    Completion, // The batch finishing, alerts and warnings before its results expire
+    Failures,   // Only a batch failing and storage alerts
+    EveryStage, // Every stage finishing on top of what `Completion` sends
+}
+
+/// Hours of the day in which only critical notifications are sent, from `start_hour` up to
+/// `end_hour`, wrapping past midnight when `end_hour` is the smaller
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start_hour: u32,
+    pub end_hour: u32,
+    #[serde(default)]
+    pub utc_offset_minutes: i32, // Of the time zone the hours are in, e.g. 60 for CET
+}
+
+/// The notification preferences of a job or a tenant
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NotificationPreferences {
+    #[serde(default)]
+    pub notify_on: NotifyOn,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// An event a submitter's webhook may be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationEvent {
+    StageFinished,
+    BatchCompleted, // Finished in any other final state than the failed ones
+    BatchFailed,    // Failed or timed out
+    StorageAlert,
+    QualityDrift, // A stage's outputs drifted from earlier stages of its pipeline, see `drift`
+    ResultsExpiring,
+}
+
+impl NotificationEvent {
+    /// Critical events are sent during quiet hours too
+    pub fn is_critical(&self) -> bool {
+        matches!(
+            self,
+            NotificationEvent::BatchFailed | NotificationEvent::StorageAlert
+        )
+    }
+}
+
+impl QuietHours {
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let hour = (now + Duration::minutes(self.utc_offset_minutes as i64)).hour();
+        match self.start_hour <= self.end_hour {
+            true => hour >= self.start_hour && hour < self.end_hour,
+            false => hour >= self.start_hour || hour < self.end_hour,
+        }
+    }
+}
+
+impl NotificationPreferences {
+    /// Whether the webhook is sent the event at the given time
+    pub fn allows(&self, event: NotificationEvent, now: DateTime<Utc>) -> bool {
+        let wanted = match self.notify_on {
+            NotifyOn::Completion => event != NotificationEvent::StageFinished,
+            NotifyOn::Failures => event.is_critical(),
+            NotifyOn::EveryStage => true,
+        };
+        let quiet = self.quiet_hours.is_some_and(|hours| hours.contains(now));
+        wanted && (event.is_critical() || !quiet)
+    }
+
+    /// Checks the preferences, returning every invalid field with its message
+    pub fn issues(&self) -> Vec<(String, String)> {
+        let Some(hours) = self.quiet_hours else {
+            return Vec::new();
+        };
+        let mut issues = Vec::new();
+        for (field, hour) in [("start_hour", hours.start_hour), ("end_hour", hours.end_hour)] {
+            if hour > 23 {
+                issues.push((
+                    format!("quiet_hours.{}", field),
+                    format!("{} is not an hour of the day, 0 to 23", hour),
+                ));
+            }
+        }
+        if hours.start_hour == hours.end_hour {
+            issues.push((
+                "quiet_hours".to_string(),
+                "Quiet hours have to start and end at different hours".to_string(),
+            ));
+        }
+        // Time zones range from UTC-12 to UTC+14
+        if !(-12 * 60..=14 * 60).contains(&hours.utc_offset_minutes) {
+            issues.push((
+                "quiet_hours.utc_offset_minutes".to_string(),
+                format!("{} is not the offset of a time zone", hours.utc_offset_minutes),
+            ));
+        }
+        issues
+    }
+}
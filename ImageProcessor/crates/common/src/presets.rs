@@ -0,0 +1,171 @@
+use crate::{DatasetProcessingJob, ImageOperation};
+
+// ============================================================================
+// PRESETS
+// Named operation chains for common jobs, shipped with the crate. A job refers
+// to one by name ("web-optimize") or by name and version ("web-optimize@1"),
+// a name alone resolves to the latest version. Published versions are never
+// changed, a different chain is a new version.
+//
+// Tenants save chains of their own as named pipelines, which the api-server
+// keeps in the database and expands like presets, see `pipeline` on the job.
+// ============================================================================
+
+#[derive(serde::Serialize, Debug, Clone, Copy)]
+pub struct Preset {
+    pub name: &'static str,
+    pub version: u32,
+    pub description: &'static str,
+    pub operations: &'static [ImageOperation],
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "web-optimize",
+        version: 1,
+        description: "Fits images within 1920 pixels for serving on the web",
+        operations: &[ImageOperation::ResizeLongEdge {
+            long_edge: 1920,
+            filter: None,
+        }],
+    },
+    Preset {
+        name: "ml-preprocess-224",
+        version: 1,
+        description: "Grayscale 224 pixel crops for training image classifiers",
+        operations: &[
+            ImageOperation::ResizeLongEdge {
+                long_edge: 256,
+                filter: None,
+            },
+            ImageOperation::Crop {
+                x: 16,
+                y: 16,
+                width: 224,
+                height: 224,
+            },
+            ImageOperation::GrayScale,
+        ],
+    },
+    // Version 1 fits the long edge and crops at a fixed offset, which misses the centre of
+    // anything but square images and leaves the crop short on portrait and landscape ones
+    Preset {
+        name: "ml-preprocess-224",
+        version: 2,
+        description: "Grayscale 224 pixel center crops for training image classifiers",
+        operations: &[
+            ImageOperation::ResizeShortEdge {
+                short_edge: 256,
+                filter: None,
+            },
+            ImageOperation::CenterCrop {
+                width: 224,
+                height: 224,
+            },
+            ImageOperation::GrayScale,
+        ],
+    },
+    Preset {
+        name: "anonymize",
+        version: 1,
+        description: "Blurs images heavily enough that faces and text can't be recognized",
+        operations: &[
+            ImageOperation::ResizeLongEdge {
+                long_edge: 1024,
+                filter: None,
+            },
+            ImageOperation::Blur { sigma: 12.0 },
+        ],
+    },
+];
+
+impl Preset {
+    /// The reference that pins this exact version, e.g. "web-optimize@1"
+    pub fn reference(&self) -> String {
+        format!("{}@{}", self.name, self.version)
+    }
+}
+
+/// Looks up a preset by "name" (latest version) or "name@version"
+pub fn find(reference: &str) -> Result<&'static Preset, String> {
+    let (name, version) = match reference.split_once('@') {
+        Some((name, version)) => {
+            let version = version
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid preset version in {}", reference))?;
+            (name, Some(version))
+        }
+        None => (reference, None),
+    };
+
+    PRESETS
+        .iter()
+        .filter(|preset| preset.name == name)
+        .filter(|preset| version.is_none_or(|version| preset.version == version))
+        .max_by_key(|preset| preset.version)
+        .ok_or_else(|| format!("Unknown preset {}", reference))
+}
+
+impl DatasetProcessingJob {
+    /// Replaces the job's preset with its operations, ahead of any operations the job lists
+    /// itself. Returns the preset that was applied, if the job named one.
+    ///
+    /// The operations of a DAG job that read the dataset read the preset's outputs instead.
+    pub fn expand_preset(&mut self) -> Result<Option<&'static Preset>, String> {
+        let Some(reference) = self.preset.take() else {
+            return Ok(None);
+        };
+        let preset = find(&reference)?;
+        self.prepend_operations(preset.operations);
+        Ok(Some(preset))
+    }
+
+    /// Runs the operations ahead of the job's own. The operations of a DAG job that read the
+    /// dataset read the outputs of the last one instead.
+    pub fn prepend_operations(&mut self, operations: &[ImageOperation]) {
+        let count = operations.len();
+        if !self.dependencies.is_empty() && count > 0 {
+            for parents in &mut self.dependencies {
+                match parents.is_empty() {
+                    true => parents.push(count - 1),
+                    false => parents.iter_mut().for_each(|parent| *parent += count),
+                }
+            }
+            let chain = (0..count).map(|i| i.checked_sub(1).into_iter().collect());
+            self.dependencies.splice(0..0, chain);
+        }
+        self.requires_approval
+            .iter_mut()
+            .for_each(|operation| *operation += count);
+        self.operations.splice(0..0, operations.iter().cloned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dimensions::Dimensions;
+
+    #[test]
+    fn name_alone_resolves_to_the_latest_version() {
+        assert_eq!(find("ml-preprocess-224").unwrap().version, 2);
+        assert_eq!(find("ml-preprocess-224@1").unwrap().version, 1);
+        assert!(find("ml-preprocess-224@3").is_err());
+    }
+
+    #[test]
+    fn ml_preprocess_crops_224_pixels_from_any_aspect_ratio() {
+        let preset = find("ml-preprocess-224").unwrap();
+        for input in [
+            Dimensions { width: 1920, height: 1080 },
+            Dimensions { width: 480, height: 640 },
+            Dimensions { width: 300, height: 300 },
+        ] {
+            let output = preset
+                .operations
+                .iter()
+                .fold(input, |dims, operation| operation.output_dimensions(dims));
+            assert_eq!(output, Dimensions { width: 224, height: 224 }, "from {:?}", input);
+        }
+    }
+}
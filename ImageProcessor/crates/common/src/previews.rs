@@ -0,0 +1,31 @@
+use crate::tenancy::tenant_key;
+
+// ============================================================================
+// PREVIEWS
+// A job with `previews` gets a small thumbnail of every final output, so a UI
+// can show a grid of results without downloading full-resolution images. The
+// image worker running the last stage of an image writes its thumbnail next to
+// the output, as a square JPEG of `previews.size` pixels named after the image
+// task. A thumbnail that can't be written only leaves a gap in the grid, the
+// output itself is unaffected.
+// ============================================================================
+
+/// The prefix holding the thumbnails of a batch, e.g. `previews/{batch}/`
+pub fn previews_prefix(tenant_id: Option<&str>, batch_id: &uuid::Uuid) -> String {
+    tenant_key(tenant_id, &format!("previews/{}/", batch_id))
+}
+
+/// The key of the thumbnail of an image task's output
+pub fn preview_key(
+    tenant_id: Option<&str>,
+    batch_id: &uuid::Uuid,
+    image_task_id: &uuid::Uuid,
+) -> String {
+    format!("{}{}.jpg", previews_prefix(tenant_id, batch_id), image_task_id)
+}
+
+/// The image task a thumbnail was made for, `None` for keys not written by `preview_key`
+pub fn preview_task_id(prefix: &str, key: &str) -> Option<uuid::Uuid> {
+    let name = key.strip_prefix(prefix)?.strip_suffix(".jpg")?;
+    uuid::Uuid::parse_str(name).ok()
+}
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// OPERATION PROFILES
+// The scheduler keeps a moving average of how long the image tasks of every
+// operation take, updated whenever a stage finishes. Stages that become ready
+// together are released cheapest first, and the image workers' memory
+// thresholds are lowered ahead of expensive operations: their tasks hold
+// decoded images for longer, so memory keeps climbing for a while after the
+// governor stopped taking new tasks.
+// ============================================================================
+
+/// Shares of the memory ceiling at which an image worker's memory governor acts
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MemoryThresholds {
+    pub grow_below: f64,   // Another task is allowed below this share
+    pub shrink_at: f64,    // One task is taken away at or above this share
+    pub pause_at: f64,     // Consumption pauses at or above this share
+    pub resume_below: f64, // and resumes once below this one
+}
+
+impl Default for MemoryThresholds {
+    fn default() -> Self {
+        Self {
+            grow_below: 0.7,
+            shrink_at: 0.85,
+            pause_at: 1.0,
+            resume_below: 1.0,
+        }
+    }
+}
+
+// The thresholds of the slowest operations are this much lower than the defaults
+const MAX_HEADROOM: f64 = 0.25;
+// Operations this many times slower than the reference get the full headroom
+const MAX_SLOWDOWN: f64 = 4.0;
+
+impl MemoryThresholds {
+    /// Thresholds for an operation taking `cost_ms` per image. Operations up to `reference_ms`
+    /// get the defaults, slower ones proportionally more headroom up to `MAX_HEADROOM`.
+    pub fn for_cost(cost_ms: f64, reference_ms: f64) -> Self {
+        let slowdown = (cost_ms / reference_ms.max(1.0)).clamp(1.0, MAX_SLOWDOWN);
+        let scale = 1.0 - MAX_HEADROOM * (slowdown - 1.0) / (MAX_SLOWDOWN - 1.0);
+
+        let defaults = Self::default();
+        Self {
+            grow_below: defaults.grow_below * scale,
+            shrink_at: defaults.shrink_at * scale,
+            pause_at: defaults.pause_at * scale,
+            resume_below: defaults.resume_below * scale,
+        }
+    }
+
+    /// Whether the thresholds are ordered and within the ceiling
+    pub fn is_valid(&self) -> bool {
+        0.0 < self.grow_below
+            && self.grow_below <= self.shrink_at
+            && self.shrink_at <= self.pause_at
+            && self.resume_below <= self.pause_at
+            && self.pause_at <= 1.0
+    }
+}
+
+/// Folds the mean latency of a finished stage into the moving average of its operation.
+/// `smoothing` is the weight of the new stage, between 0 and 1.
+pub fn moving_average(previous: Option<f64>, observed: f64, smoothing: f64) -> f64 {
+    match previous {
+        Some(previous) => previous + smoothing.clamp(0.0, 1.0) * (observed - previous),
+        None => observed,
+    }
+}
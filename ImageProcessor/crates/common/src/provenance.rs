@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::keys::results_key;
+
+// ============================================================================
+// PROVENANCE
+// Every image task a worker runs records the SHA-256 of its encoded input, of
+// the operations it applied and of its encoded output, and links them to the
+// record of the stage its input came from:
+//
+//   chain_hash = SHA-256(previous + "\n" + input + "\n" + operations + "\n" + output)
+//
+// with every hash in lowercase hex and `previous` empty for a stage reading the
+// uploaded dataset. Changing any input, operation or output of an image, in any
+// stage, changes the chain hash of its final output.
+//
+// Once a batch completes, the chain hashes of its final outputs are folded into
+// a Merkle root: sorted, then hashed in pairs as SHA-256(left + right) level by
+// level, an odd hash out moving up unchanged. The root is stored on the batch
+// and written with every link of the batch to provenance.json in its results,
+// so consumers of the results can recompute both from the files they received.
+// ============================================================================
+
+/// The key of the provenance file of a batch, e.g. `results/{batch}/provenance.json`
+pub fn provenance_key(tenant_id: Option<&str>, batch_id: &uuid::Uuid) -> String {
+    results_key(tenant_id, batch_id, "provenance.json")
+}
+
+/// The link an image task adds to the chain of its image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageProvenance {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>, // Chain hash of the task the input came from
+    pub input_hash: String,
+    pub operations_hash: String, // Of the operations, output format and policies applied
+    pub output_hash: String,
+    pub chain_hash: String,
+}
+
+impl ImageProvenance {
+    pub fn new(
+        previous: Option<String>,
+        input_hash: String,
+        operations_hash: String,
+        output_hash: String,
+    ) -> Self {
+        let chain_hash = chain_hash(
+            previous.as_deref(),
+            &input_hash,
+            &operations_hash,
+            &output_hash,
+        );
+        Self {
+            previous,
+            input_hash,
+            operations_hash,
+            output_hash,
+            chain_hash,
+        }
+    }
+}
+
+/// Every link of a completed batch and the root over its final outputs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchProvenance {
+    pub batch_id: uuid::Uuid,
+    pub root: Option<String>, // None when no final output recorded a link
+    pub links: Vec<ProvenanceLink>, // By stage, then path in the dataset
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProvenanceLink {
+    pub image_task_id: uuid::Uuid,
+    pub stage: u32,
+    pub source_path: Option<String>,
+    pub output_key: Option<String>,
+    pub is_result: bool, // A final output, its chain hash is a leaf of the root
+    #[serde(flatten)]
+    pub provenance: ImageProvenance,
+}
+
+/// SHA-256 of the bytes in lowercase hex
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// The chain hash of a link, see the top of the module
+pub fn chain_hash(
+    previous: Option<&str>,
+    input_hash: &str,
+    operations_hash: &str,
+    output_hash: &str,
+) -> String {
+    let link = [previous.unwrap_or_default(), input_hash, operations_hash, output_hash];
+    sha256_hex(link.join("\n").as_bytes())
+}
+
+/// The Merkle root over the chain hashes of a batch's final outputs, `None` without any
+pub fn batch_root(mut chain_hashes: Vec<String>) -> Option<String> {
+    chain_hashes.sort();
+    while chain_hashes.len() > 1 {
+        chain_hashes = chain_hashes
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => sha256_hex(format!("{}{}", left, right).as_bytes()),
+                [odd] => odd.clone(),
+                _ => unreachable!("chunks hold one or two hashes"),
+            })
+            .collect();
+    }
+    chain_hashes.pop()
+}
@@ -0,0 +1,306 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::lifecycle::BatchState;
+use crate::keys::results_key;
+
+// ============================================================================
+// BATCH REPORTS
+// Once a batch reached a final state, the alerting service writes a summary
+// of it for people rather than scripts: image counts and durations per stage,
+// the failed images with thumbnails, an estimate of what the batch cost and
+// links to its results. It is stored as Markdown and HTML next to the results
+// and referenced by the notice sent to the batch's webhook, so it can be
+// passed around as is.
+// ============================================================================
+
+/// The key of a report of a batch, e.g. `results/{batch}/report.html`
+pub fn report_key(tenant_id: Option<&str>, batch_id: &uuid::Uuid, extension: &str) -> String {
+    results_key(tenant_id, batch_id, &format!("report.{}", extension))
+}
+
+/// Summary of a finished batch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchReport {
+    pub batch_id: uuid::Uuid,
+    pub dataset_key: String,
+    pub owner: Option<String>,
+    pub state: BatchState,
+    pub time_created: DateTime<Utc>,
+    pub time_completed: DateTime<Utc>,
+    pub stages: Vec<StageSummary>,
+    pub failures: Vec<FailureSummary>, // The first failed images, see `failures_total`
+    pub failures_total: usize,
+    pub cost: Option<CostEstimate>, // Without configured rates there is nothing to estimate
+    pub links: Vec<ReportLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StageSummary {
+    pub stage: u32,
+    pub operation: String,
+    pub images: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    #[serde(default)]
+    pub filtered: u64, // Left out by a quality gate, neither succeeded nor failed
+    pub duration_secs: Option<i64>, // From the end of the previous stage, or the submission
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailureSummary {
+    pub filename: String,
+    pub stage: u32,
+    pub error: String,
+    pub thumbnail_url: Option<String>, // Presigned link to the image the stage failed on
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CostEstimate {
+    pub compute_hours: f64, // Summed latencies of the image tasks that succeeded
+    pub gb_written: f64,
+    pub amount: f64,
+    pub currency: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReportLink {
+    pub label: String,
+    pub url: String,
+}
+
+/// Sent to the batch's webhook once its report was written
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchFinishedNotice {
+    pub batch_id: uuid::Uuid,
+    pub state: BatchState,
+    pub images: u64,
+    pub failed: u64,
+    pub report_html_key: String,
+    pub report_markdown_key: String,
+    pub report_url: Option<String>, // Presigned link to the HTML report
+    pub message: String,
+}
+
+impl BatchReport {
+    pub fn images(&self) -> u64 {
+        self.stages.first().map_or(0, |stage| stage.images)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.stages.iter().map(|stage| stage.failed).sum()
+    }
+
+    pub fn duration_secs(&self) -> i64 {
+        (self.time_completed - self.time_created).num_seconds().max(0)
+    }
+
+    fn title(&self) -> String {
+        format!("Batch {} {}", self.batch_id, state_name(&self.state))
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = format!("# {}\n\n", self.title());
+
+        out.push_str(&format!("- Dataset: `{}`\n", self.dataset_key));
+        if let Some(owner) = &self.owner {
+            out.push_str(&format!("- Owner: {}\n", owner));
+        }
+        out.push_str(&format!(
+            "- Submitted: {}\n- Finished: {} ({})\n- Images: {}, {} failed\n",
+            self.time_created.to_rfc3339(),
+            self.time_completed.to_rfc3339(),
+            format_duration(self.duration_secs()),
+            self.images(),
+            self.failed()
+        ));
+        if let Some(cost) = &self.cost {
+            out.push_str(&format!("- Estimated cost: {}\n", cost.summary()));
+        }
+
+        out.push_str("\n## Stages\n\n");
+        out.push_str("| Stage | Operation | Images | Succeeded | Failed | Filtered | Duration |\n");
+        out.push_str("|---|---|---|---|---|---|---|\n");
+        for stage in &self.stages {
+            out.push_str(&format!(
+                "| {} | {} | {} | {} | {} | {} | {} |\n",
+                stage.stage,
+                stage.operation,
+                stage.images,
+                stage.succeeded,
+                stage.failed,
+                stage.filtered,
+                stage.duration_secs.map_or("-".to_string(), format_duration)
+            ));
+        }
+
+        if !self.failures.is_empty() {
+            out.push_str(&format!("\n## Failures ({})\n\n", self.failures_total));
+            for failure in &self.failures {
+                out.push_str(&format!(
+                    "- `{}` in stage {}: {}",
+                    failure.filename, failure.stage, failure.error
+                ));
+                if let Some(url) = &failure.thumbnail_url {
+                    out.push_str(&format!(" ([image]({}))", url));
+                }
+                out.push('\n');
+            }
+            if self.failures_total > self.failures.len() {
+                out.push_str(&format!(
+                    "- and {} more\n",
+                    self.failures_total - self.failures.len()
+                ));
+            }
+        }
+
+        if !self.links.is_empty() {
+            out.push_str("\n## Links\n\n");
+            for link in &self.links {
+                out.push_str(&format!("- [{}]({})\n", link.label, link.url));
+            }
+        }
+
+        out
+    }
+
+    pub fn to_html(&self) -> String {
+        let title = escape_html(&self.title());
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+             <style>body{{font-family:sans-serif;max-width:960px;margin:2em auto}}\
+             table{{border-collapse:collapse}}td,th{{border:1px solid #ccc;padding:4px 8px}}\
+             img{{max-width:96px;max-height:96px}}</style>\n</head>\n<body>\n<h1>{}</h1>\n<ul>\n",
+            title, title
+        );
+
+        out.push_str(&format!(
+            "<li>Dataset: <code>{}</code></li>\n",
+            escape_html(&self.dataset_key)
+        ));
+        if let Some(owner) = &self.owner {
+            out.push_str(&format!("<li>Owner: {}</li>\n", escape_html(owner)));
+        }
+        out.push_str(&format!(
+            "<li>Submitted: {}</li>\n<li>Finished: {} ({})</li>\n<li>Images: {}, {} failed</li>\n",
+            self.time_created.to_rfc3339(),
+            self.time_completed.to_rfc3339(),
+            format_duration(self.duration_secs()),
+            self.images(),
+            self.failed()
+        ));
+        if let Some(cost) = &self.cost {
+            out.push_str(&format!(
+                "<li>Estimated cost: {}</li>\n",
+                escape_html(&cost.summary())
+            ));
+        }
+        out.push_str("</ul>\n");
+
+        out.push_str(
+            "<h2>Stages</h2>\n<table>\n<tr><th>Stage</th><th>Operation</th><th>Images</th>\
+             <th>Succeeded</th><th>Failed</th><th>Filtered</th><th>Duration</th></tr>\n",
+        );
+        for stage in &self.stages {
+            out.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+                 <td>{}</td></tr>\n",
+                stage.stage,
+                escape_html(&stage.operation),
+                stage.images,
+                stage.succeeded,
+                stage.failed,
+                stage.filtered,
+                stage.duration_secs.map_or("-".to_string(), format_duration)
+            ));
+        }
+        out.push_str("</table>\n");
+
+        if !self.failures.is_empty() {
+            out.push_str(&format!(
+                "<h2>Failures ({})</h2>\n<table>\n\
+                 <tr><th></th><th>File</th><th>Stage</th><th>Error</th></tr>\n",
+                self.failures_total
+            ));
+            for failure in &self.failures {
+                let thumbnail = failure.thumbnail_url.as_ref().map_or(String::new(), |url| {
+                    let url = escape_html(url);
+                    format!("<a href=\"{}\"><img src=\"{}\" alt=\"\"></a>", url, url)
+                });
+                out.push_str(&format!(
+                    "<tr><td>{}</td><td><code>{}</code></td><td>{}</td><td>{}</td></tr>\n",
+                    thumbnail,
+                    escape_html(&failure.filename),
+                    failure.stage,
+                    escape_html(&failure.error)
+                ));
+            }
+            out.push_str("</table>\n");
+            if self.failures_total > self.failures.len() {
+                out.push_str(&format!(
+                    "<p>and {} more</p>\n",
+                    self.failures_total - self.failures.len()
+                ));
+            }
+        }
+
+        if !self.links.is_empty() {
+            out.push_str("<h2>Links</h2>\n<ul>\n");
+            for link in &self.links {
+                out.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    escape_html(&link.url),
+                    escape_html(&link.label)
+                ));
+            }
+            out.push_str("</ul>\n");
+        }
+
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+}
+
+impl CostEstimate {
+    fn summary(&self) -> String {
+        format!(
+            "{:.2} {} ({:.2} compute hours, {:.2} GB written)",
+            self.amount, self.currency, self.compute_hours, self.gb_written
+        )
+    }
+}
+
+fn state_name(state: &BatchState) -> &'static str {
+    match state {
+        BatchState::Completed => "completed",
+        BatchState::Failed => "failed",
+        BatchState::Cancelled => "was cancelled",
+        BatchState::TimedOut => "timed out",
+        _ => "is still running",
+    }
+}
+
+/// A duration like "1h 02m 03s"
+fn format_duration(secs: i64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs % 3600 / 60, secs % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", secs),
+        (0, _) => format!("{}m {:02}s", minutes, secs),
+        _ => format!("{}h {:02}m {:02}s", hours, minutes, secs),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
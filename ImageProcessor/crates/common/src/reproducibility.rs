@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::ImageOperation;
+use crate::formats::{DEFAULT_QUALITY, OutputFormat};
+use crate::resampling::effective_filter;
+
+// ============================================================================
+// CONFIGURATION SNAPSHOTS
+// Every component that touches a batch records the configuration it ran with,
+// so a batch can be re-run with identical settings long after it completed.
+// ============================================================================
+
+/// Version of the S3 key layout used for stage outputs. Bump whenever the layout changes.
+pub const KEY_SCHEME_VERSION: u32 = 1;
+
+/// The effective configuration of one component at the time it handled a batch
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ConfigSnapshot {
+    pub component: String,         // e.g. "img-api-server" or "consumers"
+    pub component_version: String, // Crate version of the component
+    pub stage: Option<u32>,        // The stage being processed, None for submission
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<uuid::Uuid>, // The dataset task being processed, None for submission
+    pub key_scheme_version: u32,
+    pub codec_versions: BTreeMap<String, String>, // Versions of the libraries that read/write images
+    pub settings: BTreeMap<String, String>,       // Buckets, topics, limits, defaults, ...
+    #[serde(with = "crate::timestamp")]
+    pub captured_at: DateTime<Utc>,
+}
+
+impl ConfigSnapshot {
+    pub fn new(component: &str, component_version: &str) -> Self {
+        Self {
+            component: component.to_string(),
+            component_version: component_version.to_string(),
+            stage: None,
+            task_id: None,
+            key_scheme_version: KEY_SCHEME_VERSION,
+            codec_versions: BTreeMap::new(),
+            settings: BTreeMap::new(),
+            captured_at: Utc::now(),
+        }
+    }
+
+    pub fn for_stage(mut self, stage: u32) -> Self {
+        self.stage = Some(stage);
+        self
+    }
+
+    /// Snapshot of the processing of a dataset task, recorded once however often it is delivered
+    pub fn for_task(mut self, task_id: uuid::Uuid) -> Self {
+        self.task_id = Some(task_id);
+        self
+    }
+
+    pub fn with_codec(mut self, name: &str, version: &str) -> Self {
+        self.codec_versions
+            .insert(name.to_string(), version.to_string());
+        self
+    }
+
+    pub fn with_setting(mut self, name: &str, value: impl ToString) -> Self {
+        self.settings.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Records the operations with the parameters they leave to defaults filled in, so a re-run
+    /// doesn't depend on defaults that changed since
+    pub fn with_operations<'a>(
+        mut self,
+        operations: impl IntoIterator<Item = &'a ImageOperation>,
+    ) -> Self {
+        for (i, operation) in operations.into_iter().enumerate() {
+            record_operation(&mut self.settings, &format!("operations[{}]", i), operation);
+        }
+        self
+    }
+
+    /// Records the format outputs are encoded in when no operation converts them
+    pub fn with_output_format(self, format: Option<OutputFormat>) -> Self {
+        match format {
+            Some(format) => self.with_setting(
+                "output_format",
+                format!("{:?} quality {}", format, DEFAULT_QUALITY),
+            ),
+            None => self.with_setting("output_format", "format of the input"),
+        }
+    }
+}
+
+/// Records an operation under `key`, and the filter of a resize naming none under `key.filter`
+fn record_operation(
+    settings: &mut BTreeMap<String, String>,
+    key: &str,
+    operation: &ImageOperation,
+) {
+    settings.insert(key.to_string(), format!("{:?}", operation));
+
+    match operation {
+        ImageOperation::Resize {
+            scaling_factor,
+            filter: None,
+        } => {
+            let filter = effective_filter(None, *scaling_factor > 1.0);
+            settings.insert(format!("{}.filter", key), filter);
+        }
+        // The direction depends on the size of each image
+        ImageOperation::ResizeLongEdge { filter: None, .. }
+        | ImageOperation::ResizeShortEdge { filter: None, .. } => {
+            let filter = format!(
+                "{} when downscaling, {} when upscaling",
+                effective_filter(None, false),
+                effective_filter(None, true)
+            );
+            settings.insert(format!("{}.filter", key), filter);
+        }
+        ImageOperation::ByResolution { rules } => {
+            for (j, rule) in rules.iter().enumerate() {
+                let key = format!("{}.rules[{}].operation", key, j);
+                record_operation(settings, &key, &rule.operation);
+            }
+        }
+        _ => {}
+    }
+}
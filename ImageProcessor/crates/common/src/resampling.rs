@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// RESAMPLING
+// Resizes use the filter their operation names, or the one for their
+// direction when it names none. Downscales average every source pixel an
+// output pixel covers, so fine detail like text, fabric or foliage turns into
+// its average shade instead of aliasing into moiré. Upscales interpolate with
+// Lanczos3, the sharpest of the filters. Thumbnails are downscales too.
+// ============================================================================
+
+/// The filter a resize samples the source image with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResampleFilter {
+    Nearest,    // Copies the closest pixel, keeps hard edges, e.g. of pixel art and masks
+    Bilinear,   // Blends the 2x2 closest pixels, fast and soft
+    Lanczos3,   // Windowed sinc over 6x6 pixels, sharp with slight ringing
+    CatmullRom, // Cubic over 4x4 pixels, between Bilinear and Lanczos3
+}
+
+/// Name of the filter a resize with `filter` samples with, in the direction `upscale` tells
+pub fn effective_filter(filter: Option<ResampleFilter>, upscale: bool) -> String {
+    match (filter, upscale) {
+        (Some(filter), _) => format!("{:?}", filter),
+        (None, false) => "Area".to_string(), // Averages the source pixels, see the header
+        (None, true) => format!("{:?}", ResampleFilter::Lanczos3),
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tenancy::hash_api_key;
+
+// ============================================================================
+// SHARED RESULTS
+// The owner of a batch can share it with someone without an API key through a
+// link holding a random token. Opening it lists presigned links to the
+// batch's thumbnails, or to all of its results, depending on the scope the
+// link was created with. Only the token's hash is stored, like for managed API
+// keys, so a link can't be recovered or forged from the database. A link
+// stops working once it expired or its batch was deleted.
+// ============================================================================
+
+/// What a shared link gives access to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShareScope {
+    #[default]
+    Previews, // The thumbnails of the outputs, see `previews`
+    Results, // Every object the batch published
+}
+
+/// A new share token, two random UUIDs' worth of entropy
+pub fn generate_share_token() -> String {
+    format!(
+        "shr_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// What is stored of a share token and looked up when a link is opened
+pub fn hash_share_token(token: &str) -> String {
+    hash_api_key(token)
+}
@@ -0,0 +1,47 @@
+use crate::keys::stage_key;
+use crate::naming::OutputLayout;
+
+// ============================================================================
+// DATASET SIDECARS
+// Datasets for training often carry a file describing their images next to
+// them, e.g. a `labels.csv` mapping filenames to classes. The decomposer keeps
+// the files whose name is in `decomposer.sidecar_names` instead of skipping
+// them, and once the batch completed the scheduler writes them next to the
+// final outputs, in the same place relative to the images as in the upload.
+// Outputs can be renamed on the way (formats, hash suffixes, flattening), so
+// a job with `rewrite_sidecars` gets the filename references of its CSV
+// sidecars replaced by the names the images ended up with. Other formats,
+// e.g. Parquet, are kept as they are.
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SidecarFormat {
+    Csv,
+    Other,
+}
+
+/// Whether a file of a dataset is a sidecar, by its file name
+pub fn is_sidecar(path: &str, names: &[String]) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    names.iter().any(|name| name.eq_ignore_ascii_case(file_name))
+}
+
+impl SidecarFormat {
+    pub fn from_path(path: &str) -> Self {
+        match path.to_lowercase().ends_with(".csv") {
+            true => SidecarFormat::Csv,
+            false => SidecarFormat::Other,
+        }
+    }
+}
+
+/// Where a sidecar is written next to the final outputs of a dataset, whose stages write under
+/// `output_prefix` and whose last stage is `last_stage`
+pub fn sidecar_output_key(
+    output_prefix: &str,
+    last_stage: u32,
+    layout: OutputLayout,
+    path: &str,
+) -> String {
+    stage_key(output_prefix, last_stage + 1, &layout.output_name(path))
+}
@@ -0,0 +1,72 @@
+use crate::config::SloTarget;
+
+// ============================================================================
+// LATENCY SLOS
+// Latency of an image task is the time between a worker picking it up and the
+// task finishing. The scheduler aggregates it per operation into rolling
+// windows, and checks every finished batch against the targets declared in
+// the config.
+// ============================================================================
+
+/// Latency percentiles of a set of image tasks, in milliseconds
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyPercentiles {
+    pub count: u64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl LatencyPercentiles {
+    /// Computes the percentiles of the given latencies, `None` if there are none
+    pub fn from_samples(mut samples: Vec<f64>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_by(f64::total_cmp);
+
+        Some(Self {
+            count: samples.len() as u64,
+            p50_ms: nearest_rank(&samples, 0.50),
+            p95_ms: nearest_rank(&samples, 0.95),
+            p99_ms: nearest_rank(&samples, 0.99),
+        })
+    }
+}
+
+/// The smallest sample at least `quantile` of the samples are less than or equal to
+fn nearest_rank(sorted: &[f64], quantile: f64) -> f64 {
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// A percentile that exceeded its target
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct SloViolation {
+    pub operation: String,
+    pub percentile: String, // "p50", "p95" or "p99"
+    pub target_ms: f64,
+    pub observed_ms: f64,
+}
+
+impl SloTarget {
+    /// The percentiles of `operation` that are above their target
+    pub fn violations(&self, operation: &str, observed: &LatencyPercentiles) -> Vec<SloViolation> {
+        [
+            ("p50", self.p50_ms, observed.p50_ms),
+            ("p95", self.p95_ms, observed.p95_ms),
+            ("p99", self.p99_ms, observed.p99_ms),
+        ]
+        .into_iter()
+        .filter_map(|(percentile, target, observed_ms)| {
+            let target_ms = target?;
+            (observed_ms > target_ms).then(|| SloViolation {
+                operation: operation.to_string(),
+                percentile: percentile.to_string(),
+                target_ms,
+                observed_ms,
+            })
+        })
+        .collect()
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+
+// ============================================================================
+// OBJECT TAGGING
+// Every object a batch writes carries the job's tags and owner as S3 object
+// tags, so bucket lifecycle rules and cost allocation can key on them without
+// looking the batch up. S3 allows at most 10 tags per object.
+// ============================================================================
+
+pub const MAX_OBJECT_TAGS: usize = 10;
+const MAX_KEY_LEN: usize = 128;
+const MAX_VALUE_LEN: usize = 256;
+pub const OWNER_TAG: &str = "owner";
+
+/// The tags written on the objects of a job, the owner is stored under `OWNER_TAG`
+pub fn object_tags(
+    owner: Option<&str>,
+    tags: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let mut object_tags = tags.clone();
+    if let Some(owner) = owner {
+        object_tags.insert(OWNER_TAG.to_string(), owner.to_string());
+    }
+
+    object_tags
+}
+
+/// Checks the tags of a job against the limits S3 puts on object tags
+pub fn validate_object_tags(
+    owner: Option<&str>,
+    tags: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    if owner.is_some() && tags.contains_key(OWNER_TAG) {
+        return Err(format!("The {} tag is set from the job's owner", OWNER_TAG));
+    }
+
+    let object_tags = object_tags(owner, tags);
+    if object_tags.len() > MAX_OBJECT_TAGS {
+        return Err(format!(
+            "A job can have at most {} tags including its owner, got {}",
+            MAX_OBJECT_TAGS,
+            object_tags.len()
+        ));
+    }
+
+    for (key, value) in &object_tags {
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(format!(
+                "Tag keys must be 1 to {} characters: {}",
+                MAX_KEY_LEN, key
+            ));
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(format!(
+                "The value of tag {} is longer than {} characters",
+                key, MAX_VALUE_LEN
+            ));
+        }
+        if key.starts_with("aws:") {
+            return Err(format!("Tag {} uses the reserved aws: prefix", key));
+        }
+    }
+
+    Ok(())
+}
+
+/// The tags in the URL query form of the `x-amz-tagging` header, `None` when there are none
+pub fn tagging_header(tags: &BTreeMap<String, String>) -> Option<String> {
+    if tags.is_empty() {
+        return None;
+    }
+
+    let pairs: Vec<String> = tags
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}={}",
+                urlencoding::encode(key),
+                urlencoding::encode(value)
+            )
+        })
+        .collect();
+    Some(pairs.join("&"))
+}
@@ -0,0 +1,92 @@
+// ============================================================================
+// TENANCY
+// With authentication enabled every job belongs to the tenant whose API key
+// submitted it. The tenant id is copied onto every task and database document
+// of the job, and every object of the tenant is stored below its own prefix
+// so a job can only read datasets its tenant uploaded. Jobs submitted without
+// authentication have no tenant and keep the unprefixed keys.
+//
+// Tenants come from the API keys of the config, or are onboarded through the
+// admin endpoints of the api-server, which keep them in the database with
+// their quotas and API keys. Only the SHA-256 of a managed key is stored, the
+// key itself is returned once when it is issued.
+// ============================================================================
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const MAX_TENANT_ID_LEN: usize = 64;
+
+/// Checks that a tenant id can be used as a segment of an object key
+pub fn validate_tenant_id(tenant_id: &str) -> Result<(), String> {
+    if tenant_id.is_empty() || tenant_id.len() > MAX_TENANT_ID_LEN {
+        return Err(format!(
+            "Tenant ids must be 1 to {} characters: {}",
+            MAX_TENANT_ID_LEN, tenant_id
+        ));
+    }
+    if !tenant_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Tenant ids may only contain letters, digits, '-' and '_': {}",
+            tenant_id
+        ));
+    }
+
+    Ok(())
+}
+
+/// Prefix of every object of a tenant, empty without a tenant
+pub fn tenant_prefix(tenant_id: Option<&str>) -> String {
+    match tenant_id {
+        Some(tenant_id) => format!("tenants/{}/", tenant_id),
+        None => String::new(),
+    }
+}
+
+/// The key of an object of the tenant, e.g. `tenants/acme/uploads/cats/input.zip`
+pub fn tenant_key(tenant_id: Option<&str>, key: &str) -> String {
+    format!("{}{}", tenant_prefix(tenant_id), key)
+}
+
+/// The key relative to the tenant's prefix, `None` if the object doesn't belong to the tenant
+pub fn strip_tenant_prefix<'a>(tenant_id: Option<&str>, key: &'a str) -> Option<&'a str> {
+    key.strip_prefix(&tenant_prefix(tenant_id))
+}
+
+/// Whether the API keys of an onboarded tenant are accepted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TenantStatus {
+    #[default]
+    Active,
+    Suspended, // Its keys are rejected, its batches and objects are kept
+}
+
+/// Limits of an onboarded tenant, nothing is limited by default
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TenantQuotas {
+    pub max_active_batches: Option<u64>, // Batches that haven't reached a final state
+    pub max_dataset_bytes: Option<u64>,  // Tighter than the api-server's own limit, if set
+}
+
+/// A new managed API key, two random UUIDs' worth of entropy
+pub fn generate_api_key() -> String {
+    format!(
+        "ipk_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// What is stored of a managed API key and looked up when it is presented
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// The start of an API key, enough for an operator to tell the keys of a tenant apart
+pub fn api_key_hint(key: &str) -> String {
+    key.chars().take(12).collect()
+}
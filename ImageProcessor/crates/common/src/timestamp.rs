@@ -0,0 +1,82 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serializer};
+
+// ============================================================================
+// STORED TIMESTAMPS
+// Timestamps are stored in MongoDB as RFC 3339 strings with millisecond
+// precision. The fixed width makes them order the same as strings and as
+// times, which a variable number of fractional digits doesn't: `...:00Z` sorts
+// after `...:00.5Z`. Stored fields serialize through this module with
+// `#[serde(with = "common::timestamp")]`, and queries format their bounds with
+// `format` so both sides of a comparison have the same precision.
+//
+// Any RFC 3339 string still deserializes, so timestamps written before keep
+// reading back.
+// ============================================================================
+
+/// The stored form of a timestamp, e.g. `2025-01-31T12:00:00.000Z`
+pub fn format(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// The stored form of the current time
+pub fn now() -> String {
+    format(&Utc::now())
+}
+
+pub fn serialize<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(time))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    DateTime::<Utc>::deserialize(deserializer)
+}
+
+/// Like the parent module, for optional timestamps. Fields using it need `#[serde(default)]` to
+/// read documents without them.
+pub mod optional {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        time: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => serializer.serialize_some(&super::format(time)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Option::<DateTime<Utc>>::deserialize(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn stored_timestamps_order_like_times() {
+        let whole_second = Utc.with_ymd_and_hms(2025, 1, 31, 12, 0, 0).unwrap();
+        let later = whole_second + chrono::Duration::milliseconds(500);
+
+        assert_eq!(format(&whole_second), "2025-01-31T12:00:00.000Z");
+        assert!(format(&whole_second) < format(&later));
+    }
+
+    #[test]
+    fn any_rfc3339_string_reads_back() {
+        let time: DateTime<Utc> =
+            deserialize(serde::de::value::StrDeserializer::<serde::de::value::Error>::new(
+                "2025-01-31T12:00:00.123456789Z",
+            ))
+            .unwrap();
+
+        assert_eq!(format(&time), "2025-01-31T12:00:00.123Z");
+    }
+}
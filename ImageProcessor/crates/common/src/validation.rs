@@ -0,0 +1,406 @@
+use crate::ImageOperation;
+use crate::formats::OutputFormat;
+
+// ============================================================================
+// PIPELINE VALIDATION
+// Semantic checks on the ordering of operations in a pipeline. Each pair of
+// consecutive operations is looked up in a small compatibility matrix, and the
+// colour state of the image is tracked so colour-dependent operations can be
+// rejected once the image has been reduced to a single channel.
+// ============================================================================
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueSeverity {
+    Warning,
+    Error,
+}
+
+/// A single problem found while validating a pipeline
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct PipelineIssue {
+    pub severity: IssueSeverity,
+    pub stage: u32, // Index of the operation that triggered the issue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>, // Path of the offending field in the job, e.g. "operations[1].scaling_factor"
+    pub message: String,
+}
+
+/// Result of validating a full pipeline
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+pub struct PipelineReport {
+    pub issues: Vec<PipelineIssue>,
+}
+
+impl PipelineReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|issue| issue.severity == IssueSeverity::Error)
+    }
+
+    pub fn warnings(&self) -> Vec<PipelineIssue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == IssueSeverity::Warning)
+            .cloned()
+            .collect()
+    }
+
+    pub fn errors(&self) -> Vec<PipelineIssue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == IssueSeverity::Error)
+            .cloned()
+            .collect()
+    }
+}
+
+/// A problem with one of the parameters of an operation
+pub struct ParameterIssue {
+    pub field: String, // Path of the parameter inside the operation, e.g. "rules[0].operation.sigma"
+    pub message: String,
+}
+
+impl ParameterIssue {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+// Upscaling further only interpolates, and a 10000px image would become a gigapixel one
+pub const MAX_SCALING_FACTOR: f32 = 8.0;
+// Standard deviation of the noise as a share of the full channel range, beyond it most pixels clip
+pub const MAX_NOISE_LEVEL: f32 = 1.0;
+
+// Entropy in bits of an image using all 256 luma levels equally often
+pub const MAX_ENTROPY: f64 = 8.0;
+
+// JPEG quality below which the 8x8 blocks of the compression become visible
+pub const LOW_JPEG_QUALITY: u8 = 50;
+
+/// How a pair of consecutive operations interact
+pub enum Compatibility {
+    Compatible,
+    Warn(&'static str),
+    Incompatible(&'static str),
+}
+
+impl ImageOperation {
+    /// Whether the operation only makes sense on an image that still has colour information
+    pub fn requires_color(&self) -> bool {
+        match self {
+            // Inverting a single channel only flips its brightness
+            ImageOperation::InvertColors => true,
+            ImageOperation::Resize { .. }
+            | ImageOperation::GrayScale
+            | ImageOperation::Noise { .. }
+            | ImageOperation::Crop { .. }
+            | ImageOperation::Rotate { .. }
+            | ImageOperation::Blur { .. }
+            | ImageOperation::BlurPerMegapixel { .. }
+            | ImageOperation::ResizeLongEdge { .. }
+            | ImageOperation::ResizeShortEdge { .. }
+            | ImageOperation::CenterCrop { .. }
+            | ImageOperation::Convert { .. }
+            | ImageOperation::QualityGate { .. } => false,
+            ImageOperation::ByResolution { rules } => {
+                rules.iter().any(|rule| rule.operation.requires_color())
+            }
+        }
+    }
+
+    /// Whether the output of the operation is a single-channel image
+    pub fn produces_grayscale(&self) -> bool {
+        match self {
+            ImageOperation::GrayScale => true,
+            // Only grayscale for every image if every bucket converts and no image falls through
+            ImageOperation::ByResolution { rules } => {
+                rules.iter().any(|rule| rule.max_megapixels.is_none())
+                    && rules.iter().all(|rule| rule.operation.produces_grayscale())
+            }
+            _ => false,
+        }
+    }
+
+    /// Problems with the parameters of the operation itself, independent of the pipeline
+    pub fn parameter_issues(&self) -> Vec<ParameterIssue> {
+        let mut issues = Vec::new();
+
+        match self {
+            ImageOperation::Resize { scaling_factor, .. }
+                if !scaling_factor.is_finite()
+                    || *scaling_factor <= 0.0
+                    || *scaling_factor > MAX_SCALING_FACTOR =>
+            {
+                issues.push(ParameterIssue::new(
+                    "scaling_factor",
+                    format!(
+                        "Resize needs a scaling_factor above 0 and at most {}",
+                        MAX_SCALING_FACTOR
+                    ),
+                ));
+            }
+            ImageOperation::Noise { noise_level }
+                if !noise_level.is_finite()
+                    || *noise_level < 0.0
+                    || *noise_level > MAX_NOISE_LEVEL =>
+            {
+                issues.push(ParameterIssue::new(
+                    "noise_level",
+                    format!("Noise needs a noise_level between 0 and {}", MAX_NOISE_LEVEL),
+                ));
+            }
+            ImageOperation::Crop { width, height, .. } if *width == 0 || *height == 0 => {
+                let field = if *width == 0 { "width" } else { "height" };
+                issues.push(ParameterIssue::new(
+                    field,
+                    "Crop needs a width and height of at least 1 pixel",
+                ));
+            }
+            ImageOperation::Rotate { degrees } if !degrees.is_finite() => {
+                issues.push(ParameterIssue::new(
+                    "degrees",
+                    "Rotate needs a finite number of degrees",
+                ));
+            }
+            ImageOperation::Blur { sigma } if !sigma.is_finite() || *sigma <= 0.0 => {
+                issues.push(ParameterIssue::new("sigma", "Blur needs a positive sigma"));
+            }
+            ImageOperation::Convert { quality, .. } if !(1..=100).contains(quality) => {
+                issues.push(ParameterIssue::new(
+                    "quality",
+                    "Convert needs a quality between 1 and 100",
+                ));
+            }
+            ImageOperation::QualityGate {
+                max_blur_score,
+                min_entropy,
+                ..
+            } => {
+                if max_blur_score.is_some_and(|max| !(0.0..=1.0).contains(&max)) {
+                    issues.push(ParameterIssue::new(
+                        "max_blur_score",
+                        "QualityGate needs a max_blur_score between 0 and 1",
+                    ));
+                }
+                if min_entropy.is_some_and(|min| !(0.0..=MAX_ENTROPY).contains(&min)) {
+                    issues.push(ParameterIssue::new(
+                        "min_entropy",
+                        format!("QualityGate needs a min_entropy between 0 and {}", MAX_ENTROPY),
+                    ));
+                }
+            }
+            ImageOperation::ByResolution { rules } => {
+                for (i, rule) in rules.iter().enumerate() {
+                    issues.extend(rule.operation.parameter_issues().into_iter().map(|issue| {
+                        ParameterIssue {
+                            field: format!("rules[{}].operation.{}", i, issue.field),
+                            message: issue.message,
+                        }
+                    }));
+                }
+            }
+            _ => {}
+        }
+
+        issues
+    }
+
+    fn is_resize(&self) -> bool {
+        matches!(
+            self,
+            ImageOperation::Resize { .. }
+                | ImageOperation::ResizeLongEdge { .. }
+                | ImageOperation::ResizeShortEdge { .. }
+        )
+    }
+}
+
+/// The compatibility matrix between an operation and the one that directly follows it
+pub fn compatibility(prev: &ImageOperation, next: &ImageOperation) -> Compatibility {
+    match (prev, next) {
+        (ImageOperation::GrayScale, ImageOperation::GrayScale) => {
+            Compatibility::Warn("GrayScale applied twice in a row, the second one has no effect")
+        }
+        (ImageOperation::InvertColors, ImageOperation::InvertColors) => {
+            Compatibility::Warn("InvertColors applied twice in a row cancels itself out")
+        }
+        (ImageOperation::Noise { .. }, next) if next.is_resize() => Compatibility::Warn(
+            "Resize after Noise resamples the noise, consider adding noise after resizing",
+        ),
+        (
+            ImageOperation::Noise { .. },
+            ImageOperation::Blur { .. } | ImageOperation::BlurPerMegapixel { .. },
+        ) => Compatibility::Warn("Blur after Noise smooths most of the noise away"),
+        (
+            ImageOperation::Convert {
+                format: OutputFormat::Jpeg,
+                quality,
+            },
+            ImageOperation::Noise { .. },
+        ) if *quality < LOW_JPEG_QUALITY => Compatibility::Warn(
+            "Noise after a low quality JPEG conversion amplifies its compression artifacts",
+        ),
+        (ImageOperation::Convert { .. }, ImageOperation::Convert { .. }) => {
+            Compatibility::Warn("Convert applied twice in a row, only the second format is kept")
+        }
+        _ => Compatibility::Compatible,
+    }
+}
+
+/// Validates the ordering of operations in a pipeline and returns every issue found.
+///
+/// Errors mean the pipeline cannot produce a meaningful result and should be rejected,
+/// warnings are returned to the client alongside the dispatched job.
+pub fn validate_pipeline(operations: &[ImageOperation]) -> PipelineReport {
+    let inputs: Vec<Option<usize>> = (0..operations.len()).map(|i| i.checked_sub(1)).collect();
+    validate_graph(operations, &inputs)
+}
+
+/// Validates the operations of a DAG job like `validate_pipeline`, every operation against the
+/// ones on the way from the dataset to it. `inputs[i]` is the operation whose outputs operation
+/// `i` reads, see `DatasetProcessingJob::inputs`.
+pub fn validate_graph(operations: &[ImageOperation], inputs: &[Option<usize>]) -> PipelineReport {
+    let mut report = PipelineReport::default();
+    let mut is_grayscale: Vec<bool> = Vec::with_capacity(operations.len());
+
+    for (stage, op) in operations.iter().enumerate() {
+        let input = inputs.get(stage).copied().flatten().filter(|input| *input < stage);
+        let input_grayscale = input.is_some_and(|input| is_grayscale[input]);
+        let stage = stage as u32;
+
+        // GrayScale can be several stages back, so the colour state is carried along from the
+        // dataset to every operation instead of being part of the pairwise matrix
+        if input_grayscale && op.requires_color() {
+            report.issues.push(PipelineIssue {
+                severity: IssueSeverity::Error,
+                stage,
+                field: Some(format!("operations[{}]", stage)),
+                message: format!(
+                    "{:?} requires colour information but the image is grayscale at this point",
+                    op
+                ),
+            });
+        }
+
+        for issue in op.parameter_issues() {
+            report.issues.push(PipelineIssue {
+                severity: IssueSeverity::Error,
+                stage,
+                field: Some(format!("operations[{}].{}", stage, issue.field)),
+                message: issue.message,
+            });
+        }
+        for message in op.adaptive_issues() {
+            report.issues.push(PipelineIssue {
+                severity: IssueSeverity::Error,
+                stage,
+                field: Some(format!("operations[{}]", stage)),
+                message,
+            });
+        }
+
+        if let Some(input) = input {
+            let prev = &operations[input];
+            match compatibility(prev, op) {
+                Compatibility::Compatible => {}
+                Compatibility::Warn(message) => report.issues.push(PipelineIssue {
+                    severity: IssueSeverity::Warning,
+                    stage,
+                    field: Some(format!("operations[{}]", stage)),
+                    message: message.to_string(),
+                }),
+                Compatibility::Incompatible(message) => report.issues.push(PipelineIssue {
+                    severity: IssueSeverity::Error,
+                    stage,
+                    field: Some(format!("operations[{}]", stage)),
+                    message: message.to_string(),
+                }),
+            }
+        }
+
+        is_grayscale.push(input_grayscale || op.produces_grayscale());
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg(quality: u8) -> ImageOperation {
+        ImageOperation::Convert {
+            format: OutputFormat::Jpeg,
+            quality,
+        }
+    }
+
+    #[test]
+    fn invert_after_grayscale_is_an_error() {
+        let report = validate_pipeline(&[ImageOperation::GrayScale, ImageOperation::InvertColors]);
+
+        assert!(report.has_errors());
+        assert_eq!(report.errors()[0].stage, 1);
+    }
+
+    #[test]
+    fn colour_state_is_carried_across_stages() {
+        let report = validate_pipeline(&[
+            ImageOperation::GrayScale,
+            ImageOperation::Blur { sigma: 1.0 },
+            ImageOperation::InvertColors,
+        ]);
+
+        assert_eq!(report.errors().len(), 1);
+        assert_eq!(report.errors()[0].stage, 2);
+    }
+
+    #[test]
+    fn invert_before_grayscale_is_valid() {
+        let report = validate_pipeline(&[ImageOperation::InvertColors, ImageOperation::GrayScale]);
+
+        assert!(report.issues.is_empty());
+    }
+
+    #[test]
+    fn noise_after_low_quality_jpeg_warns() {
+        let report = validate_pipeline(&[jpeg(30), ImageOperation::Noise { noise_level: 0.1 }]);
+
+        assert!(!report.has_errors());
+        assert_eq!(report.warnings().len(), 1);
+        assert_eq!(report.warnings()[0].stage, 1);
+    }
+
+    #[test]
+    fn noise_after_high_quality_jpeg_is_valid() {
+        let report = validate_pipeline(&[jpeg(90), ImageOperation::Noise { noise_level: 0.1 }]);
+
+        assert!(report.issues.is_empty());
+    }
+
+    #[test]
+    fn invalid_parameters_point_at_their_field() {
+        let report = validate_pipeline(&[ImageOperation::Resize {
+            scaling_factor: MAX_SCALING_FACTOR + 1.0,
+            filter: None,
+        }]);
+
+        assert_eq!(
+            report.errors()[0].field.as_deref(),
+            Some("operations[0].scaling_factor")
+        );
+    }
+
+    #[test]
+    fn graph_checks_operations_against_their_own_input() {
+        // Both branches read the dataset, so the grayscale one doesn't affect the other
+        let operations = [ImageOperation::GrayScale, ImageOperation::InvertColors];
+        let report = validate_graph(&operations, &[None, None]);
+
+        assert!(report.issues.is_empty());
+    }
+}
@@ -0,0 +1,37 @@
+// Exposes the resolved versions of the image codec crates as environment variables, so they can
+// be recorded in the configuration snapshot of every batch this consumer processes.
+use std::{env, fs, path::Path};
+
+const CODEC_CRATES: [(&str, &str); 4] = [
+    ("zip", "ZIP_VERSION"),
+    ("tar", "TAR_VERSION"),
+    ("flate2", "FLATE2_VERSION"),
+    ("imagesize", "IMAGESIZE_VERSION"),
+];
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let lockfile = Path::new(&manifest_dir).join("../../Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lockfile.display());
+
+    let contents = fs::read_to_string(&lockfile).unwrap_or_default();
+    for (name, var) in CODEC_CRATES {
+        println!(
+            "cargo:rustc-env={}={}",
+            var,
+            locked_version(&contents, name)
+        );
+    }
+}
+
+/// Finds the version of a package in Cargo.lock
+fn locked_version(lockfile: &str, name: &str) -> String {
+    let needle = format!("name = \"{}\"", name);
+    lockfile
+        .lines()
+        .skip_while(|line| *line != needle)
+        .nth(1)
+        .and_then(|line| line.strip_prefix("version = \""))
+        .map(|version| version.trim_end_matches('"').to_string())
+        .unwrap_or("unknown".to_string())
+}
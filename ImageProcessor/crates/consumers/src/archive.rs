@@ -0,0 +1,268 @@
+use common::datasets::DatasetFormat;
+use common::error::ProcessorError;
+use common::formats::MAGIC_BYTES;
+use common::inspection::ArchiveEntry;
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use zip::ZipArchive;
+
+// ============================================================================
+// DATASET SOURCES
+// Where the decomposer reads the images of a dataset from. Archives are
+// spooled to disk first, a prefix of loose images is only listed. Every source
+// lists its files up front, with their first bytes for telling the images
+// from the other files, so output name collisions are resolved before
+// anything is uploaded, and archives are then read in the listed order. A
+// gzipped tar can't seek, it is decompressed once to list its files and once
+// more to read them, skipping over what isn't read.
+// ============================================================================
+
+/// The files of an archive, read in the order they are listed
+pub(crate) trait ArchiveReader: Send {
+    /// Paths of every file in the archive, directories excluded
+    fn files(&self) -> &[String];
+
+    /// Every entry of the archive as its listing describes it, for `common::inspection`
+    fn entries(&self) -> &[ArchiveEntry];
+
+    /// The first `MAGIC_BYTES` bytes of the file at `index` of `files`, fewer if they couldn't
+    /// be read
+    fn header(&self, index: usize) -> &[u8];
+
+    /// Extracts the file at `index` of `files` into memory. Files have to be read in ascending
+    /// order.
+    fn read(&mut self, index: usize) -> Result<Vec<u8>, ProcessorError>;
+}
+
+/// The images of a dataset of many images
+pub(crate) enum DatasetSource {
+    Archive(Box<dyn ArchiveReader>),
+    Prefix {
+        prefix: String,
+        keys: Vec<String>,     // Keys of the loose images below the prefix
+        headers: Vec<Vec<u8>>, // The first bytes of every key, empty if they couldn't be read
+    },
+}
+
+impl DatasetSource {
+    /// Paths of every file of the dataset, relative to the prefix for loose images
+    pub(crate) fn files(&self) -> Vec<String> {
+        match self {
+            DatasetSource::Archive(reader) => reader.files().to_vec(),
+            DatasetSource::Prefix { prefix, keys, .. } => keys
+                .iter()
+                .map(|key| key.strip_prefix(prefix.as_str()).unwrap_or(key).to_string())
+                .collect(),
+        }
+    }
+
+    /// The first bytes of the file at `index` of `files`, see `ArchiveReader::header`
+    pub(crate) fn header(&self, index: usize) -> &[u8] {
+        match self {
+            DatasetSource::Archive(reader) => reader.header(index),
+            DatasetSource::Prefix { headers, .. } => &headers[index],
+        }
+    }
+}
+
+/// The first `MAGIC_BYTES` bytes of a file. An unreadable one, e.g. an encrypted zip entry, fails
+/// when it is read instead.
+fn read_header(file: impl Read) -> Vec<u8> {
+    let mut header = Vec::with_capacity(MAGIC_BYTES);
+    let _ = file.take(MAGIC_BYTES as u64).read_to_end(&mut header);
+    header
+}
+
+/// Opens an archive spooled to disk
+pub(crate) fn open_archive(
+    format: DatasetFormat,
+    file: File,
+) -> Result<Box<dyn ArchiveReader>, ProcessorError> {
+    match format {
+        DatasetFormat::Zip => Ok(Box::new(ZipReader::new(file)?)),
+        DatasetFormat::TarGz => Ok(Box::new(TarGzReader::new(file)?)),
+        format => Err(ProcessorError::Internal(format!(
+            "{:?} datasets aren't archives",
+            format
+        ))),
+    }
+}
+
+struct ZipReader {
+    archive: ZipArchive<File>,
+    entries: Vec<ArchiveEntry>,
+    files: Vec<String>,
+    headers: Vec<Vec<u8>>, // The first bytes of every file
+    indices: Vec<usize>,   // Position of every file among the archive's entries
+}
+
+impl ZipReader {
+    fn new(file: File) -> Result<Self, ProcessorError> {
+        let mut archive = ZipArchive::new(file).map_err(|e| {
+            ProcessorError::Validation(format!("Failed to read zip archive: {}", e))
+        })?;
+
+        let mut entries = Vec::new();
+        let mut files = Vec::new();
+        let mut headers = Vec::new();
+        let mut indices = Vec::new();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).map_err(|e| {
+                ProcessorError::Validation(format!("Failed to get file from zip: {}", e))
+            })?;
+            entries.push(ArchiveEntry {
+                path: file.name().to_string(),
+                size: file.size(),
+                is_file: !file.is_dir(),
+            });
+            if file.is_dir() {
+                continue;
+            }
+            files.push(file.name().to_string());
+            headers.push(read_header(&mut file));
+            indices.push(i);
+        }
+
+        Ok(Self {
+            archive,
+            entries,
+            files,
+            headers,
+            indices,
+        })
+    }
+}
+
+impl ArchiveReader for ZipReader {
+    fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    fn header(&self, index: usize) -> &[u8] {
+        &self.headers[index]
+    }
+
+    fn read(&mut self, index: usize) -> Result<Vec<u8>, ProcessorError> {
+        let name = &self.files[index];
+        let mut file = self.archive.by_index(self.indices[index]).map_err(|e| {
+            ProcessorError::Validation(format!("Failed to get file from zip: {}", e))
+        })?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).map_err(|e| {
+            ProcessorError::Validation(format!("Failed to read {} from zip: {}", name, e))
+        })?;
+        Ok(buf)
+    }
+}
+
+struct TarGzReader {
+    entries: Vec<ArchiveEntry>,
+    files: Vec<String>,
+    headers: Vec<Vec<u8>>,  // The first bytes of every file
+    spans: Vec<(u64, u64)>, // Offset and size of every file in the decompressed archive
+    stream: MultiGzDecoder<File>,
+    position: Option<u64>, // Bytes of the decompressed archive read, unknown after a failed read
+}
+
+impl TarGzReader {
+    fn new(mut file: File) -> Result<Self, ProcessorError> {
+        let invalid =
+            |e: io::Error| ProcessorError::Validation(format!("Failed to read tar archive: {}", e));
+
+        let mut entries = Vec::new();
+        let mut files = Vec::new();
+        let mut headers = Vec::new();
+        let mut spans = Vec::new();
+        let mut archive = tar::Archive::new(MultiGzDecoder::new(&mut file));
+        for entry in archive.entries().map_err(invalid)? {
+            let mut entry = entry.map_err(invalid)?;
+            let path = entry.path().map_err(invalid)?.to_string_lossy().into_owned();
+            let is_file = entry.header().entry_type().is_file();
+            entries.push(ArchiveEntry {
+                path: path.clone(),
+                size: entry.size(),
+                is_file,
+            });
+            if !is_file {
+                continue;
+            }
+            files.push(path);
+            spans.push((entry.raw_file_position(), entry.size()));
+            headers.push(read_header(&mut entry));
+        }
+        drop(archive);
+
+        file.seek(SeekFrom::Start(0))
+            .map_err(|e| ProcessorError::Internal(format!("Failed to rewind archive: {}", e)))?;
+        Ok(Self {
+            entries,
+            files,
+            headers,
+            spans,
+            stream: MultiGzDecoder::new(file),
+            position: Some(0),
+        })
+    }
+}
+
+impl ArchiveReader for TarGzReader {
+    fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    fn header(&self, index: usize) -> &[u8] {
+        &self.headers[index]
+    }
+
+    fn read(&mut self, index: usize) -> Result<Vec<u8>, ProcessorError> {
+        let name = &self.files[index];
+        let (offset, size) = self.spans[index];
+        let position = match self.position {
+            Some(position) if position <= offset => position,
+            Some(_) => {
+                return Err(ProcessorError::Internal(format!(
+                    "{} was read out of order",
+                    name
+                )))
+            }
+            None => {
+                return Err(ProcessorError::Validation(format!(
+                    "Failed to read {} from tar, an earlier file was unreadable",
+                    name
+                )))
+            }
+        };
+        let failed = |e: io::Error| {
+            ProcessorError::Validation(format!("Failed to read {} from tar: {}", name, e))
+        };
+
+        // Only set again once the whole file was read
+        self.position = None;
+        let skipped = io::copy(
+            &mut (&mut self.stream).take(offset - position),
+            &mut io::sink(),
+        )
+        .map_err(failed)?;
+        let mut buf = Vec::new();
+        (&mut self.stream)
+            .take(size)
+            .read_to_end(&mut buf)
+            .map_err(failed)?;
+        if skipped != offset - position || buf.len() as u64 != size {
+            return Err(failed(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        self.position = Some(offset + size);
+        Ok(buf)
+    }
+}
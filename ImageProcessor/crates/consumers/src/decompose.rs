@@ -0,0 +1,1115 @@
+use crate::archive::{self, DatasetSource};
+use crate::spool;
+use crate::store::DecomposerStore;
+use crate::utils::ConsumerAppState;
+use bytes::Bytes;
+use common::budgets::{BudgetCheckpoint, DecompositionBudget};
+use common::correlation;
+use common::datasets::DatasetFormat;
+use common::error::{ProcessorError, S3ErrorKind};
+use common::lifecycle::BatchState;
+use common::metrics;
+use common::dimensions::{propagate_dimensions, Dimensions};
+use common::envelope::MessageEnvelope;
+use common::events::BatchEventKind;
+use common::failures::FailureKind;
+use common::formats::{converted_name, image_input_format, InputFormat, MAGIC_BYTES};
+use common::inspection::{inspect_entries, ArchiveEntry};
+use common::keys::{image_stage_prefix, sidecar_key, stage_key};
+use common::naming::resolve_output_names;
+use common::reproducibility::ConfigSnapshot;
+use common::sidecars::is_sidecar;
+use common::{DatasetProcessingTask, ImageOperation, ImageTask};
+use db_utils::types::{DBImageTask, DatasetSidecar, TaskStatus};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use queue::ProducerClient;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::{PutOptions, StorageBackend};
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{error, info, warn, Instrument};
+
+// ============================================================================
+// DECOMPOSITION
+// Turns a dataset task, one stage of a batch on an archive, a prefix of loose
+// images or a single image, into one image task per image. What it reads and
+// records goes through `DecomposerStore`, see `store`.
+// ============================================================================
+
+pub const DECOMPOSER_GROUP: &str = "decompose-tasks";
+
+// Enough of the file for imagesize to find the dimensions of any supported format
+const IMAGE_HEADER_BYTES: u64 = 64 * 1024;
+
+/// Where the stages of the task's batch write their images, see `common::keys`
+fn stage_prefix(msg: &DatasetProcessingTask) -> Option<String> {
+    common::keys::stage_prefix(msg.tenant_id.as_deref(), &msg.dataset_key, &msg.batch_id)
+}
+
+/// Decomposes a dataset of many images, an archive or a prefix of loose images, into image tasks
+async fn process_images(
+    msg: DatasetProcessingTask,
+    state: Arc<ConsumerAppState>,
+    dataset_key: &str,
+    format: DatasetFormat,
+    input_formats: &[InputFormat],
+) -> Result<u64, ProcessorError> {
+    let stage_prefix = Arc::new(stage_prefix(&msg).ok_or_else(|| {
+        ProcessorError::Validation(format!("{} is not a dataset upload key", dataset_key))
+    })?);
+    let stage = msg.stage;
+    let input_folder = msg.input_folder();
+    let mut budget = DecompositionBudget::from_config(&state.config.decomposer);
+
+    let mut source = match format {
+        DatasetFormat::Prefix => {
+            let keys = storage::retry_throttled(|| state.storage.list(dataset_key)).await?;
+            let concurrency = state.config.decomposer.max_concurrent_uploads.max(1);
+            let headers = read_headers(Arc::clone(&state.storage), keys.clone(), concurrency).await;
+            DatasetSource::Prefix {
+                prefix: dataset_key.to_string(),
+                keys,
+                headers,
+            }
+        }
+        format => {
+            let download_timer = metrics::S3_DOWNLOAD_SECONDS.start_timer();
+            let download = async {
+                let body = storage::retry_throttled(|| state.storage.get_object_stream(dataset_key))
+                    .await?;
+                // The archive is written to disk instead of memory, only the entries being
+                // uploaded are buffered
+                spool::spool_to_tempfile(body).await
+            };
+            // A download crawling along can't outlast the budget either
+            let archive = match budget.within(download).await {
+                Ok(archive) => archive?,
+                Err(exceeded) => {
+                    let checkpoint = budget.checkpoint(exceeded, 0, 0, None);
+                    return Err(exceed_budget(&state, &msg.task_id, checkpoint).await);
+                }
+            };
+            download_timer.observe_duration();
+            let reader = archive::open_archive(format, archive)?;
+            // Later stages read what the first one wrote, the upload was checked by then
+            if stage == 0 {
+                validate_dataset(&state, &msg.batch_id, reader.entries()).await?;
+            }
+            DatasetSource::Archive(reader)
+        }
+    };
+    let mut tasks_in_queue: FuturesUnordered<JoinHandle<ImageOutcome>> = FuturesUnordered::new();
+
+    // First, we list the images of the dataset so output name collisions can be resolved
+    // before anything is uploaded. Only the first stage keeps the sidecars.
+    let mut entries: Vec<(usize, String)> = Vec::new();
+    let mut sidecars: VecDeque<(usize, String)> = VecDeque::new();
+    let mut skipped: u64 = 0;
+    for (i, filename) in source.files().into_iter().enumerate() {
+        if stage == 0 && is_sidecar(&filename, &state.config.decomposer.sidecar_names) {
+            sidecars.push_back((i, filename));
+            continue;
+        }
+        // Images are told by their content, whatever their name
+        if image_input_format(source.header(i), &filename, input_formats).is_none() {
+            skipped += 1;
+            continue;
+        }
+        entries.push((i, filename));
+    }
+    if skipped > 0 {
+        info!(skipped, "Left out files that aren't images of an allowed format");
+    }
+
+    // The names are resolved once in the format the stage reads and once in the one it writes,
+    // a conversion can make entries like "01.png" and "01.jpg" collide from this stage on
+    let paths: Vec<String> = entries.iter().map(|(_, name)| name.clone()).collect();
+    let names_in = |format| -> Result<Vec<String>, ProcessorError> {
+        let converted: Vec<String> = paths.iter().map(|p| converted_name(p, format)).collect();
+        resolve_output_names(&converted, msg.output_layout, msg.collision_policy)
+            .map_err(ProcessorError::Validation)
+    };
+    let output_format = msg.output_format();
+    let input_names = names_in(msg.input_format())?;
+    let output_names = names_in(output_format)?;
+
+    let image_count = entries.len() as u64;
+    let upstream_operations = Arc::new(msg.upstream_operations.clone());
+    // Every operation of a fused stage, for the size of its single output
+    let stage_operations: Arc<Vec<ImageOperation>> = Arc::new(msg.operations().cloned().collect());
+    let max_in_flight = state.config.decomposer.max_buffered_images.max(1);
+    let mut outcome = ArchiveOutcome::default();
+    // Counts the images registered before the current one, for a budget checkpoint
+    let numbered = (0_u64..).zip(entries.into_iter().zip(input_names).zip(output_names));
+    for (registered, (((i, filename), input_name), output_name)) in numbered {
+        // The images in flight are finished before the task fails, so the checkpoint is accurate
+        if let Err(exceeded) = budget.check() {
+            while let Some(joined) = tasks_in_queue.next().await {
+                outcome.add(joined);
+            }
+            let checkpoint = budget.checkpoint(exceeded, registered, image_count, Some(filename));
+            return Err(exceed_budget(&state, &msg.task_id, checkpoint).await);
+        }
+        let expected_name = converted_name(&filename, output_format);
+        if output_name != msg.output_layout.output_name(&expected_name) {
+            info!(%filename, %output_name, "Renamed image to avoid an output collision");
+        }
+
+        // Only as many images are in flight as can be buffered, the finished ones are collected
+        // as we go
+        while tasks_in_queue.len() >= max_in_flight {
+            if let Some(joined) = tasks_in_queue.next().await {
+                outcome.add(joined);
+            }
+        }
+
+        // Wait for an earlier image to finish uploading before extracting another one
+        let permit = Arc::clone(&state.image_buffers)
+            .acquire_owned()
+            .await
+            .map_err(|e| ProcessorError::Internal(format!("Image buffer semaphore closed: {}", e)))?;
+
+        // Archives are read in order, so the sidecars listed before this image are kept first
+        while sidecars.front().is_some_and(|(j, _)| *j < i) {
+            if let Some((j, path)) = sidecars.pop_front() {
+                keep_sidecar(&state, &msg, &stage_prefix, &mut source, j, &path).await;
+            }
+        }
+
+        // An entry that can't be read fails its own image, not the whole archive
+        let input = match &mut source {
+            DatasetSource::Archive(reader) => {
+                let buf = reader.read(i);
+                if let Ok(buf) = &buf {
+                    budget.add_extracted(buf.len() as u64);
+                }
+                ImageInput::Extracted(buf)
+            }
+            DatasetSource::Prefix { keys, .. } => ImageInput::Stored(keys[i].clone()),
+        };
+
+        // Otherwise, we can create that image task, and also send the image key back to s3.
+        let storage = state.storage.clone();
+        let uploads = state.uploads.clone();
+        let database = state.database.clone();
+        let operation = msg.operation.clone();
+        let fused_operations = msg.fused_operations.clone();
+        let producer = state.producer.clone();
+        let outbox = state.config.outbox.enabled;
+        let stage_prefix = Arc::clone(&stage_prefix);
+        let upstream_operations = Arc::clone(&upstream_operations);
+        let stage_operations = Arc::clone(&stage_operations);
+        let object_tags = msg.object_tags.clone();
+        let tenant_id = msg.tenant_id.clone();
+        let input_task_id = msg.input_task_id();
+
+        let image = async move { // Each thread will process one image
+            let _permit = permit;
+            // Stages reading the dataset read a loose image where it is
+            let input_key = match &input {
+                ImageInput::Stored(key) if input_folder == 0 => key.clone(),
+                _ => stage_key(&stage_prefix, input_folder, &input_name),
+            };
+            let output_key = stage_key(&stage_prefix, stage + 1, &output_name);
+
+            // The original size only needs the image header, every later stage's size is derived
+            // from it through the dimension math of the upstream operations
+            let original_dimensions = match &input {
+                ImageInput::Extracted(Ok(buf)) => {
+                    imagesize::blob_size(buf).ok().map(|size| Dimensions {
+                        width: size.width as u32,
+                        height: size.height as u32,
+                    })
+                }
+                ImageInput::Extracted(Err(_)) => None,
+                ImageInput::Stored(key) => read_image_header(storage.as_ref(), key).await,
+            };
+            let input_dimensions =
+                original_dimensions.map(|dims| propagate_dimensions(&upstream_operations, dims));
+            let output_dimensions =
+                input_dimensions.map(|dims| propagate_dimensions(&stage_operations, dims));
+
+            // A redelivered dataset task finds the image tasks its previous attempt created, they
+            // only need to be published if that attempt didn't get to it
+            let mapped_task_id = database.query_mappings(&msg.task_id, &filename).await;
+
+            // Create the initial image task
+            let image_task = ImageTask {
+                s3_key: input_key.clone(),
+                dataset_id: msg.task_id,
+                batch_id: msg.batch_id,
+                task_id: Some(mapped_task_id.unwrap_or_else(uuid::Uuid::new_v4)),
+                operation,
+                fused_operations,
+                depends_on: None,
+                dependency_dataset_task_id: input_task_id,
+                input_dimensions,
+                output_dimensions,
+                affinity_key: msg.use_local_cache.then(|| filename.clone()),
+                output_key: Some(output_key),
+                source_path: Some(filename.clone()),
+                hash_suffix: msg.hash_suffix,
+                cache_scope: msg.cache_scope,
+                default_format: msg.default_format,
+                alpha_policy: msg.alpha_policy,
+                metadata_policy: msg.metadata_policy,
+                previews: msg.previews,
+                object_tags: object_tags.clone(),
+                priority: msg.priority,
+                tenant_id,
+            };
+
+            let result: Result<(), ProcessorError> = async {
+                let buf = match input {
+                    ImageInput::Extracted(buf) => Some(buf?),
+                    ImageInput::Stored(_) => None,
+                };
+                if let Some(task_id) = mapped_task_id {
+                    if let Some(existing) = database.get_image_task(&task_id).await? {
+                        return resume_image_task(database.as_ref(), &producer, existing, outbox).await;
+                    }
+                }
+
+                // Only the first stage uploads the extracted image, every later stage reads it or
+                // the output the worker wrote for the stage it reads from. The marker lets a retry
+                // skip the upload.
+                if let Some(buf) = buf {
+                    if stage == 0 && !database.has_upload_marker(&msg.task_id, &input_key).await? {
+                        let size = buf.len() as u64;
+                        let _upload = uploads.acquire().await.map_err(|e| {
+                            ProcessorError::Internal(format!("Upload semaphore closed: {}", e))
+                        })?;
+                        let upload_timer = metrics::S3_UPLOAD_SECONDS.start_timer();
+                        let options = PutOptions {
+                            tags: object_tags,
+                            ..Default::default()
+                        };
+                        let buf = Bytes::from(buf);
+                        storage::retry_throttled(|| {
+                            storage.put_object(&input_key, buf.clone(), &options)
+                        })
+                        .await?;
+                        upload_timer.observe_duration();
+                        database
+                            .add_upload_marker(&msg.task_id, &input_key, size)
+                            .await?;
+                    }
+                }
+
+                register_image_task(
+                    database.as_ref(),
+                    &producer,
+                    image_task.clone(),
+                    &filename,
+                    &output_name,
+                    mapped_task_id.is_none(),
+                    outbox,
+                )
+                .await
+            }
+            .await;
+
+            match result {
+                Ok(()) => ImageOutcome::Registered,
+                Err(e) => {
+                    fail_image(database.as_ref(), &image_task, &output_name, mapped_task_id.is_none(), e)
+                        .await
+                }
+            }
+        };
+        tasks_in_queue.push(tokio::spawn(correlation::inherit(image).in_current_span()));
+    }
+    for (j, path) in sidecars {
+        keep_sidecar(&state, &msg, &stage_prefix, &mut source, j, &path).await;
+    }
+
+    while let Some(joined) = tasks_in_queue.next().await {
+        outcome.add(joined);
+    }
+
+    // Without a record of every image the stage could never be completed
+    if let Some(e) = outcome.lost {
+        return Err(e);
+    }
+    if outcome.failed > 0 {
+        warn!(
+            failed = outcome.failed,
+            image_count, "Some images of {} failed to decompose", dataset_key
+        );
+    }
+
+    Ok(image_count)
+}
+
+/// Records how far a dataset task got before it exceeded its budget, see `common::budgets`.
+/// Returns the error failing its stage.
+async fn exceed_budget(
+    state: &ConsumerAppState,
+    task_id: &uuid::Uuid,
+    checkpoint: BudgetCheckpoint,
+) -> ProcessorError {
+    warn!(
+        images_registered = checkpoint.images_registered,
+        images_total = checkpoint.images_total,
+        extracted_bytes = checkpoint.extracted_bytes,
+        "{}",
+        checkpoint.exceeded
+    );
+    if let Err(e) = state
+        .database
+        .record_budget_checkpoint(task_id, &checkpoint)
+        .await
+    {
+        error!(error = %e, "Failed to record the budget checkpoint");
+    }
+
+    ProcessorError::Validation(format!(
+        "{}, {} of {} images were registered",
+        checkpoint.exceeded, checkpoint.images_registered, checkpoint.images_total
+    ))
+}
+
+/// Stores a sidecar of the dataset and records it on the batch, see `common::sidecars`. The
+/// images don't depend on it, so a sidecar that can't be kept is only logged.
+async fn keep_sidecar(
+    state: &ConsumerAppState,
+    msg: &DatasetProcessingTask,
+    stage_prefix: &str,
+    source: &mut DatasetSource,
+    index: usize,
+    path: &str,
+) {
+    let result: Result<(), ProcessorError> = async {
+        let key = match source {
+            DatasetSource::Archive(reader) => {
+                let key = sidecar_key(stage_prefix, path);
+                let buf = Bytes::from(reader.read(index)?);
+                let options = PutOptions {
+                    tags: msg.object_tags.clone(),
+                    ..Default::default()
+                };
+                storage::retry_throttled(|| state.storage.put_object(&key, buf.clone(), &options))
+                    .await?;
+                key
+            }
+            // A loose sidecar is read where it is
+            DatasetSource::Prefix { keys, .. } => keys[index].clone(),
+        };
+        let sidecar = DatasetSidecar {
+            path: path.to_string(),
+            key,
+            output_prefix: stage_prefix.to_string(),
+        };
+        state.database.add_dataset_sidecar(&msg.batch_id, &sidecar).await?;
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => info!(%path, "Kept dataset sidecar"),
+        Err(e) => warn!(%path, error = %e, "Failed to keep dataset sidecar"),
+    }
+}
+
+/// One image of a dataset of many images
+enum ImageInput {
+    Extracted(Result<Vec<u8>, ProcessorError>), // From an archive, the first stage uploads it
+    Stored(String),                             // The key of a loose image
+}
+
+/// What became of one image of an archive
+enum ImageOutcome {
+    Registered,
+    Failed,               // Recorded as a failed image task
+    Lost(ProcessorError), // Not even the failure could be recorded
+}
+
+/// The images of an archive that didn't make it to a worker
+#[derive(Default)]
+struct ArchiveOutcome {
+    failed: u64,
+    lost: Option<ProcessorError>, // The first image that has no record at all
+}
+
+impl ArchiveOutcome {
+    fn add(&mut self, joined: Result<ImageOutcome, JoinError>) {
+        match joined {
+            Ok(ImageOutcome::Registered) => {}
+            Ok(ImageOutcome::Failed) => self.failed += 1,
+            Ok(ImageOutcome::Lost(e)) => {
+                self.lost.get_or_insert(e);
+            }
+            Err(join_err) => {
+                self.lost
+                    .get_or_insert(ProcessorError::Internal(format!("Join error: {}", join_err)));
+            }
+        }
+    }
+}
+
+/// Records an image that couldn't be extracted, uploaded or published as a failed image task.
+///
+/// The other images of the archive go on, the scheduler fails the stage once they all finished,
+/// like it does for an image a worker couldn't process.
+async fn fail_image(
+    database: &dyn DecomposerStore,
+    image_task: &ImageTask,
+    output_name: &str,
+    create_mapping: bool,
+    error: ProcessorError,
+) -> ImageOutcome {
+    let filename = image_task.source_path.as_deref().unwrap_or(&image_task.s3_key);
+    error!(%filename, %error, "Failed to decompose image");
+    metrics::TASKS_FAILED.with_label_values(&["image"]).inc();
+
+    let Some(task_id) = image_task.task_id else {
+        return ImageOutcome::Lost(error);
+    };
+    if create_mapping {
+        let _ = database
+            .create_mapping(image_task.dataset_id, filename, output_name, task_id)
+            .await;
+    }
+
+    alert_on_denied_access(database, &image_task.batch_id, &image_task.s3_key, &error).await;
+
+    // Nothing was decoded yet, so only storage failures tell anything about the image
+    let kind = match error.s3_error_kind() {
+        Some(_) => FailureKind::StorageError,
+        None => FailureKind::Other,
+    };
+    let recorded: Result<_, ProcessorError> = async {
+        database.db_add_task_idempotent(image_task).await?;
+        database
+            .mark_image_task_failed_before_start(
+                &task_id,
+                &error.to_string(),
+                kind,
+                error.s3_error_kind(),
+            )
+            .await
+    }
+    .await;
+    match recorded {
+        Ok(failed) => {
+            // Only listed for the user, the failed task already keeps the stage consistent
+            if let Some(failed) = failed {
+                if let Err(e) = database.record_image_failure(&failed).await {
+                    error!(%filename, error = %e, "Failed to record the image in the batch's failures");
+                }
+            }
+            ImageOutcome::Failed
+        }
+        Err(e) => {
+            error!(%filename, error = %e, "Failed to record the failure of the image");
+            ImageOutcome::Lost(error)
+        }
+    }
+}
+
+/// Handles a dataset task whose key is a single image instead of an archive, the image is a
+/// dataset with one entry.
+///
+/// Stages reading the dataset read the uploaded image where it is, later stages read the output
+/// of the stage they read from, like the images of an archive.
+async fn process_single_image(
+    msg: DatasetProcessingTask,
+    state: Arc<ConsumerAppState>,
+    input_formats: &[InputFormat],
+) -> Result<u64, ProcessorError> {
+    let stage = msg.stage;
+    // Its extension let it through already, later stages read what the first one wrote
+    if stage == 0 {
+        check_image_format(state.storage.as_ref(), &msg.dataset_key, input_formats).await?;
+    }
+    let filename = msg
+        .dataset_key
+        .rsplit('/')
+        .next()
+        .unwrap_or(&msg.dataset_key)
+        .to_string();
+    let input_name = msg
+        .output_layout
+        .output_name(&converted_name(&filename, msg.input_format()));
+    let output_name = msg
+        .output_layout
+        .output_name(&converted_name(&filename, msg.output_format()));
+    let prefix = stage_prefix(&msg).unwrap_or_else(|| {
+        image_stage_prefix(msg.tenant_id.as_deref(), &filename, &msg.batch_id)
+    });
+
+    let input_key = match msg.input_folder() {
+        0 => msg.dataset_key.clone(),
+        folder => stage_key(&prefix, folder, &input_name),
+    };
+    let output_key = stage_key(&prefix, stage + 1, &output_name);
+
+    // A redelivered dataset task finds the image task its previous attempt created
+    let mapped_task_id = state.database.query_mappings(&msg.task_id, &filename).await;
+    if let Some(task_id) = mapped_task_id {
+        if let Some(existing) = state.database.get_image_task(&task_id).await? {
+            let outbox = state.config.outbox.enabled;
+            resume_image_task(state.database.as_ref(), &state.producer, existing, outbox).await?;
+            return Ok(1);
+        }
+    }
+
+    // Like for archives, every stage's size is derived from the header of the uploaded image
+    let input_dimensions = read_image_header(state.storage.as_ref(), &msg.dataset_key)
+        .await
+        .map(|size| propagate_dimensions(&msg.upstream_operations, size));
+    let stage_operations: Vec<ImageOperation> = msg.operations().cloned().collect();
+    let output_dimensions =
+        input_dimensions.map(|dims| propagate_dimensions(&stage_operations, dims));
+
+    let image_task = ImageTask {
+        s3_key: input_key,
+        dataset_id: msg.task_id,
+        batch_id: msg.batch_id,
+        task_id: Some(mapped_task_id.unwrap_or_else(uuid::Uuid::new_v4)),
+        operation: msg.operation.clone(),
+        fused_operations: msg.fused_operations.clone(),
+        depends_on: None,
+        dependency_dataset_task_id: msg.input_task_id(),
+        input_dimensions,
+        output_dimensions,
+        affinity_key: msg.use_local_cache.then(|| filename.clone()),
+        output_key: Some(output_key),
+        source_path: Some(filename.clone()),
+        hash_suffix: msg.hash_suffix,
+        cache_scope: msg.cache_scope,
+        default_format: msg.default_format,
+        alpha_policy: msg.alpha_policy,
+        metadata_policy: msg.metadata_policy,
+        previews: msg.previews,
+        object_tags: msg.object_tags.clone(),
+        priority: msg.priority,
+        tenant_id: msg.tenant_id.clone(),
+    };
+
+    register_image_task(
+        state.database.as_ref(),
+        &state.producer,
+        image_task,
+        &filename,
+        &output_name,
+        mapped_task_id.is_none(),
+        state.config.outbox.enabled,
+    )
+    .await?;
+    Ok(1)
+}
+
+/// Fails a single image whose content is of a format that isn't allowed, e.g. a GIF uploaded as
+/// `input.png`. Content that can't be read or isn't of a known format is left for the worker.
+async fn check_image_format(
+    storage: &dyn StorageBackend,
+    key: &str,
+    input_formats: &[InputFormat],
+) -> Result<(), ProcessorError> {
+    let Ok(header) = storage.get_object_range(key, 0..MAGIC_BYTES as u64).await else {
+        return Ok(());
+    };
+    match InputFormat::detect(&header) {
+        Some(format) if !input_formats.contains(&format) => Err(ProcessorError::Validation(
+            format!("{} is a {:?} image, the allowed formats are {:?}", key, format, input_formats),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The first `MAGIC_BYTES` bytes of every loose image, `concurrency` at a time. A key whose bytes
+/// can't be read gets an empty header, its extension decides and its image fails when it is read.
+async fn read_headers(
+    storage: Arc<dyn StorageBackend>,
+    keys: Vec<String>,
+    concurrency: usize,
+) -> Vec<Vec<u8>> {
+    // Owned, so the reads don't borrow across the awaits of the handler's future
+    futures::stream::iter(keys)
+        .map(|key| {
+            let storage = Arc::clone(&storage);
+            async move {
+                storage
+                    .get_object_range(&key, 0..MAGIC_BYTES as u64)
+                    .await
+                    .map(|header| header.to_vec())
+                    .unwrap_or_default()
+            }
+        })
+        .buffered(concurrency)
+        .collect()
+        .await
+}
+
+/// Reads the size of an image from the start of the object, without downloading all of it
+async fn read_image_header(storage: &dyn StorageBackend, key: &str) -> Option<Dimensions> {
+    let header = storage
+        .get_object_range(key, 0..IMAGE_HEADER_BYTES)
+        .await
+        .ok()?;
+
+    imagesize::blob_size(&header).ok().map(|size| Dimensions {
+        width: size.width as u32,
+        height: size.height as u32,
+    })
+}
+
+/// Records a new image task and its mapping, then publishes it unless it has to wait for the
+/// image it depends on. With `outbox` the task is published through its outbox entry, see
+/// `db_utils::outbox`.
+async fn register_image_task(
+    database: &dyn DecomposerStore,
+    producer: &ProducerClient,
+    mut image_task: ImageTask,
+    filename: &str,
+    output_name: &str,
+    create_mapping: bool,
+    outbox: bool,
+) -> Result<(), ProcessorError> {
+    if create_mapping {
+        let _ = database.create_mapping(image_task.dataset_id, filename, output_name, image_task.task_id.expect("Line 110")).await;
+    }
+
+    // Here, we query our mappings to see if the dependency image task already
+    // exists
+    if let Some(val) = &image_task.dependency_dataset_task_id {
+        let depends_on_image =
+            database.query_mappings(val, filename).await;
+        image_task.depends_on = depends_on_image;
+    }
+
+    // Nothing to wait for, the task is recorded as Ready along with its entry
+    if outbox && image_task.depends_on.is_none() {
+        if database.add_task_with_outbox(&image_task).await? {
+            send_from_outbox(database, producer, image_task).await;
+        }
+        return Ok(());
+    }
+
+    // A task recorded by an earlier delivery is kept as it is
+    database.db_add_task_idempotent(&image_task).await?;
+    if filter_after_dependency(database, image_task.task_id, image_task.depends_on).await? {
+        return Ok(());
+    }
+
+    // Tasks whose dependency hasn't finished yet stay Waiting in the db, the worker
+    // publishes them once the image they depend on has been processed
+    let ready = match &image_task.depends_on {
+        Some(dependency) => database.image_task_succeeded(dependency).await,
+        None => true,
+    };
+
+    if ready && outbox {
+        enqueue_image_task(database, producer, image_task.task_id).await?;
+    } else if ready {
+        let task_id = image_task.task_id;
+        producer.send_image_task(image_task).await?;
+        if let Some(task_id) = task_id {
+            let _ = database.update_image_task_status(&task_id, TaskStatus::Ready).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Publishes an image task created by an earlier attempt at the same dataset task, unless that
+/// attempt already published it or its dependency hasn't finished yet
+async fn resume_image_task(
+    database: &dyn DecomposerStore,
+    producer: &ProducerClient,
+    task: DBImageTask,
+    outbox: bool,
+) -> Result<(), ProcessorError> {
+    if !matches!(task.status, TaskStatus::Waiting) {
+        return Ok(());
+    }
+    if filter_after_dependency(database, task.task_id, task.depends_on).await? {
+        return Ok(());
+    }
+
+    let ready = match &task.depends_on {
+        Some(dependency) => database.image_task_succeeded(dependency).await,
+        None => true,
+    };
+    if ready && outbox {
+        enqueue_image_task(database, producer, task.task_id).await?;
+    } else if ready {
+        producer.send_image_task(ImageTask::from(&task)).await?;
+        if let Some(task_id) = task.task_id {
+            database
+                .update_image_task_status(&task_id, TaskStatus::Ready)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Marks a Waiting image task Ready along with its outbox entry and sends it, unless it wasn't
+/// Waiting anymore
+async fn enqueue_image_task(
+    database: &dyn DecomposerStore,
+    producer: &ProducerClient,
+    task_id: Option<uuid::Uuid>,
+) -> Result<(), ProcessorError> {
+    let Some(task_id) = task_id else {
+        return Ok(());
+    };
+    if let Some(task) = database.enqueue_image_task(&task_id).await? {
+        send_from_outbox(database, producer, task).await;
+    }
+    Ok(())
+}
+
+/// Sends an image task whose outbox entry was committed and marks the entry sent. A task that
+/// can't be sent is left to the relay of the scheduler, its entry stays unsent.
+async fn send_from_outbox(database: &dyn DecomposerStore, producer: &ProducerClient, task: ImageTask) {
+    let Some(task_id) = task.task_id else {
+        return;
+    };
+    match producer.send_image_task(task).await {
+        Ok(_) => {
+            if let Err(e) = database.mark_outbox_sent(&task_id).await {
+                warn!(%task_id, error = %e, "Failed to mark the outbox entry of a task sent");
+            }
+        }
+        Err(e) => warn!(%task_id, error = %e, "Failed to send image task, left to the relay"),
+    }
+}
+
+/// Filters out an image task whose dependency a quality gate left out or that failed, the image
+/// it would read was never written. Returns whether it did.
+async fn filter_after_dependency(
+    database: &dyn DecomposerStore,
+    task_id: Option<uuid::Uuid>,
+    depends_on: Option<uuid::Uuid>,
+) -> Result<bool, ProcessorError> {
+    let (Some(task_id), Some(dependency)) = (task_id, depends_on) else {
+        return Ok(false);
+    };
+    let reason = match database.get_image_task(&dependency).await? {
+        Some(dependency) => dependency.status.upstream_filter_reason(),
+        None => None,
+    };
+    let Some(reason) = reason else {
+        return Ok(false);
+    };
+
+    database
+        .mark_image_task_filtered_before_start(&task_id, reason)
+        .await?;
+    Ok(true)
+}
+
+/// Checks the listing of an archive against the dataset limits and records the report on the
+/// batch, see `common::inspection`. Fails with the violations if the archive broke a limit.
+async fn validate_dataset(
+    state: &ConsumerAppState,
+    batch_id: &uuid::Uuid,
+    entries: &[ArchiveEntry],
+) -> Result<(), ProcessorError> {
+    let report = inspect_entries(entries, &state.config.dataset_limits);
+    info!(
+        entries = report.entries,
+        uncompressed_bytes = report.uncompressed_bytes,
+        violations = report.violations.len(),
+        "Inspected dataset"
+    );
+    // The report only explains the outcome, the limits are enforced regardless
+    if let Err(e) = state.database.record_dataset_validation(batch_id, &report).await {
+        error!(error = %e, "Failed to record the dataset validation report");
+    }
+
+    match report.passed() {
+        true => Ok(()),
+        false => Err(ProcessorError::Validation(report.summary())),
+    }
+}
+
+/// Marks a dataset task as running and checks the batch is in the matching lifecycle state.
+///
+/// The first stage moves a new batch to Decomposing, later stages were moved to Processing by the
+/// scheduler when it released them. Anything else (e.g. a cancelled batch) is rejected. A
+/// redelivered task resumes the decomposition its previous attempt started.
+///
+/// Returns whether the stage is to be decomposed. A redelivered task whose stage already finished
+/// isn't, and leaves the batch alone so the scheduler still releases the stages depending on it.
+async fn start_stage(
+    database: &dyn DecomposerStore,
+    msg: &DatasetProcessingTask,
+) -> Result<bool, ProcessorError> {
+    if let Some(status) = database.dataset_task_status(&msg.task_id).await? {
+        if status.is_final() {
+            info!(?status, "Skipping dataset task, its stage already finished");
+            return Ok(false);
+        }
+    }
+
+    let batch_state = database
+        .batch_state(&msg.batch_id)
+        .await?
+        .ok_or_else(|| ProcessorError::NotFound(format!("Batch {} does not exist", msg.batch_id)))?;
+
+    if msg.stage == 0 {
+        // A redelivered task finds the batch where its previous attempt left it
+        if !matches!(
+            batch_state,
+            BatchState::Decomposing | BatchState::Processing { stage: 0 }
+        ) {
+            database
+                .transition_batch(&msg.batch_id, BatchState::Decomposing)
+                .await?;
+        }
+    } else {
+        // Stages of a DAG job run side by side, the batch shows the latest one released
+        if !matches!(batch_state, BatchState::Processing { stage } if stage >= msg.stage) {
+            return Err(ProcessorError::Validation(format!(
+                "batch {} is {:?}, expected stage {} to be processing",
+                msg.batch_id, batch_state, msg.stage
+            )));
+        }
+    }
+
+    if !database
+        .transition_dataset_task(&msg.task_id, TaskStatus::Ready, TaskStatus::Running)
+        .await?
+    {
+        info!("Resuming decomposition of dataset task");
+    }
+    Ok(true)
+}
+
+/// Holds the lease on a running dataset task until the returned handle is aborted, so the
+/// scheduler only republishes the task if this consumer dies while decomposing it
+fn spawn_heartbeat(state: &ConsumerAppState, task_id: uuid::Uuid) -> JoinHandle<()> {
+    let database = Arc::clone(&state.database);
+    let lease = Duration::from_secs(state.config.decomposer.lease_secs);
+    let every = Duration::from_secs(state.config.decomposer.heartbeat_secs.max(1));
+
+    tokio::spawn(
+        async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                match database.renew_dataset_task_lease(&task_id, lease).await {
+                    Ok(true) => {}
+                    // Cancelled or failed meanwhile, there is nothing left to hold
+                    Ok(false) => return,
+                    Err(e) => warn!(error = %e, "Failed to renew the dataset task lease"),
+                }
+            }
+        }
+        .in_current_span(),
+    )
+}
+
+/// Fails a dataset task and the batch it belongs to
+/// Raises a storage alert on the batch if storage denied access to the key, every other image
+/// of the batch would be denied too
+async fn alert_on_denied_access(
+    database: &dyn DecomposerStore,
+    batch_id: &uuid::Uuid,
+    key: &str,
+    error: &ProcessorError,
+) {
+    if error.s3_error_kind() != Some(S3ErrorKind::AccessDenied) {
+        return;
+    }
+    if let Err(e) = database
+        .raise_storage_alert(batch_id, S3ErrorKind::AccessDenied, key, &error.to_string())
+        .await
+    {
+        error!(%batch_id, error = %e, "Failed to raise storage alert on the batch");
+    }
+}
+
+/// Fails a dataset task and its batch, publishing the events of both
+async fn fail_stage(
+    state: &ConsumerAppState,
+    batch_id: &uuid::Uuid,
+    task_id: &uuid::Uuid,
+    stage: u32,
+    tenant_id: Option<&str>,
+    from: TaskStatus,
+) {
+    metrics::TASKS_FAILED.with_label_values(&["dataset"]).inc();
+    if let Ok(true) = state
+        .database
+        .transition_dataset_task(task_id, from, TaskStatus::Failure)
+        .await
+    {
+        let finished = BatchEventKind::StageFinished {
+            task_id: *task_id,
+            stage,
+            succeeded: false,
+            images: 0,
+        };
+        state.events.publish(*batch_id, tenant_id, finished).await;
+    }
+    match state.database.transition_batch(batch_id, BatchState::Failed).await {
+        Ok(_) => {
+            let done = BatchEventKind::BatchDone {
+                state: BatchState::Failed,
+            };
+            state.events.publish(*batch_id, tenant_id, done).await;
+        }
+        Err(e) => error!(%batch_id, error = %e, "Failed to mark batch as failed"),
+    }
+}
+
+/// Decomposes the dataset task of an envelope. A task that can't be decomposed fails its stage
+/// and batch and is acknowledged like any other, the poison pill policy never sees it.
+pub async fn handle_dataset_task(
+    envelope: MessageEnvelope<DatasetProcessingTask>,
+    app_state: Arc<ConsumerAppState>,
+) -> Result<(), ProcessorError> {
+    let span = tracing::info_span!(
+        "dataset_task",
+        batch_id = %envelope.payload.batch_id,
+        task_id = %envelope.payload.task_id,
+        stage = envelope.payload.stage,
+    );
+    async move {
+        // Messages written before envelopes existed have no id to deduplicate by
+        let message_id = (!envelope.message_id.is_nil()).then_some(envelope.message_id);
+        let msg = envelope.payload;
+        if let Some(message_id) = message_id {
+            match app_state.database.has_processed_message(&message_id).await {
+                Ok(true) => {
+                    info!(%message_id, "Skipping redelivered message");
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(e) => error!(%message_id, error = %e, "Failed to check message against the ledger"),
+            }
+        }
+
+        let input_formats = &app_state.config.decomposer.input_formats;
+
+        let mut config =
+            ConfigSnapshot::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+                .for_stage(msg.stage)
+                .for_task(msg.task_id)
+                .with_operations(
+                    std::iter::once(&msg.operation).chain(&msg.fused_operations),
+                )
+                .with_codec("zip", env!("ZIP_VERSION"))
+                .with_codec("tar", env!("TAR_VERSION"))
+                .with_codec("flate2", env!("FLATE2_VERSION"))
+                .with_codec("imagesize", env!("IMAGESIZE_VERSION"))
+                .with_setting("s3_bucket", &app_state.config.s3.bucket)
+                .with_setting("storage_backend", format!("{:?}", app_state.config.storage.backend))
+                .with_setting("image_topic", app_state.producer.topic())
+                .with_setting("input_formats", format!("{:?}", input_formats))
+                .with_setting("output_layout", format!("{:?}", msg.output_layout))
+                .with_setting("collision_policy", format!("{:?}", msg.collision_policy))
+                .with_setting("hash_suffix", msg.hash_suffix)
+                .with_setting(
+                    "sidecar_names",
+                    app_state.config.decomposer.sidecar_names.join(","),
+                );
+        // Only the last stage encodes the job's output format
+        if msg.default_format.is_some() {
+            config = config.with_output_format(msg.default_format);
+        }
+        if let Err(e) = app_state
+            .database
+            .add_config_snapshot(&msg.batch_id, &config)
+            .await
+        {
+            error!(error = %e, "Failed to record config snapshot");
+        }
+
+        let task_id = msg.task_id;
+        let batch_id = msg.batch_id;
+        let stage = msg.stage;
+        let tenant_id = msg.tenant_id.clone();
+        // The cancellation already marked the dataset task, there is nothing to fail
+        if let Ok(true) = app_state.database.is_batch_cancelled(&batch_id).await {
+            info!("Skipping dataset task, the batch was cancelled");
+            return Ok(());
+        }
+        match start_stage(app_state.database.as_ref(), &msg).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => {
+                error!(error = %e, "Skipping dataset task");
+                fail_stage(&app_state, &batch_id, &task_id, stage, tenant_id.as_deref(), TaskStatus::Ready).await;
+                return Ok(());
+            }
+        }
+        let started = BatchEventKind::TaskStarted { task_id, stage };
+        app_state.events.publish(batch_id, tenant_id.as_deref(), started).await;
+
+        let heartbeat = spawn_heartbeat(&app_state, task_id);
+        let key = msg.dataset_key.clone();
+        let result = match DatasetFormat::from_key(&key, input_formats) {
+            Ok(DatasetFormat::Image) => {
+                info!(%key, "Single image file received");
+                process_single_image(msg, Arc::clone(&app_state), input_formats).await
+            }
+            Ok(format) => {
+                process_images(
+                    msg,
+                    Arc::clone(&app_state),
+                    &key,
+                    format,
+                    input_formats,
+                )
+                .await
+            }
+            Err(e) => Err(ProcessorError::Validation(e)),
+        };
+        heartbeat.abort();
+        match result {
+            Ok(image_count) => {
+                info!(image_count, "Successfully processed task");
+                // The first stage's decomposition is the whole Decomposing
+                // state, later stages already are in Processing
+                if stage == 0 {
+                    if let Err(e) = app_state
+                        .database
+                        .transition_batch(&batch_id, BatchState::Processing { stage: 0 })
+                        .await
+                    {
+                        error!(error = %e, "Failed to move batch to processing");
+                    }
+                }
+                // The scheduler completes the stage once all of these finish
+                if let Err(e) = app_state
+                    .database
+                    .mark_dataset_decomposed(&task_id, image_count)
+                    .await
+                {
+                    error!(error = %e, "Failed to record decomposition");
+                }
+            }
+            Err(e) => {
+                match e.s3_error_kind() {
+                    Some(S3ErrorKind::NoSuchKey) => {
+                        error!(%key, error = %e, "Dataset is missing from storage")
+                    }
+                    _ => error!(error = %e, "Failed to process this task"),
+                }
+                alert_on_denied_access(app_state.database.as_ref(), &batch_id, &key, &e).await;
+                fail_stage(&app_state, &batch_id, &task_id, stage, tenant_id.as_deref(), TaskStatus::Running).await;
+            }
+        }
+
+        if let Some(message_id) = message_id {
+            if let Err(e) = app_state
+                .database
+                .record_processed_message(&message_id, DECOMPOSER_GROUP)
+                .await
+            {
+                error!(%message_id, error = %e, "Failed to record message in the ledger");
+            }
+        }
+        Ok(())
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,5 @@
+mod archive;
+pub mod decompose;
+mod spool;
+pub mod store;
+pub mod utils;
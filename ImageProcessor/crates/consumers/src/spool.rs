@@ -0,0 +1,32 @@
+use common::error::ProcessorError;
+use futures::StreamExt;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use storage::ObjectStream;
+use tokio::io::AsyncWriteExt;
+
+/// Streams an object into an anonymous temporary file and returns it rewound to the start.
+/// The file is removed by the OS once it is dropped.
+pub(crate) async fn spool_to_tempfile(mut body: ObjectStream) -> Result<File, ProcessorError> {
+    let file = tempfile::tempfile()
+        .map_err(|e| ProcessorError::Internal(format!("Failed to create temp file: {}", e)))?;
+    let mut writer = tokio::fs::File::from_std(file);
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        writer
+            .write_all(&chunk)
+            .await
+            .map_err(|e| ProcessorError::Internal(format!("Failed to write temp file: {}", e)))?;
+    }
+    writer
+        .flush()
+        .await
+        .map_err(|e| ProcessorError::Internal(format!("Failed to write temp file: {}", e)))?;
+
+    let mut file = writer.into_std().await;
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| ProcessorError::Internal(format!("Failed to rewind temp file: {}", e)))?;
+
+    Ok(file)
+}
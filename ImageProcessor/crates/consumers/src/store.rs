@@ -0,0 +1,391 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use common::budgets::BudgetCheckpoint;
+use common::error::{ProcessorError, S3ErrorKind};
+use common::failures::FailureKind;
+use common::inspection::DatasetValidationReport;
+use common::lifecycle::BatchState;
+use common::reproducibility::ConfigSnapshot;
+use common::ImageTask;
+use db_utils::types::{DBClient, DBImageTask, DatasetSidecar, TaskStatus};
+
+// ============================================================================
+// DECOMPOSER STORE
+// What the decomposer reads and records while it turns a dataset task into
+// image tasks. `DBClient` is the store of the running service, tests hand the
+// handler one kept in memory instead.
+// ============================================================================
+
+#[async_trait]
+pub trait DecomposerStore: Send + Sync {
+    /// Whether a message was handled already, see `db_utils::dedup`
+    async fn has_processed_message(&self, message_id: &uuid::Uuid) -> Result<bool, ProcessorError>;
+
+    async fn record_processed_message(
+        &self,
+        message_id: &uuid::Uuid,
+        consumer: &str,
+    ) -> Result<(), ProcessorError>;
+
+    async fn add_config_snapshot(
+        &self,
+        batch_id: &uuid::Uuid,
+        config: &ConfigSnapshot,
+    ) -> Result<(), ProcessorError>;
+
+    async fn is_batch_cancelled(&self, batch_id: &uuid::Uuid) -> Result<bool, ProcessorError>;
+
+    /// `None` if the batch doesn't exist
+    async fn batch_state(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Option<BatchState>, ProcessorError>;
+
+    async fn transition_batch(
+        &self,
+        batch_id: &uuid::Uuid,
+        next: BatchState,
+    ) -> Result<(), ProcessorError>;
+
+    /// `None` if the dataset task doesn't exist
+    async fn dataset_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<TaskStatus>, ProcessorError>;
+
+    /// Returns `false` if the task wasn't in the `from` status
+    async fn transition_dataset_task(
+        &self,
+        task_id: &uuid::Uuid,
+        from: TaskStatus,
+        to: TaskStatus,
+    ) -> Result<bool, ProcessorError>;
+
+    /// Returns `false` once the task isn't running anymore
+    async fn renew_dataset_task_lease(
+        &self,
+        task_id: &uuid::Uuid,
+        lease: Duration,
+    ) -> Result<bool, ProcessorError>;
+
+    async fn mark_dataset_decomposed(
+        &self,
+        task_id: &uuid::Uuid,
+        image_count: u64,
+    ) -> Result<(), ProcessorError>;
+
+    async fn record_budget_checkpoint(
+        &self,
+        task_id: &uuid::Uuid,
+        checkpoint: &BudgetCheckpoint,
+    ) -> Result<(), ProcessorError>;
+
+    async fn record_dataset_validation(
+        &self,
+        batch_id: &uuid::Uuid,
+        report: &DatasetValidationReport,
+    ) -> Result<(), ProcessorError>;
+
+    /// Returns `false` if one with the same path was recorded before
+    async fn add_dataset_sidecar(
+        &self,
+        batch_id: &uuid::Uuid,
+        sidecar: &DatasetSidecar,
+    ) -> Result<bool, ProcessorError>;
+
+    async fn raise_storage_alert(
+        &self,
+        batch_id: &uuid::Uuid,
+        kind: S3ErrorKind,
+        key: &str,
+        message: &str,
+    ) -> Result<bool, ProcessorError>;
+
+    /// The image task created for an entry of the dataset task, if any was
+    async fn query_mappings(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        image_filename: &str,
+    ) -> Option<uuid::Uuid>;
+
+    async fn create_mapping(
+        &self,
+        dataset_task_id: uuid::Uuid,
+        image_filename: &str,
+        output_name: &str,
+        image_task_id: uuid::Uuid,
+    ) -> Result<(), ProcessorError>;
+
+    async fn has_upload_marker(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        s3_key: &str,
+    ) -> Result<bool, ProcessorError>;
+
+    async fn add_upload_marker(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        s3_key: &str,
+        size: u64,
+    ) -> Result<(), ProcessorError>;
+
+    async fn get_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    /// Records a Waiting image task, returns `false` if it was recorded before
+    async fn db_add_task_idempotent(&self, task: &ImageTask) -> Result<bool, ProcessorError>;
+
+    /// Records a Ready image task along with its outbox entry, see `db_utils::outbox`
+    async fn add_task_with_outbox(&self, task: &ImageTask) -> Result<bool, ProcessorError>;
+
+    /// Marks a Waiting image task Ready along with its outbox entry
+    async fn enqueue_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<ImageTask>, ProcessorError>;
+
+    async fn mark_outbox_sent(&self, task_id: &uuid::Uuid) -> Result<(), ProcessorError>;
+
+    async fn image_task_succeeded(&self, task_id: &uuid::Uuid) -> bool;
+
+    async fn update_image_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+    ) -> Result<(), ProcessorError>;
+
+    async fn mark_image_task_failed_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    async fn mark_image_task_filtered_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    async fn record_image_failure(&self, task: &DBImageTask) -> Result<(), ProcessorError>;
+}
+
+#[async_trait]
+impl DecomposerStore for DBClient {
+    async fn has_processed_message(&self, message_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        DBClient::has_processed_message(self, message_id).await
+    }
+
+    async fn record_processed_message(
+        &self,
+        message_id: &uuid::Uuid,
+        consumer: &str,
+    ) -> Result<(), ProcessorError> {
+        DBClient::record_processed_message(self, message_id, consumer).await
+    }
+
+    async fn add_config_snapshot(
+        &self,
+        batch_id: &uuid::Uuid,
+        config: &ConfigSnapshot,
+    ) -> Result<(), ProcessorError> {
+        DBClient::add_config_snapshot(self, batch_id, config).await
+    }
+
+    async fn is_batch_cancelled(&self, batch_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        DBClient::is_batch_cancelled(self, batch_id).await
+    }
+
+    async fn batch_state(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Option<BatchState>, ProcessorError> {
+        let batch = self.get_batch(batch_id).await?;
+        Ok(batch.map(|batch| batch.state))
+    }
+
+    async fn transition_batch(
+        &self,
+        batch_id: &uuid::Uuid,
+        next: BatchState,
+    ) -> Result<(), ProcessorError> {
+        DBClient::transition_batch(self, batch_id, next)
+            .await
+            .map(|_| ())
+    }
+
+    async fn dataset_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<TaskStatus>, ProcessorError> {
+        let task = self.get_dataset_task(task_id).await?;
+        Ok(task.map(|task| task.status))
+    }
+
+    async fn transition_dataset_task(
+        &self,
+        task_id: &uuid::Uuid,
+        from: TaskStatus,
+        to: TaskStatus,
+    ) -> Result<bool, ProcessorError> {
+        DBClient::transition_dataset_task(self, task_id, from, to).await
+    }
+
+    async fn renew_dataset_task_lease(
+        &self,
+        task_id: &uuid::Uuid,
+        lease: Duration,
+    ) -> Result<bool, ProcessorError> {
+        DBClient::renew_dataset_task_lease(self, task_id, lease).await
+    }
+
+    async fn mark_dataset_decomposed(
+        &self,
+        task_id: &uuid::Uuid,
+        image_count: u64,
+    ) -> Result<(), ProcessorError> {
+        DBClient::mark_dataset_decomposed(self, task_id, image_count).await
+    }
+
+    async fn record_budget_checkpoint(
+        &self,
+        task_id: &uuid::Uuid,
+        checkpoint: &BudgetCheckpoint,
+    ) -> Result<(), ProcessorError> {
+        DBClient::record_budget_checkpoint(self, task_id, checkpoint).await
+    }
+
+    async fn record_dataset_validation(
+        &self,
+        batch_id: &uuid::Uuid,
+        report: &DatasetValidationReport,
+    ) -> Result<(), ProcessorError> {
+        DBClient::record_dataset_validation(self, batch_id, report).await
+    }
+
+    async fn add_dataset_sidecar(
+        &self,
+        batch_id: &uuid::Uuid,
+        sidecar: &DatasetSidecar,
+    ) -> Result<bool, ProcessorError> {
+        DBClient::add_dataset_sidecar(self, batch_id, sidecar).await
+    }
+
+    async fn raise_storage_alert(
+        &self,
+        batch_id: &uuid::Uuid,
+        kind: S3ErrorKind,
+        key: &str,
+        message: &str,
+    ) -> Result<bool, ProcessorError> {
+        DBClient::raise_storage_alert(self, batch_id, kind, key, message).await
+    }
+
+    async fn query_mappings(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        image_filename: &str,
+    ) -> Option<uuid::Uuid> {
+        DBClient::query_mappings(self, dataset_task_id, image_filename).await
+    }
+
+    async fn create_mapping(
+        &self,
+        dataset_task_id: uuid::Uuid,
+        image_filename: &str,
+        output_name: &str,
+        image_task_id: uuid::Uuid,
+    ) -> Result<(), ProcessorError> {
+        DBClient::create_mapping(
+            self,
+            dataset_task_id,
+            image_filename,
+            output_name,
+            image_task_id,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn has_upload_marker(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        s3_key: &str,
+    ) -> Result<bool, ProcessorError> {
+        DBClient::has_upload_marker(self, dataset_task_id, s3_key).await
+    }
+
+    async fn add_upload_marker(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        s3_key: &str,
+        size: u64,
+    ) -> Result<(), ProcessorError> {
+        DBClient::add_upload_marker(self, dataset_task_id, s3_key, size).await
+    }
+
+    async fn get_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::get_image_task(self, task_id).await
+    }
+
+    async fn db_add_task_idempotent(&self, task: &ImageTask) -> Result<bool, ProcessorError> {
+        DBClient::db_add_task_idempotent(self, task).await
+    }
+
+    async fn add_task_with_outbox(&self, task: &ImageTask) -> Result<bool, ProcessorError> {
+        DBClient::add_task_with_outbox(self, task).await
+    }
+
+    async fn enqueue_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<ImageTask>, ProcessorError> {
+        DBClient::enqueue_image_task(self, task_id).await
+    }
+
+    async fn mark_outbox_sent(&self, task_id: &uuid::Uuid) -> Result<(), ProcessorError> {
+        DBClient::mark_outbox_sent(self, task_id).await
+    }
+
+    async fn image_task_succeeded(&self, task_id: &uuid::Uuid) -> bool {
+        DBClient::image_task_succeeded(self, task_id).await
+    }
+
+    async fn update_image_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+    ) -> Result<(), ProcessorError> {
+        DBClient::update_image_task_status(self, task_id, status).await
+    }
+
+    async fn mark_image_task_failed_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::mark_image_task_failed_before_start(self, task_id, error, kind, storage_error)
+            .await
+    }
+
+    async fn mark_image_task_filtered_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::mark_image_task_filtered_before_start(self, task_id, reason).await
+    }
+
+    async fn record_image_failure(&self, task: &DBImageTask) -> Result<(), ProcessorError> {
+        DBClient::record_image_failure(self, task).await
+    }
+}
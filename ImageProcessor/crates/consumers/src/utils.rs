@@ -1,12 +1,19 @@
-use aws_sdk_s3::Client;
-use db_utils::types::DBClient;
-use queue::{ProducerClient, consumer::ConsumerClient};
+use common::config::Config;
+use queue::{ProducerClient, consumer::ConsumerClient, events::BatchEventPublisher};
 use std::sync::Arc;
+use storage::StorageBackend;
+use tokio::sync::Semaphore;
+
+use crate::store::DecomposerStore;
 
 #[derive(Clone)]
-pub(crate) struct ConsumerAppState {
-    pub(crate) producer: Arc<ProducerClient>,
-    pub(crate) consumer: Arc<ConsumerClient>,
-    pub(crate) database: Arc<DBClient>,
-    pub(crate) s3: Arc<Client>,
+pub struct ConsumerAppState {
+    pub producer: Arc<ProducerClient>,
+    pub events: BatchEventPublisher, // Progress of the batches, see `common::events`
+    pub consumer: Arc<ConsumerClient>,
+    pub database: Arc<dyn DecomposerStore>,
+    pub storage: Arc<dyn StorageBackend>,
+    pub config: Arc<Config>,
+    pub image_buffers: Arc<Semaphore>, // One permit per extracted image waiting to be uploaded
+    pub uploads: Arc<Semaphore>,       // One permit per image being uploaded to storage
 }
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use common::{ImageOperation, error::ProcessorError, operations_name, timestamp};
+use futures::TryStreamExt;
+use mongodb::bson::{Document, doc, from_bson, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::status::group_sum;
+use crate::types::*;
+
+// ============================================================================
+// BYTE ACCOUNTING
+// Workers record the bytes every image task read and wrote. Totals only count
+// the tasks both are known for, so a stage that received some of its inputs
+// through the local handoff still gets a meaningful ratio of output to input
+// (its data amplification).
+// ============================================================================
+
+impl ByteTotals {
+    /// Bytes written per byte read, e.g. 5.0 for an augmentation or 0.1 for thumbnails
+    pub fn amplification(&self) -> Option<f64> {
+        (self.bytes_read > 0).then(|| self.bytes_written as f64 / self.bytes_read as f64)
+    }
+
+    pub fn merge(&mut self, other: &ByteTotals) {
+        self.tasks += other.tasks;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+/// Keeps the succeeded image tasks whose bytes were measured
+fn measured_tasks(mut filter: Document) -> Result<Document, ProcessorError> {
+    filter.insert("status", to_bson(&TaskStatus::Success).map_err(bson_error)?);
+    filter.insert("bytes_read", doc! { "$ne": null });
+    filter.insert("bytes_written", doc! { "$ne": null });
+    Ok(doc! { "$match": filter })
+}
+
+fn byte_totals(group: &Document) -> Result<ByteTotals, ProcessorError> {
+    Ok(ByteTotals {
+        tasks: group_sum(group, "tasks")?,
+        bytes_read: group_sum(group, "bytes_read")?,
+        bytes_written: group_sum(group, "bytes_written")?,
+    })
+}
+
+impl DBClient {
+    /// Bytes read and written by the image tasks of a batch, keyed by the dataset task (stage)
+    /// they belong to
+    pub async fn stage_byte_totals(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<HashMap<uuid::Uuid, ByteTotals>, ProcessorError> {
+        let pipeline = vec![
+            measured_tasks(doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? })?,
+            doc! { "$group": {
+                "_id": "$dataset_id",
+                "tasks": { "$sum": 1 },
+                "bytes_read": { "$sum": "$bytes_read" },
+                "bytes_written": { "$sum": "$bytes_written" },
+            } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_tasks
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        let mut totals = HashMap::new();
+        for group in groups {
+            let dataset_id: uuid::Uuid =
+                from_bson(group.get("_id").cloned().unwrap_or_default()).map_err(bson_error)?;
+            totals.insert(dataset_id, byte_totals(&group)?);
+        }
+
+        Ok(totals)
+    }
+
+    /// Bytes read and written by the image tasks that finished since the given time, keyed by
+    /// the name of the operations they applied, e.g. "Resize" or "Resize+Blur" for fused tasks
+    pub async fn operation_byte_totals_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<HashMap<String, ByteTotals>, ProcessorError> {
+        let filter = doc! {
+            "time_completed": { "$gte": timestamp::format(&since) },
+        };
+        // Grouped by the operations with their parameters, names are merged below
+        let pipeline = vec![
+            measured_tasks(filter)?,
+            doc! { "$group": {
+                "_id": { "operation": "$operation", "fused_operations": "$fused_operations" },
+                "tasks": { "$sum": 1 },
+                "bytes_read": { "$sum": "$bytes_read" },
+                "bytes_written": { "$sum": "$bytes_written" },
+            } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_tasks
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        let mut totals: HashMap<String, ByteTotals> = HashMap::new();
+        for group in groups {
+            let key = group.get_document("_id").map_err(bson_error)?;
+            let operation: ImageOperation =
+                from_bson(key.get("operation").cloned().unwrap_or_default()).map_err(bson_error)?;
+            // Tasks recorded before fusing existed have no fused operations
+            let fused_operations: Vec<ImageOperation> = match key.get("fused_operations") {
+                Some(fused) => from_bson(fused.clone()).map_err(bson_error)?,
+                None => Vec::new(),
+            };
+
+            let name = operations_name(std::iter::once(&operation).chain(&fused_operations));
+            totals.entry(name).or_default().merge(&byte_totals(&group)?);
+        }
+
+        Ok(totals)
+    }
+}
@@ -0,0 +1,87 @@
+use chrono::Utc;
+use common::{error::{ProcessorError, S3ErrorKind}, timestamp};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// STORAGE ALERTS
+// A task denied access to storage fails fast, every other image of the batch
+// would fail the same way. The first one raises an alert on its batch, which
+// the alerting service sends once to the batch's and the operators' webhooks.
+// ============================================================================
+
+impl DBClient {
+    /// Raises a storage alert on a batch for the error a task ran into on the given key.
+    ///
+    /// Returns `false` if the batch already has one, e.g. raised by another task of the batch.
+    pub async fn raise_storage_alert(
+        &self,
+        batch_id: &uuid::Uuid,
+        kind: S3ErrorKind,
+        key: &str,
+        message: &str,
+    ) -> Result<bool, ProcessorError> {
+        let alert = StorageAlert {
+            kind,
+            key: key.to_string(),
+            message: message.to_string(),
+            time_raised: Utc::now(),
+            time_sent: None,
+        };
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "storage_alert": null,
+        };
+        let update = doc! { "$set": { "storage_alert": to_bson(&alert).map_err(bson_error)? } };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Returns the batches with a storage alert that wasn't sent yet
+    pub async fn get_unsent_storage_alerts(
+        &self,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! {
+            "storage_alert": { "$ne": null },
+            "storage_alert.time_sent": null,
+        };
+
+        self.dataset_batch_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records that the storage alert of a batch was sent.
+    ///
+    /// Returns `false` if it had already been recorded, e.g. by another alerting instance.
+    pub async fn mark_storage_alert_sent(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "storage_alert": { "$ne": null },
+            "storage_alert.time_sent": null,
+        };
+        let update = doc! {
+            "$set": { "storage_alert.time_sent": timestamp::now() }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+}
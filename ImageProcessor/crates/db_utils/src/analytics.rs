@@ -0,0 +1,56 @@
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::{bson::doc, options::FindOptions};
+
+use crate::error::{db_error, is_duplicate_key};
+use crate::types::*;
+
+// ============================================================================
+// OPERATION ANALYTICS
+// The phase timings of the image tasks the workers processed, see
+// `common::analytics`. A task is recorded once, the timings of a redelivery
+// that ran it again are dropped.
+// ============================================================================
+
+impl DBClient {
+    pub async fn record_task_metrics(&self, metrics: &DBTaskMetrics) -> Result<(), ProcessorError> {
+        match self.task_metrics.insert_one(metrics, None).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_key(&e) => Ok(()),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    /// The timings recorded since the given time, optionally of one operation or release. At
+    /// most `limit` are returned, the most recent ones.
+    pub async fn get_task_metrics_since(
+        &self,
+        since: DateTime<Utc>,
+        operation: Option<&str>,
+        release: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<DBTaskMetrics>, ProcessorError> {
+        let mut filter = doc! {
+            "time_recorded": { "$gte": timestamp::format(&since) },
+        };
+        if let Some(operation) = operation {
+            filter.insert("operation", operation);
+        }
+        if let Some(release) = release {
+            filter.insert("release", release);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "time_recorded": -1 })
+            .limit(limit as i64)
+            .build();
+
+        self.task_metrics
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,56 @@
+use common::{approval::StageApproval, error::ProcessorError};
+use mongodb::{
+    bson::{doc, to_bson},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// STAGE APPROVAL
+// Stages holding their outputs for review, see `common::approval`. A decision
+// is recorded once, on a stage that succeeded, so approving a stage that was
+// rejected meanwhile or deciding twice changes nothing.
+// ============================================================================
+
+impl DBDatasetTask {
+    /// Whether the stages after this one may read its outputs, so far as its review goes
+    pub fn is_approved(&self) -> bool {
+        !self.requires_approval || self.approval.as_ref().is_some_and(|approval| approval.approved)
+    }
+}
+
+impl DBClient {
+    /// Records the decision on the outputs of a stage that succeeded and awaits one. A rejected
+    /// stage fails, which skips the stages after it.
+    ///
+    /// Returns the updated task, `None` if the stage wasn't awaiting a decision.
+    pub async fn decide_stage_approval(
+        &self,
+        task_id: &uuid::Uuid,
+        approval: &StageApproval,
+    ) -> Result<Option<DBDatasetTask>, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+            "requires_approval": true,
+            "approval": null,
+        };
+        let mut set = doc! { "approval": to_bson(approval).map_err(bson_error)? };
+        if !approval.approved {
+            set.insert(
+                "status",
+                to_bson(&TaskStatus::Failure).map_err(bson_error)?,
+            );
+        }
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.dataset_tasks
+            .find_one_and_update(filter, doc! { "$set": set }, options)
+            .await
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,48 @@
+use common::error::ProcessorError;
+use futures::TryStreamExt;
+use mongodb::bson::{Document, doc, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::status::group_sum;
+use crate::types::*;
+
+// ============================================================================
+// RESULT CACHE
+// Workers with the result cache enabled record on every image task whether
+// its output was reused, tasks that didn't consult the cache have no record.
+// ============================================================================
+
+impl DBClient {
+    /// Result cache hits and misses of the image tasks of a batch
+    pub async fn batch_cache_counts(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<CacheCounts, ProcessorError> {
+        let pipeline = vec![
+            doc! { "$match": {
+                "batch_id": to_bson(batch_id).map_err(bson_error)?,
+                "cache_hit": { "$ne": null },
+            } },
+            doc! { "$group": { "_id": "$cache_hit", "count": { "$sum": 1 } } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_tasks
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        let mut counts = CacheCounts::default();
+        for group in groups {
+            match group.get_bool("_id").map_err(bson_error)? {
+                true => counts.hits += group_sum(&group, "count")?,
+                false => counts.misses += group_sum(&group, "count")?,
+            }
+        }
+
+        Ok(counts)
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use common::{
+    capabilities::{HeldTask, WorkerCapabilities},
+    error::ProcessorError,
+    timestamp,
+};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson, to_document},
+    options::UpdateOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// WORKER CAPABILITIES
+// One record per image worker, overwritten by its heartbeats, and the tasks
+// held for lack of a capability until the scheduler requeues them, see
+// `common::capabilities`. Held tasks are recorded by their hold id, so a
+// redelivered hold is recorded once.
+// ============================================================================
+
+impl DBClient {
+    /// Records what a worker can run and that it is alive
+    pub async fn record_worker_heartbeat(
+        &self,
+        worker_id: &str,
+        capabilities: &WorkerCapabilities,
+    ) -> Result<(), ProcessorError> {
+        let update = doc! {
+            "$set": {
+                "capabilities": to_bson(capabilities).map_err(bson_error)?,
+                "last_seen": timestamp::now(),
+            }
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.workers
+            .update_one(doc! { "worker_id": worker_id }, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Returns the workers heard from since the given time
+    pub async fn get_live_workers(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DBWorker>, ProcessorError> {
+        let filter = doc! {
+            "last_seen": { "$gte": timestamp::format(&since) },
+        };
+
+        self.workers
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records a held task unless it was recorded before
+    pub async fn save_held_task(&self, held: &HeldTask) -> Result<(), ProcessorError> {
+        let record = DBHeldTask::from(held);
+        let filter = doc! { "hold_id": to_bson(&record.hold_id).map_err(bson_error)? };
+        let update = doc! { "$setOnInsert": to_document(&record).map_err(bson_error)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.held_tasks
+            .update_one(filter, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    pub async fn get_held_tasks(&self) -> Result<Vec<DBHeldTask>, ProcessorError> {
+        self.held_tasks
+            .find(doc! {}, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Removes a held task before it is requeued.
+    ///
+    /// Returns `false` if it was already removed, e.g. by a scheduler that stalled past its lease.
+    pub async fn claim_held_task(&self, hold_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        let filter = doc! { "hold_id": to_bson(hold_id).map_err(bson_error)? };
+
+        self.held_tasks
+            .delete_one(filter, None)
+            .await
+            .map(|result| result.deleted_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Puts back a held task whose requeue failed, for the next pass to retry
+    pub async fn restore_held_task(&self, task: &DBHeldTask) -> Result<(), ProcessorError> {
+        self.held_tasks
+            .insert_one(task, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Counts the held tasks none of the workers heard from since the given time can run
+    pub async fn count_unservable_held_tasks(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<u64, ProcessorError> {
+        let workers = self.get_live_workers(since).await?;
+        let held = self.get_held_tasks().await?;
+
+        Ok(held
+            .iter()
+            .filter(|task| {
+                !workers
+                    .iter()
+                    .any(|worker| worker.capabilities.satisfies(&task.capability))
+            })
+            .count() as u64)
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use common::{checkpoints::PartitionOffset, error::ProcessorError, timestamp};
+use futures::{TryStreamExt, future::BoxFuture};
+use mongodb::{
+    bson::doc,
+    options::UpdateOptions,
+};
+
+use crate::error::db_error;
+use crate::types::*;
+
+// ============================================================================
+// CONSUMER CHECKPOINTS
+// The offsets each consumer group committed, recorded for the Checkpoint
+// start position, see `common::checkpoints`. One record per group, topic and
+// partition, overwritten as the group moves on.
+// ============================================================================
+
+impl DBClient {
+    /// Records the offsets a consumer group committed
+    pub async fn save_consumer_checkpoints(
+        &self,
+        group_id: &str,
+        offsets: &[PartitionOffset],
+    ) -> Result<(), ProcessorError> {
+        let now = timestamp::now();
+        for offset in offsets {
+            let filter = doc! {
+                "group_id": group_id,
+                "topic": &offset.topic,
+                "partition": offset.partition,
+            };
+            let update = doc! { "$set": { "offset": offset.offset, "time_updated": now.clone() } };
+            let options = UpdateOptions::builder().upsert(true).build();
+
+            self.consumer_checkpoints
+                .update_one(filter, update, options)
+                .await
+                .map_err(db_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the offsets it is handed for the group, see `ConsumerClient::with_checkpoints`. A
+    /// failed write is logged, the next one catches up.
+    pub fn checkpoint_recorder(
+        self: &Arc<Self>,
+        group_id: &str,
+    ) -> impl Fn(Vec<PartitionOffset>) -> BoxFuture<'static, ()> + Send + Sync + 'static {
+        let db = Arc::clone(self);
+        let group_id = group_id.to_string();
+        move |offsets| {
+            let (db, group_id) = (Arc::clone(&db), group_id.clone());
+            Box::pin(async move {
+                if let Err(e) = db.save_consumer_checkpoints(&group_id, &offsets).await {
+                    tracing::warn!(%group_id, error = %e, "Failed to record consumer checkpoints");
+                }
+            })
+        }
+    }
+
+    /// The offsets last recorded for a consumer group, empty if it never recorded any
+    pub async fn get_consumer_checkpoints(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<PartitionOffset>, ProcessorError> {
+        let checkpoints: Vec<DBConsumerCheckpoint> = self
+            .consumer_checkpoints
+            .find(doc! { "group_id": group_id }, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        Ok(checkpoints
+            .into_iter()
+            .map(|checkpoint| PartitionOffset {
+                topic: checkpoint.topic,
+                partition: checkpoint.partition,
+                offset: checkpoint.offset,
+            })
+            .collect())
+    }
+}
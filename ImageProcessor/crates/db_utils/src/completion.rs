@@ -0,0 +1,278 @@
+use common::{
+    error::{ProcessorError, S3ErrorKind},
+    failures::FailureKind,
+    faults,
+    lifecycle::BatchState,
+    timestamp,
+};
+use mongodb::{
+    bson::{Document, doc, to_bson},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::leadership::fence;
+use crate::types::*;
+
+// ============================================================================
+// TASK COMPLETION
+// Status changes of running work, each a single findAndModify that only
+// matches tasks in the expected status. The caller gets the updated task back,
+// or None if another writer (or an earlier delivery) got there first.
+// ============================================================================
+
+impl DBClient {
+    /// Marks an image task as picked up by a worker.
+    ///
+    /// Running tasks are matched too, a redelivery after a worker crash takes the task over.
+    /// Returns `None` if the task doesn't exist or already finished.
+    pub async fn mark_image_task_running(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let from = [TaskStatus::Waiting, TaskStatus::Ready, TaskStatus::Running];
+        let set = doc! {
+            "status": to_bson(&TaskStatus::Running).map_err(bson_error)?,
+            "time_started": timestamp::now(),
+        };
+
+        self.update_image_task(task_id, &from, set).await
+    }
+
+    /// Marks a running image task as successfully processed, with the bytes it moved when they
+    /// were measured and whether its output came from the result cache when it was consulted.
+    pub async fn mark_image_task_succeeded(
+        &self,
+        task_id: &uuid::Uuid,
+        bytes: Option<ImageTaskBytes>,
+        cache_hit: Option<bool>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let mut set = doc! {
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+            "time_completed": timestamp::now(),
+        };
+        if let Some(bytes) = bytes {
+            set.insert("bytes_read", to_bson(&bytes.read).map_err(bson_error)?);
+            set.insert(
+                "bytes_written",
+                to_bson(&bytes.written).map_err(bson_error)?,
+            );
+        }
+        if let Some(cache_hit) = cache_hit {
+            set.insert("cache_hit", cache_hit);
+        }
+
+        self.update_image_task(task_id, &[TaskStatus::Running], set)
+            .await
+    }
+
+    /// Marks a running image task as failed, recording why, the kind of failure and the storage
+    /// error behind it, or the backtrace of an operation that panicked.
+    pub async fn mark_image_task_failed(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+        backtrace: Option<&str>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let set = doc! {
+            "status": to_bson(&TaskStatus::Failure).map_err(bson_error)?,
+            "error": error,
+            "failure_kind": to_bson(&kind).map_err(bson_error)?,
+            "storage_error": to_bson(&storage_error).map_err(bson_error)?,
+            "backtrace": backtrace,
+            "time_completed": timestamp::now(),
+        };
+
+        self.update_image_task(task_id, &[TaskStatus::Running], set)
+            .await
+    }
+
+    /// Fails an image task that never reached a worker, e.g. because its image couldn't be
+    /// extracted or uploaded. A task a worker already picked up is left to the worker.
+    pub async fn mark_image_task_failed_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let set = doc! {
+            "status": to_bson(&TaskStatus::Failure).map_err(bson_error)?,
+            "error": error,
+            "failure_kind": to_bson(&kind).map_err(bson_error)?,
+            "storage_error": to_bson(&storage_error).map_err(bson_error)?,
+            "time_completed": timestamp::now(),
+        };
+
+        self.update_image_task(task_id, &[TaskStatus::Waiting, TaskStatus::Ready], set)
+            .await
+    }
+
+    /// Marks a running image task as filtered, its image failed a quality gate for `reason`
+    pub async fn mark_image_task_filtered(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let set = doc! {
+            "status": to_bson(&TaskStatus::Filtered).map_err(bson_error)?,
+            "filter_reason": reason,
+            "time_completed": timestamp::now(),
+        };
+
+        self.update_image_task(task_id, &[TaskStatus::Running], set)
+            .await
+    }
+
+    /// Filters out the image tasks waiting on one that was filtered out or failed, and the tasks
+    /// waiting on those, since the image they would read never gets written. Returns how many
+    /// were filtered.
+    pub async fn filter_waiting_dependents(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<u64, ProcessorError> {
+        let mut filtered = 0;
+        let mut pending = vec![*task_id];
+        while let Some(task_id) = pending.pop() {
+            for dependent in self.get_waiting_dependents(&task_id).await? {
+                let Some(dependent_id) = dependent.task_id else {
+                    continue;
+                };
+                if self
+                    .mark_image_task_filtered_before_start(&dependent_id, reason)
+                    .await?
+                    .is_some()
+                {
+                    filtered += 1;
+                    pending.push(dependent_id);
+                }
+            }
+        }
+
+        Ok(filtered)
+    }
+
+    /// Filters out an image task that never reached a worker because the image it reads was
+    /// filtered out or failed in an earlier stage, see `TaskStatus::upstream_filter_reason`
+    pub async fn mark_image_task_filtered_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let set = doc! {
+            "status": to_bson(&TaskStatus::Filtered).map_err(bson_error)?,
+            "filter_reason": reason,
+            "time_completed": timestamp::now(),
+        };
+
+        self.update_image_task(task_id, &[TaskStatus::Waiting, TaskStatus::Ready], set)
+            .await
+    }
+
+    /// Claims a waiting image task for publishing by moving it to Ready, so it is published once
+    /// however many workers release it.
+    ///
+    /// Returns the claimed task, or `None` if it wasn't waiting, e.g. because another worker
+    /// claimed it first.
+    pub async fn claim_waiting_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let set = doc! { "status": to_bson(&TaskStatus::Ready).map_err(bson_error)? };
+
+        self.update_image_task(task_id, &[TaskStatus::Waiting], set)
+            .await
+    }
+
+    /// Hands a claimed image task that couldn't be published back to Waiting, for the next release
+    /// to claim again
+    pub async fn unclaim_image_task(&self, task_id: &uuid::Uuid) -> Result<(), ProcessorError> {
+        let set = doc! { "status": to_bson(&TaskStatus::Waiting).map_err(bson_error)? };
+
+        self.update_image_task(task_id, &[TaskStatus::Ready], set)
+            .await
+            .map(|_| ())
+    }
+
+    /// Completes a running dataset task with a final status, on behalf of the scheduler leading
+    /// with the fencing token `token`, see `leadership`.
+    ///
+    /// Returns the completed task, or `None` if it wasn't running, e.g. because another scheduler
+    /// completed it first.
+    pub async fn mark_dataset_task_complete(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+        token: u64,
+    ) -> Result<Option<DBDatasetTask>, ProcessorError> {
+        if !status.is_final() {
+            return Err(ProcessorError::Validation(format!(
+                "{:?} is not a final status",
+                status
+            )));
+        }
+
+        let mut filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Running).map_err(bson_error)?,
+        };
+        let mut set = doc! {
+            "status": to_bson(&status).map_err(bson_error)?,
+            "time_completed": timestamp::now(),
+        };
+        fence(&mut filter, &mut set, token);
+
+        self.dataset_tasks
+            .find_one_and_update(filter, doc! { "$set": set }, return_updated())
+            .await
+            .map_err(db_error)
+    }
+
+    /// Completes a batch whose last stage finished, passing through Finalizing.
+    pub async fn mark_batch_complete(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<DBDatasetProcessingJob, ProcessorError> {
+        let batch = self.get_batch(batch_id).await?.ok_or_else(|| {
+            ProcessorError::NotFound(format!("Batch {} does not exist", batch_id))
+        })?;
+        if batch.state != BatchState::Finalizing {
+            self.transition_batch(batch_id, BatchState::Finalizing)
+                .await?;
+        }
+
+        self.transition_batch(batch_id, BatchState::Completed).await
+    }
+
+    async fn update_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+        from: &[TaskStatus],
+        set: Document,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        faults::db_write("image task status change")?;
+        let from = from
+            .iter()
+            .map(to_bson)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(bson_error)?;
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": { "$in": from },
+        };
+
+        self.image_tasks
+            .find_one_and_update(filter, doc! { "$set": set }, return_updated())
+            .await
+            .map_err(db_error)
+    }
+}
+
+fn return_updated() -> FindOneAndUpdateOptions {
+    FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build()
+}
@@ -0,0 +1,122 @@
+use common::{ImageTask, error::ProcessorError, timestamp};
+use mongodb::{
+    Collection, IndexModel,
+    bson::{Document, doc, to_bson, to_document},
+    options::{IndexOptions, UpdateOptions},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// DEDUPLICATION
+// Kafka delivers messages at least once, so every write a handler makes has
+// to survive the message being handled again. Task ids are unique in the
+// database, image tasks are inserted with an upsert that keeps the first
+// copy, and the decomposer records the messages it handled in a ledger.
+// ============================================================================
+
+async fn create_unique_index<T: Send + Sync>(collection: &Collection<T>, keys: Document) {
+    let index = IndexModel::builder()
+        .keys(keys)
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+
+    // Fails when the collection already holds duplicates, which have to be removed by hand
+    if let Err(e) = collection.create_index(index, None).await {
+        tracing::error!(
+            collection = collection.name(),
+            error = %e,
+            "Failed to create unique index"
+        );
+    }
+}
+
+impl DBClient {
+    /// Creates the unique indexes deduplication relies on, if they don't exist yet
+    pub(crate) async fn ensure_indexes(&self) {
+        create_unique_index(&self.image_tasks, doc! { "task_id": 1 }).await;
+        create_unique_index(&self.dataset_tasks, doc! { "task_id": 1 }).await;
+        create_unique_index(&self.processed_messages, doc! { "message_id": 1 }).await;
+        create_unique_index(&self.image_failures, doc! { "image_task_id": 1 }).await;
+        create_unique_index(&self.operation_profiles, doc! { "operation": 1 }).await;
+        create_unique_index(&self.leader_leases, doc! { "name": 1 }).await;
+        create_unique_index(&self.mapping_sets, doc! { "dataset_task_id": 1, "chunk": 1 }).await;
+        create_unique_index(&self.outbox, doc! { "task_id": 1 }).await;
+        create_unique_index(&self.notification_preferences, doc! { "tenant_id": 1 }).await;
+        create_unique_index(&self.workers, doc! { "worker_id": 1 }).await;
+        create_unique_index(&self.held_tasks, doc! { "hold_id": 1 }).await;
+        create_unique_index(&self.pipelines, doc! { "tenant_id": 1, "name": 1 }).await;
+        create_unique_index(&self.tenants, doc! { "tenant_id": 1 }).await;
+        create_unique_index(&self.tenant_api_keys, doc! { "key_hash": 1 }).await;
+        create_unique_index(&self.share_links, doc! { "token_hash": 1 }).await;
+        create_unique_index(&self.task_metrics, doc! { "image_task_id": 1 }).await;
+        create_unique_index(
+            &self.consumer_checkpoints,
+            doc! { "group_id": 1, "topic": 1, "partition": 1 },
+        )
+        .await;
+        create_unique_index(
+            &self.consumer_lag,
+            doc! { "group_id": 1, "topic": 1, "partition": 1 },
+        )
+        .await;
+        create_unique_index(
+            &self.latency_windows,
+            doc! { "operation": 1, "window_start": 1 },
+        )
+        .await;
+    }
+
+    /// Records an image task unless a task with the same id exists already.
+    ///
+    /// Returns whether the task was inserted, an existing task is left untouched.
+    pub async fn db_add_task_idempotent(&self, task: &ImageTask) -> Result<bool, ProcessorError> {
+        let record = DBImageTask::from(task);
+        let filter = doc! { "task_id": to_bson(&record.task_id).map_err(bson_error)? };
+        let update = doc! { "$setOnInsert": to_document(&record).map_err(bson_error)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.image_tasks
+            .update_one(filter, update, options)
+            .await
+            .map(|result| result.upserted_id.is_some())
+            .map_err(db_error)
+    }
+
+    /// Whether a message with this id was handled before
+    pub async fn has_processed_message(
+        &self,
+        message_id: &uuid::Uuid,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! { "message_id": to_bson(message_id).map_err(bson_error)? };
+
+        self.processed_messages
+            .count_documents(filter, None)
+            .await
+            .map(|count| count > 0)
+            .map_err(db_error)
+    }
+
+    /// Records that a message was handled. Recording it twice keeps a single entry.
+    pub async fn record_processed_message(
+        &self,
+        message_id: &uuid::Uuid,
+        consumer: &str,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "message_id": to_bson(message_id).map_err(bson_error)? };
+        let update = doc! {
+            "$setOnInsert": {
+                "consumer": consumer,
+                "time_created": timestamp::now(),
+            }
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.processed_messages
+            .update_one(filter, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,176 @@
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, lifecycle::BatchState, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// BATCH DELETION
+// A batch owns its documents and the objects under its own prefixes, e.g.
+// results/{batch}/ and previews/{batch}/. The stages write under the prefix of
+// the dataset they read instead, as does the decomposer extracting it, so
+// every batch submitted for the same dataset writes the same keys. Those
+// objects, and the upload itself, are only deleted with the last batch of the
+// dataset.
+//
+// The janitor of the scheduler deletes the images the decomposer extracted and
+// the outputs of the stages other stages read, once the batch finished long
+// enough ago. Those of a dataset shared with other batches are kept.
+// ============================================================================
+
+/// Number of documents a batch deletion removed
+#[derive(Debug, Clone, Default)]
+pub struct DeletedDocuments {
+    pub batches: u64,
+    pub dataset_tasks: u64,
+    pub image_tasks: u64,
+    pub other: u64, // Mappings, upload markers, failures and download audits
+}
+
+impl DBClient {
+    /// Whether a batch other than the given one was submitted for the dataset
+    pub async fn dataset_shared(
+        &self,
+        dataset_key: &str,
+        batch_id: &uuid::Uuid,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "dataset_key": dataset_key,
+            "batch_id": { "$ne": to_bson(batch_id).map_err(bson_error)? },
+        };
+
+        self.dataset_batch_tasks
+            .find_one(filter, None)
+            .await
+            .map(|batch| batch.is_some())
+            .map_err(db_error)
+    }
+
+    /// Keys of the images the decomposer extracted for the dataset tasks
+    pub async fn get_extracted_keys(
+        &self,
+        dataset_task_ids: &[uuid::Uuid],
+    ) -> Result<Vec<String>, ProcessorError> {
+        let filter =
+            doc! { "dataset_task_id": { "$in": to_bson(dataset_task_ids).map_err(bson_error)? } };
+
+        let markers: Vec<DBUploadMarker> = self
+            .upload_markers
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+        Ok(markers.into_iter().map(|marker| marker.s3_key).collect())
+    }
+
+    /// Keys of the outputs the image tasks of the dataset tasks wrote
+    pub async fn get_output_keys(
+        &self,
+        dataset_task_ids: &[uuid::Uuid],
+    ) -> Result<Vec<String>, ProcessorError> {
+        let filter = doc! {
+            "dataset_id": { "$in": to_bson(dataset_task_ids).map_err(bson_error)? },
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+        };
+
+        let tasks: Vec<DBImageTask> = self
+            .image_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+        Ok(tasks.into_iter().filter_map(|task| task.output_key).collect())
+    }
+
+    /// Finished batches whose intermediate outputs the janitor hasn't handled yet, finished
+    /// before the cutoff
+    pub async fn get_batches_with_intermediates(
+        &self,
+        finished_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let final_states = [
+            BatchState::Completed,
+            BatchState::Failed,
+            BatchState::Cancelled,
+            BatchState::TimedOut,
+        ];
+        let filter = doc! {
+            "state": { "$in": to_bson(&final_states).map_err(bson_error)? },
+            "time_completed": {
+                "$lte": timestamp::format(&finished_before)
+            },
+            "intermediates_swept_at": null,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "time_completed": 1 })
+            .limit(limit)
+            .build();
+
+        self.dataset_batch_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records that the janitor handled the intermediate outputs of a batch
+    pub async fn mark_intermediates_swept(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$set": { "intermediates_swept_at": timestamp::now() }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Deletes every document of a batch. The batch document goes last, so a deletion that is
+    /// interrupted can be run again.
+    pub async fn delete_batch_documents(
+        &self,
+        batch_id: &uuid::Uuid,
+        dataset_task_ids: &[uuid::Uuid],
+    ) -> Result<DeletedDocuments, ProcessorError> {
+        let by_batch = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let by_task =
+            doc! { "dataset_task_id": { "$in": to_bson(dataset_task_ids).map_err(bson_error)? } };
+
+        let mut other = 0;
+        other += deleted(self.mappings.delete_many(by_task.clone(), None).await)?;
+        other += deleted(self.mapping_sets.delete_many(by_task.clone(), None).await)?;
+        other += deleted(self.upload_markers.delete_many(by_task, None).await)?;
+        other += deleted(self.image_failures.delete_many(by_batch.clone(), None).await)?;
+        other += deleted(self.download_audits.delete_many(by_batch.clone(), None).await)?;
+
+        Ok(DeletedDocuments {
+            image_tasks: deleted(self.image_tasks.delete_many(by_batch.clone(), None).await)?,
+            dataset_tasks: deleted(self.dataset_tasks.delete_many(by_batch.clone(), None).await)?,
+            batches: deleted(self.dataset_batch_tasks.delete_many(by_batch, None).await)?,
+            other,
+        })
+    }
+}
+
+fn deleted(
+    result: mongodb::error::Result<mongodb::results::DeleteResult>,
+) -> Result<u64, ProcessorError> {
+    result.map(|res| res.deleted_count).map_err(db_error)
+}
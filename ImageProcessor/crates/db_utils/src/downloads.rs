@@ -0,0 +1,38 @@
+use common::error::ProcessorError;
+use mongodb::bson::{doc, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// DOWNLOAD AUDITS
+// Every request served by the api-server's download proxy is recorded, so
+// access to results can be reviewed where presigned URLs aren't allowed.
+// ============================================================================
+
+impl DBClient {
+    pub async fn add_download_audit(&self, audit: &DBDownloadAudit) -> Result<(), ProcessorError> {
+        self.download_audits
+            .insert_one(audit, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Records that building the results archive of the batch failed, replacing an earlier
+    /// failure
+    pub async fn set_archive_failure(
+        &self,
+        batch_id: &uuid::Uuid,
+        failure: &ArchiveFailure,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "archive_failure": to_bson(failure).map_err(bson_error)? } };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,173 @@
+use common::{
+    drift::{ImageQualitySample, StageQualitySummary, summarize},
+    error::ProcessorError,
+    timestamp,
+};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// QUALITY DRIFT
+// The metrics the image workers recorded on their sampled outputs, summarized
+// per stage once it finished and kept on the stage by its pipeline, which the
+// baselines of later stages are read by. See `common::drift`.
+// ============================================================================
+
+impl DBClient {
+    /// Records what was measured of the output of an image task
+    pub async fn set_image_task_quality(
+        &self,
+        task_id: &uuid::Uuid,
+        sample: &ImageQualitySample,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "quality": to_bson(sample).map_err(bson_error)? } };
+
+        self.image_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Summarizes the sampled outputs of a stage's succeeded image tasks
+    pub async fn stage_quality_summary(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+    ) -> Result<StageQualitySummary, ProcessorError> {
+        let filter = doc! {
+            "dataset_id": to_bson(dataset_task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+            "quality": { "$ne": null },
+        };
+        let samples: Vec<ImageQualitySample> = self
+            .image_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect::<Vec<DBImageTask>>()
+            .await
+            .map_err(db_error)?
+            .into_iter()
+            .filter_map(|task| task.quality)
+            .collect();
+
+        let values = |metric: fn(&ImageQualitySample) -> Option<f64>| -> Vec<f64> {
+            samples.iter().filter_map(metric).collect()
+        };
+        Ok(StageQualitySummary {
+            sharpness: summarize(&values(|sample| Some(sample.sharpness))),
+            brightness: summarize(&values(|sample| Some(sample.brightness))),
+            ssim: summarize(&values(|sample| sample.ssim)),
+        })
+    }
+
+    /// The summaries of the latest stages of other batches that ran the pipeline, newest first
+    pub async fn get_quality_baseline(
+        &self,
+        pipeline: &str,
+        batch_id: &uuid::Uuid,
+        limit: u32,
+    ) -> Result<Vec<StageQualitySummary>, ProcessorError> {
+        let filter = doc! {
+            "quality.pipeline": pipeline,
+            "batch_id": { "$ne": to_bson(batch_id).map_err(bson_error)? },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "quality.time_checked": -1 })
+            .limit(limit as i64)
+            .build();
+
+        let stages: Vec<DBDatasetTask> = self
+            .dataset_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+        Ok(stages
+            .into_iter()
+            .filter_map(|stage| stage.quality.map(|quality| quality.summary))
+            .collect())
+    }
+
+    /// Records the quality of a finished stage, and its drifts on its batch.
+    ///
+    /// Returns `false` if the stage was checked already, e.g. by a scheduler that lost its lease.
+    pub async fn record_stage_quality(
+        &self,
+        task: &DBDatasetTask,
+        quality: &StageQuality,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(&task.task_id).map_err(bson_error)?,
+            "quality": null,
+        };
+        let update = doc! { "$set": { "quality": to_bson(quality).map_err(bson_error)? } };
+        let recorded = self
+            .dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map_err(db_error)?
+            .modified_count
+            == 1;
+        if !recorded || quality.drifts.is_empty() {
+            return Ok(recorded);
+        }
+
+        let filter = doc! { "batch_id": to_bson(&task.batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$push": {
+                "quality_drifts": { "$each": to_bson(&quality.drifts).map_err(bson_error)? }
+            }
+        };
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| true)
+            .map_err(db_error)
+    }
+
+    /// Returns the stages with drifts that weren't sent yet
+    pub async fn get_unsent_quality_drifts(&self) -> Result<Vec<DBDatasetTask>, ProcessorError> {
+        let filter = doc! {
+            "quality.drifts.0": { "$exists": true },
+            "quality.alert_sent_at": null,
+        };
+
+        self.dataset_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records that the drifts of a stage were sent, before they are.
+    ///
+    /// Returns `false` if it had already been recorded, e.g. by another alerting instance.
+    pub async fn claim_quality_alert(&self, task_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "quality.drifts.0": { "$exists": true },
+            "quality.alert_sent_at": null,
+        };
+        let update = doc! {
+            "$set": { "quality.alert_sent_at": timestamp::now() }
+        };
+
+        self.dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|result| result.modified_count == 1)
+            .map_err(db_error)
+    }
+}
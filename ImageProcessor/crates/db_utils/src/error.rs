@@ -0,0 +1,40 @@
+use common::error::ProcessorError;
+use mongodb::error::{
+    Error, ErrorKind, RETRYABLE_WRITE_ERROR, TRANSIENT_TRANSACTION_ERROR, WriteFailure,
+};
+
+/// Converts a driver error, treating connection problems and errors the server labels as
+/// retryable as transient
+pub(crate) fn db_error(error: Error) -> ProcessorError {
+    let transient = matches!(
+        *error.kind,
+        ErrorKind::Io(_)
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::DnsResolve { .. }
+    ) || error.contains_label(RETRYABLE_WRITE_ERROR)
+        || error.contains_label(TRANSIENT_TRANSACTION_ERROR);
+
+    match *error.kind {
+        ErrorKind::BsonSerialization(_) | ErrorKind::BsonDeserialization(_) => {
+            ProcessorError::serialization(error)
+        }
+        _ => ProcessorError::database(error, transient),
+    }
+}
+
+/// Converts an error raised while building or reading a bson document
+pub(crate) fn bson_error(error: impl std::fmt::Display) -> ProcessorError {
+    ProcessorError::serialization(error)
+}
+
+/// Whether a write failed on a unique index, e.g. an upsert racing another one
+pub(crate) fn is_duplicate_key(error: &Error) -> bool {
+    const DUPLICATE_KEY: i32 = 11000;
+
+    match &*error.kind {
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
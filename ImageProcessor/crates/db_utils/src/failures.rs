@@ -0,0 +1,150 @@
+use chrono::Utc;
+use common::{error::ProcessorError, failures::FailureKind, operations_name};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{Document, doc, from_bson, to_bson, to_document},
+    options::{FindOptions, UpdateOptions},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::status::group_sum;
+use crate::types::*;
+
+// ============================================================================
+// IMAGE FAILURES
+// A bad image fails its own image task while the rest of the batch goes on.
+// Every failed image is also recorded by the file it came from, so users can
+// find and resubmit just the bad files instead of digging through the tasks.
+// Failures are also counted by kind, stage and operation, see
+// `common::failures`, so problems shared by many images stand out.
+// ============================================================================
+
+impl DBClient {
+    /// Records why an image task failed, replacing an earlier failure of the same task.
+    pub async fn record_image_failure(&self, task: &DBImageTask) -> Result<(), ProcessorError> {
+        let Some(task_id) = task.task_id else {
+            return Ok(());
+        };
+        let stage = self
+            .get_dataset_task(&task.dataset_id)
+            .await?
+            .map(|dataset_task| dataset_task.stage)
+            .unwrap_or_default();
+        let operations = std::iter::once(&task.operation).chain(&task.fused_operations);
+
+        let failure = DBImageFailure {
+            id: None,
+            batch_id: task.batch_id,
+            dataset_id: task.dataset_id,
+            image_task_id: task_id,
+            filename: task
+                .source_path
+                .clone()
+                .unwrap_or_else(|| task.s3_key.clone()),
+            stage,
+            operation: operations_name(operations),
+            error: task.error.clone().unwrap_or_default(),
+            kind: task.failure_kind.unwrap_or_default(),
+            storage_error: task.storage_error,
+            backtrace: task.backtrace.clone(),
+            time_failed: task.time_completed.unwrap_or_else(Utc::now),
+        };
+        let filter = doc! { "image_task_id": to_bson(&task_id).map_err(bson_error)? };
+        let update = doc! { "$set": to_document(&failure).map_err(bson_error)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.image_failures
+            .update_one(filter, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Every failed image of a batch, or only those that failed with `kind`, by stage and filename
+    pub async fn get_image_failures(
+        &self,
+        batch_id: &uuid::Uuid,
+        kind: Option<FailureKind>,
+    ) -> Result<Vec<DBImageFailure>, ProcessorError> {
+        let mut filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        if let Some(kind) = kind {
+            filter.insert("kind", to_bson(&kind).map_err(bson_error)?);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "stage": 1, "filename": 1 })
+            .build();
+
+        self.image_failures
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Counts the failed images of a batch by kind of failure, stage and operation, the largest
+    /// groups first
+    pub async fn get_failure_groups(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Vec<FailureGroup>, ProcessorError> {
+        let pipeline = vec![
+            doc! { "$match": { "batch_id": to_bson(batch_id).map_err(bson_error)? } },
+            doc! { "$group": {
+                "_id": { "kind": "$kind", "stage": "$stage", "operation": "$operation" },
+                "count": { "$sum": 1 },
+                "sample_error": { "$first": "$error" },
+                "sample_filename": { "$first": "$filename" },
+            } },
+            doc! { "$sort": { "count": -1, "_id.stage": 1 } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_failures
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        groups
+            .iter()
+            .map(|group| {
+                let key = group.get_document("_id").map_err(bson_error)?;
+                // Failures recorded before they were classified have no kind
+                let kind = match key.get("kind") {
+                    Some(kind) => from_bson(kind.clone()).unwrap_or_default(),
+                    None => FailureKind::Other,
+                };
+                Ok(FailureGroup {
+                    kind,
+                    stage: from_bson(key.get("stage").cloned().unwrap_or_default())
+                        .map_err(bson_error)?,
+                    operation: key.get_str("operation").unwrap_or_default().to_string(),
+                    count: group_sum(group, "count")?,
+                    sample_error: group.get_str("sample_error").unwrap_or_default().to_string(),
+                    sample_filename: group
+                        .get_str("sample_filename")
+                        .unwrap_or_default()
+                        .to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Forgets the failures of image tasks that are being retried
+    pub async fn clear_image_failures(
+        &self,
+        task_ids: &[uuid::Uuid],
+    ) -> Result<u64, ProcessorError> {
+        let filter = doc! { "image_task_id": { "$in": to_bson(task_ids).map_err(bson_error)? } };
+
+        self.image_failures
+            .delete_many(filter, None)
+            .await
+            .map(|res| res.deleted_count)
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// BATCH FAIRNESS
+// A worker given an image task counts the tasks of its batch that are running
+// before starting it. With `fairness.max_in_flight_per_batch` of them running
+// the task is deferred instead: the worker records when it is due again and
+// lets the message go, and the scheduler requeues it once it is due, so the
+// workers serve the other batches meanwhile. A deferral is kept in MongoDB,
+// so it survives the worker and the scheduler stopping.
+//
+// Only tasks started within `running_ttl_secs` count, a task whose worker
+// died stays Running until it is redelivered. Workers count at the same time
+// without a lock, so a batch can briefly run a few tasks over its bound.
+// ============================================================================
+
+impl DBClient {
+    /// Counts the image tasks of a batch that are running and started after `started_after`
+    pub async fn count_running_image_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+        started_after: DateTime<Utc>,
+    ) -> Result<u64, ProcessorError> {
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Running).map_err(bson_error)?,
+            "time_started": { "$gt": timestamp::format(&started_after) },
+        };
+
+        self.image_tasks
+            .count_documents(filter, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Defers an image task that hasn't started until `until`.
+    ///
+    /// Returns `false` if it already started or finished, e.g. on a redelivery.
+    pub async fn defer_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+        until: DateTime<Utc>,
+    ) -> Result<bool, ProcessorError> {
+        let from = [TaskStatus::Waiting, TaskStatus::Ready]
+            .iter()
+            .map(to_bson)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(bson_error)?;
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": { "$in": from },
+        };
+        let update = doc! {
+            "$set": { "deferred_until": timestamp::format(&until) },
+            "$inc": { "deferrals": 1 },
+        };
+
+        self.image_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|result| result.matched_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Deferred image tasks due by `now`, the longest waiting first
+    pub async fn get_due_deferred_image_tasks(
+        &self,
+        now: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let filter = doc! {
+            "deferred_until": { "$lte": timestamp::format(&now) },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "deferred_until": 1 })
+            .limit(limit)
+            .build();
+
+        self.image_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records that a deferred image task was requeued. A task deferred again since it was read
+    /// keeps its new deferral.
+    pub async fn clear_image_task_deferral(
+        &self,
+        task: &DBImageTask,
+    ) -> Result<(), ProcessorError> {
+        let deferred_until = task
+            .deferred_until
+            .map(|until| timestamp::format(&until));
+        let filter = doc! {
+            "task_id": to_bson(&task.task_id).map_err(bson_error)?,
+            "deferred_until": deferred_until,
+        };
+        let update = doc! { "$unset": { "deferred_until": "" } };
+
+        self.image_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
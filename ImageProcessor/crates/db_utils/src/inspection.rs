@@ -0,0 +1,60 @@
+use common::error::ProcessorError;
+use common::inspection::DatasetValidationReport;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOneOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// DATASET INSPECTION
+// The decomposer records what it found in a dataset on its batch before the
+// first stage decomposes it, see `common::inspection`. A redelivered dataset
+// task inspects the dataset again and replaces the report. Dry runs read the
+// latest report of a dataset to estimate a job without downloading it.
+// ============================================================================
+
+impl DBClient {
+    /// Records the inspection of the batch's dataset
+    pub async fn record_dataset_validation(
+        &self,
+        batch_id: &uuid::Uuid,
+        report: &DatasetValidationReport,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$set": { "dataset_validation": to_bson(report).map_err(bson_error)? }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// The latest inspection of a dataset recorded by any batch submitted for it, with that
+    /// batch's id
+    pub async fn get_latest_dataset_validation(
+        &self,
+        dataset_key: &str,
+    ) -> Result<Option<(uuid::Uuid, DatasetValidationReport)>, ProcessorError> {
+        let filter = doc! { "dataset_key": dataset_key, "dataset_validation": { "$ne": null } };
+        let options = FindOneOptions::builder()
+            .sort(doc! { "time_created": -1 })
+            .build();
+
+        let batch = self
+            .dataset_batch_tasks
+            .find_one(filter, options)
+            .await
+            .map_err(db_error)?;
+        Ok(batch.and_then(|batch| {
+            batch
+                .dataset_validation
+                .map(|report| (batch.batch_id, report))
+        }))
+    }
+}
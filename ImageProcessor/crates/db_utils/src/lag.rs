@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use common::{error::ProcessorError, lag::PartitionLag, timestamp};
+use futures::future::BoxFuture;
+use mongodb::{
+    bson::doc,
+    options::UpdateOptions,
+};
+
+use crate::error::db_error;
+use crate::types::*;
+
+// ============================================================================
+// CONSUMER LAG
+// The lag of each consumer group as last reported by the scheduler, for an
+// autoscaler reading the backlog of a group, see `common::lag`. One record per
+// group, topic and partition, overwritten by every report.
+// ============================================================================
+
+impl DBClient {
+    /// Records the lag a consumer group was reported with
+    pub async fn save_consumer_lag(
+        &self,
+        group_id: &str,
+        lags: &[PartitionLag],
+    ) -> Result<(), ProcessorError> {
+        let now = timestamp::now();
+        for lag in lags {
+            let filter = doc! {
+                "group_id": group_id,
+                "topic": &lag.topic,
+                "partition": lag.partition,
+            };
+            let update = doc! {
+                "$set": {
+                    "committed_offset": lag.committed_offset,
+                    "high_watermark": lag.high_watermark,
+                    "lag": lag.lag,
+                    "time_updated": now.clone(),
+                }
+            };
+            let options = UpdateOptions::builder().upsert(true).build();
+
+            self.consumer_lag
+                .update_one(filter, update, options)
+                .await
+                .map_err(db_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Records the lag it is handed, see `LagReporter::with_sink`. A failed write is logged, the
+    /// next report catches up.
+    pub fn lag_recorder(
+        self: &Arc<Self>,
+    ) -> impl Fn(String, Vec<PartitionLag>) -> BoxFuture<'static, ()> + Send + Sync + 'static {
+        let db = Arc::clone(self);
+        move |group_id, lags| {
+            let db = Arc::clone(&db);
+            Box::pin(async move {
+                if let Err(e) = db.save_consumer_lag(&group_id, &lags).await {
+                    tracing::warn!(%group_id, error = %e, "Failed to record consumer lag");
+                }
+            })
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use common::{error::ProcessorError, timestamp};
+use mongodb::{
+    bson::{Document, doc, to_bson},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+};
+
+use crate::error::{bson_error, db_error, is_duplicate_key};
+use crate::types::*;
+
+// ============================================================================
+// SCHEDULER LEADERSHIP
+// Several scheduler instances can run as hot standbys, only the one holding
+// the lease moves batches along. The leader renews its lease every tick, and
+// once it stops, e.g. because its node died, the next standby to tick takes
+// over. Every takeover raises the lease's fencing token. The writes that move
+// stages along carry the token of the leader making them and leave the tasks
+// a newer leader already wrote to alone, so a leader that stalled past its
+// lease can't dispatch a stage the new one dispatched too.
+// ============================================================================
+
+/// Adds the fencing token of the writing leader to the filter and update of a dataset task write,
+/// which then only matches tasks no newer leader has written to
+pub(crate) fn fence(filter: &mut Document, set: &mut Document, token: u64) {
+    filter.insert(
+        "$or",
+        vec![
+            doc! { "fence": null },
+            doc! { "fence": { "$lte": token as i64 } },
+        ],
+    );
+    set.insert("fence", token as i64);
+}
+
+impl DBClient {
+    /// Takes or renews the lease named `name` for `holder`.
+    ///
+    /// Returns the fencing token while `holder` leads, or `None` if another instance holds an
+    /// unexpired lease.
+    pub async fn acquire_leadership(
+        &self,
+        name: &str,
+        holder: &str,
+        lease: Duration,
+    ) -> Result<Option<u64>, ProcessorError> {
+        let now = Utc::now();
+        let expires = timestamp::format(&(now + lease));
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        // Renewing keeps the token, nobody else wrote in between
+        let filter = doc! {
+            "name": name,
+            "holder": holder,
+            "lease_expires": { "$gte": timestamp::format(&now) },
+        };
+        let update = doc! { "$set": { "lease_expires": expires.clone() } };
+        if let Some(lease) = self
+            .leader_leases
+            .find_one_and_update(filter, update, options.clone())
+            .await
+            .map_err(db_error)?
+        {
+            return Ok(Some(lease.token));
+        }
+
+        // Taking over an expired lease, or the first one, raises the token. While the lease is
+        // held the upsert collides with it on the unique name instead.
+        let filter = doc! {
+            "name": name,
+            "lease_expires": { "$lt": timestamp::format(&now) },
+        };
+        let update = doc! {
+            "$set": { "holder": holder, "lease_expires": expires },
+            "$inc": { "token": 1_i64 },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        match self
+            .leader_leases
+            .find_one_and_update(filter, update, options)
+            .await
+        {
+            Ok(lease) => Ok(lease.map(|lease| lease.token)),
+            Err(e) if is_duplicate_key(&e) => Ok(None),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    /// Moves a dataset task from one status to another on behalf of the leader holding `token`,
+    /// like `transition_dataset_task`.
+    ///
+    /// Returns `false` without changing anything if the task wasn't in the `from` status or a
+    /// newer leader wrote to it.
+    pub async fn transition_dataset_task_fenced(
+        &self,
+        task_id: &uuid::Uuid,
+        from: TaskStatus,
+        to: TaskStatus,
+        token: u64,
+    ) -> Result<bool, ProcessorError> {
+        let mut filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&from).map_err(bson_error)?,
+        };
+        let mut set = doc! { "status": to_bson(&to).map_err(bson_error)? };
+        if to.is_final() {
+            set.insert(
+                "time_completed",
+                timestamp::now(),
+            );
+        }
+        fence(&mut filter, &mut set, token);
+
+        self.dataset_tasks
+            .update_one(filter, doc! { "$set": set }, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Claims a waiting dataset task for publishing by moving it to Ready, on behalf of the leader
+    /// holding `token`. The claim matches on the Waiting status, so of the leaders and ticks
+    /// releasing the task at once exactly one gets it.
+    ///
+    /// Returns the claimed task, or `None` if it wasn't waiting or a newer leader wrote to it.
+    pub async fn claim_waiting_dataset_task(
+        &self,
+        task_id: &uuid::Uuid,
+        token: u64,
+    ) -> Result<Option<DBDatasetTask>, ProcessorError> {
+        let mut filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Waiting).map_err(bson_error)?,
+        };
+        let mut set = doc! { "status": to_bson(&TaskStatus::Ready).map_err(bson_error)? };
+        fence(&mut filter, &mut set, token);
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.dataset_tasks
+            .find_one_and_update(filter, doc! { "$set": set }, options)
+            .await
+            .map_err(db_error)
+    }
+}
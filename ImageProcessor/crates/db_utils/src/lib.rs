@@ -1,26 +1,130 @@
-use chrono::Utc;
-use common::{DatasetProcessingJob, DatasetProcessingTask, ImageTask};
+use chrono::{DateTime, Utc};
+use common::{
+    DatasetProcessingJob, DatasetProcessingTask, ImageTask, config::MongoConfig,
+    correlation,
+    error::ProcessorError,
+    lifecycle::BatchState,
+    reproducibility::ConfigSnapshot,
+    timestamp,
+};
 use mongodb::{
     Client,
     bson::{Bson, doc},
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, FindOptions, ReadPreference, ReturnDocument,
+        SelectionCriteria,
+    },
     results::{InsertManyResult, InsertOneResult},
 };
+use futures::TryStreamExt;
+use std::collections::HashMap;
+mod accounting;
+mod alerts;
+mod analytics;
+mod approval;
+mod cache;
+mod capabilities;
+mod checkpoints;
+mod completion;
+mod dedup;
+mod drift;
+pub mod deletion;
+mod downloads;
+mod error;
+mod failures;
+mod fairness;
+mod inspection;
+mod lag;
+mod leadership;
+mod manifests;
+mod mappings;
+mod metadata;
+mod migrations;
+mod notifications;
+mod outbox;
+mod pipelines;
+mod profiles;
+mod provenance;
+mod queries;
+mod reports;
+pub mod lifecycle;
+mod recovery;
+mod retention;
+mod retry;
+mod scheduling;
+mod sharing;
+mod sidecars;
+mod slo;
+mod status;
+mod tenants;
+mod uploads;
 pub mod types;
 
+use error::{bson_error, db_error};
 use types::*;
 
 impl DBClient {
-    pub async fn new(db_name: &str) -> Self {
-        let clnt = Client::with_uri_str("mongodb://mongodb:27017")
+    pub async fn new(config: &MongoConfig) -> Self {
+        let clnt = Client::with_uri_str(&config.uri)
             .await
             .expect("Failed to connect to MongoDB");
-        let db = clnt.database(db_name);
+        let client = Self::from_client(&clnt, config);
+        client.ensure_indexes().await;
+
+        client
+    }
+
+    /// Connects for a read replica of the api-server, see `ApiConfig::read_only`.
+    ///
+    /// Reads go to the secondaries of the replica set, or to the primary while none is available,
+    /// so they may trail the writes of the other services by the replication lag. Indexes are
+    /// left to the clients of the write path.
+    pub async fn new_read_only(config: &MongoConfig) -> Self {
+        let mut options = ClientOptions::parse(&config.uri)
+            .await
+            .expect("Failed to parse the MongoDB uri");
+        options.selection_criteria = Some(SelectionCriteria::ReadPreference(
+            ReadPreference::SecondaryPreferred {
+                options: Default::default(),
+            },
+        ));
+        let clnt = Client::with_options(options).expect("Failed to connect to MongoDB");
+
+        Self::from_client(&clnt, config)
+    }
+
+    fn from_client(clnt: &Client, config: &MongoConfig) -> Self {
+        let db = clnt.database(&config.database);
 
         Self {
+            client: clnt.clone(),
             image_tasks: db.collection::<DBImageTask>("image_tasks"),
             dataset_tasks: db.collection::<DBDatasetTask>("dataset_tasks"),
             dataset_batch_tasks: db.collection::<DBDatasetProcessingJob>("dataset_batch_tasks"),
             mappings: db.collection::<DBMapping>("mappings"),
+            mapping_sets: db.collection::<DBMappingSet>("mapping_sets"),
+            upload_markers: db.collection::<DBUploadMarker>("upload_markers"),
+            download_audits: db.collection::<DBDownloadAudit>("download_audits"),
+            processed_messages: db.collection::<DBProcessedMessage>("processed_messages"),
+            latency_windows: db.collection::<DBLatencyWindow>("latency_windows"),
+            operation_profiles: db.collection::<DBOperationProfile>("operation_profiles"),
+            image_failures: db.collection::<DBImageFailure>("image_failures"),
+            leader_leases: db.collection::<DBLeaderLease>("leader_leases"),
+            consumer_checkpoints: db.collection::<DBConsumerCheckpoint>("consumer_checkpoints"),
+            consumer_lag: db.collection::<DBConsumerLag>("consumer_lag"),
+            outbox: db.collection::<DBOutboxEntry>("outbox"),
+            dataset_outbox: db.collection::<DBDatasetOutboxEntry>("dataset_outbox"),
+            topic_migrations: db.collection::<DBTopicMigration>("topic_migrations"),
+            notification_preferences: db
+                .collection::<DBNotificationPreferences>("notification_preferences"),
+            workers: db.collection::<DBWorker>("workers"),
+            held_tasks: db.collection::<DBHeldTask>("held_tasks"),
+            pipelines: db.collection::<DBPipeline>("pipelines"),
+            tenants: db.collection::<DBTenant>("tenants"),
+            tenant_api_keys: db.collection::<DBTenantApiKey>("tenant_api_keys"),
+            tenant_audit: db.collection::<DBTenantAudit>("tenant_audit"),
+            share_links: db.collection::<DBShareLink>("share_links"),
+            task_metrics: db.collection::<DBTaskMetrics>("task_metrics"),
         }
     }
 
@@ -28,28 +132,31 @@ impl DBClient {
         &self,
         dataset_task_id: uuid::Uuid,
         image_filename: &str,
+        output_name: &str,
         image_task_id: uuid::Uuid,
-    ) -> Result<InsertOneResult, String> {
+    ) -> Result<InsertOneResult, ProcessorError> {
         // first, we want to create the actual struct
         let data = DBMapping {
             id: None,
-            dataset_task_id: dataset_task_id,
+            dataset_task_id,
             image_filename: image_filename.to_string(),
-            image_task_id: image_task_id,
+            output_name: output_name.to_string(),
+            image_task_id,
         };
 
         self.mappings
             .insert_one(data, None)
             .await
-            .map_err(|e| e.to_string())
+            .map_err(db_error)
     }
 
+    /// The image task created for an image of a dataset task, looked up in its mappings and in
+    /// its mapping sets once they were compacted, see `mappings`
     pub async fn query_mappings(
         &self,
         dataset_task_id: &uuid::Uuid,
         image_filename: &str,
     ) -> Option<uuid::Uuid> {
-        
         let filter = doc! {
             "dataset_task_id": mongodb::bson::to_bson(&dataset_task_id).unwrap(),
             "image_filename": Bson::String(image_filename.to_string()),
@@ -57,25 +164,121 @@ impl DBClient {
 
         let res_document = self.mappings.find_one(filter, None).await.ok()?;
 
-        println!("{:?}", res_document);
+        tracing::debug!(mapping = ?res_document, "Queried image task mapping");
 
-        let result = res_document.map(|map| map.image_task_id);
-        // println!("{:?}", result);
-
-        result
+        match res_document {
+            Some(map) => Some(map.image_task_id),
+            None => self.query_mapping_sets(dataset_task_id, image_filename).await,
+        }
     }
 
-    pub async fn db_add_task(&self, task: &ImageTask) -> Result<InsertOneResult, String> {
+    pub async fn db_add_task(&self, task: &ImageTask) -> Result<InsertOneResult, ProcessorError> {
         self.image_tasks
             .insert_one(<&ImageTask as Into<DBImageTask>>::into(task), None)
             .await
-            .map_err(|e| e.to_string())
+            .map_err(db_error)
+    }
+
+    /// Sets the status of an image task, stamping `time_completed` when the status is final.
+    pub async fn update_image_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": mongodb::bson::to_bson(task_id).map_err(bson_error)? };
+        let mut set = doc! { "status": mongodb::bson::to_bson(&status).map_err(bson_error)? };
+        if status.is_final() {
+            set.insert("time_completed", timestamp::now());
+        }
+
+        self.image_tasks
+            .update_one(filter, doc! { "$set": set }, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Records the key an image task's output was actually written to
+    pub async fn set_image_task_output_key(
+        &self,
+        task_id: &uuid::Uuid,
+        output_key: &str,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": mongodb::bson::to_bson(task_id).map_err(bson_error)? };
+
+        self.image_tasks
+            .update_one(filter, doc! { "$set": { "output_key": output_key } }, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Fetches a single image task by id.
+    pub async fn get_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let filter = doc! { "task_id": mongodb::bson::to_bson(task_id).map_err(bson_error)? };
+
+        self.image_tasks
+            .find_one(filter, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Whether the image task with the given id finished successfully
+    pub async fn image_task_succeeded(&self, task_id: &uuid::Uuid) -> bool {
+        let Ok(task_id) = mongodb::bson::to_bson(task_id) else {
+            return false;
+        };
+        let filter = doc! {
+            "task_id": task_id,
+            "status": mongodb::bson::to_bson(&TaskStatus::Success).unwrap(),
+        };
+
+        matches!(self.image_tasks.count_documents(filter, None).await, Ok(count) if count > 0)
+    }
+
+    /// Whether the image task with the given id was filtered out by a quality gate
+    pub async fn image_task_filtered(&self, task_id: &uuid::Uuid) -> bool {
+        let Ok(task_id) = mongodb::bson::to_bson(task_id) else {
+            return false;
+        };
+        let filter = doc! {
+            "task_id": task_id,
+            "status": mongodb::bson::to_bson(&TaskStatus::Filtered).unwrap(),
+        };
+
+        matches!(self.image_tasks.count_documents(filter, None).await, Ok(count) if count > 0)
+    }
+
+    /// Returns the image tasks still waiting on the given image task
+    pub async fn get_waiting_dependents(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let filter = doc! {
+            "depends_on": mongodb::bson::to_bson(task_id).map_err(bson_error)?,
+            "status": mongodb::bson::to_bson(&TaskStatus::Waiting).map_err(bson_error)?,
+        };
+
+        self.image_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
     }
 
     pub async fn add_multi_operation_dataset(
         &self,
         ds_task: &DatasetProcessingJob,
-    ) -> Result<InsertOneResult, String> {
+        config: ConfigSnapshot,
+        sweep: Option<SweepMembership>,
+        parent_batch_id: Option<uuid::Uuid>,
+        retention_days: u32,
+    ) -> Result<InsertOneResult, ProcessorError> {
         // First, we convert the DatasetProcessingJob into a dataset batch task
 
         let db_task = DBDatasetProcessingJob {
@@ -90,12 +293,190 @@ impl DBClient {
 
             dataset_key: ds_task.dataset_key.clone(),
             operations: ds_task.operations.clone(),
+            dependencies: ds_task.dependencies.clone(),
+            requires_approval: ds_task.requires_approval.clone(),
+            annotations: HashMap::new(),
+            snapshots: Vec::new(),
+            config_snapshots: vec![config],
+            sweep,
+            state: BatchState::Created,
+            state_history: Vec::new(),
+            tags: ds_task.tags.clone(),
+            owner: ds_task.owner.clone(),
+            retention: Some(ResultsRetention {
+                days: retention_days,
+                expires_at: None,
+                warning_sent_at: None,
+                results_deleted_at: None,
+                notification_url: ds_task.notification_url.clone(),
+            }),
+            notification_preferences: ds_task.notification_preferences,
+            priority: ds_task.priority,
+            pipeline_mode: ds_task.pipeline_mode,
+            use_local_cache: ds_task.use_local_cache,
+            output_layout: ds_task.output_layout,
+            collision_policy: ds_task.collision_policy,
+            hash_suffix: ds_task.hash_suffix,
+            cache_scope: ds_task.cache_scope,
+            output_format: ds_task.output_format,
+            alpha_policy: ds_task.alpha_policy,
+            metadata_policy: ds_task.metadata_policy,
+            rewrite_sidecars: ds_task.rewrite_sidecars,
+            previews: ds_task.previews,
+            parent_batch_id,
+            tenant_id: ds_task.tenant_id.clone(),
+            slo_violations: Vec::new(),
+            manifests_published: 0,
+            last_manifest_at: None,
+            report: None,
+            storage_alert: None,
+            sidecars: Vec::new(),
+            dataset_validation: None,
+            intermediates_swept_at: None,
+            provenance_root: None,
+            batch_manifest_key: None,
+            quality_drifts: Vec::new(),
+            archive_failure: None,
         };
 
         self.dataset_batch_tasks
             .insert_one(db_task, None)
             .await
-            .map_err(|e| e.to_string())
+            .map_err(db_error)
+    }
+
+    /// Fetches the batch document for the given batch id, if it exists.
+    pub async fn get_batch(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Option<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! { "batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? };
+
+        self.dataset_batch_tasks
+            .find_one(filter, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Returns every batch created by the given parameter sweep.
+    pub async fn get_sweep_batches(
+        &self,
+        sweep_id: &uuid::Uuid,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! { "sweep.sweep_id": mongodb::bson::to_bson(sweep_id).map_err(bson_error)? };
+
+        self.dataset_batch_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Returns every batch cloned from the given batch, oldest first.
+    pub async fn get_child_batches(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! { "parent_batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? };
+        let options = FindOptions::builder()
+            .sort(doc! { "time_created": 1 })
+            .build();
+
+        self.dataset_batch_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records a new results snapshot on the batch document.
+    pub async fn add_batch_snapshot(
+        &self,
+        batch_id: &uuid::Uuid,
+        snapshot: &BatchSnapshot,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$push": { "snapshots": mongodb::bson::to_bson(snapshot).map_err(bson_error)? }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Records the configuration a component used while handling a batch.
+    ///
+    /// A snapshot of a dataset task is recorded once, redeliveries of the task don't add another.
+    pub async fn add_config_snapshot(
+        &self,
+        batch_id: &uuid::Uuid,
+        config: &ConfigSnapshot,
+    ) -> Result<(), ProcessorError> {
+        let mut filter =
+            doc! { "batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? };
+        if let Some(task_id) = &config.task_id {
+            filter.insert(
+                "config_snapshots.task_id",
+                doc! { "$ne": mongodb::bson::to_bson(task_id).map_err(bson_error)? },
+            );
+        }
+        let update = doc! {
+            "$push": { "config_snapshots": mongodb::bson::to_bson(config).map_err(bson_error)? }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Replaces the annotations attached to a batch.
+    ///
+    /// Returns the updated batch document, or `None` if no batch exists with the given id.
+    pub async fn set_batch_annotations(
+        &self,
+        batch_id: &uuid::Uuid,
+        annotations: &HashMap<String, serde_json::Value>,
+    ) -> Result<Option<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! { "batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$set": { "annotations": mongodb::bson::to_bson(annotations).map_err(bson_error)? }
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.dataset_batch_tasks
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Counts the image tasks created since the given time, optionally restricted to one status.
+    pub async fn count_image_tasks_since(
+        &self,
+        since: DateTime<Utc>,
+        status: Option<TaskStatus>,
+    ) -> Result<u64, ProcessorError> {
+        let mut filter = doc! {
+            "time_created": { "$gte": timestamp::format(&since) }
+        };
+        if let Some(status) = status {
+            filter.insert("status", mongodb::bson::to_bson(&status).map_err(bson_error)?);
+        }
+
+        self.image_tasks
+            .count_documents(filter, None)
+            .await
+            .map_err(db_error)
     }
 
     /// Adds a list of dataset processing tasks to the database.
@@ -111,21 +492,21 @@ impl DBClient {
     /// # Returns
     ///
     /// * `Ok(InsertManyResult)` on successful insertion.
-    /// * `Err(String)` if the insertion fails, containing the error message.
+    /// * `Err(ProcessorError)` if the insertion fails, containing the error message.
     ///
     /// # Errors
     ///
     /// Returns an error if the database insertion fails.
     pub async fn add_datasets(
         &self,
-        task: &Vec<DatasetProcessingTask>,
-    ) -> Result<InsertManyResult, String> {
+        task: &[DatasetProcessingTask],
+    ) -> Result<InsertManyResult, ProcessorError> {
         let db_entries: Vec<DBDatasetTask> =
-            task.iter().map(|el| DBDatasetTask::from(el)).collect();
+            task.iter().map(DBDatasetTask::from).collect();
         self.dataset_tasks
             .insert_many(db_entries, None)
             .await
-            .map_err(|e| e.to_string())
+            .map_err(db_error)
     }
 }
 
@@ -136,21 +517,77 @@ impl From<&DatasetProcessingTask> for DBDatasetTask {
             task_id: value.task_id,
             batch_id: value.batch_id,
             dataset_key: value.dataset_key.clone(),
-            depends_on: value.depends_on,
+            depends_on: value.depends_on.clone(),
             operation: value.operation.clone(),
+            fused_operations: value.fused_operations.clone(),
+            stage: value.stage,
+            input: value.input,
+            upstream_operations: value.upstream_operations.clone(),
+            requires_approval: value.requires_approval,
+            approval: None,
+            use_local_cache: value.use_local_cache,
+            output_layout: value.output_layout,
+            collision_policy: value.collision_policy,
+            hash_suffix: value.hash_suffix,
+            cache_scope: value.cache_scope,
+            default_format: value.default_format,
+            alpha_policy: value.alpha_policy,
+            metadata_policy: value.metadata_policy,
+            previews: value.previews,
+            object_tags: value.object_tags.clone(),
+            priority: value.priority,
+            tenant_id: value.tenant_id.clone(),
+            correlation_id: correlation::current(),
+            image_count: None,
+            lease_expires: None,
+            recoveries: 0,
+            fence: None,
+            mappings_compacted: false,
+            budget_checkpoint: None,
+            notice_sent_at: None,
+            quality: None,
 
             time_created: Utc::now(),
             time_completed: None,
             status: {
-                match value.depends_on {
-                    Some(_) => TaskStatus::Waiting,
-                    None => TaskStatus::Ready,
+                match value.depends_on.is_empty() {
+                    true => TaskStatus::Ready,
+                    false => TaskStatus::Waiting,
                 }
             },
         }
     }
 }
 
+impl From<&DBDatasetTask> for DatasetProcessingTask {
+    fn from(value: &DBDatasetTask) -> Self {
+        DatasetProcessingTask {
+            dataset_key: value.dataset_key.clone(),
+            task_id: value.task_id,
+            batch_id: value.batch_id,
+            operation: value.operation.clone(),
+            fused_operations: value.fused_operations.clone(),
+            depends_on: value.depends_on.clone(),
+            stage: value.stage,
+            input: value.input,
+            upstream_operations: value.upstream_operations.clone(),
+            requires_approval: value.requires_approval,
+            use_local_cache: value.use_local_cache,
+            output_layout: value.output_layout,
+            collision_policy: value.collision_policy,
+            hash_suffix: value.hash_suffix,
+            cache_scope: value.cache_scope,
+            default_format: value.default_format,
+            alpha_policy: value.alpha_policy,
+            metadata_policy: value.metadata_policy,
+            previews: value.previews,
+            object_tags: value.object_tags.clone(),
+            priority: value.priority,
+            tenant_id: value.tenant_id.clone(),
+        }
+    }
+}
+
 impl From<&ImageTask> for DBImageTask {
     fn from(task: &ImageTask) -> Self {
         DBImageTask {
@@ -159,12 +596,71 @@ impl From<&ImageTask> for DBImageTask {
             dataset_id: task.dataset_id,
             batch_id: task.batch_id,
             operation: task.operation.clone(),
+            fused_operations: task.fused_operations.clone(),
             task_id: task.task_id,
             time_created: Utc::now(),
             time_completed: None,
             status: TaskStatus::Waiting,
-            depends_on: None,
+            depends_on: task.depends_on,
             dependency_dataset_task_id: task.dependency_dataset_task_id,
+            input_dimensions: task.input_dimensions,
+            output_dimensions: task.output_dimensions,
+            affinity_key: task.affinity_key.clone(),
+            output_key: task.output_key.clone(),
+            source_path: task.source_path.clone(),
+            hash_suffix: task.hash_suffix,
+            cache_scope: task.cache_scope,
+            default_format: task.default_format,
+            alpha_policy: task.alpha_policy,
+            metadata_policy: task.metadata_policy,
+            previews: task.previews,
+            object_tags: task.object_tags.clone(),
+            error: None,
+            failure_kind: None,
+            storage_error: None,
+            backtrace: None,
+            filter_reason: None,
+            metadata: None,
+            provenance: None,
+            time_started: None,
+            priority: task.priority,
+            tenant_id: task.tenant_id.clone(),
+            bytes_read: None,
+            bytes_written: None,
+            cache_hit: None,
+            manifest_sequence: None,
+            quality: None,
+            deferred_until: None,
+            deferrals: 0,
+        }
+    }
+}
+
+impl From<&DBImageTask> for ImageTask {
+    fn from(task: &DBImageTask) -> Self {
+        ImageTask {
+            s3_key: task.s3_key.clone(),
+            dataset_id: task.dataset_id,
+            batch_id: task.batch_id,
+            task_id: task.task_id,
+            depends_on: task.depends_on,
+            dependency_dataset_task_id: task.dependency_dataset_task_id,
+            operation: task.operation.clone(),
+            fused_operations: task.fused_operations.clone(),
+            input_dimensions: task.input_dimensions,
+            output_dimensions: task.output_dimensions,
+            affinity_key: task.affinity_key.clone(),
+            output_key: task.output_key.clone(),
+            source_path: task.source_path.clone(),
+            hash_suffix: task.hash_suffix,
+            cache_scope: task.cache_scope,
+            default_format: task.default_format,
+            alpha_policy: task.alpha_policy,
+            metadata_policy: task.metadata_policy,
+            previews: task.previews,
+            object_tags: task.object_tags.clone(),
+            priority: task.priority,
+            tenant_id: task.tenant_id.clone(),
         }
     }
 }
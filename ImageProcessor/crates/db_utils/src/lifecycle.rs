@@ -0,0 +1,185 @@
+use chrono::{Duration, Utc};
+use common::{error::ProcessorError, lifecycle::BatchState, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{Bson, doc, to_bson},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+/// The coarse task status mirrored on the batch document for older readers
+pub fn batch_task_status(state: &BatchState) -> TaskStatus {
+    match state {
+        BatchState::Created => TaskStatus::Waiting,
+        BatchState::Decomposing | BatchState::Processing { .. } | BatchState::Finalizing => {
+            TaskStatus::Running
+        }
+        BatchState::Completed => TaskStatus::Success,
+        BatchState::Failed | BatchState::TimedOut => TaskStatus::Failure,
+        BatchState::Cancelled => TaskStatus::Cancelled,
+    }
+}
+
+/// Number of tasks a cancellation stopped
+#[derive(Debug, Clone, Default)]
+pub struct CancelledTasks {
+    pub dataset_tasks: u64,
+    pub image_tasks: u64,
+}
+
+impl DBClient {
+    /// Moves a batch to a new lifecycle state.
+    ///
+    /// The transition is checked against the batch state machine and applied only if the batch
+    /// is still in the state it was read in, so concurrent updates can't skip a check. Returns
+    /// the updated batch, `NotFound` if the batch doesn't exist, `Validation` if the transition
+    /// is illegal and `Conflict` if another writer changed the state first.
+    pub async fn transition_batch(
+        &self,
+        batch_id: &uuid::Uuid,
+        next: BatchState,
+    ) -> Result<DBDatasetProcessingJob, ProcessorError> {
+        let batch = self
+            .get_batch(batch_id)
+            .await?
+            .ok_or_else(|| ProcessorError::NotFound(format!("Batch {} does not exist", batch_id)))?;
+        let next = batch
+            .state
+            .transition(next)
+            .map_err(ProcessorError::Validation)?;
+
+        let now = Utc::now();
+        let status = batch_task_status(&next);
+        let mut set = doc! {
+            "state": to_bson(&next).map_err(bson_error)?,
+            "status": to_bson(&status).map_err(bson_error)?,
+        };
+        if next.is_final() {
+            set.insert("time_completed", timestamp::format(&now));
+            // The retention period of the results starts once nothing writes them anymore
+            if let Some(retention) = &batch.retention {
+                let expires_at = now + Duration::days(retention.days as i64);
+                set.insert("retention.expires_at", timestamp::format(&expires_at));
+            }
+        } else if batch.state.is_final() {
+            // Reopened by a retry, the report of the first attempt is replaced once it finishes
+            // and storage that still denies access alerts again
+            set.insert("report", Bson::Null);
+            set.insert("storage_alert", Bson::Null);
+        }
+        let change = BatchStateChange {
+            from: batch.state,
+            to: next,
+            time: now,
+        };
+
+        // Batches created before the state machine have no state field, they are still Created
+        let current_state = match batch.state {
+            BatchState::Created => {
+                doc! { "$in": [to_bson(&batch.state).map_err(bson_error)?, null] }
+            }
+            state => doc! { "$eq": to_bson(&state).map_err(bson_error)? },
+        };
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "state": current_state,
+        };
+        let update = doc! {
+            "$set": set,
+            "$push": { "state_history": to_bson(&change).map_err(bson_error)? },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.dataset_batch_tasks
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| {
+                ProcessorError::Conflict(format!(
+                    "Batch {} changed state concurrently, {:?} to {:?} was not applied",
+                    batch_id, batch.state, next
+                ))
+            })
+    }
+
+    /// Cancels a batch and every one of its tasks that hasn't finished yet.
+    ///
+    /// The batch is moved to Cancelled first, so decomposers and workers that check it stop
+    /// picking up its tasks, then the unfinished dataset and image tasks are marked Cancelled.
+    /// Image tasks created by a decomposition that was already running are caught by the workers.
+    pub async fn cancel_batch(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<(DBDatasetProcessingJob, CancelledTasks), ProcessorError> {
+        let batch = self
+            .transition_batch(batch_id, BatchState::Cancelled)
+            .await?;
+
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "status": { "$in": [
+                to_bson(&TaskStatus::Waiting).map_err(bson_error)?,
+                to_bson(&TaskStatus::Ready).map_err(bson_error)?,
+                to_bson(&TaskStatus::Running).map_err(bson_error)?,
+            ] },
+        };
+        let update = doc! {
+            "$set": {
+                "status": to_bson(&TaskStatus::Cancelled).map_err(bson_error)?,
+                "time_completed": timestamp::now(),
+            }
+        };
+
+        let dataset_tasks = self
+            .dataset_tasks
+            .update_many(filter.clone(), update.clone(), None)
+            .await
+            .map_err(db_error)?
+            .modified_count;
+        let image_tasks = self
+            .image_tasks
+            .update_many(filter, update, None)
+            .await
+            .map_err(db_error)?
+            .modified_count;
+
+        Ok((
+            batch,
+            CancelledTasks {
+                dataset_tasks,
+                image_tasks,
+            },
+        ))
+    }
+
+    /// Whether the batch was cancelled, missing batches count as not cancelled
+    pub async fn is_batch_cancelled(&self, batch_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        Ok(self
+            .get_batch(batch_id)
+            .await?
+            .is_some_and(|batch| batch.state == BatchState::Cancelled))
+    }
+
+    /// Returns every batch that hasn't reached a final state.
+    ///
+    /// Batches recorded before the lifecycle have no state and only their task status, they are
+    /// left out rather than read as Created.
+    pub async fn get_active_batches(&self) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! {
+            "state": { "$exists": true },
+            "state.state": { "$nin": ["Completed", "Failed", "Cancelled", "TimedOut"] },
+        };
+
+        self.dataset_batch_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+}
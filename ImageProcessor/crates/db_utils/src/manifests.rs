@@ -0,0 +1,139 @@
+use common::{error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{Document, doc, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// RESULTS MANIFESTS
+// The numbered manifests a batch publishes its outputs in, see
+// `common::manifest`. A number is claimed on the batch before its manifest is
+// written, so concurrent schedulers never publish the same one, and the
+// outputs are marked with it once it is out. An attempt that fails in between
+// leaves its outputs unmarked for the next manifest.
+//
+// The single manifest of a completed batch is rewritten whole by every
+// attempt, only its key is recorded on the batch.
+// ============================================================================
+
+impl DBClient {
+    /// Counts the outputs of a dataset task that are in no manifest yet
+    pub async fn count_unpublished_results(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+    ) -> Result<u64, ProcessorError> {
+        self.image_tasks
+            .count_documents(unpublished_filter(dataset_task_id)?, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// The successful image tasks of a dataset task whose output is in no manifest yet
+    pub async fn get_unpublished_results(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        self.image_tasks
+            .find(unpublished_filter(dataset_task_id)?, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Claims the next manifest number of a batch.
+    ///
+    /// Returns `None` if another scheduler claimed it since the batch was read.
+    pub async fn claim_manifest_sequence(
+        &self,
+        batch: &DBDatasetProcessingJob,
+    ) -> Result<Option<u32>, ProcessorError> {
+        let sequence = batch.manifests_published;
+        // Batches that never published a manifest have no counter yet
+        let current = match sequence {
+            0 => doc! { "$in": [0, null] },
+            sequence => doc! { "$eq": sequence },
+        };
+        let filter = doc! {
+            "batch_id": to_bson(&batch.batch_id).map_err(bson_error)?,
+            "manifests_published": current,
+        };
+        let update = doc! {
+            "$inc": { "manifests_published": 1 },
+            "$set": { "last_manifest_at": timestamp::now() },
+        };
+
+        let claimed = self
+            .dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map_err(db_error)?
+            .modified_count
+            == 1;
+        Ok(claimed.then_some(sequence))
+    }
+
+    /// Every image task of a batch, by path in the dataset
+    pub async fn get_batch_image_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let options = FindOptions::builder()
+            .sort(doc! { "source_path": 1 })
+            .build();
+
+        self.image_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records where the manifest of a completed batch was written
+    pub async fn set_batch_manifest_key(
+        &self,
+        batch_id: &uuid::Uuid,
+        key: &str,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "batch_manifest_key": key } };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Records the manifest the outputs of the given image tasks were published in
+    pub async fn mark_published_results(
+        &self,
+        task_ids: &[uuid::Uuid],
+        sequence: u32,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": { "$in": to_bson(task_ids).map_err(bson_error)? } };
+        let update = doc! { "$set": { "manifest_sequence": sequence } };
+
+        self.image_tasks
+            .update_many(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
+
+fn unpublished_filter(dataset_task_id: &uuid::Uuid) -> Result<Document, ProcessorError> {
+    Ok(doc! {
+        "dataset_id": to_bson(dataset_task_id).map_err(bson_error)?,
+        "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+        "manifest_sequence": null,
+    })
+}
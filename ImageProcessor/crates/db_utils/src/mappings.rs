@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::{FindOneOptions, FindOptions, ReplaceOptions},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// MAPPING COMPACTION
+// The decomposer records a mapping per image, which later stages resolve their
+// dependencies by. Once a dataset task has finished no mapping is added to it,
+// so the scheduler folds its mappings into mapping sets, one document holding
+// every filename and the image task created for it, and deletes the per-image
+// documents. Datasets with more than MAPPINGS_PER_SET images are split over
+// several sets, documents are limited to 16MB.
+//
+// Lookups check the per-image documents first and the sets after them. The
+// sets are written before the mappings are deleted, so a lookup running
+// during a compaction finds the image in one of the two.
+// ============================================================================
+
+// About 1.5MB per set with filenames of 100 characters
+const MAPPINGS_PER_SET: usize = 10_000;
+
+impl DBClient {
+    /// Finished dataset tasks whose mappings weren't compacted yet, completed before the cutoff
+    pub async fn get_uncompacted_dataset_tasks(
+        &self,
+        completed_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<DBDatasetTask>, ProcessorError> {
+        let final_statuses = [TaskStatus::Success, TaskStatus::Failure, TaskStatus::Cancelled];
+        let filter = doc! {
+            "status": { "$in": to_bson(&final_statuses).map_err(bson_error)? },
+            "time_completed": {
+                "$lte": timestamp::format(&completed_before)
+            },
+            "mappings_compacted": { "$ne": true },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "time_completed": 1 })
+            .limit(limit)
+            .build();
+
+        self.dataset_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Folds the mappings of a finished dataset task into mapping sets and deletes them.
+    ///
+    /// Returns the number of mappings folded. Running it again after an interruption redoes
+    /// what is left, sets are replaced rather than added to.
+    pub async fn compact_mappings(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+    ) -> Result<usize, ProcessorError> {
+        let task_filter = doc! { "dataset_task_id": to_bson(dataset_task_id).map_err(bson_error)? };
+        let options = FindOptions::builder()
+            .sort(doc! { "image_filename": 1 })
+            .build();
+        let mappings: Vec<DBMapping> = self
+            .mappings
+            .find(task_filter.clone(), options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        // No mappings writes no set. Nothing left means an earlier run was interrupted after
+        // deleting them, and the sets it wrote stay as they are.
+        for (chunk, mappings) in mappings.chunks(MAPPINGS_PER_SET).enumerate() {
+            let set = DBMappingSet {
+                id: None,
+                dataset_task_id: *dataset_task_id,
+                chunk: chunk as u32,
+                images: mappings
+                    .iter()
+                    .map(|mapping| MappedImage {
+                        image_filename: mapping.image_filename.clone(),
+                        output_name: mapping.output_name.clone(),
+                        image_task_id: mapping.image_task_id,
+                    })
+                    .collect(),
+            };
+            let mut filter = task_filter.clone();
+            filter.insert("chunk", chunk as u32);
+            self.mapping_sets
+                .replace_one(filter, set, ReplaceOptions::builder().upsert(true).build())
+                .await
+                .map_err(db_error)?;
+        }
+        if !mappings.is_empty() {
+            self.mappings
+                .delete_many(task_filter, None)
+                .await
+                .map_err(db_error)?;
+        }
+
+        let filter = doc! { "task_id": to_bson(dataset_task_id).map_err(bson_error)? };
+        self.dataset_tasks
+            .update_one(filter, doc! { "$set": { "mappings_compacted": true } }, None)
+            .await
+            .map_err(db_error)?;
+
+        Ok(mappings.len())
+    }
+
+    /// The image task created for an image of a dataset task whose mappings were compacted
+    pub(crate) async fn query_mapping_sets(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        image_filename: &str,
+    ) -> Option<uuid::Uuid> {
+        let filter = doc! {
+            "dataset_task_id": to_bson(dataset_task_id).ok()?,
+            "images.image_filename": image_filename,
+        };
+        // Only the matching image is read back, not the whole set
+        let options = FindOneOptions::builder()
+            .projection(doc! { "dataset_task_id": 1, "chunk": 1, "images.$": 1 })
+            .build();
+
+        let set = self.mapping_sets.find_one(filter, options).await.ok()??;
+        set.images.first().map(|image| image.image_task_id)
+    }
+}
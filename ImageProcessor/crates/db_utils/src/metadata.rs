@@ -0,0 +1,71 @@
+use common::{error::ProcessorError, metadata::ImageMetadata};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// IMAGE METADATA
+// Every image task records what its worker read from its input, see
+// `common::metadata`. The tasks reading the uploaded dataset hold what the
+// user uploaded, e.g. the cameras the images were taken with, which is what
+// the batch's images are looked up by.
+// ============================================================================
+
+impl DBClient {
+    /// Records what the worker read from the input of an image task
+    pub async fn set_image_task_metadata(
+        &self,
+        task_id: &uuid::Uuid,
+        metadata: &ImageMetadata,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "metadata": to_bson(metadata).map_err(bson_error)? } };
+
+        self.image_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Returns the image tasks of a batch that read the uploaded dataset and recorded its
+    /// metadata, narrowed to the camera and GPS presence given, ordered by path in the dataset
+    pub async fn get_image_metadata(
+        &self,
+        batch_id: &uuid::Uuid,
+        camera_make: Option<&str>,
+        camera_model: Option<&str>,
+        has_gps: Option<bool>,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let mut filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "dependency_dataset_task_id": null,
+            "metadata": { "$ne": null },
+        };
+        if let Some(camera_make) = camera_make {
+            filter.insert("metadata.camera_make", camera_make);
+        }
+        if let Some(camera_model) = camera_model {
+            filter.insert("metadata.camera_model", camera_model);
+        }
+        if let Some(has_gps) = has_gps {
+            filter.insert("metadata.has_gps", has_gps);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "source_path": 1 })
+            .build();
+
+        self.image_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+}
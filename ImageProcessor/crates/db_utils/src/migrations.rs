@@ -0,0 +1,68 @@
+use common::{
+    error::ProcessorError,
+    migration::{MigrationError, MigrationState, TopicMigrationReport},
+    timestamp,
+};
+use mongodb::bson::{doc, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// TOPIC MIGRATIONS
+// The state of the topic migrations the api-server runs in the background,
+// one record per migration, see `common::migration`.
+// ============================================================================
+
+impl DBClient {
+    pub async fn insert_topic_migration(
+        &self,
+        migration: &DBTopicMigration,
+    ) -> Result<(), ProcessorError> {
+        self.topic_migrations
+            .insert_one(migration, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Records how a running migration ended
+    pub async fn finish_topic_migration(
+        &self,
+        migration_id: &uuid::Uuid,
+        result: &Result<TopicMigrationReport, MigrationError>,
+    ) -> Result<(), ProcessorError> {
+        let mut set = doc! { "time_finished": timestamp::now() };
+        match result {
+            Ok(report) => {
+                set.insert("state", to_bson(&MigrationState::Completed).map_err(bson_error)?);
+                set.insert("report", to_bson(report).map_err(bson_error)?);
+            }
+            Err(error) => {
+                set.insert("state", to_bson(&MigrationState::Failed).map_err(bson_error)?);
+                set.insert("error", to_bson(error).map_err(bson_error)?);
+            }
+        }
+        let update = doc! { "$set": set };
+
+        self.topic_migrations
+            .update_one(
+                doc! { "migration_id": to_bson(migration_id).map_err(bson_error)? },
+                update,
+                None,
+            )
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    pub async fn get_topic_migration(
+        &self,
+        migration_id: &uuid::Uuid,
+    ) -> Result<Option<DBTopicMigration>, ProcessorError> {
+        self.topic_migrations
+            .find_one(doc! { "migration_id": to_bson(migration_id).map_err(bson_error)? }, None)
+            .await
+            .map_err(db_error)
+    }
+}
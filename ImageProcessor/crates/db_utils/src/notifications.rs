@@ -0,0 +1,124 @@
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, notifications::NotificationPreferences, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::UpdateOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// NOTIFICATION PREFERENCES
+// Preferences are kept on the batch when the job set them, and per tenant in
+// their own collection, see `common::notifications`. Finished stages are
+// claimed by the alerting instance that notifies about them, whether the
+// preferences asked for the notice or not, so they are only looked at once.
+// ============================================================================
+
+impl DBClient {
+    /// The preferences of a tenant, `None` for every caller without authentication
+    pub async fn get_notification_preferences(
+        &self,
+        tenant_id: Option<&str>,
+    ) -> Result<Option<NotificationPreferences>, ProcessorError> {
+        self.notification_preferences
+            .find_one(doc! { "tenant_id": tenant_id }, None)
+            .await
+            .map(|record| record.map(|record| record.preferences))
+            .map_err(db_error)
+    }
+
+    /// Replaces the preferences of a tenant
+    pub async fn set_notification_preferences(
+        &self,
+        tenant_id: Option<&str>,
+        preferences: &NotificationPreferences,
+    ) -> Result<(), ProcessorError> {
+        let update = doc! {
+            "$set": {
+                "preferences": to_bson(preferences).map_err(bson_error)?,
+                "time_updated": timestamp::now(),
+            }
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.notification_preferences
+            .update_one(doc! { "tenant_id": tenant_id }, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Replaces the preferences of a batch, returning `false` if there is no such batch
+    pub async fn set_batch_notification_preferences(
+        &self,
+        batch_id: &uuid::Uuid,
+        preferences: &NotificationPreferences,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$set": { "notification_preferences": to_bson(preferences).map_err(bson_error)? }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|result| result.matched_count == 1)
+            .map_err(db_error)
+    }
+
+    /// The preferences that apply to a batch: its own, else its tenant's, else the defaults
+    pub async fn batch_notification_preferences(
+        &self,
+        batch: &DBDatasetProcessingJob,
+    ) -> Result<NotificationPreferences, ProcessorError> {
+        if let Some(preferences) = batch.notification_preferences {
+            return Ok(preferences);
+        }
+        self.get_notification_preferences(batch.tenant_id.as_deref())
+            .await
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Returns the stages that finished since the given time and weren't notified about yet
+    pub async fn get_stages_awaiting_notice(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DBDatasetTask>, ProcessorError> {
+        let finished = [TaskStatus::Success, TaskStatus::Failure];
+        let filter = doc! {
+            "status": { "$in": to_bson(&finished).map_err(bson_error)? },
+            "time_completed": { "$gte": timestamp::format(&since) },
+            "notice_sent_at": null,
+        };
+
+        self.dataset_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records that a finished stage was notified about, before it is.
+    ///
+    /// Returns `false` if it had already been recorded, e.g. by another alerting instance.
+    pub async fn claim_stage_notice(&self, task_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "notice_sent_at": null,
+        };
+        let update = doc! {
+            "$set": { "notice_sent_at": timestamp::now() }
+        };
+
+        self.dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|result| result.modified_count == 1)
+            .map_err(db_error)
+    }
+}
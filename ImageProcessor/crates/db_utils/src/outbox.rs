@@ -0,0 +1,251 @@
+use chrono::{DateTime, Utc};
+use common::{DatasetProcessingTask, ImageTask, error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    ClientSession,
+    bson::{doc, to_bson, to_document},
+    options::{FindOptions, UpdateOptions},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// TRANSACTIONAL OUTBOX
+// The decomposer records an image task and then sends it to Kafka. Crashing
+// between the two left a task nothing was ever sent for, or one sent while its
+// write was lost. With the outbox the task is marked Ready in the same MongoDB
+// transaction that writes an entry holding the message, and the message is
+// only sent once the transaction committed. The decomposer sends it right
+// away and marks the entry sent. Entries left unsent, because the send failed
+// or the decomposer stopped before it, are sent by the relay of the scheduler.
+// The api-server does the same for the first stages of a new batch, whose
+// dataset tasks are recorded in one transaction with their entries.
+//
+// A message can go out twice, when the process stopped between the send and
+// marking the entry, which the workers tolerate as they do any redelivery.
+// ============================================================================
+
+impl DBClient {
+    /// Records an image task without a dependency as Ready along with its outbox entry, in one
+    /// transaction.
+    ///
+    /// Returns whether the task was inserted, an existing task and its entry are left untouched.
+    pub async fn add_task_with_outbox(&self, task: &ImageTask) -> Result<bool, ProcessorError> {
+        let mut record = DBImageTask::from(task);
+        record.status = TaskStatus::Ready;
+        let filter = doc! { "task_id": to_bson(&record.task_id).map_err(bson_error)? };
+        let update = doc! { "$setOnInsert": to_document(&record).map_err(bson_error)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        let mut session = self.start_transaction().await?;
+        let inserted = self
+            .image_tasks
+            .update_one_with_session(filter, update, options, &mut session)
+            .await
+            .map_err(db_error)?
+            .upserted_id
+            .is_some();
+        if inserted {
+            self.insert_outbox_entry(task, &mut session).await?;
+        }
+        session.commit_transaction().await.map_err(db_error)?;
+
+        Ok(inserted)
+    }
+
+    /// Moves a Waiting image task to Ready and writes its outbox entry, in one transaction.
+    ///
+    /// Returns the message of the entry, or `None` if the task wasn't Waiting anymore.
+    pub async fn enqueue_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<ImageTask>, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Waiting).map_err(bson_error)?,
+        };
+        let update = doc! {
+            "$set": { "status": to_bson(&TaskStatus::Ready).map_err(bson_error)? }
+        };
+
+        let mut session = self.start_transaction().await?;
+        let Some(record) = self
+            .image_tasks
+            .find_one_and_update_with_session(filter, update, None, &mut session)
+            .await
+            .map_err(db_error)?
+        else {
+            return Ok(None);
+        };
+        let task = ImageTask::from(&record);
+        self.insert_outbox_entry(&task, &mut session).await?;
+        session.commit_transaction().await.map_err(db_error)?;
+
+        Ok(Some(task))
+    }
+
+    /// Records the dataset tasks of a new batch along with an outbox entry for each task of a
+    /// first stage, in one transaction. Later stages are published by the scheduler once their
+    /// dependency finished.
+    pub async fn add_datasets_with_outbox(
+        &self,
+        tasks: &[DatasetProcessingTask],
+    ) -> Result<(), ProcessorError> {
+        let records: Vec<DBDatasetTask> = tasks.iter().map(DBDatasetTask::from).collect();
+        let now = Utc::now();
+        let entries: Vec<DBDatasetOutboxEntry> = tasks
+            .iter()
+            .filter(|task| task.depends_on.is_empty())
+            .map(|task| DBDatasetOutboxEntry {
+                id: None,
+                task_id: task.task_id,
+                task: task.clone(),
+                time_created: now,
+                time_sent: None,
+            })
+            .collect();
+
+        let mut session = self.start_transaction().await?;
+        self.dataset_tasks
+            .insert_many_with_session(records, None, &mut session)
+            .await
+            .map_err(db_error)?;
+        if !entries.is_empty() {
+            self.dataset_outbox
+                .insert_many_with_session(entries, None, &mut session)
+                .await
+                .map_err(db_error)?;
+        }
+        session.commit_transaction().await.map_err(db_error)
+    }
+
+    /// Records that the message of an image task's entry was sent
+    pub async fn mark_outbox_sent(&self, task_id: &uuid::Uuid) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "time_sent": timestamp::now() } };
+
+        self.outbox
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Records that the message of a dataset task's entry was sent
+    pub async fn mark_dataset_outbox_sent(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "time_sent": timestamp::now() } };
+
+        self.dataset_outbox
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Entries still unsent that were written before the cutoff, oldest first
+    pub async fn get_unsent_outbox_entries(
+        &self,
+        created_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<DBOutboxEntry>, ProcessorError> {
+        let filter = doc! {
+            "time_sent": null,
+            "time_created": { "$lte": timestamp::format(&created_before) },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "time_created": 1 })
+            .limit(limit)
+            .build();
+
+        self.outbox
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Dataset task entries still unsent that were written before the cutoff, oldest first
+    pub async fn get_unsent_dataset_outbox_entries(
+        &self,
+        created_before: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<DBDatasetOutboxEntry>, ProcessorError> {
+        let filter = doc! {
+            "time_sent": null,
+            "time_created": { "$lte": timestamp::format(&created_before) },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "time_created": 1 })
+            .limit(limit)
+            .build();
+
+        self.dataset_outbox
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Deletes the image and dataset task entries sent before the cutoff, returning how many
+    /// there were
+    pub async fn delete_sent_outbox_entries(
+        &self,
+        sent_before: DateTime<Utc>,
+    ) -> Result<u64, ProcessorError> {
+        let filter = doc! {
+            "time_sent": { "$lte": timestamp::format(&sent_before) },
+        };
+
+        let images = self
+            .outbox
+            .delete_many(filter.clone(), None)
+            .await
+            .map_err(db_error)?;
+        let datasets = self
+            .dataset_outbox
+            .delete_many(filter, None)
+            .await
+            .map_err(db_error)?;
+        Ok(images.deleted_count + datasets.deleted_count)
+    }
+
+    async fn start_transaction(&self) -> Result<ClientSession, ProcessorError> {
+        let mut session = self.client.start_session(None).await.map_err(db_error)?;
+        session.start_transaction(None).await.map_err(db_error)?;
+        Ok(session)
+    }
+
+    async fn insert_outbox_entry(
+        &self,
+        task: &ImageTask,
+        session: &mut ClientSession,
+    ) -> Result<(), ProcessorError> {
+        let Some(task_id) = task.task_id else {
+            return Err(ProcessorError::Internal(
+                "Image tasks need an id to be published through the outbox".to_string(),
+            ));
+        };
+        let entry = DBOutboxEntry {
+            id: None,
+            task_id,
+            task: task.clone(),
+            time_created: Utc::now(),
+            time_sent: None,
+        };
+
+        self.outbox
+            .insert_one_with_session(entry, None, session)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
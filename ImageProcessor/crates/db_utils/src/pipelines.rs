@@ -0,0 +1,90 @@
+use common::{ImageOperation, error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// NAMED PIPELINES
+// Chains of operations a tenant saves by name and refers to in its jobs
+// instead of sending the operations every time, one document per tenant and
+// name. Replacing a pipeline bumps its version, and every batch records the
+// version it ran in its config snapshot, so a batch stays reproducible after
+// the pipeline changed.
+// ============================================================================
+
+impl DBClient {
+    /// The pipeline of a tenant with the given name
+    pub async fn get_pipeline(
+        &self,
+        tenant_id: Option<&str>,
+        name: &str,
+    ) -> Result<Option<DBPipeline>, ProcessorError> {
+        self.pipelines
+            .find_one(doc! { "tenant_id": tenant_id, "name": name }, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Every pipeline of a tenant, by name
+    pub async fn get_pipelines(
+        &self,
+        tenant_id: Option<&str>,
+    ) -> Result<Vec<DBPipeline>, ProcessorError> {
+        let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+
+        self.pipelines
+            .find(doc! { "tenant_id": tenant_id }, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Creates the pipeline of a tenant, or replaces it with a new version, and returns it
+    pub async fn put_pipeline(
+        &self,
+        tenant_id: Option<&str>,
+        name: &str,
+        description: Option<&str>,
+        operations: &[ImageOperation],
+    ) -> Result<Option<DBPipeline>, ProcessorError> {
+        let now = timestamp::now();
+        let update = doc! {
+            "$set": {
+                "description": description,
+                "operations": to_bson(operations).map_err(bson_error)?,
+                "time_updated": now.clone(),
+            },
+            "$inc": { "version": 1 },
+            "$setOnInsert": { "time_created": now },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.pipelines
+            .find_one_and_update(doc! { "tenant_id": tenant_id, "name": name }, update, options)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Deletes the pipeline of a tenant, returning `false` if there is no such pipeline
+    pub async fn delete_pipeline(
+        &self,
+        tenant_id: Option<&str>,
+        name: &str,
+    ) -> Result<bool, ProcessorError> {
+        self.pipelines
+            .delete_one(doc! { "tenant_id": tenant_id, "name": name }, None)
+            .await
+            .map(|result| result.deleted_count == 1)
+            .map_err(db_error)
+    }
+}
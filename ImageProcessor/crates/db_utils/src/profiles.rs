@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use common::{error::ProcessorError, profiles::moving_average};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson, to_document},
+    options::UpdateOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// OPERATION PROFILES
+// One document per operation, folded forward by the scheduler that completed
+// a stage. A stage is completed by a single scheduler, so it is counted once,
+// two stages of the same operation finishing in the same tick may lose one of
+// the updates, which the moving average absorbs.
+// ============================================================================
+
+impl DBClient {
+    /// Latencies in milliseconds of the image tasks of a stage that succeeded, keyed by operation
+    /// name
+    pub async fn stage_image_task_latencies(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+    ) -> Result<HashMap<String, Vec<f64>>, ProcessorError> {
+        let filter = doc! {
+            "dataset_id": to_bson(dataset_task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+        };
+
+        self.image_task_latencies(filter).await
+    }
+
+    /// Folds the latencies of a finished stage into the profile of its operation
+    pub async fn record_operation_profile(
+        &self,
+        operation: &str,
+        samples: &[f64],
+        smoothing: f64,
+    ) -> Result<(), ProcessorError> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let mean_ms = samples.iter().sum::<f64>() / samples.len() as f64;
+
+        let filter = doc! { "operation": operation };
+        let previous = self
+            .operation_profiles
+            .find_one(filter.clone(), None)
+            .await
+            .map_err(db_error)?;
+
+        let profile = DBOperationProfile {
+            id: None,
+            operation: operation.to_string(),
+            mean_ms: moving_average(previous.as_ref().map(|p| p.mean_ms), mean_ms, smoothing),
+            stages: previous.as_ref().map_or(0, |p| p.stages) + 1,
+            images: previous.as_ref().map_or(0, |p| p.images) + samples.len() as u64,
+            time_updated: Utc::now(),
+        };
+        let update = doc! { "$set": to_document(&profile).map_err(bson_error)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.operation_profiles
+            .update_one(filter, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Every operation profile, keyed by operation name
+    pub async fn get_operation_profiles(
+        &self,
+    ) -> Result<HashMap<String, DBOperationProfile>, ProcessorError> {
+        let profiles: Vec<DBOperationProfile> = self
+            .operation_profiles
+            .find(doc! {}, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        Ok(profiles
+            .into_iter()
+            .map(|profile| (profile.operation.clone(), profile))
+            .collect())
+    }
+}
@@ -0,0 +1,74 @@
+use common::{error::ProcessorError, provenance::ImageProvenance};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// PROVENANCE
+// Workers record the link every image task adds to the chain of its image,
+// see `common::provenance`. The next stage of the image reads it back to
+// extend the chain, and the scheduler reads every link of a completed batch to
+// compute its root.
+// ============================================================================
+
+impl DBClient {
+    /// Records the link an image task added to the chain of its image
+    pub async fn set_image_task_provenance(
+        &self,
+        task_id: &uuid::Uuid,
+        provenance: &ImageProvenance,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "provenance": to_bson(provenance).map_err(bson_error)? } };
+
+        self.image_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// The succeeded image tasks of a batch that recorded a link, by path in the dataset
+    pub async fn get_provenance_links(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+            "provenance": { "$ne": null },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "source_path": 1 })
+            .build();
+
+        self.image_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Stores the root over the final outputs of a completed batch
+    pub async fn set_batch_provenance_root(
+        &self,
+        batch_id: &uuid::Uuid,
+        root: Option<&str>,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "provenance_root": root } };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,130 @@
+use common::error::ProcessorError;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{Document, doc, from_bson, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::status::group_sum;
+use crate::types::*;
+
+// ============================================================================
+// IMAGE TASK QUERIES
+// The image tasks of a batch as the api-server reads them: listed a page at a
+// time, and summed up by stage and status with how long the workers took on
+// them. A task is timed from when a worker last picked it up until it
+// finished, tasks that never reached a worker aren't timed.
+// ============================================================================
+
+impl TaskStats {
+    pub fn merge(&mut self, other: &TaskStats) {
+        self.images.merge(&other.images);
+        self.timed += other.timed;
+        self.processing_ms += other.processing_ms;
+    }
+
+    /// Mean processing time of the timed tasks, `None` while none finished
+    pub fn avg_processing_ms(&self) -> Option<f64> {
+        match self.timed {
+            0 => None,
+            timed => Some(self.processing_ms as f64 / timed as f64),
+        }
+    }
+}
+
+impl DBClient {
+    /// A page of the image tasks of a batch, in the order they were created, optionally only
+    /// those in one status. Returns the page and how many tasks match in all.
+    pub async fn get_image_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+        status: Option<TaskStatus>,
+        page: Pagination,
+    ) -> Result<(Vec<DBImageTask>, u64), ProcessorError> {
+        let mut filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        if let Some(status) = status {
+            filter.insert("status", to_bson(&status).map_err(bson_error)?);
+        }
+        let total = self
+            .image_tasks
+            .count_documents(filter.clone(), None)
+            .await
+            .map_err(db_error)?;
+        // Tasks created together are told apart by id, so pages don't overlap
+        let options = FindOptions::builder()
+            .sort(doc! { "time_created": 1, "task_id": 1 })
+            .skip(page.skip)
+            .limit(page.limit as i64)
+            .build();
+
+        let tasks = self
+            .image_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        Ok((tasks, total))
+    }
+
+    /// Counts the image tasks of a batch by status and sums their processing time, for the whole
+    /// batch and by the dataset task they belong to.
+    pub async fn get_batch_stats(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<BatchTaskStats, ProcessorError> {
+        // Timestamps are stored as RFC 3339 strings, unset ones parse to null and aren't summed
+        let parse = |field: &str| {
+            doc! { "$dateFromString": { "dateString": field, "onError": null, "onNull": null } }
+        };
+        let pipeline = vec![
+            doc! { "$match": { "batch_id": to_bson(batch_id).map_err(bson_error)? } },
+            doc! { "$project": {
+                "dataset_id": 1,
+                "status": 1,
+                "processing_ms": {
+                    "$subtract": [parse("$time_completed"), parse("$time_started")],
+                },
+            } },
+            doc! { "$group": {
+                "_id": { "dataset_id": "$dataset_id", "status": "$status" },
+                "count": { "$sum": 1 },
+                "timed": { "$sum": { "$cond": [{ "$ne": ["$processing_ms", null] }, 1, 0] } },
+                "processing_ms": { "$sum": "$processing_ms" },
+            } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_tasks
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        let mut stats = BatchTaskStats::default();
+        for group in groups {
+            let key = group.get_document("_id").map_err(bson_error)?;
+            let dataset_id: uuid::Uuid =
+                from_bson(key.get("dataset_id").cloned().unwrap_or_default())
+                    .map_err(bson_error)?;
+            let status: TaskStatus = from_bson(key.get("status").cloned().unwrap_or_default())
+                .map_err(bson_error)?;
+
+            let mut group_stats = TaskStats {
+                timed: group_sum(&group, "timed")?,
+                processing_ms: group_sum(&group, "processing_ms")?,
+                ..Default::default()
+            };
+            group_stats.images.add(&status, group_sum(&group, "count")?);
+            stats.total.merge(&group_stats);
+            stats.stages.entry(dataset_id).or_default().merge(&group_stats);
+        }
+
+        Ok(stats)
+    }
+}
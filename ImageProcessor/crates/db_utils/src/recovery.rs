@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, timestamp};
+use mongodb::bson::{doc, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::leadership::fence;
+use crate::types::*;
+
+// ============================================================================
+// DECOMPOSITION LEASES
+// The decomposer holds a lease on the dataset task it is splitting into image
+// tasks and renews it while it works. A consumer that dies mid-archive stops
+// renewing, and the scheduler republishes the task once the lease expired so
+// another consumer resumes the decomposition.
+// ============================================================================
+
+impl DBDatasetTask {
+    /// Whether the consumer decomposing the task stopped renewing its lease
+    pub fn lease_expired(&self, now: DateTime<Utc>) -> bool {
+        self.image_count.is_none() && self.lease_expires.is_some_and(|expires| expires < now)
+    }
+}
+
+impl DBClient {
+    /// Extends the lease on a running dataset task.
+    ///
+    /// Returns `false` if the task isn't running anymore, e.g. because it was cancelled.
+    pub async fn renew_dataset_task_lease(
+        &self,
+        task_id: &uuid::Uuid,
+        lease: Duration,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Running).map_err(bson_error)?,
+        };
+        let update = doc! {
+            "$set": { "lease_expires": timestamp::format(&(Utc::now() + lease)) }
+        };
+
+        self.dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.matched_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Takes over the expired lease of a dataset task to republish it, counting the recovery.
+    ///
+    /// Only matches while the lease is still the expired one, so a task is recovered once even
+    /// with several schedulers running. Returns `false` if another scheduler took it over, a
+    /// newer leader than the one holding `token` wrote to it, or its consumer renewed the lease
+    /// after all.
+    pub async fn claim_expired_lease(
+        &self,
+        task: &DBDatasetTask,
+        lease: Duration,
+        token: u64,
+    ) -> Result<bool, ProcessorError> {
+        let mut filter = doc! {
+            "task_id": to_bson(&task.task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Running).map_err(bson_error)?,
+            "lease_expires": task.lease_expires.as_ref().map(timestamp::format),
+        };
+        let mut set =
+            doc! { "lease_expires": timestamp::format(&(Utc::now() + lease)) };
+        fence(&mut filter, &mut set, token);
+        let update = doc! { "$set": set, "$inc": { "recoveries": 1 } };
+
+        self.dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, lifecycle::BatchState, timestamp};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// BATCH REPORTS
+// Every finished batch gets one report, claimed by the alerting instance that
+// writes it. A retry reopening the batch forgets the report, so the batch is
+// reported again once it finishes the second time.
+// ============================================================================
+
+impl DBClient {
+    /// Returns the batches that reached a final state since the given time and have no report yet
+    pub async fn get_batches_awaiting_report(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let final_states = [
+            BatchState::Completed,
+            BatchState::Failed,
+            BatchState::Cancelled,
+            BatchState::TimedOut,
+        ];
+        let filter = doc! {
+            "state": { "$in": to_bson(&final_states).map_err(bson_error)? },
+            "time_completed": { "$gte": timestamp::format(&since) },
+            "report": null,
+        };
+
+        self.dataset_batch_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records where the report of a batch is written, before it is.
+    ///
+    /// Returns `false` if the batch already has a report, e.g. claimed by another alerting
+    /// instance.
+    pub async fn claim_batch_report(
+        &self,
+        batch_id: &uuid::Uuid,
+        report: &BatchReportRecord,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "report": null,
+        };
+        let update = doc! { "$set": { "report": to_bson(report).map_err(bson_error)? } };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+}
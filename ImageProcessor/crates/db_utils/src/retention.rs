@@ -0,0 +1,100 @@
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson},
+    options::FindOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// RESULTS RETENTION
+// A batch's outputs are kept for the retention period it was submitted with,
+// counted from the moment it reached a final state. Owners are warned once
+// before that period runs out, and the scheduler deletes the outputs after.
+// ============================================================================
+
+impl DBClient {
+    /// Returns the batches whose results expire before the given time and whose owner hasn't
+    /// been warned yet
+    pub async fn get_batches_expiring_before(
+        &self,
+        deadline: DateTime<Utc>,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! {
+            "retention.expires_at": { "$lte": timestamp::format(&deadline) },
+            "retention.warning_sent_at": null,
+        };
+
+        self.dataset_batch_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records that the expiry warning of a batch was sent.
+    ///
+    /// Returns `false` if it had already been recorded, e.g. by another alerting instance.
+    pub async fn mark_expiry_warning_sent(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "retention.warning_sent_at": null,
+        };
+        let update = doc! {
+            "$set": { "retention.warning_sent_at": timestamp::now() }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Returns the batches whose results expired before the given time and haven't been
+    /// deleted yet, those that expired first first
+    pub async fn get_expired_batches(
+        &self,
+        now: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! {
+            "retention.expires_at": { "$lte": timestamp::format(&now) },
+            "retention.results_deleted_at": null,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "retention.expires_at": 1 })
+            .limit(limit)
+            .build();
+
+        self.dataset_batch_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records that the expired results of a batch were deleted
+    pub async fn mark_results_deleted(&self, batch_id: &uuid::Uuid) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$set": { "retention.results_deleted_at": timestamp::now() }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::Utc;
+use common::{error::ProcessorError, lifecycle::BatchState};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{Bson, doc, to_bson},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::lifecycle::batch_task_status;
+use crate::types::*;
+
+// ============================================================================
+// RETRIES
+// The failed tasks of a batch can be run again without resubmitting it. Failed
+// image tasks go back to Ready, the stage they belong to back to Running so the
+// scheduler completes it once more, and the stages skipped because of it back
+// to Waiting. A Failed batch is reopened for this, see `BatchState::can_reopen_to`.
+// ============================================================================
+
+impl DBClient {
+    /// Reopens a Failed batch in `next` to retry its failed tasks.
+    ///
+    /// Like `transition_batch` the change is applied only if the batch is still Failed, returning
+    /// `Validation` if it can't be reopened in `next` and `Conflict` if another writer was first.
+    pub async fn reopen_batch(
+        &self,
+        batch_id: &uuid::Uuid,
+        next: BatchState,
+    ) -> Result<DBDatasetProcessingJob, ProcessorError> {
+        let batch = self.get_batch(batch_id).await?.ok_or_else(|| {
+            ProcessorError::NotFound(format!("Batch {} does not exist", batch_id))
+        })?;
+        if !batch.state.can_reopen_to(&next) {
+            return Err(ProcessorError::Validation(format!(
+                "A batch in {:?} can't be reopened in {:?}",
+                batch.state, next
+            )));
+        }
+
+        let change = BatchStateChange {
+            from: batch.state,
+            to: next,
+            time: Utc::now(),
+        };
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "state": to_bson(&batch.state).map_err(bson_error)?,
+        };
+        let mut set = doc! {
+            "state": to_bson(&next).map_err(bson_error)?,
+            "status": to_bson(&batch_task_status(&next)).map_err(bson_error)?,
+            "time_completed": null,
+        };
+        // The results are written again, so their retention period starts over once it finishes
+        if batch.retention.is_some() {
+            set.insert("retention.expires_at", Bson::Null);
+            set.insert("retention.results_deleted_at", Bson::Null);
+        }
+        let update = doc! {
+            "$set": set,
+            "$push": { "state_history": to_bson(&change).map_err(bson_error)? },
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.dataset_batch_tasks
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(db_error)?
+            .ok_or_else(|| {
+                ProcessorError::Conflict(format!(
+                    "Batch {} changed state concurrently, it was not reopened",
+                    batch_id
+                ))
+            })
+    }
+
+    /// The failed image tasks of a batch, or only the given ones of them
+    pub async fn get_failed_image_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+        task_ids: Option<&[uuid::Uuid]>,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let mut filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Failure).map_err(bson_error)?,
+        };
+        if let Some(task_ids) = task_ids {
+            filter.insert(
+                "task_id",
+                doc! { "$in": to_bson(task_ids).map_err(bson_error)? },
+            );
+        }
+
+        self.image_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Moves a failed image task back to Ready for a retry.
+    ///
+    /// Returns the reset task, or `None` if it wasn't failed anymore.
+    pub async fn reset_failed_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Failure).map_err(bson_error)?,
+        };
+        let update = doc! {
+            "$set": {
+                "status": to_bson(&TaskStatus::Ready).map_err(bson_error)?,
+                "error": null,
+                "failure_kind": null,
+                "backtrace": null,
+                "time_completed": null,
+            }
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.image_tasks
+            .find_one_and_update(filter, update, options)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Moves a failed dataset task back to `to` for a retry, with a fresh decomposition lease.
+    ///
+    /// Returns false if the task wasn't failed anymore.
+    pub async fn reset_failed_dataset_task(
+        &self,
+        task_id: &uuid::Uuid,
+        to: TaskStatus,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Failure).map_err(bson_error)?,
+        };
+        let update = doc! {
+            "$set": {
+                "status": to_bson(&to).map_err(bson_error)?,
+                "time_completed": null,
+                "lease_expires": null,
+                "recoveries": 0,
+                "notice_sent_at": null,
+            }
+        };
+
+        self.dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,157 @@
+use common::{PipelineMode, budgets::BudgetCheckpoint, dag, error::ProcessorError, timestamp};
+use futures::TryStreamExt;
+use mongodb::bson::{Document, doc, from_bson, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// STAGE SCHEDULING
+// Dataset tasks of later stages stay Waiting until every stage they depend on
+// has finished. The scheduler moves them along with conditional updates, so a
+// transition only happens once even with several scheduler instances running.
+// ============================================================================
+
+impl DBDatasetProcessingJob {
+    /// Number of stages the batch runs, a fused batch runs all its operations in one
+    pub fn stage_count(&self) -> usize {
+        match self.pipeline_mode {
+            PipelineMode::Staged => self.operations.len(),
+            PipelineMode::Fused => self.operations.len().min(1),
+        }
+    }
+}
+
+impl DBDatasetTask {
+    /// The dataset task whose outputs the stage reads, see `common::dag::input_task_id`
+    pub fn input_task_id(&self) -> Option<uuid::Uuid> {
+        dag::input_task_id(self.input, &self.depends_on)
+    }
+}
+
+impl DBClient {
+    /// Fetches a single dataset task by id.
+    pub async fn get_dataset_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBDatasetTask>, ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+
+        self.dataset_tasks
+            .find_one(filter, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Returns every dataset task currently in the given status.
+    pub async fn get_dataset_tasks_with_status(
+        &self,
+        status: TaskStatus,
+    ) -> Result<Vec<DBDatasetTask>, ProcessorError> {
+        let filter = doc! { "status": to_bson(&status).map_err(bson_error)? };
+
+        self.dataset_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Moves a dataset task from one status to another, stamping `time_completed` when the new
+    /// status is final.
+    ///
+    /// Returns `false` without changing anything if the task wasn't in the `from` status.
+    pub async fn transition_dataset_task(
+        &self,
+        task_id: &uuid::Uuid,
+        from: TaskStatus,
+        to: TaskStatus,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "task_id": to_bson(task_id).map_err(bson_error)?,
+            "status": to_bson(&from).map_err(bson_error)?,
+        };
+        let mut set = doc! { "status": to_bson(&to).map_err(bson_error)? };
+        if to.is_final() {
+            set.insert(
+                "time_completed",
+                timestamp::now(),
+            );
+        }
+
+        self.dataset_tasks
+            .update_one(filter, doc! { "$set": set }, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Records that every image task of a dataset task has been created.
+    pub async fn mark_dataset_decomposed(
+        &self,
+        task_id: &uuid::Uuid,
+        image_count: u64,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+        let update = doc! { "$set": { "image_count": image_count as i64 } };
+
+        self.dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Records how far a dataset task got before it exceeded its decomposition budget
+    pub async fn record_budget_checkpoint(
+        &self,
+        task_id: &uuid::Uuid,
+        checkpoint: &BudgetCheckpoint,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "task_id": to_bson(task_id).map_err(bson_error)? };
+        let update =
+            doc! { "$set": { "budget_checkpoint": to_bson(checkpoint).map_err(bson_error)? } };
+
+        self.dataset_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Counts the image tasks of a single dataset task by status.
+    pub async fn image_status_counts(
+        &self,
+        dataset_id: &uuid::Uuid,
+    ) -> Result<StatusCounts, ProcessorError> {
+        let pipeline = vec![
+            doc! { "$match": { "dataset_id": to_bson(dataset_id).map_err(bson_error)? } },
+            doc! { "$group": { "_id": "$status", "count": { "$sum": 1 } } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_tasks
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        let mut counts = StatusCounts::default();
+        for group in groups {
+            let status: TaskStatus = from_bson(group.get("_id").cloned().unwrap_or_default())
+                .map_err(bson_error)?;
+            let count = group
+                .get_i32("count")
+                .map(|c| c as u64)
+                .or_else(|_| group.get_i64("count").map(|c| c as u64))
+                .map_err(bson_error)?;
+            counts.add(&status, count);
+        }
+
+        Ok(counts)
+    }
+}
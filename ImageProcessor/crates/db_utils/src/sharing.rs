@@ -0,0 +1,41 @@
+use chrono::{DateTime, Utc};
+use common::error::ProcessorError;
+use mongodb::bson::doc;
+
+use crate::error::db_error;
+use crate::types::*;
+
+// ============================================================================
+// SHARED RESULTS
+// Links sharing a batch's results with whoever holds their token, see
+// `common::sharing`. They are looked up by the token's hash when opened, so
+// nothing stored here opens a link.
+// ============================================================================
+
+impl DBShareLink {
+    /// Whether the link can still be opened at the given time
+    pub fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at > now
+    }
+}
+
+impl DBClient {
+    pub async fn create_share_link(&self, link: &DBShareLink) -> Result<(), ProcessorError> {
+        self.share_links
+            .insert_one(link, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// The link with the given token hash, whether it expired or not
+    pub async fn find_share_link(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<DBShareLink>, ProcessorError> {
+        self.share_links
+            .find_one(doc! { "token_hash": token_hash }, None)
+            .await
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,55 @@
+use common::error::ProcessorError;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// DATASET SIDECARS
+// The first stage records the sidecars of a dataset on its batch as it finds
+// them, see `common::sidecars`. A redelivered dataset task finds them recorded
+// already and leaves them be.
+// ============================================================================
+
+impl DBClient {
+    /// Records a sidecar of the batch's dataset.
+    ///
+    /// Returns `false` if one with the same path was recorded before.
+    pub async fn add_dataset_sidecar(
+        &self,
+        batch_id: &uuid::Uuid,
+        sidecar: &DatasetSidecar,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "sidecars.path": { "$ne": &sidecar.path },
+        };
+        let update = doc! { "$push": { "sidecars": to_bson(sidecar).map_err(bson_error)? } };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|res| res.modified_count == 1)
+            .map_err(db_error)
+    }
+
+    /// The successful image tasks of a dataset task, with the keys their outputs ended up under
+    pub async fn get_stage_results(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let filter = doc! {
+            "dataset_id": to_bson(dataset_task_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+        };
+
+        self.image_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use common::{error::ProcessorError, operations_name, slo::SloViolation, timestamp};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{Document, doc, to_bson, to_document},
+    options::{FindOptions, UpdateOptions},
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// LATENCY SLOS
+// Latencies are read back from the image tasks themselves, the aggregated
+// windows are upserted per operation and window start so a window aggregated
+// twice (e.g. by two schedulers) is stored once.
+// ============================================================================
+
+impl DBClient {
+    /// Latencies in milliseconds of the image tasks that succeeded within `[start, end)`, keyed
+    /// by operation name
+    pub async fn image_task_latencies_between(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<HashMap<String, Vec<f64>>, ProcessorError> {
+        let filter = doc! {
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+            "time_completed": {
+                "$gte": timestamp::format(&start),
+                "$lt": timestamp::format(&end),
+            },
+        };
+
+        self.image_task_latencies(filter).await
+    }
+
+    /// Latencies in milliseconds of the image tasks of a batch that succeeded, keyed by
+    /// operation name
+    pub async fn batch_image_task_latencies(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<HashMap<String, Vec<f64>>, ProcessorError> {
+        let filter = doc! {
+            "batch_id": to_bson(batch_id).map_err(bson_error)?,
+            "status": to_bson(&TaskStatus::Success).map_err(bson_error)?,
+        };
+
+        self.image_task_latencies(filter).await
+    }
+
+    pub(crate) async fn image_task_latencies(
+        &self,
+        filter: Document,
+    ) -> Result<HashMap<String, Vec<f64>>, ProcessorError> {
+        let tasks: Vec<DBImageTask> = self
+            .image_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        // Tasks finished before workers recorded when they started have no latency
+        let mut latencies: HashMap<String, Vec<f64>> = HashMap::new();
+        for task in tasks {
+            let (Some(started), Some(completed)) = (task.time_started, task.time_completed) else {
+                continue;
+            };
+            // Fused tasks are measured as a whole, under the name of their chain
+            let operations = std::iter::once(&task.operation).chain(&task.fused_operations);
+            latencies
+                .entry(operations_name(operations))
+                .or_default()
+                .push((completed - started).num_milliseconds().max(0) as f64);
+        }
+
+        Ok(latencies)
+    }
+
+    /// Stores the latency window of an operation, replacing a window with the same start
+    pub async fn upsert_latency_window(
+        &self,
+        window: &DBLatencyWindow,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! {
+            "operation": &window.operation,
+            "window_start": timestamp::format(&window.window_start),
+        };
+        let update = doc! { "$set": to_document(window).map_err(bson_error)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.latency_windows
+            .update_one(filter, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Whether any latency window starting at the given time was stored
+    pub async fn has_latency_window(&self, start: DateTime<Utc>) -> Result<bool, ProcessorError> {
+        let filter = doc! { "window_start": timestamp::format(&start) };
+
+        self.latency_windows
+            .count_documents(filter, None)
+            .await
+            .map(|count| count > 0)
+            .map_err(db_error)
+    }
+
+    /// Returns the latency windows that started at or after the given time, oldest first
+    pub async fn get_latency_windows_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DBLatencyWindow>, ProcessorError> {
+        let filter = doc! {
+            "window_start": { "$gte": timestamp::format(&since) }
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "window_start": 1 })
+            .build();
+
+        self.latency_windows
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Records the latency targets a batch missed
+    pub async fn set_batch_slo_violations(
+        &self,
+        batch_id: &uuid::Uuid,
+        violations: &[SloViolation],
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! { "batch_id": to_bson(batch_id).map_err(bson_error)? };
+        let update = doc! {
+            "$set": { "slo_violations": to_bson(violations).map_err(bson_error)? }
+        };
+
+        self.dataset_batch_tasks
+            .update_one(filter, update, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// Returns the batches that finished after the given time and missed a latency target,
+    /// most recent first
+    pub async fn get_batches_violating_slo_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DBDatasetProcessingJob>, ProcessorError> {
+        let filter = doc! {
+            "time_completed": { "$gte": timestamp::format(&since) },
+            "slo_violations.0": { "$exists": true },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "time_completed": -1 })
+            .build();
+
+        self.dataset_batch_tasks
+            .find(filter, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use common::error::ProcessorError;
+use futures::TryStreamExt;
+use mongodb::bson::{Document, doc, from_bson};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+impl StatusCounts {
+    pub fn add(&mut self, status: &TaskStatus, count: u64) {
+        match status {
+            TaskStatus::Waiting => self.waiting += count,
+            TaskStatus::Ready => self.ready += count,
+            TaskStatus::Running => self.running += count,
+            TaskStatus::Success => self.success += count,
+            TaskStatus::Failure => self.failure += count,
+            TaskStatus::Cancelled => self.cancelled += count,
+            TaskStatus::Filtered => self.filtered += count,
+        }
+    }
+
+    pub fn merge(&mut self, other: &StatusCounts) {
+        self.waiting += other.waiting;
+        self.ready += other.ready;
+        self.running += other.running;
+        self.success += other.success;
+        self.failure += other.failure;
+        self.cancelled += other.cancelled;
+        self.filtered += other.filtered;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.waiting
+            + self.ready
+            + self.running
+            + self.success
+            + self.failure
+            + self.cancelled
+            + self.filtered
+    }
+}
+
+impl TaskStatus {
+    /// Whether the task won't change status anymore
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Success
+                | TaskStatus::Failure
+                | TaskStatus::Cancelled
+                | TaskStatus::Filtered
+        )
+    }
+
+    /// Why the image tasks depending on one that finished with this status are filtered out, or
+    /// `None` if they can run. A failed image is recorded in the batch's failures once, in the
+    /// stage it failed in, and the later stages carry on without it.
+    pub fn upstream_filter_reason(&self) -> Option<&'static str> {
+        match self {
+            TaskStatus::Filtered => Some("Filtered out in an earlier stage"),
+            TaskStatus::Failure => Some("Failed in an earlier stage"),
+            _ => None,
+        }
+    }
+}
+
+impl DBClient {
+    /// Returns every dataset task of a batch, ordered by stage.
+    pub async fn get_dataset_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Vec<DBDatasetTask>, ProcessorError> {
+        let filter =
+            doc! { "batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? };
+
+        let mut tasks: Vec<DBDatasetTask> = self
+            .dataset_tasks
+            .find(filter, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+        tasks.sort_by_key(|task| task.stage);
+
+        Ok(tasks)
+    }
+
+    /// Counts the image tasks of a batch by status, grouped by the dataset task they belong to.
+    pub async fn image_status_counts_by_dataset_task(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<HashMap<uuid::Uuid, StatusCounts>, ProcessorError> {
+        let pipeline = vec![
+            doc! { "$match": { "batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? } },
+            doc! { "$group": {
+                "_id": { "dataset_id": "$dataset_id", "status": "$status" },
+                "count": { "$sum": 1 },
+            } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_tasks
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        let mut counts: HashMap<uuid::Uuid, StatusCounts> = HashMap::new();
+        for group in groups {
+            let key = group.get_document("_id").map_err(bson_error)?;
+            let dataset_id: uuid::Uuid =
+                from_bson(key.get("dataset_id").cloned().unwrap_or_default())
+                    .map_err(bson_error)?;
+            let status: TaskStatus = from_bson(key.get("status").cloned().unwrap_or_default())
+                .map_err(bson_error)?;
+            counts
+                .entry(dataset_id)
+                .or_default()
+                .add(&status, group_count(&group)?);
+        }
+
+        Ok(counts)
+    }
+
+    /// Counts the image tasks of a batch by status, grouped by the top-level folder of the image
+    /// inside the dataset (e.g. the class of a labeled dataset).
+    pub async fn image_status_counts_by_prefix(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Vec<PrefixStatusCounts>, ProcessorError> {
+        let pipeline = vec![
+            doc! { "$match": { "batch_id": mongodb::bson::to_bson(batch_id).map_err(bson_error)? } },
+            doc! { "$project": {
+                "status": 1,
+                "parts": { "$split": [{ "$ifNull": ["$source_path", ""] }, "/"] },
+            } },
+            doc! { "$group": {
+                "_id": {
+                    "prefix": { "$cond": [
+                        { "$gt": [{ "$size": "$parts" }, 1] },
+                        { "$arrayElemAt": ["$parts", 0] },
+                        "",
+                    ] },
+                    "status": "$status",
+                },
+                "count": { "$sum": 1 },
+            } },
+        ];
+
+        let groups: Vec<Document> = self
+            .image_tasks
+            .aggregate(pipeline, None)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)?;
+
+        let mut counts: HashMap<String, StatusCounts> = HashMap::new();
+        for group in groups {
+            let key = group.get_document("_id").map_err(bson_error)?;
+            let prefix = key.get_str("prefix").unwrap_or_default().to_string();
+            let status: TaskStatus = from_bson(key.get("status").cloned().unwrap_or_default())
+                .map_err(bson_error)?;
+
+            counts.entry(prefix).or_default().add(&status, group_count(&group)?);
+        }
+
+        let mut prefixes: Vec<PrefixStatusCounts> = counts
+            .into_iter()
+            .map(|(prefix, images)| PrefixStatusCounts { prefix, images })
+            .collect();
+        prefixes.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+
+        Ok(prefixes)
+    }
+}
+
+/// Reads the `count` produced by a `$sum` stage
+fn group_count(group: &Document) -> Result<u64, ProcessorError> {
+    group_sum(group, "count")
+}
+
+/// Reads a field produced by a `$sum` stage, which Mongo returns as either i32 or i64
+pub(crate) fn group_sum(group: &Document, field: &str) -> Result<u64, ProcessorError> {
+    group
+        .get_i32(field)
+        .map(|c| c as u64)
+        .or_else(|_| group.get_i64(field).map(|c| c as u64))
+        .map_err(bson_error)
+}
@@ -0,0 +1,217 @@
+use chrono::{DateTime, Utc};
+use common::{
+    error::ProcessorError,
+    lifecycle::BatchState,
+    tenancy::{TenantQuotas, TenantStatus},
+    timestamp,
+};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{Document, doc, to_bson},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+};
+
+use crate::error::{bson_error, db_error, is_duplicate_key};
+use crate::types::*;
+
+// ============================================================================
+// TENANTS
+// Tenants onboarded through the admin endpoints of the api-server, with their
+// managed API keys and a record of every change made to them, see
+// `common::tenancy`. Keys are looked up by their hash on every request, so a
+// revoked key is turned away at once. Tenants of the config's API keys have
+// no document here and no quotas.
+// ============================================================================
+
+impl DBTenantApiKey {
+    /// Whether the key is accepted at the given time
+    pub fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.time_revoked.is_none() && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+impl DBClient {
+    /// Records a new tenant, returning `false` if one with the same id exists already
+    pub async fn create_tenant(&self, tenant: &DBTenant) -> Result<bool, ProcessorError> {
+        match self.tenants.insert_one(tenant, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    pub async fn get_tenant(&self, tenant_id: &str) -> Result<Option<DBTenant>, ProcessorError> {
+        self.tenants
+            .find_one(doc! { "tenant_id": tenant_id }, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Every onboarded tenant, by id
+    pub async fn get_tenants(&self) -> Result<Vec<DBTenant>, ProcessorError> {
+        let options = FindOptions::builder().sort(doc! { "tenant_id": 1 }).build();
+
+        self.tenants
+            .find(doc! {}, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Changes the given settings of a tenant and returns it, `None` if there is no such tenant
+    pub async fn update_tenant(
+        &self,
+        tenant_id: &str,
+        name: Option<&str>,
+        status: Option<TenantStatus>,
+        quotas: Option<&TenantQuotas>,
+    ) -> Result<Option<DBTenant>, ProcessorError> {
+        let mut set = Document::new();
+        if let Some(name) = name {
+            set.insert("name", name);
+        }
+        if let Some(status) = status {
+            set.insert("status", to_bson(&status).map_err(bson_error)?);
+        }
+        if let Some(quotas) = quotas {
+            set.insert("quotas", to_bson(quotas).map_err(bson_error)?);
+        }
+        set.insert("time_updated", timestamp::now());
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        self.tenants
+            .find_one_and_update(doc! { "tenant_id": tenant_id }, doc! { "$set": set }, options)
+            .await
+            .map_err(db_error)
+    }
+
+    pub async fn add_tenant_api_key(&self, key: &DBTenantApiKey) -> Result<(), ProcessorError> {
+        self.tenant_api_keys
+            .insert_one(key, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// The managed key with the given hash, whether it is still valid or not
+    pub async fn find_tenant_api_key(
+        &self,
+        key_hash: &str,
+    ) -> Result<Option<DBTenantApiKey>, ProcessorError> {
+        self.tenant_api_keys
+            .find_one(doc! { "key_hash": key_hash }, None)
+            .await
+            .map_err(db_error)
+    }
+
+    /// Every managed key of a tenant, revoked and expired ones included, oldest first
+    pub async fn get_tenant_api_keys(
+        &self,
+        tenant_id: &str,
+    ) -> Result<Vec<DBTenantApiKey>, ProcessorError> {
+        let options = FindOptions::builder().sort(doc! { "time_created": 1 }).build();
+
+        self.tenant_api_keys
+            .find(doc! { "tenant_id": tenant_id }, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Revokes a key of a tenant, returning `false` if it has no such key or it was revoked
+    /// already
+    pub async fn revoke_tenant_api_key(
+        &self,
+        tenant_id: &str,
+        key_id: &uuid::Uuid,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "tenant_id": tenant_id,
+            "key_id": to_bson(key_id).map_err(bson_error)?,
+            "time_revoked": null,
+        };
+        let update = doc! {
+            "$set": { "time_revoked": timestamp::now() }
+        };
+
+        self.tenant_api_keys
+            .update_one(filter, update, None)
+            .await
+            .map(|result| result.modified_count == 1)
+            .map_err(db_error)
+    }
+
+    /// Makes a key of a tenant expire at the given time, returning `false` if it has no such key
+    /// or it was revoked or rotated already
+    pub async fn expire_tenant_api_key(
+        &self,
+        tenant_id: &str,
+        key_id: &uuid::Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "tenant_id": tenant_id,
+            "key_id": to_bson(key_id).map_err(bson_error)?,
+            "time_revoked": null,
+            "expires_at": null,
+        };
+        let update = doc! {
+            "$set": { "expires_at": timestamp::format(&expires_at) }
+        };
+
+        self.tenant_api_keys
+            .update_one(filter, update, None)
+            .await
+            .map(|result| result.modified_count == 1)
+            .map_err(db_error)
+    }
+
+    pub async fn record_tenant_audit(&self, entry: &DBTenantAudit) -> Result<(), ProcessorError> {
+        self.tenant_audit
+            .insert_one(entry, None)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    /// The changes made to a tenant, oldest first
+    pub async fn get_tenant_audit(
+        &self,
+        tenant_id: &str,
+    ) -> Result<Vec<DBTenantAudit>, ProcessorError> {
+        let options = FindOptions::builder().sort(doc! { "time": 1 }).build();
+
+        self.tenant_audit
+            .find(doc! { "tenant_id": tenant_id }, options)
+            .await
+            .map_err(db_error)?
+            .try_collect()
+            .await
+            .map_err(db_error)
+    }
+
+    /// Batches of a tenant that haven't reached a final state, for its `max_active_batches`
+    pub async fn count_active_batches(&self, tenant_id: &str) -> Result<u64, ProcessorError> {
+        let final_states = [
+            BatchState::Completed,
+            BatchState::Failed,
+            BatchState::Cancelled,
+            BatchState::TimedOut,
+        ];
+        let filter = doc! {
+            "tenant_id": tenant_id,
+            "state": { "$nin": to_bson(&final_states).map_err(bson_error)? },
+        };
+
+        self.dataset_batch_tasks
+            .count_documents(filter, None)
+            .await
+            .map_err(db_error)
+    }
+}
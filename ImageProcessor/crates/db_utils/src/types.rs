@@ -1,10 +1,36 @@
 use chrono::{DateTime, Utc};
-use common::ImageOperation;
+use common::{
+    CacheScope, DatasetProcessingTask, ImageOperation, ImageTask, PipelineMode, Priority,
+    alpha::AlphaPolicy,
+    analytics::TaskTimings,
+    approval::StageApproval,
+    budgets::BudgetCheckpoint,
+    capabilities::{Capability, HeldTask, WorkerCapabilities},
+    config::MessageEncoding,
+    dag::StageInput,
+    dimensions::Dimensions,
+    drift::{ImageQualitySample, QualityDrift, StageQualitySummary},
+    error::S3ErrorKind,
+    failures::FailureKind,
+    formats::OutputFormat,
+    inspection::DatasetValidationReport,
+    lifecycle::BatchState,
+    metadata::{ImageMetadata, MetadataPolicy},
+    migration::{MigrationError, MigrationState, TopicMigrationPlan, TopicMigrationReport},
+    naming::{CollisionPolicy, OutputLayout},
+    notifications::NotificationPreferences,
+    provenance::ImageProvenance,
+    reproducibility::ConfigSnapshot,
+    sharing::ShareScope,
+    slo::{LatencyPercentiles, SloViolation},
+    tenancy::{TenantQuotas, TenantStatus},
+};
 use mongodb::{
     Collection,
     bson::{doc, oid::ObjectId},
 };
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
 // ============================================================================
 // SHARED ENUMS
@@ -17,6 +43,72 @@ pub enum TaskStatus {
     Failure,
     Running,
     Ready,
+    Cancelled, // The batch was cancelled before the task finished
+    Filtered,  // A quality gate left the image out, it reaches no later stage
+}
+
+/// Progress of every image below one top-level folder of the dataset
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PrefixStatusCounts {
+    pub prefix: String, // Top-level folder, empty for images at the root of the dataset
+    pub images: StatusCounts,
+}
+
+/// Number of tasks in each status
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StatusCounts {
+    pub waiting: u64,
+    pub ready: u64,
+    pub running: u64,
+    pub success: u64,
+    pub failure: u64,
+    pub cancelled: u64,
+    #[serde(default)]
+    pub filtered: u64,
+}
+
+/// Progress and processing time of a set of image tasks, see `get_batch_stats`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TaskStats {
+    pub images: StatusCounts,
+    pub timed: u64, // Tasks that finished after a worker picked them up
+    pub processing_ms: u64, // Summed over the timed tasks
+}
+
+/// The image tasks of a batch, all of them and by the dataset task (stage) they belong to
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BatchTaskStats {
+    pub total: TaskStats,
+    pub stages: HashMap<uuid::Uuid, TaskStats>,
+}
+
+/// A page of a listing, `limit` entries after skipping the first `skip`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Pagination {
+    pub skip: u64,
+    pub limit: u64,
+}
+
+/// Bytes an image task moved through storage
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ImageTaskBytes {
+    pub read: Option<u64>, // None when the input was handed over locally instead of downloaded
+    pub written: u64,
+}
+
+/// Bytes read and written by a set of image tasks, counting only the tasks both are known for
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ByteTotals {
+    pub tasks: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Image tasks that reused a cached output (hits) or computed and cached theirs (misses)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
 }
 
 // ============================================================================
@@ -34,11 +126,229 @@ pub struct DBDatasetProcessingJob {
     pub batch_id: uuid::Uuid, // A unique ID, copied straight from the Kafka job
     pub dataset_key: String, // Key of the dataset zip folder inside of s3
     pub operations: Vec<ImageOperation>, // A list of the different operations to be applied
+    #[serde(default)]
+    pub dependencies: Vec<Vec<usize>>, // What each operation runs after, empty for a chain
+    #[serde(default)]
+    pub requires_approval: Vec<usize>, // Operations whose outputs are reviewed first
     
     // Additional metadata for the database
+    #[serde(with = "common::timestamp")]
     pub time_created: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
     pub time_completed: Option<DateTime<Utc>>,
     pub status: TaskStatus,
+
+    // Position in the batch lifecycle, `status` mirrors it for older readers
+    #[serde(default)]
+    pub state: BatchState,
+    #[serde(default)]
+    pub state_history: Vec<BatchStateChange>,
+
+    // User supplied metadata (experiment id, git commit, notes, ...) attached to the batch
+    #[serde(default)]
+    pub annotations: HashMap<String, serde_json::Value>,
+
+    // Copies of the results prefix that the batch can be rolled back to
+    #[serde(default)]
+    pub snapshots: Vec<BatchSnapshot>,
+
+    // Effective configuration of every component that handled the batch, for reproducibility
+    #[serde(default)]
+    pub config_snapshots: Vec<ConfigSnapshot>,
+
+    // Set when the batch is one combination of a parameter sweep
+    #[serde(default)]
+    pub sweep: Option<SweepMembership>,
+
+    // Copied to the S3 object tags of every output
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+
+    // How long the outputs are kept, missing on batches submitted before retention was tracked
+    #[serde(default)]
+    pub retention: Option<ResultsRetention>,
+
+    // Events the submitter's webhook is sent, the tenant's preferences apply without them, see
+    // `common::notifications`
+    #[serde(default)]
+    pub notification_preferences: Option<NotificationPreferences>,
+
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub pipeline_mode: PipelineMode,
+
+    // Output options of the submitted job, kept so the batch can be cloned
+    #[serde(default)]
+    pub use_local_cache: bool,
+    #[serde(default)]
+    pub output_layout: OutputLayout,
+    #[serde(default)]
+    pub collision_policy: CollisionPolicy,
+    #[serde(default)]
+    pub hash_suffix: bool,
+    #[serde(default)]
+    pub cache_scope: CacheScope,
+    #[serde(default)]
+    pub output_format: Option<OutputFormat>,
+    #[serde(default)]
+    pub alpha_policy: AlphaPolicy,
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy,
+    #[serde(default)]
+    pub rewrite_sidecars: bool,
+    #[serde(default)]
+    pub previews: bool,
+
+    // The batch this one was cloned from
+    #[serde(default)]
+    pub parent_batch_id: Option<uuid::Uuid>,
+
+    // Tenant of the API key that submitted the batch, `None` without authentication
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+
+    // Latency targets the batch's image tasks missed, checked once the batch finished
+    #[serde(default)]
+    pub slo_violations: Vec<SloViolation>,
+
+    // Partial results manifests published while the batch ran, see `common::manifest`
+    #[serde(default)]
+    pub manifests_published: u32, // Sequence number of the next manifest
+    #[serde(default, with = "common::timestamp::optional")]
+    pub last_manifest_at: Option<DateTime<Utc>>,
+
+    // Summary written once the batch finished, see `common::report`
+    #[serde(default)]
+    pub report: Option<BatchReportRecord>,
+
+    // Storage the batch's tasks may not access, raised by the first task denied and sent by the
+    // alerting service
+    #[serde(default)]
+    pub storage_alert: Option<StorageAlert>,
+
+    // Files of the dataset written next to the final outputs, see `common::sidecars`
+    #[serde(default)]
+    pub sidecars: Vec<DatasetSidecar>,
+
+    // What the checks of the archive found before it was decomposed, see `common::inspection`
+    #[serde(default)]
+    pub dataset_validation: Option<DatasetValidationReport>,
+
+    // Set once the janitor handled the outputs of the intermediate stages, see `deletion`
+    #[serde(default, with = "common::timestamp::optional")]
+    pub intermediates_swept_at: Option<DateTime<Utc>>,
+
+    // Merkle root over the chain hashes of the final outputs, set once the batch completed,
+    // see `common::provenance`
+    #[serde(default)]
+    pub provenance_root: Option<String>,
+
+    // Where the manifest of every input and output was written once the batch completed, see
+    // `common::manifest::BatchManifest`
+    #[serde(default)]
+    pub batch_manifest_key: Option<String>,
+
+    // Metrics of the stages that drifted from their pipeline's baseline, see `common::drift`
+    #[serde(default)]
+    pub quality_drifts: Vec<QualityDrift>,
+
+    // Set when building the results archive of the download proxy failed, so downloads report it
+    // instead of waiting for an archive that never appears
+    #[serde(default)]
+    pub archive_failure: Option<ArchiveFailure>,
+}
+
+/// Why the results archive of a batch couldn't be built
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ArchiveFailure {
+    pub error: String,
+    #[serde(with = "common::timestamp")]
+    pub failed_at: DateTime<Utc>,
+}
+
+/// How long the outputs of a batch are kept and whether its owner was warned before they expire
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ResultsRetention {
+    pub days: u32,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub expires_at: Option<DateTime<Utc>>, // Set once the batch reaches a final state
+    #[serde(default, with = "common::timestamp::optional")]
+    pub warning_sent_at: Option<DateTime<Utc>>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub results_deleted_at: Option<DateTime<Utc>>, // Set once the expired outputs were deleted
+    pub notification_url: Option<String>, // Webhook of the submitter, notified on top of the operators
+}
+
+/// Where the report of a finished batch is stored
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BatchReportRecord {
+    pub html_key: String,
+    pub markdown_key: String,
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+}
+
+/// A storage error no retry gets past, e.g. a bucket policy denying the workers, which fails
+/// every image of the batch alike
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StorageAlert {
+    pub kind: S3ErrorKind,
+    pub key: String, // The first key the error was seen on
+    pub message: String,
+    #[serde(with = "common::timestamp")]
+    pub time_raised: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub time_sent: Option<DateTime<Utc>>,
+}
+
+/// The sampled outputs of a finished stage compared with earlier stages of its pipeline, see
+/// `common::drift`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StageQuality {
+    pub pipeline: String, // Upstream operations and the stage's own, what the baseline is kept by
+    pub summary: StageQualitySummary,
+    pub drifts: Vec<QualityDrift>,
+    #[serde(with = "common::timestamp")]
+    pub time_checked: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub alert_sent_at: Option<DateTime<Utc>>, // Set once the alerting service sent the drifts
+}
+
+/// A sidecar file found in the dataset of a batch
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DatasetSidecar {
+    pub path: String,          // Path inside the dataset, e.g. "cats/labels.csv"
+    pub key: String,           // Where the original is stored
+    pub output_prefix: String, // The prefix the stages of the dataset write under
+}
+
+/// A single transition in the lifecycle of a batch
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BatchStateChange {
+    pub from: BatchState,
+    pub to: BatchState,
+    #[serde(with = "common::timestamp")]
+    pub time: DateTime<Utc>,
+}
+
+/// Links a batch to the parameter sweep it was created by
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SweepMembership {
+    pub sweep_id: uuid::Uuid,
+    pub parameters: BTreeMap<String, serde_json::Value>, // "{stage}.{field}" -> value used by this batch
+}
+
+/// A copy-based snapshot of a batch's results prefix
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BatchSnapshot {
+    pub snapshot_id: uuid::Uuid,
+    pub prefix: String, // S3 prefix holding the copied objects
+    pub object_count: u64,
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
 }
 
 /// Database representation of a dataset processing task
@@ -51,10 +361,79 @@ pub struct DBDatasetTask {
     pub task_id: uuid::Uuid,
     pub batch_id: uuid::Uuid,
     pub dataset_key: String,
-    pub depends_on: Option<uuid::Uuid>,
+    // Tasks that have to succeed before this one is released, see `common::dag`
+    #[serde(default, deserialize_with = "common::dag::one_or_many")]
+    pub depends_on: Vec<uuid::Uuid>,
     pub operation: ImageOperation,
+    #[serde(default)]
+    pub fused_operations: Vec<ImageOperation>,
+    #[serde(default)]
+    pub stage: u32,
+    #[serde(default)]
+    pub input: Option<StageInput>,
+    #[serde(default)]
+    pub upstream_operations: Vec<ImageOperation>,
+    // The stages after it wait for `approval`, see `common::approval`
+    #[serde(default)]
+    pub requires_approval: bool,
+    #[serde(default)]
+    pub approval: Option<StageApproval>,
+    #[serde(default)]
+    pub use_local_cache: bool,
+    #[serde(default)]
+    pub output_layout: OutputLayout,
+    #[serde(default)]
+    pub collision_policy: CollisionPolicy,
+    #[serde(default)]
+    pub hash_suffix: bool,
+    #[serde(default)]
+    pub cache_scope: CacheScope,
+    #[serde(default)]
+    pub default_format: Option<OutputFormat>,
+    #[serde(default)]
+    pub alpha_policy: AlphaPolicy,
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy,
+    #[serde(default)]
+    pub previews: bool,
+    #[serde(default)]
+    pub object_tags: BTreeMap<String, String>,
 
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    // Request that created the task, restored when the scheduler publishes it later
+    #[serde(default)]
+    pub correlation_id: Option<uuid::Uuid>,
+
+    // Number of image tasks created for this stage, set once decomposition has finished
+    #[serde(default)]
+    pub image_count: Option<u64>,
+    // Renewed by the decomposer while it works on the task, see `recovery`
+    #[serde(default, with = "common::timestamp::optional")]
+    pub lease_expires: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub recoveries: u32,
+    // Fencing token of the last scheduler that moved the task along, see `leadership`
+    #[serde(default)]
+    pub fence: Option<u64>,
+    // Set once the stage's mappings were folded into mapping sets, see `mappings`
+    #[serde(default)]
+    pub mappings_compacted: bool,
+    // How far the decomposition got before it exceeded its budget, see `common::budgets`
+    #[serde(default)]
+    pub budget_checkpoint: Option<BudgetCheckpoint>,
+    // Set once the alerting service handled the stage finishing, see `notifications`
+    #[serde(default, with = "common::timestamp::optional")]
+    pub notice_sent_at: Option<DateTime<Utc>>,
+    // Set once the scheduler checked the stage's sampled outputs, see `drift`
+    #[serde(default)]
+    pub quality: Option<StageQuality>,
+
+    #[serde(with = "common::timestamp")]
     pub time_created: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
     pub time_completed: Option<DateTime<Utc>>,
     pub status: TaskStatus,
 }
@@ -73,8 +452,71 @@ pub struct DBImageTask {
     pub depends_on: Option<uuid::Uuid>,
     pub dependency_dataset_task_id: Option<uuid::Uuid>,
     pub operation: ImageOperation,
+    #[serde(default)]
+    pub fused_operations: Vec<ImageOperation>,
+    #[serde(default)]
+    pub input_dimensions: Option<Dimensions>,
+    #[serde(default)]
+    pub output_dimensions: Option<Dimensions>,
+    #[serde(default)]
+    pub affinity_key: Option<String>,
+    #[serde(default)]
+    pub output_key: Option<String>,
+    #[serde(default)]
+    pub source_path: Option<String>,
+    #[serde(default)]
+    pub hash_suffix: bool, // output_key is replaced by the hashed name once the task succeeded
+    #[serde(default)]
+    pub cache_scope: CacheScope,
+    #[serde(default)]
+    pub default_format: Option<OutputFormat>,
+    #[serde(default)]
+    pub alpha_policy: AlphaPolicy,
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy,
+    #[serde(default)]
+    pub previews: bool,
+    #[serde(default)]
+    pub object_tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub error: Option<String>, // Why the task failed, set along with the Failure status
+    #[serde(default)]
+    pub failure_kind: Option<FailureKind>, // Set along with `error`, see `common::failures`
+    #[serde(default)]
+    pub storage_error: Option<S3ErrorKind>, // Set when storage failed it, e.g. a missing input
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>, // Where an operation panicked, with an OperationPanic
+    #[serde(default)]
+    pub filter_reason: Option<String>, // Why a quality gate left the image out, with Filtered
+    #[serde(default)]
+    pub metadata: Option<ImageMetadata>, // What the worker read from the input, once it ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<ImageProvenance>, // The link of its output, see `provenance`
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub bytes_read: Option<u64>, // Size of the encoded input, unknown when it was handed over locally
+    #[serde(default)]
+    pub bytes_written: Option<u64>, // Size of the uploaded output, set once the task succeeded
+    #[serde(default)]
+    pub cache_hit: Option<bool>, // Whether the output came from the result cache, set once the task succeeded
+    #[serde(default)]
+    pub manifest_sequence: Option<u32>, // The results manifest listing the output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<ImageQualitySample>, // Set when the output was sampled, see `drift`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(with = "common::timestamp::optional")]
+    pub deferred_until: Option<DateTime<Utc>>, // When the scheduler requeues a deferred task
+    #[serde(default)]
+    pub deferrals: u32, // Times a worker deferred the task, see `fairness`
 
+    #[serde(with = "common::timestamp")]
     pub time_created: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub time_started: Option<DateTime<Utc>>, // When a worker last picked the task up
+    #[serde(default, with = "common::timestamp::optional")]
     pub time_completed: Option<DateTime<Utc>>,
     pub status: TaskStatus,
 }
@@ -91,8 +533,370 @@ pub struct DBMapping {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub dataset_task_id: uuid::Uuid,
+    pub image_filename: String, // Path of the entry inside the archive, what dependencies are resolved by
+    #[serde(default)]
+    pub output_name: String, // Name the image is stored under, differs from the path after a rename
+    pub image_task_id: uuid::Uuid,
+}
+
+/// The mappings of a completed dataset task folded into one document, or several for datasets
+/// too large for one, see `mappings`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBMappingSet {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub dataset_task_id: uuid::Uuid,
+    pub chunk: u32, // Position of the document among those of its dataset task, from 0
+    pub images: Vec<MappedImage>,
+}
+
+/// One image of a `DBMappingSet`, what a `DBMapping` held besides its dataset task
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MappedImage {
     pub image_filename: String,
+    pub output_name: String,
+    pub image_task_id: uuid::Uuid,
+}
+
+/// An image task to publish, written in the transaction that marks the task Ready, see `outbox`
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DBOutboxEntry {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub task_id: uuid::Uuid,
+    pub task: ImageTask, // The message sent to Kafka
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub time_sent: Option<DateTime<Utc>>, // Unsent entries are picked up by the relay
+}
+
+/// A first-stage dataset task of a new batch to publish, written in the transaction that records
+/// the dataset tasks of the batch, see `outbox`
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DBDatasetOutboxEntry {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub task_id: uuid::Uuid,
+    pub task: DatasetProcessingTask, // The message sent to Kafka
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub time_sent: Option<DateTime<Utc>>, // Unsent entries are picked up by the relay
+}
+
+/// A topic migration the api-server runs in the background, see `common::migration`
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DBTopicMigration {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub migration_id: uuid::Uuid,
+    pub plan: TopicMigrationPlan,
+    pub state: MigrationState,
+    pub report: Option<TopicMigrationReport>, // Set once completed
+    pub error: Option<MigrationError>,        // Set once failed
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub time_finished: Option<DateTime<Utc>>,
+}
+
+/// Records that an image extracted from a dataset was uploaded, so a retried decomposition can
+/// skip the upload
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBUploadMarker {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub dataset_task_id: uuid::Uuid,
+    pub s3_key: String,
+    pub size: u64, // Bytes uploaded
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+}
+
+/// A Kafka message that was handled, so a redelivery of it can be skipped
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBProcessedMessage {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub message_id: uuid::Uuid, // Id of the message envelope
+    pub consumer: String,       // Consumer group that handled it
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+}
+
+/// An image that failed to decompose or process, see `failures`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBImageFailure {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub batch_id: uuid::Uuid,
+    pub dataset_id: uuid::Uuid, // Dataset task of the stage the image failed in
+    pub image_task_id: uuid::Uuid,
+    pub filename: String, // Path of the image in the uploaded dataset
+    pub stage: u32,
+    #[serde(default)]
+    pub operation: String, // Operations of the failed task, e.g. "Resize+Blur"
+    pub error: String,
+    #[serde(default)]
+    pub kind: FailureKind, // Other for failures recorded before they were classified
+    #[serde(default)]
+    pub storage_error: Option<S3ErrorKind>, // e.g. NoSuchKey for an input that disappeared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>, // Where an operation panicked on the image
+    #[serde(with = "common::timestamp")]
+    pub time_failed: DateTime<Utc>,
+}
+
+/// How many images of a batch failed with one kind of failure in one stage
+#[derive(Clone, Debug)]
+pub struct FailureGroup {
+    pub kind: FailureKind,
+    pub stage: u32,
+    pub operation: String,
+    pub count: u64,
+    pub sample_error: String,    // The error of one of the images, they tend to be alike
+    pub sample_filename: String, // The image that error is from
+}
+
+/// Latency percentiles of one operation's image tasks that finished within a window
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBLatencyWindow {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub operation: String,
+    #[serde(with = "common::timestamp")]
+    pub window_start: DateTime<Utc>,
+    #[serde(with = "common::timestamp")]
+    pub window_end: DateTime<Utc>,
+    pub latency: LatencyPercentiles,
+}
+
+/// The offset a consumer group last committed on a partition, see `common::checkpoints`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBConsumerCheckpoint {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub group_id: String,
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    #[serde(with = "common::timestamp")]
+    pub time_updated: DateTime<Utc>,
+}
+
+/// How far a consumer group trails a partition as last reported, see `common::lag`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBConsumerLag {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub group_id: String,
+    pub topic: String,
+    pub partition: i32,
+    pub committed_offset: Option<i64>, // None if the group never committed on this partition
+    pub high_watermark: i64,
+    pub lag: i64,
+    #[serde(with = "common::timestamp")]
+    pub time_updated: DateTime<Utc>,
+}
+
+/// The lease of the scheduler instance currently moving batches along, see `leadership`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBLeaderLease {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,   // What the lease is for, e.g. "scheduler"
+    pub holder: String, // The instance holding it
+    pub token: u64,     // Fencing token, raised whenever another instance takes over
+    #[serde(with = "common::timestamp")]
+    pub lease_expires: DateTime<Utc>,
+}
+
+/// Moving average of the latency of an operation's image tasks, see `common::profiles`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBOperationProfile {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub operation: String, // Name of the operation, or of the chain of a fused stage
+    pub mean_ms: f64,
+    pub stages: u32, // Stages folded into the average
+    pub images: u64, // Image tasks measured over those stages
+    #[serde(with = "common::timestamp")]
+    pub time_updated: DateTime<Utc>,
+}
+
+/// The notification preferences of a tenant, or of every caller without authentication
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBNotificationPreferences {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub tenant_id: Option<String>,
+    pub preferences: NotificationPreferences,
+    #[serde(with = "common::timestamp")]
+    pub time_updated: DateTime<Utc>,
+}
+
+/// A chain of operations a tenant saved by name, see `pipelines`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBPipeline {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub tenant_id: Option<String>,
+    pub name: String,
+    pub version: u32, // From 1, bumped every time the pipeline is replaced
+    pub description: Option<String>,
+    pub operations: Vec<ImageOperation>,
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+    #[serde(with = "common::timestamp")]
+    pub time_updated: DateTime<Utc>,
+}
+
+impl DBPipeline {
+    /// The reference recorded on the batches that ran this version, e.g. "thumbnail-web@2"
+    pub fn reference(&self) -> String {
+        format!("{}@{}", self.name, self.version)
+    }
+}
+
+/// A tenant onboarded through the admin endpoints, see `tenants`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBTenant {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub tenant_id: String,
+    pub name: String, // For operators, e.g. the organization's name
+    pub status: TenantStatus,
+    #[serde(default)]
+    pub quotas: TenantQuotas,
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+    #[serde(with = "common::timestamp")]
+    pub time_updated: DateTime<Utc>,
+}
+
+/// A managed API key of a tenant, only its hash is kept
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBTenantApiKey {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub key_id: uuid::Uuid,
+    pub tenant_id: String,
+    pub key_hash: String, // SHA-256 of the key, see `common::tenancy::hash_api_key`
+    pub key_hint: String, // The start of the key
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+    #[serde(default, with = "common::timestamp::optional")]
+    pub expires_at: Option<DateTime<Utc>>, // Set when the key is rotated, after a grace period
+    #[serde(default, with = "common::timestamp::optional")]
+    pub time_revoked: Option<DateTime<Utc>>,
+}
+
+/// A change made to a tenant or its keys through the admin endpoints
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBTenantAudit {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub tenant_id: String,
+    pub action: String, // e.g. "key_issued"
+    pub key_id: Option<uuid::Uuid>,
+    pub details: Option<String>,
+    pub correlation_id: Option<uuid::Uuid>, // Of the request that made the change
+    #[serde(with = "common::timestamp")]
+    pub time: DateTime<Utc>,
+}
+
+/// What an image worker can run as of its last heartbeat, see `common::capabilities`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBWorker {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub worker_id: String,
+    pub capabilities: WorkerCapabilities,
+    #[serde(with = "common::timestamp")]
+    pub last_seen: DateTime<Utc>,
+}
+
+/// A task a worker couldn't run, waiting for a worker that can
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBHeldTask {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub hold_id: uuid::Uuid,
+    pub original_topic: String,
+    pub capability: Capability,
+    pub rejected_by: String,
+    #[serde(with = "common::timestamp")]
+    pub held_at: DateTime<Utc>,
+    #[serde(default)]
+    pub encoding: MessageEncoding, // Of `message`, hex encoded unless JSON
+    pub message: String,           // Requeued unchanged on `original_topic`
+}
+
+impl From<&HeldTask> for DBHeldTask {
+    fn from(held: &HeldTask) -> Self {
+        Self {
+            id: None,
+            hold_id: held.hold_id,
+            original_topic: held.original_topic.clone(),
+            capability: held.capability.clone(),
+            rejected_by: held.rejected_by.clone(),
+            held_at: held.held_at,
+            encoding: held.encoding,
+            message: held.message.clone(),
+        }
+    }
+}
+
+/// A link sharing a batch's results without an API key, only its token's hash is kept
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBShareLink {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub share_id: uuid::Uuid,
+    pub batch_id: uuid::Uuid,
+    pub tenant_id: Option<String>, // Of the caller that shared the batch
+    pub token_hash: String, // SHA-256 of the token, see `common::sharing::hash_share_token`
+    pub scope: ShareScope,
+    #[serde(with = "common::timestamp")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
+}
+
+/// How long a succeeded image task spent in each phase, see `common::analytics`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBTaskMetrics {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
     pub image_task_id: uuid::Uuid,
+    pub batch_id: uuid::Uuid,
+    pub tenant_id: Option<String>,
+    pub operation: String, // Name of the operation, or of the chain of a fused stage
+    pub release: String,
+    pub worker_id: String,
+    pub cache_hit: Option<bool>,
+    pub bytes_read: Option<u64>,
+    pub bytes_written: u64,
+    pub timings: TaskTimings,
+    #[serde(with = "common::timestamp")]
+    pub time_recorded: DateTime<Utc>,
+}
+
+/// One request to the results download proxy
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DBDownloadAudit {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub batch_id: uuid::Uuid,
+    pub s3_key: String,
+    pub range: Option<String>, // Range header sent by the client, if any
+    pub status: u16,
+    pub bytes_served: u64,
+    pub client: Option<String>, // X-Forwarded-For of the request
+    pub user_agent: Option<String>,
+    #[serde(with = "common::timestamp")]
+    pub time_created: DateTime<Utc>,
 }
 
 // ============================================================================
@@ -100,8 +904,31 @@ pub struct DBMapping {
 // Provides access to MongoDB collections
 // ============================================================================
 pub struct DBClient {
+    pub(crate) client: mongodb::Client, // Starts the sessions of transactions, see `outbox`
     pub image_tasks: Collection<DBImageTask>,
     pub dataset_tasks: Collection<DBDatasetTask>,
     pub dataset_batch_tasks: Collection<DBDatasetProcessingJob>,
     pub mappings: Collection<DBMapping>,
+    pub mapping_sets: Collection<DBMappingSet>,
+    pub upload_markers: Collection<DBUploadMarker>,
+    pub download_audits: Collection<DBDownloadAudit>,
+    pub processed_messages: Collection<DBProcessedMessage>,
+    pub latency_windows: Collection<DBLatencyWindow>,
+    pub operation_profiles: Collection<DBOperationProfile>,
+    pub image_failures: Collection<DBImageFailure>,
+    pub leader_leases: Collection<DBLeaderLease>,
+    pub consumer_checkpoints: Collection<DBConsumerCheckpoint>,
+    pub consumer_lag: Collection<DBConsumerLag>,
+    pub outbox: Collection<DBOutboxEntry>,
+    pub dataset_outbox: Collection<DBDatasetOutboxEntry>,
+    pub topic_migrations: Collection<DBTopicMigration>,
+    pub notification_preferences: Collection<DBNotificationPreferences>,
+    pub workers: Collection<DBWorker>,
+    pub held_tasks: Collection<DBHeldTask>,
+    pub pipelines: Collection<DBPipeline>,
+    pub tenants: Collection<DBTenant>,
+    pub tenant_api_keys: Collection<DBTenantApiKey>,
+    pub tenant_audit: Collection<DBTenantAudit>,
+    pub share_links: Collection<DBShareLink>,
+    pub task_metrics: Collection<DBTaskMetrics>,
 }
@@ -0,0 +1,61 @@
+use common::{error::ProcessorError, timestamp};
+use mongodb::{
+    bson::{doc, to_bson},
+    options::UpdateOptions,
+};
+
+use crate::error::{bson_error, db_error};
+use crate::types::*;
+
+// ============================================================================
+// UPLOAD MARKERS
+// The decomposer records every image it extracted and uploaded. When a
+// dataset task is delivered again (after a crash or a failed publish), images
+// that already have a marker aren't uploaded a second time.
+// ============================================================================
+
+impl DBClient {
+    /// Whether the dataset task already uploaded an object to the given key
+    pub async fn has_upload_marker(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        s3_key: &str,
+    ) -> Result<bool, ProcessorError> {
+        let filter = doc! {
+            "dataset_task_id": to_bson(dataset_task_id).map_err(bson_error)?,
+            "s3_key": s3_key,
+        };
+
+        self.upload_markers
+            .count_documents(filter, None)
+            .await
+            .map(|count| count > 0)
+            .map_err(db_error)
+    }
+
+    /// Records an upload of the dataset task. Recording the same key twice keeps a single marker.
+    pub async fn add_upload_marker(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        s3_key: &str,
+        size: u64,
+    ) -> Result<(), ProcessorError> {
+        let filter = doc! {
+            "dataset_task_id": to_bson(dataset_task_id).map_err(bson_error)?,
+            "s3_key": s3_key,
+        };
+        let update = doc! {
+            "$set": {
+                "size": size as i64,
+                "time_created": timestamp::now(),
+            }
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        self.upload_markers
+            .update_one(filter, update, options)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
@@ -0,0 +1,79 @@
+use bytes::Bytes;
+use common::{CacheScope, ImageOperation, ImageTask, metrics, tenancy::tenant_key};
+use sha2::{Digest, Sha256};
+use storage::{PutOptions, StorageBackend};
+use tracing::{debug, warn};
+
+// ============================================================================
+// RESULT CACHE
+// An image task's output only depends on its input bytes, its operations and
+// the worker's version, so the encoded output is stored under a hash of the
+// three and reused by later tasks that hash the same. Entries live in the
+// tenant's prefix unless the job opted into the shared namespace, which every
+// opted-in job reads and writes. Only workers write entries, from outputs they
+// computed themselves, so sharing can't be used to plant results for others.
+// ============================================================================
+
+const CACHE_PREFIX: &str = "cache/";
+const SHARED_CACHE_PREFIX: &str = "shared-cache/";
+
+/// What determines the output of the task besides its input, serialized. The default format and
+/// the policies change the output as much as an operation does.
+pub(crate) fn operations_descriptor(task: &ImageTask) -> Result<Vec<u8>, String> {
+    let operations: Vec<&ImageOperation> = task.operations().collect();
+    let policies = (task.alpha_policy, task.metadata_policy);
+    serde_json::to_vec(&(operations, task.target_format(), policies)).map_err(|e| e.to_string())
+}
+
+/// The key the output of the task is cached under, for the given encoded input
+pub(crate) fn cache_key(task: &ImageTask, input: &[u8]) -> Result<String, String> {
+    let operations = operations_descriptor(task)?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    // Length prefixed, so the operations can't run into the input
+    hasher.update((operations.len() as u64).to_le_bytes());
+    hasher.update(&operations);
+    hasher.update(input);
+    let hash = hex::encode(hasher.finalize());
+
+    Ok(match task.cache_scope {
+        CacheScope::Tenant => tenant_key(
+            task.tenant_id.as_deref(),
+            &format!("{}{}", CACHE_PREFIX, hash),
+        ),
+        CacheScope::Shared => format!("{}{}", SHARED_CACHE_PREFIX, hash),
+    })
+}
+
+/// The cached output under the key, `None` on a miss
+pub(crate) async fn lookup(
+    storage: &dyn StorageBackend,
+    key: &str,
+    scope: CacheScope,
+) -> Option<Bytes> {
+    // Backends don't tell a missing object apart from a failed read, either way it is computed
+    let cached = match storage.get_object(key).await {
+        Ok(output) => Some(output),
+        Err(e) => {
+            debug!(%key, error = %e, "No cached output");
+            None
+        }
+    };
+
+    let result = if cached.is_some() { "hit" } else { "miss" };
+    metrics::RESULT_CACHE_LOOKUPS
+        .with_label_values(&[scope.name(), result])
+        .inc();
+    cached
+}
+
+/// Caches a computed output. A failure only costs a later task the reuse, so it is logged.
+pub(crate) async fn store(storage: &dyn StorageBackend, key: &str, output: Bytes) {
+    if let Err(e) = storage
+        .put_object(key, output, &PutOptions::default())
+        .await
+    {
+        warn!(%key, error = %e, "Failed to cache the output");
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use common::{
+    config::Config,
+    control::{ControlCommand, ControlMessage},
+    envelope::MessageEnvelope,
+    logging::set_log_level,
+    metrics,
+};
+use queue::{concurrency::ConcurrencyLimit, consumer::ConsumerClient, routing::MessageRouter};
+
+use crate::hooks::OperationAllowlist;
+use crate::memory::GovernorThresholds;
+
+// ============================================================================
+// RUNTIME CONTROL
+// Every worker consumes the control topic next to its image tasks and applies
+// the commands addressed to the whole fleet or to its own worker id, see
+// `common::control`.
+// ============================================================================
+
+const CONTROL_GROUP_PREFIX: &str = "image-worker-control";
+
+/// What the control commands act on
+pub struct WorkerControls {
+    pub worker_id: String,
+    pub concurrency: Arc<ConcurrencyLimit>,
+    pub consumer: Arc<ConsumerClient>, // Consumer of the image tasks, shut down to drain
+    pub allowlist: Arc<OperationAllowlist>,
+    pub thresholds: Arc<GovernorThresholds>,
+}
+
+/// Consumes the control topic for as long as the worker runs
+pub fn spawn_control_consumer(config: &Config, controls: WorkerControls) {
+    let consumer = match ConsumerClient::broadcast_from_config(
+        &config.kafka,
+        CONTROL_GROUP_PREFIX,
+        &[&config.kafka.control_topic],
+    ) {
+        Ok(consumer) => consumer,
+        Err(e) => {
+            tracing::error!(error = %e, "WORKER: Failed to consume control commands");
+            return;
+        }
+    };
+
+    let controls = Arc::new(controls);
+    let mut router = MessageRouter::new();
+    for message_type in ControlCommand::MESSAGE_TYPES {
+        let controls = Arc::clone(&controls);
+        let handler = move |envelope: MessageEnvelope<ControlMessage>| {
+            let controls = Arc::clone(&controls);
+            async move {
+                apply(&controls, envelope.payload);
+                Ok(())
+            }
+        };
+        router = router.on(message_type, handler);
+    }
+
+    tokio::spawn(async move { consumer.start_routing(router).await });
+}
+
+fn apply(controls: &WorkerControls, message: ControlMessage) {
+    if !message.applies_to(&controls.worker_id) {
+        return;
+    }
+    tracing::info!(command = ?message.command, "WORKER: Applying control command");
+
+    match message.command {
+        // The memory governor keeps moving the limit within its bounds from here
+        ControlCommand::SetConcurrency { limit } => {
+            controls.concurrency.set_limit(limit);
+            metrics::WORKER_CONCURRENCY_LIMIT.set(controls.concurrency.limit() as i64);
+        }
+        ControlCommand::SetLogLevel { level } => set_log_level(level),
+        ControlCommand::Drain => controls.consumer.shutdown(),
+        ControlCommand::ReloadAllowlist => match Config::load() {
+            Ok(config) => {
+                let operations = config.worker.allowed_operations;
+                controls.allowlist.replace(&operations);
+            }
+            Err(e) => tracing::error!(error = %e, "WORKER: Failed to reload the allowlist"),
+        },
+        ControlCommand::SetMemoryThresholds { thresholds } if thresholds.is_valid() => {
+            controls.thresholds.replace(thresholds);
+        }
+        ControlCommand::SetMemoryThresholds { thresholds } => {
+            tracing::warn!(?thresholds, "WORKER: Ignoring invalid memory thresholds");
+        }
+    }
+}
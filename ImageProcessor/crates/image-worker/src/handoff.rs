@@ -0,0 +1,78 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+
+// ============================================================================
+// LOCAL HANDOFF
+// When every stage of an image is pinned to the same worker (use_local_cache),
+// a stage leaves its decoded pixels in a spill file for the next one, which
+// then skips the S3 download and the decode. The encoded output is still
+// uploaded, so a stage that lands on another worker, or finds no spill file,
+// falls back to reading it from S3.
+// ============================================================================
+
+pub struct LocalHandoff {
+    dir: PathBuf,
+    ttl: Duration, // Spill files nobody picked up are removed after this long
+}
+
+impl LocalHandoff {
+    pub fn new(dir: &str, ttl: Duration) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            ttl,
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.raw", hex::encode(Sha256::digest(key))))
+    }
+
+    /// Removes and returns the pixels left for the object key, if there are any
+    pub(crate) async fn take(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.path(key);
+        let bytes = tokio::fs::read(&path).await.ok()?;
+        let _ = tokio::fs::remove_file(&path).await;
+
+        Some(bytes)
+    }
+
+    /// Leaves pixels for the stage reading the object key. Written to a temporary name first,
+    /// so a reader never sees a partial file.
+    pub(crate) async fn put(&self, key: &str, bytes: Vec<u8>) -> std::io::Result<()> {
+        let path = self.path(key);
+        let partial = path.with_extension("partial");
+
+        tokio::fs::write(&partial, bytes).await?;
+        tokio::fs::rename(&partial, &path).await
+    }
+
+    /// Removes spill files older than the ttl, e.g. those of the last stage or of stages that
+    /// ran elsewhere
+    pub async fn sweep(&self) -> std::io::Result<usize> {
+        let mut removed = 0;
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let expired = entry
+                .metadata()
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|age| age > self.ttl);
+
+            if expired && tokio::fs::remove_file(entry.path()).await.is_ok() {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use common::{
+    ImageTask,
+    config::Config,
+    failures::{ClassifiedError, FailureKind},
+};
+
+// ============================================================================
+// WORKER HOOKS
+// Extension points around the processing of an image task, for deployments
+// that need extra validation, custom metrics or notifications without forking
+// the processing loop. Hooks are registered in `register_hooks` and run in
+// registration order.
+// ============================================================================
+
+/// Custom behavior run by the worker at fixed points of every image task. Every method has a
+/// no-op default, so a hook only implements the points it cares about.
+#[async_trait]
+pub(crate) trait WorkerHooks: Send + Sync {
+    /// Runs before the input is decoded. `input` is the encoded object, or `None` when the
+    /// previous stage handed its pixels over locally. An error fails the task.
+    async fn pre_decode(&self, _task: &ImageTask, _input: Option<&[u8]>) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Runs after the output was encoded, before it is uploaded to `output_key`. An error fails
+    /// the task and nothing is uploaded.
+    async fn post_encode(
+        &self,
+        _task: &ImageTask,
+        _output: &[u8],
+        _output_key: &str,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Runs once the task has failed, whatever failed it
+    async fn on_failure(&self, _task: &ImageTask, _error: &str) {}
+
+    /// The kind recorded on the tasks this hook fails, see `common::failures`
+    fn failure_kind(&self) -> FailureKind {
+        FailureKind::Other
+    }
+}
+
+/// The hooks of the worker, run one after the other
+#[derive(Clone, Default)]
+pub struct HookRegistry {
+    hooks: Vec<Arc<dyn WorkerHooks>>,
+}
+
+impl HookRegistry {
+    pub(crate) fn register(&mut self, hook: Arc<dyn WorkerHooks>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Runs every `pre_decode` hook, stopping at the first error
+    pub(crate) async fn pre_decode(
+        &self,
+        task: &ImageTask,
+        input: Option<&[u8]>,
+    ) -> Result<(), ClassifiedError> {
+        for hook in &self.hooks {
+            hook.pre_decode(task, input)
+                .await
+                .map_err(|e| ClassifiedError::new(hook.failure_kind(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Runs every `post_encode` hook, stopping at the first error
+    pub(crate) async fn post_encode(
+        &self,
+        task: &ImageTask,
+        output: &[u8],
+        output_key: &str,
+    ) -> Result<(), ClassifiedError> {
+        for hook in &self.hooks {
+            hook.post_encode(task, output, output_key)
+                .await
+                .map_err(|e| ClassifiedError::new(hook.failure_kind(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Runs every `on_failure` hook
+    pub(crate) async fn on_failure(&self, task: &ImageTask, error: &str) {
+        for hook in &self.hooks {
+            hook.on_failure(task, error).await;
+        }
+    }
+}
+
+/// Rejects encoded inputs larger than the configured limit before they are decoded
+struct InputSizeLimit {
+    max_bytes: u64,
+}
+
+#[async_trait]
+impl WorkerHooks for InputSizeLimit {
+    async fn pre_decode(&self, task: &ImageTask, input: Option<&[u8]>) -> Result<(), String> {
+        match input {
+            Some(input) if input.len() as u64 > self.max_bytes => Err(format!(
+                "Input {} is {} bytes, the limit is {}",
+                task.s3_key,
+                input.len(),
+                self.max_bytes
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn failure_kind(&self) -> FailureKind {
+        FailureKind::ResourceLimit
+    }
+}
+
+/// Rejects tasks applying an operation the worker doesn't run, every operation is allowed while
+/// the list is empty. The list is replaced at runtime by the `reload_allowlist` command.
+pub struct OperationAllowlist {
+    operations: RwLock<HashSet<String>>,
+}
+
+impl OperationAllowlist {
+    pub fn new(operations: &[String]) -> Self {
+        Self {
+            operations: RwLock::new(operations.iter().cloned().collect()),
+        }
+    }
+
+    pub(crate) fn replace(&self, operations: &[String]) {
+        *self.operations.write().expect("Allowlist lock poisoned") =
+            operations.iter().cloned().collect();
+    }
+}
+
+#[async_trait]
+impl WorkerHooks for OperationAllowlist {
+    async fn pre_decode(&self, task: &ImageTask, _input: Option<&[u8]>) -> Result<(), String> {
+        let operations = self.operations.read().expect("Allowlist lock poisoned");
+        if operations.is_empty() {
+            return Ok(());
+        }
+
+        match task.operations().find(|op| !operations.contains(op.name())) {
+            Some(op) => Err(format!("{} is not allowed on this worker", op.name())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The hooks this deployment runs. Downstream deployments register theirs here, e.g.
+/// `hooks.register(Arc::new(MyValidation::new(config)))`.
+pub fn register_hooks(config: &Config, allowlist: Arc<OperationAllowlist>) -> HookRegistry {
+    let mut hooks = HookRegistry::default();
+
+    hooks.register(allowlist);
+
+    if let Some(max_bytes) = config.worker.max_input_bytes {
+        hooks.register(Arc::new(InputSizeLimit { max_bytes }));
+    }
+
+    hooks
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+use common::{error::ProcessorError, metrics};
+use sha2::{Digest, Sha256};
+use storage::StorageBackend;
+use tracing::{debug, warn};
+
+// ============================================================================
+// INPUT CACHE
+// Every stage downloads its input, which past the first stage is the output an
+// earlier stage wrote moments ago, often on this very worker when the stages
+// are pinned to it. The worker keeps the encoded objects it read, and the
+// outputs of pinned stages, in a memory LRU and moves what falls out of it to
+// a directory when one is configured. Entries are keyed by object key and
+// ETag, so a lookup costs a HEAD instead of a GET and an overwritten object is
+// never served stale. Objects without an ETag (local storage) are always read.
+// ============================================================================
+
+const ENTRY_EXTENSION: &str = "input";
+
+/// Sizes of the entries of one tier by name, evicting the least recently used beyond a bound
+struct Lru {
+    entries: HashMap<String, (u64, u64)>, // Name to the tick of its last use and its size
+    uses: BTreeMap<u64, String>,          // Tick of the last use to name, oldest first
+    clock: u64,
+    bytes: u64,
+    capacity: u64,
+}
+
+impl Lru {
+    fn new(capacity: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            uses: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    /// Marks the entry as just used, returns whether it is held
+    fn touch(&mut self, name: &str) -> bool {
+        let Some((used, _)) = self.entries.get_mut(name) else {
+            return false;
+        };
+        self.uses.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.uses.insert(self.clock, name.to_string());
+        true
+    }
+
+    /// Adds an entry, returns the names evicted to make room for it
+    fn insert(&mut self, name: String, size: u64) -> Vec<String> {
+        if self.touch(&name) {
+            return Vec::new();
+        }
+        self.clock += 1;
+        self.entries.insert(name.clone(), (self.clock, size));
+        self.uses.insert(self.clock, name);
+        self.bytes += size;
+
+        let mut evicted = Vec::new();
+        while self.bytes > self.capacity {
+            let Some((_, oldest)) = self.uses.pop_first() else {
+                break;
+            };
+            if let Some((_, size)) = self.entries.remove(&oldest) {
+                self.bytes -= size;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some((used, size)) = self.entries.remove(name) {
+            self.uses.remove(&used);
+            self.bytes -= size;
+        }
+    }
+}
+
+struct MemoryTier {
+    lru: Lru,
+    objects: HashMap<String, Bytes>,
+}
+
+struct DiskTier {
+    dir: PathBuf,
+    lru: Mutex<Lru>,
+}
+
+pub struct InputCache {
+    memory: Mutex<MemoryTier>,
+    disk: Option<DiskTier>,
+}
+
+/// The name of the entry holding the object's content as of `etag`
+fn entry_name(key: &str, etag: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+    // Keys can't contain a NUL, so no key and ETag run into another pair
+    hasher.update([0]);
+    hasher.update(etag.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+impl InputCache {
+    /// A cache holding up to `memory_bytes` in memory, and up to `disk_bytes` in `dir` if given.
+    /// A restarted worker starts cold, entries an earlier run left in `dir` are removed.
+    pub fn new(
+        memory_bytes: u64,
+        dir: Option<&str>,
+        disk_bytes: u64,
+    ) -> std::io::Result<Self> {
+        let disk = match dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                for entry in std::fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                Some(DiskTier {
+                    dir: PathBuf::from(dir),
+                    lru: Mutex::new(Lru::new(disk_bytes)),
+                })
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            memory: Mutex::new(MemoryTier {
+                lru: Lru::new(memory_bytes),
+                objects: HashMap::new(),
+            }),
+            disk,
+        })
+    }
+
+    /// Reads an object, from the cache while its ETag still matches
+    pub(crate) async fn read(
+        &self,
+        storage: &dyn StorageBackend,
+        key: &str,
+    ) -> Result<Bytes, ProcessorError> {
+        let Some(etag) = storage.head_object(key).await?.etag else {
+            return storage.get_object(key).await;
+        };
+        let name = entry_name(key, &etag);
+
+        if let Some(bytes) = self.memory_get(&name) {
+            metrics::INPUT_CACHE_LOOKUPS
+                .with_label_values(&["memory"])
+                .inc();
+            return Ok(bytes);
+        }
+        if let Some(bytes) = self.disk_get(&name).await {
+            metrics::INPUT_CACHE_LOOKUPS.with_label_values(&["disk"]).inc();
+            self.put(name, bytes.clone()).await;
+            return Ok(bytes);
+        }
+        metrics::INPUT_CACHE_LOOKUPS.with_label_values(&["none"]).inc();
+
+        let bytes = storage.get_object(key).await?;
+        self.put(name, bytes.clone()).await;
+        Ok(bytes)
+    }
+
+    /// Keeps an output the worker just wrote for the stage reading it next. A failure only costs
+    /// that stage the download, so it is logged.
+    pub(crate) async fn keep(&self, storage: &dyn StorageBackend, key: &str, bytes: Bytes) {
+        match storage.head_object(key).await {
+            Ok(info) => {
+                if let Some(etag) = info.etag {
+                    self.put(entry_name(key, &etag), bytes).await;
+                }
+            }
+            Err(e) => debug!(%key, error = %e, "Failed to read the ETag of the output to cache"),
+        }
+    }
+
+    fn memory_get(&self, name: &str) -> Option<Bytes> {
+        let mut memory = self.memory.lock().unwrap();
+        match memory.lru.touch(name) {
+            true => memory.objects.get(name).cloned(),
+            false => None,
+        }
+    }
+
+    async fn disk_get(&self, name: &str) -> Option<Bytes> {
+        let disk = self.disk.as_ref()?;
+        if !disk.lru.lock().unwrap().touch(name) {
+            return None;
+        }
+
+        match tokio::fs::read(disk.path(name)).await {
+            Ok(bytes) => Some(Bytes::from(bytes)),
+            Err(e) => {
+                warn!(error = %e, "Failed to read a cached input, forgetting it");
+                disk.lru.lock().unwrap().remove(name);
+                None
+            }
+        }
+    }
+
+    /// Adds an entry to memory, moving the entries it evicts to disk. Entries larger than the
+    /// memory tier go to disk right away.
+    async fn put(&self, name: String, bytes: Bytes) {
+        let size = bytes.len() as u64;
+        let spilled = {
+            let mut memory = self.memory.lock().unwrap();
+            let spilled = match size > memory.lru.capacity {
+                true => vec![(name, bytes)],
+                false => {
+                    memory.objects.insert(name.clone(), bytes);
+                    let evicted = memory.lru.insert(name, size);
+                    evicted
+                        .into_iter()
+                        .filter_map(|name| memory.objects.remove(&name).map(|bytes| (name, bytes)))
+                        .collect()
+                }
+            };
+            metrics::INPUT_CACHE_BYTES
+                .with_label_values(&["memory"])
+                .set(memory.lru.bytes as i64);
+            spilled
+        };
+
+        if let Some(disk) = &self.disk {
+            for (name, bytes) in spilled {
+                if let Err(e) = disk.put(name, bytes).await {
+                    warn!(error = %e, "Failed to move a cached input to disk");
+                }
+            }
+        }
+    }
+}
+
+impl DiskTier {
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", name, ENTRY_EXTENSION))
+    }
+
+    /// Writes an entry and removes the ones it evicts. Written to a temporary name first, so a
+    /// reader never sees a partial file.
+    async fn put(&self, name: String, bytes: Bytes) -> std::io::Result<()> {
+        let size = bytes.len() as u64;
+        if size > self.lru.lock().unwrap().capacity {
+            return Ok(());
+        }
+        let path = self.path(&name);
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, &bytes).await?;
+        tokio::fs::rename(&partial, &path).await?;
+
+        let (evicted, bytes) = {
+            let mut lru = self.lru.lock().unwrap();
+            (lru.insert(name, size), lru.bytes)
+        };
+        metrics::INPUT_CACHE_BYTES
+            .with_label_values(&["disk"])
+            .set(bytes as i64);
+        for name in evicted {
+            let _ = tokio::fs::remove_file(self.path(&name)).await;
+        }
+        Ok(())
+    }
+}
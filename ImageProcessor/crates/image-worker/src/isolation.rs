@@ -0,0 +1,67 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe, PanicHookInfo};
+
+use common::failures::FailureKind;
+
+use crate::tasks::TaskFailure;
+
+// ============================================================================
+// PANIC ISOLATION
+// Decoders and operations run on blocking threads, where a panic on a broken
+// image would only surface as a join error that doesn't say where it came
+// from. The CPU-bound section of every task runs in `isolate` instead, which
+// catches the panic and fails just that task, with the panic's message and a
+// backtrace of where it was raised. The tasks running next to it carry on, and
+// so does the worker.
+//
+// The backtrace is captured by the panic hook on the panicking thread, it is
+// gone once the panic unwound. Panics outside an isolated section still go to
+// the default hook.
+// ============================================================================
+
+thread_local! {
+    static ISOLATED: Cell<bool> = const { Cell::new(false) };
+    static BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Installs the panic hook capturing the backtraces of isolated sections, once at startup
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info: &PanicHookInfo| {
+        match ISOLATED.get() {
+            true => {
+                let backtrace = Backtrace::force_capture().to_string();
+                BACKTRACE.set(Some(backtrace));
+            }
+            false => default_hook(info),
+        }
+    }));
+}
+
+/// Runs the CPU-bound section of a task, turning a panic into an `OperationPanic` failure of the
+/// task carrying the panic's message and backtrace
+pub(crate) fn isolate<T>(work: impl FnOnce() -> Result<T, TaskFailure>) -> Result<T, TaskFailure> {
+    let outer = ISOLATED.replace(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(work));
+    ISOLATED.set(outer);
+
+    result.unwrap_or_else(|payload| {
+        let message = format!("Operation panicked: {}", panic_message(payload.as_ref()));
+        let failure = TaskFailure::new(FailureKind::OperationPanic, message);
+        Err(TaskFailure {
+            backtrace: BACKTRACE.take(),
+            ..failure
+        })
+    })
+}
+
+/// The message `panic!` was given, panics with any other payload have none
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
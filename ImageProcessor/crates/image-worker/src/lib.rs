@@ -0,0 +1,11 @@
+mod cache;
+pub mod control;
+pub mod handoff;
+pub mod hooks;
+pub mod input_cache;
+pub mod isolation;
+pub mod memory;
+mod simulation;
+pub mod store;
+pub mod tasks;
+pub mod utils;
@@ -0,0 +1,244 @@
+use common::analytics::DEFAULT_RELEASE;
+use common::capabilities::WorkerCapabilities;
+use common::config::{Config, QueueKind};
+use common::envelope::MessageEnvelope;
+use common::faults;
+use common::logging;
+use common::metrics;
+use common::ImageTask;
+use db_utils::types::DBClient;
+use queue::ProducerClient;
+use queue::admin::KafkaAdmin;
+use queue::concurrency::ConcurrencyLimit;
+use queue::consumer::ConsumerClient;
+use queue::events::BatchEventPublisher;
+use queue::holding::CapabilityHolding;
+use queue::migration::TopicSwitcher;
+use queue::partitioner::AffinityPartitioner;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+use image_worker::control::{self, WorkerControls};
+use image_worker::handoff::LocalHandoff;
+use image_worker::hooks::{self, OperationAllowlist};
+use image_worker::input_cache::InputCache;
+use image_worker::isolation;
+use image_worker::memory::{self, GovernorThresholds};
+use image_worker::tasks::handle_task;
+use image_worker::utils::WorkerAppState;
+
+const HANDOFF_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const WORKER_GROUP: &str = "image-workers";
+// Pinned workers never join their group, so they can't share the subscribed workers' one
+const PINNED_WORKER_GROUP: &str = "image-workers-pinned";
+
+/// Registers what the worker can run every interval, for as long as the worker runs, so the
+/// scheduler knows which held tasks a worker of the fleet can pick up
+fn spawn_heartbeat(
+    database: Arc<DBClient>,
+    worker_id: String,
+    capabilities: WorkerCapabilities,
+    interval: Duration,
+) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = database
+                .record_worker_heartbeat(&worker_id, &capabilities)
+                .await
+            {
+                warn!(error = %e, "WORKER: Failed to record heartbeat");
+            }
+        }
+    });
+}
+
+/// Removes the spill files nobody picked up, for as long as the worker runs
+fn spawn_handoff_sweeper(handoff: Arc<LocalHandoff>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(HANDOFF_SWEEP_INTERVAL).await;
+            match handoff.sweep().await {
+                Ok(0) => {}
+                Ok(removed) => info!(removed, "WORKER: Removed expired handoff files"),
+                Err(e) => error!(error = %e, "WORKER: Failed to sweep handoff directory"),
+            }
+        }
+    });
+}
+
+/// Exits when workers subscribed to the image topics are running: they would be handed the
+/// partitions pinned here as well, and handle their tasks a second time
+fn refuse_subscribed_workers(config: &Config) {
+    if config.kafka.backend != QueueKind::Kafka {
+        return;
+    }
+    let members = KafkaAdmin::new(config.kafka.consumer_brokers())
+        .group_members(WORKER_GROUP)
+        .expect("WORKER: Failed to check for subscribed workers");
+    if members > 0 {
+        panic!(
+            "WORKER: {} workers of group {} consume every partition, pinned workers can't run \
+             next to them",
+            members, WORKER_GROUP
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Config::load().expect("WORKER: Failed to load config");
+    logging::init(&config.logging);
+    faults::install(&config.faults);
+    isolation::install_panic_hook();
+
+    let producer = ProducerClient::from_config(&config, &config.kafka.image_topic)
+        .with_partitioner(AffinityPartitioner);
+    let db_client = Arc::new(DBClient::new(&config.mongo).await);
+    // Starts at the maximum, the memory governor backs off from there if it has to
+    let concurrency = Arc::new(ConcurrencyLimit::new(config.worker.max_concurrency));
+    let pinned = config.worker.pinned_partitions.as_slice();
+    let group_id = match pinned.is_empty() {
+        true => WORKER_GROUP,
+        false => PINNED_WORKER_GROUP,
+    };
+    let checkpoints = db_client
+        .get_consumer_checkpoints(group_id)
+        .await
+        .expect("WORKER: Failed to read consumer checkpoints");
+    // A pool dedicated to some operations consumes only the topics they are routed to
+    let topics: Vec<&str> = match config.worker.topics.is_empty() {
+        true => vec![&config.kafka.image_topic],
+        false => config.worker.topics.iter().map(String::as_str).collect(),
+    };
+    info!(?topics, "WORKER: Consuming image tasks");
+    let worker_id = config
+        .worker
+        .worker_id
+        .clone()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    // A pinned worker keeps the partitions, and with them the images, whose stages it cached
+    let consumer = match pinned {
+        [] => ConsumerClient::from_config(&config.kafka, group_id, &topics),
+        partitions => {
+            info!(?partitions, "WORKER: Pinned to partitions of the image topics");
+            refuse_subscribed_workers(&config);
+            ConsumerClient::pinned_from_config(&config.kafka, group_id, &topics, partitions)
+        }
+    };
+    let mut consumer = consumer
+        .and_then(|consumer| {
+            consumer.with_start_position(&config.kafka.start_position, &checkpoints)
+        })
+        .expect("WORKER: Failed to create consumer")
+        .with_concurrency_limit(Arc::clone(&concurrency))
+        .with_checkpoints(db_client.checkpoint_recorder(group_id));
+    if config.capabilities.holding {
+        let producer = ProducerClient::from_config(&config, &config.capabilities.holding_topic);
+        consumer = consumer.with_capability_holding(CapabilityHolding::new(producer, &worker_id));
+    }
+    let consumer = Arc::new(consumer);
+    // Moves along when the image topic is migrated, see `queue::migration`
+    TopicSwitcher::new()
+        .producer(&producer)
+        .consumer(&consumer)
+        .start(&config.kafka)
+        .await;
+    let thresholds = Arc::new(GovernorThresholds::default());
+    memory::spawn_memory_governor(
+        Arc::clone(&concurrency),
+        Arc::clone(&thresholds),
+        config.worker.clone(),
+    );
+
+    let allowlist = Arc::new(OperationAllowlist::new(&config.worker.allowed_operations));
+    info!(%worker_id, "WORKER: Accepting control commands");
+    control::spawn_control_consumer(
+        &config,
+        WorkerControls {
+            worker_id: worker_id.clone(),
+            concurrency,
+            consumer: Arc::clone(&consumer),
+            allowlist: Arc::clone(&allowlist),
+            thresholds,
+        },
+    );
+
+    let handoff = config.worker.handoff_dir.as_deref().map(|dir| {
+        let ttl = Duration::from_secs(config.worker.handoff_ttl_secs);
+        Arc::new(LocalHandoff::new(dir, ttl).expect("WORKER: Failed to create handoff directory"))
+    });
+    if let Some(handoff) = &handoff {
+        spawn_handoff_sweeper(Arc::clone(handoff));
+    }
+    let input_cache = (config.worker.input_cache_mb > 0).then(|| {
+        let cache = InputCache::new(
+            config.worker.input_cache_mb * 1024 * 1024,
+            config.worker.input_cache_dir.as_deref(),
+            config.worker.input_cache_disk_mb * 1024 * 1024,
+        );
+        Arc::new(cache.expect("WORKER: Failed to create input cache directory"))
+    });
+
+    let capabilities = WorkerCapabilities::new(&[], config.worker.gpu);
+    spawn_heartbeat(
+        Arc::clone(&db_client),
+        worker_id.clone(),
+        capabilities.clone(),
+        Duration::from_secs(config.capabilities.heartbeat_secs.max(1)),
+    );
+
+    let app_state = Arc::new(WorkerAppState {
+        producer: Arc::new(producer),
+        events: BatchEventPublisher::from_config(&config),
+        database: db_client,
+        storage: storage::from_config(&config.storage, &config.s3.bucket).await,
+        handoff,
+        input_cache,
+        simulation: config.simulation.enabled.then(|| config.simulation.clone()),
+        hooks: hooks::register_hooks(&config, allowlist),
+        result_cache: config.worker.result_cache,
+        max_processing: config.worker.max_processing_secs.map(Duration::from_secs),
+        decode_limits: image_ops::DecodeLimits::from(&config.worker),
+        previews: config.previews.clone(),
+        quality_sample_rate: config.quality_drift.sample_rate,
+        capabilities,
+        gpu_operations: config.capabilities.gpu_operations.clone(),
+        fairness: config.fairness.clone(),
+        worker_id,
+        analytics_release: config.analytics.enabled.then(|| {
+            config
+                .analytics
+                .release
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RELEASE.to_string())
+        }),
+    });
+    if app_state.simulation.is_some() {
+        info!("WORKER: Simulation mode, images are not processed");
+    }
+    if !app_state.hooks.is_empty() {
+        info!(hooks = app_state.hooks.len(), "WORKER: Running hooks");
+    }
+
+    metrics::spawn_server(config.metrics.address.clone());
+
+    // Docker sends SIGTERM on stop, the image being processed is finished before exiting
+    consumer.shutdown_on_signal();
+
+    consumer
+        .start_consuming_envelopes({
+            let app_state = Arc::clone(&app_state);
+            // The envelope tells how long the task waited on its topic
+            move |envelope: MessageEnvelope<ImageTask>| {
+                handle_task(envelope.payload, envelope.produced_at, Arc::clone(&app_state))
+            }
+        })
+        .await;
+
+    info!("WORKER: Shut down");
+}
@@ -0,0 +1,121 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use common::{config::WorkerConfig, metrics, profiles::MemoryThresholds};
+use queue::concurrency::ConcurrencyLimit;
+
+// ============================================================================
+// MEMORY GOVERNOR
+// Decoded images take far more memory than their files, so a fixed number of
+// concurrent tasks either leaves memory unused on small images or runs out of
+// it on large ones. The governor watches the worker's resident memory and
+// moves the concurrency limit between the configured bounds to stay under the
+// ceiling, pausing consumption entirely while above it. The scheduler lowers
+// the thresholds ahead of expensive operations, see `common::profiles`.
+// ============================================================================
+
+/// The thresholds the governor currently works with, replaced by the `set_memory_thresholds`
+/// command
+#[derive(Default)]
+pub struct GovernorThresholds {
+    thresholds: RwLock<MemoryThresholds>,
+}
+
+impl GovernorThresholds {
+    pub(crate) fn get(&self) -> MemoryThresholds {
+        *self.thresholds.read().expect("Thresholds lock poisoned")
+    }
+
+    pub(crate) fn replace(&self, thresholds: MemoryThresholds) {
+        *self.thresholds.write().expect("Thresholds lock poisoned") = thresholds;
+    }
+}
+
+/// Resident memory of this process, from /proc on Linux
+fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    Some(kib * 1024)
+}
+
+/// The limit to use next, given the current one and the share of the ceiling in use
+fn next_limit(
+    current: usize,
+    usage: f64,
+    thresholds: &MemoryThresholds,
+    config: &WorkerConfig,
+) -> usize {
+    let min = config.min_concurrency.max(1);
+    let max = config.max_concurrency.max(min);
+
+    let next = if usage >= thresholds.pause_at {
+        current / 2
+    } else if usage >= thresholds.shrink_at {
+        current.saturating_sub(1)
+    } else if usage < thresholds.grow_below {
+        current + 1
+    } else {
+        current
+    };
+
+    next.clamp(min, max)
+}
+
+/// Adjusts the limit to the resident memory every check interval, for as long as the worker
+/// runs. Does nothing without a memory ceiling.
+pub fn spawn_memory_governor(
+    limit: Arc<ConcurrencyLimit>,
+    thresholds: Arc<GovernorThresholds>,
+    config: WorkerConfig,
+) {
+    metrics::WORKER_CONCURRENCY_LIMIT.set(limit.limit() as i64);
+
+    let Some(ceiling_mb) = config.memory_ceiling_mb else {
+        return;
+    };
+    if resident_bytes().is_none() {
+        tracing::warn!("WORKER: Resident memory is unavailable, concurrency stays at its maximum");
+        return;
+    }
+    let ceiling = ceiling_mb * 1024 * 1024;
+    let interval = Duration::from_millis(config.memory_check_interval_ms.max(100));
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(rss) = resident_bytes() else {
+                continue;
+            };
+            metrics::PROCESS_RSS_BYTES.set(rss as i64);
+            let usage = rss as f64 / ceiling as f64;
+            let thresholds = thresholds.get();
+
+            let current = limit.limit();
+            let next = next_limit(current, usage, &thresholds, &config);
+            if next != current {
+                tracing::info!(
+                    rss_mb = rss / (1024 * 1024),
+                    ceiling_mb,
+                    from = current,
+                    to = next,
+                    "WORKER: Changing concurrency"
+                );
+                limit.set_limit(next);
+                metrics::WORKER_CONCURRENCY_LIMIT.set(next as i64);
+            }
+
+            // Tasks being handled still finish, only new ones wait until memory is released
+            if usage >= thresholds.pause_at && !limit.is_paused() {
+                tracing::warn!(usage, "WORKER: Above the pause threshold, pausing consumption");
+                limit.pause();
+            } else if usage < thresholds.resume_below && limit.is_paused() {
+                tracing::info!(usage, "WORKER: Below the resume threshold, resuming consumption");
+                limit.resume();
+            }
+        }
+    });
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use common::{
+    ImageTask, operations_name,
+    config::{LatencyProfile, SimulationConfig},
+};
+use rand::Rng;
+use rand_distr::{Distribution, LogNormal};
+use storage::PutOptions;
+
+use crate::utils::WorkerAppState;
+
+// ============================================================================
+// SIMULATION MODE
+// Stands in for `process_image` during load tests. The input image is never
+// downloaded or decoded, the task only sleeps for a latency drawn from the
+// operation's distribution, so the coordinator, Kafka and Mongo see a
+// realistic stream of completions at a fraction of the cost.
+// ============================================================================
+
+/// z-score of the 99th percentile of a standard normal distribution
+const P99_Z: f64 = 2.326;
+
+/// Simulates processing an image task, failing it on purpose at the configured rate
+pub(crate) async fn simulate_image(
+    task: &ImageTask,
+    state: &WorkerAppState,
+    simulation: &SimulationConfig,
+) -> Result<(), String> {
+    // A fused task takes as long as its operations one after the other
+    let operation = operations_name(task.operations());
+    let latency = task
+        .operations()
+        .map(|op| sample_latency(simulation.latency_for(op.name())))
+        .sum();
+    tokio::time::sleep(latency).await;
+
+    if rand::thread_rng().gen_bool(simulation.failure_rate.clamp(0.0, 1.0)) {
+        return Err(format!(
+            "Simulated failure of {} after {:?}",
+            operation, latency
+        ));
+    }
+
+    if simulation.write_placeholders {
+        let output_key = task
+            .output_key
+            .clone()
+            .ok_or(format!("Image task for {} has no output key", task.s3_key))?;
+
+        let options = PutOptions {
+            metadata: [("simulated".to_string(), "true".to_string())].into(),
+            tags: task.object_tags.clone(),
+            ..Default::default()
+        };
+        state
+            .storage
+            .put_object(&output_key, Default::default(), &options)
+            .await
+            .map_err(|e| format!("Failed to upload placeholder {}: {}", output_key, e))?;
+    }
+
+    Ok(())
+}
+
+/// Draws a latency from the log-normal distribution matching the profile
+fn sample_latency(profile: LatencyProfile) -> Duration {
+    let median = profile.median_ms.max(0.001);
+    let p99 = profile.p99_ms.max(median);
+
+    let mu = median.ln();
+    let sigma = (p99.ln() - mu) / P99_Z;
+
+    let millis = match LogNormal::new(mu, sigma) {
+        Ok(distribution) => distribution.sample(&mut rand::thread_rng()),
+        Err(_) => median,
+    };
+
+    Duration::from_secs_f64(millis / 1000.0)
+}
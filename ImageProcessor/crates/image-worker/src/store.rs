@@ -0,0 +1,282 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use common::drift::ImageQualitySample;
+use common::error::{ProcessorError, S3ErrorKind};
+use common::failures::FailureKind;
+use common::metadata::ImageMetadata;
+use common::provenance::ImageProvenance;
+use db_utils::types::{DBClient, DBImageTask, DBTaskMetrics, ImageTaskBytes, TaskStatus};
+
+// ============================================================================
+// WORKER STORE
+// What the worker reads and records around an image task: its status, what it
+// found out about the image, and the tasks waiting on it. `DBClient` is the
+// store of the running service, tests hand the handler one kept in memory
+// instead.
+// ============================================================================
+
+#[async_trait]
+pub trait WorkerStore: Send + Sync {
+    async fn is_batch_cancelled(&self, batch_id: &uuid::Uuid) -> Result<bool, ProcessorError>;
+
+    /// The image tasks of the batch started after `started_after` that are still running
+    async fn count_running_image_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+        started_after: DateTime<Utc>,
+    ) -> Result<u64, ProcessorError>;
+
+    /// Returns `false` if the task wasn't deferred, see `db_utils::fairness`
+    async fn defer_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+        until: DateTime<Utc>,
+    ) -> Result<bool, ProcessorError>;
+
+    async fn get_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    async fn update_image_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+    ) -> Result<(), ProcessorError>;
+
+    /// `None` if the task already finished
+    async fn mark_image_task_running(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    /// The finishing marks return `None` if an earlier delivery finished the task already
+    async fn mark_image_task_succeeded(
+        &self,
+        task_id: &uuid::Uuid,
+        bytes: Option<ImageTaskBytes>,
+        cache_hit: Option<bool>,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    async fn mark_image_task_filtered(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    async fn mark_image_task_failed(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+        backtrace: Option<&str>,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    async fn record_image_failure(&self, task: &DBImageTask) -> Result<(), ProcessorError>;
+
+    async fn raise_storage_alert(
+        &self,
+        batch_id: &uuid::Uuid,
+        kind: S3ErrorKind,
+        key: &str,
+        message: &str,
+    ) -> Result<bool, ProcessorError>;
+
+    async fn record_task_metrics(&self, metrics: &DBTaskMetrics) -> Result<(), ProcessorError>;
+
+    async fn set_image_task_output_key(
+        &self,
+        task_id: &uuid::Uuid,
+        output_key: &str,
+    ) -> Result<(), ProcessorError>;
+
+    async fn set_image_task_provenance(
+        &self,
+        task_id: &uuid::Uuid,
+        provenance: &ImageProvenance,
+    ) -> Result<(), ProcessorError>;
+
+    async fn set_image_task_metadata(
+        &self,
+        task_id: &uuid::Uuid,
+        metadata: &ImageMetadata,
+    ) -> Result<(), ProcessorError>;
+
+    async fn set_image_task_quality(
+        &self,
+        task_id: &uuid::Uuid,
+        sample: &ImageQualitySample,
+    ) -> Result<(), ProcessorError>;
+
+    /// The Waiting tasks of the next stage that read the task's output
+    async fn get_waiting_dependents(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError>;
+
+    /// Moves a Waiting task to Ready, `None` if it wasn't Waiting anymore
+    async fn claim_waiting_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError>;
+
+    /// Hands a claimed task that couldn't be published back to Waiting
+    async fn unclaim_image_task(&self, task_id: &uuid::Uuid) -> Result<(), ProcessorError>;
+
+    /// Filters out the tasks waiting on the task, returns how many there were
+    async fn filter_waiting_dependents(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<u64, ProcessorError>;
+}
+
+#[async_trait]
+impl WorkerStore for DBClient {
+    async fn is_batch_cancelled(&self, batch_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        DBClient::is_batch_cancelled(self, batch_id).await
+    }
+
+    async fn count_running_image_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+        started_after: DateTime<Utc>,
+    ) -> Result<u64, ProcessorError> {
+        DBClient::count_running_image_tasks(self, batch_id, started_after).await
+    }
+
+    async fn defer_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+        until: DateTime<Utc>,
+    ) -> Result<bool, ProcessorError> {
+        DBClient::defer_image_task(self, task_id, until).await
+    }
+
+    async fn get_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::get_image_task(self, task_id).await
+    }
+
+    async fn update_image_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+    ) -> Result<(), ProcessorError> {
+        DBClient::update_image_task_status(self, task_id, status).await
+    }
+
+    async fn mark_image_task_running(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::mark_image_task_running(self, task_id).await
+    }
+
+    async fn mark_image_task_succeeded(
+        &self,
+        task_id: &uuid::Uuid,
+        bytes: Option<ImageTaskBytes>,
+        cache_hit: Option<bool>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::mark_image_task_succeeded(self, task_id, bytes, cache_hit).await
+    }
+
+    async fn mark_image_task_filtered(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::mark_image_task_filtered(self, task_id, reason).await
+    }
+
+    async fn mark_image_task_failed(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+        backtrace: Option<&str>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::mark_image_task_failed(self, task_id, error, kind, storage_error, backtrace).await
+    }
+
+    async fn record_image_failure(&self, task: &DBImageTask) -> Result<(), ProcessorError> {
+        DBClient::record_image_failure(self, task).await
+    }
+
+    async fn raise_storage_alert(
+        &self,
+        batch_id: &uuid::Uuid,
+        kind: S3ErrorKind,
+        key: &str,
+        message: &str,
+    ) -> Result<bool, ProcessorError> {
+        DBClient::raise_storage_alert(self, batch_id, kind, key, message).await
+    }
+
+    async fn record_task_metrics(&self, metrics: &DBTaskMetrics) -> Result<(), ProcessorError> {
+        DBClient::record_task_metrics(self, metrics).await
+    }
+
+    async fn set_image_task_output_key(
+        &self,
+        task_id: &uuid::Uuid,
+        output_key: &str,
+    ) -> Result<(), ProcessorError> {
+        DBClient::set_image_task_output_key(self, task_id, output_key).await
+    }
+
+    async fn set_image_task_provenance(
+        &self,
+        task_id: &uuid::Uuid,
+        provenance: &ImageProvenance,
+    ) -> Result<(), ProcessorError> {
+        DBClient::set_image_task_provenance(self, task_id, provenance).await
+    }
+
+    async fn set_image_task_metadata(
+        &self,
+        task_id: &uuid::Uuid,
+        metadata: &ImageMetadata,
+    ) -> Result<(), ProcessorError> {
+        DBClient::set_image_task_metadata(self, task_id, metadata).await
+    }
+
+    async fn set_image_task_quality(
+        &self,
+        task_id: &uuid::Uuid,
+        sample: &ImageQualitySample,
+    ) -> Result<(), ProcessorError> {
+        DBClient::set_image_task_quality(self, task_id, sample).await
+    }
+
+    async fn get_waiting_dependents(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        DBClient::get_waiting_dependents(self, task_id).await
+    }
+
+    async fn claim_waiting_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DBClient::claim_waiting_image_task(self, task_id).await
+    }
+
+    async fn unclaim_image_task(&self, task_id: &uuid::Uuid) -> Result<(), ProcessorError> {
+        DBClient::unclaim_image_task(self, task_id).await
+    }
+
+    async fn filter_waiting_dependents(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<u64, ProcessorError> {
+        DBClient::filter_waiting_dependents(self, task_id, reason).await
+    }
+}
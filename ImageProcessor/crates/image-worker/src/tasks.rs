@@ -0,0 +1,845 @@
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use common::{ImageOperation, ImageTask, operations_name};
+use image::{DynamicImage, ImageFormat};
+use common::analytics::TaskTimings;
+use common::drift::is_sampled;
+use common::error::{ProcessorError, S3ErrorKind};
+use common::events::BatchEventKind;
+use common::failures::{ClassifiedError, FailureKind};
+use common::faults;
+use common::metadata::ImageMetadata;
+use common::metrics;
+use common::naming::with_hash_suffix;
+use common::previews::preview_key;
+use common::provenance::{self, ImageProvenance};
+use db_utils::types::{DBTaskMetrics, ImageTaskBytes, TaskStatus};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use storage::PutOptions;
+use tracing::{debug, error, info, warn};
+
+use crate::handoff::LocalHandoff;
+use crate::utils::WorkerAppState;
+use crate::{cache, isolation, simulation};
+
+// ============================================================================
+// IMAGE TASKS
+// Runs the operations of an image task on its input and records the outcome,
+// publishing the tasks of the next stage that were waiting on it. What it reads
+// and records goes through `WorkerStore`, see `store`.
+// ============================================================================
+
+// Hex digits of the SHA-256 of the output kept in hashed names
+const HASH_SUFFIX_LEN: usize = 8;
+
+/// The input of a stage, either the previous stage's pixels or the encoded object
+enum Input {
+    Decoded((DynamicImage, ImageFormat)),
+    Encoded(Bytes),
+}
+
+/// What an image task did, recorded on the task once it succeeded
+struct ProcessedImage {
+    bytes: ImageTaskBytes,
+    cache_hit: Option<bool>, // None when the result cache wasn't consulted
+    timings: TaskTimings,    // Of the download, processing and upload
+}
+
+/// The result of an image task unless a quality gate left its image out, see
+/// `image_ops::quality`
+enum Gated<T> {
+    Passed(T),
+    Filtered(String), // Why the image was left out, nothing was written for it
+}
+
+impl<T> Gated<T> {
+    fn map<U>(self, f: impl FnOnce(T) -> U) -> Gated<U> {
+        match self {
+            Gated::Passed(value) => Gated::Passed(f(value)),
+            Gated::Filtered(reason) => Gated::Filtered(reason),
+        }
+    }
+}
+
+/// Why an image task failed, with the storage error behind it if storage failed it
+pub(crate) struct TaskFailure {
+    pub(crate) message: String,
+    pub(crate) kind: FailureKind,
+    pub(crate) storage_error: Option<S3ErrorKind>,
+    pub(crate) key: Option<String>,       // The object storage failed on
+    pub(crate) backtrace: Option<String>, // Where an operation panicked, see `isolation`
+}
+
+impl TaskFailure {
+    pub(crate) fn new(kind: FailureKind, message: impl ToString) -> Self {
+        Self {
+            message: message.to_string(),
+            kind,
+            storage_error: None,
+            key: None,
+            backtrace: None,
+        }
+    }
+
+    /// A failed storage call on the given key, a missing object counts as `NoSuchKey`
+    fn storage(error: ProcessorError, key: &str) -> Self {
+        let storage_error = match &error {
+            ProcessorError::NotFound(_) => Some(S3ErrorKind::NoSuchKey),
+            error => error.s3_error_kind(),
+        };
+        Self {
+            message: error.to_string(),
+            kind: FailureKind::StorageError,
+            storage_error,
+            key: Some(key.to_string()),
+            backtrace: None,
+        }
+    }
+}
+
+impl From<String> for TaskFailure {
+    fn from(message: String) -> Self {
+        Self::new(FailureKind::Other, message)
+    }
+}
+
+impl From<ClassifiedError> for TaskFailure {
+    fn from(error: ClassifiedError) -> Self {
+        Self::new(error.kind, error.message)
+    }
+}
+
+/// Reads the pixels the previous stage left on this worker, `None` if there are none or they
+/// can't be read
+async fn read_local_input(
+    handoff: &LocalHandoff,
+    key: &str,
+) -> Option<(DynamicImage, ImageFormat)> {
+    let decoded = match handoff.take(key).await {
+        Some(raw) => tokio::task::spawn_blocking(move || image_ops::raw::decode_raw(&raw))
+            .await
+            .ok()
+            .and_then(Result::ok),
+        None => None,
+    };
+
+    let result = if decoded.is_some() { "hit" } else { "miss" };
+    metrics::LOCAL_HANDOFFS.with_label_values(&[result]).inc();
+    decoded
+}
+
+/// Downloads the task's input image, applies its operation and uploads the result to the
+/// task's output key (the input of the next stage). Returns the bytes downloaded and uploaded,
+/// or why a quality gate left the image out before anything was uploaded.
+async fn process_image(
+    task: &ImageTask,
+    state: &WorkerAppState,
+) -> Result<Gated<ProcessedImage>, TaskFailure> {
+    let mut output_key = task
+        .output_key
+        .clone()
+        .ok_or(format!("Image task for {} has no output key", task.s3_key))?;
+
+    // Only stages pinned to this worker can expect the previous stage to have run here. Pixels
+    // handed over locally leave the EXIF block behind, so jobs keeping it go through storage.
+    let handoff = state
+        .handoff
+        .as_ref()
+        .filter(|_| task.affinity_key.is_some() && !task.metadata_policy.keeps_metadata());
+    let local_input = match handoff {
+        Some(handoff) => read_local_input(handoff, &task.s3_key).await,
+        None => None,
+    };
+
+    let mut timings = TaskTimings::default();
+    let input = match local_input {
+        Some(decoded) => Input::Decoded(decoded),
+        None => {
+            let started = Instant::now();
+            let download_timer = metrics::S3_DOWNLOAD_SECONDS.start_timer();
+            let storage = state.storage.as_ref();
+            let bytes = match &state.input_cache {
+                Some(cache) => storage::retry_throttled(|| cache.read(storage, &task.s3_key)).await,
+                None => storage::retry_throttled(|| storage.get_object(&task.s3_key)).await,
+            }
+            .map_err(|e| TaskFailure::storage(e, &task.s3_key))?;
+            download_timer.observe_duration();
+            timings.download_ms = Some(elapsed_ms(started));
+            Input::Encoded(bytes)
+        }
+    };
+    let bytes_read = match &input {
+        Input::Encoded(bytes) => Some(bytes.len() as u64),
+        Input::Decoded(_) => None,
+    };
+    let metadata = match &input {
+        Input::Encoded(bytes) => image_ops::metadata::read_metadata(bytes),
+        Input::Decoded((img, _)) => Some(ImageMetadata {
+            width: img.width(),
+            height: img.height(),
+            ..Default::default()
+        }),
+    };
+    let encoded_input = match &input {
+        Input::Encoded(bytes) => Some(bytes.as_ref()),
+        Input::Decoded(_) => None,
+    };
+    state.hooks.pre_decode(task, encoded_input).await?;
+    let input_hash = encoded_input.map(provenance::sha256_hex);
+    // Kept for the SSIM of the sampled outputs, see `common::drift`
+    let sampled = task
+        .task_id
+        .is_some_and(|task_id| is_sampled(&task_id, state.quality_sample_rate));
+    let sampled_input = match (&input, sampled) {
+        (Input::Encoded(bytes), true) => Some(bytes.clone()),
+        _ => None,
+    };
+
+    // Pixels handed over locally have no encoded input to hash
+    let cache_key = match encoded_input {
+        Some(encoded) if state.result_cache => Some(cache::cache_key(task, encoded)?),
+        _ => None,
+    };
+    let cached = match &cache_key {
+        Some(cache_key) => cache::lookup(state.storage.as_ref(), cache_key, task.cache_scope).await,
+        None => None,
+    };
+    let cache_hit = cache_key.as_ref().map(|_| cached.is_some());
+
+    let operations: Vec<ImageOperation> = task.operations().cloned().collect();
+    let operation = operations_name(&operations);
+    let output = match cached {
+        Some(output) => output,
+        None => {
+            let started = Instant::now();
+            let (timeout, limits) = (state.max_processing, state.decode_limits);
+            let output = match run_operations(
+                task,
+                input,
+                operations,
+                handoff,
+                &output_key,
+                timeout,
+                limits,
+            )
+            .await?
+            {
+                Gated::Passed(output) => output,
+                Gated::Filtered(reason) => return Ok(Gated::Filtered(reason)),
+            };
+            timings.process_ms = Some(elapsed_ms(started));
+            if let Some(cache_key) = &cache_key {
+                cache::store(state.storage.as_ref(), cache_key, output.clone()).await;
+            }
+            output
+        }
+    };
+
+    let provenance = image_provenance(task, state, input_hash, &output).await?;
+
+    // The hash only changes when the content does, so CDNs can cache the name forever
+    if task.hash_suffix {
+        let hash = hex::encode(Sha256::digest(&output));
+        output_key = with_hash_suffix(&output_key, &hash[..HASH_SUFFIX_LEN]);
+    }
+    state.hooks.post_encode(task, &output, &output_key).await?;
+    let bytes_written = output.len() as u64;
+
+    let started = Instant::now();
+    let upload_timer = metrics::S3_UPLOAD_SECONDS.start_timer();
+    let options = PutOptions {
+        tags: task.object_tags.clone(),
+        ..Default::default()
+    };
+    storage::retry_throttled(|| state.storage.put_object(&output_key, output.clone(), &options))
+        .await
+        .map_err(|e| TaskFailure::storage(e, &output_key))?;
+    upload_timer.observe_duration();
+    timings.upload_ms = elapsed_ms(started);
+    // The next stage of a pinned image runs here too
+    if let Some(cache) = &state.input_cache
+        && task.affinity_key.is_some()
+    {
+        cache
+            .keep(state.storage.as_ref(), &output_key, output.clone())
+            .await;
+    }
+
+    if let Some(bytes_read) = bytes_read {
+        metrics::IMAGE_BYTES_READ
+            .with_label_values(&[&operation])
+            .inc_by(bytes_read);
+    }
+    metrics::IMAGE_BYTES_WRITTEN
+        .with_label_values(&[&operation])
+        .inc_by(bytes_written);
+
+    if task.hash_suffix
+        && let Some(task_id) = task.task_id
+    {
+        state
+            .database
+            .set_image_task_output_key(&task_id, &output_key)
+            .await
+            .map_err(|e| format!("Failed to record output key {}: {}", output_key, e))?;
+    }
+
+    // The next stage extends the chain from this link, see `common::provenance`
+    if let (Some(provenance), Some(task_id)) = (&provenance, task.task_id) {
+        state
+            .database
+            .set_image_task_provenance(&task_id, provenance)
+            .await
+            .map_err(|e| format!("Failed to record the provenance of the output: {}", e))?;
+    }
+
+    // Only describes the input, a task that can't record it still wrote its output
+    if let (Some(metadata), Some(task_id)) = (&metadata, task.task_id)
+        && let Err(e) = state.database.set_image_task_metadata(&task_id, metadata).await
+    {
+        warn!(error = %e, "Failed to record the metadata of the input");
+    }
+
+    if sampled && let Some(task_id) = task.task_id {
+        measure_output(&task_id, &output_key, sampled_input, output.clone(), state).await;
+    }
+
+    if task.previews
+        && let Some(task_id) = task.task_id
+    {
+        write_preview(task, &task_id, output, state).await;
+    }
+
+    Ok(Gated::Passed(ProcessedImage {
+        bytes: ImageTaskBytes {
+            read: bytes_read,
+            written: bytes_written,
+        },
+        cache_hit,
+        timings,
+    }))
+}
+
+fn elapsed_ms(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
+}
+
+/// The link the task adds to the chain of its image, see `common::provenance`. The input of a
+/// stage handed over locally is the output the task it depends on recorded, `None` if that task
+/// recorded none.
+async fn image_provenance(
+    task: &ImageTask,
+    state: &WorkerAppState,
+    input_hash: Option<String>,
+    output: &[u8],
+) -> Result<Option<ImageProvenance>, TaskFailure> {
+    let dependency = match &task.depends_on {
+        Some(depends_on) => state
+            .database
+            .get_image_task(depends_on)
+            .await
+            .map_err(|e| format!("Failed to read the task the image depends on: {}", e))?
+            .and_then(|dependency| dependency.provenance),
+        None => None,
+    };
+    let input_hash =
+        input_hash.or_else(|| dependency.as_ref().map(|link| link.output_hash.clone()));
+    let Some(input_hash) = input_hash else {
+        return Ok(None);
+    };
+
+    let operations_hash = provenance::sha256_hex(&cache::operations_descriptor(task)?);
+    Ok(Some(ImageProvenance::new(
+        dependency.map(|link| link.chain_hash),
+        input_hash,
+        operations_hash,
+        provenance::sha256_hex(output),
+    )))
+}
+
+/// Decodes the input, applies the operations and encodes the result, handing the pixels over to
+/// the next stage when it is pinned to this worker. Fails with a `ResourceLimit` if the input is
+/// over the decode limits, with a `Timeout` once `timeout` passed, and with an `OperationPanic`
+/// if decoding or an operation panicked, see `isolation`.
+async fn run_operations(
+    task: &ImageTask,
+    input: Input,
+    operations: Vec<ImageOperation>,
+    handoff: Option<&Arc<LocalHandoff>>,
+    output_key: &str,
+    timeout: Option<Duration>,
+    limits: image_ops::DecodeLimits,
+) -> Result<Gated<Bytes>, TaskFailure> {
+    // Decoding and pixel work are CPU bound, so they run off the async runtime
+    // A fused task runs its whole chain here, only the final image is uploaded
+    let key = task.s3_key.clone();
+    let target = task.target_format();
+    let alpha_policy = task.alpha_policy;
+    let metadata_policy = task.metadata_policy;
+    let hand_off = handoff.is_some();
+    let processing_timer = metrics::IMAGE_PROCESSING_SECONDS
+        .with_label_values(&[&operations_name(&operations)])
+        .start_timer();
+    let processing = tokio::task::spawn_blocking(move || {
+        isolation::isolate(move || {
+            let (img, format, exif) = match input {
+                Input::Decoded((img, format)) => (img, format, None),
+                Input::Encoded(bytes) => {
+                    let exif = match metadata_policy.keeps_metadata() {
+                        true => image_ops::metadata::read_exif(&bytes),
+                        false => None,
+                    };
+                    let (img, format) = image_ops::decode_with_limits(&bytes, &key, &limits)?;
+                    (img, format, exif)
+                }
+            };
+            let processed = match image_ops::quality::apply_gated(img, &operations) {
+                Ok(processed) => processed,
+                Err(reason) => return Ok(Gated::Filtered(reason)),
+            };
+            let processed = image_ops::apply_alpha_policy(processed, alpha_policy);
+            // The next stage encodes in whatever format this one wrote, converted or not
+            let output_format =
+                target.map_or(format, |(target, _)| image_ops::image_format(target));
+            let raw = match hand_off {
+                true => image_ops::raw::encode_raw(&processed, output_format),
+                false => None,
+            };
+            // The format asked for can't hold the image, e.g. 16-bit pixels as AVIF
+            image_ops::encode_output(processed, format, target)
+                .map(|(encoded, _)| {
+                    let encoded = image_ops::metadata::apply_metadata_policy(
+                        encoded,
+                        exif,
+                        metadata_policy,
+                    );
+                    Gated::Passed((encoded, raw))
+                })
+                .map_err(|e| TaskFailure::new(FailureKind::UnsupportedFormat, e))
+        })
+    });
+    // The blocking task can't be cancelled, it runs to the end with nobody waiting for it
+    let joined = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, processing).await.map_err(|_| {
+            let message = format!("Operations took longer than {:?}", timeout);
+            TaskFailure::new(FailureKind::Timeout, message)
+        })?,
+        None => processing.await,
+    };
+    // Panics are caught inside the blocking task, one escaping it is still only this task's
+    let gated = joined.map_err(|e| match e.is_panic() {
+        true => TaskFailure::new(FailureKind::OperationPanic, format!("Operation panicked: {}", e)),
+        false => TaskFailure::new(FailureKind::Other, format!("Join error: {}", e)),
+    })??;
+    processing_timer.observe_duration();
+    let (output, raw_output) = match gated {
+        Gated::Passed(output) => output,
+        Gated::Filtered(reason) => return Ok(Gated::Filtered(reason)),
+    };
+
+    // Left under the unhashed key, which is what the next stage reads
+    if let (Some(handoff), Some(raw)) = (handoff, raw_output)
+        && let Err(e) = handoff.put(output_key, raw).await
+    {
+        warn!(%output_key, error = %e, "Failed to hand the output over locally");
+    }
+
+    Ok(Gated::Passed(Bytes::from(output)))
+}
+
+/// Records the quality metrics of a sampled output, see `common::drift`. An output that can't be
+/// measured is only left out of the sample.
+async fn measure_output(
+    task_id: &uuid::Uuid,
+    output_key: &str,
+    input: Option<Bytes>,
+    output: Bytes,
+    state: &WorkerAppState,
+) {
+    let (key, limits) = (output_key.to_string(), state.decode_limits);
+    let sample = tokio::task::spawn_blocking(move || {
+        isolation::isolate(|| {
+            image_ops::quality::measure(input.as_deref(), &output, &key, &limits)
+                .map_err(TaskFailure::from)
+        })
+        .map_err(|failure| failure.message)
+    })
+    .await
+    .map_err(|e| format!("Join error: {}", e))
+    .and_then(|sample| sample);
+
+    let recorded = match sample {
+        Ok(sample) => state
+            .database
+            .set_image_task_quality(task_id, &sample)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = recorded {
+        warn!(%output_key, error = %e, "Failed to measure the quality of the output");
+    }
+}
+
+/// Writes the thumbnail of a final output, see `common::previews`. A thumbnail that can't be
+/// written is only logged, the output it belongs to was written already.
+async fn write_preview(
+    task: &ImageTask,
+    task_id: &uuid::Uuid,
+    output: Bytes,
+    state: &WorkerAppState,
+) {
+    let key = preview_key(task.tenant_id.as_deref(), &task.batch_id, task_id);
+    let (size, quality, filter) = (
+        state.previews.size,
+        state.previews.quality,
+        state.previews.filter,
+    );
+    let source = task.s3_key.clone();
+    let thumbnail = tokio::task::spawn_blocking(move || {
+        isolation::isolate(|| {
+            image_ops::thumbnail(&output, &source, size, quality, filter).map_err(TaskFailure::from)
+        })
+        .map_err(|failure| failure.message)
+    })
+    .await
+    .map_err(|e| format!("Join error: {}", e))
+    .and_then(|thumbnail| thumbnail);
+    let thumbnail = match thumbnail {
+        Ok(thumbnail) => Bytes::from(thumbnail),
+        Err(e) => {
+            warn!(%key, error = %e, "Failed to create the preview of the output");
+            return;
+        }
+    };
+
+    let options = PutOptions {
+        content_type: Some("image/jpeg".to_string()),
+        tags: task.object_tags.clone(),
+        ..Default::default()
+    };
+    if let Err(e) =
+        storage::retry_throttled(|| state.storage.put_object(&key, thumbnail.clone(), &options))
+            .await
+    {
+        warn!(%key, error = %e, "Failed to write the preview of the output");
+    }
+}
+
+/// Publishes the tasks of the next stage that were waiting on the image we just processed.
+///
+/// Each is claimed first, so a redelivery of the task or a worker releasing it concurrently
+/// doesn't publish it twice. One that couldn't be published is handed back for the redelivery
+/// of this task to release.
+async fn release_dependents(
+    task_id: &uuid::Uuid,
+    state: &WorkerAppState,
+) -> Result<(), ProcessorError> {
+    let dependents = state.database.get_waiting_dependents(task_id).await?;
+
+    for dependent_id in dependents.iter().filter_map(|dependent| dependent.task_id) {
+        let Some(claimed) = state
+            .database
+            .claim_waiting_image_task(&dependent_id)
+            .await?
+        else {
+            continue;
+        };
+        if let Err(e) = state
+            .producer
+            .send_image_task(ImageTask::from(&claimed))
+            .await
+        {
+            state.database.unclaim_image_task(&dependent_id).await?;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Defers a task whose batch already runs `max_in_flight` image tasks, see `db_utils::fairness`.
+/// Returns whether it was deferred, a count or deferral that fails lets the task run.
+async fn defer_over_quota(
+    task: &ImageTask,
+    task_id: &uuid::Uuid,
+    max_in_flight: u64,
+    state: &WorkerAppState,
+) -> bool {
+    let fairness = &state.fairness;
+    let now = chrono::Utc::now();
+    let started_after = now - chrono::Duration::seconds(fairness.running_ttl_secs as i64);
+    let running = match state
+        .database
+        .count_running_image_tasks(&task.batch_id, started_after)
+        .await
+    {
+        Ok(running) => running,
+        Err(e) => {
+            warn!(error = %e, "Failed to count the running tasks of the batch");
+            return false;
+        }
+    };
+    if running < max_in_flight {
+        return false;
+    }
+
+    let until = now + chrono::Duration::seconds(fairness.defer_secs as i64);
+    match state.database.defer_image_task(task_id, until).await {
+        Ok(deferred) => {
+            if deferred {
+                metrics::IMAGE_TASKS_DEFERRED.inc();
+                info!(running, %until, "Deferred image task, its batch runs as many as it may");
+            }
+            deferred
+        }
+        Err(e) => {
+            warn!(error = %e, "Failed to defer image task");
+            false
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "image_task",
+    skip_all,
+    fields(batch_id = %task.batch_id, task_id = tracing::field::Empty)
+)]
+pub async fn handle_task(
+    task: ImageTask,
+    produced_at: DateTime<Utc>,
+    state: Arc<WorkerAppState>,
+) -> Result<(), ProcessorError> {
+    // Nothing could record the outcome, the poison pill policy decides what happens to it
+    let Some(task_id) = task.task_id else {
+        return Err(ProcessorError::Validation(format!(
+            "Received image task without an id for {}",
+            task.s3_key
+        )));
+    };
+    tracing::Span::current().record("task_id", tracing::field::display(task_id));
+    let picked_up = Instant::now();
+    let queue_wait_ms = (Utc::now() - produced_at).num_milliseconds().max(0) as u64;
+
+    // Left to a worker of the fleet that can run it, see `common::capabilities`
+    if let Some(capability) = state.capabilities.missing(&task, &state.gpu_operations) {
+        return Err(ProcessorError::Unsupported(capability));
+    }
+
+    // Tasks created by a decomposition that outlived the cancellation aren't marked yet
+    if let Ok(true) = state.database.is_batch_cancelled(&task.batch_id).await {
+        info!("Skipping image task, the batch was cancelled");
+        if let Err(e) = state
+            .database
+            .update_image_task_status(&task_id, TaskStatus::Cancelled)
+            .await
+        {
+            error!(error = %e, "Failed to mark task as cancelled");
+        }
+        return Ok(());
+    }
+
+    // The scheduler hands it out again later, the workers serve the other batches meanwhile
+    if let Some(max_in_flight) = state.fairness.max_in_flight_per_batch
+        && defer_over_quota(&task, &task_id, max_in_flight, &state).await
+    {
+        return Ok(());
+    }
+
+    match state.database.mark_image_task_running(&task_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            info!("Skipping image task, it already finished");
+            // The delivery that finished it may have failed to release the tasks waiting on it
+            let Some(finished) = state.database.get_image_task(&task_id).await? else {
+                return Ok(());
+            };
+            if matches!(finished.status, TaskStatus::Success) {
+                release_dependents(&task_id, &state).await?;
+            } else if let Some(reason) = finished.status.upstream_filter_reason() {
+                state.database.filter_waiting_dependents(&task_id, reason).await?;
+            }
+            return Ok(());
+        }
+        Err(e) => error!(error = %e, "Failed to mark task as running"),
+    }
+    // Left running as after a crash, which the scheduler's timeout of stale batches has to catch
+    if faults::drop_handler() {
+        warn!("Dropping image task, injected fault");
+        return Ok(());
+    }
+
+    debug!(s3_key = %task.s3_key, "Running image task");
+
+    // Simulated tasks move no bytes worth accounting for
+    let result = match &state.simulation {
+        Some(simulation) => simulation::simulate_image(&task, &state, simulation)
+            .await
+            .map(|_| Gated::Passed(None))
+            .map_err(TaskFailure::from),
+        None => process_image(&task, &state)
+            .await
+            .map(|gated| gated.map(Some)),
+    };
+
+    // Storage that still throttles after a few tries is left to the consumer, which hands the
+    // task over again later instead of failing it
+    if let Err(TaskFailure {
+        message,
+        storage_error: Some(kind),
+        ..
+    }) = &result
+        && kind.is_throttle()
+    {
+        warn!(error = %message, "Storage keeps throttling, retrying the image task later");
+        return Err(ProcessorError::classified_s3(message, *kind));
+    }
+
+    let measured = match &result {
+        Ok(Gated::Passed(Some(processed))) => {
+            Some((processed.timings, processed.bytes, processed.cache_hit))
+        }
+        _ => None,
+    };
+    let succeeded = matches!(result, Ok(Gated::Passed(_)));
+    let filtered = matches!(result, Ok(Gated::Filtered(_)));
+    let update = match result {
+        Ok(Gated::Passed(processed)) => {
+            info!("Processed image task");
+            let bytes = processed.as_ref().map(|processed| processed.bytes);
+            let cache_hit = processed.and_then(|processed| processed.cache_hit);
+            state
+                .database
+                .mark_image_task_succeeded(&task_id, bytes, cache_hit)
+                .await
+        }
+        Ok(Gated::Filtered(reason)) => {
+            info!(%reason, "Image filtered out by a quality gate");
+            state
+                .database
+                .mark_image_task_filtered(&task_id, &reason)
+                .await
+        }
+        Err(failure) => {
+            match (failure.storage_error, &failure.backtrace) {
+                (Some(S3ErrorKind::NoSuchKey), _) => {
+                    error!(error = %failure.message, "Input of image task is missing")
+                }
+                (_, Some(backtrace)) => {
+                    error!(error = %failure.message, %backtrace, "Image task panicked")
+                }
+                _ => error!(error = %failure.message, "Failed to process image task"),
+            }
+            metrics::TASKS_FAILED.with_label_values(&["image"]).inc();
+            state.hooks.on_failure(&task, &failure.message).await;
+            // Every other image of the batch would be denied too, its owner is alerted once
+            if failure.storage_error == Some(S3ErrorKind::AccessDenied) {
+                let key = failure.key.as_deref().unwrap_or(&task.s3_key);
+                let denied = S3ErrorKind::AccessDenied;
+                if let Err(e) = state
+                    .database
+                    .raise_storage_alert(&task.batch_id, denied, key, &failure.message)
+                    .await
+                {
+                    error!(error = %e, "Failed to raise storage alert on the batch");
+                }
+            }
+            let failed = state
+                .database
+                .mark_image_task_failed(
+                    &task_id,
+                    &failure.message,
+                    failure.kind,
+                    failure.storage_error,
+                    failure.backtrace.as_deref(),
+                )
+                .await;
+            if let Ok(Some(failed)) = &failed
+                && let Err(e) = state.database.record_image_failure(failed).await
+            {
+                error!(error = %e, "Failed to record the image in the batch's failures");
+            }
+            failed
+        }
+    };
+    // Handing the error back retries the task, which finishes it again with the same result
+    let updated = match update {
+        Ok(updated) => updated,
+        Err(e) => {
+            error!(error = %e, "Failed to update status of task");
+            return Err(e);
+        }
+    };
+    // A task finished by an earlier delivery already had its event
+    if updated.is_some() {
+        let completed = BatchEventKind::ImageCompleted {
+            image_task_id: task_id,
+            dataset_task_id: task.dataset_id,
+            succeeded,
+            filtered,
+        };
+        state
+            .events
+            .publish(task.batch_id, task.tenant_id.as_deref(), completed)
+            .await;
+    }
+    // Like the event, the timings are recorded by the delivery that finished the task
+    if updated.is_some()
+        && let Some((timings, bytes, cache_hit)) = measured
+    {
+        let timings = TaskTimings {
+            queue_wait_ms: Some(queue_wait_ms),
+            total_ms: elapsed_ms(picked_up),
+            ..timings
+        };
+        record_task_metrics(&task, task_id, timings, bytes, cache_hit, &state).await;
+    }
+
+    if succeeded && let Err(e) = release_dependents(&task_id, &state).await {
+        error!(error = %e, "Failed to release tasks depending on the task");
+        return Err(e);
+    }
+    // The later stages of the image have nothing to read, the other images carry on without it
+    if let Some(updated) = &updated
+        && let Some(reason) = updated.status.upstream_filter_reason()
+        && let Err(e) = state.database.filter_waiting_dependents(&task_id, reason).await
+    {
+        error!(error = %e, "Failed to filter out tasks depending on the task");
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Keeps the phase timings of a succeeded task for the operation analytics, see
+/// `common::analytics`. Only the report misses a task whose timings can't be recorded.
+async fn record_task_metrics(
+    task: &ImageTask,
+    task_id: uuid::Uuid,
+    timings: TaskTimings,
+    bytes: ImageTaskBytes,
+    cache_hit: Option<bool>,
+    state: &WorkerAppState,
+) {
+    let Some(release) = &state.analytics_release else {
+        return;
+    };
+    let record = DBTaskMetrics {
+        id: None,
+        image_task_id: task_id,
+        batch_id: task.batch_id,
+        tenant_id: task.tenant_id.clone(),
+        operation: operations_name(task.operations()),
+        release: release.clone(),
+        worker_id: state.worker_id.clone(),
+        cache_hit,
+        bytes_read: bytes.read,
+        bytes_written: bytes.written,
+        timings,
+        time_recorded: Utc::now(),
+    };
+    if let Err(e) = state.database.record_task_metrics(&record).await {
+        warn!(error = %e, "Failed to record the timings of the task");
+    }
+}
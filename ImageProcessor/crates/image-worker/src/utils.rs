@@ -0,0 +1,34 @@
+use common::capabilities::WorkerCapabilities;
+use common::config::{FairnessConfig, PreviewConfig, SimulationConfig};
+use image_ops::DecodeLimits;
+use queue::{ProducerClient, events::BatchEventPublisher};
+use std::sync::Arc;
+use std::time::Duration;
+use storage::StorageBackend;
+
+use crate::handoff::LocalHandoff;
+use crate::hooks::HookRegistry;
+use crate::input_cache::InputCache;
+use crate::store::WorkerStore;
+
+#[derive(Clone)]
+pub struct WorkerAppState {
+    pub producer: Arc<ProducerClient>,
+    pub events: BatchEventPublisher, // Progress of the batches, see `common::events`
+    pub database: Arc<dyn WorkerStore>,
+    pub storage: Arc<dyn StorageBackend>,
+    pub handoff: Option<Arc<LocalHandoff>>, // Set when pinned stages hand pixels over locally
+    pub input_cache: Option<Arc<InputCache>>, // Set when inputs are kept, see `input_cache`
+    pub simulation: Option<SimulationConfig>, // Set when the worker simulates instead of processing
+    pub hooks: HookRegistry,
+    pub result_cache: bool, // Outputs are cached and reused, see `cache`
+    pub max_processing: Option<Duration>, // Longer running operations fail the task
+    pub decode_limits: DecodeLimits, // Bounds on the inputs decoded, see `image_ops`
+    pub previews: PreviewConfig, // Thumbnails of the outputs of jobs that ask for them
+    pub quality_sample_rate: u32, // One in this many outputs is measured, see `drift`
+    pub capabilities: WorkerCapabilities, // Tasks needing more are held, see `capabilities`
+    pub gpu_operations: Vec<String>, // Operations that need `capabilities.gpu`
+    pub fairness: FairnessConfig, // Bound on the running tasks of a batch, see `fairness`
+    pub worker_id: String,
+    pub analytics_release: Option<String>, // `None` when timings aren't recorded
+}
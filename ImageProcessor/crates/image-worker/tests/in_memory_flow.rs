@@ -0,0 +1,738 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use common::{
+    DatasetProcessingJob, ImageTask, IntoDatasetTasks,
+    budgets::BudgetCheckpoint,
+    capabilities::WorkerCapabilities,
+    config::{Config, QueueKind},
+    dimensions::Dimensions,
+    drift::ImageQualitySample,
+    envelope::MessageEnvelope,
+    error::{ProcessorError, S3ErrorKind},
+    failures::FailureKind,
+    inspection::DatasetValidationReport,
+    lifecycle::BatchState,
+    metadata::ImageMetadata,
+    provenance::ImageProvenance,
+    reproducibility::ConfigSnapshot,
+};
+use consumers::{decompose, store::DecomposerStore, utils::ConsumerAppState};
+use db_utils::types::{DBImageTask, DBTaskMetrics, DatasetSidecar, ImageTaskBytes, TaskStatus};
+use image::{DynamicImage, ImageFormat, RgbImage};
+use image_worker::{store::WorkerStore, tasks, utils::WorkerAppState};
+use queue::{
+    ProducerClient,
+    consumer::ConsumerClient,
+    events::BatchEventPublisher,
+    memory::{MemoryQueue, MessageQueue},
+};
+use storage::{LocalStorage, LocalUrlSigner, PutOptions, StorageBackend};
+use tokio::sync::Semaphore;
+
+// ============================================================================
+// IN-MEMORY FLOW
+// A batch submitted to the in-process queue is decomposed and processed by the
+// handlers of the decomposer and the worker, without Kafka or MongoDB. Both
+// record what they do in `MemoryStore`, which keeps the records the flow needs
+// and accepts the rest.
+// ============================================================================
+
+const DATASET_TOPIC: &str = "dataset-tasks";
+const IMAGE_TOPIC: &str = "image-tasks";
+
+/// The batch, dataset task and image tasks of the flow
+#[derive(Default)]
+struct MemoryStore {
+    batches: Mutex<HashMap<uuid::Uuid, BatchState>>,
+    dataset_tasks: Mutex<HashMap<uuid::Uuid, TaskStatus>>,
+    decomposed: Mutex<HashMap<uuid::Uuid, u64>>, // Images of every decomposed dataset task
+    image_tasks: Mutex<HashMap<uuid::Uuid, DBImageTask>>,
+    mappings: Mutex<HashMap<(uuid::Uuid, String), uuid::Uuid>>,
+}
+
+impl MemoryStore {
+    fn image_task(&self, task_id: &uuid::Uuid) -> Option<DBImageTask> {
+        self.image_tasks.lock().unwrap().get(task_id).cloned()
+    }
+
+    fn image_tasks(&self) -> Vec<DBImageTask> {
+        self.image_tasks.lock().unwrap().values().cloned().collect()
+    }
+
+    fn add_image_task(&self, task: &ImageTask, status: TaskStatus) -> bool {
+        let Some(task_id) = task.task_id else {
+            return false;
+        };
+        let mut tasks = self.image_tasks.lock().unwrap();
+        if tasks.contains_key(&task_id) {
+            return false;
+        }
+        let mut record = DBImageTask::from(task);
+        record.status = status;
+        tasks.insert(task_id, record);
+        true
+    }
+
+    /// Applies `update` to a task that isn't final yet, `None` for a task that is
+    fn update_unfinished(
+        &self,
+        task_id: &uuid::Uuid,
+        update: impl FnOnce(&mut DBImageTask),
+    ) -> Option<DBImageTask> {
+        let mut tasks = self.image_tasks.lock().unwrap();
+        let task = tasks
+            .get_mut(task_id)
+            .filter(|task| !task.status.is_final())?;
+        update(task);
+        Some(task.clone())
+    }
+
+    fn finish(
+        &self,
+        task_id: &uuid::Uuid,
+        update: impl FnOnce(&mut DBImageTask),
+    ) -> Option<DBImageTask> {
+        self.update_unfinished(task_id, |task| {
+            update(task);
+            task.time_completed = Some(Utc::now());
+        })
+    }
+}
+
+#[async_trait]
+impl DecomposerStore for MemoryStore {
+    async fn has_processed_message(&self, _: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        Ok(false)
+    }
+
+    async fn record_processed_message(
+        &self,
+        _: &uuid::Uuid,
+        _: &str,
+    ) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn add_config_snapshot(
+        &self,
+        _: &uuid::Uuid,
+        _: &ConfigSnapshot,
+    ) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn is_batch_cancelled(&self, batch_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        let batches = self.batches.lock().unwrap();
+        Ok(batches.get(batch_id) == Some(&BatchState::Cancelled))
+    }
+
+    async fn batch_state(
+        &self,
+        batch_id: &uuid::Uuid,
+    ) -> Result<Option<BatchState>, ProcessorError> {
+        Ok(self.batches.lock().unwrap().get(batch_id).cloned())
+    }
+
+    async fn transition_batch(
+        &self,
+        batch_id: &uuid::Uuid,
+        next: BatchState,
+    ) -> Result<(), ProcessorError> {
+        self.batches.lock().unwrap().insert(*batch_id, next);
+        Ok(())
+    }
+
+    async fn dataset_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<TaskStatus>, ProcessorError> {
+        Ok(self.dataset_tasks.lock().unwrap().get(task_id).cloned())
+    }
+
+    async fn transition_dataset_task(
+        &self,
+        task_id: &uuid::Uuid,
+        from: TaskStatus,
+        to: TaskStatus,
+    ) -> Result<bool, ProcessorError> {
+        let mut tasks = self.dataset_tasks.lock().unwrap();
+        match tasks.get_mut(task_id) {
+            Some(status) if std::mem::discriminant(status) == std::mem::discriminant(&from) => {
+                *status = to;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn renew_dataset_task_lease(
+        &self,
+        task_id: &uuid::Uuid,
+        _: Duration,
+    ) -> Result<bool, ProcessorError> {
+        let tasks = self.dataset_tasks.lock().unwrap();
+        Ok(matches!(tasks.get(task_id), Some(TaskStatus::Running)))
+    }
+
+    async fn mark_dataset_decomposed(
+        &self,
+        task_id: &uuid::Uuid,
+        image_count: u64,
+    ) -> Result<(), ProcessorError> {
+        self.decomposed
+            .lock()
+            .unwrap()
+            .insert(*task_id, image_count);
+        Ok(())
+    }
+
+    async fn record_budget_checkpoint(
+        &self,
+        _: &uuid::Uuid,
+        _: &BudgetCheckpoint,
+    ) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn record_dataset_validation(
+        &self,
+        _: &uuid::Uuid,
+        _: &DatasetValidationReport,
+    ) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn add_dataset_sidecar(
+        &self,
+        _: &uuid::Uuid,
+        _: &DatasetSidecar,
+    ) -> Result<bool, ProcessorError> {
+        Ok(true)
+    }
+
+    async fn raise_storage_alert(
+        &self,
+        _: &uuid::Uuid,
+        _: S3ErrorKind,
+        _: &str,
+        _: &str,
+    ) -> Result<bool, ProcessorError> {
+        Ok(true)
+    }
+
+    async fn query_mappings(
+        &self,
+        dataset_task_id: &uuid::Uuid,
+        image_filename: &str,
+    ) -> Option<uuid::Uuid> {
+        let key = (*dataset_task_id, image_filename.to_string());
+        self.mappings.lock().unwrap().get(&key).copied()
+    }
+
+    async fn create_mapping(
+        &self,
+        dataset_task_id: uuid::Uuid,
+        image_filename: &str,
+        _: &str,
+        image_task_id: uuid::Uuid,
+    ) -> Result<(), ProcessorError> {
+        let key = (dataset_task_id, image_filename.to_string());
+        self.mappings.lock().unwrap().insert(key, image_task_id);
+        Ok(())
+    }
+
+    async fn has_upload_marker(&self, _: &uuid::Uuid, _: &str) -> Result<bool, ProcessorError> {
+        Ok(false)
+    }
+
+    async fn add_upload_marker(
+        &self,
+        _: &uuid::Uuid,
+        _: &str,
+        _: u64,
+    ) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn get_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        Ok(self.image_task(task_id))
+    }
+
+    async fn db_add_task_idempotent(&self, task: &ImageTask) -> Result<bool, ProcessorError> {
+        Ok(self.add_image_task(task, TaskStatus::Waiting))
+    }
+
+    async fn add_task_with_outbox(&self, task: &ImageTask) -> Result<bool, ProcessorError> {
+        Ok(self.add_image_task(task, TaskStatus::Ready))
+    }
+
+    async fn enqueue_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<ImageTask>, ProcessorError> {
+        let mut tasks = self.image_tasks.lock().unwrap();
+        match tasks.get_mut(task_id) {
+            Some(task) if matches!(task.status, TaskStatus::Waiting) => {
+                task.status = TaskStatus::Ready;
+                Ok(Some(ImageTask::from(&*task)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn mark_outbox_sent(&self, _: &uuid::Uuid) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn image_task_succeeded(&self, task_id: &uuid::Uuid) -> bool {
+        self.image_task(task_id)
+            .is_some_and(|task| matches!(task.status, TaskStatus::Success))
+    }
+
+    async fn update_image_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+    ) -> Result<(), ProcessorError> {
+        if let Some(task) = self.image_tasks.lock().unwrap().get_mut(task_id) {
+            task.status = status;
+        }
+        Ok(())
+    }
+
+    async fn mark_image_task_failed_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        Ok(self.finish(task_id, |task| {
+            task.status = TaskStatus::Failure;
+            task.error = Some(error.to_string());
+            task.failure_kind = Some(kind);
+            task.storage_error = storage_error;
+        }))
+    }
+
+    async fn mark_image_task_filtered_before_start(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        Ok(self.finish(task_id, |task| {
+            task.status = TaskStatus::Filtered;
+            task.filter_reason = Some(reason.to_string());
+        }))
+    }
+
+    async fn record_image_failure(&self, _: &DBImageTask) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl WorkerStore for MemoryStore {
+    async fn is_batch_cancelled(&self, batch_id: &uuid::Uuid) -> Result<bool, ProcessorError> {
+        DecomposerStore::is_batch_cancelled(self, batch_id).await
+    }
+
+    async fn count_running_image_tasks(
+        &self,
+        batch_id: &uuid::Uuid,
+        _: DateTime<Utc>,
+    ) -> Result<u64, ProcessorError> {
+        let tasks = self.image_tasks.lock().unwrap();
+        let running = tasks.values().filter(|task| {
+            task.batch_id == *batch_id && matches!(task.status, TaskStatus::Running)
+        });
+        Ok(running.count() as u64)
+    }
+
+    async fn defer_image_task(
+        &self,
+        _: &uuid::Uuid,
+        _: DateTime<Utc>,
+    ) -> Result<bool, ProcessorError> {
+        Ok(false)
+    }
+
+    async fn get_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        Ok(self.image_task(task_id))
+    }
+
+    async fn update_image_task_status(
+        &self,
+        task_id: &uuid::Uuid,
+        status: TaskStatus,
+    ) -> Result<(), ProcessorError> {
+        DecomposerStore::update_image_task_status(self, task_id, status).await
+    }
+
+    async fn mark_image_task_running(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        Ok(self.update_unfinished(task_id, |task| {
+            task.status = TaskStatus::Running;
+            task.time_started = Some(Utc::now());
+        }))
+    }
+
+    async fn mark_image_task_succeeded(
+        &self,
+        task_id: &uuid::Uuid,
+        bytes: Option<ImageTaskBytes>,
+        cache_hit: Option<bool>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        Ok(self.finish(task_id, |task| {
+            task.status = TaskStatus::Success;
+            task.bytes_read = bytes.and_then(|bytes| bytes.read);
+            task.bytes_written = bytes.map(|bytes| bytes.written);
+            task.cache_hit = cache_hit;
+        }))
+    }
+
+    async fn mark_image_task_filtered(
+        &self,
+        task_id: &uuid::Uuid,
+        reason: &str,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DecomposerStore::mark_image_task_filtered_before_start(self, task_id, reason).await
+    }
+
+    async fn mark_image_task_failed(
+        &self,
+        task_id: &uuid::Uuid,
+        error: &str,
+        kind: FailureKind,
+        storage_error: Option<S3ErrorKind>,
+        _: Option<&str>,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        DecomposerStore::mark_image_task_failed_before_start(
+            self,
+            task_id,
+            error,
+            kind,
+            storage_error,
+        )
+        .await
+    }
+
+    async fn record_image_failure(&self, _: &DBImageTask) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn raise_storage_alert(
+        &self,
+        _: &uuid::Uuid,
+        _: S3ErrorKind,
+        _: &str,
+        _: &str,
+    ) -> Result<bool, ProcessorError> {
+        Ok(true)
+    }
+
+    async fn record_task_metrics(&self, _: &DBTaskMetrics) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn set_image_task_output_key(
+        &self,
+        task_id: &uuid::Uuid,
+        output_key: &str,
+    ) -> Result<(), ProcessorError> {
+        if let Some(task) = self.image_tasks.lock().unwrap().get_mut(task_id) {
+            task.output_key = Some(output_key.to_string());
+        }
+        Ok(())
+    }
+
+    async fn set_image_task_provenance(
+        &self,
+        task_id: &uuid::Uuid,
+        provenance: &ImageProvenance,
+    ) -> Result<(), ProcessorError> {
+        if let Some(task) = self.image_tasks.lock().unwrap().get_mut(task_id) {
+            task.provenance = Some(provenance.clone());
+        }
+        Ok(())
+    }
+
+    async fn set_image_task_metadata(
+        &self,
+        task_id: &uuid::Uuid,
+        metadata: &ImageMetadata,
+    ) -> Result<(), ProcessorError> {
+        if let Some(task) = self.image_tasks.lock().unwrap().get_mut(task_id) {
+            task.metadata = Some(metadata.clone());
+        }
+        Ok(())
+    }
+
+    async fn set_image_task_quality(
+        &self,
+        _: &uuid::Uuid,
+        _: &ImageQualitySample,
+    ) -> Result<(), ProcessorError> {
+        Ok(())
+    }
+
+    async fn get_waiting_dependents(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Vec<DBImageTask>, ProcessorError> {
+        let tasks = self.image_tasks.lock().unwrap();
+        let waiting = tasks.values().filter(|task| {
+            task.depends_on == Some(*task_id) && matches!(task.status, TaskStatus::Waiting)
+        });
+        Ok(waiting.cloned().collect())
+    }
+
+    async fn claim_waiting_image_task(
+        &self,
+        task_id: &uuid::Uuid,
+    ) -> Result<Option<DBImageTask>, ProcessorError> {
+        let task = DecomposerStore::enqueue_image_task(self, task_id).await?;
+        Ok(task.and_then(|_| self.image_task(task_id)))
+    }
+
+    async fn unclaim_image_task(&self, task_id: &uuid::Uuid) -> Result<(), ProcessorError> {
+        DecomposerStore::update_image_task_status(self, task_id, TaskStatus::Waiting).await
+    }
+
+    async fn filter_waiting_dependents(
+        &self,
+        _: &uuid::Uuid,
+        _: &str,
+    ) -> Result<u64, ProcessorError> {
+        Ok(0)
+    }
+}
+
+fn png(width: u32, height: u32) -> Bytes {
+    let img = DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        image::Rgb([(x * 16) as u8, (y * 16) as u8, 200])
+    }));
+    let mut bytes = Cursor::new(Vec::new());
+    img.write_to(&mut bytes, ImageFormat::Png).unwrap();
+    Bytes::from(bytes.into_inner())
+}
+
+fn decomposer_state(
+    config: &Arc<Config>,
+    queue: &Arc<dyn MessageQueue>,
+    store: &Arc<MemoryStore>,
+    storage: &Arc<dyn StorageBackend>,
+) -> ConsumerAppState {
+    let consumer = ConsumerClient::in_memory(
+        Arc::clone(queue),
+        decompose::DECOMPOSER_GROUP,
+        &[DATASET_TOPIC],
+    );
+    ConsumerAppState {
+        producer: Arc::new(ProducerClient::in_memory(Arc::clone(queue), IMAGE_TOPIC)),
+        events: BatchEventPublisher::from_config(config),
+        consumer: Arc::new(consumer.unwrap()),
+        database: Arc::clone(store) as Arc<dyn DecomposerStore>,
+        storage: Arc::clone(storage),
+        config: Arc::clone(config),
+        image_buffers: Arc::new(Semaphore::new(config.decomposer.max_buffered_images.max(1))),
+        uploads: Arc::new(Semaphore::new(
+            config.decomposer.max_concurrent_uploads.max(1),
+        )),
+    }
+}
+
+fn worker_state(
+    config: &Config,
+    queue: &Arc<dyn MessageQueue>,
+    store: &Arc<MemoryStore>,
+    storage: &Arc<dyn StorageBackend>,
+) -> WorkerAppState {
+    WorkerAppState {
+        producer: Arc::new(ProducerClient::in_memory(Arc::clone(queue), IMAGE_TOPIC)),
+        events: BatchEventPublisher::from_config(config),
+        database: Arc::clone(store) as Arc<dyn WorkerStore>,
+        storage: Arc::clone(storage),
+        handoff: None,
+        input_cache: None,
+        simulation: None,
+        hooks: Default::default(),
+        result_cache: false,
+        max_processing: None,
+        decode_limits: image_ops::DecodeLimits::from(&config.worker),
+        previews: config.previews.clone(),
+        quality_sample_rate: 0,
+        capabilities: WorkerCapabilities::new(&[], false),
+        gpu_operations: Vec::new(),
+        fairness: config.fairness.clone(),
+        worker_id: "worker-1".to_string(),
+        analytics_release: None,
+    }
+}
+
+#[tokio::test]
+async fn submitted_batch_is_decomposed_and_processed_without_kafka() {
+    let root = tempfile::tempdir().unwrap();
+    let storage: Arc<dyn StorageBackend> = Arc::new(LocalStorage::new(
+        root.path(),
+        LocalUrlSigner::new("http://localhost:3030", "test"),
+    ));
+    let queue: Arc<dyn MessageQueue> = Arc::new(MemoryQueue::new());
+    let store = Arc::new(MemoryStore::default());
+    let mut config = Config::default();
+    // Events go to the shared in-process queue, nobody reads them here
+    config.kafka.backend = QueueKind::Memory;
+    let config = Arc::new(config);
+
+    storage
+        .put_object("uploads/cats/a.png", png(8, 4), &PutOptions::default())
+        .await
+        .unwrap();
+    storage
+        .put_object("uploads/cats/b.png", png(6, 6), &PutOptions::default())
+        .await
+        .unwrap();
+
+    // Submit, as the api-server does once the batch and its dataset tasks are recorded
+    let batch_id = uuid::Uuid::new_v4();
+    let job: DatasetProcessingJob = serde_json::from_value(serde_json::json!({
+        "batch_id": batch_id,
+        "dataset_key": "uploads/cats/",
+        "operations": [{ "Resize": { "scaling_factor": 0.5 } }, "GrayScale"],
+        "pipeline_mode": "Fused",
+    }))
+    .unwrap();
+    let tasks = job.into_dataset_tasks();
+    assert_eq!(tasks.len(), 1);
+    let dataset_task_id = tasks[0].task_id;
+    store
+        .batches
+        .lock()
+        .unwrap()
+        .insert(batch_id, BatchState::Created);
+    store
+        .dataset_tasks
+        .lock()
+        .unwrap()
+        .insert(dataset_task_id, TaskStatus::Ready);
+    let sent = ProducerClient::in_memory(Arc::clone(&queue), DATASET_TOPIC)
+        .send_dataset(batch_id, tasks)
+        .await
+        .unwrap();
+    assert_eq!(sent.successes.len(), 1);
+    assert!(sent.failures.is_empty());
+
+    let decomposer = Arc::new(decomposer_state(&config, &queue, &store, &storage));
+    let decomposing = tokio::spawn({
+        let decomposer = Arc::clone(&decomposer);
+        async move {
+            let consumer = Arc::clone(&decomposer.consumer);
+            consumer
+                .start_consuming_envelopes(move |envelope| {
+                    decompose::handle_dataset_task(envelope, Arc::clone(&decomposer))
+                })
+                .await
+        }
+    });
+
+    let worker_consumer = Arc::new(
+        ConsumerClient::in_memory(Arc::clone(&queue), "image-workers", &[IMAGE_TOPIC]).unwrap(),
+    );
+    let worker = Arc::new(worker_state(&config, &queue, &store, &storage));
+    let processing = tokio::spawn({
+        let consumer = Arc::clone(&worker_consumer);
+        async move {
+            consumer
+                .start_consuming_envelopes(move |envelope: MessageEnvelope<ImageTask>| {
+                    tasks::handle_task(envelope.payload, envelope.produced_at, Arc::clone(&worker))
+                })
+                .await
+        }
+    });
+
+    let finished = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let tasks = store.image_tasks();
+            if tasks.len() == 2 && tasks.iter().all(|task| task.status.is_final()) {
+                return tasks;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("every image is processed");
+    decomposer.consumer.shutdown();
+    worker_consumer.shutdown();
+    decomposing.await.unwrap();
+    processing.await.unwrap();
+
+    assert_eq!(
+        store.batches.lock().unwrap().get(&batch_id),
+        Some(&BatchState::Processing { stage: 0 })
+    );
+    assert_eq!(
+        store.decomposed.lock().unwrap().get(&dataset_task_id),
+        Some(&2)
+    );
+
+    assert!(
+        finished
+            .iter()
+            .all(|task| matches!(task.status, TaskStatus::Success))
+    );
+    let mut finished: Vec<(String, Option<Dimensions>)> = finished
+        .into_iter()
+        .map(|task| (task.output_key.unwrap(), task.output_dimensions))
+        .collect();
+    finished.sort_by(|a, b| a.0.cmp(&b.0));
+    let (small, square) = (
+        Dimensions {
+            width: 4,
+            height: 2,
+        },
+        Dimensions {
+            width: 3,
+            height: 3,
+        },
+    );
+    assert_eq!(
+        finished,
+        vec![
+            (format!("cats/{}/1/a.png", batch_id), Some(small)),
+            (format!("cats/{}/1/b.png", batch_id), Some(square)),
+        ]
+    );
+
+    // The outputs are what the decomposer expected them to be
+    for (key, dimensions) in &finished {
+        let output = storage.get_object(key).await.unwrap();
+        let decoded = image::load_from_memory(&output).unwrap();
+        let expected = dimensions.unwrap();
+        assert_eq!(
+            (decoded.width(), decoded.height()),
+            (expected.width, expected.height)
+        );
+        assert!(
+            decoded
+                .to_rgb8()
+                .pixels()
+                .all(|p| p[0] == p[1] && p[1] == p[2])
+        );
+    }
+}
@@ -0,0 +1,507 @@
+use std::io::Cursor;
+
+use common::{
+    ImageOperation,
+    alpha::{AlphaPolicy, DEFAULT_BACKGROUND},
+    config::WorkerConfig,
+    dimensions::{Dimensions, crop_rect, rotated_dimensions},
+    failures::{ClassifiedError, FailureKind},
+    formats::{OutputFormat, target_format},
+    metadata::MetadataPolicy,
+    resampling::ResampleFilter,
+};
+use image::{
+    ColorType, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits,
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder},
+    imageops::FilterType,
+};
+use rand_distr::{Distribution, Normal};
+
+pub mod metadata;
+pub mod quality;
+pub mod raw;
+
+// 1 is the slowest and smallest, 10 the fastest. Datasets are encoded once and downloaded many
+// times, but the slowest speeds take seconds per image.
+const AVIF_SPEED: u8 = 6;
+
+/// The result of running a list of operations on an encoded image
+pub struct ProcessedImage {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+    pub extension: &'static str,
+    pub input_dimensions: Dimensions,
+    pub output_dimensions: Dimensions,
+}
+
+/// Bounds on the images `decode_with_limits` decodes, so a small file declaring huge dimensions,
+/// a decompression bomb, fails before its pixels are allocated. Every bound is off while unset,
+/// and the image crate's own allocation limit applies without `max_alloc_bytes`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeLimits {
+    pub max_pixels: Option<u64>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_alloc_bytes: Option<u64>, // Memory the decoder may allocate for a single image
+}
+
+impl From<&WorkerConfig> for DecodeLimits {
+    fn from(config: &WorkerConfig) -> Self {
+        Self {
+            max_pixels: config.max_pixels,
+            max_width: config.max_width,
+            max_height: config.max_height,
+            max_alloc_bytes: config.max_decode_alloc_mb.map(|mb| mb * 1024 * 1024),
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// The limits the image crate enforces while decoding
+    fn image_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        if let Some(max_alloc) = self.max_alloc_bytes {
+            limits.max_alloc = Some(max_alloc);
+        }
+        limits
+    }
+}
+
+/// Decodes an image, using the content to detect the format and the key as a fallback
+pub fn decode(bytes: &[u8], key: &str) -> Result<(DynamicImage, ImageFormat), ClassifiedError> {
+    decode_with_limits(bytes, key, &DecodeLimits::default())
+}
+
+/// Decodes an image within the limits. The dimensions are read from the header and checked
+/// before any pixel is decoded, an image over a limit fails with a `ResourceLimit`.
+pub fn decode_with_limits(
+    bytes: &[u8],
+    key: &str,
+    limits: &DecodeLimits,
+) -> Result<(DynamicImage, ImageFormat), ClassifiedError> {
+    let format = image::guess_format(bytes)
+        .or_else(|_| ImageFormat::from_path(key))
+        .map_err(|e| {
+            let message = format!("Unknown image format for {}: {}", key, e);
+            ClassifiedError::new(FailureKind::UnsupportedFormat, message)
+        })?;
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits.image_limits());
+    let decoder = reader.into_decoder().map_err(|e| decode_error(key, e))?;
+
+    let (width, height) = decoder.dimensions();
+    if let Some(max_pixels) = limits.max_pixels
+        && width as u64 * height as u64 > max_pixels
+    {
+        let message = format!(
+            "{} is {}x{} pixels, the limit is {} pixels",
+            key, width, height, max_pixels
+        );
+        return Err(ClassifiedError::new(FailureKind::ResourceLimit, message));
+    }
+    let img = DynamicImage::from_decoder(decoder).map_err(|e| decode_error(key, e))?;
+
+    Ok((img, format))
+}
+
+fn decode_error(key: &str, error: image::ImageError) -> ClassifiedError {
+    let kind = match &error {
+        image::ImageError::Unsupported(_) => FailureKind::UnsupportedFormat,
+        image::ImageError::Limits(_) => FailureKind::ResourceLimit,
+        _ => FailureKind::DecodeError,
+    };
+    ClassifiedError::new(kind, format!("Failed to decode {}: {}", key, error))
+}
+
+/// Converts the pixels of an image to a type the encoder of `format` takes. A 16-bit TIFF keeps
+/// its depth as TIFF or PNG, a WebP, GIF or BMP of it is 8-bit.
+fn encodable(img: DynamicImage, format: ImageFormat) -> DynamicImage {
+    match format {
+        // JPEG has no alpha channel, so anything with transparency is flattened to RGB
+        ImageFormat::Jpeg => match img {
+            DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => img,
+            img if img.color().has_alpha() => flatten(&img, DEFAULT_BACKGROUND),
+            img => DynamicImage::ImageRgb8(img.to_rgb8()),
+        },
+        ImageFormat::WebP | ImageFormat::Gif | ImageFormat::Bmp | ImageFormat::Avif => match img {
+            DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => img,
+            img if img.color().has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()),
+            img => DynamicImage::ImageRgb8(img.to_rgb8()),
+        },
+        ImageFormat::Png => match img {
+            DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb16(img.to_rgb16()),
+            DynamicImage::ImageRgba32F(_) => DynamicImage::ImageRgba16(img.to_rgba16()),
+            img => img,
+        },
+        // TIFF takes no grey with alpha
+        ImageFormat::Tiff => match img {
+            DynamicImage::ImageLumaA8(_) => DynamicImage::ImageRgba8(img.to_rgba8()),
+            DynamicImage::ImageLumaA16(_) => DynamicImage::ImageRgba16(img.to_rgba16()),
+            img => img,
+        },
+        _ => img,
+    }
+}
+
+/// Encodes an image back into the format it was read in
+pub fn encode(img: DynamicImage, format: ImageFormat) -> Result<Vec<u8>, String> {
+    let img = encodable(img, format);
+
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, format)
+        .map_err(|e| format!("Failed to encode image as {:?}: {}", format, e))?;
+
+    Ok(out.into_inner())
+}
+
+/// The image format an output format is encoded with
+pub fn image_format(format: OutputFormat) -> ImageFormat {
+    match format {
+        OutputFormat::Jpeg => ImageFormat::Jpeg,
+        OutputFormat::Png => ImageFormat::Png,
+        OutputFormat::Webp => ImageFormat::WebP,
+        OutputFormat::Avif => ImageFormat::Avif,
+    }
+}
+
+/// Encodes an image in a requested output format. JPEG and AVIF are encoded at `quality` (1-100),
+/// PNG and WebP are lossless and ignore it.
+pub fn encode_as(img: DynamicImage, format: OutputFormat, quality: u8) -> Result<Vec<u8>, String> {
+    let quality = quality.clamp(1, 100);
+    let mut out = Vec::new();
+
+    let result = match format {
+        // Neither encoder takes 16-bit or float pixels, and JPEG has no alpha channel either
+        OutputFormat::Jpeg => encodable(img, ImageFormat::Jpeg)
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality)),
+        OutputFormat::Avif => {
+            let img = encodable(img, ImageFormat::Avif);
+            img.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut out, AVIF_SPEED, quality,
+            ))
+        }
+        OutputFormat::Png | OutputFormat::Webp => return encode(img, image_format(format)),
+    };
+    result.map_err(|e| format!("Failed to encode image as {:?}: {}", format, e))?;
+
+    Ok(out)
+}
+
+/// Encodes the output of a task, in the target format if one was requested and in the format the
+/// input was read in otherwise. Returns the bytes and the format they are in.
+pub fn encode_output(
+    img: DynamicImage,
+    input_format: ImageFormat,
+    target: Option<(OutputFormat, u8)>,
+) -> Result<(Vec<u8>, ImageFormat), String> {
+    match target {
+        Some((format, quality)) => Ok((encode_as(img, format, quality)?, image_format(format))),
+        None => Ok((encode(img, input_format)?, input_format)),
+    }
+}
+
+/// Decodes an image, applies every operation in order and the alpha policy after them, and
+/// encodes the result in the format the last `Convert` asks for, or the input format without one.
+/// An image a quality gate leaves out is an error. The output keeps the EXIF block of the input
+/// as far as the metadata policy allows, see `metadata`.
+pub fn process_bytes(
+    bytes: &[u8],
+    key: &str,
+    operations: &[ImageOperation],
+    alpha_policy: AlphaPolicy,
+    metadata_policy: MetadataPolicy,
+) -> Result<ProcessedImage, String> {
+    let (img, format) = decode(bytes, key)?;
+    let input_dimensions = dimensions(&img);
+
+    let processed = quality::apply_gated(img, operations)
+        .map_err(|reason| format!("Filtered out by a quality gate: {}", reason))?;
+    let processed = apply_alpha_policy(processed, alpha_policy);
+    let output_dimensions = dimensions(&processed);
+    let (encoded, format) = encode_output(processed, format, target_format(operations))?;
+    let exif = match metadata_policy.keeps_metadata() {
+        true => metadata::read_exif(&bytes::Bytes::copy_from_slice(bytes)),
+        false => None,
+    };
+    let bytes = metadata::apply_metadata_policy(encoded, exif, metadata_policy);
+
+    Ok(ProcessedImage {
+        bytes,
+        content_type: format.to_mime_type(),
+        extension: format.extensions_str().first().copied().unwrap_or("bin"),
+        input_dimensions,
+        output_dimensions,
+    })
+}
+
+/// A square JPEG thumbnail of an encoded image, scaled to cover `size` pixels and cropped to the
+/// centre, see `common::previews`
+pub fn thumbnail(
+    bytes: &[u8],
+    key: &str,
+    size: u32,
+    quality: u8,
+    filter: Option<ResampleFilter>,
+) -> Result<Vec<u8>, String> {
+    let (img, _) = decode(bytes, key)?;
+    let size = size.max(1);
+    // The shorter side scaled to `size`, the longer one cropped to it
+    let scale = size as f64 / img.width().min(img.height()).max(1) as f64;
+    let width = ((img.width() as f64 * scale).round() as u32).max(size);
+    let height = ((img.height() as f64 * scale).round() as u32).max(size);
+    let scaled = premultiplied(img, |img| resample(img, width, height, filter));
+    let thumbnail = scaled.crop_imm((width - size) / 2, (height - size) / 2, size, size);
+    encode_as(thumbnail, OutputFormat::Jpeg, quality)
+}
+
+/// Resizes an image to exactly `width` x `height` pixels with the filter, or the one for the
+/// direction of the resize without one, see `common::resampling`
+pub fn resample(
+    img: DynamicImage,
+    width: u32,
+    height: u32,
+    filter: Option<ResampleFilter>,
+) -> DynamicImage {
+    let filter = match filter {
+        Some(ResampleFilter::Nearest) => FilterType::Nearest,
+        Some(ResampleFilter::Bilinear) => FilterType::Triangle,
+        Some(ResampleFilter::Lanczos3) => FilterType::Lanczos3,
+        Some(ResampleFilter::CatmullRom) => FilterType::CatmullRom,
+        // Each source pixel adds to the one output pixel it falls in
+        None if width <= img.width() && height <= img.height() => {
+            return img.thumbnail_exact(width, height);
+        }
+        None => FilterType::Lanczos3,
+    };
+    img.resize_exact(width, height, filter)
+}
+
+fn dimensions(img: &DynamicImage) -> Dimensions {
+    Dimensions {
+        width: img.width(),
+        height: img.height(),
+    }
+}
+
+/// Applies a single operation to an image, resolving size-relative parameters against the
+/// actual size of the image first
+pub fn apply_operation(img: DynamicImage, operation: &ImageOperation) -> DynamicImage {
+    let Some(operation) = operation.resolve(dimensions(&img)) else {
+        // No bucket matched this image, it passes through unchanged
+        return img;
+    };
+
+    match &operation {
+        ImageOperation::Resize {
+            scaling_factor,
+            filter,
+        } => {
+            let width = ((img.width() as f32 * scaling_factor).round() as u32).max(1);
+            let height = ((img.height() as f32 * scaling_factor).round() as u32).max(1);
+            premultiplied(img, |img| resample(img, width, height, *filter))
+        }
+        ImageOperation::GrayScale => img.grayscale(),
+        ImageOperation::Noise { noise_level } => add_noise(img, *noise_level),
+        ImageOperation::InvertColors => {
+            let mut img = img;
+            img.invert();
+            img
+        }
+        ImageOperation::Crop {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let (x, y, width, height) = crop_rect(dimensions(&img), *x, *y, *width, *height);
+            img.crop_imm(x, y, width, height)
+        }
+        ImageOperation::Rotate { degrees } => rotate(img, *degrees),
+        ImageOperation::Blur { sigma } => premultiplied(img, |img| img.blur(*sigma)),
+        // Only changes how the output is encoded, see `encode_output`
+        ImageOperation::Convert { .. } => img,
+        // Only decides whether the image goes on, see `quality::apply_gated`
+        ImageOperation::QualityGate { .. } => img,
+        ImageOperation::ResizeLongEdge { .. }
+        | ImageOperation::ResizeShortEdge { .. }
+        | ImageOperation::CenterCrop { .. }
+        | ImageOperation::BlurPerMegapixel { .. }
+        | ImageOperation::ByResolution { .. } => {
+            unreachable!("resolve always returns an absolute operation")
+        }
+    }
+}
+
+/// Applies the alpha policy of a job to an image that went through the operations of its stage
+pub fn apply_alpha_policy(img: DynamicImage, policy: AlphaPolicy) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    match policy {
+        AlphaPolicy::Preserve => img,
+        AlphaPolicy::Flatten { background } => flatten(&img, background),
+        AlphaPolicy::Drop => match img {
+            DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLuma8(img.to_luma8()),
+            img => DynamicImage::ImageRgb8(img.to_rgb8()),
+        },
+    }
+}
+
+/// Composites an image over an opaque background colour
+fn flatten(img: &DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let mut out = image::RgbImage::new(img.width(), img.height());
+    for (pixel, source) in out.pixels_mut().zip(img.to_rgba8().pixels()) {
+        let alpha = source.0[3] as f32 / 255.0;
+        for (c, value) in pixel.0.iter_mut().enumerate() {
+            let blended = source.0[c] as f32 * alpha + background[c] as f32 * (1.0 - alpha);
+            *value = blended.round() as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(out)
+}
+
+/// Runs a filter on the premultiplied colours of an image with an alpha channel, so the colour
+/// of transparent pixels doesn't bleed into their neighbours, and returns the image in its
+/// original pixel type
+fn premultiplied(
+    img: DynamicImage,
+    filter: impl FnOnce(DynamicImage) -> DynamicImage,
+) -> DynamicImage {
+    let color = img.color();
+    if !color.has_alpha() {
+        return filter(img);
+    }
+
+    let mut buf = img.to_rgba32f();
+    for pixel in buf.pixels_mut() {
+        let alpha = pixel.0[3];
+        for channel in pixel.0.iter_mut().take(3) {
+            *channel *= alpha;
+        }
+    }
+
+    let mut buf = filter(DynamicImage::ImageRgba32F(buf)).into_rgba32f();
+    for pixel in buf.pixels_mut() {
+        let alpha = pixel.0[3];
+        for channel in pixel.0.iter_mut().take(3) {
+            *channel = match alpha > 0.0 {
+                true => (*channel / alpha).min(1.0),
+                false => 0.0,
+            };
+        }
+    }
+
+    let filtered = DynamicImage::ImageRgba32F(buf);
+    match color {
+        ColorType::La8 => DynamicImage::ImageLumaA8(filtered.to_luma_alpha8()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(filtered.to_luma_alpha16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(filtered.to_rgba16()),
+        ColorType::Rgba32F => filtered,
+        _ => DynamicImage::ImageRgba8(filtered.to_rgba8()),
+    }
+}
+
+/// Rotates an image clockwise. Quarter turns are lossless, other angles are resampled bilinearly
+/// onto a canvas that fits the whole rotated image, with the uncovered corners left transparent
+/// (or black for images without an alpha channel).
+fn rotate(img: DynamicImage, degrees: f32) -> DynamicImage {
+    let turns = degrees.rem_euclid(360.0);
+    match turns {
+        0.0 => return img,
+        90.0 => return img.rotate90(),
+        180.0 => return img.rotate180(),
+        270.0 => return img.rotate270(),
+        _ => {}
+    }
+
+    let input = dimensions(&img);
+    let output = rotated_dimensions(input, turns);
+    let has_alpha = img.color().has_alpha();
+    let is_luma = matches!(img, DynamicImage::ImageLuma8(_));
+    let src = img.to_rgba8();
+
+    let (sin, cos) = turns.to_radians().sin_cos();
+    let (src_cx, src_cy) = (input.width as f32 / 2.0, input.height as f32 / 2.0);
+    let (dst_cx, dst_cy) = (output.width as f32 / 2.0, output.height as f32 / 2.0);
+
+    let mut dst = image::RgbaImage::new(output.width, output.height);
+    for (x, y, pixel) in dst.enumerate_pixels_mut() {
+        // Map the centre of the output pixel back into the source by rotating the other way
+        let dx = x as f32 + 0.5 - dst_cx;
+        let dy = y as f32 + 0.5 - dst_cy;
+        let sx = dx * cos + dy * sin + src_cx - 0.5;
+        let sy = -dx * sin + dy * cos + src_cy - 0.5;
+
+        if let Some(sample) = sample_bilinear(&src, sx, sy) {
+            *pixel = sample;
+        }
+    }
+
+    let rotated = DynamicImage::ImageRgba8(dst);
+    match (has_alpha, is_luma) {
+        (true, _) => rotated,
+        (false, true) => DynamicImage::ImageLuma8(rotated.to_luma8()),
+        (false, false) => DynamicImage::ImageRgb8(rotated.to_rgb8()),
+    }
+}
+
+fn sample_bilinear(src: &image::RgbaImage, x: f32, y: f32) -> Option<image::Rgba<u8>> {
+    let (width, height) = (src.width() as f32, src.height() as f32);
+    if x < -0.5 || y < -0.5 || x > width - 0.5 || y > height - 0.5 {
+        return None;
+    }
+
+    let x = x.clamp(0.0, width - 1.0);
+    let y = y.clamp(0.0, height - 1.0);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = (
+        (x0 + 1).min(src.width() - 1),
+        (y0 + 1).min(src.height() - 1),
+    );
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let mut out = [0u8; 4];
+    for (c, value) in out.iter_mut().enumerate() {
+        let top =
+            src.get_pixel(x0, y0).0[c] as f32 * (1.0 - fx) + src.get_pixel(x1, y0).0[c] as f32 * fx;
+        let bottom =
+            src.get_pixel(x0, y1).0[c] as f32 * (1.0 - fx) + src.get_pixel(x1, y1).0[c] as f32 * fx;
+        *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+
+    Some(image::Rgba(out))
+}
+
+/// Adds gaussian noise to every colour channel, `noise_level` is the standard deviation as a
+/// fraction of the full channel range
+fn add_noise(img: DynamicImage, noise_level: f32) -> DynamicImage {
+    let Ok(normal) = Normal::new(0.0f32, noise_level.abs() * 255.0) else {
+        return img;
+    };
+    let mut rng = rand::thread_rng();
+
+    match img {
+        DynamicImage::ImageLuma8(mut buf) => {
+            for pixel in buf.pixels_mut() {
+                pixel.0[0] = (pixel.0[0] as f32 + normal.sample(&mut rng)).clamp(0.0, 255.0) as u8;
+            }
+            DynamicImage::ImageLuma8(buf)
+        }
+        img => {
+            let has_alpha = img.color().has_alpha();
+            let mut buf = img.to_rgba8();
+            for pixel in buf.pixels_mut() {
+                // Leave the alpha channel untouched
+                for channel in pixel.0.iter_mut().take(3) {
+                    *channel = (*channel as f32 + normal.sample(&mut rng)).clamp(0.0, 255.0) as u8;
+                }
+            }
+            match has_alpha {
+                true => DynamicImage::ImageRgba8(buf),
+                false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(buf).to_rgb8()),
+            }
+        }
+    }
+}
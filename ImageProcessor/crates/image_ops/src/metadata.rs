@@ -0,0 +1,161 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+use common::metadata::{ImageMetadata, MetadataPolicy};
+use exif::{Context, Exif, In, Reader, Tag, Value};
+use image::ImageReader;
+use img_parts::{DynImage, ImageEXIF};
+
+// ============================================================================
+// EXIF BLOCKS
+// The block is read from and written to the container of the encoded image,
+// an APP1 segment in JPEG, an eXIf chunk in PNG and an EXIF chunk in WebP, as
+// the TIFF data all three hold. Stripping the GPS position empties the GPS
+// directory of the block in place: its entries and the values they point to
+// are zeroed, so no offset elsewhere in the block changes.
+// ============================================================================
+
+// Tag of the IFD0 entry pointing at the GPS directory
+const GPS_IFD_TAG: u16 = 0x8825;
+// A TIFF directory entry: tag (2), type (2), count (4), value or offset (4)
+const ENTRY_LEN: usize = 12;
+
+/// The EXIF block of an encoded image, `None` if it has none or its format can't hold one
+pub fn read_exif(bytes: &Bytes) -> Option<Bytes> {
+    DynImage::from_bytes(bytes.clone()).ok()??.exif()
+}
+
+/// What an image task records about its encoded input, `None` if not even its header can be read
+pub fn read_metadata(bytes: &Bytes) -> Option<ImageMetadata> {
+    let (width, height) = ImageReader::new(Cursor::new(bytes.as_ref()))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    let mut metadata = ImageMetadata {
+        width,
+        height,
+        ..Default::default()
+    };
+
+    let Some(exif) = read_exif(bytes).and_then(|exif| Reader::new().read_raw(exif.to_vec()).ok())
+    else {
+        return Some(metadata);
+    };
+    metadata.camera_make = ascii_field(&exif, Tag::Make);
+    metadata.camera_model = ascii_field(&exif, Tag::Model);
+    metadata.orientation = exif
+        .get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .and_then(|orientation| u16::try_from(orientation).ok());
+    metadata.has_gps = exif.fields().any(|field| field.tag.context() == Context::Gps);
+    Some(metadata)
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).trim_matches(['\0', ' ']).to_string())
+            .filter(|value| !value.is_empty()),
+        _ => None,
+    }
+}
+
+/// Writes the EXIF block of the input into the encoded output as the policy allows. Outputs in a
+/// format that can't hold the block are left without it, as are blocks whose GPS directory can't
+/// be found to strip it.
+pub fn apply_metadata_policy(
+    output: Vec<u8>,
+    exif: Option<Bytes>,
+    policy: MetadataPolicy,
+) -> Vec<u8> {
+    let exif = match (policy, exif) {
+        (MetadataPolicy::Strip, _) | (_, None) => return output,
+        (MetadataPolicy::Preserve, Some(exif)) => exif,
+        (MetadataPolicy::StripGps, Some(exif)) => {
+            let mut exif = exif.to_vec();
+            if strip_gps(&mut exif).is_none() {
+                return output;
+            }
+            Bytes::from(exif)
+        }
+    };
+
+    let encoded = Bytes::from(output);
+    let mut image = match DynImage::from_bytes(encoded.clone()) {
+        Ok(Some(image)) => image,
+        _ => return Vec::from(encoded),
+    };
+    image.set_exif(Some(exif));
+    let mut out = Vec::with_capacity(encoded.len());
+    match image.encoder().write_to(&mut out) {
+        Ok(_) => out,
+        Err(_) => Vec::from(encoded),
+    }
+}
+
+/// Empties the GPS directory of an EXIF block in place, see the top of the module. Returns `None`
+/// if the block can't be read far enough to do so.
+fn strip_gps(tiff: &mut [u8]) -> Option<()> {
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+
+    let ifd0 = read_u32(tiff, 4, big_endian)? as usize;
+    let entries = read_u16(tiff, ifd0, big_endian)? as usize;
+    let Some(pointer) = (0..entries)
+        .map(|i| ifd0 + 2 + i * ENTRY_LEN)
+        .find(|entry| read_u16(tiff, *entry, big_endian) == Some(GPS_IFD_TAG))
+    else {
+        // Nothing to strip
+        return Some(());
+    };
+
+    let gps_ifd = read_u32(tiff, pointer + 8, big_endian)? as usize;
+    let gps_entries = read_u16(tiff, gps_ifd, big_endian)? as usize;
+    for i in 0..gps_entries {
+        let entry = gps_ifd + 2 + i * ENTRY_LEN;
+        let kind = read_u16(tiff, entry + 2, big_endian)?;
+        let count = read_u32(tiff, entry + 4, big_endian)? as usize;
+        let len = value_len(kind)?.checked_mul(count)?;
+        // Values of up to 4 bytes sit in the entry itself
+        if len > 4 {
+            let offset = read_u32(tiff, entry + 8, big_endian)? as usize;
+            tiff.get_mut(offset..offset.checked_add(len)?)?.fill(0);
+        }
+    }
+    // No entries left, and the zeroed first one reads as the end of the directory chain
+    let end = gps_ifd + 2 + gps_entries * ENTRY_LEN;
+    tiff.get_mut(gps_ifd..end)?.fill(0);
+    Some(())
+}
+
+/// Size in bytes of one value of a TIFF field type
+fn value_len(kind: u16) -> Option<usize> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1), // BYTE, ASCII, SBYTE, UNDEFINED
+        3 | 8 => Some(2),         // SHORT, SSHORT
+        4 | 9 | 11 => Some(4),    // LONG, SLONG, FLOAT
+        5 | 10 | 12 => Some(8),   // RATIONAL, SRATIONAL, DOUBLE
+        _ => None,
+    }
+}
+
+fn read_u16(buf: &[u8], at: usize, big_endian: bool) -> Option<u16> {
+    let bytes: [u8; 2] = buf.get(at..at + 2)?.try_into().ok()?;
+    Some(match big_endian {
+        true => u16::from_be_bytes(bytes),
+        false => u16::from_le_bytes(bytes),
+    })
+}
+
+fn read_u32(buf: &[u8], at: usize, big_endian: bool) -> Option<u32> {
+    let bytes: [u8; 4] = buf.get(at..at + 4)?.try_into().ok()?;
+    Some(match big_endian {
+        true => u32::from_be_bytes(bytes),
+        false => u32::from_le_bytes(bytes),
+    })
+}
@@ -0,0 +1,212 @@
+use common::{ImageOperation, drift::ImageQualitySample};
+use image::{DynamicImage, GrayImage, imageops::FilterType};
+
+use crate::DecodeLimits;
+
+// ============================================================================
+// QUALITY GATES
+// A QualityGate measures the image as the operations before it left it and
+// lets it through unchanged, or leaves it out of the rest of the pipeline
+// when it misses one of its bounds. Both metrics are computed on luma:
+//
+//   blur score  1 / (1 + variance of the Laplacian / BLUR_SCALE), close to 0
+//               for an image full of sharp edges and 1 for a flat one
+//   entropy     Shannon entropy of the luma histogram, 0 for a single shade
+//               and 8 bits for an image using every shade equally often
+//
+// The sampled outputs of the stages are measured here too, for the quality
+// drift checks, see `common::drift`.
+// ============================================================================
+
+// Variance of the Laplacian at which the blur score is 0.5, about that of a slightly soft photo
+const BLUR_SCALE: f64 = 100.0;
+
+// Longer side the images are scaled down to before their SSIM is computed
+const SSIM_SIZE: u32 = 256;
+// Side of the windows the SSIM is averaged over
+const SSIM_WINDOW: u32 = 8;
+// Aspect ratios further apart than this are different framings, with no SSIM between them
+const MAX_ASPECT_DIFFERENCE: f64 = 0.01;
+
+/// Why the image misses the bounds of a quality gate, `None` if it passes or the operation isn't
+/// a gate. The metrics are only computed for the bounds the gate sets.
+pub fn gate_failure(img: &DynamicImage, operation: &ImageOperation) -> Option<String> {
+    let ImageOperation::QualityGate {
+        min_width,
+        min_height,
+        max_blur_score,
+        min_entropy,
+    } = operation
+    else {
+        return None;
+    };
+
+    if let Some(min_width) = min_width.filter(|min| img.width() < *min) {
+        return Some(format!("{}px wide, below {}px", img.width(), min_width));
+    }
+    if let Some(min_height) = min_height.filter(|min| img.height() < *min) {
+        return Some(format!("{}px high, below {}px", img.height(), min_height));
+    }
+
+    let luma = img.to_luma8();
+    if let Some(max_blur_score) = max_blur_score {
+        let score = blur_score(&luma);
+        if score > *max_blur_score {
+            return Some(format!("Blur score {:.3} above {}", score, max_blur_score));
+        }
+    }
+    if let Some(min_entropy) = min_entropy {
+        let entropy = entropy(&luma);
+        if entropy < *min_entropy {
+            return Some(format!("Entropy {:.3} bits below {}", entropy, min_entropy));
+        }
+    }
+    None
+}
+
+/// Applies the operations in order, stopping at the first quality gate the image misses.
+/// Returns the processed image, or why a gate left it out.
+pub fn apply_gated(
+    img: DynamicImage,
+    operations: &[ImageOperation],
+) -> Result<DynamicImage, String> {
+    operations
+        .iter()
+        .try_fold(img, |img, operation| match gate_failure(&img, operation) {
+            Some(reason) => Err(reason),
+            None => Ok(crate::apply_operation(img, operation)),
+        })
+}
+
+/// The blur score of an image, see the top of the module. Images too small to have an inner
+/// pixel count as flat.
+pub fn blur_score(luma: &GrayImage) -> f64 {
+    1.0 / (1.0 + sharpness(luma) / BLUR_SCALE)
+}
+
+/// The variance of the Laplacian of an image, 0 for a flat one and for images too small to have
+/// an inner pixel
+pub fn sharpness(luma: &GrayImage) -> f64 {
+    let (width, height) = luma.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+
+    // 4-neighbour Laplacian over the inner pixels, with Welford's running variance
+    let (mut count, mut mean, mut m2) = (0.0, 0.0, 0.0);
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let at = |x: u32, y: u32| luma.get_pixel(x, y).0[0] as f64;
+            let laplacian =
+                at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y);
+            count += 1.0;
+            let delta = laplacian - mean;
+            mean += delta / count;
+            m2 += delta * (laplacian - mean);
+        }
+    }
+
+    m2 / count
+}
+
+/// The Shannon entropy of the luma histogram of an image in bits, see the top of the module
+pub fn entropy(luma: &GrayImage) -> f64 {
+    let mut histogram = [0u64; 256];
+    for pixel in luma.pixels() {
+        histogram[pixel.0[0] as usize] += 1;
+    }
+
+    let total = (luma.width() as u64 * luma.height() as u64).max(1) as f64;
+    histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// The mean luma of an image, 0 to 255
+pub fn brightness(luma: &GrayImage) -> f64 {
+    let total = (luma.width() as u64 * luma.height() as u64).max(1) as f64;
+    luma.pixels().map(|pixel| pixel.0[0] as f64).sum::<f64>() / total
+}
+
+/// The structural similarity of two images of the same size, averaged over windows of
+/// `SSIM_WINDOW` pixels: 1 for identical images, around 0 for unrelated ones
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (width, height) = a.dimensions();
+    let window = SSIM_WINDOW.min(width).min(height).max(1);
+
+    let (mut total, mut windows) = (0.0, 0.0);
+    for top in (0..=height.saturating_sub(window)).step_by(window as usize) {
+        for left in (0..=width.saturating_sub(window)).step_by(window as usize) {
+            let n = (window * window) as f64;
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0);
+            for y in top..top + window {
+                for x in left..left + window {
+                    let pa = a.get_pixel(x, y).0[0] as f64;
+                    let pb = b.get_pixel(x, y).0[0] as f64;
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1.0;
+        }
+    }
+    match windows > 0.0 {
+        true => total / windows,
+        false => 1.0,
+    }
+}
+
+/// Measures an encoded output for the quality drift checks, see `common::drift`. The SSIM is
+/// only computed against an encoded input with the output's aspect ratio, both scaled down to
+/// at most `SSIM_SIZE` pixels.
+pub fn measure(
+    input: Option<&[u8]>,
+    output: &[u8],
+    key: &str,
+    limits: &DecodeLimits,
+) -> Result<ImageQualitySample, String> {
+    let (output, _) = crate::decode_with_limits(output, key, limits)?;
+    let output_luma = output.to_luma8();
+    let input = match input {
+        Some(input) => Some(crate::decode_with_limits(input, key, limits)?.0),
+        None => None,
+    };
+
+    let aspect = |img: &DynamicImage| img.width() as f64 / img.height().max(1) as f64;
+    let ssim = input
+        .filter(|input| (aspect(input) / aspect(&output) - 1.0).abs() <= MAX_ASPECT_DIFFERENCE)
+        .map(|input| {
+            let scale = (SSIM_SIZE as f64 / output.width().max(output.height()) as f64).min(1.0);
+            let width = ((output.width() as f64 * scale).round() as u32).max(1);
+            let height = ((output.height() as f64 * scale).round() as u32).max(1);
+            let scaled = |img: &DynamicImage| {
+                img.resize_exact(width, height, FilterType::Triangle)
+                    .to_luma8()
+            };
+            ssim(&scaled(&input), &scaled(&output))
+        });
+
+    Ok(ImageQualitySample {
+        sharpness: sharpness(&output_luma),
+        brightness: brightness(&output_luma),
+        ssim,
+    })
+}
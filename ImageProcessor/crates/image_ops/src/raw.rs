@@ -0,0 +1,72 @@
+use image::{DynamicImage, ImageBuffer, ImageFormat};
+
+// ============================================================================
+// RAW PIXEL BUFFERS
+// Decoded images handed from one stage to the next on the same worker, so
+// the next stage skips decoding. The header records the pixel layout and the
+// format the image was read in, which the last stage encodes back to.
+//
+//   magic (6) | layout (1) | width (4, LE) | height (4, LE) | ext len (1) | ext | pixels
+// ============================================================================
+
+const MAGIC: &[u8; 6] = b"IPRAW1";
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + 4 + 1;
+
+/// Pixel layouts that can be handed over, other images take the encoded path
+fn layout_of(img: &DynamicImage) -> Option<u8> {
+    match img {
+        DynamicImage::ImageLuma8(_) => Some(0),
+        DynamicImage::ImageLumaA8(_) => Some(1),
+        DynamicImage::ImageRgb8(_) => Some(2),
+        DynamicImage::ImageRgba8(_) => Some(3),
+        _ => None,
+    }
+}
+
+/// Serializes the pixels of an image, `None` if its layout can't be handed over
+pub fn encode_raw(img: &DynamicImage, format: ImageFormat) -> Option<Vec<u8>> {
+    let layout = layout_of(img)?;
+    let extension = format.extensions_str().first()?.as_bytes();
+    let pixels = img.as_bytes();
+
+    let mut out = Vec::with_capacity(HEADER_LEN + extension.len() + pixels.len());
+    out.extend_from_slice(MAGIC);
+    out.push(layout);
+    out.extend_from_slice(&img.width().to_le_bytes());
+    out.extend_from_slice(&img.height().to_le_bytes());
+    out.push(extension.len() as u8);
+    out.extend_from_slice(extension);
+    out.extend_from_slice(pixels);
+
+    Some(out)
+}
+
+/// Reads a buffer written by `encode_raw`
+pub fn decode_raw(bytes: &[u8]) -> Result<(DynamicImage, ImageFormat), String> {
+    let invalid = || "Invalid raw pixel buffer".to_string();
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid());
+    }
+
+    let layout = bytes[6];
+    let width = u32::from_le_bytes(bytes[7..11].try_into().map_err(|_| invalid())?);
+    let height = u32::from_le_bytes(bytes[11..15].try_into().map_err(|_| invalid())?);
+    let extension_end = HEADER_LEN + bytes[15] as usize;
+    let extension = bytes
+        .get(HEADER_LEN..extension_end)
+        .and_then(|ext| std::str::from_utf8(ext).ok())
+        .ok_or_else(invalid)?;
+    let format = ImageFormat::from_extension(extension).ok_or_else(invalid)?;
+    let pixels = bytes[extension_end..].to_vec();
+
+    let img = match layout {
+        0 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        1 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8),
+        2 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+        3 => ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+
+    Ok((img, format))
+}
@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    Extension,
+    response::{IntoResponse, Json, Response},
+};
+use common::{
+    datasets::{ARCHIVE_EXTENSIONS, dataset_extension},
+    tenancy::{strip_tenant_prefix, tenant_key},
+    validation::validate_pipeline,
+};
+use storage::PutOptions;
+
+use crate::VALID_UPLOAD_EXTENSIONS;
+use crate::auth::Caller;
+use crate::schema;
+use crate::utils::{
+    APIError, AdhocUploadRequest, AdhocUploadResponse, AppState, ProcessImageRequest,
+    ProcessImageResponse,
+};
+
+// Ad-hoc images live under their own prefix so they never mix with dataset uploads or results
+const ADHOC_PREFIX: &str = "adhoc/";
+const MAX_ADHOC_IMAGE_BYTES: u64 = 10 * 1024 * 1024;
+const ADHOC_TIMEOUT: Duration = Duration::from_secs(20);
+const PRESIGN_EXPIRY: Duration = Duration::from_secs(900);
+
+/// Creates a presigned URL for uploading a single image to process with `/process_image`.
+///
+/// # Returns
+/// - `200 OK` with the key of the image and the presigned upload URL.
+/// - `400 Bad Request` if the file is not a supported image.
+/// - `500 Internal Server Error` if URL generation fails.
+#[axum::debug_handler]
+pub async fn create_image_upload(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<AdhocUploadRequest>,
+) -> Result<Json<AdhocUploadResponse>, Response> {
+    let ext = dataset_extension(&request.filename).unwrap_or("");
+    if ARCHIVE_EXTENSIONS.contains(&ext) || !VALID_UPLOAD_EXTENSIONS.contains(&ext) {
+        let images: Vec<&str> = VALID_UPLOAD_EXTENSIONS
+            .into_iter()
+            .filter(|ext| !ARCHIVE_EXTENSIONS.contains(ext))
+            .collect();
+        return Err(APIError::unsupported_extension(ext, &images).into_response());
+    }
+
+    let image_key = tenant_key(
+        caller.tenant_id(),
+        &format!("{}{}/input.{}", ADHOC_PREFIX, uuid::Uuid::new_v4(), ext),
+    );
+    let presigned_url = state
+        .storage
+        .presign_put(&image_key, PRESIGN_EXPIRY)
+        .await
+        .map_err(|_| {
+            APIError::UploadError("Failed to generate presigned URL".to_string()).into_response()
+        })?;
+
+    Ok(Json(AdhocUploadResponse {
+        image_key,
+        presigned_url,
+    }))
+}
+
+/// Runs a list of operations on a single uploaded image within the request.
+///
+/// Meant for previewing pipeline parameters interactively, so only small images are accepted and
+/// the processing is bounded by a timeout. The result is written next to the input and returned
+/// as a presigned download URL.
+///
+/// # Returns
+/// - `200 OK` with the result key, a presigned URL to download it and the image sizes.
+/// - `404 Not Found` if the image hasn't been uploaded.
+/// - `422 Unprocessable Entity` if the pipeline is invalid or the image is too large.
+/// - `504 Gateway Timeout` if processing didn't finish in time.
+#[axum::debug_handler]
+pub async fn process_image(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<ProcessImageRequest>,
+) -> Result<Json<ProcessImageResponse>, Response> {
+    let started = Instant::now();
+
+    let is_adhoc_key = strip_tenant_prefix(caller.tenant_id(), &request.image_key)
+        .is_some_and(|key| key.starts_with(ADHOC_PREFIX));
+    if !is_adhoc_key {
+        return Err(APIError::ValidationError(format!(
+            "Only images uploaded through /process_image/upload can be processed, got {}",
+            request.image_key
+        ))
+        .into_response());
+    }
+
+    let report = validate_pipeline(&request.operations);
+    schema::check_pipeline(&report).map_err(IntoResponse::into_response)?;
+
+    let info = state
+        .storage
+        .head_object(&request.image_key)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    // Check the size before downloading the body, large images belong in a batch
+    let size = info.size;
+    if size > MAX_ADHOC_IMAGE_BYTES {
+        return Err(APIError::ValidationError(format!(
+            "Image is {} bytes, ad-hoc processing is limited to {} bytes",
+            size, MAX_ADHOC_IMAGE_BYTES
+        ))
+        .into_response());
+    }
+
+    let bytes = state
+        .storage
+        .get_object(&request.image_key)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let key = request.image_key.clone();
+    let operations = request.operations.clone();
+    let (alpha_policy, metadata_policy) = (request.alpha_policy, request.metadata_policy);
+    let work = tokio::task::spawn_blocking(move || {
+        image_ops::process_bytes(&bytes, &key, &operations, alpha_policy, metadata_policy)
+    });
+    let processed = tokio::time::timeout(ADHOC_TIMEOUT, work)
+        .await
+        .map_err(|_| {
+            APIError::TimeoutError(format!(
+                "Processing took longer than {}s, submit the image as a batch instead",
+                ADHOC_TIMEOUT.as_secs()
+            ))
+            .into_response()
+        })?
+        .map_err(|e| APIError::UploadError(format!("Join error: {}", e)).into_response())?
+        .map_err(|e| APIError::ValidationError(e).into_response())?;
+
+    let prefix = request
+        .image_key
+        .rsplit_once('/')
+        .map(|(prefix, _)| prefix)
+        .unwrap_or(ADHOC_PREFIX.trim_end_matches('/'));
+    let result_key = format!("{}/output.{}", prefix, processed.extension);
+
+    let options = PutOptions {
+        content_type: Some(processed.content_type.to_string()),
+        ..Default::default()
+    };
+    state
+        .storage
+        .put_object(&result_key, processed.bytes.into(), &options)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let result_url = state
+        .storage
+        .presign_get(&result_key, PRESIGN_EXPIRY)
+        .await
+        .map_err(|_| {
+            APIError::UploadError("Failed to generate presigned URL".to_string()).into_response()
+        })?;
+
+    Ok(Json(ProcessImageResponse {
+        result_key,
+        result_url,
+        input_dimensions: processed.input_dimensions,
+        output_dimensions: processed.output_dimensions,
+        warnings: report.warnings(),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    }))
+}
@@ -0,0 +1,634 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension,
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{Duration, Utc};
+use common::{
+    Priority,
+    analytics::WindowSamples,
+    config::KafkaConfig,
+    control::{ControlCommand, ControlMessage},
+    error::ProcessorError,
+    migration::MigrationState,
+};
+use db_utils::types::{ByteTotals, DBTopicMigration};
+use queue::{
+    admin::{KafkaAdmin, TopicDescription, TopicSpec},
+    lag::{consumer_group_lag, total_lag},
+    migration::{TopicMigrationPlan, migrate_topic},
+};
+
+use crate::utils::{
+    APIError, AlterPartitionsRequest, AppState, ByteStatsParams, ByteStatsResponse,
+    ControlCommandResponse, FieldError, KafkaHealthResponse, LatencyWindow,
+    OperationAnalyticsParams, OperationAnalyticsResponse, OperationBytes, OperationSlo,
+    ProducerHealth, SloReportParams, SloReportResponse, SloViolatingBatch,
+    TopicListParams, TopicListResponse, TopicMigrationAccepted, TopicMigrationStatus,
+    TopicSummary,
+};
+
+// Kafka rejects longer topic names
+const MAX_TOPIC_NAME_LEN: usize = 249;
+
+/// Starts migrating the dataset or image topic to a new topic with a different partition layout,
+/// in the background, see `common::migration`. Every service moves its producers and consumers of
+/// the topic along, so other topics, whose users don't, can't be migrated.
+///
+/// # Returns
+/// - `202 Accepted` with the id the migration is followed by, see `get_topic_migration_handler`.
+/// - `409 Conflict` if the new topic already exists.
+/// - `422 Unprocessable Entity` for another topic, or a plan without partitions or a new topic.
+/// - `500 Internal Server Error` if the brokers or the database can't be reached.
+#[axum::debug_handler]
+pub async fn migrate_topic_handler(
+    Extension(state): Extension<AppState>,
+    Json(plan): Json<TopicMigrationPlan>,
+) -> Result<(StatusCode, Json<TopicMigrationAccepted>), Response> {
+    let kafka = &state.config.kafka;
+    let mut errors = Vec::new();
+    if plan.from_topic != kafka.dataset_topic && plan.from_topic != kafka.image_topic {
+        errors.push(FieldError::new(
+            "from_topic",
+            format!("Only {} and {} can be migrated", kafka.dataset_topic, kafka.image_topic),
+        ));
+    }
+    if plan.to_topic.is_empty() || plan.to_topic == plan.from_topic {
+        errors.push(FieldError::new("to_topic", "Has to name a new topic"));
+    }
+    if plan.partitions < 1 {
+        errors.push(FieldError::new("partitions", "A topic needs at least one partition"));
+    }
+    if !errors.is_empty() {
+        return Err(APIError::InvalidFields(errors).into_response());
+    }
+
+    // Checked again when the migration creates the topic, this only answers right away
+    let brokers = kafka.brokers.clone();
+    let to_topic = plan.to_topic.clone();
+    let existing = tokio::task::spawn_blocking(move || {
+        KafkaAdmin::new(&brokers).describe_topics(Some(&to_topic))
+    })
+    .await
+    .map_err(|e| APIError::SendTaskError(format!("Join error: {}", e)).into_response())?;
+    match existing {
+        Ok(topics) if !topics.is_empty() => {
+            return Err(APIError::topic_exists(&plan.to_topic).into_response());
+        }
+        Ok(_) | Err(ProcessorError::NotFound(_)) => {}
+        Err(e) => return Err(APIError::from(e).into_response()),
+    }
+
+    let migration = DBTopicMigration {
+        id: None,
+        migration_id: uuid::Uuid::new_v4(),
+        plan,
+        state: MigrationState::Running,
+        report: None,
+        error: None,
+        time_created: Utc::now(),
+        time_finished: None,
+    };
+    state
+        .db
+        .insert_topic_migration(&migration)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let migration_id = migration.migration_id;
+    tokio::spawn(async move {
+        let kafka = &state.config.kafka;
+        let plan = &migration.plan;
+        let result = migrate_topic(
+            &kafka.brokers,
+            plan,
+            kafka.priority.dedicated_topics,
+            &state.migration_producer,
+        )
+        .await;
+        match &result {
+            Ok(report) => tracing::info!(
+                %migration_id,
+                from = %plan.from_topic,
+                to = %plan.to_topic,
+                mirrored = report.messages_mirrored,
+                "Migrated topic"
+            ),
+            Err(e) => tracing::error!(%migration_id, error = %e, "Topic migration failed"),
+        }
+        if let Err(e) = state.db.finish_topic_migration(&migration_id, &result).await {
+            tracing::error!(%migration_id, error = %e, "Failed to record the end of a migration");
+        }
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(TopicMigrationAccepted {
+            migration_id,
+            state: MigrationState::Running,
+        }),
+    ))
+}
+
+/// The state of a topic migration, and its report or error once it ended
+///
+/// # Returns
+/// - `200 OK` with a `TopicMigrationStatus`.
+/// - `404 Not Found` if no migration has the id.
+#[axum::debug_handler]
+pub async fn get_topic_migration_handler(
+    Extension(state): Extension<AppState>,
+    Path(migration_id): Path<uuid::Uuid>,
+) -> Result<Json<TopicMigrationStatus>, Response> {
+    let migration = state
+        .db
+        .get_topic_migration(&migration_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .ok_or_else(|| {
+            APIError::NotFoundError(format!("Migration {} does not exist", migration_id))
+                .into_response()
+        })?;
+
+    Ok(Json(TopicMigrationStatus {
+        migration_id: migration.migration_id,
+        plan: migration.plan,
+        state: migration.state,
+        report: migration.report,
+        error: migration.error,
+        time_created: migration.time_created,
+        time_finished: migration.time_finished,
+    }))
+}
+
+/// Reports the latency percentiles of every operation over the last `hours` hours, window by
+/// window, next to its declared target, along with the batches that finished in that time and
+/// missed a target.
+#[axum::debug_handler]
+pub async fn slo_report_handler(
+    Extension(state): Extension<AppState>,
+    Query(params): Query<SloReportParams>,
+) -> Result<Json<SloReportResponse>, Response> {
+    let since = Utc::now() - Duration::hours(params.hours as i64);
+    let targets = &state.config.slo.targets;
+
+    let windows = state
+        .db
+        .get_latency_windows_since(since)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    // Operations with a target are listed even when none of their tasks ran
+    let mut operations: BTreeMap<String, OperationSlo> = targets
+        .iter()
+        .map(|(operation, target)| {
+            let slo = OperationSlo {
+                operation: operation.clone(),
+                target: Some(*target),
+                windows: Vec::new(),
+            };
+            (operation.clone(), slo)
+        })
+        .collect();
+    for window in windows {
+        let target = targets.get(&window.operation).copied();
+        let violations = target
+            .map(|target| target.violations(&window.operation, &window.latency))
+            .unwrap_or_default();
+
+        operations
+            .entry(window.operation.clone())
+            .or_insert_with(|| OperationSlo {
+                operation: window.operation.clone(),
+                target,
+                windows: Vec::new(),
+            })
+            .windows
+            .push(LatencyWindow {
+                window_start: window.window_start,
+                window_end: window.window_end,
+                latency: window.latency,
+                violations,
+            });
+    }
+
+    let violating_batches = state
+        .db
+        .get_batches_violating_slo_since(since)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .map(|batch| SloViolatingBatch {
+            batch_id: batch.batch_id,
+            time_completed: batch.time_completed,
+            violations: batch.slo_violations,
+        })
+        .collect();
+
+    Ok(Json(SloReportResponse {
+        since,
+        window_secs: state.config.slo.window_secs,
+        operations: operations.into_values().collect(),
+        violating_batches,
+    }))
+}
+
+/// Reports the bytes read and written per operation by the image tasks that finished in the
+/// last `hours` hours, and the data amplification of every operation (bytes written per byte
+/// read), e.g. around 5 for an augmentation or 0.1 for thumbnails.
+#[axum::debug_handler]
+pub async fn byte_stats_handler(
+    Extension(state): Extension<AppState>,
+    Query(params): Query<ByteStatsParams>,
+) -> Result<Json<ByteStatsResponse>, Response> {
+    let since = Utc::now() - Duration::hours(params.hours as i64);
+
+    let totals: BTreeMap<String, ByteTotals> = state
+        .db
+        .operation_byte_totals_since(since)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .collect();
+
+    let mut total = ByteTotals::default();
+    let operations = totals
+        .into_iter()
+        .map(|(operation, totals)| {
+            total.merge(&totals);
+            OperationBytes {
+                operation,
+                bytes: (&totals).into(),
+            }
+        })
+        .collect();
+
+    Ok(Json(ByteStatsResponse {
+        since,
+        operations,
+        total: (&total).into(),
+    }))
+}
+
+/// Reports the throughput and the latency percentiles of every phase (queue wait, download,
+/// processing, upload) of the image tasks that finished in the last `hours` hours, per operation
+/// and release, in windows of `bucket_hours`, see `common::analytics`. `?operation=` and
+/// `?release=` narrow the report, e.g. to compare an operation across two releases.
+///
+/// Only the most recent `analytics.max_samples` tasks are read. When there were more, the window
+/// holding the oldest task read is flagged `truncated`, and earlier windows are left out.
+///
+/// # Returns
+/// - `200 OK` with an `OperationAnalyticsResponse`.
+/// - `400 Bad Request` if `hours` or `bucket_hours` is zero, or `hours` is above
+///   `analytics.max_hours`.
+#[axum::debug_handler]
+pub async fn operation_analytics_handler(
+    Extension(state): Extension<AppState>,
+    Query(params): Query<OperationAnalyticsParams>,
+) -> Result<Json<OperationAnalyticsResponse>, Response> {
+    let config = &state.config.analytics;
+    let hours = params.hours.unwrap_or(config.default_hours);
+    let bucket_hours = params.bucket_hours.unwrap_or(config.bucket_hours);
+    let mut errors = Vec::new();
+    if hours == 0 {
+        errors.push(FieldError::new("hours", "Must be at least 1"));
+    }
+    if hours > config.max_hours {
+        errors.push(FieldError::new(
+            "hours",
+            format!("Must be at most {}", config.max_hours),
+        ));
+    }
+    if bucket_hours == 0 {
+        errors.push(FieldError::new("bucket_hours", "Must be at least 1"));
+    }
+    if !errors.is_empty() {
+        return Err(APIError::InvalidFields(errors).into_response());
+    }
+
+    let now = Utc::now();
+    let since = now - Duration::hours(hours as i64);
+    let records = state
+        .db
+        .get_task_metrics_since(
+            since,
+            params.operation.as_deref(),
+            params.release.as_deref(),
+            config.max_samples,
+        )
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    let samples = records.len() as u64;
+    let truncated = samples >= config.max_samples;
+    // Sorted newest first, tasks before the last one read may be missing
+    let oldest_read = records.last().map(|record| record.time_recorded);
+
+    // Windows start at `since`, the last one ends now
+    let bucket = Duration::hours(bucket_hours as i64);
+    let mut windows: BTreeMap<(String, String, i64), WindowSamples> = BTreeMap::new();
+    for record in records {
+        let index = (record.time_recorded - since).num_seconds().max(0) / bucket.num_seconds();
+        windows
+            .entry((record.operation, record.release, index))
+            .or_default()
+            .add(&record.timings, record.bytes_read, record.bytes_written);
+    }
+    let windows = windows
+        .into_iter()
+        .map(|((operation, release, index), samples)| {
+            let start = since + bucket * index as i32;
+            let end = (start + bucket).min(now);
+            let mut window = samples.into_window(operation, release, start, end);
+            window.truncated = truncated && oldest_read.is_some_and(|oldest| start <= oldest);
+            window
+        })
+        .collect();
+
+    Ok(Json(OperationAnalyticsResponse {
+        since,
+        bucket_hours,
+        samples,
+        truncated,
+        windows,
+    }))
+}
+
+/// Publishes a runtime command for the image workers on the control topic, e.g.
+/// `{"command": "set_concurrency", "limit": 8}`. Without a `worker_id` every worker applies it.
+///
+/// # Returns
+/// - `200 OK` with the message type the command was published under.
+/// - `422 Unprocessable Entity` if a concurrency of zero or unordered memory thresholds are
+///   requested.
+/// - `500 Internal Server Error` if the command can't be published.
+#[axum::debug_handler]
+pub async fn send_control_command(
+    Extension(state): Extension<AppState>,
+    Json(message): Json<ControlMessage>,
+) -> Result<Json<ControlCommandResponse>, Response> {
+    if let ControlCommand::SetConcurrency { limit: 0 } = message.command {
+        return Err(
+            APIError::ValidationError("The concurrency has to be at least 1".to_string())
+                .into_response(),
+        );
+    }
+    if let ControlCommand::SetMemoryThresholds { thresholds } = &message.command
+        && !thresholds.is_valid()
+    {
+        return Err(APIError::ValidationError(
+            "Memory thresholds have to be ordered grow_below <= shrink_at <= pause_at <= 1, with \
+             resume_below <= pause_at"
+                .to_string(),
+        )
+        .into_response());
+    }
+
+    let message_type = message.command.message_type();
+    state
+        .control_producer
+        .send_message(message_type, &message)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(ControlCommandResponse {
+        message_type: message_type.to_string(),
+        worker_id: message.worker_id,
+    }))
+}
+
+/// Reports which Kafka cluster the api-server's producers send to and how their recent sends
+/// went. Producers of the other services report the same through the `kafka_producer_failed_over`
+/// and `kafka_consecutive_send_failures` metrics.
+#[axum::debug_handler]
+pub async fn kafka_health_handler(
+    Extension(state): Extension<AppState>,
+) -> Json<KafkaHealthResponse> {
+    let failover = &state.config.kafka.failover;
+    let producers = [&state.kafka_client, &state.control_producer]
+        .into_iter()
+        .map(|producer| ProducerHealth {
+            topic: producer.topic(),
+            health: producer.cluster_health(),
+        })
+        .collect();
+
+    Json(KafkaHealthResponse {
+        primary_brokers: state.config.kafka.brokers.clone(),
+        secondary_brokers: failover.secondary_brokers.clone(),
+        consumers_on_secondary: failover.secondary_brokers.is_some() && failover.consume_secondary,
+        producers,
+    })
+}
+
+/// The topics the services read and write, which can't be deleted through the API
+fn pipeline_topics(kafka: &KafkaConfig) -> Vec<String> {
+    let image_topics = kafka.image_topics();
+    let mut topics = vec![
+        kafka.dataset_topic.clone(),
+        kafka.control_topic.clone(),
+        kafka.manifest_topic.clone(),
+        kafka.events_topic.clone(),
+        kafka.migration_topic.clone(),
+    ];
+    topics.extend(image_topics.iter().cloned());
+    if kafka.priority.dedicated_topics {
+        for topic in std::iter::once(&kafka.dataset_topic).chain(&image_topics) {
+            for priority in [Priority::High, Priority::Low] {
+                topics.push(priority.topic(topic));
+            }
+        }
+    }
+    let dead_letters: Vec<String> = topics
+        .iter()
+        .map(|topic| format!("{}{}", topic, kafka.poison_pill.dead_letter_suffix))
+        .collect();
+    topics.extend(dead_letters);
+    topics
+}
+
+/// Runs a blocking call against the brokers off the async runtime
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, ProcessorError> + Send + 'static,
+) -> Result<T, Response> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| APIError::SendTaskError(format!("Join error: {}", e)).into_response())?
+        .map_err(|e| APIError::from(e).into_response())
+}
+
+/// Lists the topics of the cluster with their partition counts and, given a `group`, the lag of
+/// that consumer group on each of them.
+///
+/// # Returns
+/// - `200 OK` with every topic, Kafka's internal topics left out.
+/// - `500 Internal Server Error` if the brokers can't be reached.
+#[axum::debug_handler]
+pub async fn list_topics_handler(
+    Extension(state): Extension<AppState>,
+    Query(params): Query<TopicListParams>,
+) -> Result<Json<TopicListResponse>, Response> {
+    let brokers = state.config.kafka.brokers.clone();
+    let group = params.group.clone();
+
+    let topics = blocking(move || {
+        let descriptions = KafkaAdmin::new(&brokers).describe_topics(None)?;
+        let mut topics = Vec::with_capacity(descriptions.len());
+        for topic in descriptions {
+            let partition_lag = match &group {
+                Some(group) => consumer_group_lag(&brokers, group, &topic.name)
+                    .map_err(|e| ProcessorError::kafka(e, true))?,
+                None => Vec::new(),
+            };
+            topics.push(TopicSummary {
+                lag: group.as_ref().map(|_| total_lag(&partition_lag)),
+                name: topic.name,
+                partitions: topic.partitions,
+                replication: topic.replication,
+                partition_lag,
+            });
+        }
+        Ok(topics)
+    })
+    .await?;
+
+    Ok(Json(TopicListResponse {
+        group: params.group,
+        topics,
+    }))
+}
+
+/// Creates a topic, e.g. `{"name": "thumbnails", "partitions": 6, "replication": 3,
+/// "config": {"retention.ms": "86400000"}}`.
+///
+/// # Returns
+/// - `201 Created` with the topic as the brokers describe it.
+/// - `422 Unprocessable Entity` if a setting is invalid or the topic already exists.
+/// - `500 Internal Server Error` if the brokers can't be reached.
+#[axum::debug_handler]
+pub async fn create_topic_handler(
+    Extension(state): Extension<AppState>,
+    Json(spec): Json<TopicSpec>,
+) -> Result<(StatusCode, Json<TopicDescription>), Response> {
+    let mut errors = Vec::new();
+    let valid_name = spec
+        .name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if spec.name.is_empty() || spec.name.len() > MAX_TOPIC_NAME_LEN || !valid_name {
+        errors.push(FieldError::new(
+            "name",
+            format!(
+                "A topic name has 1 to {} letters, digits, '.', '_' or '-'",
+                MAX_TOPIC_NAME_LEN
+            ),
+        ));
+    }
+    if spec.name.starts_with("__") {
+        errors.push(FieldError::new("name", "Names starting with '__' are Kafka's own"));
+    }
+    if spec.partitions < 1 {
+        errors.push(FieldError::new("partitions", "A topic needs at least one partition"));
+    }
+    if spec.replication < 1 {
+        errors.push(FieldError::new("replication", "A topic needs at least one replica"));
+    }
+    if !errors.is_empty() {
+        return Err(APIError::InvalidFields(errors).into_response());
+    }
+
+    KafkaAdmin::new(&state.config.kafka.brokers)
+        .create_topic_with(&spec)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    tracing::info!(topic = %spec.name, partitions = spec.partitions, "Created topic");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(TopicDescription {
+            name: spec.name,
+            partitions: spec.partitions,
+            replication: spec.replication as usize,
+        }),
+    ))
+}
+
+/// Deletes a topic and every message on it. The topics the services use are refused, they would
+/// be recreated empty by the next api-server start with work lost in between.
+///
+/// # Returns
+/// - `204 No Content` once the topic is deleted.
+/// - `404 Not Found` if the topic doesn't exist.
+/// - `422 Unprocessable Entity` for a topic the services use.
+#[axum::debug_handler]
+pub async fn delete_topic_handler(
+    Extension(state): Extension<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, Response> {
+    if pipeline_topics(&state.config.kafka).contains(&name) {
+        return Err(APIError::ValidationError(format!(
+            "{} is used by the pipeline and can't be deleted",
+            name
+        ))
+        .into_response());
+    }
+
+    KafkaAdmin::new(&state.config.kafka.brokers)
+        .delete_topic(&name)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    tracing::info!(topic = %name, "Deleted topic");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Grows a topic to the given number of partitions. Messages sent afterwards can hash to other
+/// partitions than the earlier messages of their key, so only grow topics whose consumers don't
+/// rely on the order of a key across the change.
+///
+/// # Returns
+/// - `200 OK` with the topic as the brokers describe it afterwards.
+/// - `404 Not Found` if the topic doesn't exist.
+/// - `422 Unprocessable Entity` if the topic already has as many partitions or more.
+#[axum::debug_handler]
+pub async fn alter_partitions_handler(
+    Extension(state): Extension<AppState>,
+    Path(name): Path<String>,
+    Json(request): Json<AlterPartitionsRequest>,
+) -> Result<Json<TopicDescription>, Response> {
+    let brokers = state.config.kafka.brokers.clone();
+    let topic = name.clone();
+    let current = blocking(move || KafkaAdmin::new(&brokers).describe_topics(Some(&topic)))
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            APIError::NotFoundError(format!("Topic {} does not exist", name)).into_response()
+        })?;
+    if request.partitions <= current.partitions as usize {
+        return Err(APIError::ValidationError(format!(
+            "{} already has {} partitions, Kafka can only add partitions",
+            name, current.partitions
+        ))
+        .into_response());
+    }
+
+    KafkaAdmin::new(&state.config.kafka.brokers)
+        .alter_partitions(&name, request.partitions)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    tracing::info!(
+        topic = %name,
+        from = current.partitions,
+        to = request.partitions,
+        "Added partitions to topic"
+    );
+
+    Ok(Json(TopicDescription {
+        partitions: request.partitions as i32,
+        ..current
+    }))
+}
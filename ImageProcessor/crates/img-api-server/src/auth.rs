@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use common::{
+    config::AuthConfig,
+    tenancy::{TenantStatus, hash_api_key},
+};
+use db_utils::types::DBClient;
+
+use crate::errors::{CatalogError, ErrorCode};
+use crate::utils::APIError;
+
+// ============================================================================
+// AUTHENTICATION
+// Every request is mapped to the caller its `X-API-Key` header belongs to
+// before it reaches a handler, and the caller is handed to the handlers as an
+// extension. Tenants only see their own batches and objects, see
+// `common::tenancy`. Without any configured key the server stays open and
+// every request is anonymous. Keys that aren't in the config are looked up
+// among the managed keys of the onboarded tenants, when they are enabled.
+// ============================================================================
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Who made a request
+#[derive(Clone, Debug)]
+pub enum Caller {
+    Anonymous, // Authentication is disabled
+    Tenant(String),
+    Admin,
+}
+
+impl Caller {
+    /// The tenant the caller's jobs and objects belong to
+    pub fn tenant_id(&self) -> Option<&str> {
+        match self {
+            Caller::Tenant(tenant_id) => Some(tenant_id),
+            Caller::Anonymous | Caller::Admin => None,
+        }
+    }
+
+    /// Whether the caller may see the batches and objects of the given tenant
+    pub fn can_access(&self, tenant_id: Option<&str>) -> bool {
+        match self {
+            Caller::Tenant(own) => tenant_id == Some(own.as_str()),
+            Caller::Anonymous | Caller::Admin => true,
+        }
+    }
+}
+
+/// What the API keys of the requests are checked against
+#[derive(Clone)]
+pub struct AuthState {
+    pub config: Arc<AuthConfig>,
+    pub db: Arc<DBClient>, // Holds the managed keys, see `AuthConfig::managed_keys`
+}
+
+/// Middleware resolving the API key of the request into its caller
+pub async fn authenticate(
+    State(auth): State<AuthState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    // Read before awaiting, the request itself can't be held across the lookup
+    let key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let caller = match resolve_caller(&auth, key).await {
+        Ok(caller) => caller,
+        Err(e) => return e.into_response(),
+    };
+
+    request.extensions_mut().insert(caller);
+    next.run(request).await
+}
+
+async fn resolve_caller(state: &AuthState, key: Option<String>) -> Result<Caller, APIError> {
+    let auth = &state.config;
+    if !auth.enabled() {
+        return Ok(Caller::Anonymous);
+    }
+
+    let key = key
+        .ok_or_else(|| APIError::Catalogued(CatalogError::new(ErrorCode::MissingApiKey)))?;
+
+    if auth.admin_keys.contains(&key) {
+        return Ok(Caller::Admin);
+    }
+    if let Some(tenant_id) = auth.api_keys.get(&key) {
+        return Ok(Caller::Tenant(tenant_id.clone()));
+    }
+    match auth.managed_keys {
+        true => resolve_managed_key(&state.db, &key).await,
+        false => Err(APIError::Catalogued(CatalogError::new(ErrorCode::UnknownApiKey))),
+    }
+}
+
+/// The tenant of a managed key. Revoked and expired keys are unknown, the keys of a suspended
+/// tenant are turned away.
+async fn resolve_managed_key(db: &DBClient, key: &str) -> Result<Caller, APIError> {
+    let unknown = || APIError::Catalogued(CatalogError::new(ErrorCode::UnknownApiKey));
+    let managed = db
+        .find_tenant_api_key(&hash_api_key(key))
+        .await?
+        .filter(|managed| managed.is_valid(Utc::now()))
+        .ok_or_else(unknown)?;
+    let tenant = db
+        .get_tenant(&managed.tenant_id)
+        .await?
+        .ok_or_else(unknown)?;
+
+    match tenant.status {
+        TenantStatus::Active => Ok(Caller::Tenant(tenant.tenant_id)),
+        TenantStatus::Suspended => Err(APIError::Catalogued(
+            CatalogError::new(ErrorCode::TenantSuspended).with("tenant_id", tenant.tenant_id),
+        )),
+    }
+}
+
+/// Middleware turning tenants away from the admin endpoints
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    match request.extensions().get::<Caller>() {
+        Some(Caller::Tenant(_)) | None => {
+            APIError::Catalogued(CatalogError::new(ErrorCode::AdminKeyRequired)).into_response()
+        }
+        Some(Caller::Anonymous | Caller::Admin) => next.run(request).await,
+    }
+}
@@ -0,0 +1,1128 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    Extension,
+    body::Bytes,
+    extract::{Path, Query},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::Utc;
+use common::{
+    DatasetProcessingJob, DatasetProcessingTask, ImageTask, approval::StageApproval,
+    error::ProcessorError, events::BatchEventKind, failures::FailureKind,
+    keys::{results_archive_key, results_prefix},
+    lifecycle::BatchState, operations_name, previews::previews_prefix, tenancy::tenant_key,
+};
+use db_utils::types::{
+    BatchSnapshot, ByteTotals, DBDatasetProcessingJob, DBDatasetTask, DBImageTask, Pagination,
+    ResultsRetention, StatusCounts, TaskStatus,
+};
+use storage::{copy_prefix, delete_prefix, restore_prefix};
+
+use crate::auth::Caller;
+use crate::dispatch_job;
+use crate::sweep::set_parameter;
+use crate::utils::{
+    APIError, AppState, BatchAnnotationsResponse, BatchCancelResponse, BatchCloneRequest,
+    BatchCloneResponse, BatchDeleteResponse, BatchFailureSummaryResponse, BatchFailuresResponse,
+    BatchImageTasksResponse, BatchMetadataResponse, BatchPrefixStatusResponse, BatchRetryRequest,
+    BatchRetryResponse, BatchRollbackResponse, BatchSnapshotResponse, BatchStatsResponse,
+    BatchStatusResponse, FailureGroupSummary, FailureParams, ImageFailure, ImageMetadataEntry,
+    ImageTaskEntry, ImageTaskParams, MetadataParams, PrefixStatus, RetentionStatus, RollbackParams,
+    StageBytes, StageReviewRequest, StageReviewResponse, StageStatus,
+};
+
+// Image tasks listed per page unless asked for fewer, and at most
+const DEFAULT_IMAGE_TASKS_PAGE: u64 = 100;
+const MAX_IMAGE_TASKS_PAGE: u64 = 1000;
+
+pub(crate) fn percentage(part: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        total => part as f64 * 100.0 / total as f64,
+    }
+}
+
+/// Looks up a batch the caller may see, the batches of other tenants are reported as missing
+pub(crate) async fn find_batch(
+    state: &AppState,
+    caller: &Caller,
+    batch_id: &uuid::Uuid,
+) -> Result<DBDatasetProcessingJob, Response> {
+    state
+        .db
+        .get_batch(batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .filter(|batch| caller.can_access(batch.tenant_id.as_deref()))
+        .ok_or_else(|| APIError::batch_not_found(batch_id).into_response())
+}
+
+/// Replaces the user supplied annotations on a batch.
+///
+/// Annotations are arbitrary JSON values keyed by name (experiment id, git commit, notes, ...)
+/// and are stored on the batch document so pipeline runs can be correlated with ML experiments.
+///
+/// # Returns
+/// - `200 OK` with the batch id and its new annotations.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn put_batch_annotations(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Json(annotations): Json<HashMap<String, serde_json::Value>>,
+) -> Result<Json<BatchAnnotationsResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+    let batch = state
+        .db
+        .set_batch_annotations(&batch_id, &annotations)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .ok_or_else(|| APIError::batch_not_found(&batch_id).into_response())?;
+
+    Ok(Json(BatchAnnotationsResponse {
+        batch_id: batch.batch_id,
+        annotations: batch.annotations,
+    }))
+}
+
+/// Takes a copy-based snapshot of the batch's results prefix.
+///
+/// The objects are copied to `snapshots/{batch_id}/{snapshot_id}/` below the prefix of the batch's
+/// tenant, and the snapshot is recorded on the batch document so it can later be restored with
+/// the rollback endpoint.
+#[axum::debug_handler]
+pub async fn create_batch_snapshot(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchSnapshotResponse>, Response> {
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+    let tenant_id = batch.tenant_id.as_deref();
+
+    let snapshot_id = uuid::Uuid::new_v4();
+    let prefix = tenant_key(tenant_id, &format!("snapshots/{}/{}/", batch_id, snapshot_id));
+
+    let results = results_prefix(tenant_id, &batch_id);
+    let object_count = copy_prefix(state.storage.as_ref(), &results, &prefix)
+        .await
+        .map_err(|e| APIError::from(e).into_response())? as u64;
+
+    let snapshot = BatchSnapshot {
+        snapshot_id,
+        prefix,
+        object_count,
+        time_created: Utc::now(),
+    };
+    state
+        .db
+        .add_batch_snapshot(&batch_id, &snapshot)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(BatchSnapshotResponse {
+        batch_id,
+        snapshot_id,
+        object_count,
+    }))
+}
+
+/// Restores the batch's results prefix from a previously taken snapshot.
+///
+/// The snapshot is copied back first, then whatever else is under the results prefix is removed,
+/// so objects written by a bad reprocess don't linger next to the restored ones and a failed
+/// rollback never loses results the snapshot doesn't have.
+#[axum::debug_handler]
+pub async fn rollback_batch(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Query(params): Query<RollbackParams>,
+) -> Result<Json<BatchRollbackResponse>, Response> {
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+
+    let snapshot = batch
+        .snapshots
+        .iter()
+        .find(|snapshot| snapshot.snapshot_id == params.to)
+        .ok_or_else(|| {
+            APIError::NotFoundError(format!(
+                "Snapshot {} does not exist for batch {}",
+                params.to, batch_id
+            ))
+            .into_response()
+        })?;
+
+    let results = results_prefix(batch.tenant_id.as_deref(), &batch_id);
+    let (objects_restored, objects_removed) =
+        restore_prefix(state.storage.as_ref(), &snapshot.prefix, &results)
+            .await
+            .map_err(|e| APIError::from(e).into_response())?;
+
+    // The download proxy rebuilds the archive from the restored results
+    state
+        .storage
+        .delete_objects(&[results_archive_key(batch.tenant_id.as_deref(), &batch_id)])
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(BatchRollbackResponse {
+        batch_id,
+        snapshot_id: snapshot.snapshot_id,
+        objects_removed: objects_removed as u64,
+        objects_restored: objects_restored as u64,
+    }))
+}
+
+/// Cancels a batch and every task of it that hasn't finished.
+///
+/// Decomposers and workers check the batch before handling its tasks, so work that is already
+/// queued in Kafka is dropped instead of processed.
+///
+/// # Returns
+/// - `200 OK` with the number of dataset and image tasks that were cancelled.
+/// - `404 Not Found` if no batch exists with the given id.
+/// - `422 Unprocessable Entity` if the batch already finished.
+#[axum::debug_handler]
+pub async fn cancel_batch(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchCancelResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+    let (batch, cancelled) = state
+        .db
+        .cancel_batch(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    let done = BatchEventKind::BatchDone { state: batch.state };
+    state
+        .event_publisher
+        .publish(batch_id, batch.tenant_id.as_deref(), done)
+        .await;
+
+    Ok(Json(BatchCancelResponse {
+        batch_id,
+        state: batch.state,
+        dataset_tasks_cancelled: cancelled.dataset_tasks,
+        image_tasks_cancelled: cancelled.image_tasks,
+    }))
+}
+
+/// Deletes a finished batch with its documents and the objects it wrote.
+///
+/// The objects under the batch's own prefixes always go: its reports and manifests, thumbnails,
+/// snapshots and results archive. The upload, the images extracted from it and the stage outputs
+/// are written under the prefix of the dataset, so they only go if no other batch was submitted
+/// for the same dataset, see `db_utils::deletion`. Objects are deleted before documents, so a
+/// deletion that failed halfway can be sent again.
+///
+/// # Returns
+/// - `200 OK` with the number of documents and objects deleted.
+/// - `404 Not Found` if no batch exists with the given id.
+/// - `422 Unprocessable Entity` if the batch hasn't finished, it has to be cancelled first.
+#[axum::debug_handler]
+pub async fn delete_batch(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchDeleteResponse>, Response> {
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+    if !batch.state.is_final() {
+        return Err(APIError::ValidationError(format!(
+            "A {:?} batch has to be cancelled before it can be deleted",
+            batch.state
+        ))
+        .into_response());
+    }
+
+    let db_error = |e: ProcessorError| APIError::from(e).into_response();
+    let tenant_id = batch.tenant_id.as_deref();
+    let storage = state.storage.as_ref();
+    let task_ids: Vec<uuid::Uuid> = state
+        .db
+        .get_dataset_tasks(&batch_id)
+        .await
+        .map_err(db_error)?
+        .iter()
+        .map(|task| task.task_id)
+        .collect();
+
+    let mut prefixes = vec![
+        results_prefix(tenant_id, &batch_id),
+        previews_prefix(tenant_id, &batch_id),
+    ];
+    prefixes.extend(batch.snapshots.iter().map(|snapshot| snapshot.prefix.clone()));
+    let mut keys = Vec::new();
+    let dataset_shared = state
+        .db
+        .dataset_shared(&batch.dataset_key, &batch_id)
+        .await
+        .map_err(db_error)?;
+    if !dataset_shared {
+        keys.extend(state.db.get_extracted_keys(&task_ids).await.map_err(db_error)?);
+        keys.extend(state.db.get_output_keys(&task_ids).await.map_err(db_error)?);
+        keys.extend(batch.sidecars.iter().map(|sidecar| sidecar.key.clone()));
+        // A prefix dataset is every image below it
+        match batch.dataset_key.ends_with('/') {
+            true => prefixes.push(batch.dataset_key.clone()),
+            false => keys.push(batch.dataset_key.clone()),
+        }
+    }
+
+    let mut objects_deleted = keys.len() as u64;
+    for prefix in &prefixes {
+        objects_deleted += delete_prefix(storage, prefix).await.map_err(db_error)? as u64;
+    }
+    keys.push(results_archive_key(tenant_id, &batch_id));
+    storage.delete_objects(&keys).await.map_err(db_error)?;
+
+    let deleted = state
+        .db
+        .delete_batch_documents(&batch_id, &task_ids)
+        .await
+        .map_err(db_error)?;
+
+    Ok(Json(BatchDeleteResponse {
+        batch_id,
+        documents_deleted: deleted.batches
+            + deleted.dataset_tasks
+            + deleted.image_tasks
+            + deleted.other,
+        objects_deleted,
+        dataset_shared,
+    }))
+}
+
+/// Approves the outputs of a stage held for review, releasing the stages after it.
+///
+/// # Returns
+/// - `200 OK` with the recorded decision.
+/// - `404 Not Found` if no batch exists with the given id or it has no such stage.
+/// - `422 Unprocessable Entity` if the stage doesn't await a review, see `review_stage`.
+#[axum::debug_handler]
+pub async fn approve_stage(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path((batch_id, stage)): Path<(uuid::Uuid, u32)>,
+    Json(request): Json<StageReviewRequest>,
+) -> Result<Json<StageReviewResponse>, Response> {
+    review_stage(&state, &caller, batch_id, stage, request, true).await
+}
+
+/// Rejects the outputs of a stage holding them for review. The stage fails, so the stages after
+/// it are skipped and the batch fails.
+///
+/// # Returns
+/// - `200 OK` with the recorded decision.
+/// - `404 Not Found` if no batch exists with the given id or it has no such stage.
+/// - `422 Unprocessable Entity` if the stage doesn't await a review, see `review_stage`.
+#[axum::debug_handler]
+pub async fn reject_stage(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path((batch_id, stage)): Path<(uuid::Uuid, u32)>,
+    Json(request): Json<StageReviewRequest>,
+) -> Result<Json<StageReviewResponse>, Response> {
+    review_stage(&state, &caller, batch_id, stage, request, false).await
+}
+
+/// Records the decision on a stage, see `common::approval`. Only a stage that requires approval
+/// and succeeded can be reviewed, once, while its batch is still running.
+async fn review_stage(
+    state: &AppState,
+    caller: &Caller,
+    batch_id: uuid::Uuid,
+    stage: u32,
+    request: StageReviewRequest,
+    approved: bool,
+) -> Result<Json<StageReviewResponse>, Response> {
+    let invalid = |message: String| APIError::ValidationError(message).into_response();
+    let batch = find_batch(state, caller, &batch_id).await?;
+    if batch.state.is_final() {
+        return Err(invalid(format!("A {:?} batch can't be reviewed", batch.state)));
+    }
+    if request.reviewer.trim().is_empty() {
+        return Err(invalid("A review needs the name of its reviewer".to_string()));
+    }
+
+    let db_error = |e: ProcessorError| APIError::from(e).into_response();
+    let task = state
+        .db
+        .get_dataset_tasks(&batch_id)
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .find(|task| task.stage == stage)
+        .ok_or_else(|| {
+            APIError::NotFoundError(format!("Batch {} has no stage {}", batch_id, stage))
+                .into_response()
+        })?;
+    if !task.requires_approval {
+        return Err(invalid(format!("Stage {} doesn't require approval", stage)));
+    }
+    if let Some(approval) = &task.approval {
+        let decision = match approval.approved {
+            true => "approved",
+            false => "rejected",
+        };
+        return Err(invalid(format!(
+            "Stage {} was already {} by {}",
+            stage, decision, approval.reviewer
+        )));
+    }
+    if !matches!(task.status, TaskStatus::Success) {
+        return Err(invalid(format!(
+            "Stage {} is {:?}, only a stage that succeeded can be reviewed",
+            stage, task.status
+        )));
+    }
+
+    let approval = StageApproval {
+        approved,
+        reviewer: request.reviewer.trim().to_string(),
+        comment: request.comment,
+        time_decided: Utc::now(),
+    };
+    // Only fails to match if another review was recorded since the task was read
+    let task = state
+        .db
+        .decide_stage_approval(&task.task_id, &approval)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| invalid(format!("Stage {} was reviewed meanwhile", stage)))?;
+
+    let tenant_id = batch.tenant_id.as_deref();
+    let reviewed = BatchEventKind::StageReviewed {
+        task_id: task.task_id,
+        stage,
+        approved,
+    };
+    state.event_publisher.publish(batch_id, tenant_id, reviewed).await;
+    // The scheduler releases the stages after an approved one and skips those after a rejected
+    // one, the batch fails right away like after any failed stage
+    if !approved {
+        let batch = state
+            .db
+            .transition_batch(&batch_id, BatchState::Failed)
+            .await
+            .map_err(db_error)?;
+        let done = BatchEventKind::BatchDone { state: batch.state };
+        state.event_publisher.publish(batch_id, tenant_id, done).await;
+    }
+
+    Ok(Json(StageReviewResponse {
+        batch_id,
+        stage,
+        task_id: task.task_id,
+        status: task.status,
+        approval,
+    }))
+}
+
+/// Runs the failed tasks of a batch again, or only the given ones of them.
+///
+/// Failed image tasks are requeued to the workers and the stage they belong to is completed once
+/// more when they finish. Stages that failed while splitting their input are decomposed again.
+/// A Failed batch is reopened at the earliest retried stage, and the stages skipped because of
+/// the failure run after it as usual. The body is optional, `{"task_ids": [...]}` limits the
+/// retry to the given image or dataset tasks.
+///
+/// # Returns
+/// - `200 OK` with the state of the batch and the number of tasks that were requeued.
+/// - `404 Not Found` if no batch exists with the given id.
+/// - `422 Unprocessable Entity` if the batch completed, was cancelled or timed out.
+#[axum::debug_handler]
+pub async fn retry_batch(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    body: Bytes,
+) -> Result<Json<BatchRetryResponse>, Response> {
+    let request: BatchRetryRequest = match body.is_empty() {
+        true => BatchRetryRequest::default(),
+        false => serde_json::from_slice(&body).map_err(|e| {
+            APIError::ValidationError(format!("Invalid retry request: {}", e)).into_response()
+        })?,
+    };
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+    if batch.state.is_final() && batch.state != BatchState::Failed {
+        return Err(APIError::ValidationError(format!(
+            "A {:?} batch can't be retried",
+            batch.state
+        ))
+        .into_response());
+    }
+
+    let db_error = |e: ProcessorError| APIError::from(e).into_response();
+    let task_ids = request.task_ids.as_deref();
+    let dataset_tasks = state
+        .db
+        .get_dataset_tasks(&batch_id)
+        .await
+        .map_err(db_error)?;
+    let failed = |task_id: &uuid::Uuid| {
+        dataset_tasks.iter().any(|task| {
+            task.task_id == *task_id && matches!(task.status, TaskStatus::Failure)
+        })
+    };
+
+    // Stages that failed before creating their image tasks. Skipped stages have none either,
+    // they run again once the stage they depend on succeeds.
+    let redecompose: Vec<&DBDatasetTask> = dataset_tasks
+        .iter()
+        .filter(|task| {
+            matches!(task.status, TaskStatus::Failure)
+                && task.image_count.is_none()
+                && !task.depends_on.iter().any(failed)
+                && task_ids.is_none_or(|ids| ids.contains(&task.task_id))
+        })
+        .collect();
+
+    // Images that failed before reaching a worker never made it to storage
+    let (failed_images, not_started): (Vec<DBImageTask>, Vec<DBImageTask>) = state
+        .db
+        .get_failed_image_tasks(&batch_id, task_ids)
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .partition(|task| task.time_started.is_some());
+    let retried_stages: Vec<&DBDatasetTask> = dataset_tasks
+        .iter()
+        .filter(|task| {
+            failed_images
+                .iter()
+                .any(|image| image.dataset_id == task.task_id)
+        })
+        .collect();
+
+    let Some(first_stage) = redecompose
+        .iter()
+        .chain(&retried_stages)
+        .min_by_key(|task| task.stage)
+    else {
+        return Ok(Json(BatchRetryResponse {
+            batch_id,
+            state: batch.state,
+            dataset_tasks_requeued: 0,
+            image_tasks_requeued: 0,
+            image_tasks_not_retried: not_started.len() as u64,
+        }));
+    };
+
+    let mut batch_state = batch.state;
+    if batch.state == BatchState::Failed {
+        let next = match first_stage.stage {
+            0 if first_stage.image_count.is_none() => BatchState::Decomposing,
+            stage => BatchState::Processing { stage },
+        };
+        batch_state = state
+            .db
+            .reopen_batch(&batch_id, next)
+            .await
+            .map_err(db_error)?
+            .state;
+    }
+
+    // Every image goes back before its stage, or the scheduler fails the stage again right away
+    let mut requeue = Vec::new();
+    for image in &failed_images {
+        let Some(task_id) = image.task_id else {
+            continue;
+        };
+        if let Some(image) = state
+            .db
+            .reset_failed_image_task(&task_id)
+            .await
+            .map_err(db_error)?
+        {
+            requeue.push(image);
+        }
+    }
+    for task in &retried_stages {
+        state
+            .db
+            .reset_failed_dataset_task(&task.task_id, TaskStatus::Running)
+            .await
+            .map_err(db_error)?;
+    }
+    for task in &redecompose {
+        state
+            .db
+            .reset_failed_dataset_task(&task.task_id, TaskStatus::Ready)
+            .await
+            .map_err(db_error)?;
+    }
+    for task in &dataset_tasks {
+        let skipped = matches!(task.status, TaskStatus::Failure)
+            && task.image_count.is_none()
+            && task.stage > first_stage.stage
+            && !redecompose
+                .iter()
+                .any(|retried| retried.task_id == task.task_id);
+        if skipped {
+            state
+                .db
+                .reset_failed_dataset_task(&task.task_id, TaskStatus::Waiting)
+                .await
+                .map_err(db_error)?;
+        }
+    }
+
+    let requeued_ids: Vec<uuid::Uuid> = requeue.iter().filter_map(|image| image.task_id).collect();
+    state
+        .db
+        .clear_image_failures(&requeued_ids)
+        .await
+        .map_err(db_error)?;
+
+    let mut image_tasks_requeued = 0;
+    for image in &requeue {
+        match state
+            .image_producer
+            .send_image_task(ImageTask::from(image))
+            .await
+        {
+            Ok(_) => image_tasks_requeued += 1,
+            // Failed again, so the stage still completes once its other images finish
+            Err(e) => {
+                let Some(task_id) = image.task_id else {
+                    continue;
+                };
+                if let Some(failed) = state
+                    .db
+                    .mark_image_task_failed_before_start(
+                        &task_id,
+                        &e.to_string(),
+                        FailureKind::Other,
+                        None,
+                    )
+                    .await
+                    .map_err(db_error)?
+                {
+                    state
+                        .db
+                        .record_image_failure(&failed)
+                        .await
+                        .map_err(db_error)?;
+                }
+            }
+        }
+    }
+
+    let mut dataset_tasks_requeued = 0;
+    for task in &redecompose {
+        if let Err(e) = state
+            .kafka_client
+            .send_dataset_task(&DatasetProcessingTask::from(*task))
+            .await
+        {
+            // Nothing would complete the reopened stage, so the batch fails again
+            state
+                .db
+                .transition_dataset_task(&task.task_id, TaskStatus::Ready, TaskStatus::Failure)
+                .await
+                .map_err(db_error)?;
+            state
+                .db
+                .transition_batch(&batch_id, BatchState::Failed)
+                .await
+                .map_err(db_error)?;
+            return Err(APIError::from(e).into_response());
+        }
+        dataset_tasks_requeued += 1;
+    }
+
+    tracing::info!(
+        %batch_id,
+        stage = first_stage.stage,
+        dataset_tasks_requeued,
+        image_tasks_requeued,
+        "Retrying the failed tasks of the batch"
+    );
+    Ok(Json(BatchRetryResponse {
+        batch_id,
+        state: batch_state,
+        dataset_tasks_requeued,
+        image_tasks_requeued,
+        image_tasks_not_retried: not_started.len() as u64,
+    }))
+}
+
+/// Submits a new batch over the dataset of an existing one, with the same pipeline and output
+/// options except for the given overrides, for re-running a batch with one knob changed.
+///
+/// `operations` replaces the pipeline, then every entry of `parameters` overwrites one parameter
+/// of one stage. The new batch records the source as its parent, and both show up in each
+/// other's status.
+///
+/// # Returns
+/// - `200 OK` with the new batch id and its pipeline.
+/// - `404 Not Found` if no batch exists with the given id, or its dataset is gone.
+/// - `422 Unprocessable Entity` if an override doesn't apply or the resulting pipeline is invalid.
+#[axum::debug_handler]
+pub async fn clone_batch(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Json(request): Json<BatchCloneRequest>,
+) -> Result<Json<BatchCloneResponse>, Response> {
+    let source = find_batch(&state, &caller, &batch_id).await?;
+
+    // Operations given by the request run as a chain, the graph and approvals of the source are
+    // theirs
+    let (dependencies, requires_approval) = match request.operations.is_some() {
+        true => (Vec::new(), Vec::new()),
+        false => (source.dependencies, source.requires_approval),
+    };
+    let mut operations = request.operations.unwrap_or(source.operations);
+    for param in &request.parameters {
+        let op = operations.get_mut(param.stage as usize).ok_or_else(|| {
+            APIError::ValidationError(format!("The pipeline has no stage {}", param.stage))
+                .into_response()
+        })?;
+        *op = set_parameter(op, &param.field, &param.value)
+            .map_err(|e| APIError::ValidationError(e).into_response())?;
+    }
+
+    let job = DatasetProcessingJob {
+        batch_id: None,
+        dataset_key: source.dataset_key,
+        operations: operations.clone(),
+        dependencies,
+        requires_approval,
+        preset: None, // Expanded into the operations of the source
+        pipeline: None,
+        use_local_cache: source.use_local_cache,
+        output_layout: request.output_layout.unwrap_or(source.output_layout),
+        collision_policy: request.collision_policy.unwrap_or(source.collision_policy),
+        hash_suffix: request.hash_suffix.unwrap_or(source.hash_suffix),
+        cache_scope: source.cache_scope,
+        output_format: request.output_format.or(source.output_format),
+        alpha_policy: request.alpha_policy.unwrap_or(source.alpha_policy),
+        metadata_policy: request.metadata_policy.unwrap_or(source.metadata_policy),
+        rewrite_sidecars: source.rewrite_sidecars,
+        previews: source.previews,
+        tags: request.tags.unwrap_or(source.tags),
+        tenant_id: source.tenant_id,
+        owner: source.owner,
+        notification_url: source.retention.and_then(|retention| retention.notification_url),
+        notification_preferences: source.notification_preferences,
+        priority: request.priority.unwrap_or(source.priority),
+        pipeline_mode: request.pipeline_mode.unwrap_or(source.pipeline_mode),
+    };
+    let result = dispatch_job(&state, job, None, Some(batch_id)).await?;
+
+    Ok(Json(BatchCloneResponse {
+        batch_id: result.batch_id,
+        parent_batch_id: batch_id,
+        operations,
+        task_ids: result.task_ids,
+        warnings: result.warnings,
+    }))
+}
+
+/// Reports the progress of a batch.
+///
+/// Aggregates the statuses of the batch's dataset tasks and image tasks, and computes for every
+/// stage the percentage of its images that have finished processing.
+///
+/// # Returns
+/// - `200 OK` with a `BatchStatusResponse`.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_status(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchStatusResponse>, Response> {
+    batch_status(&state, &caller, batch_id).await.map(Json)
+}
+
+/// Builds the progress report of a batch, shared by every API version
+pub(crate) async fn batch_status(
+    state: &AppState,
+    caller: &Caller,
+    batch_id: uuid::Uuid,
+) -> Result<BatchStatusResponse, Response> {
+    let batch = find_batch(state, caller, &batch_id).await?;
+    let children = state
+        .db
+        .get_child_batches(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let dataset_tasks = state
+        .db
+        .get_dataset_tasks(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    let mut image_counts = state
+        .db
+        .image_status_counts_by_dataset_task(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let mut dataset_task_counts = StatusCounts::default();
+    let mut image_task_counts = StatusCounts::default();
+    let mut stages = Vec::with_capacity(dataset_tasks.len());
+
+    for task in dataset_tasks {
+        dataset_task_counts.add(&task.status, 1);
+
+        let images = image_counts.remove(&task.task_id).unwrap_or_default();
+        image_task_counts.merge(&images);
+
+        let completion_percentage = percentage(images.success + images.failure, images.total());
+
+        stages.push(StageStatus {
+            stage: task.stage,
+            task_id: task.task_id,
+            operation: task.operation,
+            fused_operations: task.fused_operations,
+            status: task.status,
+            images,
+            completion_percentage,
+            requires_approval: task.requires_approval,
+            approval: task.approval,
+            budget_checkpoint: task.budget_checkpoint,
+            quality: task.quality.map(|quality| quality.summary),
+        });
+    }
+
+    Ok(BatchStatusResponse {
+        batch_id,
+        dataset_key: batch.dataset_key,
+        status: batch.status,
+        state: batch.state,
+        time_created: batch.time_created,
+        time_completed: batch.time_completed,
+        dataset_tasks: dataset_task_counts,
+        image_tasks: image_task_counts,
+        stages,
+        annotations: batch.annotations,
+        config_snapshots: batch.config_snapshots,
+        retention: batch.retention.map(retention_status),
+        parent_batch_id: batch.parent_batch_id,
+        child_batch_ids: children.into_iter().map(|child| child.batch_id).collect(),
+        dataset_validation: batch.dataset_validation,
+        provenance_root: batch.provenance_root,
+        batch_manifest_key: batch.batch_manifest_key,
+        quality_drifts: batch.quality_drifts,
+    })
+}
+
+fn retention_status(retention: ResultsRetention) -> RetentionStatus {
+    let now = Utc::now();
+    RetentionStatus {
+        days: retention.days,
+        expires_at: retention.expires_at,
+        days_remaining: retention
+            .expires_at
+            .map(|expires_at| (expires_at - now).num_days().max(0)),
+        expired: retention.expires_at.is_some_and(|expires_at| expires_at <= now),
+        warning_sent_at: retention.warning_sent_at,
+        results_deleted_at: retention.results_deleted_at,
+    }
+}
+
+/// Reports the bytes every stage of a batch read and wrote, and the data amplification of the
+/// stage (bytes written per byte read).
+///
+/// Only image tasks that succeeded and downloaded their input are counted, inputs handed over
+/// locally by the previous stage weren't read from storage. The result cache hits and misses of
+/// the batch are reported next to the bytes, and every stage's images are counted by status
+/// with the mean time a worker took on them.
+///
+/// # Returns
+/// - `200 OK` with a `BatchStatsResponse`, stages in pipeline order.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_stats(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchStatsResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+
+    let dataset_tasks = state
+        .db
+        .get_dataset_tasks(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    let mut stage_totals = state
+        .db
+        .stage_byte_totals(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    let cache = state
+        .db
+        .batch_cache_counts(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    let mut task_stats = state
+        .db
+        .get_batch_stats(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let mut total = ByteTotals::default();
+    let stages = dataset_tasks
+        .into_iter()
+        .map(|task| {
+            let totals = stage_totals.remove(&task.task_id).unwrap_or_default();
+            total.merge(&totals);
+            let images = task_stats.stages.remove(&task.task_id).unwrap_or_default();
+            let operations = std::iter::once(&task.operation).chain(&task.fused_operations);
+            StageBytes {
+                stage: task.stage,
+                task_id: task.task_id,
+                operation: operations_name(operations),
+                bytes: (&totals).into(),
+                avg_processing_ms: images.avg_processing_ms(),
+                images: images.images,
+            }
+        })
+        .collect();
+
+    Ok(Json(BatchStatsResponse {
+        batch_id,
+        stages,
+        total: (&total).into(),
+        cache,
+        avg_processing_ms: task_stats.total.avg_processing_ms(),
+        images: task_stats.total.images,
+    }))
+}
+
+/// Lists the image tasks of a batch a page at a time, in the order they were created, with
+/// their status and how long a worker took on the finished ones.
+///
+/// `?status=` lists only the tasks in one status, e.g. `Running` to find stuck images.
+/// `?offset=` and `?limit=` page through them, up to `MAX_IMAGE_TASKS_PAGE` per page.
+///
+/// # Returns
+/// - `200 OK` with a `BatchImageTasksResponse`, empty until the batch was decomposed.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_image_tasks(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Query(params): Query<ImageTaskParams>,
+) -> Result<Json<BatchImageTasksResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+
+    let page = Pagination {
+        skip: params.offset.unwrap_or(0),
+        limit: params
+            .limit
+            .unwrap_or(DEFAULT_IMAGE_TASKS_PAGE)
+            .min(MAX_IMAGE_TASKS_PAGE),
+    };
+    let stages: HashMap<uuid::Uuid, u32> = state
+        .db
+        .get_dataset_tasks(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .map(|task| (task.task_id, task.stage))
+        .collect();
+    let (tasks, total) = state
+        .db
+        .get_image_tasks(&batch_id, params.status, page)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let images = tasks
+        .into_iter()
+        .filter_map(|task| {
+            let operations = std::iter::once(&task.operation).chain(&task.fused_operations);
+            let processing_ms = task
+                .time_started
+                .zip(task.time_completed)
+                .map(|(started, completed)| (completed - started).num_milliseconds());
+            Some(ImageTaskEntry {
+                image_task_id: task.task_id?,
+                stage: stages.get(&task.dataset_id).copied().unwrap_or_default(),
+                operation: operations_name(operations),
+                filename: task.source_path.unwrap_or(task.s3_key),
+                status: task.status,
+                output_key: task.output_key,
+                error: task.error,
+                time_created: task.time_created,
+                time_started: task.time_started,
+                time_completed: task.time_completed,
+                processing_ms,
+            })
+        })
+        .collect();
+
+    Ok(Json(BatchImageTasksResponse {
+        batch_id,
+        total,
+        offset: page.skip,
+        limit: page.limit,
+        images,
+    }))
+}
+
+/// Lists the images of a batch that failed, with the stage they failed in and why.
+///
+/// The other images of the batch are processed regardless, so the listed files can be fixed and
+/// resubmitted on their own. `?kind=` lists only the failures of one kind, e.g. `Timeout`.
+///
+/// # Returns
+/// - `200 OK` with a `BatchFailuresResponse`, empty while no image failed.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_failures(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Query(params): Query<FailureParams>,
+) -> Result<Json<BatchFailuresResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+
+    let failures = state
+        .db
+        .get_image_failures(&batch_id, params.kind)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .map(|failure| ImageFailure {
+            filename: failure.filename,
+            stage: failure.stage,
+            operation: failure.operation,
+            kind: failure.kind,
+            error: failure.error,
+            storage_error: failure.storage_error,
+            backtrace: failure.backtrace,
+            image_task_id: failure.image_task_id,
+            time_failed: failure.time_failed,
+        })
+        .collect();
+
+    Ok(Json(BatchFailuresResponse { batch_id, failures }))
+}
+
+/// Lists what the workers read from the uploaded images of a batch, their size and the camera
+/// and orientation their EXIF names, see `common::metadata`.
+///
+/// Images are listed once their first stage ran. `?camera_make=`, `?camera_model=` and
+/// `?has_gps=` list only the images matching all of the given values.
+///
+/// # Returns
+/// - `200 OK` with a `BatchMetadataResponse`, empty until the first stage ran.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_metadata(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Query(params): Query<MetadataParams>,
+) -> Result<Json<BatchMetadataResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+
+    let images = state
+        .db
+        .get_image_metadata(
+            &batch_id,
+            params.camera_make.as_deref(),
+            params.camera_model.as_deref(),
+            params.has_gps,
+        )
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .filter_map(|task| {
+            Some(ImageMetadataEntry {
+                filename: task.source_path.unwrap_or(task.s3_key),
+                image_task_id: task.task_id?,
+                metadata: task.metadata?,
+            })
+        })
+        .collect();
+
+    Ok(Json(BatchMetadataResponse { batch_id, images }))
+}
+
+/// Counts the failed images of a batch by kind of failure, and by kind, stage and operation
+/// together, with one error and file of each group to start digging from.
+///
+/// # Returns
+/// - `200 OK` with a `BatchFailureSummaryResponse`, empty while no image failed.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_failure_summary(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchFailureSummaryResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+
+    let groups = state
+        .db
+        .get_failure_groups(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let mut by_kind = BTreeMap::new();
+    for group in &groups {
+        *by_kind.entry(group.kind.as_str()).or_default() += group.count;
+    }
+    let groups: Vec<_> = groups
+        .into_iter()
+        .map(|group| FailureGroupSummary {
+            kind: group.kind,
+            stage: group.stage,
+            operation: group.operation,
+            count: group.count,
+            sample_error: group.sample_error,
+            sample_filename: group.sample_filename,
+        })
+        .collect();
+
+    Ok(Json(BatchFailureSummaryResponse {
+        batch_id,
+        total: groups.iter().map(|group| group.count).sum(),
+        by_kind,
+        groups,
+    }))
+}
+
+/// Rolls up the progress of a batch per top-level folder of the dataset.
+///
+/// For labeled datasets laid out as `{class}/{image}` this shows which classes are failing.
+/// Images at the root of the dataset are grouped under an empty prefix.
+#[axum::debug_handler]
+pub async fn get_batch_status_by_prefix(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchPrefixStatusResponse>, Response> {
+    find_batch(&state, &caller, &batch_id).await?;
+
+    let prefixes = state
+        .db
+        .image_status_counts_by_prefix(&batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .map(|counts| {
+            let total = counts.images.total();
+            PrefixStatus {
+                completion_percentage: percentage(
+                    counts.images.success + counts.images.failure,
+                    total,
+                ),
+                failure_percentage: percentage(counts.images.failure, total),
+                prefix: counts.prefix,
+                images: counts.images,
+            }
+        })
+        .collect();
+
+    Ok(Json(BatchPrefixStatusResponse { batch_id, prefixes }))
+}
@@ -0,0 +1,38 @@
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use common::correlation;
+use tracing::Instrument;
+
+// ============================================================================
+// CORRELATION
+// Every request is handled on behalf of a correlation id, the caller's
+// `X-Correlation-Id` when it sends a valid one and a new id otherwise. The
+// messages sent for the request carry it to the other services, see
+// `common::correlation`, and the response returns it so a client can quote it
+// when following its batch through the logs.
+// ============================================================================
+
+pub const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+/// Middleware running the request in a span of its correlation id
+pub async fn correlate(request: Request, next: Next) -> Response {
+    let correlation_id = request
+        .headers()
+        .get(CORRELATION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| uuid::Uuid::parse_str(value).ok())
+        .unwrap_or_else(uuid::Uuid::new_v4);
+    let span = tracing::info_span!(
+        "request",
+        %correlation_id,
+        method = %request.method(),
+        path = request.uri().path(),
+    );
+
+    let mut response = correlation::scope(correlation_id, next.run(request))
+        .instrument(span)
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&correlation_id.to_string()) {
+        response.headers_mut().insert(CORRELATION_ID_HEADER, value);
+    }
+    response
+}
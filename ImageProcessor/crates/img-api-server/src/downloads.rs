@@ -0,0 +1,364 @@
+use std::{
+    collections::HashSet,
+    io::Write,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Extension,
+    body::Body,
+    extract::Path,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{Duration, Utc};
+use common::{
+    error::ProcessorError,
+    keys::{results_archive_key, results_prefix},
+    lifecycle::BatchState,
+};
+use db_utils::types::{ArchiveFailure, DBDownloadAudit};
+use storage::{PutOptions, StorageBackend};
+use subtle::ConstantTimeEq;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::auth::Caller;
+use crate::batch::find_batch;
+use crate::utils::{APIError, AppState};
+
+// ============================================================================
+// DOWNLOAD PROXY
+// Streams the results archive of a batch through the api-server, for clients
+// that can't be handed presigned URLs. Range requests are honoured so large
+// downloads can be resumed, and every request is audited. The archive is
+// built in the background on the first download, once per batch however many
+// clients ask for it meanwhile. A failed build is recorded on the batch and
+// reported to downloads until it is tried again.
+// ============================================================================
+
+// Seconds clients are asked to wait before asking again while the archive is built
+const ARCHIVE_RETRY_AFTER_SECS: u32 = 5;
+// Minutes after a failed build before a download builds the archive again
+const ARCHIVE_REBUILD_AFTER_MINS: i64 = 15;
+
+/// The batches whose results archive is being built
+#[derive(Default)]
+pub struct ArchiveBuilds {
+    building: Mutex<HashSet<uuid::Uuid>>,
+}
+
+impl ArchiveBuilds {
+    /// Returns `false` if the archive of the batch is already being built
+    fn start(&self, batch_id: uuid::Uuid) -> bool {
+        self.building.lock().unwrap().insert(batch_id)
+    }
+
+    fn finish(&self, batch_id: &uuid::Uuid) {
+        self.building.lock().unwrap().remove(batch_id);
+    }
+}
+
+/// What a `Range` header asks for, relative to the size of the object
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range. Headers that are malformed or ask for several ranges are
+/// ignored and the whole object is served, as RFC 9110 allows.
+fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return RangeRequest::Full;
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return RangeRequest::Full;
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        // The last `n` bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(n) => size.saturating_sub(n)..size,
+            Err(_) => return RangeRequest::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let end = match end {
+                "" => size,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.saturating_add(1).min(size),
+                    _ => return RangeRequest::Full,
+                },
+            };
+            start..end
+        }
+    };
+
+    if range.start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(range)
+}
+
+fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| presented.as_bytes().ct_eq(token.as_bytes()).into())
+}
+
+fn archive_error(e: impl std::fmt::Display) -> ProcessorError {
+    ProcessorError::Internal(format!("Failed to write results archive: {}", e))
+}
+
+/// Starts building the results archive of a batch unless a build already runs.
+///
+/// Returns `NotFound` if the batch has no results.
+async fn start_archive_build(
+    state: &AppState,
+    tenant_id: Option<&str>,
+    batch_id: uuid::Uuid,
+) -> Result<(), ProcessorError> {
+    let prefix = results_prefix(tenant_id, &batch_id);
+    let keys = state.storage.list(&prefix).await?;
+    if keys.is_empty() {
+        return Err(ProcessorError::NotFound(format!(
+            "Batch {} has no results",
+            batch_id
+        )));
+    }
+    if !state.archive_builds.start(batch_id) {
+        return Ok(());
+    }
+
+    let storage = Arc::clone(&state.storage);
+    let db = Arc::clone(&state.db);
+    let builds = Arc::clone(&state.archive_builds);
+    let archive_key = results_archive_key(tenant_id, &batch_id);
+    tokio::spawn(async move {
+        let built = build_results_archive(storage.as_ref(), &prefix, keys, &archive_key).await;
+        if let Err(e) = built {
+            tracing::error!(batch_id = %batch_id, error = %e, "Failed to build results archive");
+            let failure = ArchiveFailure {
+                error: e.to_string(),
+                failed_at: Utc::now(),
+            };
+            if let Err(e) = db.set_archive_failure(&batch_id, &failure).await {
+                tracing::error!(
+                    batch_id = %batch_id,
+                    error = %e,
+                    "Failed to record archive failure"
+                );
+            }
+        }
+        // Only once the failure is recorded, or a download could wait for the next build
+        builds.finish(&batch_id);
+    });
+    Ok(())
+}
+
+/// Zips the objects under the batch's results prefix and stores the archive.
+///
+/// Images are already compressed, so entries are stored as they are. The archive is spooled to
+/// a temporary file, written off the async runtime, and uploaded from it.
+async fn build_results_archive(
+    storage: &dyn StorageBackend,
+    prefix: &str,
+    keys: Vec<String>,
+    archive_key: &str,
+) -> Result<(), ProcessorError> {
+    let file = tempfile::NamedTempFile::new()
+        .map_err(|e| ProcessorError::Internal(format!("Failed to create temp file: {}", e)))?;
+    let mut archive = ZipWriter::new(file);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+
+    for key in keys {
+        let bytes = storage.get_object(&key).await?;
+        let name = key.strip_prefix(prefix).unwrap_or(&key).to_string();
+        archive = tokio::task::spawn_blocking(move || {
+            archive.start_file(name, options).map_err(archive_error)?;
+            archive.write_all(&bytes).map_err(archive_error)?;
+            Ok::<_, ProcessorError>(archive)
+        })
+        .await
+        .map_err(archive_error)??;
+    }
+    let file = tokio::task::spawn_blocking(move || archive.finish().map_err(archive_error))
+        .await
+        .map_err(archive_error)??;
+
+    let options = PutOptions {
+        content_type: Some("application/zip".to_string()),
+        ..Default::default()
+    };
+    storage.put_file(archive_key, file.path(), &options).await
+}
+
+/// Streams the results archive of a completed batch. The first download starts building it.
+///
+/// Requires the proxy token as a bearer token, and the API key of the batch's tenant once
+/// authentication is enabled. A single `Range` is honoured, so interrupted downloads can be
+/// resumed.
+///
+/// # Returns
+/// - `200 OK` with the whole archive, or `206 Partial Content` with the requested range.
+/// - `202 Accepted` with a `Retry-After` header while the archive is built.
+/// - `401 Unauthorized` if the token is missing or wrong.
+/// - `404 Not Found` if the batch doesn't exist or has no results.
+/// - `416 Range Not Satisfiable` if the range starts past the end of the archive.
+/// - `422 Unprocessable Entity` if the batch hasn't completed.
+/// - `500 Internal Server Error` if building the archive failed recently, it is built again once
+///   the response's `retry_at` has passed.
+#[axum::debug_handler]
+pub async fn download_results(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let token = state.config.downloads.proxy_token.as_deref().unwrap_or("");
+    if token.is_empty() || !is_authorized(&headers, token) {
+        return Err(
+            APIError::Unauthorized("A valid download token is required".to_string())
+                .into_response(),
+        );
+    }
+
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+    if batch.state != BatchState::Completed {
+        return Err(APIError::ValidationError(format!(
+            "Batch {} is {:?}, results can only be downloaded once it completed",
+            batch_id, batch.state
+        ))
+        .into_response());
+    }
+
+    let tenant_id = batch.tenant_id.as_deref();
+    let key = results_archive_key(tenant_id, &batch_id);
+    let size = match state.storage.head_object(&key).await {
+        Ok(info) => info.size,
+        Err(ProcessorError::NotFound(_)) => {
+            if let Some(failure) = &batch.archive_failure {
+                let retry_at = failure.failed_at + Duration::minutes(ARCHIVE_REBUILD_AFTER_MINS);
+                if Utc::now() < retry_at {
+                    return Err(APIError::archive_build_failed(&batch_id, retry_at).into_response());
+                }
+            }
+            start_archive_build(&state, tenant_id, batch_id)
+                .await
+                .map_err(|e| APIError::from(e).into_response())?;
+            return Ok((
+                StatusCode::ACCEPTED,
+                [(header::RETRY_AFTER, ARCHIVE_RETRY_AFTER_SECS.to_string())],
+            )
+                .into_response());
+        }
+        Err(e) => return Err(APIError::from(e).into_response()),
+    };
+
+    let range_header = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok());
+    let (status, range) = match parse_range(range_header, size) {
+        RangeRequest::Full => (StatusCode::OK, 0..size),
+        RangeRequest::Partial(range) => (StatusCode::PARTIAL_CONTENT, range),
+        RangeRequest::Unsatisfiable => (StatusCode::RANGE_NOT_SATISFIABLE, 0..0),
+    };
+
+    let audit = DBDownloadAudit {
+        id: None,
+        batch_id,
+        s3_key: key.clone(),
+        range: range_header.map(|h| h.to_string()),
+        status: status.as_u16(),
+        bytes_served: range.end - range.start,
+        client: header_string(&headers, "x-forwarded-for"),
+        user_agent: header_string(&headers, header::USER_AGENT.as_str()),
+        time_created: Utc::now(),
+    };
+    // Downloads aren't served unless they can be audited
+    state
+        .db
+        .add_download_audit(&audit)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+        return Ok((
+            status,
+            [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+        )
+            .into_response());
+    }
+
+    let body = state
+        .storage
+        .get_object_range_stream(&key, range.clone())
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let mut response = Response::new(Body::from_stream(body));
+    *response.status_mut() = status;
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    response_headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(range.end - range.start),
+    );
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}.zip\"", batch_id))
+    {
+        response_headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    if status == StatusCode::PARTIAL_CONTENT
+        && let Ok(content_range) =
+            HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end - 1, size))
+    {
+        response_headers.insert(header::CONTENT_RANGE, content_range);
+    }
+
+    Ok(response)
+}
+
+fn header_string(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges_are_clipped_to_the_object() {
+        assert_eq!(parse_range(Some("bytes=0-99"), 1000), RangeRequest::Partial(0..100));
+        assert_eq!(parse_range(Some("bytes=900-"), 1000), RangeRequest::Partial(900..1000));
+        assert_eq!(parse_range(Some("bytes=900-5000"), 1000), RangeRequest::Partial(900..1000));
+        assert_eq!(parse_range(Some("bytes=-100"), 1000), RangeRequest::Partial(900..1000));
+        assert_eq!(parse_range(Some("bytes=1000-"), 1000), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn other_ranges_serve_the_whole_object() {
+        for header in [None, Some("items=0-1"), Some("bytes=5-2"), Some("bytes=0-1,4-5")] {
+            assert_eq!(parse_range(header, 1000), RangeRequest::Full, "{:?}", header);
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// ============================================================================
+// API DTOs
+// The REST layer has its own request/response types instead of exposing the
+// internal types from `common` directly, so internal refactors don't change
+// the JSON that external clients see.
+//
+// v1 keeps the field names and shapes the API has always used (snake_case,
+// externally tagged operations) and is frozen: fields may be added but never
+// renamed or removed. v2 lives under `/v2` and uses camelCase fields with
+// `type` tagged operations.
+// ============================================================================
+
+pub mod v1;
+pub mod v2;
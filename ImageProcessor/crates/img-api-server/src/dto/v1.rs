@@ -0,0 +1,528 @@
+use common::{
+    DatasetProcessingJob, ImageOperation,
+    adaptive::ResolutionRule,
+    alpha::AlphaPolicy,
+    formats::OutputFormat,
+    metadata::MetadataPolicy,
+    naming::{CollisionPolicy, OutputLayout},
+    notifications,
+    resampling::ResampleFilter,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A pipeline operation as accepted by `/send_task`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Operation {
+    Resize {
+        scaling_factor: f32,
+        filter: Option<Filter>,
+    },
+    GrayScale,
+    Noise {
+        noise_level: f32,
+    },
+    InvertColors,
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Rotate {
+        degrees: f32,
+    },
+    Blur {
+        sigma: f32,
+    },
+    BlurPerMegapixel {
+        sigma_per_megapixel: f32,
+    },
+    ResizeLongEdge {
+        long_edge: u32,
+        filter: Option<Filter>,
+    },
+    ResizeShortEdge {
+        short_edge: u32,
+        filter: Option<Filter>,
+    },
+    CenterCrop {
+        width: u32,
+        height: u32,
+    },
+    ByResolution {
+        rules: Vec<ResolutionRuleDto>,
+    },
+    Convert {
+        format: Format,
+        quality: u8,
+    },
+    QualityGate {
+        min_width: Option<u32>,
+        min_height: Option<u32>,
+        max_blur_score: Option<f64>,
+        min_entropy: Option<f64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResolutionRuleDto {
+    pub max_megapixels: Option<f32>,
+    pub operation: Operation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Format {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Lanczos3,
+    CatmullRom,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum Layout {
+    #[default]
+    KeepStructure,
+    Flatten,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum Collisions {
+    #[default]
+    Suffix,
+    Error,
+    KeepStructure,
+}
+
+/// What happens to transparency, e.g. `{"flatten": {"background": [255, 255, 255]}}`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Alpha {
+    #[default]
+    Preserve,
+    Flatten {
+        background: [u8; 3],
+    },
+    Drop,
+}
+
+/// What happens to the EXIF of the images, e.g. `"strip_gps"`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Metadata {
+    Preserve,
+    #[default]
+    Strip,
+    StripGps,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum CacheScope {
+    #[default]
+    Tenant,
+    Shared,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum PipelineMode {
+    #[default]
+    Staged,
+    Fused,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    #[default]
+    Completion,
+    Failures,
+    EveryStage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct QuietHours {
+    pub start_hour: u32,
+    pub end_hour: u32,
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct NotificationPreferences {
+    #[serde(default)]
+    pub notify_on: NotifyOn,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// The body of `/send_task`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct JobRequest {
+    pub dataset_key: String,
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    #[serde(default)]
+    pub dependencies: Vec<Vec<usize>>,
+    #[serde(default)]
+    pub requires_approval: Vec<usize>,
+    #[serde(default)]
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub pipeline: Option<String>, // A pipeline saved through `PUT /pipelines/:name`
+    #[serde(default)]
+    pub use_local_cache: bool,
+    #[serde(default)]
+    pub output_layout: Layout,
+    #[serde(default)]
+    pub collision_policy: Collisions,
+    #[serde(default)]
+    pub hash_suffix: bool,
+    #[serde(default)]
+    pub cache_scope: CacheScope,
+    #[serde(default)]
+    pub output_format: Option<Format>,
+    #[serde(default)]
+    pub alpha_policy: Alpha,
+    #[serde(default)]
+    pub metadata_policy: Metadata,
+    #[serde(default)]
+    pub rewrite_sidecars: bool,
+    #[serde(default)]
+    pub previews: bool,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub notification_url: Option<String>,
+    #[serde(default)]
+    pub notification_preferences: Option<NotificationPreferences>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub pipeline_mode: PipelineMode,
+    #[serde(default)]
+    pub dry_run: bool, // Only estimate the job, see `estimate`
+}
+
+impl From<Operation> for ImageOperation {
+    fn from(op: Operation) -> Self {
+        match op {
+            Operation::Resize {
+                scaling_factor,
+                filter,
+            } => ImageOperation::Resize {
+                scaling_factor,
+                filter: filter.map(Into::into),
+            },
+            Operation::GrayScale => ImageOperation::GrayScale,
+            Operation::Noise { noise_level } => ImageOperation::Noise { noise_level },
+            Operation::InvertColors => ImageOperation::InvertColors,
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => ImageOperation::Crop {
+                x,
+                y,
+                width,
+                height,
+            },
+            Operation::Rotate { degrees } => ImageOperation::Rotate { degrees },
+            Operation::Blur { sigma } => ImageOperation::Blur { sigma },
+            Operation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            } => ImageOperation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            },
+            Operation::ResizeLongEdge { long_edge, filter } => ImageOperation::ResizeLongEdge {
+                long_edge,
+                filter: filter.map(Into::into),
+            },
+            Operation::ResizeShortEdge { short_edge, filter } => ImageOperation::ResizeShortEdge {
+                short_edge,
+                filter: filter.map(Into::into),
+            },
+            Operation::CenterCrop { width, height } => ImageOperation::CenterCrop { width, height },
+            Operation::ByResolution { rules } => ImageOperation::ByResolution {
+                rules: rules
+                    .into_iter()
+                    .map(|rule| ResolutionRule {
+                        max_megapixels: rule.max_megapixels,
+                        operation: rule.operation.into(),
+                    })
+                    .collect(),
+            },
+            Operation::Convert { format, quality } => ImageOperation::Convert {
+                format: format.into(),
+                quality,
+            },
+            Operation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            } => ImageOperation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            },
+        }
+    }
+}
+
+impl From<ImageOperation> for Operation {
+    fn from(op: ImageOperation) -> Self {
+        match op {
+            ImageOperation::Resize {
+                scaling_factor,
+                filter,
+            } => Operation::Resize {
+                scaling_factor,
+                filter: filter.map(Into::into),
+            },
+            ImageOperation::GrayScale => Operation::GrayScale,
+            ImageOperation::Noise { noise_level } => Operation::Noise { noise_level },
+            ImageOperation::InvertColors => Operation::InvertColors,
+            ImageOperation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            },
+            ImageOperation::Rotate { degrees } => Operation::Rotate { degrees },
+            ImageOperation::Blur { sigma } => Operation::Blur { sigma },
+            ImageOperation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            } => Operation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            },
+            ImageOperation::ResizeLongEdge { long_edge, filter } => Operation::ResizeLongEdge {
+                long_edge,
+                filter: filter.map(Into::into),
+            },
+            ImageOperation::ResizeShortEdge { short_edge, filter } => Operation::ResizeShortEdge {
+                short_edge,
+                filter: filter.map(Into::into),
+            },
+            ImageOperation::CenterCrop { width, height } => Operation::CenterCrop { width, height },
+            ImageOperation::ByResolution { rules } => Operation::ByResolution {
+                rules: rules
+                    .into_iter()
+                    .map(|rule| ResolutionRuleDto {
+                        max_megapixels: rule.max_megapixels,
+                        operation: rule.operation.into(),
+                    })
+                    .collect(),
+            },
+            ImageOperation::Convert { format, quality } => Operation::Convert {
+                format: format.into(),
+                quality,
+            },
+            ImageOperation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            } => Operation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            },
+        }
+    }
+}
+
+impl From<Format> for OutputFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Jpeg => OutputFormat::Jpeg,
+            Format::Png => OutputFormat::Png,
+            Format::Webp => OutputFormat::Webp,
+            Format::Avif => OutputFormat::Avif,
+        }
+    }
+}
+
+impl From<OutputFormat> for Format {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Jpeg => Format::Jpeg,
+            OutputFormat::Png => Format::Png,
+            OutputFormat::Webp => Format::Webp,
+            OutputFormat::Avif => Format::Avif,
+        }
+    }
+}
+
+impl From<Filter> for ResampleFilter {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => ResampleFilter::Nearest,
+            Filter::Bilinear => ResampleFilter::Bilinear,
+            Filter::Lanczos3 => ResampleFilter::Lanczos3,
+            Filter::CatmullRom => ResampleFilter::CatmullRom,
+        }
+    }
+}
+
+impl From<ResampleFilter> for Filter {
+    fn from(filter: ResampleFilter) -> Self {
+        match filter {
+            ResampleFilter::Nearest => Filter::Nearest,
+            ResampleFilter::Bilinear => Filter::Bilinear,
+            ResampleFilter::Lanczos3 => Filter::Lanczos3,
+            ResampleFilter::CatmullRom => Filter::CatmullRom,
+        }
+    }
+}
+
+impl From<JobRequest> for DatasetProcessingJob {
+    fn from(request: JobRequest) -> Self {
+        DatasetProcessingJob {
+            batch_id: None,
+            dataset_key: request.dataset_key,
+            operations: request.operations.into_iter().map(Into::into).collect(),
+            dependencies: request.dependencies,
+            requires_approval: request.requires_approval,
+            preset: request.preset,
+            pipeline: request.pipeline,
+            use_local_cache: request.use_local_cache,
+            output_layout: match request.output_layout {
+                Layout::KeepStructure => OutputLayout::KeepStructure,
+                Layout::Flatten => OutputLayout::Flatten,
+            },
+            collision_policy: match request.collision_policy {
+                Collisions::Suffix => CollisionPolicy::Suffix,
+                Collisions::Error => CollisionPolicy::Error,
+                Collisions::KeepStructure => CollisionPolicy::KeepStructure,
+            },
+            hash_suffix: request.hash_suffix,
+            cache_scope: match request.cache_scope {
+                CacheScope::Tenant => common::CacheScope::Tenant,
+                CacheScope::Shared => common::CacheScope::Shared,
+            },
+            output_format: request.output_format.map(Into::into),
+            alpha_policy: match request.alpha_policy {
+                Alpha::Preserve => AlphaPolicy::Preserve,
+                Alpha::Flatten { background } => AlphaPolicy::Flatten { background },
+                Alpha::Drop => AlphaPolicy::Drop,
+            },
+            metadata_policy: match request.metadata_policy {
+                Metadata::Preserve => MetadataPolicy::Preserve,
+                Metadata::Strip => MetadataPolicy::Strip,
+                Metadata::StripGps => MetadataPolicy::StripGps,
+            },
+            rewrite_sidecars: request.rewrite_sidecars,
+            previews: request.previews,
+            tags: request.tags,
+            owner: request.owner,
+            notification_url: request.notification_url,
+            notification_preferences: request.notification_preferences.map(Into::into),
+            priority: match request.priority {
+                Priority::Low => common::Priority::Low,
+                Priority::Normal => common::Priority::Normal,
+                Priority::High => common::Priority::High,
+            },
+            pipeline_mode: match request.pipeline_mode {
+                PipelineMode::Staged => common::PipelineMode::Staged,
+                PipelineMode::Fused => common::PipelineMode::Fused,
+            },
+            tenant_id: None, // Set from the API key of the request
+        }
+    }
+}
+
+impl From<NotificationPreferences> for notifications::NotificationPreferences {
+    fn from(preferences: NotificationPreferences) -> Self {
+        notifications::NotificationPreferences {
+            notify_on: match preferences.notify_on {
+                NotifyOn::Completion => notifications::NotifyOn::Completion,
+                NotifyOn::Failures => notifications::NotifyOn::Failures,
+                NotifyOn::EveryStage => notifications::NotifyOn::EveryStage,
+            },
+            quiet_hours: preferences
+                .quiet_hours
+                .map(|hours| notifications::QuietHours {
+                    start_hour: hours.start_hour,
+                    end_hour: hours.end_hour,
+                    utc_offset_minutes: hours.utc_offset_minutes,
+                }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_keep_the_v1_wire_format() {
+        let request: JobRequest = serde_json::from_value(serde_json::json!({
+            "dataset_key": "datasets/cats.zip",
+            "operations": [
+                { "Resize": { "scaling_factor": 0.5, "filter": "Lanczos3" } },
+                { "Convert": { "format": "Webp", "quality": 80 } }
+            ],
+            "output_layout": "Flatten",
+            "collision_policy": "KeepStructure",
+            "alpha_policy": { "flatten": { "background": [0, 0, 0] } },
+            "metadata_policy": "strip_gps",
+            "notification_preferences": { "notify_on": "every_stage" },
+            "priority": "High",
+            "pipeline_mode": "Fused"
+        }))
+        .unwrap();
+        let job = DatasetProcessingJob::from(request);
+
+        assert!(matches!(
+            job.operations[0],
+            ImageOperation::Resize {
+                filter: Some(ResampleFilter::Lanczos3),
+                ..
+            }
+        ));
+        assert!(matches!(
+            job.operations[1],
+            ImageOperation::Convert {
+                format: OutputFormat::Webp,
+                quality: 80
+            }
+        ));
+        assert_eq!(job.output_layout, OutputLayout::Flatten);
+        assert_eq!(job.collision_policy, CollisionPolicy::KeepStructure);
+        assert_eq!(job.alpha_policy, AlphaPolicy::Flatten { background: [0, 0, 0] });
+        assert_eq!(job.metadata_policy, MetadataPolicy::StripGps);
+        assert_eq!(
+            job.notification_preferences.map(|preferences| preferences.notify_on),
+            Some(notifications::NotifyOn::EveryStage)
+        );
+        assert_eq!(job.priority, common::Priority::High);
+        assert_eq!(job.pipeline_mode, common::PipelineMode::Fused);
+    }
+}
@@ -0,0 +1,815 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use common::{
+    DatasetProcessingJob, ImageOperation,
+    adaptive::ResolutionRule,
+    alpha::AlphaPolicy,
+    budgets::{self, BudgetExceeded},
+    formats::OutputFormat,
+    lifecycle::BatchState,
+    metadata::MetadataPolicy,
+    naming::{CollisionPolicy, OutputLayout},
+    notifications,
+    resampling::ResampleFilter,
+    validation::{IssueSeverity, PipelineIssue},
+};
+use db_utils::types::{StatusCounts, TaskStatus};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{BatchStatusResponse, StageStatus, TaskDispatchResult};
+
+/// A pipeline operation, tagged by `type`, e.g. `{"type": "resize", "scalingFactor": 0.5}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Operation {
+    Resize {
+        scaling_factor: f32,
+        filter: Option<Filter>,
+    },
+    GrayScale,
+    Noise {
+        noise_level: f32,
+    },
+    InvertColors,
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    Rotate {
+        degrees: f32,
+    },
+    Blur {
+        sigma: f32,
+    },
+    BlurPerMegapixel {
+        sigma_per_megapixel: f32,
+    },
+    ResizeLongEdge {
+        long_edge: u32,
+        filter: Option<Filter>,
+    },
+    ResizeShortEdge {
+        short_edge: u32,
+        filter: Option<Filter>,
+    },
+    CenterCrop {
+        width: u32,
+        height: u32,
+    },
+    ByResolution {
+        rules: Vec<ResolutionRuleDto>,
+    },
+    Convert {
+        format: Format,
+        quality: u8,
+    },
+    QualityGate {
+        min_width: Option<u32>,
+        min_height: Option<u32>,
+        max_blur_score: Option<f64>,
+        min_entropy: Option<f64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolutionRuleDto {
+    pub max_megapixels: Option<f32>,
+    pub operation: Operation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum Format {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Lanczos3,
+    CatmullRom,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Layout {
+    #[default]
+    KeepStructure,
+    Flatten,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Collisions {
+    #[default]
+    Suffix,
+    Error,
+    KeepStructure,
+}
+
+/// What happens to transparency, e.g. `{"flatten": {"background": [255, 255, 255]}}`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Alpha {
+    #[default]
+    Preserve,
+    Flatten {
+        background: [u8; 3],
+    },
+    Drop,
+}
+
+/// What happens to the EXIF of the images, e.g. `"stripGps"`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Metadata {
+    Preserve,
+    #[default]
+    Strip,
+    StripGps,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum CacheScope {
+    #[default]
+    Tenant,
+    Shared,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum PipelineMode {
+    #[default]
+    Staged,
+    Fused,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum NotifyOn {
+    #[default]
+    Completion,
+    Failures,
+    EveryStage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct QuietHours {
+    pub start_hour: u32,
+    pub end_hour: u32,
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationPreferences {
+    #[serde(default)]
+    pub notify_on: NotifyOn,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// The body of `/v2/send_task`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRequest {
+    pub dataset_key: String,
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    #[serde(default)]
+    pub dependencies: Vec<Vec<usize>>,
+    #[serde(default)]
+    pub requires_approval: Vec<usize>,
+    #[serde(default)]
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub pipeline: Option<String>, // A pipeline saved through `PUT /pipelines/:name`
+    #[serde(default)]
+    pub use_local_cache: bool,
+    #[serde(default)]
+    pub output_layout: Layout,
+    #[serde(default)]
+    pub collision_policy: Collisions,
+    #[serde(default)]
+    pub hash_suffix: bool,
+    #[serde(default)]
+    pub cache_scope: CacheScope,
+    #[serde(default)]
+    pub output_format: Option<Format>,
+    #[serde(default)]
+    pub alpha_policy: Alpha,
+    #[serde(default)]
+    pub metadata_policy: Metadata,
+    #[serde(default)]
+    pub rewrite_sidecars: bool,
+    #[serde(default)]
+    pub previews: bool,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub notification_url: Option<String>,
+    #[serde(default)]
+    pub notification_preferences: Option<NotificationPreferences>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub pipeline_mode: PipelineMode,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Issue {
+    pub severity: Severity,
+    pub stage: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DispatchResult {
+    pub batch_id: uuid::Uuid,
+    pub task_ids: Vec<uuid::Uuid>,
+    pub message: String,
+    pub warnings: Vec<Issue>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    Waiting,
+    Ready,
+    Running,
+    Success,
+    Failure,
+    Cancelled,
+    Filtered,
+}
+
+/// Lifecycle state of a batch, e.g. `{"state": "processing", "stage": 1}`
+#[derive(Serialize, Debug)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum State {
+    Created,
+    Decomposing,
+    Processing { stage: u32 },
+    Finalizing,
+    Completed,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Counts {
+    pub waiting: u64,
+    pub ready: u64,
+    pub running: u64,
+    pub success: u64,
+    pub failure: u64,
+    pub cancelled: u64,
+    pub filtered: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Stage {
+    pub stage: u32,
+    pub task_id: uuid::Uuid,
+    pub operation: Operation,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fused_operations: Vec<Operation>,
+    pub status: Status,
+    pub images: Counts,
+    pub completion_percentage: f64,
+    pub requires_approval: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approval: Option<Approval>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget_checkpoint: Option<BudgetCheckpoint>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Approval {
+    pub approved: bool,
+    pub reviewer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    pub time_decided: DateTime<Utc>,
+}
+
+/// How far the decomposition of a stage got before it exceeded its budget
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BudgetCheckpoint {
+    pub exceeded: Budget,
+    pub images_registered: u64,
+    pub images_total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_image: Option<String>,
+    pub extracted_bytes: u64,
+    pub elapsed_secs: u64,
+    pub time_recorded: DateTime<Utc>,
+}
+
+/// The budget a stage exceeded, e.g. `{"kind": "wallTime", "limitSecs": 3600}`
+#[derive(Serialize, Debug)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Budget {
+    WallTime { limit_secs: u64 },
+    ExtractedBytes { bytes: u64, limit: u64 },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchStatus {
+    pub batch_id: uuid::Uuid,
+    pub dataset_key: String,
+    pub state: State,
+    pub time_created: DateTime<Utc>,
+    pub time_completed: Option<DateTime<Utc>>,
+    pub dataset_tasks: Counts,
+    pub image_tasks: Counts,
+    pub stages: Vec<Stage>,
+    pub annotations: HashMap<String, serde_json::Value>,
+    pub retention: Option<Retention>,
+    pub parent_batch_id: Option<uuid::Uuid>,
+    pub child_batch_ids: Vec<uuid::Uuid>,
+    pub provenance_root: Option<String>,
+    pub batch_manifest_key: Option<String>,
+    pub quality_drifts: Vec<QualityDrift>,
+}
+
+/// A metric of a stage's outputs that drifted from earlier stages of its pipeline
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QualityDrift {
+    pub stage: u32,
+    pub pipeline: String,
+    pub metric: String, // "sharpness", "brightness" or "ssim"
+    pub observed: f64,
+    pub baseline: f64,
+    pub baseline_stages: usize,
+    pub z_score: f64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Retention {
+    pub days: u32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub days_remaining: Option<i64>,
+    pub expired: bool,
+    pub warning_sent_at: Option<DateTime<Utc>>,
+    pub results_deleted_at: Option<DateTime<Utc>>,
+}
+
+// ============================================================================
+// CONVERSIONS
+// ============================================================================
+
+impl From<Operation> for ImageOperation {
+    fn from(op: Operation) -> Self {
+        match op {
+            Operation::Resize {
+                scaling_factor,
+                filter,
+            } => ImageOperation::Resize {
+                scaling_factor,
+                filter: filter.map(Into::into),
+            },
+            Operation::GrayScale => ImageOperation::GrayScale,
+            Operation::Noise { noise_level } => ImageOperation::Noise { noise_level },
+            Operation::InvertColors => ImageOperation::InvertColors,
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => ImageOperation::Crop {
+                x,
+                y,
+                width,
+                height,
+            },
+            Operation::Rotate { degrees } => ImageOperation::Rotate { degrees },
+            Operation::Blur { sigma } => ImageOperation::Blur { sigma },
+            Operation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            } => ImageOperation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            },
+            Operation::ResizeLongEdge { long_edge, filter } => ImageOperation::ResizeLongEdge {
+                long_edge,
+                filter: filter.map(Into::into),
+            },
+            Operation::ResizeShortEdge { short_edge, filter } => ImageOperation::ResizeShortEdge {
+                short_edge,
+                filter: filter.map(Into::into),
+            },
+            Operation::CenterCrop { width, height } => ImageOperation::CenterCrop { width, height },
+            Operation::ByResolution { rules } => ImageOperation::ByResolution {
+                rules: rules
+                    .into_iter()
+                    .map(|rule| ResolutionRule {
+                        max_megapixels: rule.max_megapixels,
+                        operation: rule.operation.into(),
+                    })
+                    .collect(),
+            },
+            Operation::Convert { format, quality } => ImageOperation::Convert {
+                format: format.into(),
+                quality,
+            },
+            Operation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            } => ImageOperation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            },
+        }
+    }
+}
+
+impl From<ImageOperation> for Operation {
+    fn from(op: ImageOperation) -> Self {
+        match op {
+            ImageOperation::Resize {
+                scaling_factor,
+                filter,
+            } => Operation::Resize {
+                scaling_factor,
+                filter: filter.map(Into::into),
+            },
+            ImageOperation::GrayScale => Operation::GrayScale,
+            ImageOperation::Noise { noise_level } => Operation::Noise { noise_level },
+            ImageOperation::InvertColors => Operation::InvertColors,
+            ImageOperation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            },
+            ImageOperation::Rotate { degrees } => Operation::Rotate { degrees },
+            ImageOperation::Blur { sigma } => Operation::Blur { sigma },
+            ImageOperation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            } => Operation::BlurPerMegapixel {
+                sigma_per_megapixel,
+            },
+            ImageOperation::ResizeLongEdge { long_edge, filter } => Operation::ResizeLongEdge {
+                long_edge,
+                filter: filter.map(Into::into),
+            },
+            ImageOperation::ResizeShortEdge { short_edge, filter } => Operation::ResizeShortEdge {
+                short_edge,
+                filter: filter.map(Into::into),
+            },
+            ImageOperation::CenterCrop { width, height } => Operation::CenterCrop { width, height },
+            ImageOperation::ByResolution { rules } => Operation::ByResolution {
+                rules: rules
+                    .into_iter()
+                    .map(|rule| ResolutionRuleDto {
+                        max_megapixels: rule.max_megapixels,
+                        operation: rule.operation.into(),
+                    })
+                    .collect(),
+            },
+            ImageOperation::Convert { format, quality } => Operation::Convert {
+                format: format.into(),
+                quality,
+            },
+            ImageOperation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            } => Operation::QualityGate {
+                min_width,
+                min_height,
+                max_blur_score,
+                min_entropy,
+            },
+        }
+    }
+}
+
+impl From<Format> for OutputFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Jpeg => OutputFormat::Jpeg,
+            Format::Png => OutputFormat::Png,
+            Format::Webp => OutputFormat::Webp,
+            Format::Avif => OutputFormat::Avif,
+        }
+    }
+}
+
+impl From<OutputFormat> for Format {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Jpeg => Format::Jpeg,
+            OutputFormat::Png => Format::Png,
+            OutputFormat::Webp => Format::Webp,
+            OutputFormat::Avif => Format::Avif,
+        }
+    }
+}
+
+impl From<Filter> for ResampleFilter {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => ResampleFilter::Nearest,
+            Filter::Bilinear => ResampleFilter::Bilinear,
+            Filter::Lanczos3 => ResampleFilter::Lanczos3,
+            Filter::CatmullRom => ResampleFilter::CatmullRom,
+        }
+    }
+}
+
+impl From<ResampleFilter> for Filter {
+    fn from(filter: ResampleFilter) -> Self {
+        match filter {
+            ResampleFilter::Nearest => Filter::Nearest,
+            ResampleFilter::Bilinear => Filter::Bilinear,
+            ResampleFilter::Lanczos3 => Filter::Lanczos3,
+            ResampleFilter::CatmullRom => Filter::CatmullRom,
+        }
+    }
+}
+
+impl From<JobRequest> for DatasetProcessingJob {
+    fn from(request: JobRequest) -> Self {
+        DatasetProcessingJob {
+            batch_id: None,
+            dataset_key: request.dataset_key,
+            operations: request.operations.into_iter().map(Into::into).collect(),
+            dependencies: request.dependencies,
+            requires_approval: request.requires_approval,
+            preset: request.preset,
+            pipeline: request.pipeline,
+            use_local_cache: request.use_local_cache,
+            output_layout: match request.output_layout {
+                Layout::KeepStructure => OutputLayout::KeepStructure,
+                Layout::Flatten => OutputLayout::Flatten,
+            },
+            collision_policy: match request.collision_policy {
+                Collisions::Suffix => CollisionPolicy::Suffix,
+                Collisions::Error => CollisionPolicy::Error,
+                Collisions::KeepStructure => CollisionPolicy::KeepStructure,
+            },
+            hash_suffix: request.hash_suffix,
+            cache_scope: match request.cache_scope {
+                CacheScope::Tenant => common::CacheScope::Tenant,
+                CacheScope::Shared => common::CacheScope::Shared,
+            },
+            output_format: request.output_format.map(Into::into),
+            alpha_policy: match request.alpha_policy {
+                Alpha::Preserve => AlphaPolicy::Preserve,
+                Alpha::Flatten { background } => AlphaPolicy::Flatten { background },
+                Alpha::Drop => AlphaPolicy::Drop,
+            },
+            metadata_policy: match request.metadata_policy {
+                Metadata::Preserve => MetadataPolicy::Preserve,
+                Metadata::Strip => MetadataPolicy::Strip,
+                Metadata::StripGps => MetadataPolicy::StripGps,
+            },
+            rewrite_sidecars: request.rewrite_sidecars,
+            previews: request.previews,
+            tags: request.tags,
+            owner: request.owner,
+            notification_url: request.notification_url,
+            notification_preferences: request.notification_preferences.map(Into::into),
+            priority: match request.priority {
+                Priority::Low => common::Priority::Low,
+                Priority::Normal => common::Priority::Normal,
+                Priority::High => common::Priority::High,
+            },
+            pipeline_mode: match request.pipeline_mode {
+                PipelineMode::Staged => common::PipelineMode::Staged,
+                PipelineMode::Fused => common::PipelineMode::Fused,
+            },
+            tenant_id: None, // Set from the API key of the request
+        }
+    }
+}
+
+impl From<NotificationPreferences> for notifications::NotificationPreferences {
+    fn from(preferences: NotificationPreferences) -> Self {
+        notifications::NotificationPreferences {
+            notify_on: match preferences.notify_on {
+                NotifyOn::Completion => notifications::NotifyOn::Completion,
+                NotifyOn::Failures => notifications::NotifyOn::Failures,
+                NotifyOn::EveryStage => notifications::NotifyOn::EveryStage,
+            },
+            quiet_hours: preferences
+                .quiet_hours
+                .map(|hours| notifications::QuietHours {
+                    start_hour: hours.start_hour,
+                    end_hour: hours.end_hour,
+                    utc_offset_minutes: hours.utc_offset_minutes,
+                }),
+        }
+    }
+}
+
+impl From<PipelineIssue> for Issue {
+    fn from(issue: PipelineIssue) -> Self {
+        Issue {
+            severity: match issue.severity {
+                IssueSeverity::Warning => Severity::Warning,
+                IssueSeverity::Error => Severity::Error,
+            },
+            stage: issue.stage,
+            field: issue.field,
+            message: issue.message,
+        }
+    }
+}
+
+impl From<TaskDispatchResult> for DispatchResult {
+    fn from(result: TaskDispatchResult) -> Self {
+        DispatchResult {
+            batch_id: result.batch_id,
+            task_ids: result.task_ids,
+            message: result.message,
+            warnings: result.warnings.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<TaskStatus> for Status {
+    fn from(status: TaskStatus) -> Self {
+        match status {
+            TaskStatus::Waiting => Status::Waiting,
+            TaskStatus::Ready => Status::Ready,
+            TaskStatus::Running => Status::Running,
+            TaskStatus::Success => Status::Success,
+            TaskStatus::Failure => Status::Failure,
+            TaskStatus::Cancelled => Status::Cancelled,
+            TaskStatus::Filtered => Status::Filtered,
+        }
+    }
+}
+
+impl From<BatchState> for State {
+    fn from(state: BatchState) -> Self {
+        match state {
+            BatchState::Created => State::Created,
+            BatchState::Decomposing => State::Decomposing,
+            BatchState::Processing { stage } => State::Processing { stage },
+            BatchState::Finalizing => State::Finalizing,
+            BatchState::Completed => State::Completed,
+            BatchState::Failed => State::Failed,
+            BatchState::Cancelled => State::Cancelled,
+            BatchState::TimedOut => State::TimedOut,
+        }
+    }
+}
+
+impl From<StatusCounts> for Counts {
+    fn from(counts: StatusCounts) -> Self {
+        Counts {
+            waiting: counts.waiting,
+            ready: counts.ready,
+            running: counts.running,
+            success: counts.success,
+            failure: counts.failure,
+            cancelled: counts.cancelled,
+            filtered: counts.filtered,
+        }
+    }
+}
+
+impl From<StageStatus> for Stage {
+    fn from(stage: StageStatus) -> Self {
+        Stage {
+            stage: stage.stage,
+            task_id: stage.task_id,
+            operation: stage.operation.into(),
+            fused_operations: stage.fused_operations.into_iter().map(Into::into).collect(),
+            status: stage.status.into(),
+            images: stage.images.into(),
+            completion_percentage: stage.completion_percentage,
+            requires_approval: stage.requires_approval,
+            approval: stage.approval.map(|approval| Approval {
+                approved: approval.approved,
+                reviewer: approval.reviewer,
+                comment: approval.comment,
+                time_decided: approval.time_decided,
+            }),
+            budget_checkpoint: stage.budget_checkpoint.map(Into::into),
+        }
+    }
+}
+
+impl From<budgets::BudgetCheckpoint> for BudgetCheckpoint {
+    fn from(checkpoint: budgets::BudgetCheckpoint) -> Self {
+        BudgetCheckpoint {
+            exceeded: match checkpoint.exceeded {
+                BudgetExceeded::WallTime { limit_secs } => Budget::WallTime { limit_secs },
+                BudgetExceeded::ExtractedBytes { bytes, limit } => {
+                    Budget::ExtractedBytes { bytes, limit }
+                }
+            },
+            images_registered: checkpoint.images_registered,
+            images_total: checkpoint.images_total,
+            next_image: checkpoint.next_image,
+            extracted_bytes: checkpoint.extracted_bytes,
+            elapsed_secs: checkpoint.elapsed_secs,
+            time_recorded: checkpoint.time_recorded,
+        }
+    }
+}
+
+impl From<BatchStatusResponse> for BatchStatus {
+    fn from(status: BatchStatusResponse) -> Self {
+        BatchStatus {
+            batch_id: status.batch_id,
+            dataset_key: status.dataset_key,
+            state: status.state.into(),
+            time_created: status.time_created,
+            time_completed: status.time_completed,
+            dataset_tasks: status.dataset_tasks.into(),
+            image_tasks: status.image_tasks.into(),
+            stages: status.stages.into_iter().map(Into::into).collect(),
+            annotations: status.annotations,
+            retention: status.retention.map(|retention| Retention {
+                days: retention.days,
+                expires_at: retention.expires_at,
+                days_remaining: retention.days_remaining,
+                expired: retention.expired,
+                warning_sent_at: retention.warning_sent_at,
+                results_deleted_at: retention.results_deleted_at,
+            }),
+            parent_batch_id: status.parent_batch_id,
+            child_batch_ids: status.child_batch_ids,
+            provenance_root: status.provenance_root,
+            batch_manifest_key: status.batch_manifest_key,
+            quality_drifts: status
+                .quality_drifts
+                .into_iter()
+                .map(|drift| QualityDrift {
+                    stage: drift.stage,
+                    pipeline: drift.pipeline,
+                    metric: drift.metric.field().to_string(),
+                    observed: drift.observed,
+                    baseline: drift.baseline,
+                    baseline_stages: drift.baseline_stages,
+                    z_score: drift.z_score,
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+use crate::utils::FieldError;
+
+// ============================================================================
+// ERROR CATALOG
+// Every error the API returns carries a stable code clients can match on, a
+// message and, when the caller can fix the request, a hint saying how. The
+// messages are templates filled with the parameters of the error, rendered in
+// the language the request's Accept-Language header prefers and in English
+// when it names none of the catalog's. Codes are never renamed once released,
+// messages may be reworded.
+//
+// Errors are rendered in English where they are turned into a response, see
+// `APIError::into_response`, and the `localize` middleware renders them again
+// for the caller. Errors still built from free text, e.g. the message of an
+// invalid field, keep that text in English inside the translated template.
+// ============================================================================
+
+/// The languages of the catalog
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+}
+
+impl Locale {
+    fn from_tag(tag: &str) -> Option<Self> {
+        // Only the primary subtag is matched, e.g. es-MX is served Spanish
+        let primary = tag.split('-').next().unwrap_or_default();
+        match primary.to_ascii_lowercase().as_str() {
+            "en" => Some(Self::En),
+            "es" => Some(Self::Es),
+            _ => None,
+        }
+    }
+
+    /// The catalog language an Accept-Language header prefers, by quality and then by order
+    pub fn negotiate(accept_language: &str) -> Self {
+        let mut preferred: Option<(Self, f32)> = None;
+        for entry in accept_language.split(',') {
+            let mut parts = entry.split(';');
+            let Some(locale) = Self::from_tag(parts.next().unwrap_or_default().trim()) else {
+                continue;
+            };
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && preferred.is_none_or(|(_, best)| quality > best) {
+                preferred = Some((locale, quality));
+            }
+        }
+        preferred.map(|(locale, _)| locale).unwrap_or_default()
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::Es => "es",
+        }
+    }
+}
+
+/// The stable code of an error, returned as `code`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // Errors without an entry of their own, rendered around their free text
+    QueueUnavailable,
+    DatabaseError,
+    StorageError,
+    NotFound,
+    ValidationFailed,
+    Timeout,
+    PayloadTooLarge,
+    Unauthorized,
+    InvalidFields,
+    RateLimited,
+    // Errors with a message and hint of their own
+    UnsupportedExtension,
+    BatchNotFound,
+    DatasetNotFound,
+    MissingApiKey,
+    UnknownApiKey,
+    AdminKeyRequired,
+    TenantSuspended,
+    QuotaExceeded,
+    BodyTooLarge,
+    JsonRequired,
+    TopicExists,
+    ArchiveBuildFailed,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::QueueUnavailable | Self::DatabaseError | Self::StorageError => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Self::NotFound | Self::BatchNotFound | Self::DatasetNotFound => StatusCode::NOT_FOUND,
+            Self::ValidationFailed | Self::InvalidFields => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::PayloadTooLarge | Self::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::JsonRequired => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Unauthorized | Self::MissingApiKey | Self::UnknownApiKey => {
+                StatusCode::UNAUTHORIZED
+            }
+            Self::AdminKeyRequired | Self::TenantSuspended | Self::QuotaExceeded => {
+                StatusCode::FORBIDDEN
+            }
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::UnsupportedExtension => StatusCode::BAD_REQUEST,
+            Self::TopicExists => StatusCode::CONFLICT,
+            Self::ArchiveBuildFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The message and hint templates of the code, `{name}` is replaced by the parameter `name`
+    fn templates(self, locale: Locale) -> (&'static str, Option<&'static str>) {
+        match (self, locale) {
+            // The free text of these is the whole English message, as it always was
+            (Self::QueueUnavailable, Locale::En)
+            | (Self::DatabaseError, Locale::En)
+            | (Self::StorageError, Locale::En)
+            | (Self::NotFound, Locale::En)
+            | (Self::ValidationFailed, Locale::En)
+            | (Self::Timeout, Locale::En)
+            | (Self::PayloadTooLarge, Locale::En)
+            | (Self::Unauthorized, Locale::En) => ("{detail}", None),
+            (Self::QueueUnavailable, Locale::Es) => (
+                "No se pudo encolar la tarea: {detail}",
+                Some("Vuelva a intentarlo más tarde."),
+            ),
+            (Self::DatabaseError, Locale::Es) => (
+                "Error interno del servidor: {detail}",
+                Some("Vuelva a intentarlo más tarde."),
+            ),
+            (Self::StorageError, Locale::Es) => (
+                "Error del almacenamiento: {detail}",
+                Some("Vuelva a intentarlo más tarde."),
+            ),
+            (Self::NotFound, Locale::Es) => ("No encontrado: {detail}", None),
+            (Self::ValidationFailed, Locale::Es) => ("Solicitud no válida: {detail}", None),
+            (Self::Timeout, Locale::Es) => ("Tiempo de espera agotado: {detail}", None),
+            (Self::PayloadTooLarge, Locale::Es) => ("Contenido demasiado grande: {detail}", None),
+            (Self::Unauthorized, Locale::Es) => ("No autorizado: {detail}", None),
+
+            (Self::InvalidFields, Locale::En) => (
+                "The request has invalid fields",
+                Some("Correct the fields listed in `fields` and send the request again."),
+            ),
+            (Self::InvalidFields, Locale::Es) => (
+                "La solicitud tiene campos no válidos",
+                Some("Corrija los campos indicados en `fields` y vuelva a enviar la solicitud."),
+            ),
+            (Self::RateLimited, Locale::En) => (
+                "Rate limit exceeded, retry in {retry_after}s",
+                Some("Send fewer requests, or wait for the Retry-After header's seconds."),
+            ),
+            (Self::RateLimited, Locale::Es) => (
+                "Límite de solicitudes superado, reintente en {retry_after}s",
+                Some("Envíe menos solicitudes o espere los segundos de la cabecera Retry-After."),
+            ),
+            (Self::UnsupportedExtension, Locale::En) => (
+                "File extension '{extension}' is not supported",
+                Some("Upload a file with one of the extensions: {allowed}."),
+            ),
+            (Self::UnsupportedExtension, Locale::Es) => (
+                "La extensión de archivo '{extension}' no es compatible",
+                Some("Suba un archivo con una de las extensiones: {allowed}."),
+            ),
+            (Self::BatchNotFound, Locale::En) => ("Batch {batch_id} does not exist", None),
+            (Self::BatchNotFound, Locale::Es) => ("El lote {batch_id} no existe", None),
+            (Self::DatasetNotFound, Locale::En) => (
+                "Dataset {dataset} does not exist",
+                Some("Upload it through /upload_dataset before submitting a job for it."),
+            ),
+            (Self::DatasetNotFound, Locale::Es) => (
+                "El conjunto de datos {dataset} no existe",
+                Some("Súbalo mediante /upload_dataset antes de enviar un trabajo para él."),
+            ),
+            (Self::MissingApiKey, Locale::En) => (
+                "An X-API-Key header is required",
+                Some("Send the API key of your tenant in the X-API-Key header."),
+            ),
+            (Self::MissingApiKey, Locale::Es) => (
+                "Se requiere la cabecera X-API-Key",
+                Some("Envíe la clave de API de su organización en la cabecera X-API-Key."),
+            ),
+            (Self::UnknownApiKey, Locale::En) => (
+                "Unknown API key",
+                Some("Check the key for typos or ask an operator for a new one."),
+            ),
+            (Self::UnknownApiKey, Locale::Es) => (
+                "Clave de API desconocida",
+                Some("Compruebe que la clave esté bien escrita o pida una nueva a un operador."),
+            ),
+            (Self::AdminKeyRequired, Locale::En) => ("Admin endpoints need an admin key", None),
+            (Self::AdminKeyRequired, Locale::Es) => {
+                ("Los endpoints de administración requieren una clave de administrador", None)
+            }
+            (Self::TenantSuspended, Locale::En) => (
+                "Tenant {tenant_id} is suspended",
+                Some("Ask an operator to reactivate the tenant."),
+            ),
+            (Self::TenantSuspended, Locale::Es) => (
+                "La organización {tenant_id} está suspendida",
+                Some("Pida a un operador que reactive la organización."),
+            ),
+            (Self::QuotaExceeded, Locale::En) => (
+                "Quota exceeded: {detail}",
+                Some("Wait for running batches to finish, or ask an operator to raise the quota."),
+            ),
+            (Self::QuotaExceeded, Locale::Es) => (
+                "Cuota superada: {detail}",
+                Some("Espere a que terminen los lotes en curso o pida a un operador más cuota."),
+            ),
+            (Self::BodyTooLarge, Locale::En) => (
+                "Request bodies are limited to {limit} bytes",
+                Some("Upload large datasets through /upload_dataset instead of the request body."),
+            ),
+            (Self::BodyTooLarge, Locale::Es) => (
+                "El cuerpo de la solicitud está limitado a {limit} bytes",
+                Some("Suba los conjuntos de datos grandes mediante /upload_dataset."),
+            ),
+            (Self::JsonRequired, Locale::En) => (
+                "Expected Content-Type application/json, got '{content_type}'",
+                Some("Send the body as JSON with the header Content-Type: application/json."),
+            ),
+            (Self::JsonRequired, Locale::Es) => (
+                "Se esperaba Content-Type application/json, se recibió '{content_type}'",
+                Some("Envíe el cuerpo como JSON con la cabecera Content-Type: application/json."),
+            ),
+            (Self::TopicExists, Locale::En) => (
+                "Topic {topic} already exists",
+                Some("Migrate to a topic that doesn't exist yet, it is created for the migration."),
+            ),
+            (Self::TopicExists, Locale::Es) => (
+                "El topic {topic} ya existe",
+                Some("Migre a un topic que aún no exista, se crea para la migración."),
+            ),
+            (Self::ArchiveBuildFailed, Locale::En) => (
+                "Building the results archive of batch {batch_id} failed",
+                Some("The archive is built again on downloads from {retry_at} on."),
+            ),
+            (Self::ArchiveBuildFailed, Locale::Es) => (
+                "No se pudo generar el archivo de resultados del lote {batch_id}",
+                Some("El archivo se vuelve a generar en las descargas a partir de {retry_at}."),
+            ),
+        }
+    }
+}
+
+/// An error of the catalog with the parameters its templates are filled with
+#[derive(Debug, Clone)]
+pub struct CatalogError {
+    pub code: ErrorCode,
+    params: Vec<(&'static str, String)>,
+}
+
+impl CatalogError {
+    pub fn new(code: ErrorCode) -> Self {
+        Self {
+            code,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.params.push((name, value.to_string()));
+        self
+    }
+
+    pub fn message(&self, locale: Locale) -> String {
+        self.fill(self.code.templates(locale).0)
+    }
+
+    pub fn hint(&self, locale: Locale) -> Option<String> {
+        self.code.templates(locale).1.map(|hint| self.fill(hint))
+    }
+
+    fn fill(&self, template: &str) -> String {
+        self.params
+            .iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: ErrorCode,
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a [FieldError]>, // Only errors with invalid fields list them
+
+}
+
+/// The error a response was rendered from, kept on the response so `localize` can render it again
+#[derive(Debug, Clone)]
+pub struct RenderedError {
+    pub error: CatalogError,
+    pub fields: Vec<FieldError>,
+}
+
+impl RenderedError {
+    /// The JSON body of the error in the locale
+    pub fn body(&self, locale: Locale) -> Vec<u8> {
+        let body = ErrorBody {
+            code: self.error.code,
+            error: self.error.message(locale),
+            hint: self.error.hint(locale),
+            fields: (!self.fields.is_empty()).then_some(self.fields.as_slice()),
+        };
+        serde_json::to_vec(&body).unwrap_or_default()
+    }
+
+    /// The response of the error in English, carrying the error for `localize`
+    pub fn into_response(self) -> Response {
+        let mut response = Response::new(Body::from(self.body(Locale::En)));
+        *response.status_mut() = self.error.code.status();
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(header::CONTENT_LANGUAGE, HeaderValue::from_static("en"));
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// Middleware rendering the errors of the API in the language the caller asked for
+pub async fn localize(request: Request, next: Next) -> Response {
+    let locale = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(Locale::negotiate)
+        .unwrap_or_default();
+
+    let mut response = next.run(request).await;
+    if locale == Locale::En {
+        return response;
+    }
+    let Some(rendered) = response.extensions_mut().remove::<RenderedError>() else {
+        return response;
+    };
+    *response.body_mut() = Body::from(rendered.body(locale));
+    let headers = response.headers_mut();
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(locale.tag()));
+    response
+}
@@ -0,0 +1,187 @@
+use std::collections::HashSet;
+
+use axum::response::{IntoResponse, Response};
+use common::{
+    DatasetProcessingJob, ImageOperation, IntoDatasetTasks,
+    datasets::{DatasetFormat, dataset_extension},
+    dimensions::{Dimensions, propagate_dimensions},
+    formats::InputFormat,
+    operations_name,
+};
+
+use crate::check_job;
+use crate::utils::{APIError, AppState, DatasetEstimate, DatasetKind, JobEstimate, StageEstimate};
+
+// ============================================================================
+// DRY RUNS
+// A job sent with `dry_run` goes through the same checks as any other, then
+// is estimated instead of being recorded and published. Nothing is downloaded:
+// a prefix is listed and a sample of its images is sized, an archive is sized
+// from the latest inspection an earlier batch recorded for the same dataset,
+// see `common::inspection`. Stages write images about as large as their input
+// scaled by the pixels their operations keep, times come from the operation
+// profiles the scheduler maintains, see `common::profiles`. Both are estimates,
+// an operation without a profile leaves the processing time out.
+// ============================================================================
+
+// Images of a prefix whose size is looked up, the rest are assumed to be of their average size
+const SIZE_SAMPLE: usize = 32;
+
+// Operations with absolute sizes, e.g. a crop, are scaled as if they ran on an image of this size
+const REFERENCE_DIMENSIONS: Dimensions = Dimensions {
+    width: 1024,
+    height: 1024,
+};
+
+/// Estimates a job without recording or publishing anything. The job's tenant has to be set by
+/// the caller, like for `dispatch_job`.
+pub async fn estimate_job(
+    state: &AppState,
+    mut job: DatasetProcessingJob,
+) -> Result<JobEstimate, Response> {
+    let checked = check_job(state, &mut job).await?;
+    let dataset = inspect_dataset(state, &job.dataset_key)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let profiles = state
+        .db
+        .get_operation_profiles()
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let tasks = job.into_dataset_tasks();
+    let read_by_stages: HashSet<uuid::Uuid> =
+        tasks.iter().filter_map(|task| task.input_task_id()).collect();
+
+    let mut stages = Vec::with_capacity(tasks.len());
+    let mut unprofiled_operations = Vec::new();
+    for task in &tasks {
+        let operation = operations_name(task.operations());
+        let mean_ms = profiles
+            .get(&operation)
+            .filter(|profile| profile.stages >= state.config.profiles.min_stages)
+            .map(|profile| profile.mean_ms);
+        if mean_ms.is_none() && !unprofiled_operations.contains(&operation) {
+            unprofiled_operations.push(operation.clone());
+        }
+
+        let applied: Vec<ImageOperation> = task
+            .upstream_operations
+            .iter()
+            .chain(task.operations())
+            .cloned()
+            .collect();
+        let output_bytes = dataset
+            .image_bytes
+            .map(|bytes| (bytes as f64 * pixel_ratio(&applied)) as u64);
+        stages.push(StageEstimate {
+            stage: task.stage,
+            operation,
+            intermediate: read_by_stages.contains(&task.task_id),
+            output_bytes,
+            mean_ms,
+            processing_secs: mean_ms
+                .zip(dataset.images)
+                .map(|(mean_ms, images)| mean_ms * images as f64 / 1000.0),
+        });
+    }
+
+    // The decomposer writes the images of an archive or a single image to S3 for the first stage,
+    // the images of a prefix are read where they are
+    let extracted_bytes = match dataset.kind {
+        DatasetKind::Prefix => Some(0),
+        DatasetKind::Image | DatasetKind::Archive => dataset.image_bytes,
+    };
+    let intermediate_bytes = stages
+        .iter()
+        .filter(|stage| stage.intermediate)
+        .map(|stage| stage.output_bytes)
+        .sum::<Option<u64>>()
+        .zip(extracted_bytes)
+        .map(|(written, extracted)| written + extracted);
+    let processing_secs = stages
+        .iter()
+        .map(|stage| stage.processing_secs)
+        .sum::<Option<f64>>();
+
+    Ok(JobEstimate {
+        dataset_tasks: tasks.len(),
+        image_tasks: dataset.images.map(|images| images * tasks.len() as u64),
+        stages,
+        intermediate_bytes,
+        processing_secs,
+        unprofiled_operations,
+        warnings: checked.report.warnings(),
+        dataset,
+    })
+}
+
+/// What can be found about a dataset without downloading it
+async fn inspect_dataset(state: &AppState, dataset_key: &str) -> Result<DatasetEstimate, APIError> {
+    let input_formats = &state.config.decomposer.input_formats;
+    let format = DatasetFormat::from_key(dataset_key, input_formats)
+        .map_err(APIError::ValidationError)?;
+
+    match format {
+        DatasetFormat::Image => {
+            let info = state.storage.head_object(dataset_key).await?;
+            Ok(DatasetEstimate {
+                kind: DatasetKind::Image,
+                stored_bytes: info.size,
+                images: Some(1),
+                image_bytes: Some(info.size),
+                inspected_by: None,
+            })
+        }
+        DatasetFormat::Prefix => {
+            let keys: Vec<String> = state
+                .storage
+                .list(dataset_key)
+                .await?
+                .into_iter()
+                // The decomposer reads the start of every image for its format, the names have
+                // to do for an estimate
+                .filter(|key| {
+                    dataset_extension(key)
+                        .and_then(InputFormat::from_extension)
+                        .is_some_and(|format| input_formats.contains(&format))
+                })
+                .collect();
+
+            let mut sampled_bytes = 0;
+            for key in keys.iter().take(SIZE_SAMPLE) {
+                sampled_bytes += state.storage.head_object(key).await?.size;
+            }
+            let sampled = keys.len().min(SIZE_SAMPLE) as u64;
+            let image_bytes = match sampled {
+                0 => 0,
+                sampled_images => sampled_bytes * keys.len() as u64 / sampled_images,
+            };
+            Ok(DatasetEstimate {
+                kind: DatasetKind::Prefix,
+                stored_bytes: image_bytes,
+                images: Some(keys.len() as u64),
+                image_bytes: Some(image_bytes),
+                inspected_by: None,
+            })
+        }
+        DatasetFormat::Zip | DatasetFormat::TarGz => {
+            let info = state.storage.head_object(dataset_key).await?;
+            let inspection = state.db.get_latest_dataset_validation(dataset_key).await?;
+            Ok(DatasetEstimate {
+                kind: DatasetKind::Archive,
+                stored_bytes: info.size,
+                images: inspection.as_ref().map(|(_, report)| report.files),
+                image_bytes: inspection.as_ref().map(|(_, report)| report.uncompressed_bytes),
+                inspected_by: inspection.map(|(batch_id, _)| batch_id),
+            })
+        }
+    }
+}
+
+/// The share of an image's pixels left after the operations, which its size is assumed to follow
+fn pixel_ratio(operations: &[ImageOperation]) -> f64 {
+    let output = propagate_dimensions(operations, REFERENCE_DIMENSIONS);
+    let area = |dims: Dimensions| dims.width as f64 * dims.height as f64;
+    area(output) / area(REFERENCE_DIMENSIONS)
+}
@@ -0,0 +1,153 @@
+use std::{convert::Infallible, pin::Pin, sync::Arc};
+
+use axum::{
+    Extension,
+    extract::Path,
+    response::{
+        Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use common::{
+    config::Config,
+    envelope::MessageEnvelope,
+    events::{BATCH_EVENT_MESSAGE_TYPE, BatchEvent, BatchEventKind},
+};
+use queue::{consumer::ConsumerClient, routing::MessageRouter};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{Stream, StreamExt, wrappers::ReceiverStream};
+
+use crate::auth::Caller;
+use crate::batch::find_batch;
+use crate::utils::AppState;
+
+// ============================================================================
+// BATCH EVENT STREAMS
+// Every api-server consumes the whole events topic and fans the events out to
+// the browsers following a batch over Server-Sent Events, see
+// `common::events`. A client that falls behind misses the events it couldn't
+// keep up with rather than holding up the others, the stream ends with the
+// batch's `batch_done` event.
+// ============================================================================
+
+const EVENTS_GROUP_PREFIX: &str = "api-server-events";
+
+// Events buffered for the slowest stream before it starts missing them
+const HUB_CAPACITY: usize = 1024;
+
+// Events buffered for one client before it's disconnected
+const STREAM_CAPACITY: usize = 64;
+
+/// Fans the events consumed from the events topic out to the open streams
+pub struct BatchEventHub {
+    sender: broadcast::Sender<BatchEvent>,
+}
+
+impl BatchEventHub {
+    /// Consumes the events topic for as long as the api-server runs
+    pub fn spawn(config: &Config) -> Arc<Self> {
+        let (sender, _) = broadcast::channel(HUB_CAPACITY);
+        let hub = Arc::new(Self { sender });
+
+        let consumer = match ConsumerClient::broadcast_from_config(
+            &config.kafka,
+            EVENTS_GROUP_PREFIX,
+            &[&config.kafka.events_topic],
+        ) {
+            Ok(consumer) => consumer,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to consume batch events");
+                return hub;
+            }
+        };
+
+        let sender = hub.sender.clone();
+        let handler = move |envelope: MessageEnvelope<BatchEvent>| {
+            // Fails when no stream is open, which is fine
+            let _ = sender.send(envelope.payload);
+            async { Ok(()) }
+        };
+        let router = MessageRouter::new().on(BATCH_EVENT_MESSAGE_TYPE, handler);
+        tokio::spawn(async move { consumer.start_routing(router).await });
+
+        hub
+    }
+
+    /// The events of a batch from now on, up to and including its final one
+    fn follow(&self, batch_id: uuid::Uuid) -> ReceiverStream<BatchEvent> {
+        let mut events = self.sender.subscribe();
+        let (sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+
+        tokio::spawn(async move {
+            loop {
+                let received = tokio::select! {
+                    received = events.recv() => received,
+                    // The client went away
+                    _ = sender.closed() => return,
+                };
+                let event = match received {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!(%batch_id, missed, "Batch event stream fell behind");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if event.batch_id != batch_id {
+                    continue;
+                }
+                let last = event.is_final();
+                if sender.send(event).await.is_err() || last {
+                    return;
+                }
+            }
+        });
+
+        ReceiverStream::new(receiver)
+    }
+}
+
+/// Streams the progress events of a batch as Server-Sent Events.
+///
+/// Every event is named after its kind (`task_started`, `image_completed`, `stage_finished` or
+/// `batch_done`) and carries the JSON of the `common::events::BatchEvent`. Only events published
+/// after the stream was opened are sent, the status endpoint tells where the batch stands. A
+/// batch that already finished gets its `batch_done` event right away.
+///
+/// # Returns
+/// - `200 OK` with a `text/event-stream` that ends after the batch finished.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_events(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
+    // Subscribed before the batch is read, so it can't finish in between unnoticed
+    let events = state.events.follow(batch_id);
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+
+    // Dropping the subscription of a finished batch stops its forwarding task
+    let events: Pin<Box<dyn Stream<Item = BatchEvent> + Send>> =
+        match batch.state.is_final() {
+            true => {
+                let done = BatchEvent::new(
+                    batch_id,
+                    batch.tenant_id,
+                    BatchEventKind::BatchDone { state: batch.state },
+                );
+                Box::pin(tokio_stream::once(done))
+            }
+            false => Box::pin(events),
+        };
+
+    let stream = events.map(|event| {
+        let sse = Event::default().event(event.name()).json_data(&event);
+        Ok::<_, Infallible>(sse.unwrap_or_else(|e| {
+            tracing::warn!(error = %e, "Failed to encode batch event");
+            Event::default().comment("unencodable event")
+        }))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,233 @@
+use std::{collections::HashSet, time::Duration};
+
+use axum::{
+    Extension,
+    response::{IntoResponse, Json, Response},
+};
+use bytes::Bytes;
+use chrono::Utc;
+use common::datasets::{ARCHIVE_EXTENSIONS, dataset_extension};
+use common::error::ProcessorError;
+use common::tenancy::{strip_tenant_prefix, tenant_key};
+use storage::PutOptions;
+
+use crate::VALID_UPLOAD_EXTENSIONS;
+use crate::auth::Caller;
+use crate::multipart::check_dataset_key;
+use crate::utils::{
+    APIError, AppState, FieldError, FilesUploadCompleteRequest, FilesUploadCompleteResponse,
+    FilesUploadRequest, FilesUploadResponse, PresignedFile,
+};
+
+// ============================================================================
+// BATCHED FILE UPLOADS
+// Datasets of loose images uploaded file by file under a common prefix,
+// for clients with too many images to zip locally. The prefix can only be
+// submitted once the upload is sealed by `complete`, which checks that every
+// file arrived and writes a marker next to the prefix, so a job never runs
+// on a half-uploaded dataset.
+// ============================================================================
+
+const MAX_FILES: usize = 10_000;
+const FILE_URL_EXPIRY: Duration = Duration::from_secs(3600);
+
+/// The prefix the files of a dataset are uploaded under, inside the prefix of the tenant
+fn files_upload_prefix(tenant_id: Option<&str>, dataset_name: &str) -> String {
+    tenant_key(tenant_id, &format!("uploads/{}/files/", dataset_name))
+}
+
+/// Whether the dataset key is the prefix of a batched file upload
+pub(crate) fn is_files_upload(tenant_id: Option<&str>, dataset_key: &str) -> bool {
+    strip_tenant_prefix(tenant_id, dataset_key)
+        .is_some_and(|key| key.starts_with("uploads/") && key.ends_with("/files/"))
+}
+
+/// The marker written by `complete`, next to the prefix rather than in it so the decomposer
+/// doesn't list it as an image
+fn seal_key(dataset_key: &str) -> String {
+    format!("{}.sealed", dataset_key.trim_end_matches('/'))
+}
+
+/// Whether the upload under the prefix was completed
+pub(crate) async fn is_sealed(state: &AppState, dataset_key: &str) -> Result<bool, APIError> {
+    match state.storage.head_object(&seal_key(dataset_key)).await {
+        Ok(_) => Ok(true),
+        Err(ProcessorError::NotFound(_)) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Checks the filenames of a batched upload, reporting every invalid one at once
+fn validate_filenames(filenames: &[String]) -> Result<(), APIError> {
+    if filenames.is_empty() {
+        return Err(APIError::ValidationError(
+            "A dataset upload needs at least one file".to_string(),
+        ));
+    }
+    if filenames.len() > MAX_FILES {
+        return Err(APIError::ValidationError(format!(
+            "A dataset upload is limited to {} files, got {}",
+            MAX_FILES,
+            filenames.len()
+        )));
+    }
+
+    let images: Vec<&str> = VALID_UPLOAD_EXTENSIONS
+        .into_iter()
+        .filter(|ext| !ARCHIVE_EXTENSIONS.contains(ext))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut errors = Vec::new();
+    for (i, filename) in filenames.iter().enumerate() {
+        let field = format!("filenames[{}]", i);
+        // Relative paths only, so every key stays under the dataset's prefix
+        if filename.starts_with('/')
+            || filename.contains('\\')
+            || filename
+                .split('/')
+                .any(|part| part.is_empty() || part == "." || part == "..")
+        {
+            errors.push(FieldError::new(field, "Must be a relative path like \"cats/001.jpg\""));
+            continue;
+        }
+        let ext = dataset_extension(filename).unwrap_or("");
+        if !images.contains(&ext) {
+            errors.push(FieldError::new(
+                field,
+                format!("Must be an image, one of {}", images.join(", ")),
+            ));
+            continue;
+        }
+        if !seen.insert(filename.as_str()) {
+            errors.push(FieldError::new(field, "Is listed more than once"));
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(APIError::InvalidFields(errors)),
+    }
+}
+
+/// Creates a presigned URL per file of a dataset of loose images, all under a common prefix, for
+/// clients with too many images to build an archive for `/upload_dataset`.
+///
+/// The client uploads the files (in any order, retrying the ones that failed) and seals the
+/// dataset with `complete`, after which the prefix can be submitted as the dataset key.
+///
+/// # Returns
+/// - `200 OK` with the dataset prefix and the presigned URL of every file.
+/// - `422 Unprocessable Entity` if a filename is invalid, or the dataset was already sealed.
+/// - `500 Internal Server Error` if URL generation fails.
+#[axum::debug_handler]
+pub async fn create_files_upload(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<FilesUploadRequest>,
+) -> Result<Json<FilesUploadResponse>, Response> {
+    validate_filenames(&request.filenames).map_err(IntoResponse::into_response)?;
+
+    let dataset_key = files_upload_prefix(caller.tenant_id(), &request.dataset_name);
+    if is_sealed(&state, &dataset_key)
+        .await
+        .map_err(IntoResponse::into_response)?
+    {
+        return Err(APIError::ValidationError(format!(
+            "Dataset {} is already sealed, upload under another name",
+            dataset_key
+        ))
+        .into_response());
+    }
+
+    let mut files = Vec::with_capacity(request.filenames.len());
+    for filename in request.filenames {
+        let key = format!("{}{}", dataset_key, filename);
+        let presigned_url = state
+            .storage
+            .presign_put(&key, FILE_URL_EXPIRY)
+            .await
+            .map_err(|_| {
+                APIError::UploadError("Failed to generate presigned URL".to_string())
+                    .into_response()
+            })?;
+        files.push(PresignedFile {
+            filename,
+            key,
+            presigned_url,
+        });
+    }
+
+    Ok(Json(FilesUploadResponse {
+        dataset_key,
+        files,
+        expires_in_secs: FILE_URL_EXPIRY.as_secs(),
+    }))
+}
+
+/// Seals a batched upload once every file is uploaded, after which the dataset key can be
+/// submitted like one uploaded through `/upload_dataset`. Sealing twice is harmless.
+///
+/// # Returns
+/// - `200 OK` with the dataset key and the number of files.
+/// - `422 Unprocessable Entity` if the key isn't a batched upload of the caller or a file
+///   wasn't uploaded, listing every missing file.
+/// - `500 Internal Server Error` if the seal can't be written.
+#[axum::debug_handler]
+pub async fn complete_files_upload(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<FilesUploadCompleteRequest>,
+) -> Result<Json<FilesUploadCompleteResponse>, Response> {
+    check_dataset_key(&caller, &request.dataset_key).map_err(IntoResponse::into_response)?;
+    if !is_files_upload(caller.tenant_id(), &request.dataset_key) {
+        return Err(APIError::ValidationError(format!(
+            "{} is not a batched file upload",
+            request.dataset_key
+        ))
+        .into_response());
+    }
+    validate_filenames(&request.filenames).map_err(IntoResponse::into_response)?;
+
+    let uploaded: HashSet<String> = state
+        .storage
+        .list(&request.dataset_key)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .collect();
+    let missing: Vec<FieldError> = request
+        .filenames
+        .iter()
+        .enumerate()
+        .filter(|(_, filename)| {
+            !uploaded.contains(&format!("{}{}", request.dataset_key, filename))
+        })
+        .map(|(i, _)| FieldError::new(format!("filenames[{}]", i), "Was not uploaded"))
+        .collect();
+    if !missing.is_empty() {
+        return Err(APIError::InvalidFields(missing).into_response());
+    }
+
+    let seal = serde_json::json!({
+        "file_count": request.filenames.len(),
+        "sealed_at": Utc::now().to_rfc3339(),
+    });
+    let options = PutOptions {
+        content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+    state
+        .storage
+        .put_object(
+            &seal_key(&request.dataset_key),
+            Bytes::from(seal.to_string()),
+            &options,
+        )
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(FilesUploadCompleteResponse {
+        dataset_key: request.dataset_key,
+        file_count: request.filenames.len(),
+    }))
+}
@@ -0,0 +1,142 @@
+use axum::{
+    Extension,
+    extract::{Multipart, multipart::Field},
+    response::{IntoResponse, Json, Response},
+};
+use bytes::{Bytes, BytesMut};
+use common::DatasetProcessingJob;
+use common::datasets::dataset_extension;
+use storage::PutOptions;
+
+use crate::auth::Caller;
+use crate::utils::{APIError, AppState, InlineSubmitResponse};
+use crate::{VALID_UPLOAD_EXTENSIONS, dataset_upload_key, dispatch_job, dto};
+
+// ============================================================================
+// INLINE SUBMISSION
+// Small datasets can be submitted together with their pipeline in a single
+// multipart request, so CLI and CI callers skip the presigned upload. The
+// body is exempt from the JSON-only request limits, the dataset is bounded by
+// `api.max_inline_dataset_bytes` instead.
+// ============================================================================
+
+const PIPELINE_FIELD: &str = "pipeline";
+const DATASET_FIELD: &str = "dataset";
+
+/// Uploads the dataset of a multipart body and submits the pipeline next to it as a job.
+///
+/// The body has a `pipeline` field holding the `/send_task` JSON without its `dataset_key` and a
+/// `dataset` file field holding the zip or single image.
+///
+/// # Returns
+/// - `200 OK` with the key the dataset was uploaded to and the dispatched batch.
+/// - `400 Bad Request` if the file type isn't supported.
+/// - `413 Payload Too Large` if the dataset is larger than `api.max_inline_dataset_bytes`.
+/// - `422 Unprocessable Entity` if a field is missing or the pipeline is invalid.
+#[axum::debug_handler]
+pub async fn submit_inline(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    mut multipart: Multipart,
+) -> Result<Json<InlineSubmitResponse>, Response> {
+    let max_bytes = state.config.api.max_inline_dataset_bytes;
+    let mut pipeline = None;
+    let mut dataset = None;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| APIError::ValidationError(e.body_text()).into_response())?
+    {
+        match field.name() {
+            Some(PIPELINE_FIELD) => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| APIError::ValidationError(e.body_text()).into_response())?;
+                let value: serde_json::Value = serde_json::from_str(&text).map_err(|e| {
+                    APIError::ValidationError(format!("Invalid pipeline: {}", e)).into_response()
+                })?;
+                pipeline = Some(value);
+            }
+            Some(DATASET_FIELD) => {
+                let ext = field
+                    .file_name()
+                    .and_then(dataset_extension)
+                    .map(|ext| ext.to_lowercase())
+                    .unwrap_or_default();
+                if !VALID_UPLOAD_EXTENSIONS.contains(&ext.as_str()) {
+                    return Err(APIError::unsupported_extension(&ext, &VALID_UPLOAD_EXTENSIONS)
+                        .into_response());
+                }
+                let bytes = read_bounded(field, max_bytes)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                dataset = Some((ext, bytes));
+            }
+            // Unknown fields are skipped, like unknown keys of a JSON body
+            _ => {}
+        }
+    }
+
+    let missing = |name: &str| {
+        APIError::ValidationError(format!("The body has no '{}' field", name)).into_response()
+    };
+    let mut pipeline = pipeline.ok_or_else(|| missing(PIPELINE_FIELD))?;
+    let (ext, bytes) = dataset.ok_or_else(|| missing(DATASET_FIELD))?;
+
+    // Every inline dataset gets a name of its own, there is no earlier upload to refer to
+    let dataset_name = format!("inline-{}", uuid::Uuid::new_v4());
+    let dataset_key = dataset_upload_key(caller.tenant_id(), &dataset_name, &ext);
+
+    // Parsed before uploading, so an invalid pipeline doesn't leave an orphaned dataset behind
+    let Some(fields) = pipeline.as_object_mut() else {
+        return Err(
+            APIError::ValidationError("The pipeline has to be a JSON object".to_string())
+                .into_response(),
+        );
+    };
+    fields.insert("dataset_key".to_string(), dataset_key.clone().into());
+    let request: dto::v1::JobRequest = serde_json::from_value(pipeline).map_err(|e| {
+        APIError::ValidationError(format!("Invalid pipeline: {}", e)).into_response()
+    })?;
+
+    let options = PutOptions {
+        content_type: mime_guess::from_ext(&ext).first_raw().map(String::from),
+        ..Default::default()
+    };
+    state
+        .storage
+        .put_object(&dataset_key, bytes, &options)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    let mut job: DatasetProcessingJob = request.into();
+    job.tenant_id = caller.tenant_id().map(String::from);
+    let dispatch = dispatch_job(&state, job, None, None).await?;
+
+    Ok(Json(InlineSubmitResponse {
+        dataset_key,
+        dispatch,
+    }))
+}
+
+/// Reads a field into memory, failing as soon as it grows past `max_bytes`
+async fn read_bounded(mut field: Field<'_>, max_bytes: u64) -> Result<Bytes, APIError> {
+    let mut buffer = BytesMut::new();
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| APIError::ValidationError(e.body_text()))?
+    {
+        if (buffer.len() + chunk.len()) as u64 > max_bytes {
+            return Err(APIError::PayloadTooLarge(format!(
+                "Inline datasets are limited to {} bytes, upload larger ones through /upload_dataset",
+                max_bytes
+            )));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+
+    Ok(buffer.freeze())
+}
@@ -0,0 +1,125 @@
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Request, State},
+    http::{Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use common::config::ApiConfig;
+
+use crate::errors::{CatalogError, ErrorCode};
+use crate::utils::APIError;
+
+// ============================================================================
+// REQUEST LIMITS
+// Every request with a body is checked before it reaches a handler: the body
+// is buffered up to `max_body_bytes` only, it has to be JSON, and its arrays
+// and objects can't be nested deeper than `max_json_depth`. Without this an
+// oversized operation list was buffered and deserialized without bound.
+// ============================================================================
+
+/// Middleware enforcing the body limits of the config on POST, PUT and PATCH requests
+pub async fn enforce_request_limits(
+    State(limits): State<ApiConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !matches!(*request.method(), Method::POST | Method::PUT | Method::PATCH) {
+        return next.run(request).await;
+    }
+
+    match check_body(&limits, request).await {
+        Ok(request) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Buffers and checks the body, returning the request rebuilt around the buffered body
+async fn check_body(limits: &ApiConfig, request: Request) -> Result<Request, APIError> {
+    let too_large = || {
+        let error = CatalogError::new(ErrorCode::BodyTooLarge).with("limit", limits.max_body_bytes);
+        APIError::Catalogued(error)
+    };
+
+    // Reject on the declared length before reading anything
+    let declared_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if declared_length.is_some_and(|length| length > limits.max_body_bytes) {
+        return Err(too_large());
+    }
+
+    let (parts, body) = request.into_parts();
+    // Catches chunked bodies and lengths that were understated
+    let bytes = to_bytes(body, limits.max_body_bytes)
+        .await
+        .map_err(|_| too_large())?;
+
+    // Endpoints like /batch/:batch_id/snapshot are POSTed without a body
+    if !bytes.is_empty() {
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !is_json(content_type) {
+            let error =
+                CatalogError::new(ErrorCode::JsonRequired).with("content_type", content_type);
+            return Err(APIError::Catalogued(error));
+        }
+
+        let depth = json_depth(&bytes);
+        if depth > limits.max_json_depth {
+            return Err(APIError::ValidationError(format!(
+                "JSON body is nested {} levels deep, the limit is {}",
+                depth, limits.max_json_depth
+            )));
+        }
+    }
+
+    Ok(Request::from_parts(parts, Body::from(bytes)))
+}
+
+/// Whether the content type is `application/json`, optionally with parameters like a charset
+fn is_json(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// The deepest nesting of arrays and objects in a JSON document, without parsing it.
+///
+/// Brackets inside strings are skipped, malformed documents are left to the JSON extractor.
+fn json_depth(bytes: &[u8]) -> usize {
+    let mut depth = 0usize;
+    let mut max_depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for &byte in bytes {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    max_depth
+}
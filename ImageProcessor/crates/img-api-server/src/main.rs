@@ -1,29 +1,76 @@
-use aws_config;
-
-use aws_sdk_s3::{Client, presigning::PresigningConfig};
-
 use axum::{
     Extension, Router,
+    extract::DefaultBodyLimit,
+    http::header,
+    middleware,
     response::Json,
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post, put},
 };
 
-use std::{env, sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use chrono::Utc;
 
 use tokio::net::TcpListener;
 
-use common::DatasetProcessingJob;
-use db_utils::types::DBClient;
-use queue::{ProducerClient, admin::KafkaAdmin};
+use common::{
+    DatasetProcessingJob, IntoDatasetTasks, Priority,
+    config::{Config, QueueKind, StorageKind},
+    datasets::dataset_extension,
+    error::ProcessorError,
+    faults,
+    lifecycle::BatchState,
+    logging, metrics,
+    presets::Preset,
+    reproducibility::ConfigSnapshot,
+    tagging::validate_object_tags,
+    tenancy::{TenantQuotas, strip_tenant_prefix, tenant_key, validate_tenant_id},
+    validation::PipelineReport,
+};
+use db_utils::types::{DBClient, DBPipeline, SweepMembership, TaskStatus};
+use queue::{
+    ProducerClient, admin::KafkaAdmin, events::BatchEventPublisher, migration::TopicSwitcher,
+    partitioner::AffinityPartitioner,
+};
+use storage::ObjectInfo;
+mod adhoc;
+mod admin;
+mod auth;
+mod batch;
+mod correlation;
+mod downloads;
+mod dto;
+mod errors;
+mod estimate;
+mod events;
+mod files;
+mod inline;
+mod limits;
+mod multipart;
+mod notifications;
+mod pipelines;
+mod presigned;
+mod previews;
+mod ratelimit;
+mod schema;
+mod sharing;
+mod sweep;
+mod tenants;
 mod utils;
+mod v2;
+use crate::auth::Caller;
+use crate::errors::{CatalogError, ErrorCode};
 use crate::utils::{APIError, DatasetUploadResponse, UploadRequest};
 
-const S3_BUCKET: &str = "rust-backend-proj-bucket";
+const VALID_UPLOAD_EXTENSIONS: [&str; 10] = [
+    "jpg", "jpeg", "png", "bmp", "tiff", "tif", "webp", "zip", "tar.gz", "tgz",
+];
 
-async fn get_s3_client() -> Client {
-    let config = aws_config::load_from_env().await;
-    Client::new(&config)
+/// The key a dataset is uploaded to, inside the prefix of the uploading tenant. The extension is
+/// kept so the decomposer can tell a single image from an archive.
+pub(crate) fn dataset_upload_key(tenant_id: Option<&str>, dataset_name: &str, ext: &str) -> String {
+    tenant_key(tenant_id, &format!("uploads/{}/input.{}", dataset_name, ext))
 }
 
 /// Handles the creation of a presigned URL for dataset uploads.
@@ -33,7 +80,7 @@ async fn get_s3_client() -> Client {
 /// the dataset directly to S3.
 ///
 /// # Arguments
-/// - `state`: Shared application state containing the S3 client.
+/// - `state`: Shared application state containing the storage backend.
 /// - `request`: The upload request payload, including filename and dataset name.
 ///
 /// # Returns
@@ -42,29 +89,21 @@ async fn get_s3_client() -> Client {
 #[axum::debug_handler]
 async fn create_dataset_upload(
     Extension(state): Extension<utils::AppState>,
+    Extension(caller): Extension<Caller>,
     Json(request): Json<UploadRequest>,
 ) -> Result<Json<DatasetUploadResponse>, Response> {
     // First, we validate the content type
-    let valid_ext = ["jpg", "png", "bmp", "tiff", "tif", "zip"];
-    let ext = request.filename.split(".").last().unwrap_or("");
+    let ext = dataset_extension(&request.filename).unwrap_or("");
 
-    if !valid_ext.contains(&ext) {
-        return Err(APIError::UploadError("Wrong File type".to_string()).into_response());
+    if !VALID_UPLOAD_EXTENSIONS.contains(&ext) {
+        return Err(APIError::unsupported_extension(ext, &VALID_UPLOAD_EXTENSIONS).into_response());
     }
 
     // Otherwise, we generate a presigned url for the client to use
-    let s3_key = format!("uploads/{}/input.zip", request.dataset_name);
-    let dur = Duration::from_secs(900);
-    let conf = PresigningConfig::expires_in(dur).map_err(|_| {
-        APIError::UploadError("Failed to generate presigned URL".to_string()).into_response()
-    })?;
-
-    let url = state
-        .s3_client
-        .put_object()
-        .bucket(S3_BUCKET)
-        .key(&s3_key)
-        .presigned(conf)
+    let s3_key = dataset_upload_key(caller.tenant_id(), &request.dataset_name, ext);
+    let presigned_url = state
+        .storage
+        .presign_put(&s3_key, Duration::from_secs(900))
         .await
         .map_err(|_| {
             APIError::UploadError("Failed to generate presigned URL".to_string()).into_response()
@@ -72,93 +111,656 @@ async fn create_dataset_upload(
 
     Ok(Json(DatasetUploadResponse {
         dataset_key: s3_key,
-        presigned_url: url.uri().into(),
+        presigned_url,
     }))
 }
 
+/// Dispatches a job, or only estimates it when the request sets `dry_run`.
+///
+/// # Returns
+/// - `200 OK` with the dispatched batch, or with a `JobEstimate` for a dry run.
+/// - `404 Not Found` if the dataset doesn't exist.
+/// - `422 Unprocessable Entity` if the job is invalid.
 #[axum::debug_handler]
 async fn handle_dataset_task(
     Extension(state): Extension<utils::AppState>,
-    Json(mut request): Json<DatasetProcessingJob>,
-) -> Result<Json<utils::TaskDispatchResult>, Response> {
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<dto::v1::JobRequest>,
+) -> Result<Response, Response> {
+    let dry_run = request.dry_run;
+    let mut job: DatasetProcessingJob = request.into();
+    job.tenant_id = caller.tenant_id().map(String::from);
+    match dry_run {
+        true => estimate::estimate_job(&state, job)
+            .await
+            .map(|estimate| Json(estimate).into_response()),
+        false => dispatch_job(&state, job, None, None)
+            .await
+            .map(|result| Json(result).into_response()),
+    }
+}
+
+/// Checks that the dataset of a job exists and is within the configured limits, so a job for a
+/// missing or truncated upload is rejected here instead of failing in the decomposer
+async fn check_dataset_object(
+    state: &utils::AppState,
+    dataset_key: &str,
+) -> Result<ObjectInfo, APIError> {
+    let limits = &state.config.api;
+    let info = match state.storage.head_object(dataset_key).await {
+        Ok(info) => info,
+        Err(ProcessorError::NotFound(_)) => {
+            return Err(APIError::dataset_not_found(dataset_key));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if info.size < limits.min_dataset_bytes {
+        return Err(APIError::ValidationError(format!(
+            "Dataset {} is {} bytes, at least {} are required",
+            dataset_key, info.size, limits.min_dataset_bytes
+        )));
+    }
+    if let Some(max_bytes) = limits.max_dataset_bytes
+        && info.size > max_bytes
+    {
+        return Err(APIError::ValidationError(format!(
+            "Dataset {} is {} bytes, the limit is {}",
+            dataset_key, info.size, max_bytes
+        )));
+    }
+    if let (Some(max_age_days), Some(last_modified)) =
+        (limits.max_dataset_age_days, info.last_modified)
+        && Utc::now() - last_modified > chrono::Duration::days(max_age_days as i64)
+    {
+        return Err(APIError::ValidationError(format!(
+            "Dataset {} was last modified at {}, datasets older than {} days are rejected",
+            dataset_key,
+            last_modified.to_rfc3339(),
+            max_age_days
+        )));
+    }
+
+    Ok(info)
+}
+
+/// Checks that a dataset of loose images has any, returns how many objects are under its prefix
+async fn check_dataset_prefix(
+    state: &utils::AppState,
+    dataset_prefix: &str,
+) -> Result<usize, APIError> {
+    let keys = state.storage.list(dataset_prefix).await?;
+    if keys.is_empty() {
+        return Err(APIError::NotFoundError(format!(
+            "Dataset {} does not exist, no objects are under the prefix",
+            dataset_prefix
+        )));
+    }
+
+    Ok(keys.len())
+}
+
+fn quota_exceeded(detail: String) -> Response {
+    APIError::Catalogued(CatalogError::new(ErrorCode::QuotaExceeded).with("detail", detail))
+        .into_response()
+}
+
+/// The quotas of the job's tenant, none for tenants that weren't onboarded, checking the ones
+/// that don't depend on the dataset
+async fn check_quotas(
+    state: &utils::AppState,
+    tenant_id: Option<&str>,
+) -> Result<TenantQuotas, Response> {
+    let Some(tenant_id) = tenant_id else {
+        return Ok(TenantQuotas::default());
+    };
+    let quotas = state
+        .db
+        .get_tenant(tenant_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .map(|tenant| tenant.quotas)
+        .unwrap_or_default();
+
+    if let Some(max_active) = quotas.max_active_batches {
+        let active = state
+            .db
+            .count_active_batches(tenant_id)
+            .await
+            .map_err(|e| APIError::from(e).into_response())?;
+        if active >= max_active {
+            return Err(quota_exceeded(format!(
+                "{} batches are running, the limit is {}",
+                active, max_active
+            )));
+        }
+    }
+
+    Ok(quotas)
+}
+
+/// What the checks of a job found, for dispatching it
+struct CheckedJob {
+    preset: Option<&'static Preset>,
+    pipeline: Option<DBPipeline>, // The tenant's pipeline the job named, see `pipelines`
+    report: PipelineReport,
+    config: ConfigSnapshot, // The api-server's settings and what was found about the dataset
+}
+
+/// Runs the checks a job goes through before it is dispatched, without recording or publishing
+/// anything: expands its pipeline and preset, validates it and checks that its dataset exists
+/// within the limits. The job's tenant has to be set by the caller.
+async fn check_job(
+    state: &utils::AppState,
+    request: &mut DatasetProcessingJob,
+) -> Result<CheckedJob, Response> {
+    // The preset runs ahead of the pipeline, like the pipeline runs ahead of the job's operations
+    let pipeline = pipelines::expand_pipeline(state, request)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let preset = request
+        .expand_preset()
+        .map_err(|e| APIError::ValidationError(e).into_response())?;
+    validate_object_tags(request.owner.as_deref(), &request.tags)
+        .map_err(|e| APIError::ValidationError(e).into_response())?;
+
+    // Reject invalid fields and pipelines whose operations can't be meaningfully combined before
+    // touching the db
+    let report = schema::validate_job(request).map_err(IntoResponse::into_response)?;
+
+    // Datasets of other tenants are reported as missing, like the batches of other tenants
+    if strip_tenant_prefix(request.tenant_id.as_deref(), &request.dataset_key).is_none() {
+        return Err(APIError::dataset_not_found(&request.dataset_key).into_response());
+    }
+    let quotas = check_quotas(state, request.tenant_id.as_deref()).await?;
+    let mut config = ConfigSnapshot::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+        .with_setting("s3_bucket", &state.config.s3.bucket)
+        .with_setting("dataset_topic", state.kafka_client.topic())
+        .with_setting("valid_upload_extensions", VALID_UPLOAD_EXTENSIONS.join(","));
+    // A prefix of loose images has no single object whose size and age could be checked
+    if request.dataset_key.ends_with('/') {
+        // A batched file upload can't be processed before every file of it arrived
+        if files::is_files_upload(request.tenant_id.as_deref(), &request.dataset_key)
+            && !files::is_sealed(state, &request.dataset_key)
+                .await
+                .map_err(IntoResponse::into_response)?
+        {
+            return Err(APIError::ValidationError(format!(
+                "Dataset {} is still being uploaded, seal it with /upload_dataset/files/complete",
+                request.dataset_key
+            ))
+            .into_response());
+        }
+        let objects = check_dataset_prefix(state, &request.dataset_key)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        config = config.with_setting("dataset_objects", objects);
+    } else {
+        let dataset = check_dataset_object(state, &request.dataset_key)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if let Some(max_bytes) = quotas.max_dataset_bytes
+            && dataset.size > max_bytes
+        {
+            return Err(quota_exceeded(format!(
+                "dataset {} is {} bytes, the limit is {}",
+                request.dataset_key, dataset.size, max_bytes
+            )));
+        }
+        config = config.with_setting("dataset_size_bytes", dataset.size);
+        if let Some(last_modified) = dataset.last_modified {
+            config = config.with_setting("dataset_last_modified", last_modified.to_rfc3339());
+        }
+    }
+
+    Ok(CheckedJob {
+        preset,
+        pipeline,
+        report,
+        config,
+    })
+}
+
+/// Validates a job, records its batch and publishes its first stage. The job's tenant has to be
+/// set by the caller, its dataset must be one of the tenant's objects.
+async fn dispatch_job(
+    state: &utils::AppState,
+    mut request: DatasetProcessingJob,
+    sweep: Option<SweepMembership>,
+    parent_batch_id: Option<uuid::Uuid>,
+) -> Result<utils::TaskDispatchResult, Response> {
+    let CheckedJob {
+        preset,
+        pipeline,
+        report,
+        mut config,
+    } = check_job(state, &mut request).await?;
+
     // First, we send the initial batch dataset task to the db before splitting it
-    request.batch_id = Some(uuid::Uuid::new_v4());
+    let batch_id = uuid::Uuid::new_v4();
+    request.batch_id = Some(batch_id);
+    if let Some(preset) = preset {
+        config = config.with_setting("preset", preset.reference());
+    }
+    if let Some(pipeline) = pipeline {
+        config = config.with_setting("pipeline", pipeline.reference());
+    }
+    config = config
+        .with_operations(&request.operations)
+        .with_output_format(request.output_format);
 
-    if let Err(_) = state.db.add_multi_operation_dataset(&request).await {
+    if state
+        .db
+        .add_multi_operation_dataset(
+            &request,
+            config,
+            sweep,
+            parent_batch_id,
+            state.config.retention.results_days,
+        )
+        .await
+        .is_err()
+    {
         return Err(
             APIError::DatabaseError("Failed to send batched data into DB".to_string())
                 .into_response(),
         );
     }
 
-    // First, we add the dataset to the kafka queue, and see our results
-    let insertions = match state.kafka_client.send_dataset(request).await {
+    // Next, we record the dataset tasks before any of them is sent, so the consumer finds the
+    // task of the first stage it picks up. The scheduler publishes the later stages once their
+    // dependency has finished. With the outbox the first stages get an entry in the same
+    // transaction, which the scheduler relays should the sends below fail.
+    let tasks = request.into_dataset_tasks();
+    let task_ids: Vec<uuid::Uuid> = tasks.iter().map(|task| task.task_id).collect();
+    let outbox = state.config.outbox.enabled;
+    let recorded = match outbox {
+        true => state.db.add_datasets_with_outbox(&tasks).await,
+        false => state.db.add_datasets(&tasks).await.map(|_| ()),
+    };
+    if recorded.is_err() {
+        return Err(APIError::DatabaseError("Failed to send to DB".to_string()).into_response());
+    }
+
+    // Then, we add the first stages to the kafka queue, and see our results
+    let insertions = match state.kafka_client.send_dataset(batch_id, tasks).await {
         Ok(passed) => Ok(passed),
         Err(_) => {
             Err(APIError::SendTaskError("Failed to send task to Queue".to_string()).into_response())
         }
     }?;
 
-    // Next, we insert into the database, but only the successful ones
-    let _db_insert_result = match state.db.add_datasets(&insertions.successes).await {
-        Ok(res) => Ok(res),
-        Err(_) => Err(APIError::DatabaseError("Failed to send to DB".to_string()).into_response()),
-    }?;
+    if outbox {
+        for task in &insertions.successes {
+            if let Err(e) = state.db.mark_dataset_outbox_sent(&task.task_id).await {
+                tracing::warn!(
+                    task_id = %task.task_id,
+                    error = %e,
+                    "Failed to mark the outbox entry of a task sent"
+                );
+            }
+        }
+        if !insertions.failures.is_empty() {
+            tracing::warn!(
+                %batch_id,
+                unsent = insertions.failures.len(),
+                "Left dataset tasks to the outbox relay"
+            );
+        }
+    }
+
+    // Later stages can only run after the first one. Without the outbox a first stage that
+    // couldn't be sent is failed along with the batch, which skips the stages depending on it.
+    if !outbox && !insertions.failures.is_empty() {
+        if let Err(e) = state.db.transition_batch(&batch_id, BatchState::Failed).await {
+            tracing::error!(%batch_id, error = %e, "Failed to fail the batch of unsent tasks");
+        }
+        for task in &insertions.failures {
+            if let Err(e) = state
+                .db
+                .transition_dataset_task(&task.task_id, TaskStatus::Ready, TaskStatus::Failure)
+                .await
+            {
+                tracing::error!(task_id = %task.task_id, error = %e, "Failed to fail unsent task");
+            }
+        }
+        return Err(
+            APIError::SendTaskError("Failed to send task to Queue".to_string()).into_response(),
+        );
+    }
 
-    Ok(Json(utils::TaskDispatchResult {
+    tracing::info!(
+        batch_id = %insertions.batch_id,
+        tasks = task_ids.len(),
+        "Dispatched batch"
+    );
+    Ok(utils::TaskDispatchResult {
         batch_id: insertions.batch_id,
-        task_ids: insertions
-            .successes
-            .into_iter()
-            .map(|task| task.task_id)
-            .collect(),
+        task_ids,
         message: "Tasks successfully dispatched".to_string(),
-    }))
+        warnings: report.warnings(),
+    })
+}
+
+/// Prometheus metrics of the api-server, in the text exposition format
+async fn serve_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::encode(),
+    )
 }
 
 #[tokio::main]
 async fn main() {
-    println!("Starting server...");
-
-    // Load environment variables
-    let broker = env::var("KAFKA_BROKER").expect("Faield to receive variable from environment.");
+    // Load the config file and environment variables
+    let config = Config::load().expect("Failed to load config");
+    logging::init(&config.logging);
+    faults::install(&config.faults);
+    tracing::info!("Starting server...");
+    for tenant_id in config.auth.api_keys.values() {
+        validate_tenant_id(tenant_id).expect("Invalid tenant id in the API keys");
+    }
 
-    // First, we want to make sure that the kafka topic exists, so we can create an admin client
-    {
-        let admin_client = KafkaAdmin::new(&broker);
+    // First, we want to make sure that the kafka topic exists, so we can create an admin client.
+    // A read replica never sends to Kafka and has to start while it is down, and the in-process
+    // queue has no topics to create.
+    let read_only = config.api.read_only;
+    if !read_only && config.kafka.backend == QueueKind::Kafka {
+        let admin_client = KafkaAdmin::new(&config.kafka.brokers);
         admin_client
-            .create_topic("dataset-tasks", 3)
+            .create_topic(&config.kafka.dataset_topic, config.kafka.topic_partitions)
             .await
             .expect("Failed to create topic");
+        // Image tasks travel on the image topic and the topics operations are routed to
+        let image_topics = config.kafka.image_topics();
+        for topic in &image_topics {
+            admin_client
+                .create_topic(topic, config.kafka.topic_partitions)
+                .await
+                .expect("Failed to create image topic");
+        }
+        // Tasks an image worker couldn't run wait there for one that can
+        if config.capabilities.holding {
+            admin_client
+                .create_topic(&config.capabilities.holding_topic, config.kafka.topic_partitions)
+                .await
+                .expect("Failed to create holding topic");
+        }
+        // A single partition keeps the commands in the order they were sent
         admin_client
-            .create_topic("image-tasks", 3)
+            .create_topic(&config.kafka.control_topic, 1)
             .await
-            .expect("Failed to create image topic");
+            .expect("Failed to create control topic");
+        // A single partition keeps the switches in the order they were published
+        admin_client
+            .create_topic(&config.kafka.migration_topic, 1)
+            .await
+            .expect("Failed to create migration topic");
+        // A single partition keeps the manifests of a batch in the order they were published
+        admin_client
+            .create_topic(&config.kafka.manifest_topic, 1)
+            .await
+            .expect("Failed to create manifest topic");
+        // A single partition keeps the events of a batch in the order they happened
+        admin_client
+            .create_topic(&config.kafka.events_topic, 1)
+            .await
+            .expect("Failed to create events topic");
+
+        if config.kafka.priority.dedicated_topics {
+            for topic in std::iter::once(&config.kafka.dataset_topic).chain(&image_topics) {
+                for priority in [Priority::High, Priority::Low] {
+                    admin_client
+                        .create_topic(&priority.topic(topic), config.kafka.topic_partitions)
+                        .await
+                        .expect("Failed to create priority topic");
+                }
+            }
+        }
     }
 
     // Initialize clients
-    let db_client = DBClient::new("img-processing-server").await;
-    let s3_client = get_s3_client().await;
-    let kafka_client = ProducerClient::new(&broker, "dataset-tasks"); // This producer is responsible
+    let db_client = match read_only {
+        true => DBClient::new_read_only(&config.mongo).await,
+        false => DBClient::new(&config.mongo).await,
+    };
+    let storage = storage::from_config(&config.storage, &config.s3.bucket).await;
+    let kafka_client = ProducerClient::from_config(&config, &config.kafka.dataset_topic); // This producer is responsible
     // for sending datasets and
     // datasets only to kafka.
+    let control_producer = ProducerClient::from_config(&config, &config.kafka.control_topic);
+    let migration_producer = ProducerClient::from_config(&config, &config.kafka.migration_topic);
+    let image_producer = ProducerClient::from_config(&config, &config.kafka.image_topic)
+        .with_partitioner(AffinityPartitioner);
+    if !read_only {
+        TopicSwitcher::new()
+            .producer(&kafka_client)
+            .producer(&image_producer)
+            .start(&config.kafka)
+            .await;
+    }
+    let events = events::BatchEventHub::spawn(&config);
+    let event_publisher = BatchEventPublisher::from_config(&config);
 
     // Create application state
     let app_state = utils::AppState {
         db: Arc::new(db_client),
         kafka_client: Arc::new(kafka_client),
-        s3_client: s3_client,
+        control_producer: Arc::new(control_producer),
+        migration_producer: Arc::new(migration_producer),
+        image_producer: Arc::new(image_producer),
+        storage,
+        config: Arc::new(config),
+        events,
+        event_publisher,
+        archive_builds: Arc::default(),
     };
 
-    // Setup router
+    // Setup router, a read replica only serves the status, results and stats endpoints
     let mut app = Router::new()
-        .route("/upload_dataset", post(create_dataset_upload))
-        .route("/send_task", post(handle_dataset_task))
-        .layer(Extension(app_state));
+        .route("/pipelines", get(pipelines::list_pipelines))
+        .route("/pipelines/:name", get(pipelines::get_pipeline))
+        .route("/sweep/:sweep_id", get(sweep::get_sweep_comparison))
+        .route("/batch/:batch_id/status", get(batch::get_batch_status))
+        .route(
+            "/batch/:batch_id/status_by_prefix",
+            get(batch::get_batch_status_by_prefix),
+        )
+        .route("/batch/:batch_id/stats", get(batch::get_batch_stats))
+        .route("/batch/:batch_id/images", get(batch::get_batch_image_tasks))
+        .route("/batch/:batch_id/failures", get(batch::get_batch_failures))
+        .route(
+            "/batch/:batch_id/failures/summary",
+            get(batch::get_batch_failure_summary),
+        )
+        .route("/batch/:batch_id/metadata", get(batch::get_batch_metadata))
+        .route("/batch/:batch_id/events", get(events::get_batch_events))
+        .route("/batch/:batch_id/previews", get(previews::get_batch_previews))
+        .route("/notifications", get(notifications::get_tenant_notifications))
+        .route(
+            "/batch/:batch_id/notifications",
+            get(notifications::get_batch_notifications),
+        );
+    let mut admin = Router::new()
+        .route("/admin/slo", get(admin::slo_report_handler))
+        .route("/admin/stats/bytes", get(admin::byte_stats_handler))
+        .route("/analytics/operations", get(admin::operation_analytics_handler))
+        .route("/admin/tenants", get(tenants::list_tenants))
+        .route("/admin/tenants/:tenant_id", get(tenants::get_tenant))
+        .route(
+            "/admin/tenants/:tenant_id/audit",
+            get(tenants::get_tenant_audit),
+        );
+    if !read_only {
+        app = app
+            .route("/upload_dataset", post(create_dataset_upload))
+            .route(
+                "/upload_dataset/multipart/init",
+                post(multipart::init_multipart_upload),
+            )
+            .route(
+                "/upload_dataset/multipart/part_url",
+                get(multipart::get_part_url),
+            )
+            .route(
+                "/upload_dataset/multipart/complete",
+                post(multipart::complete_multipart_upload),
+            )
+            .route("/upload_dataset/files", post(files::create_files_upload))
+            .route(
+                "/upload_dataset/files/complete",
+                post(files::complete_files_upload),
+            )
+            .route(
+                "/pipelines/:name",
+                put(pipelines::put_pipeline).delete(pipelines::delete_pipeline),
+            )
+            .route("/send_task", post(handle_dataset_task))
+            .route("/send_sweep", post(sweep::handle_sweep))
+            .route("/process_image/upload", post(adhoc::create_image_upload))
+            .route("/process_image", post(adhoc::process_image))
+            .route(
+                "/batch/:batch_id/annotations",
+                put(batch::put_batch_annotations),
+            )
+            .route(
+                "/batch/:batch_id/snapshot",
+                post(batch::create_batch_snapshot),
+            )
+            .route("/batch/:batch_id/rollback", post(batch::rollback_batch))
+            .route("/batch/:batch_id", delete(batch::delete_batch))
+            .route("/batch/:batch_id/cancel", post(batch::cancel_batch))
+            .route("/batch/:batch_id/retry", post(batch::retry_batch))
+            .route(
+                "/batch/:batch_id/stages/:stage/approve",
+                post(batch::approve_stage),
+            )
+            .route(
+                "/batch/:batch_id/stages/:stage/reject",
+                post(batch::reject_stage),
+            )
+            .route("/batch/:batch_id/clone", post(batch::clone_batch))
+            .route("/batch/:batch_id/share", post(sharing::create_share_link))
+            .route(
+                "/notifications",
+                put(notifications::put_tenant_notifications),
+            )
+            .route(
+                "/batch/:batch_id/notifications",
+                put(notifications::put_batch_notifications),
+            );
+        admin = admin
+            .route(
+                "/admin/topics",
+                get(admin::list_topics_handler).post(admin::create_topic_handler),
+            )
+            .route("/admin/topics/:name", delete(admin::delete_topic_handler))
+            .route(
+                "/admin/topics/:name/partitions",
+                post(admin::alter_partitions_handler),
+            )
+            .route("/admin/topics/migrate", post(admin::migrate_topic_handler))
+            .route(
+                "/admin/topics/migrations/:migration_id",
+                get(admin::get_topic_migration_handler),
+            )
+            .route("/admin/control", post(admin::send_control_command))
+            .route("/admin/kafka/health", get(admin::kafka_health_handler))
+            .route("/admin/tenants", post(tenants::create_tenant))
+            .route("/admin/tenants/:tenant_id", put(tenants::update_tenant))
+            .route(
+                "/admin/tenants/:tenant_id/keys",
+                post(tenants::create_api_key),
+            )
+            .route(
+                "/admin/tenants/:tenant_id/keys/:key_id",
+                delete(tenants::revoke_api_key),
+            )
+            .route(
+                "/admin/tenants/:tenant_id/keys/:key_id/rotate",
+                post(tenants::rotate_api_key),
+            );
+    }
+    app = app
+        .merge(admin.route_layer(middleware::from_fn(auth::require_admin)))
+        .nest("/v2", v2::router(read_only));
+
+    // The proxy is only served when it is protected by a token
+    if app_state.config.downloads.proxy_token.is_some() {
+        app = app.route(
+            "/batch/:batch_id/results/download",
+            get(downloads::download_results),
+        );
+    }
+
+    app = app
+        .layer(middleware::from_fn_with_state(
+            app_state.config.api.clone(),
+            limits::enforce_request_limits,
+        ))
+        .layer(DefaultBodyLimit::max(app_state.config.api.max_body_bytes));
+    // Multipart, so it skips the JSON-only limits above and is bounded by its dataset limit
+    if !read_only {
+        let inline_limit = app_state.config.api.max_inline_dataset_bytes as usize
+            + app_state.config.api.max_body_bytes;
+        app = app.route(
+            "/submit_inline",
+            post(inline::submit_inline).layer(DefaultBodyLimit::max(inline_limit)),
+        );
+    }
+    // Inside authentication, which resolves the caller the requests are counted against
+    if let Some(per_sec) = app_state.config.api.rate_limit_per_sec.filter(|rate| *rate > 0.0) {
+        let limiter = ratelimit::RateLimiter::new(per_sec, app_state.config.api.rate_limit_burst);
+        app = app.layer(middleware::from_fn_with_state(
+            Arc::new(limiter),
+            ratelimit::limit_requests,
+        ));
+    }
+    let shared_state = app_state.clone();
+    app = app
+        .layer(middleware::from_fn_with_state(
+            auth::AuthState {
+                config: Arc::new(app_state.config.auth.clone()),
+                db: Arc::clone(&app_state.db),
+            },
+            auth::authenticate,
+        ))
+        .layer(Extension(app_state))
+        .layer(middleware::from_fn(errors::localize))
+        .layer(middleware::from_fn(correlation::correlate));
 
-    app = app.route("/info", get(|| async { "Hello There".to_string() }));
+    // Probes and scrapers don't carry API keys
+    app = app
+        .route("/info", get(|| async { "Hello There".to_string() }))
+        .route("/metrics", get(serve_metrics));
+    // Shared links are opened by whoever holds their token, without an API key
+    let shared = Router::new()
+        .route("/shared/:token", get(sharing::get_shared_results))
+        .layer(Extension(shared_state.clone()))
+        .layer(middleware::from_fn(errors::localize))
+        .layer(middleware::from_fn(correlation::correlate));
+    app = app.merge(shared);
+    // The presigned URLs of the Local backend carry their own signature instead of an API key
+    if shared_state.config.storage.backend == StorageKind::Local {
+        let presigned = Router::new()
+            .route(
+                &format!("{}/*key", storage::local::PRESIGNED_PATH),
+                get(presigned::get_presigned_object).put(presigned::put_presigned_object),
+            )
+            .layer(DefaultBodyLimit::disable())
+            .layer(Extension(shared_state))
+            .layer(middleware::from_fn(errors::localize))
+            .layer(middleware::from_fn(correlation::correlate));
+        app = app.merge(presigned);
+    }
 
     let listener = TcpListener::bind("0.0.0.0:3030").await.unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    // Anonymous callers are rate limited by their address
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -0,0 +1,159 @@
+use std::{collections::HashSet, time::Duration};
+
+use axum::{
+    Extension,
+    extract::Query,
+    response::{IntoResponse, Json, Response},
+};
+use common::datasets::dataset_extension;
+use common::tenancy::strip_tenant_prefix;
+use storage::UploadedPart;
+
+use crate::auth::Caller;
+use crate::utils::{
+    APIError, AppState, MultipartUploadCompleteRequest, MultipartUploadCompleteResponse,
+    MultipartUploadInitResponse, PartUrlParams, PartUrlResponse, UploadRequest,
+};
+use crate::{VALID_UPLOAD_EXTENSIONS, dataset_upload_key};
+
+// Limits of S3 multipart uploads
+const MAX_PARTS: i32 = 10_000;
+const MIN_PART_BYTES: u64 = 5 * 1024 * 1024;
+const PART_URL_EXPIRY: Duration = Duration::from_secs(3600);
+
+/// Only dataset uploads of the caller's tenant can be written through these endpoints
+pub(crate) fn check_dataset_key(caller: &Caller, dataset_key: &str) -> Result<(), APIError> {
+    match strip_tenant_prefix(caller.tenant_id(), dataset_key)
+        .is_some_and(|key| key.starts_with("uploads/"))
+    {
+        true => Ok(()),
+        false => Err(APIError::ValidationError(format!(
+            "{} is not a dataset upload key",
+            dataset_key
+        ))),
+    }
+}
+
+/// Starts a multipart upload of a dataset, for archives too large for the single presigned PUT
+/// of `/upload_dataset` (over 5 GB) or uploads that have to survive a dropped connection.
+///
+/// The client then fetches a presigned URL per part from `part_url`, uploads the parts (in any
+/// order, retrying the ones that failed) and finishes the upload with `complete`.
+///
+/// # Returns
+/// - `200 OK` with the dataset key, the upload id and the part limits.
+/// - `400 Bad Request` if the file extension is not supported.
+/// - `500 Internal Server Error` if the upload can't be started.
+#[axum::debug_handler]
+pub async fn init_multipart_upload(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<UploadRequest>,
+) -> Result<Json<MultipartUploadInitResponse>, Response> {
+    let ext = dataset_extension(&request.filename).unwrap_or("");
+    if !VALID_UPLOAD_EXTENSIONS.contains(&ext) {
+        return Err(APIError::unsupported_extension(ext, &VALID_UPLOAD_EXTENSIONS).into_response());
+    }
+
+    let dataset_key = dataset_upload_key(caller.tenant_id(), &request.dataset_name, ext);
+    let upload_id = state
+        .storage
+        .create_multipart_upload(&dataset_key)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(MultipartUploadInitResponse {
+        dataset_key,
+        upload_id,
+        max_parts: MAX_PARTS,
+        min_part_bytes: MIN_PART_BYTES,
+    }))
+}
+
+/// Creates a presigned URL for uploading one part of a multipart dataset upload.
+///
+/// # Returns
+/// - `200 OK` with the part number and its presigned URL.
+/// - `422 Unprocessable Entity` if the part number is out of range or the key isn't a dataset
+///   upload.
+#[axum::debug_handler]
+pub async fn get_part_url(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Query(params): Query<PartUrlParams>,
+) -> Result<Json<PartUrlResponse>, Response> {
+    check_dataset_key(&caller, &params.dataset_key).map_err(IntoResponse::into_response)?;
+    if !(1..=MAX_PARTS).contains(&params.part_number) {
+        return Err(APIError::ValidationError(format!(
+            "Part numbers go from 1 to {}, got {}",
+            MAX_PARTS, params.part_number
+        ))
+        .into_response());
+    }
+
+    let presigned_url = state
+        .storage
+        .presign_upload_part(
+            &params.dataset_key,
+            &params.upload_id,
+            params.part_number,
+            PART_URL_EXPIRY,
+        )
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(PartUrlResponse {
+        part_number: params.part_number,
+        presigned_url,
+    }))
+}
+
+/// Assembles the uploaded parts into the dataset object, after which the dataset key can be
+/// submitted like one uploaded through `/upload_dataset`.
+///
+/// # Returns
+/// - `200 OK` with the dataset key and the number of parts.
+/// - `422 Unprocessable Entity` if no parts were given or a part number appears twice.
+/// - `500 Internal Server Error` if the storage rejects the parts, e.g. a wrong ETag.
+#[axum::debug_handler]
+pub async fn complete_multipart_upload(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<MultipartUploadCompleteRequest>,
+) -> Result<Json<MultipartUploadCompleteResponse>, Response> {
+    check_dataset_key(&caller, &request.dataset_key).map_err(IntoResponse::into_response)?;
+    if request.parts.is_empty() {
+        return Err(
+            APIError::ValidationError("A multipart upload needs at least one part".to_string())
+                .into_response(),
+        );
+    }
+
+    let mut seen = HashSet::new();
+    if let Some(part) = request.parts.iter().find(|part| !seen.insert(part.part_number)) {
+        return Err(APIError::ValidationError(format!(
+            "Part {} is listed more than once",
+            part.part_number
+        ))
+        .into_response());
+    }
+
+    let parts: Vec<UploadedPart> = request
+        .parts
+        .iter()
+        .map(|part| UploadedPart {
+            part_number: part.part_number,
+            etag: part.etag.clone(),
+        })
+        .collect();
+    state
+        .storage
+        .complete_multipart_upload(&request.dataset_key, &request.upload_id, &parts)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(MultipartUploadCompleteResponse {
+        dataset_key: request.dataset_key,
+        part_count: parts.len(),
+    }))
+}
@@ -0,0 +1,140 @@
+use axum::{
+    Extension,
+    extract::Path,
+    response::{IntoResponse, Json, Response},
+};
+use common::notifications::NotificationPreferences;
+
+use crate::auth::Caller;
+use crate::batch::find_batch;
+use crate::utils::{
+    APIError, AppState, FieldError, NotificationPreferencesResponse, PreferencesSource,
+};
+
+/// Rejects preferences with invalid quiet hours, every invalid field at once
+fn check_preferences(preferences: &NotificationPreferences) -> Result<(), APIError> {
+    let errors: Vec<FieldError> = preferences
+        .issues()
+        .into_iter()
+        .map(|(field, message)| FieldError::new(field, message))
+        .collect();
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(APIError::InvalidFields(errors)),
+    }
+}
+
+/// Returns the notification preferences of the caller's tenant, which apply to every batch of
+/// the tenant that doesn't set its own.
+///
+/// # Returns
+/// - `200 OK` with the tenant's preferences, or the defaults if it set none.
+#[axum::debug_handler]
+pub async fn get_tenant_notifications(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<NotificationPreferencesResponse>, Response> {
+    let preferences = state
+        .db
+        .get_notification_preferences(caller.tenant_id())
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(NotificationPreferencesResponse {
+        batch_id: None,
+        source: match preferences {
+            Some(_) => PreferencesSource::Tenant,
+            None => PreferencesSource::Default,
+        },
+        preferences: preferences.unwrap_or_default(),
+    }))
+}
+
+/// Replaces the notification preferences of the caller's tenant.
+///
+/// # Returns
+/// - `200 OK` with the new preferences.
+/// - `422 Unprocessable Entity` if the quiet hours are invalid.
+#[axum::debug_handler]
+pub async fn put_tenant_notifications(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(preferences): Json<NotificationPreferences>,
+) -> Result<Json<NotificationPreferencesResponse>, Response> {
+    check_preferences(&preferences).map_err(IntoResponse::into_response)?;
+    state
+        .db
+        .set_notification_preferences(caller.tenant_id(), &preferences)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(NotificationPreferencesResponse {
+        batch_id: None,
+        preferences,
+        source: PreferencesSource::Tenant,
+    }))
+}
+
+/// Returns the notification preferences that apply to a batch: its own, else its tenant's.
+///
+/// # Returns
+/// - `200 OK` with the preferences and where they come from.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_notifications(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<NotificationPreferencesResponse>, Response> {
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+    let (preferences, source) = match batch.notification_preferences {
+        Some(preferences) => (preferences, PreferencesSource::Batch),
+        None => match state
+            .db
+            .get_notification_preferences(batch.tenant_id.as_deref())
+            .await
+            .map_err(|e| APIError::from(e).into_response())?
+        {
+            Some(preferences) => (preferences, PreferencesSource::Tenant),
+            None => (NotificationPreferences::default(), PreferencesSource::Default),
+        },
+    };
+
+    Ok(Json(NotificationPreferencesResponse {
+        batch_id: Some(batch_id),
+        preferences,
+        source,
+    }))
+}
+
+/// Replaces the notification preferences of a batch, e.g. to silence a long running batch. They
+/// apply from the next notification on.
+///
+/// # Returns
+/// - `200 OK` with the new preferences.
+/// - `404 Not Found` if no batch exists with the given id.
+/// - `422 Unprocessable Entity` if the quiet hours are invalid.
+#[axum::debug_handler]
+pub async fn put_batch_notifications(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Json(preferences): Json<NotificationPreferences>,
+) -> Result<Json<NotificationPreferencesResponse>, Response> {
+    check_preferences(&preferences).map_err(IntoResponse::into_response)?;
+    find_batch(&state, &caller, &batch_id).await?;
+    let updated = state
+        .db
+        .set_batch_notification_preferences(&batch_id, &preferences)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    if !updated {
+        return Err(APIError::batch_not_found(&batch_id).into_response());
+    }
+
+    Ok(Json(NotificationPreferencesResponse {
+        batch_id: Some(batch_id),
+        preferences,
+        source: PreferencesSource::Batch,
+    }))
+}
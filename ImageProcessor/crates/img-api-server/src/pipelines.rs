@@ -0,0 +1,176 @@
+use axum::{
+    Extension,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use common::{DatasetProcessingJob, presets::PRESETS, validation::validate_pipeline};
+use db_utils::types::DBPipeline;
+
+use crate::auth::Caller;
+use crate::schema::pipeline_errors;
+use crate::utils::{
+    APIError, AppState, FieldError, NamedPipelineRequest, NamedPipelineResponse,
+    PipelineListResponse,
+};
+
+const MAX_NAME_LEN: usize = 64;
+
+/// Rejects names other than lowercase letters, digits and dashes, like the presets' names
+fn check_name(name: &str) -> Result<(), APIError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    match valid {
+        true => Ok(()),
+        false => Err(APIError::InvalidFields(vec![FieldError::new(
+            "name",
+            format!(
+                "Must be up to {} lowercase letters, digits and dashes, like \"thumbnail-web\"",
+                MAX_NAME_LEN
+            ),
+        )])),
+    }
+}
+
+/// Replaces the job's pipeline with the operations of the tenant's pipeline of that name, ahead
+/// of any operations the job lists itself. Returns the pipeline that was applied, if the job
+/// named one.
+pub(crate) async fn expand_pipeline(
+    state: &AppState,
+    job: &mut DatasetProcessingJob,
+) -> Result<Option<DBPipeline>, APIError> {
+    let Some(name) = job.pipeline.take() else {
+        return Ok(None);
+    };
+    let pipeline = state
+        .db
+        .get_pipeline(job.tenant_id.as_deref(), &name)
+        .await?
+        .ok_or_else(|| {
+            APIError::InvalidFields(vec![FieldError::new(
+                "pipeline",
+                format!("Unknown pipeline {}, see /pipelines", name),
+            )])
+        })?;
+
+    job.prepend_operations(&pipeline.operations);
+    Ok(Some(pipeline))
+}
+
+/// Lists the pipelines a job can refer to by name: the presets in its `preset` field, and the
+/// pipelines the caller's tenant saved in its `pipeline` field.
+///
+/// # Returns
+/// - `200 OK` with every preset and pipeline and its operations.
+#[axum::debug_handler]
+pub async fn list_pipelines(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+) -> Result<Json<PipelineListResponse>, Response> {
+    let pipelines = state
+        .db
+        .get_pipelines(caller.tenant_id())
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(PipelineListResponse {
+        presets: PRESETS.to_vec(),
+        pipelines: pipelines.into_iter().map(Into::into).collect(),
+    }))
+}
+
+/// Returns a pipeline of the caller's tenant.
+///
+/// # Returns
+/// - `200 OK` with the latest version of the pipeline.
+/// - `404 Not Found` if the tenant has no pipeline of that name.
+#[axum::debug_handler]
+pub async fn get_pipeline(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+) -> Result<Json<NamedPipelineResponse>, Response> {
+    state
+        .db
+        .get_pipeline(caller.tenant_id(), &name)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .map(|pipeline| Json(pipeline.into()))
+        .ok_or_else(|| {
+            APIError::NotFoundError(format!("Pipeline {} does not exist", name)).into_response()
+        })
+}
+
+/// Saves a pipeline of the caller's tenant, which jobs then refer to by name in their `pipeline`
+/// field instead of listing its operations. Replacing a pipeline makes a new version of it,
+/// batches that already ran record the version they used.
+///
+/// # Returns
+/// - `200 OK` with the saved pipeline.
+/// - `422 Unprocessable Entity` if the name is invalid, or the operations are empty or can't be
+///   meaningfully combined.
+#[axum::debug_handler]
+pub async fn put_pipeline(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+    Json(request): Json<NamedPipelineRequest>,
+) -> Result<Json<NamedPipelineResponse>, Response> {
+    check_name(&name).map_err(IntoResponse::into_response)?;
+    let mut errors = pipeline_errors(&validate_pipeline(&request.operations));
+    if request.operations.is_empty() {
+        errors.push(FieldError::new(
+            "operations",
+            "A pipeline needs at least one operation",
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(APIError::InvalidFields(errors).into_response());
+    }
+
+    let pipeline = state
+        .db
+        .put_pipeline(
+            caller.tenant_id(),
+            &name,
+            request.description.as_deref(),
+            &request.operations,
+        )
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .ok_or_else(|| {
+            APIError::DatabaseError("Failed to save the pipeline".to_string()).into_response()
+        })?;
+    tracing::info!(pipeline = %pipeline.reference(), "Saved pipeline");
+
+    Ok(Json(pipeline.into()))
+}
+
+/// Deletes a pipeline of the caller's tenant. Batches that ran it keep their operations.
+///
+/// # Returns
+/// - `204 No Content` once the pipeline is deleted.
+/// - `404 Not Found` if the tenant has no pipeline of that name.
+#[axum::debug_handler]
+pub async fn delete_pipeline(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, Response> {
+    let deleted = state
+        .db
+        .delete_pipeline(caller.tenant_id(), &name)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    match deleted {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(
+            APIError::NotFoundError(format!("Pipeline {} does not exist", name)).into_response(),
+        ),
+    }
+}
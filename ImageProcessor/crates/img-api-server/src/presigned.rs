@@ -0,0 +1,76 @@
+use axum::{
+    Extension,
+    body::{Body, Bytes},
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use storage::{LocalUrlSigner, PresignedMethod, PutOptions};
+
+use crate::utils::{APIError, AppState, PresignedParams};
+
+// ============================================================================
+// LOCAL PRESIGNED URLS
+// The Local storage backend has no server of its own, so the URLs it presigns
+// point here, see `storage::local`. Whoever holds one reads or writes the one
+// object it was signed for until it expires, without an API key.
+// ============================================================================
+
+fn check_signature(
+    state: &AppState,
+    method: PresignedMethod,
+    key: &str,
+    params: &PresignedParams,
+) -> Result<(), APIError> {
+    let config = &state.config.storage;
+    let signer = LocalUrlSigner::new(&config.local_url, &config.local_signing_key);
+    match signer.verify(method, key, params.expires, &params.signature) {
+        true => Ok(()),
+        false => Err(APIError::Unauthorized(
+            "The URL is expired or wasn't signed for this object".to_string(),
+        )),
+    }
+}
+
+/// Streams an object through a URL presigned by `presign_get`.
+///
+/// # Returns
+/// - `200 OK` with the object.
+/// - `401 Unauthorized` if the URL expired or its signature doesn't match.
+/// - `404 Not Found` if the object doesn't exist.
+pub async fn get_presigned_object(
+    Extension(state): Extension<AppState>,
+    Path(key): Path<String>,
+    Query(params): Query<PresignedParams>,
+) -> Result<Response, Response> {
+    check_signature(&state, PresignedMethod::Get, &key, &params).map_err(APIError::into_response)?;
+
+    let body = state
+        .storage
+        .get_object_stream(&key)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    Ok(Response::new(Body::from_stream(body)))
+}
+
+/// Writes an object through a URL presigned by `presign_put` or `presign_upload_part`. The
+/// body is held in memory, the Local backend is meant for datasets that fit.
+///
+/// # Returns
+/// - `200 OK` once the object was written.
+/// - `401 Unauthorized` if the URL expired or its signature doesn't match.
+pub async fn put_presigned_object(
+    Extension(state): Extension<AppState>,
+    Path(key): Path<String>,
+    Query(params): Query<PresignedParams>,
+    body: Bytes,
+) -> Result<StatusCode, Response> {
+    check_signature(&state, PresignedMethod::Put, &key, &params).map_err(APIError::into_response)?;
+
+    state
+        .storage
+        .put_object(&key, body, &PutOptions::default())
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use axum::{
+    Extension,
+    extract::{Path, Query},
+    response::{IntoResponse, Json, Response},
+};
+use common::previews::{preview_task_id, previews_prefix};
+
+use crate::auth::Caller;
+use crate::batch::find_batch;
+use crate::utils::{APIError, AppState, BatchPreviewsResponse, ImagePreview, PreviewParams};
+
+/// Lists presigned links to the thumbnails of a batch's outputs, see `common::previews`.
+///
+/// Thumbnails appear as the last stage writes its outputs, so a running batch lists the ones
+/// written so far. At most `limit` are listed, capped by `previews.max_links`, as a sample for a
+/// grid of results rather than a full listing.
+///
+/// # Returns
+/// - `200 OK` with a `BatchPreviewsResponse`, empty for a batch submitted without previews.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn get_batch_previews(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Query(params): Query<PreviewParams>,
+) -> Result<Json<BatchPreviewsResponse>, Response> {
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+    let config = &state.config.previews;
+
+    let prefix = previews_prefix(batch.tenant_id.as_deref(), &batch_id);
+    let mut keys = state
+        .storage
+        .list(&prefix)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    // Listing order depends on the backend, sorted the same sample comes back every time
+    keys.sort();
+    let total = keys.len();
+
+    let limit = params.limit.unwrap_or(config.max_links).min(config.max_links);
+    let expires_in = Duration::from_secs(config.link_expiry_secs);
+    let mut previews = Vec::new();
+    for key in keys.into_iter().take(limit) {
+        let Some(image_task_id) = preview_task_id(&prefix, &key) else {
+            continue;
+        };
+        let url = state
+            .storage
+            .presign_get(&key, expires_in)
+            .await
+            .map_err(|e| APIError::from(e).into_response())?;
+        previews.push(ImagePreview { image_task_id, url });
+    }
+
+    Ok(Json(BatchPreviewsResponse {
+        batch_id,
+        total,
+        expires_in_secs: config.link_expiry_secs,
+        previews,
+    }))
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use common::metrics;
+
+use crate::auth::Caller;
+use crate::utils::APIError;
+
+// ============================================================================
+// RATE LIMITING
+// Every client gets a token bucket refilled at `rate_limit_per_sec` and
+// holding up to `rate_limit_burst` requests, a request takes one token or is
+// turned away with 429 and a Retry-After header. Tenants and admins are keyed
+// by who they authenticated as, anonymous callers by their IP. The buckets
+// live in the memory of each api-server, so behind a load balancer every
+// replica grants the full rate.
+// ============================================================================
+
+// Full buckets are forgotten this often, a client coming back starts with a full one anyway
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    per_sec: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    pruned: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: f64, burst: u32) -> Self {
+        Self {
+            per_sec,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Takes a token from the client's bucket, or returns how long until one is available
+    fn take(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.prune(now);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.per_sec;
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_sec))
+    }
+
+    /// Drops the buckets that refilled completely since they were last used
+    fn prune(&self, now: Instant) {
+        {
+            let mut pruned = self.pruned.lock().unwrap();
+            if now.duration_since(*pruned) < PRUNE_EVERY {
+                return;
+            }
+            *pruned = now;
+        }
+
+        let full_after = Duration::from_secs_f64(self.burst / self.per_sec);
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| now.duration_since(bucket.updated) < full_after);
+    }
+}
+
+/// Middleware limiting the request rate of the caller. Runs after `auth::authenticate`, which
+/// resolves the caller.
+pub async fn limit_requests(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (client, kind) = match request.extensions().get::<Caller>() {
+        Some(Caller::Tenant(tenant_id)) => (format!("tenant:{}", tenant_id), "tenant"),
+        Some(Caller::Admin) => ("admin".to_string(), "admin"),
+        Some(Caller::Anonymous) | None => {
+            let ip = request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(address)| address.ip().to_string())
+                .unwrap_or_default();
+            (format!("ip:{}", ip), "anonymous")
+        }
+    };
+
+    match limiter.take(&client) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            metrics::REQUESTS_RATE_LIMITED.with_label_values(&[kind]).inc();
+            // Rounded up, a client retrying after 0s would only be turned away again
+            APIError::TooManyRequests(wait.as_secs_f64().ceil().max(1.0) as u64).into_response()
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use common::{
+    DatasetProcessingJob,
+    validation::{PipelineReport, validate_graph},
+};
+
+use crate::utils::{APIError, FieldError};
+
+// ============================================================================
+// JOB VALIDATION
+// A job is checked field by field before anything is recorded or published,
+// and every invalid field is reported at once with its path in the body, so a
+// client can point at the offending input instead of parsing a message. The
+// operations themselves are checked by `common::validation`.
+// ============================================================================
+
+/// The errors of a pipeline report as field errors
+pub fn pipeline_errors(report: &PipelineReport) -> Vec<FieldError> {
+    report
+        .errors()
+        .into_iter()
+        .map(|issue| {
+            let field = issue
+                .field
+                .unwrap_or_else(|| format!("operations[{}]", issue.stage));
+            FieldError::new(field, issue.message)
+        })
+        .collect()
+}
+
+/// Rejects a pipeline whose report has errors
+pub fn check_pipeline(report: &PipelineReport) -> Result<(), APIError> {
+    let errors = pipeline_errors(report);
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(APIError::InvalidFields(errors)),
+    }
+}
+
+/// Validates a job whose pipeline and preset were expanded, returning the report of its
+/// operations so its warnings can be sent back with the dispatched batch
+pub fn validate_job(job: &DatasetProcessingJob) -> Result<PipelineReport, APIError> {
+    let mut errors = Vec::new();
+
+    if job.dataset_key.trim().is_empty() {
+        errors.push(FieldError::new("dataset_key", "A job needs a dataset_key"));
+    }
+    if job.operations.is_empty() {
+        errors.push(FieldError::new(
+            "operations",
+            "A job needs at least one operation, a preset or a pipeline",
+        ));
+    }
+    if let Some(url) = &job.notification_url
+        && !(url.starts_with("https://") || url.starts_with("http://"))
+    {
+        errors.push(FieldError::new(
+            "notification_url",
+            "The notification_url has to be an http(s) URL",
+        ));
+    }
+
+    if let Some(preferences) = &job.notification_preferences {
+        for (field, message) in preferences.issues() {
+            errors.push(FieldError::new(
+                format!("notification_preferences.{}", field),
+                message,
+            ));
+        }
+    }
+
+    for (field, message) in job.dependency_issues().into_iter().chain(job.approval_issues()) {
+        errors.push(FieldError::new(field, message));
+    }
+
+    let report = validate_graph(&job.operations, &job.inputs());
+    errors.extend(pipeline_errors(&report));
+
+    match errors.is_empty() {
+        true => Ok(report),
+        false => Err(APIError::InvalidFields(errors)),
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use axum::{
+    Extension, Json,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use common::{
+    keys::results_prefix,
+    previews::previews_prefix,
+    sharing::{ShareScope, generate_share_token, hash_share_token},
+};
+use db_utils::types::DBShareLink;
+
+use crate::auth::Caller;
+use crate::batch::find_batch;
+use crate::utils::{
+    APIError, AppState, FieldError, ShareRequest, ShareResponse, SharedObject,
+    SharedResultsResponse,
+};
+
+/// Creates a link sharing a batch's thumbnails, or all of its results, with anyone holding it,
+/// see `common::sharing`. The token is only returned in this response.
+///
+/// # Returns
+/// - `201 Created` with the token and the path opening the link.
+/// - `400 Bad Request` if the expiry is zero or past `sharing.max_expiry_secs`.
+/// - `404 Not Found` if no batch exists with the given id.
+#[axum::debug_handler]
+pub async fn create_share_link(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+    Json(request): Json<ShareRequest>,
+) -> Result<(StatusCode, Json<ShareResponse>), Response> {
+    let batch = find_batch(&state, &caller, &batch_id).await?;
+    let config = &state.config.sharing;
+
+    let expires_in_secs = request.expires_in_secs.unwrap_or(config.default_expiry_secs);
+    if expires_in_secs == 0 || expires_in_secs > config.max_expiry_secs {
+        return Err(APIError::InvalidFields(vec![FieldError::new(
+            "expires_in_secs",
+            format!("Must be between 1 and {}", config.max_expiry_secs),
+        )])
+        .into_response());
+    }
+
+    let token = generate_share_token();
+    let now = Utc::now();
+    let link = DBShareLink {
+        id: None,
+        share_id: uuid::Uuid::new_v4(),
+        batch_id,
+        tenant_id: batch.tenant_id,
+        token_hash: hash_share_token(&token),
+        scope: request.scope,
+        expires_at: now + chrono::Duration::seconds(expires_in_secs as i64),
+        time_created: now,
+    };
+    state
+        .db
+        .create_share_link(&link)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(ShareResponse {
+            share_id: link.share_id,
+            url: format!("/shared/{}", token),
+            token,
+            scope: link.scope,
+            expires_at: link.expires_at,
+        }),
+    ))
+}
+
+/// Lists presigned links to what a shared link gives access to. Served without an API key, the
+/// token is the credential.
+///
+/// The links expire after `sharing.link_expiry_secs`, or with the shared link if that is sooner.
+/// At most `sharing.max_links` are listed, sorted by path.
+///
+/// # Returns
+/// - `200 OK` with a `SharedResultsResponse`.
+/// - `404 Not Found` if the token is unknown, the link expired or its batch was deleted.
+#[axum::debug_handler]
+pub async fn get_shared_results(
+    Extension(state): Extension<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<SharedResultsResponse>, Response> {
+    let not_found =
+        || APIError::NotFoundError("Unknown or expired shared link".to_string()).into_response();
+    let now = Utc::now();
+    let link = state
+        .db
+        .find_share_link(&hash_share_token(&token))
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .filter(|link| link.is_valid(now))
+        .ok_or_else(not_found)?;
+    // A deleted batch takes its shared links with it
+    state
+        .db
+        .get_batch(&link.batch_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .ok_or_else(not_found)?;
+
+    let config = &state.config.sharing;
+    let prefix = match link.scope {
+        ShareScope::Previews => previews_prefix(link.tenant_id.as_deref(), &link.batch_id),
+        ShareScope::Results => results_prefix(link.tenant_id.as_deref(), &link.batch_id),
+    };
+    let mut keys = state
+        .storage
+        .list(&prefix)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    keys.sort();
+    let total = keys.len();
+
+    let remaining_secs = (link.expires_at - now).num_seconds().max(1) as u64;
+    let expires_in_secs = config.link_expiry_secs.min(remaining_secs);
+    let expires_in = Duration::from_secs(expires_in_secs);
+    let mut objects = Vec::new();
+    for key in keys.into_iter().take(config.max_links) {
+        let url = state
+            .storage
+            .presign_get(&key, expires_in)
+            .await
+            .map_err(|e| APIError::from(e).into_response())?;
+        let path = key.strip_prefix(&prefix).unwrap_or(&key).to_string();
+        objects.push(SharedObject { path, url });
+    }
+
+    Ok(Json(SharedResultsResponse {
+        batch_id: link.batch_id,
+        scope: link.scope,
+        total,
+        expires_in_secs,
+        expires_at: link.expires_at,
+        objects,
+    }))
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    Extension,
+    extract::Path,
+    response::{IntoResponse, Json, Response},
+};
+use common::{DatasetProcessingJob, ImageOperation, validation::validate_graph};
+use db_utils::types::{StatusCounts, SweepMembership};
+use serde_json::Value;
+
+use crate::auth::Caller;
+use crate::batch::percentage;
+use crate::dispatch_job;
+use crate::pipelines::expand_pipeline;
+use crate::schema;
+use crate::utils::{
+    APIError, AppState, SweepBatch, SweepBatchComparison, SweepComparisonResponse,
+    SweepDispatchResult, SweepParameter, SweepRequest,
+};
+
+// Every combination is a full batch over the dataset, so the fan-out is kept bounded
+const MAX_SWEEP_COMBINATIONS: usize = 64;
+
+/// Every combination of the swept values, as one value per parameter in the order of `parameters`
+fn combinations(parameters: &[SweepParameter]) -> Vec<Vec<&Value>> {
+    parameters.iter().fold(vec![vec![]], |acc, param| {
+        acc.iter()
+            .flat_map(|prefix| {
+                param.values.iter().map(move |value| {
+                    let mut combination = prefix.clone();
+                    combination.push(value);
+                    combination
+                })
+            })
+            .collect()
+    })
+}
+
+/// Overwrites one parameter of an operation, going through its JSON form so any field of any
+/// variant can be swept without listing them here
+pub(crate) fn set_parameter(
+    op: &ImageOperation,
+    field: &str,
+    value: &Value,
+) -> Result<ImageOperation, String> {
+    let mut json = serde_json::to_value(op).map_err(|e| e.to_string())?;
+
+    let fields = json
+        .as_object_mut()
+        .and_then(|variant| variant.values_mut().next())
+        .and_then(|fields| fields.as_object_mut())
+        .ok_or_else(|| format!("{:?} has no parameters to sweep", op))?;
+    if !fields.contains_key(field) {
+        return Err(format!("{:?} has no parameter named {}", op, field));
+    }
+    fields.insert(field.to_string(), value.clone());
+
+    serde_json::from_value(json)
+        .map_err(|e| format!("Invalid value {} for {}: {}", value, field, e))
+}
+
+fn build_operations(
+    template: &[ImageOperation],
+    parameters: &[SweepParameter],
+    combination: &[&Value],
+) -> Result<Vec<ImageOperation>, String> {
+    let mut operations = template.to_vec();
+
+    for (param, value) in parameters.iter().zip(combination) {
+        let op = operations
+            .get_mut(param.stage as usize)
+            .ok_or_else(|| format!("The pipeline has no stage {}", param.stage))?;
+        *op = set_parameter(op, &param.field, value)?;
+    }
+
+    Ok(operations)
+}
+
+/// Submits one batch per combination of the swept parameter values over the same dataset.
+///
+/// Every combination is built and validated before anything is dispatched, so an invalid value
+/// rejects the whole sweep. The batches are linked by a sweep id that can be compared with
+/// `GET /sweep/{sweep_id}`.
+///
+/// # Returns
+/// - `200 OK` with the sweep id and the batch created for every combination.
+/// - `422 Unprocessable Entity` if a combination is invalid or there are too many of them.
+#[axum::debug_handler]
+pub async fn handle_sweep(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<SweepRequest>,
+) -> Result<Json<SweepDispatchResult>, Response> {
+    let mut template = request.job;
+    // Stages of the swept parameters count the operations of the pipeline and preset
+    template.tenant_id = caller.tenant_id().map(String::from);
+    expand_pipeline(&state, &mut template)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    template
+        .expand_preset()
+        .map_err(|e| APIError::ValidationError(e).into_response())?;
+    let combinations = combinations(&request.parameters);
+    if request.parameters.is_empty() || combinations.is_empty() {
+        return Err(APIError::ValidationError(
+            "A sweep needs at least one value to try".to_string(),
+        )
+        .into_response());
+    }
+    if combinations.len() > MAX_SWEEP_COMBINATIONS {
+        return Err(APIError::ValidationError(format!(
+            "The sweep has {} combinations, the limit is {}",
+            combinations.len(),
+            MAX_SWEEP_COMBINATIONS
+        ))
+        .into_response());
+    }
+
+    // First, we build and validate every combination
+    let mut jobs = Vec::with_capacity(combinations.len());
+    let inputs = template.inputs();
+    for combination in &combinations {
+        let operations = build_operations(&template.operations, &request.parameters, combination)
+            .map_err(|e| APIError::ValidationError(e).into_response())?;
+
+        let report = validate_graph(&operations, &inputs);
+        schema::check_pipeline(&report).map_err(IntoResponse::into_response)?;
+
+        let parameters: BTreeMap<String, Value> = request
+            .parameters
+            .iter()
+            .zip(combination)
+            .map(|(param, value)| (format!("{}.{}", param.stage, param.field), (*value).clone()))
+            .collect();
+        jobs.push((operations, parameters));
+    }
+
+    // Then, we dispatch one batch per combination
+    let sweep_id = uuid::Uuid::new_v4();
+    let mut batches = Vec::with_capacity(jobs.len());
+    for (operations, parameters) in jobs {
+        let job = DatasetProcessingJob {
+            batch_id: None,
+            dataset_key: template.dataset_key.clone(),
+            operations,
+            dependencies: template.dependencies.clone(),
+            requires_approval: template.requires_approval.clone(),
+            preset: None,
+            pipeline: None,
+            use_local_cache: template.use_local_cache,
+            output_layout: template.output_layout,
+            collision_policy: template.collision_policy,
+            hash_suffix: template.hash_suffix,
+            cache_scope: template.cache_scope,
+            output_format: template.output_format,
+            alpha_policy: template.alpha_policy,
+            metadata_policy: template.metadata_policy,
+            rewrite_sidecars: template.rewrite_sidecars,
+            previews: template.previews,
+            tags: template.tags.clone(),
+            owner: template.owner.clone(),
+            notification_url: template.notification_url.clone(),
+            notification_preferences: template.notification_preferences,
+            priority: template.priority,
+            pipeline_mode: template.pipeline_mode,
+            tenant_id: caller.tenant_id().map(String::from),
+        };
+        let membership = SweepMembership {
+            sweep_id,
+            parameters: parameters.clone(),
+        };
+
+        let result = dispatch_job(&state, job, Some(membership), None).await?;
+        batches.push(SweepBatch {
+            batch_id: result.batch_id,
+            parameters,
+            warnings: result.warnings,
+        });
+    }
+
+    Ok(Json(SweepDispatchResult { sweep_id, batches }))
+}
+
+/// Compares the batches of a parameter sweep side by side.
+///
+/// # Returns
+/// - `200 OK` with the parameters, status, image progress and duration of every batch.
+/// - `404 Not Found` if no batch belongs to the sweep.
+#[axum::debug_handler]
+pub async fn get_sweep_comparison(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(sweep_id): Path<uuid::Uuid>,
+) -> Result<Json<SweepComparisonResponse>, Response> {
+    let mut sweep_batches = state
+        .db
+        .get_sweep_batches(&sweep_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    sweep_batches.retain(|batch| caller.can_access(batch.tenant_id.as_deref()));
+    if sweep_batches.is_empty() {
+        return Err(
+            APIError::NotFoundError(format!("Sweep {} does not exist", sweep_id)).into_response(),
+        );
+    }
+    sweep_batches.sort_by_key(|batch| batch.time_created);
+
+    let mut batches = Vec::with_capacity(sweep_batches.len());
+    for batch in sweep_batches {
+        let mut images = StatusCounts::default();
+        for counts in state
+            .db
+            .image_status_counts_by_dataset_task(&batch.batch_id)
+            .await
+            .map_err(|e| APIError::from(e).into_response())?
+            .values()
+        {
+            images.merge(counts);
+        }
+
+        batches.push(SweepBatchComparison {
+            batch_id: batch.batch_id,
+            parameters: batch
+                .sweep
+                .map(|sweep| sweep.parameters)
+                .unwrap_or_default(),
+            status: batch.status,
+            completion_percentage: percentage(images.success + images.failure, images.total()),
+            images,
+            duration_secs: batch
+                .time_completed
+                .map(|completed| (completed - batch.time_created).num_seconds()),
+        });
+    }
+
+    Ok(Json(SweepComparisonResponse { sweep_id, batches }))
+}
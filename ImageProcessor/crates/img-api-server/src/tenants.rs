@@ -0,0 +1,345 @@
+use axum::{
+    Extension,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{Duration, Utc};
+use common::tenancy::{api_key_hint, generate_api_key, hash_api_key, validate_tenant_id};
+use db_utils::types::{DBTenant, DBTenantApiKey, DBTenantAudit};
+
+use crate::utils::{
+    APIError, AppState, CreateTenantRequest, FieldError, IssuedApiKeyResponse,
+    RotateApiKeyRequest, TenantAuditResponse, TenantDetailsResponse, TenantListResponse,
+    TenantResponse, UpdateTenantRequest,
+};
+
+// ============================================================================
+// TENANT ONBOARDING
+// Admin endpoints creating tenants and managing their API keys and quotas, so
+// a tenant can be onboarded without editing the config and redeploying. The
+// keys only take effect with `api.auth.managed_keys`. Every change is recorded
+// in the tenant's audit log with the correlation id of its request.
+//
+// Every tenant's objects are stored below its prefix in the one bucket of the
+// storage backend, see `common::tenancy`, so the bucket and prefix of a tenant
+// are reported but can't be chosen per tenant.
+// ============================================================================
+
+fn tenant_not_found(tenant_id: &str) -> Response {
+    APIError::NotFoundError(format!("Tenant {} does not exist", tenant_id)).into_response()
+}
+
+fn key_not_found(tenant_id: &str, key_id: &uuid::Uuid) -> Response {
+    APIError::NotFoundError(format!(
+        "Tenant {} has no valid API key {}",
+        tenant_id, key_id
+    ))
+    .into_response()
+}
+
+/// Records a change made to a tenant. The change is made already, so a failure is only logged.
+async fn audit(
+    state: &AppState,
+    tenant_id: &str,
+    action: &str,
+    key_id: Option<uuid::Uuid>,
+    details: Option<String>,
+) {
+    let entry = DBTenantAudit {
+        id: None,
+        tenant_id: tenant_id.to_string(),
+        action: action.to_string(),
+        key_id,
+        details,
+        correlation_id: common::correlation::current(),
+        time: Utc::now(),
+    };
+    if let Err(e) = state.db.record_tenant_audit(&entry).await {
+        tracing::error!(tenant_id, action, error = %e, "Failed to record the tenant audit entry");
+    }
+}
+
+/// Issues a new managed key of a tenant, returning its id and the key itself
+async fn issue_key(state: &AppState, tenant_id: &str) -> Result<(uuid::Uuid, String), Response> {
+    let api_key = generate_api_key();
+    let key = DBTenantApiKey {
+        id: None,
+        key_id: uuid::Uuid::new_v4(),
+        tenant_id: tenant_id.to_string(),
+        key_hash: hash_api_key(&api_key),
+        key_hint: api_key_hint(&api_key),
+        time_created: Utc::now(),
+        expires_at: None,
+        time_revoked: None,
+    };
+    state
+        .db
+        .add_tenant_api_key(&key)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok((key.key_id, api_key))
+}
+
+/// Fails with `404 Not Found` unless the tenant was onboarded
+async fn require_tenant(state: &AppState, tenant_id: &str) -> Result<DBTenant, Response> {
+    state
+        .db
+        .get_tenant(tenant_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .ok_or_else(|| tenant_not_found(tenant_id))
+}
+
+/// Onboards a tenant. It has no API keys until one is issued with
+/// `POST /admin/tenants/:tenant_id/keys`.
+///
+/// # Returns
+/// - `201 Created` with the tenant and where its objects are stored.
+/// - `422 Unprocessable Entity` if the tenant id is invalid or taken.
+#[axum::debug_handler]
+pub async fn create_tenant(
+    Extension(state): Extension<AppState>,
+    Json(request): Json<CreateTenantRequest>,
+) -> Result<(StatusCode, Json<TenantResponse>), Response> {
+    validate_tenant_id(&request.tenant_id).map_err(|e| {
+        APIError::InvalidFields(vec![FieldError::new("tenant_id", e)]).into_response()
+    })?;
+    let now = Utc::now();
+    let tenant = DBTenant {
+        id: None,
+        tenant_id: request.tenant_id,
+        name: request.name,
+        status: Default::default(),
+        quotas: request.quotas,
+        time_created: now,
+        time_updated: now,
+    };
+
+    let created = state
+        .db
+        .create_tenant(&tenant)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    if !created {
+        return Err(APIError::InvalidFields(vec![FieldError::new(
+            "tenant_id",
+            format!("Tenant {} exists already", tenant.tenant_id),
+        )])
+        .into_response());
+    }
+    audit(&state, &tenant.tenant_id, "tenant_created", None, None).await;
+    tracing::info!(tenant_id = %tenant.tenant_id, "Onboarded tenant");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(TenantResponse::new(tenant, &state.config.s3.bucket)),
+    ))
+}
+
+/// Lists the onboarded tenants. Tenants of the config's API keys aren't listed.
+///
+/// # Returns
+/// - `200 OK` with every onboarded tenant, by id.
+#[axum::debug_handler]
+pub async fn list_tenants(
+    Extension(state): Extension<AppState>,
+) -> Result<Json<TenantListResponse>, Response> {
+    let tenants = state
+        .db
+        .get_tenants()
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(TenantListResponse {
+        tenants: tenants
+            .into_iter()
+            .map(|tenant| TenantResponse::new(tenant, &state.config.s3.bucket))
+            .collect(),
+    }))
+}
+
+/// Returns an onboarded tenant with its API keys, of which only the start is shown.
+///
+/// # Returns
+/// - `200 OK` with the tenant and its keys, revoked and expired ones included.
+/// - `404 Not Found` if there is no such tenant.
+#[axum::debug_handler]
+pub async fn get_tenant(
+    Extension(state): Extension<AppState>,
+    Path(tenant_id): Path<String>,
+) -> Result<Json<TenantDetailsResponse>, Response> {
+    let tenant = require_tenant(&state, &tenant_id).await?;
+    let keys = state
+        .db
+        .get_tenant_api_keys(&tenant_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(TenantDetailsResponse {
+        tenant: TenantResponse::new(tenant, &state.config.s3.bucket),
+        api_keys: keys.into_iter().map(Into::into).collect(),
+    }))
+}
+
+/// Renames, suspends or reactivates a tenant, or replaces its quotas. The keys of a suspended
+/// tenant are rejected at once, its batches keep running.
+///
+/// # Returns
+/// - `200 OK` with the updated tenant.
+/// - `404 Not Found` if there is no such tenant.
+#[axum::debug_handler]
+pub async fn update_tenant(
+    Extension(state): Extension<AppState>,
+    Path(tenant_id): Path<String>,
+    Json(request): Json<UpdateTenantRequest>,
+) -> Result<Json<TenantResponse>, Response> {
+    let tenant = state
+        .db
+        .update_tenant(
+            &tenant_id,
+            request.name.as_deref(),
+            request.status,
+            request.quotas.as_ref(),
+        )
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .ok_or_else(|| tenant_not_found(&tenant_id))?;
+
+    if let Some(status) = request.status {
+        let action = format!("status_set_{:?}", status).to_lowercase();
+        audit(&state, &tenant_id, &action, None, None).await;
+    }
+    if let Some(quotas) = &request.quotas {
+        let details = serde_json::to_string(quotas).ok();
+        audit(&state, &tenant_id, "quotas_set", None, details).await;
+    }
+    if let Some(name) = request.name {
+        audit(&state, &tenant_id, "renamed", None, Some(name)).await;
+    }
+
+    Ok(Json(TenantResponse::new(tenant, &state.config.s3.bucket)))
+}
+
+/// Issues an API key of a tenant. The key is only returned in this response, keep it safe.
+///
+/// # Returns
+/// - `201 Created` with the key and its id.
+/// - `404 Not Found` if there is no such tenant.
+#[axum::debug_handler]
+pub async fn create_api_key(
+    Extension(state): Extension<AppState>,
+    Path(tenant_id): Path<String>,
+) -> Result<(StatusCode, Json<IssuedApiKeyResponse>), Response> {
+    require_tenant(&state, &tenant_id).await?;
+    let (key_id, api_key) = issue_key(&state, &tenant_id).await?;
+    audit(&state, &tenant_id, "key_issued", Some(key_id), None).await;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(IssuedApiKeyResponse {
+            key_id,
+            api_key,
+            replaces: None,
+        }),
+    ))
+}
+
+/// Replaces an API key of a tenant with a new one. The old key keeps working for `grace_secs`,
+/// so the tenant's clients can be switched over without downtime.
+///
+/// # Returns
+/// - `201 Created` with the new key, and the old one with when it expires.
+/// - `404 Not Found` if the tenant has no such key, or it was revoked or rotated already.
+#[axum::debug_handler]
+pub async fn rotate_api_key(
+    Extension(state): Extension<AppState>,
+    Path((tenant_id, key_id)): Path<(String, uuid::Uuid)>,
+    Json(request): Json<RotateApiKeyRequest>,
+) -> Result<(StatusCode, Json<IssuedApiKeyResponse>), Response> {
+    let expires_at = Utc::now() + Duration::seconds(request.grace_secs as i64);
+    let expired = state
+        .db
+        .expire_tenant_api_key(&tenant_id, &key_id, expires_at)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    if !expired {
+        return Err(key_not_found(&tenant_id, &key_id));
+    }
+
+    let (new_key_id, api_key) = issue_key(&state, &tenant_id).await?;
+    audit(
+        &state,
+        &tenant_id,
+        "key_rotated",
+        Some(key_id),
+        Some(format!("Replaced by {}, expires at {}", new_key_id, expires_at.to_rfc3339())),
+    )
+    .await;
+    audit(&state, &tenant_id, "key_issued", Some(new_key_id), None).await;
+
+    let replaces = state
+        .db
+        .get_tenant_api_keys(&tenant_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?
+        .into_iter()
+        .find(|key| key.key_id == key_id)
+        .map(Into::into);
+
+    Ok((
+        StatusCode::CREATED,
+        Json(IssuedApiKeyResponse {
+            key_id: new_key_id,
+            api_key,
+            replaces,
+        }),
+    ))
+}
+
+/// Revokes an API key of a tenant, it is rejected from the next request on.
+///
+/// # Returns
+/// - `204 No Content` once the key is revoked.
+/// - `404 Not Found` if the tenant has no such key, or it was revoked already.
+#[axum::debug_handler]
+pub async fn revoke_api_key(
+    Extension(state): Extension<AppState>,
+    Path((tenant_id, key_id)): Path<(String, uuid::Uuid)>,
+) -> Result<StatusCode, Response> {
+    let revoked = state
+        .db
+        .revoke_tenant_api_key(&tenant_id, &key_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+    if !revoked {
+        return Err(key_not_found(&tenant_id, &key_id));
+    }
+    audit(&state, &tenant_id, "key_revoked", Some(key_id), None).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Returns the changes made to a tenant and its keys through these endpoints.
+///
+/// # Returns
+/// - `200 OK` with the audit log of the tenant, oldest first.
+/// - `404 Not Found` if there is no such tenant.
+#[axum::debug_handler]
+pub async fn get_tenant_audit(
+    Extension(state): Extension<AppState>,
+    Path(tenant_id): Path<String>,
+) -> Result<Json<TenantAuditResponse>, Response> {
+    require_tenant(&state, &tenant_id).await?;
+    let entries = state
+        .db
+        .get_tenant_audit(&tenant_id)
+        .await
+        .map_err(|e| APIError::from(e).into_response())?;
+
+    Ok(Json(TenantAuditResponse {
+        tenant_id,
+        entries: entries.into_iter().map(Into::into).collect(),
+    }))
+}
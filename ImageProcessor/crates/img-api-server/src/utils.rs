@@ -1,15 +1,43 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
-use aws_sdk_s3::Client; // Add this import
 use axum::{
-    http::StatusCode,
+    http::header,
     response::{IntoResponse, Response},
 };
-use db_utils::types::DBClient;
-use queue::ProducerClient;
+use chrono::{DateTime, Utc};
+use common::{
+    DatasetProcessingJob, ImageOperation, PipelineMode, Priority, alpha::AlphaPolicy, config::{Config, SloTarget}, error::{ProcessorError, S3ErrorKind}, failures::FailureKind, lifecycle::BatchState, dimensions::Dimensions, presets::Preset, reproducibility::ConfigSnapshot,
+    analytics::OperationWindow,
+    formats::OutputFormat,
+    approval::StageApproval,
+    budgets::BudgetCheckpoint,
+    drift::{QualityDrift, StageQualitySummary},
+    inspection::DatasetValidationReport,
+    metadata::{ImageMetadata, MetadataPolicy},
+    migration::{MigrationError, MigrationState, TopicMigrationPlan, TopicMigrationReport},
+    naming::{CollisionPolicy, OutputLayout},
+    notifications::NotificationPreferences,
+    sharing::ShareScope,
+    slo::{LatencyPercentiles, SloViolation},
+    tenancy::{TenantQuotas, TenantStatus, tenant_prefix},
+    validation::PipelineIssue,
+};
+use db_utils::types::{
+    ByteTotals, CacheCounts, DBClient, DBPipeline, DBTenant, DBTenantApiKey, DBTenantAudit,
+    StatusCounts, TaskStatus,
+};
+use queue::{ProducerClient, events::BatchEventPublisher, failover::ClusterHealth, lag::PartitionLag};
 use serde::{Deserialize, Serialize};
+use storage::StorageBackend;
 use thiserror::Error;
 
+use crate::errors::{CatalogError, ErrorCode, Locale, RenderedError};
+use crate::downloads::ArchiveBuilds;
+use crate::events::BatchEventHub;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadRequest {
     pub dataset_name: String,
@@ -17,21 +45,329 @@ pub struct UploadRequest {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct UploadResponse {
-    pub image_key: String,
+pub struct DatasetUploadResponse {
+    pub dataset_key: String,
+    pub presigned_url: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DatasetUploadResponse {
+#[derive(Debug, Serialize)]
+pub struct MultipartUploadInitResponse {
     pub dataset_key: String,
+    pub upload_id: String,
+    pub max_parts: i32,
+    pub min_part_bytes: u64, // Every part but the last has to be at least this large
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PartUrlParams {
+    pub dataset_key: String,
+    pub upload_id: String,
+    pub part_number: i32, // From 1 to `max_parts`
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartUrlResponse {
+    pub part_number: i32,
     pub presigned_url: String,
 }
 
-#[derive(serde::Serialize)]
-pub struct SingleTaskResult {
-    pub task_id: Option<uuid::Uuid>,
-    pub success: bool,
-    pub error_message: Option<String>,
+#[derive(Debug, Deserialize)]
+pub struct CompletedPartRequest {
+    pub part_number: i32,
+    pub etag: String, // ETag header returned by the part upload
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MultipartUploadCompleteRequest {
+    pub dataset_key: String,
+    pub upload_id: String,
+    pub parts: Vec<CompletedPartRequest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MultipartUploadCompleteResponse {
+    pub dataset_key: String,
+    pub part_count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilesUploadRequest {
+    pub dataset_name: String,
+    pub filenames: Vec<String>, // Paths relative to the dataset, e.g. "cats/001.jpg"
+}
+
+#[derive(Debug, Serialize)]
+pub struct PresignedFile {
+    pub filename: String,
+    pub key: String,
+    pub presigned_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilesUploadResponse {
+    pub dataset_key: String, // Prefix of the files, submitted as the dataset once sealed
+    pub files: Vec<PresignedFile>,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FilesUploadCompleteRequest {
+    pub dataset_key: String,
+    pub filenames: Vec<String>, // Every file of the dataset, checked to have been uploaded
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilesUploadCompleteResponse {
+    pub dataset_key: String,
+    pub file_count: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdhocUploadRequest {
+    pub filename: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdhocUploadResponse {
+    pub image_key: String,
+    pub presigned_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProcessImageRequest {
+    pub image_key: String, // Key returned by /process_image/upload
+    pub operations: Vec<ImageOperation>,
+    #[serde(default)]
+    pub alpha_policy: AlphaPolicy,
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy,
+}
+
+#[derive(Serialize)]
+pub struct ProcessImageResponse {
+    pub result_key: String,
+    pub result_url: String, // Presigned download URL of the processed image
+    pub input_dimensions: Dimensions,
+    pub output_dimensions: Dimensions,
+    pub warnings: Vec<PipelineIssue>,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SweepParameter {
+    pub stage: u32,                     // Index of the operation in the pipeline
+    pub field: String,                  // Parameter of that operation, e.g. "scaling_factor"
+    pub values: Vec<serde_json::Value>, // Every value to try
+}
+
+#[derive(Deserialize)]
+pub struct SweepRequest {
+    #[serde(flatten)]
+    pub job: DatasetProcessingJob, // The pipeline template, swept parameters are overwritten per batch
+    pub parameters: Vec<SweepParameter>,
+}
+
+#[derive(Serialize)]
+pub struct SweepBatch {
+    pub batch_id: uuid::Uuid,
+    pub parameters: BTreeMap<String, serde_json::Value>,
+    pub warnings: Vec<PipelineIssue>,
+}
+
+#[derive(Serialize)]
+pub struct InlineSubmitResponse {
+    pub dataset_key: String, // Where the api-server uploaded the dataset
+    #[serde(flatten)]
+    pub dispatch: TaskDispatchResult,
+}
+
+#[derive(Serialize)]
+pub struct SweepDispatchResult {
+    pub sweep_id: uuid::Uuid,
+    pub batches: Vec<SweepBatch>,
+}
+
+#[derive(Serialize)]
+pub struct SweepBatchComparison {
+    pub batch_id: uuid::Uuid,
+    pub parameters: BTreeMap<String, serde_json::Value>,
+    pub status: TaskStatus,
+    pub images: StatusCounts,
+    pub completion_percentage: f64,
+    pub duration_secs: Option<i64>, // Time from submission to completion, once the batch finished
+}
+
+#[derive(Serialize)]
+pub struct SweepComparisonResponse {
+    pub sweep_id: uuid::Uuid,
+    pub batches: Vec<SweepBatchComparison>,
+}
+
+#[derive(Serialize)]
+pub struct PipelineListResponse {
+    pub presets: Vec<Preset>, // Shipped with the server, every version of every preset
+    pub pipelines: Vec<NamedPipelineResponse>, // Saved by the caller's tenant
+}
+
+/// The body of `PUT /pipelines/:name`
+#[derive(Deserialize, Debug)]
+pub struct NamedPipelineRequest {
+    #[serde(default)]
+    pub description: Option<String>,
+    pub operations: Vec<ImageOperation>,
+}
+
+#[derive(Serialize)]
+pub struct NamedPipelineResponse {
+    pub name: String,
+    pub version: u32, // Bumped every time the pipeline is replaced
+    pub description: Option<String>,
+    pub operations: Vec<ImageOperation>,
+    pub time_created: DateTime<Utc>,
+    pub time_updated: DateTime<Utc>,
+}
+
+impl From<DBPipeline> for NamedPipelineResponse {
+    fn from(pipeline: DBPipeline) -> Self {
+        Self {
+            name: pipeline.name,
+            version: pipeline.version,
+            description: pipeline.description,
+            operations: pipeline.operations,
+            time_created: pipeline.time_created,
+            time_updated: pipeline.time_updated,
+        }
+    }
+}
+
+/// The body of `POST /admin/tenants`
+#[derive(Deserialize, Debug)]
+pub struct CreateTenantRequest {
+    pub tenant_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub quotas: TenantQuotas,
+}
+
+/// The body of `PUT /admin/tenants/:tenant_id`, absent fields are left as they are
+#[derive(Deserialize, Debug)]
+pub struct UpdateTenantRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub status: Option<TenantStatus>,
+    #[serde(default)]
+    pub quotas: Option<TenantQuotas>,
+}
+
+#[derive(Serialize)]
+pub struct TenantResponse {
+    pub tenant_id: String,
+    pub name: String,
+    pub status: TenantStatus,
+    pub quotas: TenantQuotas,
+    pub bucket: String, // Shared by every tenant
+    pub prefix: String, // Every object of the tenant is stored below it
+    pub time_created: DateTime<Utc>,
+    pub time_updated: DateTime<Utc>,
+}
+
+impl TenantResponse {
+    pub fn new(tenant: DBTenant, bucket: &str) -> Self {
+        Self {
+            prefix: tenant_prefix(Some(&tenant.tenant_id)),
+            bucket: bucket.to_string(),
+            tenant_id: tenant.tenant_id,
+            name: tenant.name,
+            status: tenant.status,
+            quotas: tenant.quotas,
+            time_created: tenant.time_created,
+            time_updated: tenant.time_updated,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TenantListResponse {
+    pub tenants: Vec<TenantResponse>,
+}
+
+/// A tenant with its managed keys
+#[derive(Serialize)]
+pub struct TenantDetailsResponse {
+    #[serde(flatten)]
+    pub tenant: TenantResponse,
+    pub api_keys: Vec<TenantApiKeySummary>,
+}
+
+/// A managed key without its hash
+#[derive(Serialize)]
+pub struct TenantApiKeySummary {
+    pub key_id: uuid::Uuid,
+    pub key_hint: String, // The start of the key
+    pub valid: bool,      // Neither revoked nor expired
+    pub time_created: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub time_revoked: Option<DateTime<Utc>>,
+}
+
+impl From<DBTenantApiKey> for TenantApiKeySummary {
+    fn from(key: DBTenantApiKey) -> Self {
+        Self {
+            valid: key.is_valid(Utc::now()),
+            key_id: key.key_id,
+            key_hint: key.key_hint,
+            time_created: key.time_created,
+            expires_at: key.expires_at,
+            time_revoked: key.time_revoked,
+        }
+    }
+}
+
+/// A newly issued managed key, the only time the key itself is returned
+#[derive(Serialize)]
+pub struct IssuedApiKeyResponse {
+    pub key_id: uuid::Uuid,
+    pub api_key: String,
+    pub replaces: Option<TenantApiKeySummary>, // The rotated key, valid until it expires
+}
+
+/// The body of `POST /admin/tenants/:tenant_id/keys/:key_id/rotate`
+#[derive(Deserialize, Debug)]
+pub struct RotateApiKeyRequest {
+    #[serde(default = "default_rotation_grace_secs")]
+    pub grace_secs: u64, // How long the rotated key keeps working, 0 to revoke it at once
+}
+
+fn default_rotation_grace_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Serialize)]
+pub struct TenantAuditEntry {
+    pub action: String,
+    pub key_id: Option<uuid::Uuid>,
+    pub details: Option<String>,
+    pub correlation_id: Option<uuid::Uuid>,
+    pub time: DateTime<Utc>,
+}
+
+impl From<DBTenantAudit> for TenantAuditEntry {
+    fn from(entry: DBTenantAudit) -> Self {
+        Self {
+            action: entry.action,
+            key_id: entry.key_id,
+            details: entry.details,
+            correlation_id: entry.correlation_id,
+            time: entry.time,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TenantAuditResponse {
+    pub tenant_id: String,
+    pub entries: Vec<TenantAuditEntry>, // Oldest first
 }
 
 #[derive(serde::Serialize)]
@@ -39,16 +375,611 @@ pub struct TaskDispatchResult {
     pub batch_id: uuid::Uuid,
     pub task_ids: Vec<uuid::Uuid>,
     pub message: String,
+    pub warnings: Vec<PipelineIssue>, // Non-fatal issues found while validating the pipeline
+}
+
+/// What a job sent with `dry_run` would take, nothing was recorded or published for it
+#[derive(Serialize)]
+pub struct JobEstimate {
+    pub dataset: DatasetEstimate,
+    pub dataset_tasks: usize, // One per stage
+    pub image_tasks: Option<u64>, // Every stage processes every image
+    pub stages: Vec<StageEstimate>,
+    pub intermediate_bytes: Option<u64>, // Written to S3 for later stages, extraction included
+    pub processing_secs: Option<f64>, // Summed over every image, `None` if a stage has no profile
+    pub unprofiled_operations: Vec<String>, // Not measured over enough stages yet
+    pub warnings: Vec<PipelineIssue>, // Non-fatal issues found while validating the pipeline
+}
+
+/// What was found about the dataset of a dry run, without downloading it
+#[derive(Serialize)]
+pub struct DatasetEstimate {
+    pub kind: DatasetKind,
+    pub stored_bytes: u64,
+    pub images: Option<u64>, // `None` for an archive no earlier batch inspected
+    pub image_bytes: Option<u64>, // Uncompressed, sampled for a prefix of many images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inspected_by: Option<uuid::Uuid>, // The batch whose inspection an archive was sized from
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DatasetKind {
+    Image,
+    Prefix,
+    Archive,
+}
+
+/// One stage of a dry run
+#[derive(Serialize)]
+pub struct StageEstimate {
+    pub stage: u32,
+    pub operation: String, // e.g. "Resize+Blur" for fused operations
+    pub intermediate: bool, // Whether a later stage reads its outputs from S3
+    pub output_bytes: Option<u64>,
+    pub mean_ms: Option<f64>, // Per image, from the operation's profile
+    pub processing_secs: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct ImageFailure {
+    pub filename: String, // Path of the image in the uploaded dataset
+    pub stage: u32,
+    pub operation: String, // The stage's operations, e.g. "resize+blur"
+    pub kind: FailureKind,
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_error: Option<S3ErrorKind>, // e.g. NoSuchKey for an input that disappeared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>, // Where an operation panicked on the image
+    pub image_task_id: uuid::Uuid,
+    pub time_failed: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct BatchFailuresResponse {
+    pub batch_id: uuid::Uuid,
+    pub failures: Vec<ImageFailure>, // By stage, then filename
+}
+
+#[derive(Deserialize)]
+pub struct FailureParams {
+    pub kind: Option<FailureKind>, // Only the failures of this kind, e.g. `Timeout`
+}
+
+#[derive(Serialize)]
+pub struct ImageMetadataEntry {
+    pub filename: String, // Path of the image in the uploaded dataset
+    pub image_task_id: uuid::Uuid,
+    #[serde(flatten)]
+    pub metadata: ImageMetadata,
+}
+
+#[derive(Serialize)]
+pub struct BatchMetadataResponse {
+    pub batch_id: uuid::Uuid,
+    pub images: Vec<ImageMetadataEntry>, // By filename
+}
+
+/// Narrows the metadata listing, every parameter given has to match
+#[derive(Deserialize)]
+pub struct MetadataParams {
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub has_gps: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct FailureGroupSummary {
+    pub kind: FailureKind,
+    pub stage: u32,
+    pub operation: String,
+    pub count: u64,
+    pub sample_error: String,
+    pub sample_filename: String,
+}
+
+#[derive(Serialize)]
+pub struct BatchFailureSummaryResponse {
+    pub batch_id: uuid::Uuid,
+    pub total: u64,
+    pub by_kind: BTreeMap<&'static str, u64>,
+    pub groups: Vec<FailureGroupSummary>, // The largest groups first
+}
+
+#[derive(Deserialize)]
+pub struct PreviewParams {
+    pub limit: Option<usize>, // Thumbnails listed at most, capped by `previews.max_links`
+}
+
+#[derive(Serialize)]
+pub struct ImagePreview {
+    pub image_task_id: uuid::Uuid, // The image task of the last stage that wrote the output
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct BatchPreviewsResponse {
+    pub batch_id: uuid::Uuid,
+    pub total: usize, // Thumbnails written so far, listed or not
+    pub expires_in_secs: u64,
+    pub previews: Vec<ImagePreview>,
+}
+
+/// The body of `POST /batch/:batch_id/share`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ShareRequest {
+    pub scope: ShareScope,
+    pub expires_in_secs: Option<u64>, // `sharing.default_expiry_secs` if not given
+}
+
+/// A new shared link, the only time its token is returned
+#[derive(Serialize)]
+pub struct ShareResponse {
+    pub share_id: uuid::Uuid,
+    pub token: String,
+    pub url: String, // Relative to the api-server, opened without an API key
+    pub scope: ShareScope,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct SharedObject {
+    pub path: String, // Relative to the shared results or thumbnails
+    pub url: String,
+}
+
+#[derive(Serialize)]
+pub struct SharedResultsResponse {
+    pub batch_id: uuid::Uuid,
+    pub scope: ShareScope,
+    pub total: usize, // Objects shared, listed or not
+    pub expires_in_secs: u64, // Of the links, never past the shared link's own expiry
+    pub expires_at: DateTime<Utc>,
+    pub objects: Vec<SharedObject>,
+}
+
+/// Where the notification preferences that apply come from, see `common::notifications`
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PreferencesSource {
+    Batch,
+    Tenant,
+    Default, // Neither the batch nor the tenant set any
+}
+
+#[derive(Serialize)]
+pub struct NotificationPreferencesResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_id: Option<uuid::Uuid>,
+    pub preferences: NotificationPreferences,
+    pub source: PreferencesSource,
+}
+
+#[derive(Serialize)]
+pub struct BatchAnnotationsResponse {
+    pub batch_id: uuid::Uuid,
+    pub annotations: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct BatchSnapshotResponse {
+    pub batch_id: uuid::Uuid,
+    pub snapshot_id: uuid::Uuid,
+    pub object_count: u64,
+}
+
+#[derive(Deserialize)]
+pub struct PresignedParams {
+    pub expires: i64, // Unix seconds the URL stops working at
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+pub struct RollbackParams {
+    pub to: uuid::Uuid, // The snapshot to restore
+}
+
+#[derive(Serialize)]
+pub struct BatchRollbackResponse {
+    pub batch_id: uuid::Uuid,
+    pub snapshot_id: uuid::Uuid,
+    pub objects_removed: u64, // Objects under the results prefix the snapshot doesn't have
+    pub objects_restored: u64,
+}
+
+#[derive(Serialize)]
+pub struct BatchCancelResponse {
+    pub batch_id: uuid::Uuid,
+    pub state: BatchState,
+    pub dataset_tasks_cancelled: u64,
+    pub image_tasks_cancelled: u64,
+}
+
+/// Who reviewed the outputs of a stage, see `common::approval`
+#[derive(Deserialize)]
+pub struct StageReviewRequest {
+    pub reviewer: String,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct StageReviewResponse {
+    pub batch_id: uuid::Uuid,
+    pub stage: u32,
+    pub task_id: uuid::Uuid,
+    pub status: TaskStatus,
+    pub approval: StageApproval,
+}
+
+#[derive(Serialize)]
+pub struct BatchDeleteResponse {
+    pub batch_id: uuid::Uuid,
+    pub documents_deleted: u64,
+    pub objects_deleted: u64,
+    pub dataset_shared: bool, // The dataset's objects were kept for the other batches reading it
+}
+
+/// Which failed tasks of a batch to retry, every one of them if `task_ids` is missing
+#[derive(Deserialize, Default)]
+pub struct BatchRetryRequest {
+    #[serde(default)]
+    pub task_ids: Option<Vec<uuid::Uuid>>, // Image or dataset task ids
+}
+
+#[derive(Serialize)]
+pub struct BatchRetryResponse {
+    pub batch_id: uuid::Uuid,
+    pub state: BatchState,
+    pub dataset_tasks_requeued: u64,
+    pub image_tasks_requeued: u64,
+    pub image_tasks_not_retried: u64, // Failed before reaching a worker, resubmit their files instead
+}
+
+/// One parameter of one operation of the source pipeline to change, e.g. stage 0 "scaling_factor"
+#[derive(Deserialize)]
+pub struct ParameterOverride {
+    pub stage: u32,
+    pub field: String,
+    pub value: serde_json::Value,
+}
+
+/// What a clone changes about its source batch, everything left out is copied
+#[derive(Deserialize)]
+pub struct BatchCloneRequest {
+    #[serde(default)]
+    pub operations: Option<Vec<ImageOperation>>, // Replaces the whole pipeline
+    #[serde(default)]
+    pub parameters: Vec<ParameterOverride>, // Applied after `operations`
+    #[serde(default)]
+    pub output_layout: Option<OutputLayout>,
+    #[serde(default)]
+    pub collision_policy: Option<CollisionPolicy>,
+    #[serde(default)]
+    pub hash_suffix: Option<bool>,
+    #[serde(default)]
+    pub output_format: Option<OutputFormat>,
+    #[serde(default)]
+    pub alpha_policy: Option<AlphaPolicy>,
+    #[serde(default)]
+    pub metadata_policy: Option<MetadataPolicy>,
+    #[serde(default)]
+    pub priority: Option<Priority>,
+    #[serde(default)]
+    pub pipeline_mode: Option<PipelineMode>,
+    #[serde(default)]
+    pub tags: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize)]
+pub struct BatchCloneResponse {
+    pub batch_id: uuid::Uuid,
+    pub parent_batch_id: uuid::Uuid,
+    pub operations: Vec<ImageOperation>,
+    pub task_ids: Vec<uuid::Uuid>,
+    pub warnings: Vec<PipelineIssue>,
+}
+
+#[derive(Serialize)]
+pub struct StageStatus {
+    pub stage: u32,
+    pub task_id: uuid::Uuid,
+    pub operation: ImageOperation,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fused_operations: Vec<ImageOperation>, // Run after `operation` by the same image tasks
+    pub status: TaskStatus,
+    pub images: StatusCounts,
+    pub completion_percentage: f64, // Share of the stage's images that finished, successfully or not
+    pub requires_approval: bool, // The stages after it wait for `approval`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approval: Option<StageApproval>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget_checkpoint: Option<BudgetCheckpoint>, // Set when decomposition exceeded its budget
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<StageQualitySummary>, // Of its sampled outputs, see `common::drift`
+}
+
+#[derive(Serialize)]
+pub struct BatchStatusResponse {
+    pub batch_id: uuid::Uuid,
+    pub dataset_key: String,
+    pub status: TaskStatus,
+    pub state: BatchState,
+    pub time_created: DateTime<Utc>,
+    pub time_completed: Option<DateTime<Utc>>,
+    pub dataset_tasks: StatusCounts,
+    pub image_tasks: StatusCounts,
+    pub stages: Vec<StageStatus>,
+    pub annotations: HashMap<String, serde_json::Value>,
+    pub config_snapshots: Vec<ConfigSnapshot>,
+    pub retention: Option<RetentionStatus>, // None for batches submitted before retention was tracked
+    pub parent_batch_id: Option<uuid::Uuid>, // The batch this one was cloned from
+    pub child_batch_ids: Vec<uuid::Uuid>,    // Batches cloned from this one, oldest first
+    pub dataset_validation: Option<DatasetValidationReport>, // None until the archive was checked
+    pub provenance_root: Option<String>, // None until the batch completed, see `common::provenance`
+    pub batch_manifest_key: Option<String>, // Set once the batch completed, see `common::manifest`
+    pub quality_drifts: Vec<QualityDrift>, // Stages whose outputs drifted from their baseline
+}
+
+#[derive(Serialize)]
+pub struct RetentionStatus {
+    pub days: u32,
+    pub expires_at: Option<DateTime<Utc>>, // None until the batch finished
+    pub days_remaining: Option<i64>,
+    pub expired: bool,
+    pub warning_sent_at: Option<DateTime<Utc>>,
+    pub results_deleted_at: Option<DateTime<Utc>>, // Set once the scheduler deleted the outputs
+}
+
+#[derive(Serialize)]
+pub struct PrefixStatus {
+    pub prefix: String, // Top-level folder, empty for images at the root of the dataset
+    pub images: StatusCounts,
+    pub completion_percentage: f64,
+    pub failure_percentage: f64,
+}
+
+#[derive(Serialize)]
+pub struct BatchPrefixStatusResponse {
+    pub batch_id: uuid::Uuid,
+    pub prefixes: Vec<PrefixStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct SloReportParams {
+    #[serde(default = "default_slo_hours")]
+    pub hours: u32, // How far back the report goes
+}
+
+fn default_slo_hours() -> u32 {
+    24
+}
+
+#[derive(Serialize)]
+pub struct LatencyWindow {
+    pub window_start: DateTime<Utc>,
+    pub window_end: DateTime<Utc>,
+    pub latency: LatencyPercentiles,
+    pub violations: Vec<SloViolation>,
+}
+
+#[derive(Serialize)]
+pub struct OperationSlo {
+    pub operation: String,
+    pub target: Option<SloTarget>, // None when no target is declared for the operation
+    pub windows: Vec<LatencyWindow>, // Oldest first
+}
+
+#[derive(Serialize)]
+pub struct SloViolatingBatch {
+    pub batch_id: uuid::Uuid,
+    pub time_completed: Option<DateTime<Utc>>,
+    pub violations: Vec<SloViolation>,
+}
+
+#[derive(Serialize)]
+pub struct SloReportResponse {
+    pub since: DateTime<Utc>,
+    pub window_secs: u64,
+    pub operations: Vec<OperationSlo>,
+    pub violating_batches: Vec<SloViolatingBatch>, // Most recent first
+}
+
+#[derive(Serialize)]
+pub struct ByteStats {
+    pub tasks: u64, // Image tasks whose bytes were measured
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub amplification: Option<f64>, // Bytes written per byte read, None when nothing was read
+}
+
+impl From<&ByteTotals> for ByteStats {
+    fn from(totals: &ByteTotals) -> Self {
+        ByteStats {
+            tasks: totals.tasks,
+            bytes_read: totals.bytes_read,
+            bytes_written: totals.bytes_written,
+            amplification: totals.amplification(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct StageBytes {
+    pub stage: u32,
+    pub task_id: uuid::Uuid,
+    pub operation: String, // e.g. "Resize+Blur" for a fused stage
+    #[serde(flatten)]
+    pub bytes: ByteStats,
+    pub images: StatusCounts,
+    pub avg_processing_ms: Option<f64>, // Null while none of the stage's images finished
+}
+
+#[derive(Serialize)]
+pub struct BatchStatsResponse {
+    pub batch_id: uuid::Uuid,
+    pub stages: Vec<StageBytes>,
+    pub total: ByteStats,
+    pub cache: CacheCounts, // Result cache lookups of the batch's image tasks
+    pub images: StatusCounts,
+    pub avg_processing_ms: Option<f64>, // From a worker picking an image up until it finished
+}
+
+/// Narrows and pages the listing of a batch's image tasks
+#[derive(Deserialize)]
+pub struct ImageTaskParams {
+    pub status: Option<TaskStatus>, // Only the tasks in this status, e.g. `Running`
+    pub offset: Option<u64>,
+    pub limit: Option<u64>, // Capped at `MAX_IMAGE_TASKS_PAGE`
+}
+
+#[derive(Serialize)]
+pub struct ImageTaskEntry {
+    pub image_task_id: uuid::Uuid,
+    pub filename: String, // Path of the image in the uploaded dataset
+    pub stage: u32,
+    pub operation: String, // e.g. "Resize+Blur" for a fused stage
+    pub status: TaskStatus,
+    pub output_key: Option<String>,
+    pub error: Option<String>, // Why the task failed, with the Failure status
+    pub time_created: DateTime<Utc>,
+    pub time_started: Option<DateTime<Utc>>,
+    pub time_completed: Option<DateTime<Utc>>,
+    pub processing_ms: Option<i64>, // Once it finished after a worker picked it up
+}
+
+#[derive(Serialize)]
+pub struct BatchImageTasksResponse {
+    pub batch_id: uuid::Uuid,
+    pub total: u64, // Tasks matching the filter, listed or not
+    pub offset: u64,
+    pub limit: u64,
+    pub images: Vec<ImageTaskEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct ByteStatsParams {
+    #[serde(default = "default_slo_hours")]
+    pub hours: u32, // How far back the report goes
+}
+
+#[derive(Serialize)]
+pub struct OperationBytes {
+    pub operation: String,
+    #[serde(flatten)]
+    pub bytes: ByteStats,
+}
+
+#[derive(Serialize)]
+pub struct ByteStatsResponse {
+    pub since: DateTime<Utc>,
+    pub operations: Vec<OperationBytes>, // Sorted by operation name
+    pub total: ByteStats,
+}
+
+/// Narrows the operation analytics, the config's defaults apply to what isn't given
+#[derive(Deserialize)]
+pub struct OperationAnalyticsParams {
+    pub hours: Option<u32>,        // How far back the report goes
+    pub bucket_hours: Option<u32>, // Length of the windows
+    pub operation: Option<String>, // e.g. "Resize" or "Resize+Blur" for a fused stage
+    pub release: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OperationAnalyticsResponse {
+    pub since: DateTime<Utc>,
+    pub bucket_hours: u32,
+    pub samples: u64,    // Tasks the report is made of
+    pub truncated: bool, // More tasks finished than `analytics.max_samples`, see the windows
+    pub windows: Vec<OperationWindow>, // By operation, release and start
+}
+
+/// A topic migration started in the background
+#[derive(Serialize)]
+pub struct TopicMigrationAccepted {
+    pub migration_id: uuid::Uuid, // Followed through /admin/topics/migrations/:migration_id
+    pub state: MigrationState,
+}
+
+#[derive(Serialize)]
+pub struct TopicMigrationStatus {
+    pub migration_id: uuid::Uuid,
+    pub plan: TopicMigrationPlan,
+    pub state: MigrationState,
+    pub report: Option<TopicMigrationReport>, // Once completed
+    pub error: Option<MigrationError>,        // Once failed, its `kind` says why
+    pub time_created: DateTime<Utc>,
+    pub time_finished: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct ControlCommandResponse {
+    pub message_type: String,
+    pub worker_id: Option<String>, // None when every worker applies the command
+}
+
+#[derive(Serialize)]
+pub struct ProducerHealth {
+    pub topic: String,
+    #[serde(flatten)]
+    pub health: ClusterHealth,
+}
+
+#[derive(Serialize)]
+pub struct KafkaHealthResponse {
+    pub primary_brokers: String,
+    pub secondary_brokers: Option<String>,
+    pub consumers_on_secondary: bool, // Whether consumers are configured to read from the secondary
+    pub producers: Vec<ProducerHealth>, // The api-server's own producers
+}
+
+#[derive(Deserialize)]
+pub struct TopicListParams {
+    #[serde(default)]
+    pub group: Option<String>, // Consumer group whose lag is reported on every topic
+}
+
+#[derive(Serialize)]
+pub struct TopicSummary {
+    pub name: String,
+    pub partitions: i32,
+    pub replication: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag: Option<i64>, // Total lag of the group, `None` without a group
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub partition_lag: Vec<PartitionLag>,
+}
+
+#[derive(Serialize)]
+pub struct TopicListResponse {
+    pub group: Option<String>,
+    pub topics: Vec<TopicSummary>,
+}
+
+#[derive(Deserialize)]
+pub struct AlterPartitionsRequest {
+    pub partitions: usize, // The new total, Kafka can only add partitions
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<DBClient>,
     pub kafka_client: Arc<ProducerClient>,
-    pub s3_client: Client, // Add this field
+    pub control_producer: Arc<ProducerClient>, // Runtime commands for the image workers
+    pub migration_producer: Arc<ProducerClient>, // Topic switches of migrations, see `migration`
+    pub image_producer: Arc<ProducerClient>,   // Image tasks requeued by a retry
+    pub storage: Arc<dyn StorageBackend>,
+    pub config: Arc<Config>,
+    pub events: Arc<BatchEventHub>, // Progress events streamed to browsers
+    pub event_publisher: BatchEventPublisher, // Publishes the cancellations of batches
+    pub archive_builds: Arc<ArchiveBuilds>, // Results archives being built for the download proxy
 }
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum APIError {
     #[error("Failed to send task to Producer Queue")]
     SendTaskError(String),
@@ -58,22 +989,131 @@ pub enum APIError {
 
     #[error("Failed to upload image to S3")]
     UploadError(String),
+
+    #[error("Not Found: {0}")]
+    NotFoundError(String),
+
+    #[error("Validation Error: {0}")]
+    ValidationError(String),
+
+    #[error("Timeout: {0}")]
+    TimeoutError(String),
+
+    #[error("Payload Too Large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Validation Error: {} invalid fields", .0.len())]
+    InvalidFields(Vec<FieldError>),
+
+    #[error("Too Many Requests: retry in {0}s")]
+    TooManyRequests(u64), // Seconds until the next request is accepted
+
+    #[error("{}", .0.message(Locale::En))]
+    Catalogued(CatalogError), // An error with a message and hint of its own, see `errors`
 }
 
-impl IntoResponse for APIError {
-    fn into_response(self) -> Response {
-        let res = match self {
-            APIError::SendTaskError(message) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, message.to_string())
-            }
-            APIError::DatabaseError(message) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, message.to_string())
+/// A field of a request body that failed validation, returned with 422
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String, // Path of the field in the body, e.g. "operations[1].scaling_factor"
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ProcessorError> for APIError {
+    fn from(error: ProcessorError) -> Self {
+        let message = error.to_string();
+        match error {
+            ProcessorError::Kafka { .. } => APIError::SendTaskError(message),
+            ProcessorError::S3 { .. } => APIError::UploadError(message),
+            ProcessorError::NotFound(_) => APIError::NotFoundError(message),
+            ProcessorError::Validation(_) => APIError::ValidationError(message),
+            ProcessorError::Database { .. }
+            | ProcessorError::Serialization(_)
+            | ProcessorError::Conflict(_)
+            | ProcessorError::Internal(_)
+            | ProcessorError::Unsupported(_) => APIError::DatabaseError(message),
+        }
+    }
+}
+
+impl APIError {
+    pub fn unsupported_extension(extension: &str, allowed: &[&str]) -> Self {
+        let error = CatalogError::new(ErrorCode::UnsupportedExtension)
+            .with("extension", extension)
+            .with("allowed", allowed.join(", "));
+        APIError::Catalogued(error)
+    }
+
+    pub fn batch_not_found(batch_id: &uuid::Uuid) -> Self {
+        APIError::Catalogued(CatalogError::new(ErrorCode::BatchNotFound).with("batch_id", batch_id))
+    }
+
+    pub fn dataset_not_found(dataset: &str) -> Self {
+        APIError::Catalogued(CatalogError::new(ErrorCode::DatasetNotFound).with("dataset", dataset))
+    }
+
+    pub fn topic_exists(topic: &str) -> Self {
+        APIError::Catalogued(CatalogError::new(ErrorCode::TopicExists).with("topic", topic))
+    }
+
+    pub fn archive_build_failed(batch_id: &uuid::Uuid, retry_at: DateTime<Utc>) -> Self {
+        APIError::Catalogued(
+            CatalogError::new(ErrorCode::ArchiveBuildFailed)
+                .with("batch_id", batch_id)
+                .with("retry_at", retry_at.to_rfc3339()),
+        )
+    }
+
+    /// The catalog entry of the error, errors built from free text get the code of their variant
+    fn into_catalog(self) -> (CatalogError, Vec<FieldError>) {
+        let (code, detail) = match self {
+            APIError::Catalogued(error) => return (error, Vec::new()),
+            APIError::InvalidFields(errors) => {
+                return (CatalogError::new(ErrorCode::InvalidFields), errors);
             }
-            APIError::UploadError(message) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, message.to_string())
+            APIError::TooManyRequests(retry_after_secs) => {
+                let error =
+                    CatalogError::new(ErrorCode::RateLimited).with("retry_after", retry_after_secs);
+                return (error, Vec::new());
             }
+            APIError::SendTaskError(message) => (ErrorCode::QueueUnavailable, message),
+            APIError::DatabaseError(message) => (ErrorCode::DatabaseError, message),
+            APIError::UploadError(message) => (ErrorCode::StorageError, message),
+            APIError::NotFoundError(message) => (ErrorCode::NotFound, message),
+            APIError::ValidationError(message) => (ErrorCode::ValidationFailed, message),
+            APIError::TimeoutError(message) => (ErrorCode::Timeout, message),
+            APIError::PayloadTooLarge(message) => (ErrorCode::PayloadTooLarge, message),
+            APIError::Unauthorized(message) => (ErrorCode::Unauthorized, message),
+        };
+        (CatalogError::new(code).with("detail", detail), Vec::new())
+    }
+}
+
+impl IntoResponse for APIError {
+    /// A JSON body with the code, message and hint of the error in English, see `errors::localize`
+    fn into_response(self) -> Response {
+        let retry_after = match &self {
+            APIError::TooManyRequests(retry_after_secs) => Some(*retry_after_secs),
+            _ => None,
         };
+        let (error, fields) = self.into_catalog();
+        let mut response = RenderedError { error, fields }.into_response();
+        if let Some(retry_after_secs) = retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, retry_after_secs.into());
+        }
 
-        res.into_response()
+        response
     }
 }
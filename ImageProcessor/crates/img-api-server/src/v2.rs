@@ -0,0 +1,46 @@
+use axum::{
+    Extension, Router,
+    extract::Path,
+    response::{Json, Response},
+    routing::{get, post},
+};
+
+use common::DatasetProcessingJob;
+
+use crate::auth::Caller;
+use crate::dto::v2::{BatchStatus, DispatchResult, JobRequest};
+use crate::utils::AppState;
+
+/// Routes served under `/v2`, using the camelCase DTOs from `dto::v2`. A read replica only
+/// serves the status.
+pub fn router(read_only: bool) -> Router {
+    let router = Router::new().route("/batch/:batch_id/status", get(get_batch_status));
+    match read_only {
+        true => router,
+        false => router.route("/send_task", post(send_task)),
+    }
+}
+
+#[axum::debug_handler]
+async fn send_task(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(request): Json<JobRequest>,
+) -> Result<Json<DispatchResult>, Response> {
+    let mut job: DatasetProcessingJob = request.into();
+    job.tenant_id = caller.tenant_id().map(String::from);
+    crate::dispatch_job(&state, job, None, None)
+        .await
+        .map(|result| Json(result.into()))
+}
+
+#[axum::debug_handler]
+async fn get_batch_status(
+    Extension(state): Extension<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(batch_id): Path<uuid::Uuid>,
+) -> Result<Json<BatchStatus>, Response> {
+    crate::batch::batch_status(&state, &caller, batch_id)
+        .await
+        .map(|status| Json(status.into()))
+}
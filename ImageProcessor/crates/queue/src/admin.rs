@@ -1,11 +1,58 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, time::Duration};
 
+use common::error::ProcessorError;
 use rdkafka::{
-    admin::{AdminClient, AdminOptions, NewTopic, TopicReplication},
+    admin::{AdminClient, AdminOptions, NewPartitions, NewTopic, TopicReplication, TopicResult},
     client::DefaultClientContext,
     config::ClientConfig,
+    types::RDKafkaErrorCode,
 };
 
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A topic as the brokers describe it
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TopicDescription {
+    pub name: String,
+    pub partitions: i32,
+    pub replication: usize, // Replicas of the first partition, the others are created alike
+}
+
+/// The settings of a topic to create
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct TopicSpec {
+    pub name: String,
+    pub partitions: i32,
+    #[serde(default = "default_replication")]
+    pub replication: i32,
+    #[serde(default)]
+    pub config: BTreeMap<String, String>, // Topic configs, e.g. "retention.ms"
+}
+
+fn default_replication() -> i32 {
+    1
+}
+
+/// Turns the result the brokers sent for a single topic into an error the caller can act on
+fn topic_result(results: Vec<TopicResult>) -> Result<(), ProcessorError> {
+    for result in results {
+        if let Err((topic, code)) = result {
+            let message = format!("{}: {}", topic, code);
+            return Err(match code {
+                RDKafkaErrorCode::UnknownTopicOrPartition => ProcessorError::NotFound(message),
+                RDKafkaErrorCode::TopicAlreadyExists
+                | RDKafkaErrorCode::InvalidPartitions
+                | RDKafkaErrorCode::InvalidReplicationFactor
+                | RDKafkaErrorCode::InvalidConfig
+                | RDKafkaErrorCode::InvalidTopic
+                | RDKafkaErrorCode::InvalidRequest => ProcessorError::Validation(message),
+                _ => ProcessorError::kafka(message, true),
+            });
+        }
+    }
+    Ok(())
+}
+
 pub struct KafkaAdmin {
     admin: AdminClient<DefaultClientContext>,
 }
@@ -22,7 +69,11 @@ impl KafkaAdmin {
             admin: admin_client,
         }
     }
-    
+
+    fn options() -> AdminOptions {
+        AdminOptions::new().operation_timeout(Some(ADMIN_TIMEOUT))
+    }
+
     /// This function is responsible for creating a topic
     pub async fn create_topic(&self, topic_name: &str, num_partitions: i32) -> Result<(), String> {
         let new_topic = NewTopic::new(topic_name, num_partitions, TopicReplication::Fixed(1));
@@ -34,4 +85,113 @@ impl KafkaAdmin {
             Err(e) => Err(format!("Failed to create topic: {}", e)),
         }
     }
+
+    /// Creates a topic with the given settings. Unlike `create_topic`, a topic that already
+    /// exists is an error.
+    pub async fn create_topic_with(&self, spec: &TopicSpec) -> Result<(), ProcessorError> {
+        let mut new_topic = NewTopic::new(
+            &spec.name,
+            spec.partitions,
+            TopicReplication::Fixed(spec.replication),
+        );
+        for (key, value) in &spec.config {
+            new_topic = new_topic.set(key, value);
+        }
+
+        let results = self
+            .admin
+            .create_topics(&[new_topic], &Self::options())
+            .await
+            .map_err(|e| ProcessorError::kafka(format!("Failed to create topic: {}", e), true))?;
+        topic_result(results)
+    }
+
+    /// Describes every topic of the cluster, or only `topic`. Kafka's internal topics are left
+    /// out.
+    ///
+    /// This is a blocking call, so async callers should run it through `spawn_blocking`.
+    pub fn describe_topics(
+        &self,
+        topic: Option<&str>,
+    ) -> Result<Vec<TopicDescription>, ProcessorError> {
+        let metadata = self
+            .admin
+            .inner()
+            .fetch_metadata(topic, ADMIN_TIMEOUT)
+            .map_err(|e| ProcessorError::kafka(format!("Failed to fetch metadata: {}", e), true))?;
+
+        let mut topics = Vec::new();
+        for md_topic in metadata.topics() {
+            if md_topic.name().starts_with("__") {
+                continue;
+            }
+            // A single unknown topic is still listed by the brokers, with an error
+            if let Some(error) = md_topic.error() {
+                let name = md_topic.name();
+                return Err(match RDKafkaErrorCode::from(error) {
+                    RDKafkaErrorCode::UnknownTopicOrPartition => {
+                        ProcessorError::NotFound(format!("Topic {} does not exist", name))
+                    }
+                    code => ProcessorError::kafka(format!("{}: {}", name, code), true),
+                });
+            }
+            topics.push(TopicDescription {
+                name: md_topic.name().to_string(),
+                partitions: md_topic.partitions().len() as i32,
+                replication: md_topic
+                    .partitions()
+                    .first()
+                    .map_or(0, |partition| partition.replicas().len()),
+            });
+        }
+        topics.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(topics)
+    }
+
+    /// How many consumers are members of a group, none once they all left. Consumers assigned
+    /// their partitions by hand, like pinned ones, never join their group.
+    ///
+    /// This is a blocking call, so async callers should run it through `spawn_blocking`.
+    pub fn group_members(&self, group_id: &str) -> Result<usize, ProcessorError> {
+        let groups = self
+            .admin
+            .inner()
+            .fetch_group_list(Some(group_id), ADMIN_TIMEOUT)
+            .map_err(|e| ProcessorError::kafka(format!("Failed to describe group: {}", e), true))?;
+        Ok(groups
+            .groups()
+            .iter()
+            .filter(|group| group.name() == group_id)
+            .map(|group| group.members().len())
+            .sum())
+    }
+
+    /// Deletes a topic along with every message on it
+    pub async fn delete_topic(&self, topic: &str) -> Result<(), ProcessorError> {
+        let results = self
+            .admin
+            .delete_topics(&[topic], &Self::options())
+            .await
+            .map_err(|e| ProcessorError::kafka(format!("Failed to delete topic: {}", e), true))?;
+        topic_result(results)
+    }
+
+    /// Grows a topic to `partitions` partitions. Kafka can't take partitions away, so asking for
+    /// fewer than the topic has is rejected.
+    ///
+    /// Keys hash to other partitions from then on, so messages of a key sent before and after
+    /// can be read out of order.
+    pub async fn alter_partitions(
+        &self,
+        topic: &str,
+        partitions: usize,
+    ) -> Result<(), ProcessorError> {
+        let new_partitions = NewPartitions::new(topic, partitions);
+        let results = self
+            .admin
+            .create_partitions(&[new_partitions], &Self::options())
+            .await
+            .map_err(|e| ProcessorError::kafka(format!("Failed to add partitions: {}", e), true))?;
+        topic_result(results)
+    }
 }
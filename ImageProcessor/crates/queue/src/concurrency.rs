@@ -0,0 +1,160 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    Offset, TopicPartitionList,
+};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+// ============================================================================
+// CONCURRENT CONSUMPTION
+// A consumer with a `ConcurrencyLimit` hands messages to its handler without
+// waiting for the previous one to finish. The limit can be changed and
+// consumption paused while the consumer runs, e.g. by a component watching
+// memory usage. Offsets are only committed up to the oldest message still
+// being handled, so a restart redelivers every unfinished message.
+// ============================================================================
+
+/// How many messages a consumer handles at once, shared by every partition task
+pub struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    limit: AtomicUsize,
+    paused: watch::Sender<bool>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit: AtomicUsize::new(limit),
+            paused: watch::Sender::new(false),
+        }
+    }
+
+    /// The number of messages handled at once once every shrink has taken effect
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Changes the number of messages handled at once, at least one.
+    ///
+    /// Growing takes effect immediately. Shrinking waits for handlers to finish, messages being
+    /// handled are never interrupted.
+    pub fn set_limit(&self, limit: usize) {
+        let limit = limit.max(1);
+        let previous = self.limit.swap(limit, Ordering::Relaxed);
+
+        if limit > previous {
+            self.semaphore.add_permits(limit - previous);
+        } else if limit < previous {
+            let excess = previous - limit;
+            let pending = excess - self.semaphore.forget_permits(excess);
+            if pending > 0 {
+                let semaphore = Arc::clone(&self.semaphore);
+                tokio::spawn(async move {
+                    if let Ok(permits) = semaphore.acquire_many_owned(pending as u32).await {
+                        permits.forget();
+                    }
+                });
+            }
+        }
+    }
+
+    /// Stops pulling new messages, the ones being handled still finish
+    pub fn pause(&self) {
+        self.paused
+            .send_if_modified(|paused| !std::mem::replace(paused, true));
+    }
+
+    pub fn resume(&self) {
+        self.paused
+            .send_if_modified(|paused| std::mem::replace(paused, false));
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Waits until consumption isn't paused and a message can be handled
+    pub(crate) async fn acquire(&self) -> OwnedSemaphorePermit {
+        let mut paused = self.paused.subscribe();
+        // The sender lives as long as `self`, so waiting can't fail
+        let _ = paused.wait_for(|paused| !paused).await;
+
+        Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("Concurrency semaphore closed")
+    }
+}
+
+/// Where a message came from, to commit its offset once it was handled
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct MessagePosition {
+    pub(crate) topic: String,
+    pub(crate) partition: i32,
+    pub(crate) offset: i64,
+}
+
+/// Tracks the messages being handled per partition, so the committed offset never passes one
+/// that hasn't finished
+#[derive(Default)]
+pub(crate) struct OffsetTracker {
+    in_flight: HashMap<(String, i32), BTreeSet<i64>>,
+    next: HashMap<(String, i32), i64>, // One past the newest offset received
+}
+
+impl OffsetTracker {
+    pub(crate) fn start(&mut self, position: &MessagePosition) {
+        let partition = (position.topic.clone(), position.partition);
+        self.in_flight
+            .entry(partition.clone())
+            .or_default()
+            .insert(position.offset);
+        let next = self.next.entry(partition).or_insert(position.offset + 1);
+        *next = (*next).max(position.offset + 1);
+    }
+
+    /// Marks a message as handled and commits the offset of its partition if it moved
+    pub(crate) fn finish(&mut self, position: &MessagePosition, consumer: &StreamConsumer) {
+        let partition = (position.topic.clone(), position.partition);
+        let Some(in_flight) = self.in_flight.get_mut(&partition) else {
+            return;
+        };
+        let oldest = in_flight.first().copied();
+        in_flight.remove(&position.offset);
+
+        // Finishing a newer message while an older one is still handled moves nothing
+        if oldest != Some(position.offset) {
+            return;
+        }
+        let commit_offset = match in_flight.first() {
+            Some(offset) => *offset,
+            None => self.next[&partition],
+        };
+
+        let mut offsets = TopicPartitionList::new();
+        let added = offsets.add_partition_offset(
+            &position.topic,
+            position.partition,
+            Offset::Offset(commit_offset),
+        );
+        if let Err(e) = added.and_then(|_| consumer.commit(&offsets, CommitMode::Async)) {
+            tracing::error!(
+                topic = %position.topic,
+                partition = position.partition,
+                offset = commit_offset,
+                error = %e,
+                "Failed to commit offset"
+            );
+        }
+    }
+}
@@ -1,54 +1,1284 @@
 use rdkafka::{
-    Message,
+    Message, Offset, TopicPartitionList,
     config::ClientConfig,
-    consumer::{Consumer, StreamConsumer},
+    error::{KafkaError, RDKafkaErrorCode},
+    consumer::{CommitMode, Consumer, MessageStream, StreamConsumer},
+    message::BorrowedMessage,
+};
+use common::{
+    capabilities::Capability,
+    checkpoints::PartitionOffset,
+    config::{
+        KafkaConfig, MessageEncoding, PoisonPillConfig, PriorityConfig, QueueKind,
+        StartPositionConfig,
+    },
+    correlation,
+    envelope::{MessageEnvelope, SCHEMA_VERSION},
+    error::ProcessorError,
+    metrics,
+    migration::TopicSwitch,
+    Priority,
 };
 use serde::de::DeserializeOwned;
-use futures::StreamExt;
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use std::{
+    marker::PhantomData,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::{
+    concurrency::{ConcurrencyLimit, MessagePosition, OffsetTracker},
+    encoding::{decode_envelope, encoding_of, CONTENT_TYPE_HEADER},
+    holding::CapabilityHolding, lag::consumer_group_lag,
+    memory::{MemoryQueue, MessageQueue, Subscription}, poison::PoisonPillHandler,
+    retry::is_retryable,
+    routing::{header, MessageRouter, RoutedMessage, MESSAGE_TYPE_HEADER},
+    shutdown::shutdown_signal, start::position_new_partitions};
+
+const LAG_METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Records the offsets the group committed, e.g. in the database, see `common::checkpoints`
+type CheckpointSink = Arc<dyn Fn(Vec<PartitionOffset>) -> BoxFuture<'static, ()> + Send + Sync>;
+
 pub struct ConsumerClient {
-    pub consumer: StreamConsumer,
+    pub consumer: Arc<StreamConsumer>,
+    topics: Arc<RwLock<Vec<String>>>, // Switched by a topic migration, see `switch_topic`
+    brokers: String,
+    group_id: String,
+    split_partition_streams: bool, // Consume every partition on its own task, see `new_split`
+    shutdown: CancellationToken,   // Cancelled to stop consuming, see `shutdown`
+    concurrency: Option<Arc<ConcurrencyLimit>>, // Handle messages concurrently, see `with_concurrency_limit`
+    poison_pill: Arc<PoisonPillHandler>, // What happens to messages that can't be handled, see `with_poison_pill`
+    priority_lanes: Vec<PriorityLane>, // High and Low priority topics, see `with_priority_lanes`
+    checkpoints: Option<CheckpointSink>, // Handed the committed offsets, see `with_checkpoints`
+    holding: Option<Arc<CapabilityHolding>>, // Tasks lacking a capability go there, see `with_capability_holding`
+    queue: Option<Arc<dyn MessageQueue>>, // Consumed instead of Kafka, see `in_memory`
+    from_latest: bool, // Subscribe to the queue at the next message, see `broadcast_from_config`
+    pinned: Vec<i32>, // Partitions assigned instead of subscribing, see `new_pinned`
+}
+
+/// A consumer of the High or Low priority topics, handling up to its weight of messages at once
+struct PriorityLane {
+    priority: Priority,
+    consumer: Arc<StreamConsumer>,
+    concurrency: Arc<ConcurrencyLimit>,
+}
+
+/// Turns the raw messages of a stream into what the handler is given
+pub(crate) trait MessageReader: Send + Sync + 'static {
+    type Message: Clone + Send + 'static;
+
+    /// Reads a non-empty message in its encoding with the value of its `message-type` header, the
+    /// error says why it can't be handled
+    fn read(
+        &self,
+        message_type: Option<&str>,
+        encoding: MessageEncoding,
+        payload: &[u8],
+    ) -> Result<Self::Message, String>;
+
+    fn message_id(message: &Self::Message) -> uuid::Uuid;
+
+    /// The request the message was sent for, see `common::correlation`
+    fn correlation_id(message: &Self::Message) -> Option<uuid::Uuid>;
+}
+
+/// Reads every message of a stream as an envelope of the same payload type
+struct EnvelopeReader<I>(PhantomData<fn() -> I>);
+
+impl<I: DeserializeOwned + Clone + Send + 'static> MessageReader for EnvelopeReader<I> {
+    type Message = MessageEnvelope<I>;
+
+    fn read(
+        &self,
+        _message_type: Option<&str>,
+        encoding: MessageEncoding,
+        payload: &[u8],
+    ) -> Result<Self::Message, String> {
+        decode_envelope(encoding, payload)
+    }
+
+    fn message_id(message: &Self::Message) -> uuid::Uuid {
+        message.message_id
+    }
+
+    fn correlation_id(message: &Self::Message) -> Option<uuid::Uuid> {
+        message.trace.correlation_id
+    }
+}
+
+fn kafka_error(error: KafkaError) -> ProcessorError {
+    let transient = is_retryable(&error);
+    ProcessorError::kafka(error, transient)
+}
+
+fn create_consumer(brokers: &str, group_id: &str) -> Result<StreamConsumer, ProcessorError> {
+    create_consumer_from(brokers, group_id, "earliest")
+}
+
+/// Creates a consumer starting at `offset_reset` ("earliest" or "latest") when its group has no
+/// committed offset
+fn create_consumer_from(
+    brokers: &str,
+    group_id: &str,
+    offset_reset: &str,
+) -> Result<StreamConsumer, ProcessorError> {
+    ClientConfig::new()
+        .set("group.id", group_id)
+        .set("bootstrap.servers", brokers)
+        .set("enable.partition.eof", "false")
+        .set("auto.offset.reset", offset_reset)
+        // Offsets are committed by `consume_stream` once the handler of a message has returned,
+        // so a message that was received but not handled is delivered again after a restart
+        .set("enable.auto.commit", "false")
+        .set("enable.auto.offset.store", "false")
+        .create()
+        .map_err(kafka_error)
+}
+
+fn topic_list(topics: &[&str]) -> Arc<RwLock<Vec<String>>> {
+    Arc::new(RwLock::new(topics.iter().map(|t| t.to_string()).collect()))
+}
+
+/// The topics of a priority lane for the subscribed topics
+fn lane_topics(priority: Priority, topics: &[String]) -> Vec<String> {
+    topics.iter().map(|t| priority.topic(t)).collect()
+}
+
+/// The subscribed topics followed by those of the lanes
+fn with_lanes(topics: &[String], lanes: &[Priority]) -> Vec<String> {
+    let lane_topics = lanes.iter().flat_map(|priority| lane_topics(*priority, topics));
+    topics.iter().cloned().chain(lane_topics).collect()
+}
+
+/// Subscribes the consumer to its topics, or assigns it the `pinned` partitions of each of them
+/// starting at the group's committed offsets
+fn attach(
+    consumer: &StreamConsumer,
+    topics: &[String],
+    pinned: &[i32],
+) -> Result<(), ProcessorError> {
+    if pinned.is_empty() {
+        let subscription: Vec<&str> = topics.iter().map(String::as_str).collect();
+        return consumer.subscribe(&subscription).map_err(kafka_error);
+    }
+
+    let mut assignment = TopicPartitionList::new();
+    for topic in topics {
+        for partition in pinned {
+            assignment
+                .add_partition_offset(topic, *partition, Offset::Stored)
+                .map_err(kafka_error)?;
+        }
+    }
+    consumer.assign(&assignment).map_err(kafka_error)
 }
 
 impl ConsumerClient {
-    pub fn new(brokers: &str, group_id: &str, topics: &[&str]) -> Self {
-        let consumer: StreamConsumer<rdkafka::consumer::DefaultConsumerContext> =
-            ClientConfig::new()
-                .set("group.id", group_id)
-                .set("bootstrap.servers", brokers)
-                .set("enable.partition.eof", "false")
-                .set("auto.offset.reset", "earliest")
-                .create()
-                .unwrap();
+    pub fn new(brokers: &str, group_id: &str, topics: &[&str]) -> Result<Self, ProcessorError> {
+        let consumer = create_consumer(brokers, group_id)?;
+
+        consumer.subscribe(topics).map_err(kafka_error)?;
+
+        Ok(Self {
+            consumer: Arc::new(consumer),
+            topics: topic_list(topics),
+            brokers: brokers.to_string(),
+            group_id: group_id.to_string(),
+            split_partition_streams: false,
+            shutdown: CancellationToken::new(),
+            concurrency: None,
+            poison_pill: Arc::new(PoisonPillHandler::new(brokers, PoisonPillConfig::default())),
+            priority_lanes: Vec::new(),
+            checkpoints: None,
+            holding: None,
+            queue: None,
+            from_latest: false,
+            pinned: Vec::new(),
+        })
+    }
+
+    /// Creates a consumer that handles every partition of its topics on a separate task.
+    ///
+    /// Messages of one partition are still handled one at a time and in order, but partitions no
+    /// longer wait on each other, so a single process gets the throughput of several consumers.
+    /// Offsets are committed per partition once a message has been handled, so the committed
+    /// offset of a partition never runs ahead of the work done on it.
+    pub fn new_split(
+        brokers: &str,
+        group_id: &str,
+        topics: &[&str],
+    ) -> Result<Self, ProcessorError> {
+        let consumer = create_consumer(brokers, group_id)?;
+
+        consumer.subscribe(topics).map_err(kafka_error)?;
+
+        Ok(Self {
+            consumer: Arc::new(consumer),
+            topics: topic_list(topics),
+            brokers: brokers.to_string(),
+            group_id: group_id.to_string(),
+            split_partition_streams: true,
+            shutdown: CancellationToken::new(),
+            concurrency: None,
+            poison_pill: Arc::new(PoisonPillHandler::new(brokers, PoisonPillConfig::default())),
+            priority_lanes: Vec::new(),
+            checkpoints: None,
+            holding: None,
+            queue: None,
+            from_latest: false,
+            pinned: Vec::new(),
+        })
+    }
+
+    /// Creates a consumer that receives every message of its topics, in a consumer group of its
+    /// own named after `group_prefix`. It starts with the messages sent after it was created, so
+    /// a restarted process doesn't apply old messages again.
+    pub fn new_broadcast(
+        brokers: &str,
+        group_prefix: &str,
+        topics: &[&str],
+    ) -> Result<Self, ProcessorError> {
+        let group_id = format!("{}-{}", group_prefix, uuid::Uuid::new_v4());
+        let consumer = create_consumer_from(brokers, &group_id, "latest")?;
+
+        consumer.subscribe(topics).map_err(kafka_error)?;
+
+        Ok(Self {
+            consumer: Arc::new(consumer),
+            topics: topic_list(topics),
+            brokers: brokers.to_string(),
+            group_id,
+            split_partition_streams: false,
+            shutdown: CancellationToken::new(),
+            concurrency: None,
+            poison_pill: Arc::new(PoisonPillHandler::new(brokers, PoisonPillConfig::default())),
+            priority_lanes: Vec::new(),
+            checkpoints: None,
+            holding: None,
+            queue: None,
+            from_latest: false,
+            pinned: Vec::new(),
+        })
+    }
+
+    /// Creates a consumer for the consumer brokers of the config (the secondary cluster while
+    /// consumers are pointed at it), split per partition if the config asks for it, applying the
+    /// config's poison pill policies. With the Memory backend it consumes the in-process queue.
+    pub fn from_config(
+        config: &KafkaConfig,
+        group_id: &str,
+        topics: &[&str],
+    ) -> Result<Self, ProcessorError> {
+        let brokers = config.consumer_brokers();
+        let client = match (config.backend, config.split_partition_streams) {
+            (QueueKind::Memory, _) => Self::in_memory(MemoryQueue::shared(), group_id, topics)?,
+            (QueueKind::Kafka, true) => Self::new_split(brokers, group_id, topics)?,
+            (QueueKind::Kafka, false) => Self::new(brokers, group_id, topics)?,
+        };
+        let client = client.with_poison_pill(config.poison_pill.clone());
+        match config.priority.dedicated_topics {
+            true => client.with_priority_lanes(&config.priority),
+            false => Ok(client),
+        }
+    }
+
+    /// Creates a consumer of the in-process queue instead of Kafka, see `memory`. Messages are
+    /// handled as they are received, there are no offsets to commit, lag to report or partitions
+    /// to split, and the priority topics are consumed along with the others.
+    pub fn in_memory(
+        queue: Arc<dyn MessageQueue>,
+        group_id: &str,
+        topics: &[&str],
+    ) -> Result<Self, ProcessorError> {
+        // The Kafka consumer is never subscribed, and without brokers it never connects
+        let consumer = create_consumer("", group_id)?;
+        let poison_pill =
+            PoisonPillHandler::in_memory(Arc::clone(&queue), PoisonPillConfig::default());
+
+        Ok(Self {
+            consumer: Arc::new(consumer),
+            topics: topic_list(topics),
+            brokers: String::new(),
+            group_id: group_id.to_string(),
+            split_partition_streams: false,
+            shutdown: CancellationToken::new(),
+            concurrency: None,
+            poison_pill: Arc::new(poison_pill),
+            priority_lanes: Vec::new(),
+            checkpoints: None,
+            holding: None,
+            queue: Some(queue),
+            from_latest: false,
+            pinned: Vec::new(),
+        })
+    }
+
+    /// Creates a consumer that receives every message of its topics, see `new_broadcast`, from
+    /// the consumer brokers or the in-process queue of the config
+    pub fn broadcast_from_config(
+        config: &KafkaConfig,
+        group_prefix: &str,
+        topics: &[&str],
+    ) -> Result<Self, ProcessorError> {
+        match config.backend {
+            QueueKind::Kafka => {
+                Self::new_broadcast(config.consumer_brokers(), group_prefix, topics)
+            }
+            QueueKind::Memory => {
+                let group_id = format!("{}-{}", group_prefix, uuid::Uuid::new_v4());
+                let client = Self::in_memory(MemoryQueue::shared(), &group_id, topics)?;
+                Ok(Self {
+                    from_latest: true,
+                    ..client
+                })
+            }
+        }
+    }
+
+    /// Creates a consumer pinned to specific partitions of its topics instead of joining the
+    /// group's rebalancing, so the same worker always receives the same images.
+    ///
+    /// Pinned consumers never join their group, so its members can't share it: Kafka refuses the
+    /// offsets a non-member commits while the group has members. Give pinned consumers a group of
+    /// their own.
+    pub fn new_pinned(
+        brokers: &str,
+        group_id: &str,
+        topics: &[&str],
+        partitions: &[i32],
+    ) -> Result<Self, ProcessorError> {
+        let consumer = create_consumer(brokers, group_id)?;
+        let topics = topic_list(topics);
+        attach(&consumer, &topics.read().expect("Topics lock poisoned"), partitions)?;
+
+        Ok(Self {
+            consumer: Arc::new(consumer),
+            topics,
+            brokers: brokers.to_string(),
+            group_id: group_id.to_string(),
+            split_partition_streams: false,
+            shutdown: CancellationToken::new(),
+            concurrency: None,
+            poison_pill: Arc::new(PoisonPillHandler::new(brokers, PoisonPillConfig::default())),
+            priority_lanes: Vec::new(),
+            checkpoints: None,
+            holding: None,
+            queue: None,
+            from_latest: false,
+            pinned: partitions.to_vec(),
+        })
+    }
+
+    /// Creates a consumer pinned to partitions of its topics, see `new_pinned`, from the consumer
+    /// brokers of the config, applying the config's poison pill policies and priority topics like
+    /// `from_config`. The priority topics are pinned to the same partitions. The in-process queue
+    /// has a single partition, so it is consumed as a whole.
+    pub fn pinned_from_config(
+        config: &KafkaConfig,
+        group_id: &str,
+        topics: &[&str],
+        partitions: &[i32],
+    ) -> Result<Self, ProcessorError> {
+        let client = match config.backend {
+            QueueKind::Kafka => {
+                Self::new_pinned(config.consumer_brokers(), group_id, topics, partitions)?
+            }
+            QueueKind::Memory => Self::in_memory(MemoryQueue::shared(), group_id, topics)?,
+        };
+        let client = client.with_poison_pill(config.poison_pill.clone());
+        match config.priority.dedicated_topics {
+            true => client.with_priority_lanes(&config.priority),
+            false => Ok(client),
+        }
+    }
 
-        consumer
-            .subscribe(topics)
-            .expect("Failed to create consumer");
+    /// Handles up to `limit` messages at once instead of one after the other. The limit is shared
+    /// by every partition task and can be changed, or consumption paused, while consuming.
+    pub fn with_concurrency_limit(mut self, limit: Arc<ConcurrencyLimit>) -> Self {
+        self.concurrency = Some(limit);
+        self
+    }
+
+    /// Replaces the policies applied to messages that can't be read or whose handler fails or
+    /// panics `max_handler_attempts` times. Without it every topic dead letters its poison pills.
+    pub fn with_poison_pill(mut self, config: PoisonPillConfig) -> Self {
+        let poison_pill = match &self.queue {
+            Some(queue) => PoisonPillHandler::in_memory(Arc::clone(queue), config),
+            None => PoisonPillHandler::new(&self.brokers, config),
+        };
+        self.poison_pill = Arc::new(poison_pill);
+        self
+    }
 
-        Self { consumer }
+    /// Moves the messages the consumer can't handle for lack of a capability to the holding topic
+    /// instead of applying the poison pill policy to them: those of a variant this build doesn't
+    /// know and those whose handler returns `ProcessorError::Unsupported`.
+    pub fn with_capability_holding(mut self, holding: CapabilityHolding) -> Self {
+        self.holding = Some(Arc::new(holding));
+        self
     }
 
+    /// Also consumes the High and Low priority topics of every subscribed topic, each on a consumer
+    /// of its own that handles up to the priority's weight of messages at once. The subscribed
+    /// topics themselves carry Normal work and are limited to its weight unless the consumer
+    /// already has a concurrency limit.
+    ///
+    /// Since no priority waits on another, a small High batch is picked up while a huge Normal
+    /// one is still being worked through.
+    pub fn with_priority_lanes(mut self, config: &PriorityConfig) -> Result<Self, ProcessorError> {
+        if self.queue.is_some() {
+            let lanes = with_lanes(&self.topics(), &[Priority::High, Priority::Low]);
+            *self.topics.write().expect("Topics lock poisoned") = lanes;
+            return Ok(self);
+        }
+
+        for priority in [Priority::High, Priority::Low] {
+            let topics = lane_topics(priority, &self.topics());
+            let consumer = create_consumer(&self.brokers, &self.group_id)?;
+            attach(&consumer, &topics, &self.pinned)?;
+
+            self.priority_lanes.push(PriorityLane {
+                priority,
+                consumer: Arc::new(consumer),
+                concurrency: Arc::new(ConcurrencyLimit::new(config.weight(priority))),
+            });
+        }
+
+        if self.concurrency.is_none() {
+            self.concurrency = Some(Arc::new(ConcurrencyLimit::new(
+                config.weight(Priority::Normal),
+            )));
+        }
+        Ok(self)
+    }
+
+    /// Starts the partitions the group never committed an offset on where `config` says, instead
+    /// of at their earliest message, so a new deployment doesn't work through months of old
+    /// batches. `checkpoints` are the offsets last recorded for the group, read by the Checkpoint
+    /// policy. Has to be called before consuming, and is meant for the groups of `new`,
+    /// `new_split`, `from_config` and the pinned consumers.
+    pub fn with_start_position(
+        self,
+        config: &StartPositionConfig,
+        checkpoints: &[PartitionOffset],
+    ) -> Result<Self, ProcessorError> {
+        // The in-process queue keeps no offsets, groups start at the first message
+        if self.queue.is_some() {
+            return Ok(self);
+        }
+        let topics = self.all_topics();
+        let positioned =
+            position_new_partitions(&self.brokers, &self.group_id, &topics, config, checkpoints)
+                .map_err(|e| ProcessorError::kafka(e, true))?;
+
+        for position in &positioned {
+            tracing::info!(
+                group_id = %self.group_id,
+                topic = %position.topic,
+                partition = position.partition,
+                offset = position.offset,
+                "Positioned partition the group never consumed"
+            );
+        }
+        Ok(self)
+    }
+
+    /// Hands the offsets the group committed to `record` every `LAG_METRICS_INTERVAL` while
+    /// consuming, for the Checkpoint start position of a later deployment
+    pub fn with_checkpoints<F, Fut>(mut self, record: F) -> Self
+    where
+        F: Fn(Vec<PartitionOffset>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.checkpoints = Some(Arc::new(move |offsets| Box::pin(record(offsets))));
+        self
+    }
+
+    /// The topics the consumer reads, without its priority topics
+    pub fn topics(&self) -> Vec<String> {
+        self.topics.read().expect("Topics lock poisoned").clone()
+    }
+
+    /// The topics the consumer reads, followed by its priority topics
+    fn all_topics(&self) -> Vec<String> {
+        let lanes: Vec<Priority> = self.priority_lanes.iter().map(|lane| lane.priority).collect();
+        with_lanes(&self.topics(), &lanes)
+    }
+
+    /// Moves the consumer, and its priority lanes, from `from_topic` to `to_topic` while it
+    /// consumes, or has it read both while `consume_from` is set, see `migration`. Returns
+    /// whether the consumer read `from_topic`.
+    ///
+    /// In split mode the partitions of `to_topic` are handled on the consumer's own stream,
+    /// they are only split off after a restart. The in-process queue has no migrations.
+    pub fn switch_topic(&self, switch: &TopicSwitch) -> Result<bool, ProcessorError> {
+        if self.queue.is_some() {
+            return Ok(false);
+        }
+        let topics = {
+            let mut topics = self.topics.write().expect("Topics lock poisoned");
+            if !topics.contains(&switch.from_topic) {
+                return Ok(false);
+            }
+            if !topics.contains(&switch.to_topic) {
+                topics.push(switch.to_topic.clone());
+            }
+            if !switch.consume_from {
+                topics.retain(|topic| *topic != switch.from_topic);
+            }
+            topics.clone()
+        };
+
+        attach(&self.consumer, &topics, &self.pinned)?;
+        for lane in &self.priority_lanes {
+            attach(&lane.consumer, &lane_topics(lane.priority, &topics), &self.pinned)?;
+        }
+        tracing::info!(
+            group_id = %self.group_id,
+            topics = %topics.join(", "),
+            "Switched consumer topics"
+        );
+        Ok(true)
+    }
+
+    /// Stops consuming. No new messages are pulled, the ones being handled finish and their
+    /// offsets are committed before `start_consuming` returns.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// A token that shuts the consumer down when cancelled, e.g. from another component
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Shuts the consumer down once the process receives SIGTERM or Ctrl-C
+    pub fn shutdown_on_signal(&self) {
+        let token = self.shutdown.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            tracing::info!("Shutdown signal received, draining in-flight messages");
+            token.cancel();
+        });
+    }
+
+    /// Consumes messages until the consumer is shut down.
+    ///
+    /// Each message is handed to `handler` and its offset is committed once the handler succeeds.
+    /// A handler returning a transient error, or panicking, is retried with a growing backoff,
+    /// one returning any other error gives up on the message right away. Messages that can't be
+    /// read, or whose handler gave up, are dead lettered, skipped, park their partition or crash
+    /// the process depending on the topic's `PoisonPillPolicy`, unless they only lack a
+    /// capability and the consumer holds those, see `with_capability_holding`.
+    /// On shutdown the messages already received are handled to completion, including those of
+    /// every partition task in split mode, and the final offsets are committed synchronously.
     pub async fn start_consuming<F, Fut, I>(&self, mut handler: F)
     where
-        F: FnMut(I) -> Fut + Send + 'static,
-        Fut: std::future::Future<Output = ()> + Send,
+        F: FnMut(I) -> Fut + Clone + Send + 'static,
+        Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
         I: DeserializeOwned + Send + 'static + Clone,
     {
-        let mut message_stream = self.consumer.stream();
-
-        while let Some(result) = message_stream.next().await {
-            match result {
-                Ok(msg) => {
-                    if let Some(payload) = msg.payload() {
-                        let data: I = serde_json::from_slice(payload)
-                            .expect("addd actual error handling later, this is just for fixing");
-                        
-                        handler(data.clone()).await;
+        self.start_consuming_envelopes(move |envelope: MessageEnvelope<I>| {
+            handler(envelope.payload)
+        })
+        .await
+    }
+
+    /// Like `start_consuming`, but hands the handler the whole envelope, e.g. to deduplicate
+    /// redelivered messages by their id
+    pub async fn start_consuming_envelopes<F, Fut, I>(&self, handler: F)
+    where
+        F: FnMut(MessageEnvelope<I>) -> Fut + Clone + Send + 'static,
+        Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+        I: DeserializeOwned + Send + 'static + Clone,
+    {
+        self.consume(Arc::new(EnvelopeReader(PhantomData)), handler)
+            .await
+    }
+
+    /// Like `start_consuming`, for topics shared by several message types. Every message is
+    /// handed to the handler the router registered for its `message-type` header, messages of a
+    /// type without a handler are poison pills.
+    pub async fn start_routing(&self, router: MessageRouter) {
+        self.consume(Arc::new(router), |message: RoutedMessage| message.handle())
+            .await
+    }
+
+    async fn consume<R, F, Fut>(&self, reader: Arc<R>, handler: F)
+    where
+        R: MessageReader,
+        F: FnMut(R::Message) -> Fut + Clone + Send + 'static,
+        Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+    {
+        if let Some(queue) = &self.queue {
+            let subscription = queue.subscribe(&self.group_id, &self.topics(), self.from_latest);
+            let context = self.consume_context(self.concurrency.clone());
+            consume_queue(&subscription, &self.consumer, reader.as_ref(), handler, &context).await;
+            return;
+        }
+
+        self.spawn_lag_metrics();
+
+        let partition_tasks = match self.split_partition_streams {
+            true => self.spawn_partition_streams(&reader, handler.clone()),
+            false => Vec::new(),
+        };
+        let lane_tasks = self.spawn_priority_lanes(&reader, handler.clone());
+
+        // In split mode this stream still has to be polled to serve rebalances, and it receives the
+        // messages of any partition that wasn't split off (e.g. one added after startup)
+        let context = self.consume_context(self.concurrency.clone());
+        consume_stream(self.consumer.stream(), &self.consumer, reader.as_ref(), handler, &context)
+            .await;
+
+        for task in partition_tasks {
+            if let Err(e) = task.await {
+                tracing::error!(error = %e, "Partition consumer stopped abnormally");
+            }
+        }
+        for task in lane_tasks {
+            if let Err(e) = task.await {
+                tracing::error!(error = %e, "Priority consumer stopped abnormally");
+            }
+        }
+
+        match self.consumer.commit_consumer_state(CommitMode::Sync) {
+            Ok(_) => tracing::info!(topics = %self.topics().join(", "), "Committed final offsets"),
+            // Nothing was consumed since the last commit
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {}
+            Err(e) => tracing::error!(error = %e, "Failed to commit final offsets"),
+        }
+    }
+
+    /// What a consuming loop needs besides its messages and handler, handling them `concurrency`
+    /// at a time
+    fn consume_context(&self, concurrency: Option<Arc<ConcurrencyLimit>>) -> ConsumeContext {
+        ConsumeContext {
+            shutdown: self.shutdown.clone(),
+            concurrency,
+            poison_pill: Arc::clone(&self.poison_pill),
+            holding: self.holding.clone(),
+        }
+    }
+
+    /// Consumes every priority lane on its own task until the consumer is shut down, then commits
+    /// the lane's final offsets
+    fn spawn_priority_lanes<R, F, Fut>(&self, reader: &Arc<R>, handler: F) -> Vec<JoinHandle<()>>
+    where
+        R: MessageReader,
+        F: FnMut(R::Message) -> Fut + Clone + Send + 'static,
+        Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+    {
+        self.priority_lanes
+            .iter()
+            .map(|lane| {
+                let priority = lane.priority;
+                let consumer = Arc::clone(&lane.consumer);
+                let reader = Arc::clone(reader);
+                let handler = handler.clone();
+                let context = self.consume_context(Some(Arc::clone(&lane.concurrency)));
+
+                tokio::spawn(async move {
+                    consume_stream(consumer.stream(), &consumer, reader.as_ref(), handler, &context)
+                        .await;
+
+                    match consumer.commit_consumer_state(CommitMode::Sync) {
+                        Ok(_) | Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {}
+                        Err(e) => {
+                            tracing::error!(?priority, error = %e, "Failed to commit final offsets")
+                        }
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Refreshes the consumer lag gauges of the subscribed topics every `LAG_METRICS_INTERVAL`
+    /// until the consumer is shut down, recording the committed offsets along the way
+    fn spawn_lag_metrics(&self) {
+        let brokers = self.brokers.clone();
+        let group_id = self.group_id.clone();
+        let topics = Arc::clone(&self.topics);
+        let lanes: Vec<Priority> = self.priority_lanes.iter().map(|lane| lane.priority).collect();
+        let shutdown = self.shutdown.clone();
+        let checkpoints = self.checkpoints.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let mut committed = Vec::new();
+                let topics = with_lanes(&topics.read().expect("Topics lock poisoned"), &lanes);
+                for topic in &topics {
+                    let (brokers, group_id, topic) = (brokers.clone(), group_id.clone(), topic.clone());
+                    let lags = tokio::task::spawn_blocking(move || {
+                        consumer_group_lag(&brokers, &group_id, &topic)
+                    })
+                    .await;
+
+                    match lags {
+                        Ok(Ok(lags)) => {
+                            for lag in lags {
+                                metrics::KAFKA_CONSUMER_LAG
+                                    .with_label_values(&[&lag.topic, &lag.partition.to_string()])
+                                    .set(lag.lag);
+                                if let Some(offset) = lag.committed_offset {
+                                    committed.push(PartitionOffset {
+                                        topic: lag.topic,
+                                        partition: lag.partition,
+                                        offset,
+                                    });
+                                }
+                            }
+                        }
+                        Ok(Err(e)) => tracing::warn!(error = %e, "Failed to fetch consumer lag"),
+                        Err(e) => tracing::error!(error = %e, "Lag fetch stopped abnormally"),
+                    }
+                }
+                if let Some(checkpoints) = &checkpoints {
+                    if !committed.is_empty() {
+                        checkpoints(committed).await;
                     }
                 }
+
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(LAG_METRICS_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    /// Splits every partition of the subscribed topics into its own queue and consumes each of
+    /// them on a separate task
+    fn spawn_partition_streams<R, F, Fut>(&self, reader: &Arc<R>, handler: F) -> Vec<JoinHandle<()>>
+    where
+        R: MessageReader,
+        F: FnMut(R::Message) -> Fut + Clone + Send + 'static,
+        Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+    {
+        let mut tasks = Vec::new();
+
+        for topic in &self.topics() {
+            let partition_count = self
+                .consumer
+                .fetch_metadata(Some(topic), Duration::from_secs(10))
+                .ok()
+                .and_then(|md| md.topics().first().map(|t| t.partitions().len() as i32))
+                .unwrap_or(0);
+
+            // Splitting a partition that isn't assigned yet is fine, its messages are forwarded to
+            // the queue once it is
+            for partition in 0..partition_count {
+                let Some(queue) = self.consumer.split_partition_queue(topic, partition) else {
+                    tracing::error!(%topic, partition, "Failed to split partition");
+                    continue;
+                };
+
+                let consumer = Arc::clone(&self.consumer);
+                let reader = Arc::clone(reader);
+                let handler = handler.clone();
+                let context = self.consume_context(self.concurrency.clone());
+                tasks.push(tokio::spawn(async move {
+                    consume_stream(queue.stream(), &consumer, reader.as_ref(), handler, &context)
+                        .await;
+                }));
+            }
+        }
+
+        tasks
+    }
+}
+
+/// The consumer's state shared by the loops consuming its streams
+struct ConsumeContext {
+    shutdown: CancellationToken,                 // Stops the loop, see `ConsumerClient::shutdown`
+    concurrency: Option<Arc<ConcurrencyLimit>>,  // Handle messages concurrently when set
+    poison_pill: Arc<PoisonPillHandler>,         // Applied to messages that can't be handled
+    holding: Option<Arc<CapabilityHolding>>,     // Where messages lacking a capability go
+}
+
+async fn consume_stream<R, F, Fut>(
+    mut message_stream: MessageStream<'_, rdkafka::consumer::DefaultConsumerContext>,
+    consumer: &StreamConsumer,
+    reader: &R,
+    mut handler: F,
+    context: &ConsumeContext,
+) where
+    R: MessageReader,
+    F: FnMut(R::Message) -> Fut + Clone + Send,
+    Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+{
+    if let Some(limit) = &context.concurrency {
+        consume_stream_concurrently(message_stream, consumer, reader, handler, context, limit).await;
+        return;
+    }
+    let (shutdown, poison_pill) = (&context.shutdown, context.poison_pill.as_ref());
+    let holding = context.holding.as_deref();
+
+    loop {
+        // Only waiting for the next message is interrupted by a shutdown, a message that was
+        // received is always handled and committed
+        let result = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break,
+            result = message_stream.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+        };
+
+        match result {
+            Ok(msg) => {
+                // Already buffered messages of a parked partition are left for the next consumer
+                if poison_pill.is_parked(msg.topic(), msg.partition()) {
+                    continue;
+                }
+
+                let position = message_position(&msg);
+                let content_type = header(&msg, CONTENT_TYPE_HEADER);
+                let decoded = read_message(
+                    reader,
+                    &position,
+                    header(&msg, MESSAGE_TYPE_HEADER),
+                    content_type,
+                    msg.payload(),
+                );
+                let outcome = match decoded {
+                    Ok(Some(message)) => {
+                        run_handler::<R, _, _>(&mut handler, message, poison_pill).await
+                    }
+                    Ok(None) => Ok(()),
+                    Err(rejection) => Err(rejection),
+                };
+                let commit = match outcome {
+                    Ok(()) => true,
+                    Err(rejection) => {
+                        reject(
+                            consumer,
+                            &position,
+                            msg.payload(),
+                            content_type,
+                            rejection,
+                            poison_pill,
+                            holding,
+                        )
+                        .await
+                    }
+                };
+                if !commit {
+                    continue;
+                }
+
+                if let Err(e) = consumer.commit_message(&msg, CommitMode::Async) {
+                    tracing::error!(
+                        topic = msg.topic(),
+                        partition = msg.partition(),
+                        offset = msg.offset(),
+                        error = %e,
+                        "Failed to commit offset"
+                    );
+                }
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Error occurred while consuming messages");
+            }
+        }
+    }
+}
+
+/// Why a message couldn't be handled
+enum Rejection {
+    Failed(String),          // Unreadable, or its handler gave up, see `PoisonPillHandler`
+    Unsupported(Capability), // Another consumer may handle it, see `CapabilityHolding`
+}
+
+/// A message that was handled, or why it couldn't be, along with what's needed to dead letter
+/// or hold it: its payload and content type
+type Handled = (MessagePosition, Result<(), Rejection>, Option<Vec<u8>>, Option<String>);
+
+/// Like the sequential loop of `consume_stream`, but hands every message to the handler as soon
+/// as the limit allows. Offsets are committed per partition up to the oldest message still being
+/// handled, and on shutdown every handler is awaited before its offset is committed.
+async fn consume_stream_concurrently<R, F, Fut>(
+    mut message_stream: MessageStream<'_, rdkafka::consumer::DefaultConsumerContext>,
+    consumer: &StreamConsumer,
+    reader: &R,
+    handler: F,
+    context: &ConsumeContext,
+    limit: &ConcurrencyLimit,
+) where
+    R: MessageReader,
+    F: FnMut(R::Message) -> Fut + Clone + Send,
+    Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+{
+    let (shutdown, poison_pill) = (&context.shutdown, context.poison_pill.as_ref());
+    let holding = context.holding.as_deref();
+    let mut in_flight = FuturesUnordered::new();
+    let mut offsets = OffsetTracker::default();
+
+    loop {
+        let permit = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break,
+            Some(handled) = in_flight.next() => {
+                finish_message(handled, consumer, &mut offsets, poison_pill, holding).await;
+                continue;
+            }
+            permit = limit.acquire() => permit,
+        };
+
+        let result = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break,
+            Some(handled) = in_flight.next() => {
+                finish_message(handled, consumer, &mut offsets, poison_pill, holding).await;
+                continue;
+            }
+            result = message_stream.next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+        };
+
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::error!(error = %e, "Error occurred while consuming messages");
+                continue;
+            }
+        };
+        if poison_pill.is_parked(msg.topic(), msg.partition()) {
+            continue;
+        }
+
+        let position = message_position(&msg);
+        offsets.start(&position);
+
+        let content_type = header(&msg, CONTENT_TYPE_HEADER);
+        let decoded = read_message(
+            reader,
+            &position,
+            header(&msg, MESSAGE_TYPE_HEADER),
+            content_type,
+            msg.payload(),
+        );
+        let payload = msg.payload().map(<[u8]>::to_vec);
+        let content_type = content_type.map(str::to_string);
+        let mut handler = handler.clone();
+        in_flight.push(async move {
+            let outcome = match decoded {
+                Ok(Some(message)) => {
+                    run_handler::<R, _, _>(&mut handler, message, poison_pill).await
+                }
+                Ok(None) => Ok(()),
+                Err(rejection) => Err(rejection),
+            };
+            drop(permit);
+            (position, outcome, payload, content_type)
+        });
+    }
+
+    while let Some(handled) = in_flight.next().await {
+        finish_message(handled, consumer, &mut offsets, poison_pill, holding).await;
+    }
+}
+
+/// Like `consume_stream_concurrently`, for the in-process queue. A message is gone once it was
+/// received, so there are no offsets to commit, but the messages that can't be handled still go
+/// through the poison pill policy.
+async fn consume_queue<R, F, Fut>(
+    subscription: &Subscription,
+    consumer: &StreamConsumer, // Never subscribed, only handed to the poison pill policy
+    reader: &R,
+    handler: F,
+    context: &ConsumeContext,
+) where
+    R: MessageReader,
+    F: FnMut(R::Message) -> Fut + Clone + Send,
+    Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+{
+    let (shutdown, poison_pill) = (&context.shutdown, context.poison_pill.as_ref());
+    let holding = context.holding.as_deref();
+    let limit = context
+        .concurrency
+        .clone()
+        .unwrap_or_else(|| Arc::new(ConcurrencyLimit::new(1)));
+    let mut in_flight = FuturesUnordered::new();
+
+    loop {
+        let permit = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break,
+            Some(handled) = in_flight.next() => {
+                finish_queued(handled, consumer, poison_pill, holding).await;
+                continue;
+            }
+            permit = limit.acquire() => permit,
+        };
+
+        let message = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break,
+            Some(handled) = in_flight.next() => {
+                finish_queued(handled, consumer, poison_pill, holding).await;
+                continue;
+            }
+            message = subscription.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+
+        // Every topic of the queue has a single partition
+        let position = MessagePosition {
+            topic: message.topic.clone(),
+            partition: 0,
+            offset: message.offset,
+        };
+        if poison_pill.is_parked(&position.topic, position.partition) {
+            continue;
+        }
+
+        let decoded = read_message(
+            reader,
+            &position,
+            message.header(MESSAGE_TYPE_HEADER),
+            message.header(CONTENT_TYPE_HEADER),
+            Some(message.payload.as_slice()),
+        );
+        let content_type = message.header(CONTENT_TYPE_HEADER).map(str::to_string);
+        let mut handler = handler.clone();
+        in_flight.push(async move {
+            let outcome = match decoded {
+                Ok(Some(decoded)) => {
+                    run_handler::<R, _, _>(&mut handler, decoded, poison_pill).await
+                }
+                Ok(None) => Ok(()),
+                Err(rejection) => Err(rejection),
+            };
+            drop(permit);
+            (position, outcome, Some(message.payload), content_type)
+        });
+    }
+
+    while let Some(handled) = in_flight.next().await {
+        finish_queued(handled, consumer, poison_pill, holding).await;
+    }
+}
+
+/// Rejects a message of the in-process queue that failed, it was already taken off the queue
+async fn finish_queued(
+    (position, outcome, payload, content_type): Handled,
+    consumer: &StreamConsumer,
+    poison_pill: &PoisonPillHandler,
+    holding: Option<&CapabilityHolding>,
+) {
+    if let Err(rejection) = outcome {
+        reject(
+            consumer,
+            &position,
+            payload.as_deref(),
+            content_type.as_deref(),
+            rejection,
+            poison_pill,
+            holding,
+        )
+        .await;
+    }
+}
+
+/// Rejects a message that failed and commits its offset unless the poison pill policy holds it
+/// back
+async fn finish_message(
+    (position, outcome, payload, content_type): Handled,
+    consumer: &StreamConsumer,
+    offsets: &mut OffsetTracker,
+    poison_pill: &PoisonPillHandler,
+    holding: Option<&CapabilityHolding>,
+) {
+    let commit = match outcome {
+        Ok(()) => true,
+        Err(rejection) => {
+            reject(
+                consumer,
+                &position,
+                payload.as_deref(),
+                content_type.as_deref(),
+                rejection,
+                poison_pill,
+                holding,
+            )
+            .await
+        }
+    };
+
+    // A held back message stays in flight, so the partition's offset never passes it
+    if commit {
+        offsets.finish(&position, consumer);
+    }
+}
+
+/// Moves a message that only lacks a capability to the holding topic, and applies the poison
+/// pill policy to every other one, or if it can't be held. Returns whether the offset of the
+/// message may be committed.
+async fn reject(
+    consumer: &StreamConsumer,
+    position: &MessagePosition,
+    payload: Option<&[u8]>,
+    content_type: Option<&str>,
+    rejection: Rejection,
+    poison_pill: &PoisonPillHandler,
+    holding: Option<&CapabilityHolding>,
+) -> bool {
+    let reason = match (rejection, holding) {
+        (Rejection::Failed(reason), _) => reason,
+        (Rejection::Unsupported(capability), Some(holding)) => {
+            match holding.hold(position, payload, content_type, &capability).await {
+                Ok(()) => return true,
                 Err(e) => {
-                    println!("Error occurred while consuming messages: {}", e);
+                    tracing::error!(%capability, error = %e, "Failed to hold task");
+                    format!("Needs {}, failed to hold it: {}", capability, e)
                 }
             }
         }
+        (Rejection::Unsupported(capability), None) => format!("Needs {}", capability),
+    };
+
+    poison_pill
+        .apply(consumer, position, payload, content_type, &reason)
+        .await
+}
+
+/// Hands the message to the handler on its own task, so a panic fails the message instead of
+/// the consumer. Panics and transient errors are retried up to `max_handler_attempts` times,
+/// waiting `retry_backoff` before every retry, other errors give up on the message at once.
+///
+/// The handler runs in a span of the message and on behalf of the request it was sent for, so
+/// the messages it sends carry the same correlation id. Messages without one get a new id.
+async fn run_handler<R, F, Fut>(
+    handler: &mut F,
+    message: R::Message,
+    poison_pill: &PoisonPillHandler,
+) -> Result<(), Rejection>
+where
+    R: MessageReader,
+    F: FnMut(R::Message) -> Fut,
+    Fut: std::future::Future<Output = Result<(), ProcessorError>> + Send + 'static,
+{
+    let message_id = R::message_id(&message);
+    let correlation_id = R::correlation_id(&message).unwrap_or_else(uuid::Uuid::new_v4);
+    let span = tracing::info_span!("message", %message_id, %correlation_id);
+
+    let attempts = poison_pill.max_handler_attempts();
+    let mut last_error = String::new();
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            tokio::time::sleep(poison_pill.retry_backoff(attempt)).await;
+        }
+
+        let handled = correlation::scope(correlation_id, handler(message.clone()));
+        let error = match tokio::spawn(handled.instrument(span.clone())).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(ProcessorError::Unsupported(capability))) => {
+                return Err(Rejection::Unsupported(capability));
+            }
+            Ok(Err(e)) if !e.is_transient() => {
+                tracing::error!(parent: &span, attempt, error = %e, "Handler rejected message");
+                return Err(Rejection::Failed(format!("Handler rejected message: {}", e)));
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => e.to_string(), // The handler panicked
+        };
+
+        tracing::error!(
+            parent: &span,
+            attempt,
+            attempts,
+            error = %error,
+            "Handler of message failed"
+        );
+        last_error = error;
+    }
+
+    Err(Rejection::Failed(format!(
+        "Handler failed {} times: {}",
+        attempts, last_error
+    )))
+}
+
+fn message_position(msg: &BorrowedMessage<'_>) -> MessagePosition {
+    MessagePosition {
+        topic: msg.topic().to_string(),
+        partition: msg.partition(),
+        offset: msg.offset(),
+    }
+}
+
+/// Reads the payload of a message in the encoding of its content type. Empty messages are
+/// skipped, ones that can't be read return why, so they can be rejected. A variant this build
+/// doesn't know, e.g. an operation added by a newer producer, counts as a missing capability.
+fn read_message<R: MessageReader>(
+    reader: &R,
+    position: &MessagePosition,
+    message_type: Option<&str>,
+    content_type: Option<&str>,
+    payload: Option<&[u8]>,
+) -> Result<Option<R::Message>, Rejection> {
+    let Some(payload) = payload else {
+        return Ok(None);
+    };
+
+    let message = encoding_of(content_type)
+        .and_then(|encoding| reader.read(message_type, encoding, payload))
+        .map_err(|e| {
+            tracing::error!(
+                topic = %position.topic,
+                partition = position.partition,
+                offset = position.offset,
+                error = %e,
+                "Malformed message"
+            );
+            match Capability::from_unknown_variant(&e) {
+                Some(capability) => Rejection::Unsupported(capability),
+                None => Rejection::Failed(format!("Malformed message: {}", e)),
+            }
+        })?;
+
+    metrics::TASKS_CONSUMED
+        .with_label_values(&[&position.topic])
+        .inc();
+    Ok(Some(message))
+}
+
+/// Just enough of a message to tell an envelope from a bare payload
+#[derive(serde::Deserialize)]
+struct EnvelopeHeader {
+    schema_version: Option<u32>,
+}
+
+/// Reads an envelope of any schema version, or a bare payload written before envelopes existed.
+///
+/// Fields the payload type doesn't know are ignored, so messages of a newer schema version are
+/// read as long as they only added fields.
+pub fn decode_message<I: DeserializeOwned>(payload: &[u8]) -> Result<MessageEnvelope<I>, String> {
+    let header: EnvelopeHeader = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+
+    match header.schema_version {
+        None => serde_json::from_slice::<I>(payload)
+            .map(MessageEnvelope::legacy)
+            .map_err(|e| e.to_string()),
+        Some(version) => serde_json::from_slice::<MessageEnvelope<I>>(payload).map_err(|e| {
+            match version > SCHEMA_VERSION {
+                true => format!(
+                    "{} (written with schema version {}, this build reads up to {})",
+                    e, version, SCHEMA_VERSION
+                ),
+                false => e.to_string(),
+            }
+        }),
     }
 }
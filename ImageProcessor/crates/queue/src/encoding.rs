@@ -0,0 +1,78 @@
+use common::{config::MessageEncoding, envelope::MessageEnvelope, error::ProcessorError};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::consumer::decode_message;
+
+// ============================================================================
+// MESSAGE ENCODING
+// Messages are JSON unless `kafka.image_task_encoding` switches the image
+// tasks, the one topic carrying millions of messages per batch, to
+// MessagePack. The producer names the encoding in the `content-type` header
+// and consumers read each message by it, so both encodings can be in flight
+// on a topic while it is switched. Messages without the header are JSON.
+//
+// MessagePack is written with named fields rather than as bincode or postcard
+// would: payload types skip absent fields and use tagged and flattened enums,
+// which only a self-describing format reads back, and fields added with a
+// `#[serde(default)]` stay readable like they do in JSON, see `envelope`.
+// ============================================================================
+
+pub const CONTENT_TYPE_HEADER: &str = "content-type";
+
+/// The value of the `content-type` header of messages in the encoding
+pub fn content_type(encoding: MessageEncoding) -> &'static str {
+    match encoding {
+        MessageEncoding::Json => "application/json",
+        MessageEncoding::MessagePack => "application/msgpack",
+    }
+}
+
+/// The encoding named by the `content-type` header of a message, JSON without one
+pub fn encoding_of(content_type: Option<&str>) -> Result<MessageEncoding, String> {
+    match content_type {
+        None | Some("application/json") => Ok(MessageEncoding::Json),
+        Some("application/msgpack") => Ok(MessageEncoding::MessagePack),
+        Some(other) => Err(format!("Unknown content type {}", other)),
+    }
+}
+
+/// Serializes a message in the encoding
+pub fn encode<T: Serialize>(
+    encoding: MessageEncoding,
+    message: &T,
+) -> Result<Vec<u8>, ProcessorError> {
+    match encoding {
+        MessageEncoding::Json => serde_json::to_vec(message).map_err(ProcessorError::serialization),
+        MessageEncoding::MessagePack => {
+            rmp_serde::to_vec_named(message).map_err(ProcessorError::serialization)
+        }
+    }
+}
+
+/// Reads an envelope written in the encoding, see `decode_message` for JSON
+pub(crate) fn decode_envelope<I: DeserializeOwned>(
+    encoding: MessageEncoding,
+    payload: &[u8],
+) -> Result<MessageEnvelope<I>, String> {
+    match encoding {
+        MessageEncoding::Json => decode_message(payload),
+        // Envelopes predate MessagePack, so there are no bare payloads to fall back to
+        MessageEncoding::MessagePack => rmp_serde::from_slice(payload).map_err(|e| e.to_string()),
+    }
+}
+
+/// A message as text, e.g. to be stored with a held task. Binary encodings are hex encoded.
+pub fn to_text(encoding: MessageEncoding, payload: &[u8]) -> Result<String, String> {
+    match encoding {
+        MessageEncoding::Json => String::from_utf8(payload.to_vec()).map_err(|e| e.to_string()),
+        MessageEncoding::MessagePack => Ok(hex::encode(payload)),
+    }
+}
+
+/// The message `to_text` was given
+pub fn from_text(encoding: MessageEncoding, text: &str) -> Result<Vec<u8>, String> {
+    match encoding {
+        MessageEncoding::Json => Ok(text.as_bytes().to_vec()),
+        MessageEncoding::MessagePack => hex::decode(text).map_err(|e| e.to_string()),
+    }
+}
@@ -0,0 +1,43 @@
+use common::{
+    config::Config,
+    events::{BATCH_EVENT_MESSAGE_TYPE, BatchEvent, BatchEventKind},
+};
+
+use crate::ProducerClient;
+
+/// Publishes the progress events of batches on the events topic, see `common::events`
+#[derive(Clone)]
+pub struct BatchEventPublisher {
+    producer: ProducerClient,
+}
+
+impl BatchEventPublisher {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            producer: ProducerClient::from_config(config, &config.kafka.events_topic),
+        }
+    }
+
+    /// Publishes an event of a batch. Nothing waits on events, so a failed publish is only
+    /// logged.
+    pub async fn publish(
+        &self,
+        batch_id: uuid::Uuid,
+        tenant_id: Option<&str>,
+        kind: BatchEventKind,
+    ) {
+        let event = BatchEvent::new(batch_id, tenant_id.map(str::to_string), kind);
+        if let Err(e) = self
+            .producer
+            .send_message(BATCH_EVENT_MESSAGE_TYPE, &event)
+            .await
+        {
+            tracing::warn!(
+                %batch_id,
+                event = event.name(),
+                error = %e,
+                "Failed to publish batch event"
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use common::{config::FailoverConfig, metrics};
+
+// ============================================================================
+// CLUSTER FAILOVER
+// A producer with a secondary cluster (usually the target of a mirror of the
+// primary) switches to it once `failure_threshold` sends in a row failed after
+// all of their retries. It stays there until the process restarts, failing
+// back is left to the operator once the primary is healthy again. Consumers
+// don't fail over on their own, they are pointed at the mirror through
+// `kafka.failover.consume_secondary`.
+// ============================================================================
+
+/// A Kafka cluster a producer can send to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum Cluster {
+    Primary,
+    Secondary,
+}
+
+/// Delivery health of a producer, shared between its clones
+#[derive(Debug)]
+pub(crate) struct FailoverState {
+    has_secondary: bool,
+    failure_threshold: u32,
+    failed_over: AtomicBool,
+    consecutive_failures: AtomicU32, // Sends that failed after all retries since the last success
+    failovers: AtomicU64,
+}
+
+/// Snapshot of a producer's delivery health
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ClusterHealth {
+    pub active_cluster: Cluster,
+    pub secondary_configured: bool,
+    pub consecutive_failures: u32,
+    pub failovers: u64,
+}
+
+impl FailoverState {
+    pub(crate) fn new(config: &FailoverConfig) -> Self {
+        Self {
+            has_secondary: config.secondary_brokers.is_some(),
+            failure_threshold: config.failure_threshold.max(1),
+            failed_over: AtomicBool::new(false),
+            consecutive_failures: AtomicU32::new(0),
+            failovers: AtomicU64::new(0),
+        }
+    }
+
+    /// A producer without a secondary cluster, which never fails over
+    pub(crate) fn disabled() -> Self {
+        Self::new(&FailoverConfig::default())
+    }
+
+    pub(crate) fn active_cluster(&self) -> Cluster {
+        match self.failed_over.load(Ordering::Relaxed) {
+            true => Cluster::Secondary,
+            false => Cluster::Primary,
+        }
+    }
+
+    pub(crate) fn record_success(&self, topic: &str) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        metrics::KAFKA_CONSECUTIVE_SEND_FAILURES
+            .with_label_values(&[topic])
+            .set(0);
+    }
+
+    /// Counts a send that failed after all of its retries.
+    ///
+    /// Returns true if this failure made the producer fail over, the caller then retries the
+    /// send on the secondary cluster.
+    pub(crate) fn record_failure(&self, topic: &str) -> bool {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::KAFKA_CONSECUTIVE_SEND_FAILURES
+            .with_label_values(&[topic])
+            .set(failures as i64);
+
+        if !self.has_secondary || failures < self.failure_threshold {
+            return false;
+        }
+        // Only the first of several concurrent failures switches the cluster
+        if self.failed_over.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        self.failovers.fetch_add(1, Ordering::Relaxed);
+        metrics::KAFKA_PRODUCER_FAILED_OVER
+            .with_label_values(&[topic])
+            .set(1);
+        metrics::KAFKA_FAILOVERS.with_label_values(&[topic]).inc();
+        tracing::warn!(%topic, failures, "Producer failed over to the secondary cluster");
+        true
+    }
+
+    pub(crate) fn health(&self) -> ClusterHealth {
+        ClusterHealth {
+            active_cluster: self.active_cluster(),
+            secondary_configured: self.has_secondary,
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+            failovers: self.failovers.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use common::{
+    capabilities::{Capability, HeldTask},
+    metrics,
+};
+
+use crate::{
+    concurrency::MessagePosition,
+    encoding::{encoding_of, to_text},
+    ProducerClient,
+};
+
+/// Moves the messages a consumer can't handle for lack of a capability to the holding topic,
+/// instead of applying the poison pill policy to them, see `common::capabilities`
+pub struct CapabilityHolding {
+    producer: ProducerClient, // On the holding topic
+    worker_id: String,        // Recorded on the held tasks
+}
+
+impl CapabilityHolding {
+    pub fn new(producer: ProducerClient, worker_id: &str) -> Self {
+        Self {
+            producer,
+            worker_id: worker_id.to_string(),
+        }
+    }
+
+    /// Publishes the message to the holding topic with the capability it needs, and the encoding
+    /// of its content type to requeue it in
+    pub(crate) async fn hold(
+        &self,
+        position: &MessagePosition,
+        payload: Option<&[u8]>,
+        content_type: Option<&str>,
+        capability: &Capability,
+    ) -> Result<(), String> {
+        let payload = payload.ok_or("Nothing to hold in an empty message".to_string())?;
+        let encoding = encoding_of(content_type)?;
+        let message = to_text(encoding, payload)?;
+        let held = HeldTask::new(
+            &position.topic,
+            capability.clone(),
+            &self.worker_id,
+            encoding,
+            message,
+        );
+
+        self.producer
+            .send_held_task(&held)
+            .await
+            .map_err(|e| e.to_string())?;
+        metrics::TASKS_HELD
+            .with_label_values(&[&position.topic, &capability.to_string()])
+            .inc();
+        tracing::warn!(
+            topic = %position.topic,
+            partition = position.partition,
+            offset = position.offset,
+            %capability,
+            hold_id = %held.hold_id,
+            "Moved task to {}, this worker can't run it",
+            self.producer.topic()
+        );
+        Ok(())
+    }
+}
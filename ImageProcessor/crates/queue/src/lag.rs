@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+pub use common::lag::PartitionLag;
+use rdkafka::{
+    Offset, TopicPartitionList,
+    config::ClientConfig,
+    consumer::{BaseConsumer, Consumer},
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fetches committed vs. high-watermark offsets for every partition of a topic.
+///
+/// This is a blocking call, so async callers should run it through `spawn_blocking`.
+pub fn consumer_group_lag(
+    brokers: &str,
+    group_id: &str,
+    topic: &str,
+) -> Result<Vec<PartitionLag>, String> {
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", group_id)
+        .set("enable.auto.commit", "false")
+        .create()
+        .map_err(|e| format!("Failed to create lag consumer: {}", e))?;
+
+    let metadata = consumer
+        .fetch_metadata(Some(topic), FETCH_TIMEOUT)
+        .map_err(|e| format!("Failed to fetch metadata for {}: {}", topic, e))?;
+
+    let mut tpl = TopicPartitionList::new();
+    for md_topic in metadata.topics() {
+        for partition in md_topic.partitions() {
+            tpl.add_partition(md_topic.name(), partition.id());
+        }
+    }
+
+    let committed = consumer
+        .committed_offsets(tpl, FETCH_TIMEOUT)
+        .map_err(|e| format!("Failed to fetch committed offsets for {}: {}", topic, e))?;
+
+    let mut lags = Vec::new();
+    for elem in committed.elements() {
+        let (low, high) = consumer
+            .fetch_watermarks(elem.topic(), elem.partition(), FETCH_TIMEOUT)
+            .map_err(|e| format!("Failed to fetch watermarks for {}: {}", topic, e))?;
+
+        let committed_offset = match elem.offset() {
+            Offset::Offset(offset) => Some(offset),
+            _ => None,
+        };
+
+        lags.push(PartitionLag {
+            topic: elem.topic().to_string(),
+            partition: elem.partition(),
+            committed_offset,
+            high_watermark: high,
+            lag: (high - committed_offset.unwrap_or(low)).max(0),
+        });
+    }
+
+    Ok(lags)
+}
+
+/// Sums the lag over every partition
+pub fn total_lag(lags: &[PartitionLag]) -> i64 {
+    lags.iter().map(|lag| lag.lag).sum()
+}
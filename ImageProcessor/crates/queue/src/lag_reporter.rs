@@ -0,0 +1,114 @@
+use std::{sync::Arc, time::Duration};
+
+use common::{config::LagConfig, lag::PartitionLag, metrics};
+use futures::future::BoxFuture;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::lag::consumer_group_lag;
+
+/// Records the lag of a consumer group, e.g. in the database, see `common::lag`
+type LagSink = Arc<dyn Fn(String, Vec<PartitionLag>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Fetches the lag of consumer groups it isn't part of every interval, sets the
+/// `kafka_consumer_group_lag` metric and hands the lag to its sink
+pub struct LagReporter {
+    brokers: String,
+    groups: Vec<(String, Vec<String>)>, // Consumer groups and the topics they read
+    interval: Duration,
+    sink: Option<LagSink>, // Handed the lag of every group, see `with_sink`
+    shutdown: CancellationToken,
+}
+
+impl LagReporter {
+    pub fn new(brokers: &str, interval: Duration) -> Self {
+        Self {
+            brokers: brokers.to_string(),
+            groups: Vec::new(),
+            interval,
+            sink: None,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Reports on the groups of `config`, reading from `brokers`
+    pub fn from_config(brokers: &str, config: &LagConfig) -> Self {
+        let reporter = Self::new(brokers, Duration::from_secs(config.interval_secs.max(1)));
+        config
+            .groups
+            .iter()
+            .fold(reporter, |reporter, (group_id, topics)| {
+                reporter.with_group(group_id, topics.clone())
+            })
+    }
+
+    pub fn with_group(mut self, group_id: &str, topics: Vec<String>) -> Self {
+        self.groups.push((group_id.to_string(), topics));
+        self
+    }
+
+    /// Hands the lag of every group to `record` after each report, a group whose lag couldn't be
+    /// fetched on any topic is left out
+    pub fn with_sink<F, Fut>(mut self, record: F) -> Self
+    where
+        F: Fn(String, Vec<PartitionLag>) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.sink = Some(Arc::new(move |group_id, lags| {
+            Box::pin(record(group_id, lags))
+        }));
+        self
+    }
+
+    /// A token that stops the reporter when cancelled
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Fetches the lag of every group once
+    pub async fn report(&self) {
+        for (group_id, topics) in &self.groups {
+            let mut group_lags = Vec::new();
+            for topic in topics {
+                let (brokers, group, topic) =
+                    (self.brokers.clone(), group_id.clone(), topic.clone());
+                let lags = tokio::task::spawn_blocking(move || {
+                    consumer_group_lag(&brokers, &group, &topic)
+                })
+                .await;
+
+                match lags {
+                    Ok(Ok(lags)) => group_lags.extend(lags),
+                    Ok(Err(e)) => {
+                        tracing::warn!(%group_id, error = %e, "Failed to fetch consumer group lag")
+                    }
+                    Err(e) => tracing::error!(error = %e, "Lag fetch stopped abnormally"),
+                }
+            }
+
+            for lag in &group_lags {
+                metrics::KAFKA_CONSUMER_GROUP_LAG
+                    .with_label_values(&[group_id, &lag.topic, &lag.partition.to_string()])
+                    .set(lag.lag);
+            }
+            if let Some(sink) = &self.sink {
+                if !group_lags.is_empty() {
+                    sink(group_id.clone(), group_lags).await;
+                }
+            }
+        }
+    }
+
+    /// Reports every interval in the background until the shutdown token is cancelled
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                tokio::select! {
+                    _ = self.shutdown.cancelled() => break,
+                    _ = interval.tick() => self.report().await,
+                }
+            }
+        })
+    }
+}
@@ -1,98 +1,469 @@
 use common::{
-    DatasetProcessingJob, DatasetProcessingTask, ImageTask, IntoDatasetTasks, SendDataResult,
+    capabilities::HeldTask, config::{Config, FailoverConfig, MessageEncoding, QueueKind}, envelope::MessageEnvelope, error::ProcessorError, faults, metrics, DatasetProcessingTask, ImageTask, Priority, SendDataResult,
 };
+use encoding::{content_type, encode, CONTENT_TYPE_HEADER};
+use failover::{Cluster, ClusterHealth, FailoverState};
+use memory::{MemoryQueue, MessageQueue};
+use partitioner::{DefaultPartitioner, Partitioner};
 use rdkafka::{
     config::ClientConfig,
-    producer::{FutureProducer, FutureRecord},
+    error::KafkaError,
+    message::{Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord, Producer},
     util::Timeout,
 };
-use serde_json;
+use retry::{is_retryable, RetryPolicy};
+use routing::MESSAGE_TYPE_HEADER;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 pub mod admin;
+pub mod concurrency;
 pub mod consumer;
+pub mod encoding;
+pub mod events;
+pub mod failover;
+pub mod holding;
+pub mod lag;
+pub mod lag_reporter;
+pub mod memory;
+pub mod migration;
+pub mod partitioner;
+pub mod poison;
+pub mod retry;
+pub mod routing;
+pub mod shutdown;
+mod start;
 
 #[derive(Clone)]
 pub struct ProducerClient {
     producer: FutureProducer,
-    topic: String,
+    secondary: Option<FutureProducer>, // Sent to after failing over, see `failover`
+    failover: Arc<FailoverState>,
+    topic: Arc<RwLock<String>>, // Shared between clones so a topic migration switches all of them
+    partitioner: Arc<dyn Partitioner>,
+    partition_count: Arc<AtomicI32>,
+    retry: RetryPolicy,
+    priority_topics: bool, // Send High and Low work to their own topics, see `Priority::topic`
+    operation_topics: Arc<HashMap<String, String>>, // Topics of image tasks by operation name
+    image_task_encoding: MessageEncoding, // Of the image tasks sent, see `encoding`
+    queue: Option<Arc<dyn MessageQueue>>, // Sent to instead of Kafka, see `in_memory`
+}
+
+fn create_producer(brokers: &str) -> FutureProducer {
+    ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .create()
+        .expect("Failed to create new ClientConfig")
 }
 
 impl ProducerClient {
     pub fn new(brokers: &str, topic: &str) -> Self {
-        let config = ClientConfig::new()
-            .set("bootstrap.servers", brokers)
-            .create()
-            .expect("Failed to create new ClientConfig");
+        Self {
+            producer: create_producer(brokers),
+            secondary: None,
+            failover: Arc::new(FailoverState::disabled()),
+            topic: Arc::new(RwLock::new(topic.to_string())),
+            partitioner: Arc::new(DefaultPartitioner),
+            partition_count: Arc::new(AtomicI32::new(0)),
+            retry: RetryPolicy::default(),
+            priority_topics: false,
+            operation_topics: Arc::new(HashMap::new()),
+            image_task_encoding: MessageEncoding::Json,
+            queue: None,
+        }
+    }
 
+    /// Creates a producer that publishes to the in-process queue instead of Kafka, see `memory`
+    pub fn in_memory(queue: Arc<dyn MessageQueue>, topic: &str) -> Self {
+        // The Kafka producer is never used, and without brokers it never connects
         Self {
-            producer: config,
-            topic: topic.to_string(),
+            queue: Some(queue),
+            ..Self::new("", topic)
+        }
+    }
+
+    /// Creates a producer for `topic` using the queue backend, brokers, retry, priority,
+    /// operation routing, image task encoding and failover settings of the config
+    pub fn from_config(config: &Config, topic: &str) -> Self {
+        let producer = match config.kafka.backend {
+            QueueKind::Kafka => {
+                Self::new(&config.kafka.brokers, topic).with_failover(&config.kafka.failover)
+            }
+            QueueKind::Memory => Self::in_memory(MemoryQueue::shared(), topic),
+        };
+        producer
+            .with_retry_policy(RetryPolicy::from(&config.retry))
+            .with_priority_topics(config.kafka.priority.dedicated_topics)
+            .with_operation_topics(config.kafka.operation_topics.clone())
+            .with_image_task_encoding(config.kafka.image_task_encoding)
+    }
+
+    /// Fails over to the secondary cluster of the config after sustained delivery failures.
+    ///
+    /// The secondary cluster needs the same topics as the primary, with the same partitions.
+    pub fn with_failover(mut self, config: &FailoverConfig) -> Self {
+        self.secondary = config.secondary_brokers.as_deref().map(create_producer);
+        self.failover = Arc::new(FailoverState::new(config));
+        self
+    }
+
+    /// Which cluster the producer sends to and how its recent sends went
+    pub fn cluster_health(&self) -> ClusterHealth {
+        self.failover.health()
+    }
+
+    fn active_producer(&self) -> &FutureProducer {
+        match (self.failover.active_cluster(), &self.secondary) {
+            (Cluster::Secondary, Some(secondary)) => secondary,
+            _ => &self.producer,
         }
     }
 
-    pub async fn send_image_task(&self, initial_task: ImageTask) -> Result<ImageTask, String> {
-        // Generate a new task ID if not provided, otherwise just return the task that we do have
-        // already
-        if let Some(_) = initial_task.task_id { return Ok(initial_task); }
+    /// Sends High and Low priority work to "<topic>-high" and "<topic>-low" instead of `topic`.
+    ///
+    /// Image tasks are partitioned by the partition count of `topic`, so the priority topics need
+    /// at least as many partitions.
+    pub fn with_priority_topics(mut self, enabled: bool) -> Self {
+        self.priority_topics = enabled;
+        self
+    }
+
+    /// Sends image tasks whose operation is listed, by name, to its topic instead of `topic`.
+    /// Fused tasks are routed by their first operation, the one in `ImageTask::operation`.
+    ///
+    /// Like the priority topics, the routed topics need at least as many partitions as `topic`,
+    /// and aren't switched by a topic migration.
+    pub fn with_operation_topics(mut self, topics: HashMap<String, String>) -> Self {
+        self.operation_topics = Arc::new(topics);
+        self
+    }
+
+    /// Sends image tasks in the encoding instead of JSON. Consumers read either, so the image
+    /// workers need to run a build that knows the encoding before it is switched.
+    pub fn with_image_task_encoding(mut self, encoding: MessageEncoding) -> Self {
+        self.image_task_encoding = encoding;
+        self
+    }
+
+    /// Replaces the retry policy used when a send fails
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Replaces the partitioning strategy used for image tasks.
+    ///
+    /// The partition count of the topic is fetched once here, so the topic must already exist.
+    pub fn with_partitioner(mut self, partitioner: impl Partitioner + 'static) -> Self {
+        self.refresh_partition_count();
+        self.partitioner = Arc::new(partitioner);
+        self
+    }
+
+    /// The topic messages are currently written to
+    pub fn topic(&self) -> String {
+        self.topic.read().expect("Producer topic lock poisoned").clone()
+    }
+
+    /// Points this producer (and every clone of it) at a different topic
+    pub fn switch_topic(&self, topic: &str) {
+        *self.topic.write().expect("Producer topic lock poisoned") = topic.to_string();
+        self.refresh_partition_count();
+    }
+
+    fn refresh_partition_count(&self) {
+        // The in-process queue has a single partition per topic
+        if self.queue.is_some() {
+            return;
+        }
+        let count = self
+            .active_producer()
+            .client()
+            .fetch_metadata(Some(&self.topic()), Duration::from_secs(10))
+            .ok()
+            .and_then(|md| md.topics().first().map(|t| t.partitions().len() as i32))
+            .unwrap_or(0);
+        self.partition_count.store(count, Ordering::Relaxed);
+    }
+
+    /// Sends a payload with the headers, retrying retryable failures with exponential backoff.
+    /// `routed_topic` replaces the producer's topic, see `with_operation_topics`.
+    ///
+    /// Returns the number of attempts made alongside the result of the last one.
+    async fn send_with_retry(
+        &self,
+        payload: &[u8],
+        routed_topic: Option<&str>,
+        partition: Option<i32>,
+        priority: Priority,
+        headers: &[(&str, &str)],
+    ) -> (Result<(), KafkaError>, u32) {
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            faults::kafka_send().await;
+
+            // The topic is read on every attempt so a retry after a migration goes to the new topic
+            let topic = routed_topic.map_or_else(|| self.topic(), str::to_string);
+            let topic = match self.priority_topics {
+                true => priority.topic(&topic),
+                false => topic,
+            };
+            // The in-process queue takes every message, there is nothing to retry
+            if let Some(queue) = &self.queue {
+                queue.publish(&topic, payload, headers);
+                metrics::TASKS_PRODUCED.with_label_values(&[&topic]).inc();
+                return (Ok(()), attempt);
+            }
+            let mut rec: FutureRecord<String, [u8]> = FutureRecord::to(&topic).payload(payload);
+            if let Some(partition) = partition {
+                rec = rec.partition(partition);
+            }
+            if !headers.is_empty() {
+                let owned = headers.iter().fold(OwnedHeaders::new(), |owned, &(key, value)| {
+                    owned.insert(Header {
+                        key,
+                        value: Some(value),
+                    })
+                });
+                rec = rec.headers(owned);
+            }
+
+            let error = match self.active_producer().send(rec, Timeout::Never).await {
+                Ok(_) => {
+                    metrics::TASKS_PRODUCED.with_label_values(&[&topic]).inc();
+                    self.failover.record_success(&self.topic());
+                    return (Ok(()), attempt);
+                }
+                Err((error, _)) => error,
+            };
+
+            if attempt >= self.retry.max_attempts.max(1) || !is_retryable(&error) {
+                // Only failures of the cluster count towards failing over, not e.g. an oversized
+                // message. The send that made the producer fail over gets its attempts on the
+                // secondary cluster.
+                if is_retryable(&error) && self.failover.record_failure(&self.topic()) {
+                    self.refresh_partition_count();
+                    attempt = 0;
+                    continue;
+                }
+                return (Err(error), attempt);
+            }
+
+            let backoff = self.retry.backoff(attempt);
+            tracing::warn!(
+                %topic,
+                attempt,
+                %error,
+                "Send failed, retrying in {:?}",
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    pub async fn send_image_task(
+        &self,
+        initial_task: ImageTask,
+    ) -> Result<ImageTask, ProcessorError> {
+        // Generate a new task ID if not provided, otherwise keep the one we already have
         let task = ImageTask {
-            task_id: Some(uuid::Uuid::new_v4()),
+            task_id: Some(initial_task.task_id.unwrap_or_else(uuid::Uuid::new_v4)),
             ..initial_task
         };
 
-        // Serialize the task to JSON
-        let json_payload = serde_json::to_string(&task).unwrap();
-        let rec: FutureRecord<String, String> =
-            FutureRecord::to(&self.topic).payload(&json_payload);
+        // Serialize the task in the configured encoding, named in its header
+        let payload = encode(self.image_task_encoding, &MessageEnvelope::new(&task))?;
+        let headers = [(CONTENT_TYPE_HEADER, content_type(self.image_task_encoding))];
+
+        // Route the task through the partitioner, so e.g. every stage of an image can be pinned
+        // to the same worker
+        let affinity_key = task.affinity_key.as_deref();
+        let partition_count = self.partition_count.load(Ordering::Relaxed);
+        let partition = self.partitioner.partition(affinity_key, partition_count);
+        let routed_topic = self
+            .operation_topics
+            .get(task.operation.name())
+            .map(String::as_str);
 
         // Send the task to the Kafka topic
-        let result = self.producer.send(rec, Timeout::Never).await;
+        let (result, attempts) = self
+            .send_with_retry(&payload, routed_topic, partition, task.priority, &headers)
+            .await;
 
         // Handle the result of sending the task
-        return {
+        {
             match result {
                 Ok(_) => Ok(task),
-                Err(_) => Err("Failed to upload to queue".to_string()),
+                Err(e) => Err(ProcessorError::kafka(
+                    format!(
+                        "Failed to upload to queue after {} attempt(s): {}",
+                        attempts, e
+                    ),
+                    is_retryable(&e),
+                )),
             }
-        };
+        }
     }
 
-    // TODO: add retry capability here for any failed tasks
-    pub async fn send_dataset(
+    /// Sends a single dataset task, retrying failed sends according to the retry policy
+    pub async fn send_dataset_task(
+        &self,
+        task: &DatasetProcessingTask,
+    ) -> Result<(), ProcessorError> {
+        let json_payload = serde_json::to_string(&MessageEnvelope::new(task))
+            .map_err(ProcessorError::serialization)?;
+
+        match self
+            .send_with_retry(json_payload.as_bytes(), None, None, task.priority, &[])
+            .await
+        {
+            (Ok(_), _) => Ok(()),
+            (Err(e), attempts) => Err(ProcessorError::kafka(
+                format!(
+                    "Failed to send dataset task {} after {} attempt(s): {}",
+                    task.task_id, attempts, e
+                ),
+                is_retryable(&e),
+            )),
+        }
+    }
+
+    /// Sends a message of the given type to a topic shared by several message types, the
+    /// consumer picks its handler by the type, see `ConsumerClient::start_routing`
+    pub async fn send_message<M: Serialize>(
         &self,
-        initial_dataset_task: DatasetProcessingJob,
-    ) -> Result<SendDataResult, String> {
-        let batch_id = initial_dataset_task.batch_id;
+        message_type: &str,
+        message: &M,
+    ) -> Result<(), ProcessorError> {
+        let json_payload = serde_json::to_string(&MessageEnvelope::new(message))
+            .map_err(ProcessorError::serialization)?;
+
+        let headers = [(MESSAGE_TYPE_HEADER, message_type)];
+        match self
+            .send_with_retry(json_payload.as_bytes(), None, None, Priority::Normal, &headers)
+            .await
+        {
+            (Ok(_), _) => Ok(()),
+            (Err(e), attempts) => Err(ProcessorError::kafka(
+                format!(
+                    "Failed to send {} message after {} attempt(s): {}",
+                    message_type, attempts, e
+                ),
+                is_retryable(&e),
+            )),
+        }
+    }
+
+    /// Sends a task a worker couldn't run to the holding topic, see `common::capabilities`
+    pub async fn send_held_task(&self, held: &HeldTask) -> Result<(), ProcessorError> {
+        let json_payload = serde_json::to_string(&MessageEnvelope::new(held))
+            .map_err(ProcessorError::serialization)?;
 
-        let tasks = initial_dataset_task.into_dataset_tasks();
+        match self
+            .send_with_retry(json_payload.as_bytes(), None, None, Priority::Normal, &[])
+            .await
+        {
+            (Ok(_), _) => Ok(()),
+            (Err(e), attempts) => Err(ProcessorError::kafka(
+                format!(
+                    "Failed to hold task {} after {} attempt(s): {}",
+                    held.hold_id, attempts, e
+                ),
+                is_retryable(&e),
+            )),
+        }
+    }
 
+    /// Sends a message as it was received to `topic`, e.g. a held task once a worker can run it,
+    /// naming the encoding it was received in
+    pub async fn requeue(
+        &self,
+        topic: &str,
+        message: &[u8],
+        encoding: MessageEncoding,
+    ) -> Result<(), ProcessorError> {
+        let headers = [(CONTENT_TYPE_HEADER, content_type(encoding))];
+        match self
+            .send_with_retry(message, Some(topic), None, Priority::Normal, &headers)
+            .await
+        {
+            (Ok(_), _) => Ok(()),
+            (Err(e), attempts) => Err(ProcessorError::kafka(
+                format!(
+                    "Failed to requeue message on {} after {} attempt(s): {}",
+                    topic, attempts, e
+                ),
+                is_retryable(&e),
+            )),
+        }
+    }
+
+    /// Sends the dataset tasks of a job without a dependency, retrying failed sends according to
+    /// the producer's retry policy. Tasks that still fail are returned in `failures`.
+    ///
+    /// Later stages are returned in `deferred`, the scheduler publishes them with
+    /// `send_dataset_task` once the stage they depend on has finished.
+    pub async fn send_dataset(
+        &self,
+        batch_id: uuid::Uuid,
+        tasks: Vec<DatasetProcessingTask>,
+    ) -> Result<SendDataResult, ProcessorError> {
         let mut failed: Vec<DatasetProcessingTask> = vec![];
         let mut success: Vec<DatasetProcessingTask> = vec![];
+        let mut deferred: Vec<DatasetProcessingTask> = vec![];
+        let mut attempts: HashMap<uuid::Uuid, u32> = HashMap::new();
 
         for task in tasks {
+            if !task.depends_on.is_empty() {
+                deferred.push(task);
+                continue;
+            }
+
             // Add to first queue
-            let json_payload = serde_json::to_string(&task).map_err(|_| {
-                "Failed to Serialize Task, please check the structure of the task".to_string()
+            let json_payload = serde_json::to_string(&MessageEnvelope::new(&task)).map_err(|e| {
+                ProcessorError::serialization(format!(
+                    "Failed to Serialize Task, please check the structure of the task: {}",
+                    e
+                ))
             })?;
 
-            let result = {
-                let rec: FutureRecord<String, String> =
-                    FutureRecord::to(&self.topic).payload(&json_payload);
-                self.producer.send(rec, Timeout::Never).await
-            };
+            let (result, task_attempts) = self
+                .send_with_retry(json_payload.as_bytes(), None, None, task.priority, &[])
+                .await;
+            attempts.insert(task.task_id, task_attempts);
 
             match result {
                 Ok(_) => {
                     success.push(task);
                 }
-                Err(_) => {
+                Err(e) => {
+                    tracing::error!(
+                        batch_id = %task.batch_id,
+                        task_id = %task.task_id,
+                        attempts = task_attempts,
+                        error = %e,
+                        "Failed to send dataset task"
+                    );
                     failed.push(task);
                 }
             };
         }
 
         Ok(SendDataResult {
-            batch_id: batch_id.expect("FATAL ERROR: Dataset Not identifiable"),
+            batch_id,
             successes: success,
             failures: failed,
+            deferred,
+            attempts,
         })
     }
 }
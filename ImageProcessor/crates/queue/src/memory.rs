@@ -0,0 +1,140 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, OnceLock, PoisonError},
+};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+// ============================================================================
+// IN-PROCESS QUEUE
+// Producers and consumers send and receive through a `MessageQueue` instead of
+// Kafka when `kafka.backend` is Memory, see `ProducerClient::in_memory` and
+// `ConsumerClient::in_memory`. Tests running the api-server, decomposer and
+// image worker in one process then go through the whole submit, decompose and
+// process flow without a cluster.
+//
+// Topics keep every message published, groups get them through an mpsc
+// channel shared by their consumers. There is a single partition per topic,
+// nothing is persisted, and a message is gone once a consumer received it.
+// ============================================================================
+
+/// A message as the queue carries it
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    pub topic: String,
+    pub offset: i64, // Position in the topic, from 0
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl QueuedMessage {
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Carries the messages of producers and consumers in place of Kafka
+pub trait MessageQueue: Send + Sync {
+    /// Appends a message to the topic, for every group subscribed to it
+    fn publish(&self, topic: &str, payload: &[u8], headers: &[(&str, &str)]);
+
+    /// Subscribes the group to the topics. Every group receives each message once, shared among
+    /// its consumers. Topics new to the group start at their first message, or at the next one
+    /// published with `from_latest`.
+    fn subscribe(&self, group_id: &str, topics: &[String], from_latest: bool) -> Subscription;
+}
+
+/// The messages of a group, received by whichever of its consumers asks first
+#[derive(Clone)]
+pub struct Subscription {
+    receiver: Arc<AsyncMutex<mpsc::UnboundedReceiver<QueuedMessage>>>,
+}
+
+impl Subscription {
+    /// The next message, `None` once the queue is gone
+    pub async fn recv(&self) -> Option<QueuedMessage> {
+        self.receiver.lock().await.recv().await
+    }
+}
+
+struct Group {
+    topics: HashSet<String>,
+    sender: mpsc::UnboundedSender<QueuedMessage>,
+    subscription: Subscription,
+}
+
+#[derive(Default)]
+struct Topics {
+    messages: HashMap<String, Vec<QueuedMessage>>,
+    groups: HashMap<String, Group>,
+}
+
+/// A `MessageQueue` held in memory
+#[derive(Default)]
+pub struct MemoryQueue {
+    topics: Mutex<Topics>,
+}
+
+impl MemoryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The queue every client of the process created from a config with the Memory backend uses
+    pub fn shared() -> Arc<dyn MessageQueue> {
+        static SHARED: OnceLock<Arc<MemoryQueue>> = OnceLock::new();
+        SHARED.get_or_init(|| Arc::new(MemoryQueue::new())).clone()
+    }
+}
+
+impl MessageQueue for MemoryQueue {
+    fn publish(&self, topic: &str, payload: &[u8], headers: &[(&str, &str)]) {
+        let mut topics = self.topics.lock().unwrap_or_else(PoisonError::into_inner);
+        let Topics { messages, groups } = &mut *topics;
+
+        let log = messages.entry(topic.to_string()).or_default();
+        let message = QueuedMessage {
+            topic: topic.to_string(),
+            offset: log.len() as i64,
+            payload: payload.to_vec(),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        };
+        log.push(message.clone());
+
+        // Groups hold their own receiver, so their channels are never closed
+        for group in groups.values().filter(|group| group.topics.contains(topic)) {
+            let _ = group.sender.send(message.clone());
+        }
+    }
+
+    fn subscribe(&self, group_id: &str, topics: &[String], from_latest: bool) -> Subscription {
+        let mut state = self.topics.lock().unwrap_or_else(PoisonError::into_inner);
+        let Topics { messages, groups } = &mut *state;
+
+        let group = groups.entry(group_id.to_string()).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            Group {
+                topics: HashSet::new(),
+                sender,
+                subscription: Subscription {
+                    receiver: Arc::new(AsyncMutex::new(receiver)),
+                },
+            }
+        });
+        for topic in topics {
+            if !group.topics.insert(topic.clone()) || from_latest {
+                continue;
+            }
+            for message in messages.get(topic).into_iter().flatten() {
+                let _ = group.sender.send(message.clone());
+            }
+        }
+
+        group.subscription.clone()
+    }
+}
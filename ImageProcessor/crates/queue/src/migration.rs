@@ -0,0 +1,504 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use common::{
+    config::{KafkaConfig, QueueKind},
+    Priority,
+};
+use futures::StreamExt;
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{BaseConsumer, CommitMode, Consumer, StreamConsumer},
+    error::{KafkaError, RDKafkaErrorCode},
+    message::Message,
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    Offset, TopicPartitionList,
+};
+use tokio::time::Instant;
+
+pub use common::migration::{
+    MigrationError, MigrationMode, TopicMigrationPlan, TopicMigrationReport, TopicSwitch,
+};
+
+use crate::{
+    admin::{KafkaAdmin, TopicSpec},
+    consumer::{decode_message, ConsumerClient},
+    lag::{consumer_group_lag, total_lag, PartitionLag},
+    ProducerClient,
+};
+
+// See `common::migration` for the workflow
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The old and new topic of every topic a migration moves: the topic itself and, when priority
+/// work has topics of its own, its priority topics
+fn topic_pairs(plan: &TopicMigrationPlan, priority_topics: bool) -> Vec<(String, String)> {
+    let mut pairs = vec![(plan.from_topic.clone(), plan.to_topic.clone())];
+    if priority_topics {
+        for priority in [Priority::High, Priority::Low] {
+            pairs.push((priority.topic(&plan.from_topic), priority.topic(&plan.to_topic)));
+        }
+    }
+    pairs
+}
+
+/// Runs the full migration workflow, publishing its topic switches through `switches`, a producer
+/// of the migration topic. `priority_topics` moves the priority topics of the topic along.
+pub async fn migrate_topic(
+    brokers: &str,
+    plan: &TopicMigrationPlan,
+    priority_topics: bool,
+    switches: &ProducerClient,
+) -> Result<TopicMigrationReport, MigrationError> {
+    let pairs = topic_pairs(plan, priority_topics);
+    let deadline = Instant::now() + Duration::from_secs(plan.drain_timeout_secs);
+
+    for (from, to) in &pairs {
+        create_like(brokers, from, to, plan.partitions).await?;
+    }
+
+    // Priority topics follow the topic they belong to, a single switch moves them all
+    publish_switch(switches, plan, plan_consumes_from(plan)).await?;
+    tokio::time::sleep(Duration::from_secs(plan.switch_grace_secs)).await;
+
+    let mut messages_mirrored = 0;
+    match plan.mode {
+        MigrationMode::Mirror => {
+            for (from, to) in &pairs {
+                messages_mirrored += mirror_backlog(brokers, plan, from, to, deadline).await?;
+            }
+        }
+        MigrationMode::Drain => {
+            let from_topics: Vec<&str> = pairs.iter().map(|(from, _)| from.as_str()).collect();
+            let remaining_lag = wait_for_drain(brokers, &plan.group_id, &from_topics, deadline)
+                .await?;
+            if remaining_lag > 0 {
+                return Err(MigrationError::Timeout {
+                    topic: plan.from_topic.clone(),
+                    remaining_lag,
+                });
+            }
+            publish_switch(switches, plan, false).await?;
+        }
+    }
+
+    Ok(TopicMigrationReport {
+        from_topic: plan.from_topic.clone(),
+        to_topic: plan.to_topic.clone(),
+        messages_mirrored,
+        remaining_lag: 0,
+    })
+}
+
+/// Whether consumers keep reading the old topic once switched, only until it is drained
+fn plan_consumes_from(plan: &TopicMigrationPlan) -> bool {
+    matches!(plan.mode, MigrationMode::Drain)
+}
+
+async fn publish_switch(
+    switches: &ProducerClient,
+    plan: &TopicMigrationPlan,
+    consume_from: bool,
+) -> Result<(), MigrationError> {
+    let switch = TopicSwitch {
+        from_topic: plan.from_topic.clone(),
+        to_topic: plan.to_topic.clone(),
+        consume_from,
+    };
+    switches
+        .send_message(TopicSwitch::MESSAGE_TYPE, &switch)
+        .await
+        .map_err(|e| MigrationError::kafka(e.to_string()))?;
+    tracing::info!(
+        from = %switch.from_topic,
+        to = %switch.to_topic,
+        consume_from,
+        "Published topic switch"
+    );
+    Ok(())
+}
+
+/// Creates `to` with `partitions` partitions and the replication of `from`
+async fn create_like(
+    brokers: &str,
+    from: &str,
+    to: &str,
+    partitions: i32,
+) -> Result<(), MigrationError> {
+    let (admin_brokers, from_topic, to_topic) =
+        (brokers.to_string(), from.to_string(), to.to_string());
+    let (source, existing) = blocking(move || {
+        let admin = KafkaAdmin::new(&admin_brokers);
+        let source = admin.describe_topics(Some(&from_topic));
+        let existing = admin.describe_topics(Some(&to_topic));
+        Ok((source, existing))
+    })
+    .await?;
+
+    let source = source
+        .map_err(|e| MigrationError::kafka(e.to_string()))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| MigrationError::kafka(format!("Topic {} does not exist", from)))?;
+    match existing {
+        Ok(topics) if !topics.is_empty() => {
+            return Err(MigrationError::TopicExists {
+                topic: to.to_string(),
+            });
+        }
+        Ok(_) | Err(common::error::ProcessorError::NotFound(_)) => {}
+        Err(e) => return Err(MigrationError::kafka(e.to_string())),
+    }
+
+    let spec = TopicSpec {
+        name: to.to_string(),
+        partitions,
+        replication: source.replication as i32,
+        config: Default::default(),
+    };
+    KafkaAdmin::new(brokers)
+        .create_topic_with(&spec)
+        .await
+        .map_err(|e| MigrationError::kafka(e.to_string()))
+}
+
+/// Copies what the group left unconsumed on `from` to `to`, under a group of its own that starts
+/// at the group's committed offsets, so a retried migration resumes where the last one stopped.
+/// Passes are repeated until one finds nothing new, since producers that were slow to switch
+/// may still have written to `from`.
+async fn mirror_backlog(
+    brokers: &str,
+    plan: &TopicMigrationPlan,
+    from: &str,
+    to: &str,
+    deadline: Instant,
+) -> Result<u64, MigrationError> {
+    let mirror_group = format!("{}-migration", plan.group_id);
+    let (position_brokers, group_id, group, topic) = (
+        brokers.to_string(),
+        plan.group_id.clone(),
+        mirror_group.clone(),
+        from.to_string(),
+    );
+    blocking(move || position_mirror(&position_brokers, &group_id, &group, &topic)).await?;
+
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", &mirror_group)
+        .set("enable.auto.commit", "false")
+        .set("enable.auto.offset.store", "false")
+        .create()
+        .map_err(|e| MigrationError::kafka(format!("Failed to create mirror consumer: {}", e)))?;
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .create()
+        .map_err(|e| MigrationError::kafka(format!("Failed to create mirror producer: {}", e)))?;
+
+    let mut assigned = false;
+    let mut mirrored = 0u64;
+    loop {
+        let lags = lag_of(brokers, &mirror_group, from).await?;
+        let mut end_offsets: HashMap<i32, i64> = lags
+            .iter()
+            .filter(|lag| lag.lag > 0)
+            .map(|lag| (lag.partition, lag.high_watermark))
+            .collect();
+        if end_offsets.is_empty() {
+            return Ok(mirrored);
+        }
+        if Instant::now() >= deadline {
+            return Err(MigrationError::Timeout {
+                topic: from.to_string(),
+                remaining_lag: total_lag(&lags),
+            });
+        }
+
+        if !assigned {
+            let mut assignment = TopicPartitionList::new();
+            for lag in &lags {
+                assignment
+                    .add_partition_offset(from, lag.partition, Offset::Stored)
+                    .map_err(|e| MigrationError::kafka(e.to_string()))?;
+            }
+            consumer
+                .assign(&assignment)
+                .map_err(|e| MigrationError::kafka(e.to_string()))?;
+            assigned = true;
+        }
+
+        let mut stream = consumer.stream();
+        while !end_offsets.is_empty() {
+            let msg = match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(Some(Err(e))) => {
+                    return Err(MigrationError::kafka(format!("Failed to read {}: {}", from, e)));
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    return Err(MigrationError::Timeout {
+                        topic: from.to_string(),
+                        remaining_lag: total_lag(&lag_of(brokers, &mirror_group, from).await?),
+                    });
+                }
+            };
+
+            let mut record: FutureRecord<[u8], [u8]> = FutureRecord::to(to);
+            if let Some(key) = msg.key() {
+                record = record.key(key);
+            }
+            if let Some(payload) = msg.payload() {
+                record = record.payload(payload);
+            }
+            // Headers name the type and encoding of the message, see `routing` and `encoding`
+            if let Some(headers) = msg.headers() {
+                record = record.headers(headers.detach());
+            }
+            producer
+                .send(record, Timeout::Never)
+                .await
+                .map_err(|(e, _)| {
+                    MigrationError::kafka(format!("Failed to mirror message to {}: {}", to, e))
+                })?;
+            consumer
+                .store_offset_from_message(&msg)
+                .map_err(|e| MigrationError::kafka(e.to_string()))?;
+            mirrored += 1;
+
+            if end_offsets
+                .get(&msg.partition())
+                .is_some_and(|end| msg.offset() + 1 >= *end)
+            {
+                end_offsets.remove(&msg.partition());
+            }
+        }
+        drop(stream);
+
+        match consumer.commit_consumer_state(CommitMode::Sync) {
+            Ok(_) | Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {}
+            Err(e) => {
+                return Err(MigrationError::kafka(format!(
+                    "Failed to commit mirrored offsets: {}",
+                    e
+                )));
+            }
+        }
+        tracing::info!(from, to, mirrored, "Mirrored backlog");
+    }
+}
+
+/// Starts the mirror group on every partition it never committed on where `group` left off, at
+/// the earliest message when `group` never committed either.
+///
+/// This is a blocking call.
+fn position_mirror(
+    brokers: &str,
+    group_id: &str,
+    mirror_group: &str,
+    topic: &str,
+) -> Result<(), MigrationError> {
+    let mirrored = consumer_group_lag(brokers, mirror_group, topic).map_err(MigrationError::kafka)?;
+    let consumed: HashMap<i32, PartitionLag> = consumer_group_lag(brokers, group_id, topic)
+        .map_err(MigrationError::kafka)?
+        .into_iter()
+        .map(|lag| (lag.partition, lag))
+        .collect();
+
+    let mut positions = TopicPartitionList::new();
+    for lag in mirrored.iter().filter(|lag| lag.committed_offset.is_none()) {
+        // Without a committed offset the lag runs from the low watermark
+        let offset = consumed
+            .get(&lag.partition)
+            .map(|consumed| {
+                consumed
+                    .committed_offset
+                    .unwrap_or(consumed.high_watermark - consumed.lag)
+            })
+            .unwrap_or(lag.high_watermark - lag.lag);
+        positions
+            .add_partition_offset(topic, lag.partition, Offset::Offset(offset))
+            .map_err(|e| MigrationError::kafka(e.to_string()))?;
+    }
+    if positions.count() == 0 {
+        return Ok(());
+    }
+
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", mirror_group)
+        .set("enable.auto.commit", "false")
+        .create()
+        .map_err(|e| MigrationError::kafka(format!("Failed to create mirror consumer: {}", e)))?;
+    consumer
+        .commit(&positions, CommitMode::Sync)
+        .map_err(|e| MigrationError::kafka(format!("Failed to position mirror group: {}", e)))
+}
+
+/// Polls the group's lag on the topics until it reaches zero or the deadline passes.
+///
+/// Returns the lag left over when polling stopped.
+async fn wait_for_drain(
+    brokers: &str,
+    group_id: &str,
+    topics: &[&str],
+    deadline: Instant,
+) -> Result<i64, MigrationError> {
+    loop {
+        let mut remaining = 0;
+        for topic in topics {
+            remaining += total_lag(&lag_of(brokers, group_id, topic).await?);
+        }
+        if remaining == 0 || Instant::now() >= deadline {
+            return Ok(remaining);
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+async fn lag_of(
+    brokers: &str,
+    group_id: &str,
+    topic: &str,
+) -> Result<Vec<PartitionLag>, MigrationError> {
+    let (brokers, group_id, topic) = (brokers.to_string(), group_id.to_string(), topic.to_string());
+    blocking(move || consumer_group_lag(&brokers, &group_id, &topic).map_err(MigrationError::kafka))
+        .await
+}
+
+/// Runs a blocking call against the brokers off the async runtime
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, MigrationError> + Send + 'static,
+) -> Result<T, MigrationError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| MigrationError::kafka(format!("Join error: {}", e)))?
+}
+
+// ============================================================================
+// TOPIC SWITCHES
+// Every service registers the producers and consumers of the topics that can
+// be migrated with a `TopicSwitcher`, which applies the switches published on
+// the migration topic to them.
+// ============================================================================
+
+/// The producers and consumers of a service that move along when one of their topics is migrated
+#[derive(Default)]
+pub struct TopicSwitcher {
+    producers: Vec<ProducerClient>,
+    consumers: Vec<Arc<ConsumerClient>>,
+}
+
+impl TopicSwitcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Switches the producer, and every clone of it, when its topic is migrated
+    pub fn producer(mut self, producer: &ProducerClient) -> Self {
+        self.producers.push(producer.clone());
+        self
+    }
+
+    /// Switches the consumer when one of its topics is migrated, see `ConsumerClient::switch_topic`
+    pub fn consumer(mut self, consumer: &Arc<ConsumerClient>) -> Self {
+        self.consumers.push(Arc::clone(consumer));
+        self
+    }
+
+    fn apply(&self, switch: &TopicSwitch) {
+        for producer in &self.producers {
+            if producer.topic() == switch.from_topic {
+                producer.switch_topic(&switch.to_topic);
+                tracing::info!(
+                    from = %switch.from_topic,
+                    to = %switch.to_topic,
+                    "Switched producer topic"
+                );
+            }
+        }
+        for consumer in &self.consumers {
+            if let Err(e) = consumer.switch_topic(switch) {
+                tracing::error!(
+                    from = %switch.from_topic,
+                    error = %e,
+                    "Failed to switch consumer topic"
+                );
+            }
+        }
+    }
+
+    /// Applies every switch published so far, then the ones published later for as long as the
+    /// process runs. Call it before producing or consuming, so a service started with a config
+    /// still naming a migrated topic doesn't use it. The in-process queue has no migrations.
+    pub async fn start(self, config: &KafkaConfig) {
+        if config.backend == QueueKind::Memory {
+            return;
+        }
+        // Switches are applied in the order they were published, so they are read from the start
+        // of the single partition of the topic without a group
+        let consumer: StreamConsumer = match ClientConfig::new()
+            .set("bootstrap.servers", config.consumer_brokers())
+            .set("group.id", format!("topic-switcher-{}", uuid::Uuid::new_v4()))
+            .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "false")
+            .create()
+        {
+            Ok(consumer) => consumer,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to create topic switch consumer");
+                return;
+            }
+        };
+        let topic = &config.migration_topic;
+        let mut assignment = TopicPartitionList::new();
+        let assigned = assignment
+            .add_partition_offset(topic, 0, Offset::Beginning)
+            .and_then(|_| consumer.assign(&assignment));
+        if let Err(e) = assigned {
+            tracing::error!(%topic, error = %e, "Failed to read topic switches");
+            return;
+        }
+
+        // A topic that doesn't exist yet has no switches to replay
+        let published = consumer
+            .fetch_watermarks(topic, 0, FETCH_TIMEOUT)
+            .map(|(_, high)| high)
+            .unwrap_or(0);
+        let switcher = Arc::new(self);
+        let mut replayed = 0;
+        {
+            let mut stream = consumer.stream();
+            while replayed < published {
+                match stream.next().await {
+                    Some(Ok(msg)) => {
+                        replayed = msg.offset() + 1;
+                        switcher.apply_message(msg.payload());
+                    }
+                    Some(Err(e)) => {
+                        tracing::warn!(%topic, error = %e, "Failed to replay topic switches");
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        tokio::spawn(async move {
+            let mut stream = consumer.stream();
+            while let Some(msg) = stream.next().await {
+                match msg {
+                    Ok(msg) => switcher.apply_message(msg.payload()),
+                    Err(e) => tracing::warn!(error = %e, "Failed to read topic switches"),
+                }
+            }
+        });
+    }
+
+    fn apply_message(&self, payload: Option<&[u8]>) {
+        match decode_message::<TopicSwitch>(payload.unwrap_or_default()) {
+            Ok(envelope) => self.apply(&envelope.payload),
+            Err(e) => tracing::error!(error = %e, "Skipping unreadable topic switch"),
+        }
+    }
+}
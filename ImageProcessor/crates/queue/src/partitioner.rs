@@ -0,0 +1,55 @@
+// ============================================================================
+// PARTITIONING STRATEGIES
+// The producer asks its partitioner which partition a message should go to.
+// Returning None leaves the choice to librdkafka's default partitioner.
+// ============================================================================
+
+/// Decides which partition of a topic a message is written to
+pub trait Partitioner: Send + Sync {
+    fn partition(&self, key: Option<&str>, partition_count: i32) -> Option<i32>;
+}
+
+/// Leaves partition selection to Kafka
+pub struct DefaultPartitioner;
+
+impl Partitioner for DefaultPartitioner {
+    fn partition(&self, _key: Option<&str>, _partition_count: i32) -> Option<i32> {
+        None
+    }
+}
+
+/// Consistently hashes the message key (the image filename) onto a partition, so every stage of
+/// the same image lands on the same partition and therefore on the worker pinned to it.
+///
+/// Uses jump consistent hashing, so growing the topic only moves the minimum number of keys.
+pub struct AffinityPartitioner;
+
+impl Partitioner for AffinityPartitioner {
+    fn partition(&self, key: Option<&str>, partition_count: i32) -> Option<i32> {
+        let key = key?;
+        if partition_count <= 0 {
+            return None;
+        }
+        Some(jump_consistent_hash(fnv1a(key.as_bytes()), partition_count))
+    }
+}
+
+/// FNV-1a, used instead of `DefaultHasher` because the result must be stable across processes
+/// and Rust versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Jump consistent hash (Lamping & Veach)
+fn jump_consistent_hash(mut key: u64, buckets: i32) -> i32 {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1i64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as i32
+}
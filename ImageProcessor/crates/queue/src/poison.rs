@@ -0,0 +1,245 @@
+use common::{
+    config::{PoisonPillConfig, PoisonPillPolicy},
+    metrics,
+};
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    message::{Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    TopicPartitionList,
+};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use crate::{concurrency::MessagePosition, encoding::CONTENT_TYPE_HEADER, memory::MessageQueue};
+
+const DEAD_LETTER_TIMEOUT: Duration = Duration::from_secs(10);
+// Longest wait between two attempts of a handler, however many attempts are allowed
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Applies the configured `PoisonPillPolicy` to messages that can't be read or whose handler
+/// keeps failing
+pub struct PoisonPillHandler {
+    config: PoisonPillConfig,
+    producer: Option<FutureProducer>, // Only created when some topic dead letters its messages
+    queue: Option<Arc<dyn MessageQueue>>, // Dead letters go there instead, see `in_memory`
+    parked: Mutex<HashSet<(String, i32)>>,
+}
+
+impl PoisonPillHandler {
+    pub fn new(brokers: &str, config: PoisonPillConfig) -> Self {
+        let dead_letters = config.policy == PoisonPillPolicy::DeadLetter
+            || config
+                .topics
+                .values()
+                .any(|policy| *policy == PoisonPillPolicy::DeadLetter);
+
+        let producer = match dead_letters {
+            true => match ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .create()
+            {
+                Ok(producer) => Some(producer),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to create dead letter producer");
+                    None
+                }
+            },
+            false => None,
+        };
+
+        Self {
+            config,
+            producer,
+            queue: None,
+            parked: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Applies the policies to the messages of the in-process queue, see `memory`
+    pub fn in_memory(queue: Arc<dyn MessageQueue>, config: PoisonPillConfig) -> Self {
+        Self {
+            config,
+            producer: None,
+            queue: Some(queue),
+            parked: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Times a message is handed to a failing handler before it counts as a poison pill
+    pub fn max_handler_attempts(&self) -> u32 {
+        self.config.max_handler_attempts.max(1)
+    }
+
+    /// Wait before the given attempt of a handler, doubling from `retry_backoff_ms` before the
+    /// second one
+    pub fn retry_backoff(&self, attempt: u32) -> Duration {
+        let Some(retries) = attempt.checked_sub(2) else {
+            return Duration::ZERO;
+        };
+        Duration::from_millis(self.config.retry_backoff_ms)
+            .saturating_mul(2u32.saturating_pow(retries))
+            .min(MAX_RETRY_BACKOFF)
+    }
+
+    /// Whether the partition was parked by an earlier poison pill, its messages are left
+    /// unhandled and uncommitted until the consumer restarts
+    pub fn is_parked(&self, topic: &str, partition: i32) -> bool {
+        self.parked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(&(topic.to_string(), partition))
+    }
+
+    /// Applies the policy of the message's topic and returns whether its offset may be committed
+    pub(crate) async fn apply(
+        &self,
+        consumer: &StreamConsumer,
+        position: &MessagePosition,
+        payload: Option<&[u8]>,
+        content_type: Option<&str>,
+        reason: &str,
+    ) -> bool {
+        let policy = self.config.policy_for(&position.topic);
+        metrics::POISON_PILLS
+            .with_label_values(&[&position.topic, &format!("{:?}", policy)])
+            .inc();
+
+        match policy {
+            PoisonPillPolicy::DeadLetter => {
+                match self
+                    .dead_letter(position, payload, content_type, reason)
+                    .await
+                {
+                    Ok(topic) => {
+                        tracing::warn!(
+                            topic = %position.topic,
+                            partition = position.partition,
+                            offset = position.offset,
+                            %reason,
+                            "Moved poison pill to {}",
+                            topic
+                        );
+                        true
+                    }
+                    // Committing would lose the message, so hold the partition instead
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to dead letter poison pill, parking instead");
+                        self.park(consumer, position, reason);
+                        false
+                    }
+                }
+            }
+            PoisonPillPolicy::Park => {
+                self.park(consumer, position, reason);
+                false
+            }
+            PoisonPillPolicy::Skip => {
+                tracing::warn!(
+                    topic = %position.topic,
+                    partition = position.partition,
+                    offset = position.offset,
+                    %reason,
+                    "Skipping poison pill"
+                );
+                true
+            }
+            PoisonPillPolicy::Crash => {
+                tracing::error!(
+                    topic = %position.topic,
+                    partition = position.partition,
+                    offset = position.offset,
+                    %reason,
+                    "Poison pill, exiting without committing"
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Publishes the raw message to the dead letter topic of its topic, with where it came from,
+    /// why it was rejected and its content type in the headers
+    async fn dead_letter(
+        &self,
+        position: &MessagePosition,
+        payload: Option<&[u8]>,
+        content_type: Option<&str>,
+        reason: &str,
+    ) -> Result<String, String> {
+        let topic = format!("{}{}", position.topic, self.config.dead_letter_suffix);
+
+        let partition = position.partition.to_string();
+        let offset = position.offset.to_string();
+        let mut headers = vec![
+            ("x-original-topic", position.topic.as_str()),
+            ("x-original-partition", partition.as_str()),
+            ("x-original-offset", offset.as_str()),
+            ("x-error", reason),
+        ];
+        if let Some(content_type) = content_type {
+            headers.push((CONTENT_TYPE_HEADER, content_type));
+        }
+
+        if let Some(queue) = &self.queue {
+            queue.publish(&topic, payload.unwrap_or_default(), &headers);
+            return Ok(topic);
+        }
+        let producer = self
+            .producer
+            .as_ref()
+            .ok_or("No dead letter producer".to_string())?;
+
+        let headers = headers
+            .iter()
+            .fold(OwnedHeaders::new(), |headers, &(key, value)| {
+                headers.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            });
+        let mut record = FutureRecord::<(), [u8]>::to(&topic).headers(headers);
+        if let Some(payload) = payload {
+            record = record.payload(payload);
+        }
+
+        producer
+            .send(record, Timeout::After(DEAD_LETTER_TIMEOUT))
+            .await
+            .map_err(|(e, _)| e.to_string())?;
+        Ok(topic)
+    }
+
+    fn park(&self, consumer: &StreamConsumer, position: &MessagePosition, reason: &str) {
+        tracing::warn!(
+            topic = %position.topic,
+            partition = position.partition,
+            offset = position.offset,
+            %reason,
+            "Parking partition until restart"
+        );
+        self.parked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert((position.topic.clone(), position.partition));
+
+        // The in-process queue isn't paused, the consumer skips the messages of the partition
+        if self.queue.is_some() {
+            return;
+        }
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition(&position.topic, position.partition);
+        if let Err(e) = consumer.pause(&partitions) {
+            tracing::error!(
+                topic = %position.topic,
+                partition = position.partition,
+                error = %e,
+                "Failed to pause partition"
+            );
+        }
+    }
+}
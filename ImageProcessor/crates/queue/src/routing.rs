@@ -0,0 +1,114 @@
+use common::{config::MessageEncoding, envelope::MessageEnvelope, error::ProcessorError};
+use futures::future::BoxFuture;
+use rdkafka::message::{BorrowedMessage, Headers, Message};
+use serde::de::DeserializeOwned;
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use crate::{consumer::MessageReader, encoding::decode_envelope};
+
+// ============================================================================
+// MESSAGE ROUTING
+// Low volume messages (e.g. control messages to cancel or pause work) can
+// share a topic instead of each needing a topic and consumer group of their
+// own. The producer names the type of every message in its `message-type`
+// header, see `ProducerClient::send_message`, and the consumer hands it to the
+// handler registered for that type, see `ConsumerClient::start_routing`.
+// ============================================================================
+
+pub const MESSAGE_TYPE_HEADER: &str = "message-type";
+
+/// Runs the handler of a message, once per attempt
+type Handle = Arc<dyn Fn() -> BoxFuture<'static, Result<(), ProcessorError>> + Send + Sync>;
+
+/// Decodes the payload of a message in its encoding into its handler call
+type Route = Arc<dyn Fn(MessageEncoding, &[u8]) -> Result<RoutedMessage, String> + Send + Sync>;
+
+/// Typed handlers for the message types of a shared topic
+#[derive(Default)]
+pub struct MessageRouter {
+    routes: HashMap<String, Route>,
+}
+
+impl MessageRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands the messages of `message_type` to `handler`, replacing an earlier handler of the type
+    pub fn on<I, F, Fut>(mut self, message_type: &str, handler: F) -> Self
+    where
+        I: DeserializeOwned + Clone + Send + Sync + 'static,
+        F: Fn(MessageEnvelope<I>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ProcessorError>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let route: Route = Arc::new(move |encoding, payload: &[u8]| {
+            let envelope = decode_envelope::<I>(encoding, payload)?;
+            let message_id = envelope.message_id;
+            let correlation_id = envelope.trace.correlation_id;
+            let handler = Arc::clone(&handler);
+            let handle: Handle = Arc::new(move || -> BoxFuture<'static, _> {
+                Box::pin(handler(envelope.clone()))
+            });
+            Ok(RoutedMessage {
+                message_id,
+                correlation_id,
+                handle,
+            })
+        });
+
+        self.routes.insert(message_type.to_string(), route);
+        self
+    }
+}
+
+/// A message decoded by the handler of its type
+#[derive(Clone)]
+pub(crate) struct RoutedMessage {
+    message_id: uuid::Uuid,
+    correlation_id: Option<uuid::Uuid>,
+    handle: Handle,
+}
+
+impl RoutedMessage {
+    pub(crate) fn handle(&self) -> BoxFuture<'static, Result<(), ProcessorError>> {
+        (self.handle)()
+    }
+}
+
+impl MessageReader for MessageRouter {
+    type Message = RoutedMessage;
+
+    fn read(
+        &self,
+        message_type: Option<&str>,
+        encoding: MessageEncoding,
+        payload: &[u8],
+    ) -> Result<RoutedMessage, String> {
+        let message_type = message_type
+            .ok_or_else(|| format!("No {} header on a routed topic", MESSAGE_TYPE_HEADER))?;
+        let route = self
+            .routes
+            .get(message_type)
+            .ok_or_else(|| format!("No handler for message type {}", message_type))?;
+
+        route(encoding, payload)
+    }
+
+    fn message_id(message: &RoutedMessage) -> uuid::Uuid {
+        message.message_id
+    }
+
+    fn correlation_id(message: &RoutedMessage) -> Option<uuid::Uuid> {
+        message.correlation_id
+    }
+}
+
+/// The value of a header of a message, e.g. its `message-type`
+pub(crate) fn header<'a>(msg: &'a BorrowedMessage<'_>, key: &str) -> Option<&'a str> {
+    msg.headers()?
+        .iter()
+        .find(|header| header.key == key)?
+        .value
+        .and_then(|value| std::str::from_utf8(value).ok())
+}
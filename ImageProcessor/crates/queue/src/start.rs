@@ -0,0 +1,125 @@
+use std::{collections::HashMap, time::Duration};
+
+use common::{
+    checkpoints::PartitionOffset,
+    config::{StartPosition, StartPositionConfig},
+};
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{BaseConsumer, CommitMode, Consumer},
+    Offset, TopicPartitionList,
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Commits the configured start position on every partition of the topics the group never
+/// committed an offset on, and returns the partitions it positioned. Partitions starting at the
+/// earliest message are left to `auto.offset.reset`, as are the partitions of groups that
+/// committed before.
+///
+/// This is a blocking call, it has to run before the group's consumers start polling.
+pub(crate) fn position_new_partitions(
+    brokers: &str,
+    group_id: &str,
+    topics: &[String],
+    config: &StartPositionConfig,
+    checkpoints: &[PartitionOffset],
+) -> Result<Vec<PartitionOffset>, String> {
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", brokers)
+        .set("group.id", group_id)
+        .set("enable.auto.commit", "false")
+        .create()
+        .map_err(|e| format!("Failed to create positioning consumer: {}", e))?;
+    let checkpoints: HashMap<(&str, i32), i64> = checkpoints
+        .iter()
+        .map(|checkpoint| ((checkpoint.topic.as_str(), checkpoint.partition), checkpoint.offset))
+        .collect();
+
+    let mut positions = TopicPartitionList::new();
+    let mut positioned = Vec::new();
+    for topic in topics {
+        let policy = config.policy_for(topic);
+        if policy == StartPosition::Earliest {
+            continue;
+        }
+
+        let metadata = consumer
+            .fetch_metadata(Some(topic), FETCH_TIMEOUT)
+            .map_err(|e| format!("Failed to fetch metadata for {}: {}", topic, e))?;
+        let mut partitions = TopicPartitionList::new();
+        for partition in metadata.topics().iter().flat_map(|t| t.partitions()) {
+            partitions.add_partition(topic, partition.id());
+        }
+        let committed = consumer
+            .committed_offsets(partitions, FETCH_TIMEOUT)
+            .map_err(|e| format!("Failed to fetch committed offsets for {}: {}", topic, e))?;
+
+        for elem in committed.elements() {
+            if matches!(elem.offset(), Offset::Offset(_)) {
+                continue;
+            }
+            let partition = elem.partition();
+            let Some(offset) =
+                start_offset(&consumer, topic, partition, policy, config, &checkpoints)?
+            else {
+                continue;
+            };
+
+            positions
+                .add_partition_offset(topic, partition, Offset::Offset(offset))
+                .map_err(|e| format!("Failed to position {}/{}: {}", topic, partition, e))?;
+            positioned.push(PartitionOffset {
+                topic: topic.clone(),
+                partition,
+                offset,
+            });
+        }
+    }
+
+    if !positioned.is_empty() {
+        consumer
+            .commit(&positions, CommitMode::Sync)
+            .map_err(|e| format!("Failed to commit start positions: {}", e))?;
+    }
+    Ok(positioned)
+}
+
+/// Where the policy starts a partition, `None` for its earliest message
+fn start_offset(
+    consumer: &BaseConsumer,
+    topic: &str,
+    partition: i32,
+    policy: StartPosition,
+    config: &StartPositionConfig,
+    checkpoints: &HashMap<(&str, i32), i64>,
+) -> Result<Option<i64>, String> {
+    let (low, high) = consumer
+        .fetch_watermarks(topic, partition, FETCH_TIMEOUT)
+        .map_err(|e| format!("Failed to fetch watermarks for {}/{}: {}", topic, partition, e))?;
+
+    let offset = match (policy, config.timestamp) {
+        (StartPosition::Earliest, _) | (StartPosition::Timestamp, None) => None,
+        (StartPosition::Latest, _) => Some(high),
+        (StartPosition::Timestamp, Some(timestamp)) => {
+            let mut times = TopicPartitionList::new();
+            let millis = Offset::Offset(timestamp.timestamp_millis());
+            times
+                .add_partition_offset(topic, partition, millis)
+                .map_err(|e| format!("Failed to look up {}/{}: {}", topic, partition, e))?;
+            let found = consumer
+                .offsets_for_times(times, FETCH_TIMEOUT)
+                .map_err(|e| format!("Failed to look up {}/{}: {}", topic, partition, e))?;
+            // Nothing was produced since, so only what comes next is read
+            match found.find_partition(topic, partition).map(|elem| elem.offset()) {
+                Some(Offset::Offset(offset)) => Some(offset),
+                _ => Some(high),
+            }
+        }
+        // Messages older than the topic's retention are gone, the oldest one left is next
+        (StartPosition::Checkpoint, _) => checkpoints
+            .get(&(topic, partition))
+            .map(|offset| (*offset).clamp(low, high)),
+    };
+    Ok(offset)
+}
@@ -0,0 +1,78 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::Utc;
+use common::{
+    error::ProcessorError,
+    lifecycle::BatchState,
+    manifest::{BatchManifest, BatchManifestInput, BatchManifestStage, batch_manifest_key},
+};
+use db_utils::types::{DBClient, DBDatasetTask};
+use storage::{PutOptions, StorageBackend};
+use tracing::info;
+
+/// Writes the manifest of every input of a completed batch and what its stages made of it to
+/// the results of the batch, and records its key on the batch, see `common::manifest`
+pub async fn publish_batch_manifest(
+    db: &DBClient,
+    storage: &dyn StorageBackend,
+    batch_id: &uuid::Uuid,
+    tasks: &[DBDatasetTask],
+) -> Result<(), ProcessorError> {
+    let Some(batch) = db.get_batch(batch_id).await? else {
+        return Ok(());
+    };
+    if batch.state != BatchState::Completed {
+        return Ok(());
+    }
+
+    let stages: HashMap<uuid::Uuid, u32> =
+        tasks.iter().map(|task| (task.task_id, task.stage)).collect();
+    let mut inputs: BTreeMap<Option<String>, Vec<BatchManifestStage>> = BTreeMap::new();
+    for task in db.get_batch_image_tasks(batch_id).await? {
+        let Some(image_task_id) = task.task_id else {
+            continue;
+        };
+        inputs
+            .entry(task.source_path)
+            .or_default()
+            .push(BatchManifestStage {
+                stage: stages.get(&task.dataset_id).copied().unwrap_or_default(),
+                image_task_id,
+                status: format!("{:?}", task.status),
+                output_key: task.output_key,
+                size_bytes: task.bytes_written,
+                sha256: task.provenance.map(|provenance| provenance.output_hash),
+            });
+    }
+
+    let manifest = BatchManifest {
+        batch_id: batch.batch_id,
+        dataset_key: batch.dataset_key.clone(),
+        time_created: Utc::now(),
+        provenance_root: batch.provenance_root.clone(),
+        inputs: inputs
+            .into_iter()
+            .map(|(filename, mut stages)| {
+                stages.sort_by_key(|stage| stage.stage);
+                BatchManifestInput { filename, stages }
+            })
+            .collect(),
+    };
+
+    let key = batch_manifest_key(batch.tenant_id.as_deref(), &batch.batch_id);
+    let body = serde_json::to_vec(&manifest).map_err(ProcessorError::serialization)?;
+    let options = PutOptions {
+        content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+    storage.put_object(&key, body.into(), &options).await?;
+    db.set_batch_manifest_key(&batch.batch_id, &key).await?;
+
+    info!(
+        batch_id = %batch.batch_id,
+        inputs = manifest.inputs.len(),
+        %key,
+        "Published the manifest of the batch"
+    );
+    Ok(())
+}
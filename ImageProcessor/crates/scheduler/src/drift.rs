@@ -0,0 +1,59 @@
+use chrono::Utc;
+use common::{
+    config::QualityDriftConfig, drift::detect_drifts, error::ProcessorError, operations_name,
+};
+use db_utils::types::{DBClient, DBDatasetTask, StageQuality};
+
+/// What a stage's baseline is kept by: its operations after the ones of the stages upstream of
+/// it, e.g. "Resize > Blur+Convert"
+fn pipeline_name(task: &DBDatasetTask) -> String {
+    let stage = operations_name(std::iter::once(&task.operation).chain(&task.fused_operations));
+    match task.upstream_operations.is_empty() {
+        true => stage,
+        false => format!("{} > {}", operations_name(&task.upstream_operations), stage),
+    }
+}
+
+/// Summarizes the sampled outputs of a finished stage and compares them with the latest stages
+/// of the same pipeline, see `common::drift`. Stages without a sampled output aren't recorded,
+/// so they don't thin out the baseline of their pipeline.
+pub async fn check_stage_quality(
+    db: &DBClient,
+    task: &DBDatasetTask,
+    config: &QualityDriftConfig,
+) -> Result<(), ProcessorError> {
+    if config.sample_rate == 0 {
+        return Ok(());
+    }
+    let summary = db.stage_quality_summary(&task.task_id).await?;
+    if summary.sharpness.is_none() {
+        return Ok(());
+    }
+
+    let pipeline = pipeline_name(task);
+    let baseline = db
+        .get_quality_baseline(&pipeline, &task.batch_id, config.baseline_stages)
+        .await?;
+    let drifts = detect_drifts(task.stage, &pipeline, &summary, &baseline, config);
+    for drift in &drifts {
+        tracing::warn!(
+            batch_id = %task.batch_id,
+            stage = task.stage,
+            pipeline = %drift.pipeline,
+            metric = ?drift.metric,
+            observed = drift.observed,
+            baseline = drift.baseline,
+            z_score = drift.z_score,
+            "Stage quality drifted from its pipeline's baseline"
+        );
+    }
+
+    let quality = StageQuality {
+        pipeline,
+        summary,
+        drifts,
+        time_checked: Utc::now(),
+        alert_sent_at: None,
+    };
+    db.record_stage_quality(task, &quality).await.map(|_| ())
+}
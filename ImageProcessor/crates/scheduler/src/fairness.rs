@@ -0,0 +1,33 @@
+use chrono::Utc;
+use common::{ImageTask, config::FairnessConfig, error::ProcessorError};
+use db_utils::types::DBClient;
+use queue::ProducerClient;
+use tracing::info;
+
+/// Requeues the image tasks the workers deferred because their batch ran as many as it may, see
+/// `db_utils::fairness`
+pub struct DeferredTaskRequeue {
+    pub producer: ProducerClient, // On the image topic
+    pub config: FairnessConfig,
+}
+
+impl DeferredTaskRequeue {
+    /// Requeues the deferred tasks that are due, a few per pass. A failed send is left for the
+    /// next pass, a task sent twice is skipped by the worker once it finished.
+    pub async fn requeue_due(&self, db: &DBClient) -> Result<(), ProcessorError> {
+        let tasks = db
+            .get_due_deferred_image_tasks(Utc::now(), self.config.requeue_per_pass)
+            .await?;
+
+        let mut requeued = 0;
+        for task in tasks {
+            self.producer.send_image_task(ImageTask::from(&task)).await?;
+            db.clear_image_task_deferral(&task).await?;
+            requeued += 1;
+        }
+        if requeued > 0 {
+            info!(requeued, "Requeued image tasks deferred for their batch's bound");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use chrono::{Duration, Utc};
+use common::{
+    capabilities::{Capability, HeldTask},
+    config::{Config, QueueKind},
+    error::ProcessorError,
+    metrics,
+};
+use db_utils::types::DBClient;
+use queue::{ProducerClient, consumer::ConsumerClient, encoding::from_text, memory::MemoryQueue};
+use tracing::{error, info};
+
+const HOLDING_GROUP: &str = "held-image-tasks";
+
+/// Records the tasks the image workers held, see `common::capabilities`. Every instance consumes,
+/// standbys included, so held tasks are recorded while the leadership moves.
+pub fn spawn_holding_consumer(config: &Config, db: Arc<DBClient>) {
+    let topics = [config.capabilities.holding_topic.as_str()];
+    let consumer = match config.kafka.backend {
+        QueueKind::Kafka => {
+            ConsumerClient::new(config.kafka.consumer_brokers(), HOLDING_GROUP, &topics)
+        }
+        QueueKind::Memory => {
+            ConsumerClient::in_memory(MemoryQueue::shared(), HOLDING_GROUP, &topics)
+        }
+    }
+    .expect("SCHEDULER: Failed to create holding consumer")
+        .with_poison_pill(config.kafka.poison_pill.clone());
+
+    tokio::spawn(async move {
+        consumer
+            .start_consuming(move |held: HeldTask| {
+                let db = Arc::clone(&db);
+                async move { db.save_held_task(&held).await }
+            })
+            .await;
+    });
+}
+
+/// Requeues the held tasks once a live worker can run them
+pub struct HeldTaskRelease {
+    producer: ProducerClient,
+    worker_ttl: Duration, // Workers not heard from for this long don't count
+    unservable: BTreeSet<Capability>, // As of the last pass, to log the changes only
+}
+
+impl HeldTaskRelease {
+    pub fn new(producer: ProducerClient, worker_ttl_secs: u64) -> Self {
+        Self {
+            producer,
+            worker_ttl: Duration::seconds(worker_ttl_secs as i64),
+            unservable: BTreeSet::new(),
+        }
+    }
+
+    /// Requeues the held tasks a live worker can run on the topic they were held from, and
+    /// counts the others in the `unservable_held_tasks` metric by the capability they need
+    pub async fn release_servable(&mut self, db: &DBClient) -> Result<(), ProcessorError> {
+        let workers = db.get_live_workers(Utc::now() - self.worker_ttl).await?;
+
+        let mut unservable: BTreeMap<Capability, i64> = BTreeMap::new();
+        let mut released = 0;
+        for task in db.get_held_tasks().await? {
+            if !workers
+                .iter()
+                .any(|worker| worker.capabilities.satisfies(&task.capability))
+            {
+                *unservable.entry(task.capability).or_default() += 1;
+                continue;
+            }
+
+            // Claimed first so it is only requeued once, and put back if the requeue fails
+            if !db.claim_held_task(&task.hold_id).await? {
+                continue;
+            }
+            let message =
+                from_text(task.encoding, &task.message).map_err(ProcessorError::serialization)?;
+            if let Err(e) = self
+                .producer
+                .requeue(&task.original_topic, &message, task.encoding)
+                .await
+            {
+                db.restore_held_task(&task).await?;
+                return Err(e);
+            }
+            released += 1;
+        }
+        if released > 0 {
+            info!(released, "Requeued held tasks a live worker can run");
+        }
+
+        for capability in self
+            .unservable
+            .iter()
+            .filter(|capability| !unservable.contains_key(capability))
+        {
+            metrics::UNSERVABLE_HELD_TASKS
+                .with_label_values(&[&capability.to_string()])
+                .set(0);
+            info!(%capability, "A live worker can run the held tasks again");
+        }
+        for (capability, tasks) in &unservable {
+            metrics::UNSERVABLE_HELD_TASKS
+                .with_label_values(&[&capability.to_string()])
+                .set(*tasks);
+            if !self.unservable.contains(capability) {
+                error!(%capability, tasks, "No live worker can run the held tasks");
+            }
+        }
+        self.unservable = unservable.into_keys().collect();
+
+        Ok(())
+    }
+}
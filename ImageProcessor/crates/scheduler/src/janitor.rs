@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use common::{
+    error::ProcessorError,
+    keys::{results_archive_key, results_prefix},
+    previews::previews_prefix,
+};
+use db_utils::types::{DBClient, DBDatasetProcessingJob};
+use storage::{StorageBackend, delete_prefix};
+use tracing::info;
+
+use crate::result_stages;
+
+// Batches handled per scheduler pass at most, the rest wait for the next ones
+const BATCHES_PER_PASS: i64 = 5;
+
+/// Deletes the intermediate outputs of finished batches once their retention ran out, see
+/// `db_utils::deletion`, and every output of the batches whose results expired, see
+/// `db_utils::retention`. A Failed batch retried after that has lost the inputs of its later
+/// stages, and fails again.
+pub struct Janitor {
+    pub storage: Arc<dyn StorageBackend>,
+    pub keep_for: Option<Duration>, // None to keep the intermediate outputs until the results expire
+}
+
+impl Janitor {
+    pub async fn sweep_due(&self, db: &DBClient) -> Result<(), ProcessorError> {
+        self.delete_expired(db).await?;
+
+        let Some(keep_for) = self.keep_for else {
+            return Ok(());
+        };
+        let cutoff = Utc::now() - keep_for;
+        for batch in db.get_batches_with_intermediates(cutoff, BATCHES_PER_PASS).await? {
+            match db.dataset_shared(&batch.dataset_key, &batch.batch_id).await? {
+                true => info!(
+                    batch_id = %batch.batch_id,
+                    dataset_key = %batch.dataset_key,
+                    "Kept the intermediate outputs of a batch sharing its dataset"
+                ),
+                false => self.sweep(db, &batch).await?,
+            }
+            db.mark_intermediates_swept(&batch.batch_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the images extracted from the dataset of a batch and the outputs of the stages
+    /// that aren't results
+    async fn sweep(
+        &self,
+        db: &DBClient,
+        batch: &DBDatasetProcessingJob,
+    ) -> Result<(), ProcessorError> {
+        let tasks = db.get_dataset_tasks(&batch.batch_id).await?;
+        let results: Vec<uuid::Uuid> =
+            result_stages(&tasks).iter().map(|task| task.task_id).collect();
+        let intermediate: Vec<uuid::Uuid> = tasks
+            .iter()
+            .map(|task| task.task_id)
+            .filter(|task_id| !results.contains(task_id))
+            .collect();
+        let all: Vec<uuid::Uuid> = tasks.iter().map(|task| task.task_id).collect();
+
+        let mut keys = db.get_extracted_keys(&all).await?;
+        keys.extend(db.get_output_keys(&intermediate).await?);
+        storage::retry_throttled(|| self.storage.delete_objects(&keys)).await?;
+
+        info!(
+            batch_id = %batch.batch_id,
+            objects = keys.len(),
+            "Deleted the intermediate outputs of a finished batch"
+        );
+        Ok(())
+    }
+
+    /// Deletes the outputs of every stage of the batches whose results expired, what they
+    /// published and their previews and archive. The images extracted from the dataset are
+    /// inputs, they go with the intermediate outputs or the batch itself.
+    async fn delete_expired(&self, db: &DBClient) -> Result<(), ProcessorError> {
+        for batch in db.get_expired_batches(Utc::now(), BATCHES_PER_PASS).await? {
+            let tenant_id = batch.tenant_id.as_deref();
+            let tasks: Vec<uuid::Uuid> = db
+                .get_dataset_tasks(&batch.batch_id)
+                .await?
+                .iter()
+                .map(|task| task.task_id)
+                .collect();
+
+            let mut keys = db.get_output_keys(&tasks).await?;
+            keys.push(results_archive_key(tenant_id, &batch.batch_id));
+            storage::retry_throttled(|| self.storage.delete_objects(&keys)).await?;
+            let mut objects = keys.len();
+            for prefix in [
+                results_prefix(tenant_id, &batch.batch_id),
+                previews_prefix(tenant_id, &batch.batch_id),
+            ] {
+                objects +=
+                    storage::retry_throttled(|| delete_prefix(self.storage.as_ref(), &prefix))
+                        .await?;
+            }
+            db.mark_results_deleted(&batch.batch_id).await?;
+
+            info!(
+                batch_id = %batch.batch_id,
+                objects,
+                "Deleted the results of a batch whose retention ran out"
+            );
+        }
+        Ok(())
+    }
+}
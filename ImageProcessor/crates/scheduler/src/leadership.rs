@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use common::metrics;
+use db_utils::types::DBClient;
+use tracing::{error, info, warn};
+
+// Name of the lease the scheduler instances compete for
+const LEASE_NAME: &str = "scheduler";
+
+/// A stretch of time this instance leads, see `db_utils::leadership`
+pub struct Term {
+    pub token: u64,      // Fencing token the writes of this term carry
+    pub took_over: bool, // Whether the term just started, e.g. because the last leader died
+}
+
+/// The lease of one scheduler instance, standing by while another instance leads
+pub struct Leadership {
+    holder: String,
+    lease: Duration,
+    token: Option<u64>,
+}
+
+impl Leadership {
+    pub fn new(holder: String, lease: Duration) -> Self {
+        Self {
+            holder,
+            lease,
+            token: None,
+        }
+    }
+
+    /// Takes or renews the lease, returns the term while this instance leads.
+    ///
+    /// An instance that can't reach the database stands by, its lease runs out and a standby
+    /// that can takes over.
+    pub async fn renew(&mut self, db: &DBClient) -> Option<Term> {
+        let token = match db
+            .acquire_leadership(LEASE_NAME, &self.holder, self.lease)
+            .await
+        {
+            Ok(token) => token,
+            Err(e) => {
+                error!(holder = %self.holder, error = %e, "Failed to renew the scheduler lease");
+                None
+            }
+        };
+
+        let took_over = token.is_some() && token != self.token;
+        match (self.token, token) {
+            (None, Some(token)) => info!(holder = %self.holder, token, "Took over as leader"),
+            (Some(old), Some(token)) if old != token => {
+                warn!(holder = %self.holder, token, "Lease expired and taken over again")
+            }
+            (Some(_), None) => warn!(holder = %self.holder, "Lost the lead, standing by"),
+            _ => {}
+        }
+        self.token = token;
+        metrics::SCHEDULER_LEADER.set(token.is_some() as i64);
+
+        token.map(|token| Term { token, took_over })
+    }
+}
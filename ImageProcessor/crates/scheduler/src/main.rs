@@ -0,0 +1,695 @@
+use std::{env, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use common::{
+    DatasetProcessingTask,
+    config::{Config, DecomposerConfig, SloConfig},
+    correlation,
+    error::ProcessorError,
+    events::BatchEventKind,
+    faults,
+    lifecycle::BatchState,
+    logging, metrics,
+};
+use db_utils::types::{DBClient, DBDatasetTask, TaskStatus};
+use queue::{
+    ProducerClient, events::BatchEventPublisher, lag_reporter::LagReporter,
+    migration::TopicSwitcher, partitioner::AffinityPartitioner,
+};
+use tracing::{error, info};
+
+use crate::fairness::DeferredTaskRequeue;
+use crate::holding::HeldTaskRelease;
+use crate::janitor::Janitor;
+use crate::leadership::Leadership;
+use crate::manifests::ManifestPublisher;
+use crate::outbox::OutboxRelay;
+use crate::profiles::{ProfilePlanner, Profiles};
+use crate::sidecars::SidecarWriter;
+mod batch_manifest;
+mod drift;
+mod fairness;
+mod holding;
+mod janitor;
+mod leadership;
+mod manifests;
+mod mappings;
+mod outbox;
+mod profiles;
+mod provenance;
+mod sidecars;
+mod slo;
+
+/// Completes the dataset tasks whose image tasks have all finished.
+///
+/// A stage succeeds once all of its images are done and at least one of them succeeded, or none
+/// failed. The images that failed are in the batch's failures and the later stages leave them
+/// out. A stage fails only when every image that wasn't filtered out failed.
+async fn complete_finished_stages(
+    db: &DBClient,
+    events: &BatchEventPublisher,
+    config: &Config,
+    sidecars: &SidecarWriter,
+    manifests: Option<&ManifestPublisher>,
+    planner: Option<&ProfilePlanner>,
+    token: u64,
+) -> Result<(), ProcessorError> {
+    for task in db
+        .get_dataset_tasks_with_status(TaskStatus::Running)
+        .await?
+    {
+        // Images are still being created, so the counts aren't final yet
+        let Some(image_count) = task.image_count else {
+            continue;
+        };
+
+        let counts = db.image_status_counts(&task.task_id).await?;
+        if counts.success + counts.failure + counts.filtered < image_count {
+            continue;
+        }
+
+        let status = match (counts.success, counts.failure) {
+            (0, 1..) => TaskStatus::Failure,
+            _ => TaskStatus::Success,
+        };
+        if db
+            .mark_dataset_task_complete(&task.task_id, status.clone(), token)
+            .await?
+            .is_some()
+        {
+            info!(
+                batch_id = %task.batch_id,
+                task_id = %task.task_id,
+                stage = task.stage,
+                ?status,
+                "Stage finished"
+            );
+            let finished = BatchEventKind::StageFinished {
+                task_id: task.task_id,
+                stage: task.stage,
+                succeeded: matches!(status, TaskStatus::Success),
+                images: image_count,
+            };
+            events
+                .publish(task.batch_id, task.tenant_id.as_deref(), finished)
+                .await;
+            // Failed images are left out, so a failed stage still tells how long its images took
+            if let Some(planner) = planner
+                && let Err(e) = planner.record_stage(db, &task).await
+            {
+                error!(task_id = %task.task_id, error = %e, "Failed to update operation profiles");
+            }
+            if let Err(e) = drift::check_stage_quality(db, &task, &config.quality_drift).await {
+                error!(task_id = %task.task_id, error = %e, "Failed to check the stage's quality");
+            }
+            if finish_batch_stage(db, events, &task, &status, &config.slo).await? {
+                publish_results(db, sidecars, manifests, &task.batch_id).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the batch along after one of its stages finished. A failed stage fails the batch, the
+/// last of its stages succeeding completes it. Either way the finished batch is checked against
+/// the latency targets.
+///
+/// Returns whether the batch completed.
+async fn finish_batch_stage(
+    db: &DBClient,
+    events: &BatchEventPublisher,
+    task: &DBDatasetTask,
+    status: &TaskStatus,
+    slo_config: &SloConfig,
+) -> Result<bool, ProcessorError> {
+    let tenant_id = task.tenant_id.as_deref();
+    if matches!(status, TaskStatus::Failure) {
+        db.transition_batch(&task.batch_id, BatchState::Failed)
+            .await?;
+        let done = BatchEventKind::BatchDone {
+            state: BatchState::Failed,
+        };
+        events.publish(task.batch_id, tenant_id, done).await;
+        slo::check_batch_slo(db, &task.batch_id, slo_config).await?;
+        return Ok(false);
+    }
+
+    // The branches of a DAG job finish in any order, the batch completes with the last of them
+    let tasks = db.get_dataset_tasks(&task.batch_id).await?;
+    if !tasks
+        .iter()
+        .all(|task| matches!(task.status, TaskStatus::Success))
+    {
+        return Ok(false);
+    }
+
+    db.mark_batch_complete(&task.batch_id).await?;
+    info!(batch_id = %task.batch_id, "Batch completed");
+    let done = BatchEventKind::BatchDone {
+        state: BatchState::Completed,
+    };
+    events.publish(task.batch_id, tenant_id, done).await;
+    slo::check_batch_slo(db, &task.batch_id, slo_config).await?;
+    Ok(true)
+}
+
+/// The stages of a batch whose outputs no other stage reads, the result sets of the batch. A
+/// chain has one, its last stage.
+fn result_stages(tasks: &[DBDatasetTask]) -> Vec<&DBDatasetTask> {
+    tasks
+        .iter()
+        .filter(|task| {
+            !tasks
+                .iter()
+                .any(|other| other.input_task_id() == Some(task.task_id))
+        })
+        .collect()
+}
+
+/// Writes the sidecars and the last manifest of a completed batch next to each of its result sets,
+/// and the provenance and manifest of the batch next to them
+async fn publish_results(
+    db: &DBClient,
+    sidecars: &SidecarWriter,
+    manifests: Option<&ManifestPublisher>,
+    batch_id: &uuid::Uuid,
+) -> Result<(), ProcessorError> {
+    let tasks = db.get_dataset_tasks(batch_id).await?;
+    let results = result_stages(&tasks);
+
+    // Written before the manifest, which lists what the results folders hold and ends with the
+    // provenance root
+    for task in &results {
+        sidecars.publish_final(db, task).await?;
+    }
+    provenance::publish_provenance(db, sidecars.storage.as_ref(), &tasks, &results).await?;
+    batch_manifest::publish_batch_manifest(db, sidecars.storage.as_ref(), batch_id, &tasks).await?;
+    if let Some(manifests) = manifests {
+        manifests.publish_final(db, &results).await?;
+    }
+    Ok(())
+}
+
+/// Finishes the batches whose last stage finished, or one of whose stages failed, without the
+/// batch following along, e.g. because the last leader died in between. Run by a scheduler that
+/// just took over.
+async fn finish_interrupted_batches(
+    db: &DBClient,
+    events: &BatchEventPublisher,
+    slo_config: &SloConfig,
+    sidecars: &SidecarWriter,
+    manifests: Option<&ManifestPublisher>,
+) -> Result<(), ProcessorError> {
+    for batch in db.get_active_batches().await? {
+        let tasks = db.get_dataset_tasks(&batch.batch_id).await?;
+        let failed = tasks
+            .iter()
+            .find(|task| matches!(task.status, TaskStatus::Failure));
+        let last = tasks.last().filter(|_| {
+            tasks
+                .iter()
+                .all(|task| matches!(task.status, TaskStatus::Success))
+        });
+        let Some(task) = failed.or(last) else {
+            continue;
+        };
+
+        info!(
+            batch_id = %batch.batch_id,
+            stage = task.stage,
+            status = ?task.status,
+            "Finishing batch left behind by the last leader"
+        );
+        if finish_batch_stage(db, events, task, &task.status, slo_config).await? {
+            publish_results(db, sidecars, manifests, &batch.batch_id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Times out every batch that has been active for longer than the given duration, counted from
+/// its creation or its last retry
+async fn time_out_stale_batches(
+    db: &DBClient,
+    events: &BatchEventPublisher,
+    timeout: Duration,
+) -> Result<(), ProcessorError> {
+    let Ok(timeout) = chrono::Duration::from_std(timeout) else {
+        return Ok(());
+    };
+    let deadline = Utc::now() - timeout;
+
+    for batch in db.get_active_batches().await? {
+        // A batch reopened by a retry gets the full timeout again
+        let started = batch
+            .state_history
+            .iter()
+            .rev()
+            .find(|change| change.from == BatchState::Failed)
+            .map_or(batch.time_created, |change| change.time);
+        if started > deadline {
+            continue;
+        }
+        match db
+            .transition_batch(&batch.batch_id, BatchState::TimedOut)
+            .await
+        {
+            Ok(_) => {
+                info!(batch_id = %batch.batch_id, "Batch timed out");
+                let done = BatchEventKind::BatchDone {
+                    state: BatchState::TimedOut,
+                };
+                events
+                    .publish(batch.batch_id, batch.tenant_id.as_deref(), done)
+                    .await;
+            }
+            Err(e) => error!(batch_id = %batch.batch_id, error = %e, "Failed to time out batch"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Republishes the dataset tasks whose decomposer stopped renewing its lease, e.g. because the
+/// consumer crashed mid-archive, so another consumer resumes them. A task that keeps losing its
+/// consumer fails its stage after `max_recoveries` attempts.
+async fn recover_expired_leases(
+    db: &DBClient,
+    producer: &ProducerClient,
+    events: &BatchEventPublisher,
+    decomposer: &DecomposerConfig,
+    slo_config: &SloConfig,
+    token: u64,
+) -> Result<(), ProcessorError> {
+    let lease = Duration::from_secs(decomposer.lease_secs);
+    let now = Utc::now();
+
+    for task in db
+        .get_dataset_tasks_with_status(TaskStatus::Running)
+        .await?
+    {
+        if !task.lease_expired(now) {
+            continue;
+        }
+
+        if task.recoveries >= decomposer.max_recoveries {
+            if db
+                .transition_dataset_task_fenced(
+                    &task.task_id,
+                    TaskStatus::Running,
+                    TaskStatus::Failure,
+                    token,
+                )
+                .await?
+            {
+                metrics::DATASET_TASKS_RECOVERED
+                    .with_label_values(&["failed"])
+                    .inc();
+                metrics::TASKS_FAILED.with_label_values(&["dataset"]).inc();
+                error!(
+                    batch_id = %task.batch_id,
+                    task_id = %task.task_id,
+                    recoveries = task.recoveries,
+                    "Stage failed, its decomposer lease kept expiring"
+                );
+                finish_batch_stage(db, events, &task, &TaskStatus::Failure, slo_config).await?;
+            }
+            continue;
+        }
+
+        if !db.claim_expired_lease(&task, lease, token).await? {
+            continue;
+        }
+        let correlation_id = task.correlation_id.unwrap_or_else(uuid::Uuid::new_v4);
+        let message = DatasetProcessingTask::from(&task);
+        // The lease was extended by the claim, a failed send is retried once it expires again
+        correlation::scope(correlation_id, producer.send_dataset_task(&message)).await?;
+
+        metrics::DATASET_TASKS_RECOVERED
+            .with_label_values(&["republished"])
+            .inc();
+        info!(
+            batch_id = %task.batch_id,
+            task_id = %task.task_id,
+            stage = task.stage,
+            recovery = task.recoveries + 1,
+            "Republished stage, its decomposer lease expired"
+        );
+    }
+
+    Ok(())
+}
+
+/// Publishes the waiting dataset tasks whose dependencies all succeeded, and were approved if
+/// they had to be, and fails the ones one of whose dependencies failed or was rejected.
+///
+/// With operation profiles, the stages released in the same tick are published cheapest first,
+/// estimated from the images of the stage they read, so short stages don't queue behind long ones.
+async fn release_waiting_stages(
+    db: &DBClient,
+    producer: &ProducerClient,
+    profiles: Option<&Profiles>,
+    token: u64,
+) -> Result<(), ProcessorError> {
+    let mut ready: Vec<(f64, DBDatasetTask)> = Vec::new();
+    for task in db
+        .get_dataset_tasks_with_status(TaskStatus::Waiting)
+        .await?
+    {
+        // Tasks without a dependency are published by the api-server
+        if task.depends_on.is_empty() {
+            continue;
+        }
+
+        let mut dependencies = Vec::with_capacity(task.depends_on.len());
+        for depends_on in &task.depends_on {
+            dependencies.push(db.get_dataset_task(depends_on).await?);
+        }
+
+        if dependencies
+            .iter()
+            .flatten()
+            .any(|dep| matches!(dep.status, TaskStatus::Failure))
+        {
+            skip(db, &task, token).await?;
+            continue;
+        }
+        // Anything else is still waiting on one of its dependencies, or on the review of one
+        if !dependencies.iter().all(|dep| {
+            dep.as_ref()
+                .is_some_and(|dep| matches!(dep.status, TaskStatus::Success) && dep.is_approved())
+        }) {
+            continue;
+        }
+
+        let images = dependencies
+            .iter()
+            .flatten()
+            .find(|dep| task.input_task_id().is_none_or(|input| input == dep.task_id))
+            .and_then(|dep| dep.image_count)
+            .unwrap_or_default();
+        let cost = profiles.map_or(0.0, |profiles| profiles.stage_cost_ms(&task, images));
+        ready.push((cost, task));
+    }
+
+    // Stable, so stages of equal cost keep the order they were found in
+    ready.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    for (_, task) in &ready {
+        release(db, producer, task, token).await?;
+    }
+
+    Ok(())
+}
+
+async fn skip(db: &DBClient, task: &DBDatasetTask, token: u64) -> Result<(), ProcessorError> {
+    if db
+        .transition_dataset_task_fenced(
+            &task.task_id,
+            TaskStatus::Waiting,
+            TaskStatus::Failure,
+            token,
+        )
+        .await?
+    {
+        info!(
+            batch_id = %task.batch_id,
+            task_id = %task.task_id,
+            stage = task.stage,
+            "Stage skipped, the stage it depends on failed"
+        );
+    }
+
+    Ok(())
+}
+
+async fn release(
+    db: &DBClient,
+    producer: &ProducerClient,
+    waiting: &DBDatasetTask,
+    token: u64,
+) -> Result<(), ProcessorError> {
+    // Claim the task first so it is only published once, even by a leader that stalled past its
+    // lease while the next one released the stage. What is published is the task as claimed.
+    let Some(task) = db.claim_waiting_dataset_task(&waiting.task_id, token).await? else {
+        return Ok(());
+    };
+
+    // The batch has to follow along, this fails if it was cancelled or timed out meanwhile. A
+    // stage of a DAG job released after a later one leaves the batch at the later stage.
+    if let Err(e) = follow_stage(db, &task).await {
+        db.transition_dataset_task_fenced(
+            &task.task_id,
+            TaskStatus::Ready,
+            TaskStatus::Failure,
+            token,
+        )
+        .await?;
+        return Err(e);
+    }
+
+    // Sent on behalf of the request that created the batch
+    let correlation_id = task.correlation_id.unwrap_or_else(uuid::Uuid::new_v4);
+    let message = DatasetProcessingTask::from(&task);
+    if let Err(e) = correlation::scope(correlation_id, producer.send_dataset_task(&message)).await {
+        // Hand the task back so the next tick retries it
+        db.transition_dataset_task_fenced(
+            &task.task_id,
+            TaskStatus::Ready,
+            TaskStatus::Waiting,
+            token,
+        )
+        .await?;
+        return Err(e);
+    }
+
+    info!(
+        batch_id = %task.batch_id,
+        task_id = %task.task_id,
+        stage = task.stage,
+        %correlation_id,
+        "Released stage"
+    );
+    Ok(())
+}
+
+/// Moves the batch to the stage being released, unless it already shows it or a later one
+async fn follow_stage(db: &DBClient, task: &DBDatasetTask) -> Result<(), ProcessorError> {
+    let batch = db
+        .get_batch(&task.batch_id)
+        .await?
+        .ok_or_else(|| ProcessorError::NotFound(format!("Batch {} does not exist", task.batch_id)))?;
+    if matches!(batch.state, BatchState::Processing { stage } if stage >= task.stage) {
+        return Ok(());
+    }
+
+    db.transition_batch(&task.batch_id, BatchState::Processing { stage: task.stage })
+        .await
+        .map(|_| ())
+}
+
+#[tokio::main]
+async fn main() {
+    let config = Config::load().expect("SCHEDULER: Failed to load config");
+    logging::init(&config.logging);
+    faults::install(&config.faults);
+    let interval_secs: u64 = env::var("SCHEDULER_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2);
+    // Batches still running after this long are timed out, unset means no timeout
+    let batch_timeout = env::var("BATCH_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs);
+    // A leader that stopped renewing its lease for this long is taken over by a standby
+    let lease_secs: u64 = env::var("SCHEDULER_LEASE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or((interval_secs * 5).max(10));
+    let holder = format!(
+        "{}/{}",
+        env::var("HOSTNAME").unwrap_or_else(|_| "scheduler".to_string()),
+        uuid::Uuid::new_v4()
+    );
+    let mut leadership = Leadership::new(holder, Duration::from_secs(lease_secs));
+
+    let db = Arc::new(DBClient::new(&config.mongo).await);
+    // Every instance reports, standbys included, so the metric doesn't go stale while the
+    // leadership moves
+    if config.lag.enabled {
+        let mut reporter = LagReporter::from_config(config.kafka.consumer_brokers(), &config.lag);
+        if config.lag.record {
+            reporter = reporter.with_sink(db.lag_recorder());
+        }
+        reporter.spawn();
+    }
+    if config.capabilities.holding {
+        holding::spawn_holding_consumer(&config, Arc::clone(&db));
+    }
+    if config.lag.enabled || config.capabilities.holding {
+        metrics::spawn_server(config.metrics.address.clone());
+    }
+    let producer = ProducerClient::from_config(&config, &config.kafka.dataset_topic);
+    let events = BatchEventPublisher::from_config(&config);
+    let sidecars = SidecarWriter {
+        storage: storage::from_config(&config.storage, &config.s3.bucket).await,
+    };
+    let manifests = match config.manifests.enabled {
+        true => Some(ManifestPublisher {
+            storage: storage::from_config(&config.storage, &config.s3.bucket).await,
+            producer: ProducerClient::from_config(&config, &config.kafka.manifest_topic),
+            config: config.manifests.clone(),
+        }),
+        false => None,
+    };
+    // Image tasks go through the partitioner of the decomposer, see `ImageTask::affinity_key`
+    let relay = match config.outbox.enabled {
+        true => Some(OutboxRelay {
+            producer: ProducerClient::from_config(&config, &config.kafka.image_topic)
+                .with_partitioner(AffinityPartitioner),
+            dataset_producer: ProducerClient::from_config(&config, &config.kafka.dataset_topic),
+            config: config.outbox.clone(),
+        }),
+        false => None,
+    };
+    // Runs even without a bound, tasks deferred before it was lifted are still due
+    let deferred_tasks = DeferredTaskRequeue {
+        producer: ProducerClient::from_config(&config, &config.kafka.image_topic)
+            .with_partitioner(AffinityPartitioner),
+        config: config.fairness.clone(),
+    };
+    let held_producer = ProducerClient::from_config(&config, &config.kafka.image_topic);
+    // Moves along when the dataset or image topic is migrated, see `queue::migration`
+    let mut switcher = TopicSwitcher::new()
+        .producer(&producer)
+        .producer(&deferred_tasks.producer)
+        .producer(&held_producer);
+    if let Some(relay) = &relay {
+        switcher = switcher
+            .producer(&relay.producer)
+            .producer(&relay.dataset_producer);
+    }
+    switcher.start(&config.kafka).await;
+    let mut held_tasks = match config.capabilities.holding {
+        true => Some(HeldTaskRelease::new(
+            held_producer,
+            config.capabilities.worker_ttl_secs,
+        )),
+        false => None,
+    };
+    let janitor = Janitor {
+        storage: storage::from_config(&config.storage, &config.s3.bucket).await,
+        keep_for: match config.retention.intermediate_hours {
+            0 => None,
+            hours => Some(chrono::Duration::hours(hours as i64)),
+        },
+    };
+    let mut planner = match config.profiles.enabled {
+        true => Some(ProfilePlanner::new(
+            ProducerClient::from_config(&config, &config.kafka.control_topic),
+            config.profiles.clone(),
+        )),
+        false => None,
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    loop {
+        interval.tick().await;
+
+        // Standbys only keep trying to take the lease over
+        let Some(term) = leadership.renew(&db).await else {
+            continue;
+        };
+        if term.took_over
+            && let Err(e) = finish_interrupted_batches(
+                &db,
+                &events,
+                &config.slo,
+                &sidecars,
+                manifests.as_ref(),
+            )
+            .await
+        {
+            error!(error = %e, "Failed to finish the batches of the last leader");
+        }
+
+        if let Err(e) = complete_finished_stages(
+            &db,
+            &events,
+            &config,
+            &sidecars,
+            manifests.as_ref(),
+            planner.as_ref(),
+            term.token,
+        )
+        .await
+        {
+            error!(error = %e, "Failed to complete finished stages");
+        }
+        if let Some(manifests) = &manifests
+            && let Err(e) = manifests.publish_due(&db).await
+        {
+            error!(error = %e, "Failed to publish results manifests");
+        }
+        if let Some(relay) = &relay
+            && let Err(e) = relay.relay_due(&db).await
+        {
+            error!(error = %e, "Failed to relay the tasks of the outbox");
+        }
+        if let Err(e) = deferred_tasks.requeue_due(&db).await {
+            error!(error = %e, "Failed to requeue deferred image tasks");
+        }
+        if let Some(held_tasks) = &mut held_tasks
+            && let Err(e) = held_tasks.release_servable(&db).await
+        {
+            error!(error = %e, "Failed to requeue held tasks");
+        }
+        let profiles = match &planner {
+            Some(planner) => planner
+                .load(&db)
+                .await
+                .inspect_err(|e| error!(error = %e, "Failed to load operation profiles"))
+                .ok(),
+            None => None,
+        };
+        if let Err(e) = release_waiting_stages(&db, &producer, profiles.as_ref(), term.token).await
+        {
+            error!(error = %e, "Failed to release waiting stages");
+        }
+        if let (Some(planner), Some(profiles)) = (&mut planner, &profiles)
+            && let Err(e) = planner.update_thresholds(&db, profiles).await
+        {
+            error!(error = %e, "Failed to update the workers' memory thresholds");
+        }
+        if let Err(e) = recover_expired_leases(
+            &db,
+            &producer,
+            &events,
+            &config.decomposer,
+            &config.slo,
+            term.token,
+        )
+        .await
+        {
+            error!(error = %e, "Failed to recover expired leases");
+        }
+        if let Some(timeout) = batch_timeout
+            && let Err(e) = time_out_stale_batches(&db, &events, timeout).await
+        {
+            error!(error = %e, "Failed to time out stale batches");
+        }
+        if let Err(e) = slo::aggregate_closed_window(&db, &config.slo).await {
+            error!(error = %e, "Failed to aggregate latency percentiles");
+        }
+        if config.mappings.compaction
+            && let Err(e) = mappings::compact_finished_stages(&db, &config.mappings).await
+        {
+            error!(error = %e, "Failed to compact image task mappings");
+        }
+        if let Err(e) = janitor.sweep_due(&db).await {
+            error!(error = %e, "Failed to delete expired outputs");
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use common::{
+    config::ManifestConfig,
+    error::ProcessorError,
+    lifecycle::BatchState,
+    manifest::{
+        MANIFEST_MESSAGE_TYPE, ManifestEntry, ManifestNotice, ResultsManifest, manifest_key,
+    },
+};
+use db_utils::types::{DBClient, DBDatasetProcessingJob, DBDatasetTask, TaskStatus};
+use queue::ProducerClient;
+use storage::{PutOptions, StorageBackend};
+use tracing::info;
+
+/// Writes the results manifests of running batches and announces them, see `common::manifest`
+pub struct ManifestPublisher {
+    pub storage: Arc<dyn StorageBackend>,
+    pub producer: ProducerClient, // On the manifest topic
+    pub config: ManifestConfig,
+}
+
+impl ManifestPublisher {
+    /// Publishes a manifest for every batch in its last stage with `every_images` new outputs, or
+    /// with any new output once `every_secs` passed since its previous manifest.
+    pub async fn publish_due(&self, db: &DBClient) -> Result<(), ProcessorError> {
+        let now = Utc::now();
+
+        for batch in db.get_active_batches().await? {
+            let Some(task) = running_last_stage(db, &batch).await? else {
+                continue;
+            };
+            let pending = db.count_unpublished_results(&task.task_id).await?;
+            if pending == 0 {
+                continue;
+            }
+
+            let since = batch.last_manifest_at.unwrap_or(batch.time_created);
+            let by_count = self.config.every_images > 0 && pending >= self.config.every_images;
+            let by_time = self.config.every_secs > 0
+                && (now - since).num_seconds() >= self.config.every_secs as i64;
+            if by_count || by_time {
+                self.publish(db, &batch, &task, false).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publishes the last manifests of a batch once its last stage completed it, one for each of
+    /// its result stages with only the last one marked complete. Failed batches keep the
+    /// manifests they published so far.
+    pub async fn publish_final(
+        &self,
+        db: &DBClient,
+        results: &[&DBDatasetTask],
+    ) -> Result<(), ProcessorError> {
+        let Some(first) = results.first() else {
+            return Ok(());
+        };
+        let Some(mut batch) = db.get_batch(&first.batch_id).await? else {
+            return Ok(());
+        };
+        if batch.state != BatchState::Completed {
+            return Ok(());
+        }
+
+        for (i, task) in results.iter().enumerate() {
+            let complete = i + 1 == results.len();
+            self.publish(db, &batch, task, complete).await?;
+            // The next manifest claims the sequence after the one just published
+            batch.manifests_published += 1;
+        }
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        db: &DBClient,
+        batch: &DBDatasetProcessingJob,
+        task: &DBDatasetTask,
+        complete: bool,
+    ) -> Result<(), ProcessorError> {
+        // Another scheduler is publishing this one
+        let Some(sequence) = db.claim_manifest_sequence(batch).await? else {
+            return Ok(());
+        };
+
+        let results = db.get_unpublished_results(&task.task_id).await?;
+        let entries: Vec<ManifestEntry> = results
+            .iter()
+            .filter_map(|result| {
+                Some(ManifestEntry {
+                    key: result.output_key.clone()?,
+                    source_path: result.source_path.clone(),
+                    image_task_id: result.task_id?,
+                    chain_hash: result
+                        .provenance
+                        .as_ref()
+                        .map(|provenance| provenance.chain_hash.clone()),
+                })
+            })
+            .collect();
+        let manifest = ResultsManifest {
+            batch_id: batch.batch_id,
+            sequence,
+            complete,
+            time_created: Utc::now(),
+            entries,
+            provenance_root: batch.provenance_root.clone().filter(|_| complete),
+        };
+
+        let key = manifest_key(batch.tenant_id.as_deref(), &batch.batch_id, sequence);
+        let body = serde_json::to_vec(&manifest).map_err(ProcessorError::serialization)?;
+        let options = PutOptions {
+            content_type: Some("application/json".to_string()),
+            ..Default::default()
+        };
+        self.storage.put_object(&key, body.into(), &options).await?;
+
+        // Every task is marked, one without an output key would otherwise keep a manifest due
+        let task_ids: Vec<uuid::Uuid> =
+            results.iter().filter_map(|result| result.task_id).collect();
+        db.mark_published_results(&task_ids, sequence).await?;
+
+        let notice = ManifestNotice {
+            batch_id: batch.batch_id,
+            tenant_id: batch.tenant_id.clone(),
+            sequence,
+            complete,
+            manifest_key: key,
+            entries: manifest.entries.len(),
+        };
+        self.producer
+            .send_message(MANIFEST_MESSAGE_TYPE, &notice)
+            .await?;
+
+        info!(
+            batch_id = %batch.batch_id,
+            sequence,
+            entries = notice.entries,
+            complete,
+            "Published results manifest"
+        );
+        Ok(())
+    }
+}
+
+/// The dataset task of the last stage of a batch, if it is the one running
+async fn running_last_stage(
+    db: &DBClient,
+    batch: &DBDatasetProcessingJob,
+) -> Result<Option<DBDatasetTask>, ProcessorError> {
+    let Some(last_stage) = batch.stage_count().checked_sub(1) else {
+        return Ok(None);
+    };
+
+    Ok(db
+        .get_dataset_tasks(&batch.batch_id)
+        .await?
+        .into_iter()
+        .find(|task| {
+            task.stage as usize == last_stage && matches!(task.status, TaskStatus::Running)
+        }))
+}
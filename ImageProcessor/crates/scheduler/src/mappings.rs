@@ -0,0 +1,23 @@
+use chrono::{Duration, Utc};
+use common::{config::MappingConfig, error::ProcessorError};
+use db_utils::types::DBClient;
+use tracing::info;
+
+/// Compacts the mappings of the stages that finished long enough ago, a few per pass so a
+/// backlog of them doesn't hold up the other work of the scheduler
+pub async fn compact_finished_stages(
+    db: &DBClient,
+    config: &MappingConfig,
+) -> Result<(), ProcessorError> {
+    let cutoff = Utc::now() - Duration::seconds(config.compact_after_secs as i64);
+    for task in db.get_uncompacted_dataset_tasks(cutoff, config.tasks_per_pass).await? {
+        let compacted = db.compact_mappings(&task.task_id).await?;
+        info!(
+            batch_id = %task.batch_id,
+            task_id = %task.task_id,
+            compacted,
+            "Compacted the mappings of a finished stage"
+        );
+    }
+    Ok(())
+}
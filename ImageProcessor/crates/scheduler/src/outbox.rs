@@ -0,0 +1,52 @@
+use chrono::{Duration, Utc};
+use common::{config::OutboxConfig, error::ProcessorError};
+use db_utils::types::DBClient;
+use queue::ProducerClient;
+use tracing::info;
+
+/// Sends the image tasks the decomposer and the dataset tasks the api-server left in the
+/// outbox, see `db_utils::outbox`
+pub struct OutboxRelay {
+    pub producer: ProducerClient,         // On the image topic
+    pub dataset_producer: ProducerClient, // On the dataset topic
+    pub config: OutboxConfig,
+}
+
+impl OutboxRelay {
+    /// Sends the entries unsent for `relay_after_secs`, a few per pass, and deletes those sent
+    /// more than `keep_sent_hours` ago. A failed send is left for the next pass.
+    pub async fn relay_due(&self, db: &DBClient) -> Result<(), ProcessorError> {
+        let now = Utc::now();
+        let cutoff = now - Duration::seconds(self.config.relay_after_secs as i64);
+        let entries = db
+            .get_unsent_outbox_entries(cutoff, self.config.entries_per_pass)
+            .await?;
+
+        let mut relayed = 0;
+        for entry in entries {
+            self.producer.send_image_task(entry.task).await?;
+            db.mark_outbox_sent(&entry.task_id).await?;
+            relayed += 1;
+        }
+        if relayed > 0 {
+            info!(relayed, "Sent image tasks left in the outbox");
+        }
+
+        let entries = db
+            .get_unsent_dataset_outbox_entries(cutoff, self.config.entries_per_pass)
+            .await?;
+        let mut relayed = 0;
+        for entry in entries {
+            self.dataset_producer.send_dataset_task(&entry.task).await?;
+            db.mark_dataset_outbox_sent(&entry.task_id).await?;
+            relayed += 1;
+        }
+        if relayed > 0 {
+            info!(relayed, "Sent dataset tasks left in the outbox");
+        }
+
+        let sent_before = now - Duration::hours(self.config.keep_sent_hours as i64);
+        db.delete_sent_outbox_entries(sent_before).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use common::{
+    config::ProfileConfig,
+    control::{ControlCommand, ControlMessage},
+    error::ProcessorError,
+    operations_name,
+    profiles::MemoryThresholds,
+};
+use db_utils::types::{DBClient, DBDatasetTask, DBOperationProfile, TaskStatus};
+use queue::ProducerClient;
+use tracing::info;
+
+// Workers only read the control commands sent after they started, so the thresholds are sent
+// again now and then for the ones that joined since
+const THRESHOLDS_RESEND: Duration = Duration::from_secs(300);
+
+/// The operation profiles as of the current tick
+pub struct Profiles {
+    profiles: HashMap<String, DBOperationProfile>,
+    min_stages: u32,
+    reference_ms: f64,
+}
+
+impl Profiles {
+    /// Mean latency per image of the stage's operations, `None` until they were measured over
+    /// enough stages
+    pub fn cost_ms(&self, task: &DBDatasetTask) -> Option<f64> {
+        let operations = std::iter::once(&task.operation).chain(&task.fused_operations);
+        self.profiles
+            .get(&operations_name(operations))
+            .filter(|profile| profile.stages >= self.min_stages)
+            .map(|profile| profile.mean_ms)
+    }
+
+    /// Estimated time the images of a stage take altogether, operations without a profile yet
+    /// count as the reference
+    pub fn stage_cost_ms(&self, task: &DBDatasetTask, images: u64) -> f64 {
+        self.cost_ms(task).unwrap_or(self.reference_ms) * images as f64
+    }
+}
+
+/// Keeps the operation profiles up to date and the image workers' memory thresholds in line with
+/// the operations running, see `common::profiles`
+pub struct ProfilePlanner {
+    producer: ProducerClient, // On the control topic
+    config: ProfileConfig,
+    sent: Option<(MemoryThresholds, Instant)>, // Last thresholds sent to the workers
+}
+
+impl ProfilePlanner {
+    pub fn new(producer: ProducerClient, config: ProfileConfig) -> Self {
+        Self {
+            producer,
+            config,
+            sent: None,
+        }
+    }
+
+    pub async fn load(&self, db: &DBClient) -> Result<Profiles, ProcessorError> {
+        Ok(Profiles {
+            profiles: db.get_operation_profiles().await?,
+            min_stages: self.config.min_stages,
+            reference_ms: self.config.reference_ms,
+        })
+    }
+
+    /// Folds the latencies of a finished stage into the profile of its operations
+    pub async fn record_stage(
+        &self,
+        db: &DBClient,
+        task: &DBDatasetTask,
+    ) -> Result<(), ProcessorError> {
+        for (operation, samples) in db.stage_image_task_latencies(&task.task_id).await? {
+            db.record_operation_profile(&operation, &samples, self.config.smoothing)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the thresholds for the most expensive operation among the stages released or
+    /// running, or the defaults once none of them is profiled as slow
+    pub async fn update_thresholds(
+        &mut self,
+        db: &DBClient,
+        profiles: &Profiles,
+    ) -> Result<(), ProcessorError> {
+        let mut tasks = db.get_dataset_tasks_with_status(TaskStatus::Ready).await?;
+        tasks.extend(db.get_dataset_tasks_with_status(TaskStatus::Running).await?);
+
+        let slowest = tasks
+            .iter()
+            .filter_map(|task| profiles.cost_ms(task))
+            .reduce(f64::max);
+        let thresholds = slowest.map_or_else(MemoryThresholds::default, |cost_ms| {
+            MemoryThresholds::for_cost(cost_ms, self.config.reference_ms)
+        });
+
+        let due = match self.sent {
+            Some((sent, at)) => sent != thresholds || at.elapsed() >= THRESHOLDS_RESEND,
+            None => true,
+        };
+        if !due {
+            return Ok(());
+        }
+
+        let message = ControlMessage {
+            worker_id: None,
+            command: ControlCommand::SetMemoryThresholds { thresholds },
+        };
+        self.producer
+            .send_message(message.command.message_type(), &message)
+            .await?;
+
+        if self.sent.is_none_or(|(sent, _)| sent != thresholds) {
+            info!(?thresholds, slowest_ms = ?slowest, "Sent memory thresholds to the workers");
+        }
+        self.sent = Some((thresholds, Instant::now()));
+        Ok(())
+    }
+}
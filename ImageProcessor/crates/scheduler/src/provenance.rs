@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use common::{
+    error::ProcessorError,
+    lifecycle::BatchState,
+    provenance::{BatchProvenance, ProvenanceLink, batch_root, provenance_key},
+};
+use db_utils::types::{DBClient, DBDatasetTask};
+use storage::{PutOptions, StorageBackend};
+use tracing::info;
+
+/// Computes the provenance root of a completed batch over the outputs of its result sets, stores
+/// it on the batch and writes every link of the batch next to the results, see
+/// `common::provenance`
+pub async fn publish_provenance(
+    db: &DBClient,
+    storage: &dyn StorageBackend,
+    tasks: &[DBDatasetTask],
+    results: &[&DBDatasetTask],
+) -> Result<(), ProcessorError> {
+    let Some(first) = results.first() else {
+        return Ok(());
+    };
+    let Some(batch) = db.get_batch(&first.batch_id).await? else {
+        return Ok(());
+    };
+    if batch.state != BatchState::Completed {
+        return Ok(());
+    }
+
+    let stages: HashMap<uuid::Uuid, u32> =
+        tasks.iter().map(|task| (task.task_id, task.stage)).collect();
+    let mut links: Vec<ProvenanceLink> = db
+        .get_provenance_links(&batch.batch_id)
+        .await?
+        .into_iter()
+        .filter_map(|task| {
+            Some(ProvenanceLink {
+                image_task_id: task.task_id?,
+                stage: stages.get(&task.dataset_id).copied().unwrap_or_default(),
+                source_path: task.source_path,
+                output_key: task.output_key,
+                is_result: results.iter().any(|result| result.task_id == task.dataset_id),
+                provenance: task.provenance?,
+            })
+        })
+        .collect();
+    links.sort_by_key(|link| link.stage);
+
+    let leaves = links
+        .iter()
+        .filter(|link| link.is_result)
+        .map(|link| link.provenance.chain_hash.clone())
+        .collect();
+    let root = batch_root(leaves);
+    db.set_batch_provenance_root(&batch.batch_id, root.as_deref())
+        .await?;
+
+    let provenance = BatchProvenance {
+        batch_id: batch.batch_id,
+        root,
+        links,
+    };
+    let key = provenance_key(batch.tenant_id.as_deref(), &batch.batch_id);
+    let body = serde_json::to_vec(&provenance).map_err(ProcessorError::serialization)?;
+    let options = PutOptions {
+        content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+    storage.put_object(&key, body.into(), &options).await?;
+
+    info!(
+        batch_id = %batch.batch_id,
+        root = provenance.root.as_deref().unwrap_or("none"),
+        links = provenance.links.len(),
+        "Published the provenance of the batch"
+    );
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use common::{
+    error::ProcessorError,
+    keys::stage_folder,
+    lifecycle::BatchState,
+    naming::OutputLayout,
+    sidecars::{SidecarFormat, sidecar_output_key},
+    tagging,
+};
+use db_utils::types::{
+    DBClient, DBDatasetProcessingJob, DBDatasetTask, DBImageTask, DatasetSidecar,
+};
+use storage::{PutOptions, StorageBackend};
+use tracing::{error, info, warn};
+
+/// Writes the sidecars of completed batches next to their final outputs, see `common::sidecars`
+pub struct SidecarWriter {
+    pub storage: Arc<dyn StorageBackend>,
+}
+
+impl SidecarWriter {
+    /// Writes the sidecars of a batch once its last stage completed it. Failed batches have no
+    /// complete set of outputs to describe, so theirs are left where the decomposer kept them.
+    pub async fn publish_final(
+        &self,
+        db: &DBClient,
+        task: &DBDatasetTask,
+    ) -> Result<(), ProcessorError> {
+        let Some(batch) = db.get_batch(&task.batch_id).await? else {
+            return Ok(());
+        };
+        if batch.state != BatchState::Completed || batch.sidecars.is_empty() {
+            return Ok(());
+        }
+
+        let results = match batch.rewrite_sidecars {
+            true => db.get_stage_results(&task.task_id).await?,
+            false => Vec::new(),
+        };
+        // One sidecar that can't be written doesn't keep the others from the results
+        for sidecar in &batch.sidecars {
+            if let Err(e) = self.write(&batch, task, sidecar, &results).await {
+                error!(
+                    batch_id = %batch.batch_id,
+                    path = %sidecar.path,
+                    error = %e,
+                    "Failed to write dataset sidecar"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        batch: &DBDatasetProcessingJob,
+        task: &DBDatasetTask,
+        sidecar: &DatasetSidecar,
+        results: &[DBImageTask],
+    ) -> Result<(), ProcessorError> {
+        let layout = batch.output_layout;
+        let key = sidecar_output_key(&sidecar.output_prefix, task.stage, layout, &sidecar.path);
+        let body = self.storage.get_object(&sidecar.key).await?;
+
+        let format = SidecarFormat::from_path(&sidecar.path);
+        let body = match (batch.rewrite_sidecars, format) {
+            (true, SidecarFormat::Csv) => {
+                let outputs = stage_folder(&sidecar.output_prefix, task.stage + 1);
+                let renames = Renames::new(&sidecar.path, layout, &outputs, results);
+                let (body, rewritten) = rewrite_csv(&body, &renames)
+                    .map_err(|e| ProcessorError::Validation(format!("{}: {}", sidecar.path, e)))?;
+                info!(
+                    batch_id = %batch.batch_id,
+                    path = %sidecar.path,
+                    rewritten,
+                    "Rewrote the references of sidecar"
+                );
+                Bytes::from(body)
+            }
+            (true, SidecarFormat::Other) => {
+                warn!(
+                    batch_id = %batch.batch_id,
+                    path = %sidecar.path,
+                    "Only CSV sidecars have their references rewritten, kept as is"
+                );
+                body
+            }
+            (false, _) => body,
+        };
+
+        let options = PutOptions {
+            content_type: (format == SidecarFormat::Csv).then(|| "text/csv".to_string()),
+            tags: tagging::object_tags(batch.owner.as_deref(), &batch.tags),
+            ..Default::default()
+        };
+        storage::retry_throttled(|| self.storage.put_object(&key, body.clone(), &options)).await?;
+
+        info!(batch_id = %batch.batch_id, %key, "Wrote dataset sidecar next to the results");
+        Ok(())
+    }
+}
+
+/// The names the images of a dataset ended up with, keyed by the ways a sidecar may refer to
+/// them: relative to the sidecar, to the root of the dataset, or by file name alone when that is
+/// unambiguous. Values are relative to where the sidecar is written.
+struct Renames {
+    names: HashMap<String, String>,
+}
+
+impl Renames {
+    fn new(
+        sidecar_path: &str,
+        layout: OutputLayout,
+        outputs_prefix: &str,
+        results: &[DBImageTask],
+    ) -> Self {
+        let sidecar_dir = parent_dir(sidecar_path);
+        let output_dir = parent_dir(&layout.output_name(sidecar_path)).to_string();
+
+        let mut names = HashMap::new();
+        let mut file_names: HashMap<String, String> = HashMap::new();
+        let mut ambiguous = HashSet::new();
+        for result in results {
+            let (Some(source), Some(output_key)) = (&result.source_path, &result.output_key) else {
+                continue;
+            };
+            let Some(output) = output_key.strip_prefix(outputs_prefix) else {
+                continue;
+            };
+            let relative = output.strip_prefix(output_dir.as_str()).unwrap_or(output);
+
+            names.insert(source.clone(), output.to_string());
+            if let Some(source) = source.strip_prefix(sidecar_dir) {
+                names.insert(source.to_string(), relative.to_string());
+            }
+            let file_name = file_name(source).to_string();
+            if file_names
+                .insert(file_name.clone(), self::file_name(output).to_string())
+                .is_some()
+            {
+                ambiguous.insert(file_name);
+            }
+        }
+
+        for (file_name, output) in file_names {
+            if !ambiguous.contains(&file_name) {
+                names.entry(file_name).or_insert(output);
+            }
+        }
+        Self { names }
+    }
+
+    fn get(&self, reference: &str) -> Option<&str> {
+        self.names.get(reference).map(String::as_str)
+    }
+}
+
+/// Replaces every cell of a CSV that refers to an image by the image's output name, returns the
+/// rewritten CSV and how many cells changed
+fn rewrite_csv(body: &[u8], renames: &Renames) -> Result<(Vec<u8>, usize), String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(body);
+    let mut writer = csv::Writer::from_writer(Vec::new());
+
+    let mut rewritten = 0;
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("Invalid CSV: {}", e))?;
+        let fields: Vec<&str> = record
+            .iter()
+            .map(|field| match renames.get(field.trim()) {
+                Some(output) => {
+                    rewritten += 1;
+                    output
+                }
+                None => field,
+            })
+            .collect();
+        writer
+            .write_record(&fields)
+            .map_err(|e| format!("Failed to write CSV: {}", e))?;
+    }
+
+    let body = writer
+        .into_inner()
+        .map_err(|e| format!("Failed to write CSV: {}", e))?;
+    Ok((body, rewritten))
+}
+
+/// The folder of a path with its trailing '/', empty at the root
+fn parent_dir(path: &str) -> &str {
+    path.rfind('/').map_or("", |end| &path[..=end])
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
@@ -0,0 +1,83 @@
+use chrono::{DateTime, Duration, Utc};
+use common::{
+    config::SloConfig,
+    error::ProcessorError,
+    slo::{LatencyPercentiles, SloViolation},
+};
+use db_utils::types::{DBClient, DBLatencyWindow};
+
+/// Start of the window the given time falls into, windows are aligned to the epoch
+fn window_start(time: DateTime<Utc>, window_secs: i64) -> Option<DateTime<Utc>> {
+    let start = time.timestamp() - time.timestamp().rem_euclid(window_secs);
+    DateTime::from_timestamp(start, 0)
+}
+
+/// Aggregates the latency percentiles of the last window that closed, unless it was
+/// aggregated before
+pub async fn aggregate_closed_window(
+    db: &DBClient,
+    config: &SloConfig,
+) -> Result<(), ProcessorError> {
+    let window_secs = config.window_secs.max(1) as i64;
+    let Some(end) = window_start(Utc::now(), window_secs) else {
+        return Ok(());
+    };
+    let start = end - Duration::seconds(window_secs);
+    if db.has_latency_window(start).await? {
+        return Ok(());
+    }
+
+    for (operation, samples) in db.image_task_latencies_between(start, end).await? {
+        let Some(latency) = LatencyPercentiles::from_samples(samples) else {
+            continue;
+        };
+        let window = DBLatencyWindow {
+            id: None,
+            operation,
+            window_start: start,
+            window_end: end,
+            latency,
+        };
+        db.upsert_latency_window(&window).await?;
+    }
+
+    Ok(())
+}
+
+/// Compares the latency percentiles of a finished batch with the declared targets, and records
+/// the ones it missed on the batch
+pub async fn check_batch_slo(
+    db: &DBClient,
+    batch_id: &uuid::Uuid,
+    config: &SloConfig,
+) -> Result<(), ProcessorError> {
+    if config.targets.is_empty() {
+        return Ok(());
+    }
+
+    let mut violations: Vec<SloViolation> = Vec::new();
+    for (operation, samples) in db.batch_image_task_latencies(batch_id).await? {
+        let (Some(target), Some(latency)) = (
+            config.targets.get(&operation),
+            LatencyPercentiles::from_samples(samples),
+        ) else {
+            continue;
+        };
+        violations.extend(target.violations(&operation, &latency));
+    }
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    for violation in &violations {
+        tracing::warn!(
+            %batch_id,
+            operation = %violation.operation,
+            percentile = %violation.percentile,
+            observed_ms = violation.observed_ms,
+            target_ms = violation.target_ms,
+            "Batch missed a latency target"
+        );
+    }
+    db.set_batch_slo_violations(batch_id, &violations).await
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    ops::Range,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use common::{
+    config::{StorageConfig, StorageKind},
+    error::ProcessorError,
+};
+use futures::Stream;
+
+pub mod local;
+pub mod s3;
+
+pub use local::{LocalStorage, LocalUrlSigner, PresignedMethod};
+pub use s3::S3Storage;
+
+// A throttled or timed out call is made this many times in all
+const THROTTLE_ATTEMPTS: u32 = 4;
+// Wait before the second call, doubled before every further one
+const THROTTLE_BACKOFF: Duration = Duration::from_millis(250);
+
+// ============================================================================
+// OBJECT STORAGE
+// Every component reads and writes datasets, intermediate images and results
+// through `StorageBackend`, so the pipeline runs against S3, an S3-compatible
+// store (MinIO, GCS interoperability) or a local directory alike.
+// ============================================================================
+
+/// The body of an object, read chunk by chunk
+pub type ObjectStream = Pin<Box<dyn Stream<Item = Result<Bytes, ProcessorError>> + Send>>;
+
+/// Optional attributes of a written object
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    pub metadata: HashMap<String, String>,
+    pub tags: BTreeMap<String, String>, // Object tags, see `common::tagging`
+}
+
+/// A part uploaded to a multipart upload, as reported back by the client
+#[derive(Debug, Clone)]
+pub struct UploadedPart {
+    pub part_number: i32,
+    pub etag: String, // ETag header of the part upload's response
+}
+
+/// What is known about a stored object without reading it
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub etag: Option<String>, // Changes whenever the content does, unset on local storage
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Reads a whole object into memory
+    async fn get_object(&self, key: &str) -> Result<Bytes, ProcessorError>;
+
+    /// Reads an object as a stream, for objects too large to buffer
+    async fn get_object_stream(&self, key: &str) -> Result<ObjectStream, ProcessorError>;
+
+    /// Reads a byte range of an object as a stream, shorter if the object ends before the range
+    /// does
+    async fn get_object_range_stream(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<ObjectStream, ProcessorError>;
+
+    /// Reads a byte range of an object, shorter if the object ends before the range does
+    async fn get_object_range(&self, key: &str, range: Range<u64>)
+    -> Result<Bytes, ProcessorError>;
+
+    /// Returns `NotFound` if there is no object under the key
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, ProcessorError>;
+
+    async fn put_object(
+        &self,
+        key: &str,
+        body: Bytes,
+        options: &PutOptions,
+    ) -> Result<(), ProcessorError>;
+
+    /// Uploads a file of the local disk, streamed from it instead of read into memory. The
+    /// object only appears once it was written completely.
+    async fn put_file(
+        &self,
+        key: &str,
+        path: &Path,
+        options: &PutOptions,
+    ) -> Result<(), ProcessorError>;
+
+    /// A URL clients can upload the object to without credentials
+    async fn presign_put(&self, key: &str, expires_in: Duration) -> Result<String, ProcessorError>;
+
+    /// Starts an upload of the object in parts, for objects too large for a single PUT.
+    ///
+    /// Returns the id of the upload.
+    async fn create_multipart_upload(&self, key: &str) -> Result<String, ProcessorError>;
+
+    /// A URL clients can upload one part of a multipart upload to without credentials. Parts are
+    /// numbered from 1.
+    async fn presign_upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        expires_in: Duration,
+    ) -> Result<String, ProcessorError>;
+
+    /// Assembles the uploaded parts, in part number order, into the object
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<(), ProcessorError>;
+
+    /// A URL clients can download the object from without credentials
+    async fn presign_get(&self, key: &str, expires_in: Duration) -> Result<String, ProcessorError>;
+
+    /// Every key under the prefix
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ProcessorError>;
+
+    async fn copy_object(&self, from: &str, to: &str) -> Result<(), ProcessorError>;
+
+    /// Deletes the objects, keys that don't exist are skipped
+    async fn delete_objects(&self, keys: &[String]) -> Result<(), ProcessorError>;
+}
+
+/// Creates the backend selected by the config
+pub async fn from_config(config: &StorageConfig, bucket: &str) -> Arc<dyn StorageBackend> {
+    match config.backend {
+        StorageKind::S3 => Arc::new(S3Storage::from_env(bucket, config.endpoint.as_deref()).await),
+        StorageKind::Local => Arc::new(LocalStorage::new(
+            &config.local_root,
+            LocalUrlSigner::new(&config.local_url, &config.local_signing_key),
+        )),
+    }
+}
+
+/// Copies every object under `from` to the same relative key under `to`.
+///
+/// Returns the number of objects copied.
+pub async fn copy_prefix(
+    storage: &dyn StorageBackend,
+    from: &str,
+    to: &str,
+) -> Result<usize, ProcessorError> {
+    let keys = storage.list(from).await?;
+
+    for key in &keys {
+        let relative = key.strip_prefix(from).unwrap_or(key);
+        storage
+            .copy_object(key, &format!("{}{}", to, relative))
+            .await?;
+    }
+
+    Ok(keys.len())
+}
+
+/// Makes `to` hold exactly the objects under `from`. Every object is copied over before the
+/// ones `from` doesn't have are deleted, so a failure never leaves `to` with less than it had.
+///
+/// Returns the number of objects copied and deleted.
+pub async fn restore_prefix(
+    storage: &dyn StorageBackend,
+    from: &str,
+    to: &str,
+) -> Result<(usize, usize), ProcessorError> {
+    let keys = storage.list(from).await?;
+
+    let mut restored = HashSet::with_capacity(keys.len());
+    for key in &keys {
+        let relative = key.strip_prefix(from).unwrap_or(key);
+        let target = format!("{}{}", to, relative);
+        storage.copy_object(key, &target).await?;
+        restored.insert(target);
+    }
+
+    let stale: Vec<String> = storage
+        .list(to)
+        .await?
+        .into_iter()
+        .filter(|key| !restored.contains(key))
+        .collect();
+    storage.delete_objects(&stale).await?;
+
+    Ok((keys.len(), stale.len()))
+}
+
+/// Deletes every object under the given prefix.
+///
+/// Returns the number of objects deleted.
+pub async fn delete_prefix(
+    storage: &dyn StorageBackend,
+    prefix: &str,
+) -> Result<usize, ProcessorError> {
+    let keys = storage.list(prefix).await?;
+    storage.delete_objects(&keys).await?;
+
+    Ok(keys.len())
+}
+
+/// Makes a storage call again while the store throttles it or it times out, backing off in
+/// between. Any other error, or the last throttle, is returned as it is.
+pub async fn retry_throttled<T, F, Fut>(mut call: F) -> Result<T, ProcessorError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ProcessorError>>,
+{
+    let mut backoff = THROTTLE_BACKOFF;
+    for _ in 1..THROTTLE_ATTEMPTS {
+        match call().await {
+            Err(e) if e.s3_error_kind().is_some_and(|kind| kind.is_throttle()) => {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    call().await
+}
@@ -0,0 +1,474 @@
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use common::{
+    error::{ProcessorError, S3ErrorKind},
+    faults,
+};
+use futures::stream;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+use crate::{ObjectInfo, ObjectStream, PutOptions, StorageBackend, UploadedPart};
+
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+
+// Parts of unfinished multipart uploads are kept below this directory of the root
+const MULTIPART_DIR: &str = ".multipart";
+
+// Path the api-server serves the presigned URLs below
+pub const PRESIGNED_PATH: &str = "/storage";
+
+/// Objects stored as files below a root directory, the key is the relative path.
+///
+/// Meant for running the pipeline locally and in tests without credentials. Tags and metadata
+/// are not kept, and presigned URLs point to the api-server, which checks their signature and
+/// reads or writes the file, see `LocalUrlSigner`.
+pub struct LocalStorage {
+    root: PathBuf,
+    signer: LocalUrlSigner,
+}
+
+/// What a presigned URL of the Local backend lets its holder do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresignedMethod {
+    Get,
+    Put,
+}
+
+/// Signs the presigned URLs of the Local backend and checks them when they are used. A URL
+/// is `{base_url}/storage/{key}?expires={unix secs}&signature={hmac}`, the HMAC-SHA256 of the
+/// method, key and expiry under a key every service shares.
+#[derive(Clone)]
+pub struct LocalUrlSigner {
+    base_url: String,
+    key: Vec<u8>,
+}
+
+impl LocalUrlSigner {
+    pub fn new(base_url: &str, key: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            key: key.as_bytes().to_vec(),
+        }
+    }
+
+    fn mac(&self, method: PresignedMethod, key: &str, expires: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(format!("{:?}\n{}\n{}", method, key, expires).as_bytes());
+        mac
+    }
+
+    /// A URL for the object valid for `expires_in`
+    pub fn url(&self, method: PresignedMethod, key: &str, expires_in: Duration) -> String {
+        let expires = Utc::now().timestamp() + expires_in.as_secs() as i64;
+        let signature = hex::encode(self.mac(method, key, expires).finalize().into_bytes());
+        let path: Vec<String> = key
+            .split('/')
+            .map(|segment| urlencoding::encode(segment).into_owned())
+            .collect();
+        format!(
+            "{}{}/{}?expires={}&signature={}",
+            self.base_url,
+            PRESIGNED_PATH,
+            path.join("/"),
+            expires,
+            signature
+        )
+    }
+
+    /// Whether the signature was made by `url` for the method and key, and hasn't expired.
+    /// The signature is compared in constant time.
+    pub fn verify(
+        &self,
+        method: PresignedMethod,
+        key: &str,
+        expires: i64,
+        signature: &str,
+    ) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        expires >= Utc::now().timestamp()
+            && self.mac(method, key, expires).verify_slice(&signature).is_ok()
+    }
+}
+
+fn io_error(context: String, error: std::io::Error) -> ProcessorError {
+    let message = format!("{}: {}", context, error);
+    match error.kind() {
+        std::io::ErrorKind::NotFound => ProcessorError::NotFound(context),
+        std::io::ErrorKind::PermissionDenied => {
+            ProcessorError::classified_s3(message, S3ErrorKind::AccessDenied)
+        }
+        std::io::ErrorKind::TimedOut => {
+            ProcessorError::classified_s3(message, S3ErrorKind::Timeout)
+        }
+        _ => ProcessorError::s3(message, false),
+    }
+}
+
+/// Reads the reader chunk by chunk until it is exhausted
+fn reader_stream(key: &str, reader: impl AsyncRead + Unpin + Send + 'static) -> ObjectStream {
+    let key = key.to_string();
+    let chunks = stream::unfold(Some(reader), move |reader| {
+        let key = key.clone();
+        async move {
+            let mut reader = reader?;
+            let mut buf = vec![0u8; READ_CHUNK_BYTES];
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    buf.truncate(read);
+                    Some((Ok(Bytes::from(buf)), Some(reader)))
+                }
+                Err(e) => Some((Err(io_error(format!("Failed to read {}", key), e)), None)),
+            }
+        }
+    });
+    Box::pin(chunks)
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, signer: LocalUrlSigner) -> Self {
+        Self {
+            root: root.into(),
+            signer,
+        }
+    }
+
+    /// The file of a key, rejecting keys that would escape the root
+    fn path(&self, key: &str) -> Result<PathBuf, ProcessorError> {
+        let relative = Path::new(key);
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(ProcessorError::Validation(format!(
+                "Invalid object key {}",
+                key
+            )));
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    fn presigned_url(
+        &self,
+        method: PresignedMethod,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, ProcessorError> {
+        self.path(key)?;
+        Ok(self.signer.url(method, key, expires_in))
+    }
+
+    /// The file a part of a multipart upload is written to
+    fn part_key(upload_id: &str, part_number: i32) -> String {
+        format!("{}/{}/{}", MULTIPART_DIR, upload_id, part_number)
+    }
+
+    /// Every file below `dir`, as keys relative to the root
+    async fn walk(&self, dir: PathBuf) -> Result<Vec<String>, ProcessorError> {
+        let mut keys = Vec::new();
+        let mut pending = vec![dir];
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(io_error(format!("Failed to list {}", dir.display()), e)),
+            };
+
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .map_err(|e| io_error(format!("Failed to list {}", dir.display()), e))?
+            {
+                let path = entry.path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                    keys.push(relative.to_string_lossy().replace('\\', "/"));
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn get_object(&self, key: &str) -> Result<Bytes, ProcessorError> {
+        tokio::fs::read(self.path(key)?)
+            .await
+            .map(Bytes::from)
+            .map_err(|e| io_error(format!("Failed to read {}", key), e))
+    }
+
+    async fn get_object_stream(&self, key: &str) -> Result<ObjectStream, ProcessorError> {
+        let file = tokio::fs::File::open(self.path(key)?)
+            .await
+            .map_err(|e| io_error(format!("Failed to open {}", key), e))?;
+
+        Ok(reader_stream(key, file))
+    }
+
+    async fn get_object_range_stream(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<ObjectStream, ProcessorError> {
+        let mut file = tokio::fs::File::open(self.path(key)?)
+            .await
+            .map_err(|e| io_error(format!("Failed to open {}", key), e))?;
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .map_err(|e| io_error(format!("Failed to read {}", key), e))?;
+
+        Ok(reader_stream(
+            key,
+            file.take(range.end.saturating_sub(range.start)),
+        ))
+    }
+
+    async fn get_object_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Bytes, ProcessorError> {
+        let mut file = tokio::fs::File::open(self.path(key)?)
+            .await
+            .map_err(|e| io_error(format!("Failed to open {}", key), e))?;
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .map_err(|e| io_error(format!("Failed to read {}", key), e))?;
+
+        let mut buf = Vec::new();
+        file.take(range.end.saturating_sub(range.start))
+            .read_to_end(&mut buf)
+            .await
+            .map_err(|e| io_error(format!("Failed to read {}", key), e))?;
+        Ok(Bytes::from(buf))
+    }
+
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, ProcessorError> {
+        let metadata = tokio::fs::metadata(self.path(key)?)
+            .await
+            .map_err(|e| io_error(format!("Object {} does not exist", key), e))?;
+
+        Ok(ObjectInfo {
+            size: metadata.len(),
+            last_modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+            etag: None,
+        })
+    }
+
+    async fn put_object(
+        &self,
+        key: &str,
+        body: Bytes,
+        _options: &PutOptions,
+    ) -> Result<(), ProcessorError> {
+        faults::s3_put(key)?;
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error(format!("Failed to create {}", parent.display()), e))?;
+        }
+
+        tokio::fs::write(&path, body)
+            .await
+            .map_err(|e| io_error(format!("Failed to write {}", key), e))
+    }
+
+    async fn put_file(
+        &self,
+        key: &str,
+        path: &Path,
+        _options: &PutOptions,
+    ) -> Result<(), ProcessorError> {
+        faults::s3_put(key)?;
+        let target = self.path(key)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error(format!("Failed to create {}", parent.display()), e))?;
+        }
+
+        // Copied next to the object first, readers never see it half written
+        let mut partial = target.clone().into_os_string();
+        partial.push(".partial");
+        tokio::fs::copy(path, &partial)
+            .await
+            .map_err(|e| io_error(format!("Failed to write {}", key), e))?;
+        tokio::fs::rename(&partial, &target)
+            .await
+            .map_err(|e| io_error(format!("Failed to write {}", key), e))
+    }
+
+    async fn presign_put(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, ProcessorError> {
+        self.presigned_url(PresignedMethod::Put, key, expires_in)
+    }
+
+    async fn create_multipart_upload(&self, _key: &str) -> Result<String, ProcessorError> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let upload_id = format!("{:x}", nanos);
+
+        let dir = self.path(&format!("{}/{}", MULTIPART_DIR, upload_id))?;
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| io_error(format!("Failed to create {}", dir.display()), e))?;
+        Ok(upload_id)
+    }
+
+    async fn presign_upload_part(
+        &self,
+        _key: &str,
+        upload_id: &str,
+        part_number: i32,
+        expires_in: Duration,
+    ) -> Result<String, ProcessorError> {
+        self.presigned_url(
+            PresignedMethod::Put,
+            &Self::part_key(upload_id, part_number),
+            expires_in,
+        )
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<(), ProcessorError> {
+        let mut part_numbers: Vec<i32> = parts.iter().map(|part| part.part_number).collect();
+        part_numbers.sort();
+
+        let mut body = Vec::new();
+        for part_number in part_numbers {
+            let part = self.get_object(&Self::part_key(upload_id, part_number)).await?;
+            body.extend_from_slice(&part);
+        }
+        self.put_object(key, Bytes::from(body), &PutOptions::default())
+            .await?;
+
+        let dir = self.path(&format!("{}/{}", MULTIPART_DIR, upload_id))?;
+        tokio::fs::remove_dir_all(&dir)
+            .await
+            .map_err(|e| io_error(format!("Failed to remove {}", dir.display()), e))
+    }
+
+    async fn presign_get(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, ProcessorError> {
+        self.presigned_url(PresignedMethod::Get, key, expires_in)
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ProcessorError> {
+        // The prefix can end in the middle of a file name, so the walk starts at its directory
+        let dir = match prefix.rsplit_once('/') {
+            Some((dir, _)) => self.path(dir)?,
+            None => self.root.clone(),
+        };
+
+        let mut keys: Vec<String> = self
+            .walk(dir)
+            .await?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn copy_object(&self, from: &str, to: &str) -> Result<(), ProcessorError> {
+        let target = self.path(to)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error(format!("Failed to create {}", parent.display()), e))?;
+        }
+
+        tokio::fs::copy(self.path(from)?, target)
+            .await
+            .map(|_| ())
+            .map_err(|e| io_error(format!("Failed to copy {}", from), e))
+    }
+
+    async fn delete_objects(&self, keys: &[String]) -> Result<(), ProcessorError> {
+        for key in keys {
+            match tokio::fs::remove_file(self.path(key)?).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(io_error(format!("Failed to delete {}", key), e)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(url: &str, name: &str) -> String {
+        let (_, query) = url.split_once('?').unwrap();
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(&format!("{}=", name)))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn presigned_urls_only_verify_for_their_method_and_key() {
+        let signer = LocalUrlSigner::new("http://localhost:3030/", "secret");
+        let url = signer.url(PresignedMethod::Get, "results/a b.png", Duration::from_secs(60));
+        assert!(url.starts_with("http://localhost:3030/storage/results/a%20b.png?"));
+
+        let expires: i64 = query(&url, "expires").parse().unwrap();
+        let signature = query(&url, "signature");
+        assert!(signer.verify(PresignedMethod::Get, "results/a b.png", expires, &signature));
+        assert!(!signer.verify(PresignedMethod::Put, "results/a b.png", expires, &signature));
+        assert!(!signer.verify(PresignedMethod::Get, "results/c.png", expires, &signature));
+        assert!(!signer.verify(PresignedMethod::Get, "results/a b.png", expires + 1, &signature));
+
+        let other = LocalUrlSigner::new("http://localhost:3030", "other");
+        assert!(!other.verify(PresignedMethod::Get, "results/a b.png", expires, &signature));
+    }
+
+    #[test]
+    fn expired_urls_dont_verify() {
+        let signer = LocalUrlSigner::new("http://localhost:3030", "secret");
+        let expires = Utc::now().timestamp() - 1;
+        let signature = hex::encode(
+            signer
+                .mac(PresignedMethod::Get, "key", expires)
+                .finalize()
+                .into_bytes(),
+        );
+        assert!(!signer.verify(PresignedMethod::Get, "key", expires, &signature));
+    }
+}
@@ -0,0 +1,542 @@
+use std::{ops::Range, path::Path, time::Duration};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    Client,
+    config::http::HttpResponse,
+    error::{DisplayErrorContext, ProvideErrorMetadata, SdkError},
+    presigning::PresigningConfig,
+    primitives::{ByteStream, Length},
+    types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier},
+};
+use bytes::Bytes;
+use chrono::DateTime;
+use common::{
+    error::{ProcessorError, S3ErrorKind},
+    faults, metrics,
+    tagging::tagging_header,
+};
+use futures::stream;
+
+use crate::{ObjectInfo, ObjectStream, PutOptions, StorageBackend, UploadedPart};
+
+// DeleteObjects accepts at most this many keys per request
+const DELETE_BATCH_SIZE: usize = 1000;
+// Files larger than this are uploaded in parts, a single PutObject takes at most 5 GiB
+const MULTIPART_THRESHOLD: u64 = 256 * 1024 * 1024;
+// Size of the parts of an uploaded file, raised for files that would need more parts than allowed
+const MULTIPART_PART_SIZE: u64 = 64 * 1024 * 1024;
+// A multipart upload has at most this many parts
+const MAX_PARTS: u64 = 10_000;
+
+/// Objects in an S3 bucket. MinIO and GCS (through its XML API) work as well by pointing the
+/// client at their endpoint.
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+}
+
+/// An error that didn't come from the S3 API, e.g. reading a response body, worth retrying
+fn s3_error(context: String, error: impl std::fmt::Display) -> ProcessorError {
+    metrics::S3_ERRORS
+        .with_label_values(&[S3ErrorKind::Other.as_str()])
+        .inc();
+    ProcessorError::s3(format!("{}: {}", context, error), true)
+}
+
+/// An error of an S3 API call, classified so callers can retry throttles, give up on denied
+/// access and tell missing objects apart
+fn sdk_error<E>(context: String, error: SdkError<E, HttpResponse>) -> ProcessorError
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    let kind = classify(&error);
+    metrics::S3_ERRORS.with_label_values(&[kind.as_str()]).inc();
+    ProcessorError::classified_s3(format!("{}: {}", context, DisplayErrorContext(&error)), kind)
+}
+
+/// Tells apart the S3 errors callers handle differently, by their error code or, for responses
+/// without a body like those of HEAD requests, their status
+pub fn classify<E: ProvideErrorMetadata>(error: &SdkError<E, HttpResponse>) -> S3ErrorKind {
+    match error {
+        SdkError::TimeoutError(_) => return S3ErrorKind::Timeout,
+        SdkError::DispatchFailure(failure) if failure.is_timeout() => return S3ErrorKind::Timeout,
+        _ => {}
+    }
+
+    match error.code() {
+        Some("NoSuchKey" | "NotFound") => S3ErrorKind::NoSuchKey,
+        Some(
+            "AccessDenied" | "AllAccessDisabled" | "InvalidAccessKeyId" | "SignatureDoesNotMatch"
+            | "ExpiredToken",
+        ) => S3ErrorKind::AccessDenied,
+        Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestLimitExceeded") => {
+            S3ErrorKind::SlowDown
+        }
+        Some("RequestTimeout") => S3ErrorKind::Timeout,
+        _ => match error.raw_response().map(|resp| resp.status().as_u16()) {
+            Some(404) => S3ErrorKind::NoSuchKey,
+            Some(403) => S3ErrorKind::AccessDenied,
+            Some(429 | 503) => S3ErrorKind::SlowDown,
+            _ => S3ErrorKind::Other,
+        },
+    }
+}
+
+/// Adapts the body of a response to an `ObjectStream`
+fn body_stream(key: &str, body: ByteStream) -> ObjectStream {
+    let key = key.to_string();
+    let chunks = stream::unfold(body, move |mut body| {
+        let key = key.clone();
+        async move {
+            match body.next().await? {
+                Ok(chunk) => Some((Ok(chunk), body)),
+                Err(e) => Some((
+                    Err(s3_error(format!("Failed to read {} from S3", key), e)),
+                    body,
+                )),
+            }
+        }
+    });
+    Box::pin(chunks)
+}
+
+impl S3Storage {
+    pub fn new(client: Client, bucket: &str) -> Self {
+        Self {
+            client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    /// Creates a client from the AWS environment (credentials, region), talking to `endpoint`
+    /// instead of AWS if one is given
+    pub async fn from_env(bucket: &str, endpoint: Option<&str>) -> Self {
+        let sdk_config = aws_config::load_from_env().await;
+        let mut config = aws_sdk_s3::config::Builder::from(&sdk_config);
+        if let Some(endpoint) = endpoint {
+            // S3-compatible stores generally don't serve virtual-hosted bucket names
+            config = config.endpoint_url(endpoint).force_path_style(true);
+        }
+
+        Self::new(Client::from_conf(config.build()), bucket)
+    }
+
+    fn presigning_config(expires_in: Duration) -> Result<PresigningConfig, ProcessorError> {
+        PresigningConfig::expires_in(expires_in)
+            .map_err(|e| ProcessorError::Validation(format!("Invalid presign expiry: {}", e)))
+    }
+
+    /// Starts a multipart upload of an object with the attributes of `options`
+    async fn start_multipart_upload(
+        &self,
+        key: &str,
+        options: &PutOptions,
+    ) -> Result<String, ProcessorError> {
+        let resp = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(options.content_type.clone())
+            .set_metadata((!options.metadata.is_empty()).then(|| options.metadata.clone()))
+            .set_tagging(tagging_header(&options.tags))
+            .send()
+            .await
+            .map_err(|e| sdk_error(format!("Failed to start multipart upload of {}", key), e))?;
+
+        resp.upload_id()
+            .map(|id| id.to_string())
+            .ok_or_else(|| s3_error(format!("No upload id for {}", key), "missing in response"))
+    }
+
+    /// Uploads a file in parts, read from disk one part at a time. The upload is aborted if a
+    /// part fails, so its parts aren't kept around.
+    async fn put_file_multipart(
+        &self,
+        key: &str,
+        path: &Path,
+        size: u64,
+        options: &PutOptions,
+    ) -> Result<(), ProcessorError> {
+        let upload_id = self.start_multipart_upload(key, options).await?;
+        let uploaded = async {
+            let parts = self.upload_file_parts(key, &upload_id, path, size).await?;
+            self.complete_multipart_upload(key, &upload_id, &parts).await
+        }
+        .await;
+
+        if uploaded.is_err() {
+            // Best effort, parts of an upload that is never aborted are left to the bucket's
+            // lifecycle rules
+            let _ = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .send()
+                .await;
+        }
+        uploaded
+    }
+
+    async fn upload_file_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        path: &Path,
+        size: u64,
+    ) -> Result<Vec<UploadedPart>, ProcessorError> {
+        let part_size = MULTIPART_PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let mut parts = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let part_number = parts.len() as i32 + 1;
+            let length = part_size.min(size - offset);
+            let body = ByteStream::read_from()
+                .path(path)
+                .offset(offset)
+                .length(Length::Exact(length))
+                .build()
+                .await
+                .map_err(|e| s3_error(format!("Failed to read {}", path.display()), e))?;
+            let resp = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| {
+                    sdk_error(format!("Failed to upload part {} of {}", part_number, key), e)
+                })?;
+            let etag = resp.e_tag().ok_or_else(|| {
+                s3_error(format!("No ETag for part {} of {}", part_number, key), "missing")
+            })?;
+
+            parts.push(UploadedPart {
+                part_number,
+                etag: etag.to_string(),
+            });
+            offset += length;
+        }
+        Ok(parts)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn get_object(&self, key: &str) -> Result<Bytes, ProcessorError> {
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| sdk_error(format!("Failed to get {} from S3", key), e))?;
+
+        resp.body
+            .collect()
+            .await
+            .map(|body| body.into_bytes())
+            .map_err(|e| s3_error(format!("Failed to read {} from S3", key), e))
+    }
+
+    async fn get_object_stream(&self, key: &str) -> Result<ObjectStream, ProcessorError> {
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| sdk_error(format!("Failed to get {} from S3", key), e))?;
+
+        Ok(body_stream(key, resp.body))
+    }
+
+    async fn get_object_range_stream(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<ObjectStream, ProcessorError> {
+        if range.is_empty() {
+            return Ok(Box::pin(stream::empty()));
+        }
+
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .range(format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await
+            .map_err(|e| sdk_error(format!("Failed to get {} from S3", key), e))?;
+
+        Ok(body_stream(key, resp.body))
+    }
+
+    async fn get_object_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Bytes, ProcessorError> {
+        if range.is_empty() {
+            return Ok(Bytes::new());
+        }
+
+        let resp = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .range(format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await
+            .map_err(|e| sdk_error(format!("Failed to get {} from S3", key), e))?;
+
+        resp.body
+            .collect()
+            .await
+            .map(|body| body.into_bytes())
+            .map_err(|e| s3_error(format!("Failed to read {} from S3", key), e))
+    }
+
+    async fn head_object(&self, key: &str) -> Result<ObjectInfo, ProcessorError> {
+        let resp = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(service_error) if service_error.is_not_found() => {
+                    ProcessorError::NotFound(format!("Object {} does not exist", key))
+                }
+                _ => sdk_error(format!("Failed to get metadata of {}", key), e),
+            })?;
+
+        Ok(ObjectInfo {
+            size: resp.content_length().unwrap_or(0).max(0) as u64,
+            last_modified: resp
+                .last_modified()
+                .and_then(|time| DateTime::from_timestamp(time.secs(), time.subsec_nanos())),
+            etag: resp.e_tag().map(str::to_string),
+        })
+    }
+
+    async fn put_object(
+        &self,
+        key: &str,
+        body: Bytes,
+        options: &PutOptions,
+    ) -> Result<(), ProcessorError> {
+        faults::s3_put(key)?;
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(options.content_type.clone())
+            .set_metadata((!options.metadata.is_empty()).then(|| options.metadata.clone()))
+            .set_tagging(tagging_header(&options.tags))
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| sdk_error(format!("Failed to upload {} to S3", key), e))
+    }
+
+    async fn put_file(
+        &self,
+        key: &str,
+        path: &Path,
+        options: &PutOptions,
+    ) -> Result<(), ProcessorError> {
+        faults::s3_put(key)?;
+        let size = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| s3_error(format!("Failed to read {}", path.display()), e))?
+            .len();
+        if size > MULTIPART_THRESHOLD {
+            return self.put_file_multipart(key, path, size, options).await;
+        }
+
+        let body = ByteStream::from_path(path)
+            .await
+            .map_err(|e| s3_error(format!("Failed to read {}", path.display()), e))?;
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(options.content_type.clone())
+            .set_metadata((!options.metadata.is_empty()).then(|| options.metadata.clone()))
+            .set_tagging(tagging_header(&options.tags))
+            .body(body)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| sdk_error(format!("Failed to upload {} to S3", key), e))
+    }
+
+    async fn presign_put(&self, key: &str, expires_in: Duration) -> Result<String, ProcessorError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .presigned(Self::presigning_config(expires_in)?)
+            .await
+            .map(|request| request.uri().to_string())
+            .map_err(|e| sdk_error(format!("Failed to presign upload of {}", key), e))
+    }
+
+    async fn create_multipart_upload(&self, key: &str) -> Result<String, ProcessorError> {
+        self.start_multipart_upload(key, &PutOptions::default()).await
+    }
+
+    async fn presign_upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        expires_in: Duration,
+    ) -> Result<String, ProcessorError> {
+        self.client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .presigned(Self::presigning_config(expires_in)?)
+            .await
+            .map(|request| request.uri().to_string())
+            .map_err(|e| {
+                sdk_error(
+                    format!("Failed to presign part {} of {}", part_number, key),
+                    e,
+                )
+            })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: &[UploadedPart],
+    ) -> Result<(), ProcessorError> {
+        let mut parts: Vec<CompletedPart> = parts
+            .iter()
+            .map(|part| {
+                CompletedPart::builder()
+                    .part_number(part.part_number)
+                    .e_tag(&part.etag)
+                    .build()
+            })
+            .collect();
+        parts.sort_by_key(|part| part.part_number());
+        let upload = CompletedMultipartUpload::builder()
+            .set_parts(Some(parts))
+            .build();
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(upload)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| sdk_error(format!("Failed to complete multipart upload of {}", key), e))
+    }
+
+    async fn presign_get(&self, key: &str, expires_in: Duration) -> Result<String, ProcessorError> {
+        self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .presigned(Self::presigning_config(expires_in)?)
+            .await
+            .map(|request| request.uri().to_string())
+            .map_err(|e| sdk_error(format!("Failed to presign download of {}", key), e))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, ProcessorError> {
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let resp = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .map_err(|e| sdk_error(format!("Failed to list objects under {}", prefix), e))?;
+
+            keys.extend(
+                resp.contents()
+                    .iter()
+                    .filter_map(|obj| obj.key().map(|k| k.to_string())),
+            );
+
+            match resp.next_continuation_token() {
+                Some(token) => continuation_token = Some(token.to_string()),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn copy_object(&self, from: &str, to: &str) -> Result<(), ProcessorError> {
+        let copy_source = format!(
+            "{}/{}",
+            self.bucket,
+            urlencoding::encode(from).replace("%2F", "/")
+        );
+
+        self.client
+            .copy_object()
+            .bucket(&self.bucket)
+            .copy_source(copy_source)
+            .key(to)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| sdk_error(format!("Failed to copy {}", from), e))
+    }
+
+    async fn delete_objects(&self, keys: &[String]) -> Result<(), ProcessorError> {
+        for chunk in keys.chunks(DELETE_BATCH_SIZE) {
+            let objects = chunk
+                .iter()
+                .map(|key| ObjectIdentifier::builder().key(key).build())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| ProcessorError::Internal(e.to_string()))?;
+            let delete = Delete::builder()
+                .set_objects(Some(objects))
+                .quiet(true)
+                .build()
+                .map_err(|e| ProcessorError::Internal(e.to_string()))?;
+
+            self.client
+                .delete_objects()
+                .bucket(&self.bucket)
+                .delete(delete)
+                .send()
+                .await
+                .map_err(|e| sdk_error("Failed to delete objects".to_string(), e))?;
+        }
+
+        Ok(())
+    }
+}